@@ -0,0 +1,82 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue, Condition, QueryOrder};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "api_keys")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub user_id: i32,
+    #[sea_orm(column_type = "String(Some(50))")]
+    pub name: String,
+    /// Public part of the key, used to find the row before comparing the secret.
+    #[sea_orm(column_type = "String(Some(16))", unique)]
+    pub prefix: String,
+    #[sea_orm(column_type = "String(Some(100))")]
+    pub key_hash: String,
+    #[sea_orm(nullable)]
+    pub last_used_at: Option<DateTime>,
+    #[sea_orm(nullable)]
+    pub expires_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        if insert {
+            self.created_at = ActiveValue::Set(Utc::now().naive_utc());
+        }
+        Ok(self)
+    }
+}
+
+impl Model {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc())
+    }
+}
+
+impl Entity {
+    pub fn find_by_prefix(prefix: &str) -> Select<Entity> {
+        Entity::find().filter(Column::Prefix.eq(prefix))
+    }
+
+    pub fn find_by_user_id(user_id: i32) -> Select<Entity> {
+        Entity::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_desc(Column::CreatedAt)
+    }
+
+    pub fn find_by_id_and_user_id(id: i32, user_id: i32) -> Select<Entity> {
+        Entity::find().filter(
+            Condition::all()
+                .add(Column::Id.eq(id))
+                .add(Column::UserId.eq(user_id)),
+        )
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue, Condition};
+
+/// Durable copy of the Redis blacklist, so revoked refresh tokens stay revoked after a flush.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "token_blacklist")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false, column_type = "String(Some(36))")]
+    pub token_id: String,
+    pub user_id: i32,
+    pub expires_at: DateTime,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        if insert {
+            self.created_at = ActiveValue::Set(Utc::now().naive_utc());
+        }
+        Ok(self)
+    }
+}
+
+impl Entity {
+    pub fn find_active_by_token_id(token_id: &str) -> Select<Entity> {
+        Entity::find().filter(
+            Condition::all()
+                .add(Column::TokenId.eq(token_id))
+                .add(Column::ExpiresAt.gt(Utc::now().naive_utc())),
+        )
+    }
+
+    pub fn find_active() -> Select<Entity> {
+        Entity::find().filter(Column::ExpiresAt.gt(Utc::now().naive_utc()))
+    }
+}
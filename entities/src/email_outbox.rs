@@ -0,0 +1,78 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue, Condition, QueryOrder};
+
+use crate::enums::email_status_enum::EmailStatusEnum;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "email_outbox")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(column_type = "String(Some(200))")]
+    pub recipient: String,
+    #[sea_orm(column_type = "Text")]
+    pub subject: String,
+    #[sea_orm(column_type = "Text")]
+    pub body: String,
+    #[sea_orm(column_type = "SmallInteger", default_value = 0)]
+    pub attempts: i16,
+    #[sea_orm(column_type = "String(Some(7))", default_value = "PENDING")]
+    pub status: EmailStatusEnum,
+    pub next_attempt_at: DateTime,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub last_error: Option<String>,
+    /// When a worker took the row to send it, cleared once it is done.
+    #[sea_orm(nullable)]
+    pub claimed_at: Option<DateTime>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        let current_time = Utc::now().naive_utc();
+        self.updated_at = ActiveValue::Set(current_time);
+        if insert {
+            self.created_at = ActiveValue::Set(current_time);
+        }
+        Ok(self)
+    }
+}
+
+impl Entity {
+    /// Pending rows that are due, and rows claimed before `stale_before` by a worker
+    /// that never finished them.
+    pub fn find_due(now: DateTime, stale_before: DateTime) -> Select<Entity> {
+        Entity::find()
+            .filter(
+                Condition::any()
+                    .add(
+                        Condition::all()
+                            .add(Column::Status.eq(EmailStatusEnum::Pending))
+                            .add(Column::NextAttemptAt.lte(now)),
+                    )
+                    .add(
+                        Condition::all()
+                            .add(Column::Status.eq(EmailStatusEnum::Sending))
+                            .add(Column::ClaimedAt.lt(stale_before)),
+                    ),
+            )
+            .order_by_asc(Column::NextAttemptAt)
+    }
+
+    pub fn find_failed() -> Select<Entity> {
+        Entity::find()
+            .filter(Column::Status.eq(EmailStatusEnum::Failed))
+            .order_by_desc(Column::UpdatedAt)
+    }
+}
@@ -12,4 +12,7 @@ pub enum CursorEnum {
     Alpha,
     #[graphql(name = "DATE")]
     Date,
+    /// Last activity, users that were never active come last in descending order.
+    #[graphql(name = "ACTIVITY")]
+    Activity,
 }
@@ -0,0 +1,28 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::Enum;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Enum, Serialize, Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(7))")]
+pub enum EmailStatusEnum {
+    #[graphql(name = "PENDING")]
+    #[sea_orm(string_value = "PENDING")]
+    Pending,
+    #[graphql(name = "SENDING")]
+    #[sea_orm(string_value = "SENDING")]
+    Sending,
+    #[graphql(name = "SENT")]
+    #[sea_orm(string_value = "SENT")]
+    Sent,
+    #[graphql(name = "FAILED")]
+    #[sea_orm(string_value = "FAILED")]
+    Failed,
+}
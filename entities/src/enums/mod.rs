@@ -5,11 +5,13 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use cursor_enum::*;
+pub use email_status_enum::*;
 pub use oauth_provider_enum::*;
 pub use order_enum::*;
 pub use role_enum::*;
 
 pub mod cursor_enum;
+pub mod email_status_enum;
 pub mod oauth_provider_enum;
 pub mod order_enum;
 pub mod role_enum;
@@ -22,6 +22,9 @@ pub enum OAuthProviderEnum {
     #[graphql(name = "FACEBOOK")]
     #[sea_orm(string_value = "FACEBOOK")]
     Facebook,
+    #[graphql(name = "GITHUB")]
+    #[sea_orm(string_value = "GITHUB")]
+    Github,
 }
 
 impl OAuthProviderEnum {
@@ -30,6 +33,7 @@ impl OAuthProviderEnum {
             OAuthProviderEnum::Local => "LOCAL",
             OAuthProviderEnum::Google => "GOOGLE",
             OAuthProviderEnum::Facebook => "FACEBOOK",
+            OAuthProviderEnum::Github => "GITHUB",
         }
     }
 }
@@ -15,11 +15,20 @@ pub enum OrderEnum {
     Desc,
 }
 
-impl Into<Order> for OrderEnum {
-    fn into(self) -> Order {
-        match self {
+impl From<OrderEnum> for Order {
+    fn from(val: OrderEnum) -> Self {
+        match val {
             OrderEnum::Asc => Order::Asc,
             OrderEnum::Desc => Order::Desc,
         }
     }
 }
+
+impl OrderEnum {
+    pub fn reverse(self) -> Self {
+        match self {
+            OrderEnum::Asc => OrderEnum::Desc,
+            OrderEnum::Desc => OrderEnum::Asc,
+        }
+    }
+}
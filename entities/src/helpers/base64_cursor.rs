@@ -8,3 +8,31 @@ pub fn decode_cursor(after: &str) -> Option<String> {
 pub fn encode_cursor(cursor: &str) -> String {
     STANDARD.encode(cursor.as_bytes())
 }
+
+const FILTER_SEPARATOR: char = '|';
+
+/// A cursor position tied to the filters of the page it came from.
+pub struct FilteredCursor {
+    /// `None` for plain cursors issued before positions were tied to filters.
+    pub filter_hash: Option<String>,
+    pub position: String,
+}
+
+/// Positions are usernames and ids, neither contains the separator.
+pub fn decode_filtered_cursor(after: &str) -> Option<FilteredCursor> {
+    let decoded = decode_cursor(after)?;
+    Some(match decoded.split_once(FILTER_SEPARATOR) {
+        Some((filter_hash, position)) => FilteredCursor {
+            filter_hash: Some(filter_hash.to_string()),
+            position: position.to_string(),
+        },
+        None => FilteredCursor {
+            filter_hash: None,
+            position: decoded,
+        },
+    })
+}
+
+pub fn encode_filtered_cursor(filter_hash: &str, position: &str) -> String {
+    encode_cursor(&format!("{}{}{}", filter_hash, FILTER_SEPARATOR, position))
+}
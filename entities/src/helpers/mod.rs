@@ -5,7 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use base64_cursor::*;
+pub use page_cursor::*;
 pub use traits::*;
 
 pub mod base64_cursor;
+pub mod page_cursor;
 pub mod traits;
@@ -0,0 +1,18 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/// Side of the cursor a page is read from, pages without a cursor start at either end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageCursor {
+    After(Option<String>),
+    Before(Option<String>),
+}
+
+impl PageCursor {
+    pub fn is_backward(&self) -> bool {
+        matches!(self, PageCursor::Before(_))
+    }
+}
@@ -1,16 +1,23 @@
-use sea_orm::{EntityTrait, ModelTrait, Select};
+use sea_orm::{Condition, EntityTrait, ModelTrait, Select};
 
 use crate::enums::{CursorEnum, OrderEnum};
 
+use super::PageCursor;
+
 pub trait GQLQuery: EntityTrait {
+    /// Returns the page select and the select of the rows behind the cursor. Backward
+    /// pages come out in reverse order, starting next to the cursor. The `filter` holds
+    /// on both sides of the cursor, so a cursor never pages outside of it.
     fn query(
         order: OrderEnum,
         cursor: CursorEnum,
-        after: Option<String>,
+        page_cursor: PageCursor,
         search: Option<String>,
+        filter: Condition,
     ) -> (Select<Self>, Option<Select<Self>>);
 }
 
 pub trait GQLAfter: ModelTrait {
-    fn after(&self, cursor: CursorEnum) -> String;
+    /// The cursor carries `filter_hash`, so it is only valid for the filters it was paged with.
+    fn after(&self, cursor: CursorEnum, filter_hash: &str) -> String;
 }
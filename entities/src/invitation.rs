@@ -0,0 +1,83 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue, Condition, QueryOrder};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "invitations")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(column_type = "String(Some(200))")]
+    pub email: String,
+    #[sea_orm(column_type = "String(Some(100))")]
+    pub token_hash: String,
+    pub invited_by: i32,
+    pub expires_at: DateTime,
+    #[sea_orm(nullable)]
+    pub accepted_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::InvitedBy",
+        to = "super::user::Column::Id",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        if insert {
+            self.created_at = ActiveValue::Set(Utc::now().naive_utc());
+        }
+        Ok(self)
+    }
+}
+
+impl Model {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now().naive_utc()
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.accepted_at.is_some()
+    }
+}
+
+impl Entity {
+    fn pending_condition() -> Condition {
+        Condition::all()
+            .add(Column::AcceptedAt.is_null())
+            .add(Column::ExpiresAt.gt(Utc::now().naive_utc()))
+    }
+
+    pub fn find_pending() -> Select<Entity> {
+        Entity::find()
+            .filter(Self::pending_condition())
+            .order_by_desc(Column::CreatedAt)
+    }
+
+    pub fn find_pending_by_email(email: &str) -> Select<Entity> {
+        Entity::find().filter(Self::pending_condition().add(Column::Email.eq(email)))
+    }
+
+    pub fn find_pending_by_id(id: i32) -> Select<Entity> {
+        Entity::find().filter(Self::pending_condition().add(Column::Id.eq(id)))
+    }
+}
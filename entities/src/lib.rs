@@ -4,8 +4,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub mod api_key;
+pub mod blacklisted_token;
+pub mod email_outbox;
 pub mod enums;
 pub mod helpers;
+pub mod invitation;
 pub mod oauth_provider;
+pub mod recovery_code;
+pub mod reserved_username;
 pub mod uploaded_file;
 pub mod user;
@@ -5,9 +5,10 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use chrono::Utc;
-use sea_orm::{entity::prelude::*, ActiveValue, Condition};
+use sea_orm::{entity::prelude::*, ActiveValue, Condition, QueryOrder};
 
 use crate::enums::oauth_provider_enum::OAuthProviderEnum;
+use crate::helpers::{decode_cursor, encode_cursor};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "oauth_providers")]
@@ -18,6 +19,9 @@ pub struct Model {
     pub user_email: String,
     #[sea_orm(column_type = "String(Some(8))")]
     pub provider: OAuthProviderEnum,
+    /// Address the provider reported, may differ from the user's for linked accounts.
+    #[sea_orm(column_type = "String(Some(200))", nullable)]
+    pub provider_email: Option<String>,
     #[sea_orm(column_type = "Boolean", default_value = true)]
     pub two_factor: bool,
     pub created_at: DateTime,
@@ -54,7 +58,17 @@ impl ActiveModelBehavior for ActiveModel {
     }
 }
 
+impl Model {
+    pub fn after(&self) -> String {
+        encode_cursor(&self.id.to_string())
+    }
+}
+
 impl Entity {
+    pub fn find_by_email(user_email: &str) -> Select<Entity> {
+        Entity::find().filter(Column::UserEmail.eq(user_email))
+    }
+
     pub fn find_by_email_and_provider(
         user_email: &str,
         provider: OAuthProviderEnum,
@@ -65,4 +79,34 @@ impl Entity {
                 .add(Column::Provider.eq(provider)),
         )
     }
+
+    /// The link of an external identity, whatever account it belongs to.
+    pub fn find_by_provider_email(
+        provider: OAuthProviderEnum,
+        provider_email: &str,
+    ) -> Select<Entity> {
+        Entity::find().filter(
+            Condition::all()
+                .add(Column::Provider.eq(provider))
+                .add(Column::ProviderEmail.eq(provider_email)),
+        )
+    }
+
+    /// Newest links first, the cursor being the id of the last row already read.
+    pub fn find_recent_by_provider(
+        provider: OAuthProviderEnum,
+        after: Option<String>,
+    ) -> Select<Entity> {
+        let mut condition = Condition::all().add(Column::Provider.eq(provider));
+
+        if let Some(id) = after
+            .as_deref()
+            .and_then(decode_cursor)
+            .and_then(|value| value.parse::<i32>().ok())
+        {
+            condition = condition.add(Column::Id.lt(id));
+        }
+
+        Entity::find().filter(condition).order_by_desc(Column::Id)
+    }
 }
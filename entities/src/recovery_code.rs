@@ -0,0 +1,59 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue, Condition};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "recovery_codes")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub user_id: i32,
+    /// Keyed hash of the code, the plaintext is only returned when generated.
+    #[sea_orm(column_type = "String(Some(100))")]
+    pub code_hash: String,
+    #[sea_orm(nullable)]
+    pub used_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        if insert {
+            self.created_at = ActiveValue::Set(Utc::now().naive_utc());
+        }
+        Ok(self)
+    }
+}
+
+impl Entity {
+    pub fn find_unused_by_user_id(user_id: i32) -> Select<Entity> {
+        Entity::find().filter(
+            Condition::all()
+                .add(Column::UserId.eq(user_id))
+                .add(Column::UsedAt.is_null()),
+        )
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use sea_orm::{entity::prelude::*, ActiveValue};
+
+/// Usernames reserved by admins at runtime, on top of the ones in `RESERVED_USERNAMES`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "reserved_usernames")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false, column_type = "String(Some(109))")]
+    pub username: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {
+    async fn before_save<C: ConnectionTrait>(mut self, _: &C, insert: bool) -> Result<Self, DbErr> {
+        if insert {
+            self.created_at = ActiveValue::Set(Utc::now().naive_utc());
+        }
+        Ok(self)
+    }
+}
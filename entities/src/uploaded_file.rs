@@ -18,6 +18,14 @@ pub struct Model {
     pub user_id: i32,
     #[sea_orm(column_type = "String(Some(10))")]
     pub extension: String,
+    /// Locations of the resized renditions, keyed by size name.
+    #[sea_orm(column_type = "Json", nullable)]
+    pub sizes: Option<sea_orm::prelude::Json>,
+    /// Hex SHA-256 of the original rendition, set for images stored under their hash.
+    #[sea_orm(column_type = "String(Some(64))", nullable)]
+    pub content_hash: Option<String>,
+    /// Bytes stored for the file, every rendition included.
+    pub size_bytes: i64,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
@@ -55,4 +63,10 @@ impl Entity {
     pub fn find_by_id(id: &str) -> Select<Entity> {
         Entity::find().filter(Column::Id.eq(id))
     }
+
+    pub fn find_by_content_hash(user_id: i32, content_hash: &str) -> Select<Entity> {
+        Entity::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::ContentHash.eq(content_hash))
+    }
 }
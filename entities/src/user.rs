@@ -5,39 +5,98 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use chrono::Utc;
+use sea_orm::sea_query::Func;
 use sea_orm::QueryOrder;
-use sea_orm::{entity::prelude::*, ActiveValue, Condition};
+use sea_orm::{entity::prelude::*, ActiveValue, Condition, JoinType, QuerySelect, SelectTwo};
+use serde::{Deserialize, Serialize};
 
-use crate::enums::{cursor_enum::CursorEnum, order_enum::OrderEnum, role_enum::RoleEnum};
-use crate::helpers::{decode_cursor, encode_cursor, GQLAfter, GQLQuery};
+use crate::enums::{
+    cursor_enum::CursorEnum, oauth_provider_enum::OAuthProviderEnum, order_enum::OrderEnum,
+    role_enum::RoleEnum,
+};
+use crate::helpers::{
+    decode_filtered_cursor, encode_filtered_cursor, GQLAfter, GQLQuery, PageCursor,
+};
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+/// Activity the users are ordered by, never active users sort as the Unix epoch.
+pub const LAST_ACTIVE_ORDER: &str =
+    r#"COALESCE("users"."last_active_at", TIMESTAMP '1970-01-01 00:00:00')"#;
+
+const SEARCH_SCORE: &str = r#"GREATEST(similarity("users"."username", $1), word_similarity($1, "users"."first_name"), word_similarity($1, "users"."last_name"))"#;
+
+/// Placeholder password of users created through an external provider.
+pub const NO_PASSWORD: &str = "none";
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_registration_provider() -> OAuthProviderEnum {
+    OAuthProviderEnum::Local
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "users")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     #[sea_orm(column_type = "String(Some(200))", unique)]
     pub email: String,
+    #[sea_orm(column_type = "String(Some(200))", unique)]
+    #[serde(default)]
+    pub normalized_email: String,
     #[sea_orm(column_type = "String(Some(109))", unique)]
     pub username: String,
     #[sea_orm(column_type = "String(Some(50))")]
     pub first_name: String,
     #[sea_orm(column_type = "String(Some(50))")]
     pub last_name: String,
-    #[sea_orm(column_type = "Date")]
-    pub date_of_birth: chrono::NaiveDate,
+    #[sea_orm(column_type = "Date", nullable)]
+    pub date_of_birth: Option<chrono::NaiveDate>,
     #[sea_orm(column_type = "String(Some(5))", default_value = "USER")]
     pub role: RoleEnum,
     #[sea_orm(column_type = "Uuid", nullable)]
     pub picture: Option<Uuid>,
-    #[sea_orm(column_type = "SmallInteger", default_value = 0)]
-    pub version: i16,
+    #[sea_orm(column_type = "Integer", default_value = 0)]
+    pub version: i32,
     #[sea_orm(column_type = "Boolean", default_value = false)]
     pub confirmed: bool,
     #[sea_orm(column_type = "Boolean", default_value = false)]
     pub suspended: bool,
     #[sea_orm(column_type = "Text")]
     pub password: String,
+    #[sea_orm(column_type = "Boolean", default_value = false)]
+    pub username_customized: bool,
+    #[sea_orm(nullable)]
+    pub username_changed_at: Option<DateTime>,
+    #[sea_orm(column_type = "String(Some(10))", default_value = "en")]
+    pub preferred_locale: String,
+    #[sea_orm(nullable)]
+    pub deleted_at: Option<DateTime>,
+    #[sea_orm(nullable)]
+    pub last_login_at: Option<DateTime>,
+    /// Written lazily, at most every ten minutes, the presence key in Redis is fresher.
+    #[sea_orm(nullable)]
+    #[serde(default)]
+    pub last_active_at: Option<DateTime>,
+    #[sea_orm(column_type = "Boolean", default_value = false)]
+    #[serde(default)]
+    pub show_age: bool,
+    #[sea_orm(column_type = "Integer", default_value = 0)]
+    #[serde(default)]
+    pub min_token_version: i32,
+    #[sea_orm(column_type = "String(Some(50))", default_value = "UTC")]
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[sea_orm(column_type = "String(Some(50))", nullable)]
+    #[serde(default)]
+    pub terms_version: Option<String>,
+    #[sea_orm(column_type = "Boolean", default_value = false)]
+    #[serde(default)]
+    pub two_factor: bool,
+    #[sea_orm(column_type = "String(Some(8))", default_value = "LOCAL")]
+    #[serde(default = "default_registration_provider")]
+    pub registration_provider: OAuthProviderEnum,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
@@ -70,50 +129,172 @@ impl Model {
     pub fn full_name(&self) -> String {
         format!("{} {}", self.first_name, self.last_name)
     }
+
+    /// Users created through an external provider store `NO_PASSWORD`, imported ones an
+    /// unusable `!` prefixed value until they reset it, neither is a hash.
+    pub fn has_password(&self) -> bool {
+        self.password != NO_PASSWORD && !self.password.starts_with('!')
+    }
+
+    /// Same range as `Entity::find_by_version`, revoking tokens raises the minimum.
+    pub fn accepts_token_version(&self, version: i32) -> bool {
+        self.min_token_version <= version && version <= self.version
+    }
 }
 
 impl GQLAfter for Model {
-    fn after(&self, cursor: CursorEnum) -> String {
+    fn after(&self, cursor: CursorEnum, filter_hash: &str) -> String {
         match cursor {
-            CursorEnum::Alpha => encode_cursor(&self.username),
-            CursorEnum::Date => encode_cursor(&self.id.to_string()),
+            CursorEnum::Alpha => encode_filtered_cursor(filter_hash, &self.username),
+            CursorEnum::Date => encode_filtered_cursor(filter_hash, &self.id.to_string()),
+            CursorEnum::Activity => encode_filtered_cursor(
+                filter_hash,
+                &format!(
+                    "{}:{}",
+                    self.last_active_at
+                        .map(|date| date.timestamp_micros())
+                        .unwrap_or_default(),
+                    self.id
+                ),
+            ),
         }
     }
 }
 
 impl Entity {
+    fn find_active() -> Select<Entity> {
+        Self::find().filter(Column::DeletedAt.is_null())
+    }
+
     pub fn find_by_id(id: i32) -> Select<Entity> {
-        Self::find().filter(Column::Id.eq(id))
+        Self::find_active().filter(Column::Id.eq(id))
+    }
+
+    pub fn find_by_ids(ids: &[i32]) -> Select<Entity> {
+        Self::find_active().filter(Column::Id.is_in(ids.iter().copied()))
     }
 
     pub fn find_by_username(username: &str) -> Select<Entity> {
-        Self::find().filter(Column::Username.eq(username))
+        Self::find_active().filter(Column::Username.eq(username))
     }
 
+    /// Case insensitive, served by the unique `lower("email")` index.
     pub fn find_by_email(email: &str) -> Select<Entity> {
-        Self::find().filter(Column::Email.eq(email))
+        Self::find_active().filter(
+            Expr::expr(Func::lower(Expr::col((Entity, Column::Email)))).eq(email.to_lowercase()),
+        )
+    }
+
+    /// Matches every alias of the address, `normalized_email` must already be normalized.
+    pub fn find_by_normalized_email(normalized_email: &str) -> Select<Entity> {
+        Self::find_active().filter(Column::NormalizedEmail.eq(normalized_email))
+    }
+
+    /// Left joins the local provider inside the join condition, so accounts that only
+    /// use social login still come back, with `None` as their provider.
+    pub fn find_by_normalized_email_with_local_provider(
+        normalized_email: &str,
+    ) -> SelectTwo<Entity, super::oauth_provider::Entity> {
+        Self::find_by_normalized_email(normalized_email)
+            .select_also(super::oauth_provider::Entity)
+            .join(
+                JoinType::LeftJoin,
+                Relation::OAuthProvider.def().on_condition(|_, _| {
+                    Condition::all()
+                        .add(super::oauth_provider::Column::Provider.eq(OAuthProviderEnum::Local))
+                }),
+            )
+    }
+
+    /// Security changes move the version ahead, tokens stay valid while it does and are
+    /// only revoked once a credential change raises the minimum token version.
+    pub fn find_by_version(id: i32, version: i32) -> Select<Entity> {
+        Self::find_active().filter(
+            Condition::all()
+                .add(Column::Id.eq(id))
+                .add(Column::Version.gte(version))
+                .add(Column::MinTokenVersion.lte(version)),
+        )
     }
 
-    pub fn find_by_version(id: i32, version: i16) -> Select<Entity> {
+    /// Soft deleted users still hold their unique email and username
+    pub fn find_by_normalized_email_with_deleted(normalized_email: &str) -> Select<Entity> {
+        Self::find().filter(Column::NormalizedEmail.eq(normalized_email))
+    }
+
+    pub fn find_by_username_with_deleted(username: &str) -> Select<Entity> {
+        Self::find().filter(Column::Username.eq(username))
+    }
+
+    pub fn find_deleted_by_id(id: i32) -> Select<Entity> {
         Self::find().filter(
             Condition::all()
                 .add(Column::Id.eq(id))
-                .add(Column::Version.eq(version)),
+                .add(Column::DeletedAt.is_not_null()),
         )
     }
+
+    pub fn find_deleted_before(date: DateTime) -> Select<Entity> {
+        Self::find().filter(Self::deleted_before(date))
+    }
+
+    /// Active accounts that never confirmed their email.
+    pub fn find_unconfirmed() -> Select<Entity> {
+        Self::find_active().filter(Column::Confirmed.eq(false))
+    }
+
+    pub fn find_unconfirmed_before(date: DateTime) -> Select<Entity> {
+        Self::find().filter(Self::unconfirmed_before(date))
+    }
+
+    /// Soft deleted before `date`, past the grace period when it is the threshold.
+    pub fn deleted_before(date: DateTime) -> Condition {
+        Condition::all().add(Column::DeletedAt.lt(date))
+    }
+
+    /// Active accounts created before `date` that never confirmed their email.
+    pub fn unconfirmed_before(date: DateTime) -> Condition {
+        Condition::all()
+            .add(Column::DeletedAt.is_null())
+            .add(Column::Confirmed.eq(false))
+            .add(Column::CreatedAt.lt(date))
+    }
+
+    /// Visible users ranked by how closely their username or names match the query.
+    pub fn search(query: &str, threshold: f32) -> Select<Entity> {
+        Self::find_active()
+            .filter(Column::Confirmed.eq(true))
+            .filter(Column::Suspended.eq(false))
+            .filter(Expr::cust_with_values(
+                format!("{} >= $2", SEARCH_SCORE),
+                [Value::from(query), Value::from(threshold)],
+            ))
+            .order_by_desc(Expr::cust_with_values(SEARCH_SCORE, [query]))
+            .order_by_asc(Column::Id)
+    }
+}
+
+/// Activity cursors hold the last activity in microseconds and the id.
+fn parse_activity_cursor(value: &str) -> Option<(DateTime, i32)> {
+    let (last_active_at, id) = value.split_once(':')?;
+    let last_active_at = DateTime::from_timestamp_micros(last_active_at.parse().ok()?)?;
+    Some((last_active_at, id.parse().ok()?))
 }
 
 impl GQLQuery for Entity {
     fn query(
         order: OrderEnum,
         cursor: CursorEnum,
-        after: Option<String>,
+        page_cursor: PageCursor,
         search: Option<String>,
+        filter: Condition,
     ) -> (Select<Entity>, Option<Select<Entity>>) {
         let mut condition = Condition::any();
         let mut inverse_condition = None;
 
         if let Some(search) = search {
+            // Substring matches are served by the GIN trigram indexes, while the
+            // similarity operator would change which users match
             condition = condition
                 .add(Column::Username.contains(&search))
                 .add(Column::FirstName.contains(&search))
@@ -122,58 +303,96 @@ impl GQLQuery for Entity {
         if condition.is_empty() {
             condition = Condition::all()
                 .add(Column::Confirmed.eq(true))
-                .add(Column::Suspended.eq(false));
+                .add(Column::Suspended.eq(false))
+                .add(Column::DeletedAt.is_null());
         } else {
             condition = Condition::all()
                 .add(Column::Confirmed.eq(true))
                 .add(Column::Suspended.eq(false))
+                .add(Column::DeletedAt.is_null())
                 .add(condition);
         }
-        if let Some(after) = after {
-            let after = decode_cursor(&after);
-
-            if let Some(after) = after {
-                match cursor {
-                    CursorEnum::Alpha => {
-                        inverse_condition = Some(condition.clone().add(match order {
-                            OrderEnum::Asc => Column::Username.lt(&after),
-                            OrderEnum::Desc => Column::Username.gt(&after),
+        if !filter.is_empty() {
+            condition = condition.add(filter);
+        }
+
+        // Backward pages walk the order in reverse, so both directions read away from
+        // the cursor and leave the cursor row itself behind
+        let page_order = if page_cursor.is_backward() {
+            order.reverse()
+        } else {
+            order
+        };
+        let value = match page_cursor {
+            PageCursor::After(value) | PageCursor::Before(value) => value,
+        };
+
+        if let Some(value) = value
+            .as_deref()
+            .and_then(decode_filtered_cursor)
+            .map(|cursor| cursor.position)
+        {
+            match cursor {
+                CursorEnum::Alpha => {
+                    inverse_condition = Some(condition.clone().add(match page_order {
+                        OrderEnum::Asc => Column::Username.lte(&value),
+                        OrderEnum::Desc => Column::Username.gte(&value),
+                    }));
+                    condition = condition.add(match page_order {
+                        OrderEnum::Asc => Column::Username.gt(&value),
+                        OrderEnum::Desc => Column::Username.lt(&value),
+                    });
+                }
+                CursorEnum::Date => {
+                    let value = value.parse::<i32>();
+
+                    if let Ok(value) = value {
+                        inverse_condition = Some(condition.clone().add(match page_order {
+                            OrderEnum::Asc => Column::Id.lte(value),
+                            OrderEnum::Desc => Column::Id.gte(value),
                         }));
-                        condition = condition.add(match order {
-                            OrderEnum::Asc => Column::Username.gt(&after),
-                            OrderEnum::Desc => Column::Username.lt(&after),
+                        condition = condition.add(match page_order {
+                            OrderEnum::Asc => Column::Id.gt(value),
+                            OrderEnum::Desc => Column::Id.lt(value),
                         });
                     }
-                    CursorEnum::Date => {
-                        let after = after.parse::<i32>();
-
-                        if let Ok(after) = after {
-                            inverse_condition = Some(condition.clone().add(match order {
-                                OrderEnum::Asc => Column::Id.lt(after),
-                                OrderEnum::Desc => Column::Id.gt(after),
-                            }));
-                            condition = condition.add(match order {
-                                OrderEnum::Asc => Column::Id.gt(after),
-                                OrderEnum::Desc => Column::Id.lt(after),
-                            });
-                        }
+                }
+                CursorEnum::Activity => {
+                    if let Some((last_active_at, id)) = parse_activity_cursor(&value) {
+                        // Row comparisons keep users active at the same time apart by id
+                        let compare = |operator: &str| {
+                            Expr::cust_with_values(
+                                format!(
+                                    r#"({}, "users"."id") {} ($1, $2)"#,
+                                    LAST_ACTIVE_ORDER, operator
+                                ),
+                                [Value::from(last_active_at), Value::from(id)],
+                            )
+                        };
+                        inverse_condition = Some(condition.clone().add(match page_order {
+                            OrderEnum::Asc => compare("<="),
+                            OrderEnum::Desc => compare(">="),
+                        }));
+                        condition = condition.add(match page_order {
+                            OrderEnum::Asc => compare(">"),
+                            OrderEnum::Desc => compare("<"),
+                        });
                     }
                 }
             }
         }
 
+        let select = match cursor {
+            CursorEnum::Alpha => Self::find().order_by(Column::Username, page_order.into()),
+            CursorEnum::Date => Self::find().order_by(Column::Id, page_order.into()),
+            CursorEnum::Activity => Self::find()
+                .order_by(Expr::cust(LAST_ACTIVE_ORDER), page_order.into())
+                .order_by(Column::Id, page_order.into()),
+        };
+
         (
-            Self::find().filter(condition).order_by(
-                match cursor {
-                    CursorEnum::Alpha => Column::Username,
-                    CursorEnum::Date => Column::Id,
-                },
-                order.into(),
-            ),
-            match inverse_condition {
-                Some(inverse_condition) => Some(Self::find().filter(inverse_condition)),
-                None => None,
-            },
+            select.filter(condition),
+            inverse_condition.map(|inverse_condition| Self::find().filter(inverse_condition)),
         )
     }
 }
@@ -10,6 +10,33 @@ mod m20230922_000001_create_user_table;
 mod m20230922_000002_create_oauth_provider_table;
 mod m20231014_000003_create_uploaded_file_table;
 mod m20231112_000004_user_picture_foreign_key;
+mod m20231203_000005_user_username_customization;
+mod m20231204_000006_user_preferred_locale;
+mod m20231205_000007_user_soft_delete;
+mod m20231206_000008_user_optional_date_of_birth;
+mod m20231207_000009_user_last_login;
+mod m20231208_000010_uploaded_file_sizes;
+mod m20231209_000011_user_show_age;
+mod m20231210_000012_create_email_outbox_table;
+mod m20231211_000013_user_search_trigram;
+mod m20231212_000014_user_min_token_version;
+mod m20231213_000015_user_timezone;
+mod m20231214_000016_create_recovery_code_table;
+mod m20231215_000017_create_api_key_table;
+mod m20231216_000018_create_token_blacklist_table;
+mod m20231217_000019_create_invitation_table;
+mod m20231218_000020_user_normalized_email;
+mod m20231219_000021_oauth_provider_email;
+mod m20231220_000022_uploaded_file_content_hash;
+mod m20231221_000023_user_terms_version;
+mod m20231222_000024_uploaded_file_size_bytes;
+mod m20231223_000025_user_two_factor;
+mod m20231224_000026_user_registration_provider;
+mod m20231225_000027_user_lower_email;
+mod m20231226_000028_create_reserved_username_table;
+mod m20231227_000029_user_last_active;
+mod m20231228_000030_user_version_integer;
+mod m20231229_000031_email_outbox_claimed_at;
 
 pub struct Migrator;
 
@@ -21,6 +48,33 @@ impl MigratorTrait for Migrator {
             Box::new(m20230922_000002_create_oauth_provider_table::Migration),
             Box::new(m20231014_000003_create_uploaded_file_table::Migration),
             Box::new(m20231112_000004_user_picture_foreign_key::Migration),
+            Box::new(m20231203_000005_user_username_customization::Migration),
+            Box::new(m20231204_000006_user_preferred_locale::Migration),
+            Box::new(m20231205_000007_user_soft_delete::Migration),
+            Box::new(m20231206_000008_user_optional_date_of_birth::Migration),
+            Box::new(m20231207_000009_user_last_login::Migration),
+            Box::new(m20231208_000010_uploaded_file_sizes::Migration),
+            Box::new(m20231209_000011_user_show_age::Migration),
+            Box::new(m20231210_000012_create_email_outbox_table::Migration),
+            Box::new(m20231211_000013_user_search_trigram::Migration),
+            Box::new(m20231212_000014_user_min_token_version::Migration),
+            Box::new(m20231213_000015_user_timezone::Migration),
+            Box::new(m20231214_000016_create_recovery_code_table::Migration),
+            Box::new(m20231215_000017_create_api_key_table::Migration),
+            Box::new(m20231216_000018_create_token_blacklist_table::Migration),
+            Box::new(m20231217_000019_create_invitation_table::Migration),
+            Box::new(m20231218_000020_user_normalized_email::Migration),
+            Box::new(m20231219_000021_oauth_provider_email::Migration),
+            Box::new(m20231220_000022_uploaded_file_content_hash::Migration),
+            Box::new(m20231221_000023_user_terms_version::Migration),
+            Box::new(m20231222_000024_uploaded_file_size_bytes::Migration),
+            Box::new(m20231223_000025_user_two_factor::Migration),
+            Box::new(m20231224_000026_user_registration_provider::Migration),
+            Box::new(m20231225_000027_user_lower_email::Migration),
+            Box::new(m20231226_000028_create_reserved_username_table::Migration),
+            Box::new(m20231227_000029_user_last_active::Migration),
+            Box::new(m20231228_000030_user_version_integer::Migration),
+            Box::new(m20231229_000031_email_outbox_claimed_at::Migration),
         ]
     }
 }
@@ -11,8 +11,8 @@ use sea_orm_migration::{
 
 use entities::user::{Column, Entity};
 
-const USER_USERNAME_IDX: &'static str = "user_username_idx";
-const USER_ID_VERSION_IDX: &'static str = "user_id_version_idx";
+const USER_USERNAME_IDX: &str = "user_username_idx";
+const USER_ID_VERSION_IDX: &str = "user_id_version_idx";
 
 #[derive(DeriveMigrationName)]
 pub struct Migration;
@@ -11,8 +11,7 @@ use sea_orm_migration::{
 
 use entities::oauth_provider::{Column, Entity};
 
-const OAUTH_PROVIDER_USER_EMAIL_PROVIDER_IDX: &'static str =
-    "oauth_provider_user_email_provider_idx";
+const OAUTH_PROVIDER_USER_EMAIL_PROVIDER_IDX: &str = "oauth_provider_user_email_provider_idx";
 
 #[derive(DeriveMigrationName)]
 pub struct Migration;
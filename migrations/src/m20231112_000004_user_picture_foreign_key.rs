@@ -8,7 +8,7 @@ use sea_orm_migration::prelude::*;
 
 use entities::{uploaded_file, user};
 
-const FK_NAME: &'static str = "uploaded_file_user_id_fkey";
+const FK_NAME: &str = "uploaded_file_user_id_fkey";
 
 #[derive(DeriveMigrationName)]
 pub struct Migration;
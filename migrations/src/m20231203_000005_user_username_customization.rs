@@ -0,0 +1,46 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::user::{Column, Entity};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::UsernameCustomized)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::UsernameChangedAt).timestamp().null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::UsernameCustomized)
+                    .drop_column(Column::UsernameChangedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{DbBackend, Schema},
+};
+
+use entities::email_outbox::{Column, Entity};
+
+const EMAIL_OUTBOX_STATUS_NEXT_ATTEMPT_AT_IDX: &str = "email_outbox_status_next_attempt_at_idx";
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let schema = Schema::new(DbBackend::Postgres);
+        manager
+            .create_table(
+                schema
+                    .create_table_from_entity(Entity)
+                    .if_not_exists()
+                    .index(
+                        Index::create()
+                            .if_not_exists()
+                            .name(EMAIL_OUTBOX_STATUS_NEXT_ATTEMPT_AT_IDX)
+                            .col(Column::Status)
+                            .col(Column::NextAttemptAt),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .table(Entity)
+                    .name(EMAIL_OUTBOX_STATUS_NEXT_ATTEMPT_AT_IDX)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Entity).to_owned())
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+// Operator classes are not supported by the index builder, so the indexes are raw SQL
+const TRIGRAM_INDEXES: [(&str, &str); 3] = [
+    ("users_username_trgm_idx", "username"),
+    ("users_first_name_trgm_idx", "first_name"),
+    ("users_last_name_trgm_idx", "last_name"),
+];
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let connection = manager.get_connection();
+        connection
+            .execute_unprepared("CREATE EXTENSION IF NOT EXISTS pg_trgm")
+            .await?;
+
+        for (name, column) in TRIGRAM_INDEXES {
+            connection
+                .execute_unprepared(&format!(
+                    "CREATE INDEX IF NOT EXISTS \"{}\" ON \"users\" USING GIN (\"{}\" gin_trgm_ops)",
+                    name, column
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The extension is left installed as other schemas may depend on it
+        for (name, _) in TRIGRAM_INDEXES {
+            manager
+                .drop_index(Index::drop().if_exists().name(name).to_owned())
+                .await?;
+        }
+
+        Ok(())
+    }
+}
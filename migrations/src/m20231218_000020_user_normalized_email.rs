@@ -0,0 +1,101 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::user::{Column, Entity};
+
+const USER_NORMALIZED_EMAIL_IDX: &str = "user_normalized_email_idx";
+
+// Mirrors `normalize_email` with its default alias domains
+const BACKFILL_NORMALIZED_EMAIL: &str = r#"
+UPDATE "users" SET "normalized_email" = CASE
+    WHEN split_part(lower(trim("email")), '@', 2) IN ('gmail.com', 'googlemail.com')
+    THEN replace(split_part(split_part(lower(trim("email")), '@', 1), '+', 1), '.', '')
+        || '@' || split_part(lower(trim("email")), '@', 2)
+    ELSE lower(trim("email"))
+END
+"#;
+
+// Aliases registered before this migration keep their own address as key, only the
+// oldest account claims the shared one
+const RELEASE_DUPLICATES: &str = r#"
+UPDATE "users" SET "normalized_email" = lower(trim("email"))
+WHERE EXISTS (
+    SELECT 1 FROM "users" AS "older"
+    WHERE "older"."normalized_email" = "users"."normalized_email"
+    AND "older"."id" < "users"."id"
+)
+"#;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::NormalizedEmail)
+                            .string_len(200)
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        let connection = manager.get_connection();
+        connection
+            .execute_unprepared(BACKFILL_NORMALIZED_EMAIL)
+            .await?;
+        connection.execute_unprepared(RELEASE_DUPLICATES).await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .modify_column(
+                        ColumnDef::new(Column::NormalizedEmail)
+                            .string_len(200)
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name(USER_NORMALIZED_EMAIL_IDX)
+                    .table(Entity)
+                    .unique()
+                    .col(Column::NormalizedEmail)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .if_exists()
+                    .table(Entity)
+                    .name(USER_NORMALIZED_EMAIL_IDX)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::NormalizedEmail)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::oauth_provider::{Column, Entity};
+
+const OAUTH_PROVIDER_PROVIDER_EMAIL_IDX: &str = "oauth_provider_provider_provider_email_idx";
+
+// Links made before this migration were all matched by email
+const BACKFILL_PROVIDER_EMAIL: &str = r#"
+UPDATE "oauth_providers" SET "provider_email" = "user_email"
+WHERE "provider" <> 'LOCAL'
+"#;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::ProviderEmail).string_len(200).null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .get_connection()
+            .execute_unprepared(BACKFILL_PROVIDER_EMAIL)
+            .await?;
+        // An external identity belongs to one account, local rows have no provider email
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name(OAUTH_PROVIDER_PROVIDER_EMAIL_IDX)
+                    .table(Entity)
+                    .unique()
+                    .col(Column::Provider)
+                    .col(Column::ProviderEmail)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .if_exists()
+                    .table(Entity)
+                    .name(OAUTH_PROVIDER_PROVIDER_EMAIL_IDX)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::ProviderEmail)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::uploaded_file::{Column, Entity};
+
+const UPLOADED_FILE_USER_ID_CONTENT_HASH_IDX: &str = "uploaded_file_user_id_content_hash_idx";
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::ContentHash).string_len(64).null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // Not unique, two uploads racing on the same content may both insert a row
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name(UPLOADED_FILE_USER_ID_CONTENT_HASH_IDX)
+                    .table(Entity)
+                    .col(Column::UserId)
+                    .col(Column::ContentHash)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .if_exists()
+                    .table(Entity)
+                    .name(UPLOADED_FILE_USER_ID_CONTENT_HASH_IDX)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::ContentHash)
+                    .to_owned(),
+            )
+            .await
+    }
+}
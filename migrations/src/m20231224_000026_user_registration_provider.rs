@@ -0,0 +1,58 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::user::{Column, Entity};
+
+// The earliest provider row was created with the user, later ones were linked
+const BACKFILL_REGISTRATION_PROVIDER: &str = r#"
+UPDATE "users" SET "registration_provider" = "first_provider"."provider"
+FROM (
+    SELECT DISTINCT ON ("user_email") "user_email", "provider"
+    FROM "oauth_providers"
+    ORDER BY "user_email", "created_at", "id"
+) AS "first_provider"
+WHERE "first_provider"."user_email" = "users"."email"
+"#;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::RegistrationProvider)
+                            .string_len(8)
+                            .not_null()
+                            .default("LOCAL"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .get_connection()
+            .execute_unprepared(BACKFILL_REGISTRATION_PROVIDER)
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::RegistrationProvider)
+                    .to_owned(),
+            )
+            .await
+    }
+}
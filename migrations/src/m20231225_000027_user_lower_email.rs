@@ -0,0 +1,46 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+const USER_LOWER_EMAIL_IDX: &str = "user_lower_email_idx";
+
+// Provider rows follow through their `ON UPDATE CASCADE` foreign key. Addresses that
+// only differ in case stop the migration, those accounts have to be merged by hand
+const LOWERCASE_EMAILS: &str = r#"
+UPDATE "users" SET "email" = lower("email") WHERE "email" <> lower("email")
+"#;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let connection = manager.get_connection();
+        connection.execute_unprepared(LOWERCASE_EMAILS).await?;
+        // Expression indexes are not supported by the index builder. The plain unique
+        // constraint stays, the provider foreign key references it
+        connection
+            .execute_unprepared(&format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS \"{}\" ON \"users\" (lower(\"email\"))",
+                USER_LOWER_EMAIL_IDX
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .if_exists()
+                    .name(USER_LOWER_EMAIL_IDX)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,59 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm_migration::prelude::*;
+
+use entities::user::{Column, Entity, LAST_ACTIVE_ORDER};
+
+const USER_LAST_ACTIVE_IDX: &str = "user_last_active_idx";
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(Column::LastActiveAt).timestamp().null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // Expression indexes are not supported by the index builder, this one serves
+        // the activity cursor
+        manager
+            .get_connection()
+            .execute_unprepared(&format!(
+                "CREATE INDEX IF NOT EXISTS \"{}\" ON \"users\" (({}), \"id\")",
+                USER_LAST_ACTIVE_IDX, LAST_ACTIVE_ORDER
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .if_exists()
+                    .name(USER_LAST_ACTIVE_IDX)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Entity)
+                    .drop_column(Column::LastActiveAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -6,28 +6,35 @@
 
 use std::future::{ready, Ready};
 
-use actix_web::{cookie::Cookie, dev::Payload, http::header::HeaderMap, FromRequest, HttpRequest};
+use actix_web::{
+    cookie::Cookie, dev::Payload, http::header::HeaderMap, web, FromRequest, HttpRequest,
+};
 
-use crate::common::ServiceError;
+use crate::common::{InternalCause, ServiceError, SOMETHING_WENT_WRONG};
+use crate::providers::Jwt;
 
-fn get_access_token_from_headers(headers: &HeaderMap) -> Option<String> {
-    let auth_header = match headers.get("Authorization") {
-        Some(ah) => ah,
-        None => return None,
-    };
-    let auth_header = match auth_header.to_str() {
-        Ok(ah) => ah,
-        Err(_) => return None,
-    };
+/// Readable companion of the access cookie, mutations echo it in `CSRF_HEADER`.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
 
+fn get_authorization_header(headers: &HeaderMap) -> Option<&str> {
+    headers.get("Authorization")?.to_str().ok()
+}
+
+pub fn get_access_token_from_headers(headers: &HeaderMap) -> Option<String> {
+    get_bearer_token(get_authorization_header(headers)?)
+}
+
+pub fn get_api_key_from_headers(headers: &HeaderMap) -> Option<String> {
+    get_api_key(get_authorization_header(headers)?)
+}
+
+pub fn get_bearer_token(auth_header: &str) -> Option<String> {
     if auth_header.is_empty() || !auth_header.starts_with("Bearer ") {
         return None;
     }
 
-    let token = match auth_header.split_whitespace().last() {
-        Some(t) => t,
-        None => return None,
-    };
+    let token = auth_header.split_whitespace().last()?;
 
     if token.is_empty() {
         return None;
@@ -36,7 +43,18 @@ fn get_access_token_from_headers(headers: &HeaderMap) -> Option<String> {
     Some(token.to_string())
 }
 
-fn get_refresh_token_from_cookie(cookie: Option<Cookie>) -> Option<String> {
+/// Long-lived keys for server-to-server clients, sent as `ApiKey rgt_<prefix>_<secret>`.
+pub fn get_api_key(auth_header: &str) -> Option<String> {
+    let api_key = auth_header.strip_prefix("ApiKey ")?.trim();
+
+    if !api_key.starts_with("rgt_") {
+        return None;
+    }
+
+    Some(api_key.to_string())
+}
+
+fn get_token_from_cookie(cookie: Option<Cookie>) -> Option<String> {
     if let Some(cookie) = cookie {
         if cookie.value().is_empty() {
             return None;
@@ -48,16 +66,47 @@ fn get_refresh_token_from_cookie(cookie: Option<Cookie>) -> Option<String> {
     }
 }
 
+/// Double-submit check, a cross-site form can send the cookies but can not read the
+/// token to put in the header.
+pub fn has_csrf_token(request: &HttpRequest) -> bool {
+    let header = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    match (header, get_token_from_cookie(request.cookie(CSRF_COOKIE))) {
+        (Some(header), Some(cookie)) => header == cookie,
+        _ => false,
+    }
+}
+
 pub struct AuthTokens {
     pub access_token: Option<String>,
+    /// The access token came from the cookie, so mutations need the CSRF token.
+    pub access_cookie: bool,
+    /// API keys are never blacklisted nor refreshed, they are revoked instead.
+    pub api_key: Option<String>,
     pub refresh_token: Option<String>,
 }
 
 impl AuthTokens {
-    pub fn new(request: &HttpRequest) -> Self {
+    /// The access cookie is only read without an `Authorization` header, so header
+    /// clients behave the same with the cookie mode on.
+    pub fn new(request: &HttpRequest, jwt: &Jwt) -> Self {
+        let headers = request.headers();
+        let cookie_token = match (
+            get_authorization_header(headers),
+            jwt.get_access_cookie_name(),
+        ) {
+            (None, Some(name)) => get_token_from_cookie(request.cookie(name)),
+            _ => None,
+        };
+
         Self {
-            access_token: get_access_token_from_headers(request.headers()),
-            refresh_token: get_refresh_token_from_cookie(request.cookie("refresh_token")),
+            access_cookie: cookie_token.is_some(),
+            access_token: get_access_token_from_headers(headers).or(cookie_token),
+            api_key: get_api_key_from_headers(headers),
+            refresh_token: get_token_from_cookie(request.cookie(jwt.get_refresh_name())),
         }
     }
 }
@@ -67,6 +116,12 @@ impl FromRequest for AuthTokens {
     type Future = Ready<Result<AuthTokens, Self::Error>>;
 
     fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        ready(Ok(Self::new(request)))
+        match request.app_data::<web::Data<Jwt>>() {
+            Some(jwt) => ready(Ok(Self::new(request, jwt))),
+            None => ready(Err(ServiceError::internal_server_error(
+                SOMETHING_WENT_WRONG,
+                Some(InternalCause::new("Jwt provider not found in app data")),
+            ))),
+        }
     }
 }
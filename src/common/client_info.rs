@@ -0,0 +1,57 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+};
+
+use actix_web::{dev::Payload, http::header::USER_AGENT, FromRequest, HttpRequest};
+
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+const MAX_CLIENT_ID_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Stable identifier the frontend generates and persists, refresh tokens are bound
+    /// to it when present.
+    pub client_id: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(request: &HttpRequest) -> Self {
+        Self {
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            // Honours forwarding headers, only use it for display purposes
+            ip: request
+                .connection_info()
+                .realip_remote_addr()
+                .map(|value| value.to_string()),
+            client_id: request
+                .headers()
+                .get(CLIENT_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty() && value.len() <= MAX_CLIENT_ID_LENGTH)
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+impl FromRequest for ClientInfo {
+    type Error = Infallible;
+    type Future = Ready<Result<ClientInfo, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Self::new(request)))
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvRecord {
+    /// Line the record starts on, counting from one.
+    pub line: usize,
+    pub fields: Vec<String>,
+}
+
+/// Splits CSV into records as chunks arrive, so an upload never has to be held in
+/// memory. Quoted fields may hold commas, escaped quotes and line breaks.
+#[derive(Debug)]
+pub struct CsvReader {
+    line: usize,
+    record_line: usize,
+    field: Vec<u8>,
+    fields: Vec<String>,
+    in_quotes: bool,
+    after_quote: bool,
+}
+
+impl Default for CsvReader {
+    fn default() -> Self {
+        Self {
+            line: 1,
+            record_line: 1,
+            field: Vec::new(),
+            fields: Vec::new(),
+            in_quotes: false,
+            after_quote: false,
+        }
+    }
+}
+
+impl CsvReader {
+    fn end_field(&mut self) {
+        self.fields
+            .push(String::from_utf8_lossy(&self.field).into_owned());
+        self.field.clear();
+    }
+
+    fn end_record(&mut self) -> Option<CsvRecord> {
+        self.end_field();
+        let fields = std::mem::take(&mut self.fields);
+        let line = self.record_line;
+        self.record_line = self.line;
+
+        // Blank lines are not records
+        if fields.len() == 1 && fields[0].is_empty() && !self.after_quote {
+            return None;
+        }
+
+        Some(CsvRecord { line, fields })
+    }
+
+    /// Records completed by the chunk, a record cut by the chunk's end waits for the next.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<CsvRecord> {
+        let mut records = Vec::new();
+
+        for &byte in chunk {
+            if self.in_quotes {
+                match byte {
+                    b'"' => {
+                        self.in_quotes = false;
+                        self.after_quote = true;
+                    }
+                    b'\n' => {
+                        self.line += 1;
+                        self.field.push(byte);
+                    }
+                    _ => self.field.push(byte),
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' if self.after_quote => {
+                    self.field.push(byte);
+                    self.in_quotes = true;
+                    self.after_quote = false;
+                }
+                b'"' if self.field.is_empty() => self.in_quotes = true,
+                b',' => {
+                    self.end_field();
+                    self.after_quote = false;
+                }
+                b'\n' => {
+                    self.line += 1;
+                    if let Some(record) = self.end_record() {
+                        records.push(record);
+                    }
+                    self.after_quote = false;
+                }
+                b'\r' => {}
+                _ => self.field.push(byte),
+            }
+        }
+
+        records
+    }
+
+    /// The last record, when the input does not end with a line break.
+    pub fn finish(mut self) -> Option<CsvRecord> {
+        if self.field.is_empty() && self.fields.is_empty() && !self.after_quote {
+            return None;
+        }
+
+        self.end_record()
+    }
+}
@@ -5,9 +5,15 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use actix_web::{error, http::StatusCode, HttpResponse};
-use async_graphql::{Error, ErrorExtensions};
+use std::fmt;
+
+use async_graphql::{to_value, Error, ErrorExtensions, InputType, InputValueError};
 use derive_more::Display;
-use sea_orm::DbErr;
+use sea_orm::{DbErr, SqlErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{ApiSchema, RequestId};
 
 #[derive(Debug, Display)]
 pub struct InternalCause(String);
@@ -18,51 +24,111 @@ impl InternalCause {
     }
 }
 
-#[derive(Debug, Display)]
+/// A failed validation and the input field, as named in the schema, it belongs to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Displayed as the JSON array of messages the REST API has always returned.
+#[derive(Clone, Debug)]
+pub struct FieldErrors(pub Vec<FieldError>);
+
+impl fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let messages = self
+            .0
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect::<Vec<&str>>();
+        write!(
+            f,
+            "{}",
+            serde_json::to_string(&messages).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+#[derive(Clone, Debug, Display)]
 pub enum ServiceError {
     InternalServerError(String),
     BadRequest(String),
+    Validation(FieldErrors),
     Unauthorized(String),
     NotFound(String),
     Forbidden(String),
-    Conflict(String),
+    /// The details, when set, are sent as `details` next to the message.
+    #[display(fmt = "{}", _0)]
+    Conflict(String, Option<Value>),
+    PayloadTooLarge(String),
+    BadGateway(String),
+    GatewayTimeout(String),
+    ServiceUnavailable(String),
 }
 
-pub const INTERNAL_SERVER_ERROR: &'static str = "Internal Server Error";
+pub const INTERNAL_SERVER_ERROR: &str = "Internal Server Error";
 pub const INTERNAL_SERVER_ERROR_STATUS_CODE: u16 = 500;
-pub const BAD_REQUEST: &'static str = "Bad Request";
+pub const BAD_REQUEST: &str = "Bad Request";
 pub const BAD_REQUEST_STATUS_CODE: u16 = 400;
-pub const UNAUTHORIZED: &'static str = "Unauthorized";
+pub const UNAUTHORIZED: &str = "Unauthorized";
 pub const UNAUTHORIZED_STATUS_CODE: u16 = 401;
-pub const NOT_FOUND: &'static str = "Not Found";
+pub const NOT_FOUND: &str = "Not Found";
 pub const NOT_FOUND_STATUS_CODE: u16 = 404;
-pub const FORBIDDEN: &'static str = "Forbidden";
+pub const FORBIDDEN: &str = "Forbidden";
 pub const FORBIDDEN_STATUS_CODE: u16 = 403;
-pub const CONFLICT: &'static str = "Conflict";
+pub const CONFLICT: &str = "Conflict";
 pub const CONFLICT_STATUS_CODE: u16 = 409;
-pub const SOMETHING_WENT_WRONG: &'static str = "Something went wrong";
-pub const INVALID_CREDENTIALS: &'static str = "Invalid credentials";
+pub const PAYLOAD_TOO_LARGE: &str = "Payload Too Large";
+pub const PAYLOAD_TOO_LARGE_STATUS_CODE: u16 = 413;
+pub const BAD_GATEWAY: &str = "Bad Gateway";
+pub const BAD_GATEWAY_STATUS_CODE: u16 = 502;
+pub const GATEWAY_TIMEOUT: &str = "Gateway Timeout";
+pub const GATEWAY_TIMEOUT_STATUS_CODE: u16 = 504;
+pub const SERVICE_UNAVAILABLE: &str = "Service Unavailable";
+pub const SERVICE_UNAVAILABLE_STATUS_CODE: u16 = 503;
+pub const SOMETHING_WENT_WRONG: &str = "Something went wrong";
+pub const INVALID_CREDENTIALS: &str = "Invalid credentials";
+/// Extension listing every field error, split into one error each by `ErrorMapping`.
+pub const VALIDATION_ERRORS_EXTENSION: &str = "validationErrors";
 
 impl ServiceError {
     pub fn to_str_name(&self) -> &'static str {
         match self {
             ServiceError::InternalServerError(_) => INTERNAL_SERVER_ERROR,
-            ServiceError::BadRequest(_) => BAD_REQUEST,
+            ServiceError::BadRequest(_) | ServiceError::Validation(_) => BAD_REQUEST,
             ServiceError::Unauthorized(_) => UNAUTHORIZED,
             ServiceError::NotFound(_) => NOT_FOUND,
             ServiceError::Forbidden(_) => FORBIDDEN,
-            ServiceError::Conflict(_) => CONFLICT,
+            ServiceError::Conflict(..) => CONFLICT,
+            ServiceError::PayloadTooLarge(_) => PAYLOAD_TOO_LARGE,
+            ServiceError::BadGateway(_) => BAD_GATEWAY,
+            ServiceError::GatewayTimeout(_) => GATEWAY_TIMEOUT,
+            ServiceError::ServiceUnavailable(_) => SERVICE_UNAVAILABLE,
         }
     }
 
     pub fn get_status_code(&self) -> u16 {
         match self {
             ServiceError::InternalServerError(_) => INTERNAL_SERVER_ERROR_STATUS_CODE,
-            ServiceError::BadRequest(_) => BAD_REQUEST_STATUS_CODE,
+            ServiceError::BadRequest(_) | ServiceError::Validation(_) => BAD_REQUEST_STATUS_CODE,
             ServiceError::Unauthorized(_) => UNAUTHORIZED_STATUS_CODE,
             ServiceError::NotFound(_) => NOT_FOUND_STATUS_CODE,
             ServiceError::Forbidden(_) => FORBIDDEN_STATUS_CODE,
-            ServiceError::Conflict(_) => CONFLICT_STATUS_CODE,
+            ServiceError::Conflict(..) => CONFLICT_STATUS_CODE,
+            ServiceError::PayloadTooLarge(_) => PAYLOAD_TOO_LARGE_STATUS_CODE,
+            ServiceError::BadGateway(_) => BAD_GATEWAY_STATUS_CODE,
+            ServiceError::GatewayTimeout(_) => GATEWAY_TIMEOUT_STATUS_CODE,
+            ServiceError::ServiceUnavailable(_) => SERVICE_UNAVAILABLE_STATUS_CODE,
         }
     }
 
@@ -96,6 +162,12 @@ impl ServiceError {
         error
     }
 
+    pub fn validation(errors: Vec<FieldError>) -> Self {
+        let error = Self::Validation(FieldErrors(errors));
+        tracing::error!(BAD_REQUEST, message = %error);
+        error
+    }
+
     pub fn unauthorized<T: std::fmt::Display + std::fmt::Debug>(
         message: &str,
         cause: Option<T>,
@@ -145,7 +217,7 @@ impl ServiceError {
         message: &str,
         cause: Option<T>,
     ) -> Self {
-        let error = Self::Conflict(message.to_string());
+        let error = Self::Conflict(message.to_string(), None);
 
         if let Some(cause) = cause {
             tracing::error!(CONFLICT, %message, %cause);
@@ -155,10 +227,93 @@ impl ServiceError {
 
         error
     }
+
+    /// A conflict the client can act on, `details` never holds more than it needs to.
+    pub fn conflict_with_details(message: &str, details: Value) -> Self {
+        tracing::error!(CONFLICT, %message, %details);
+        Self::Conflict(message.to_string(), Some(details))
+    }
+
+    pub fn payload_too_large<T: std::fmt::Display + std::fmt::Debug>(
+        message: &str,
+        cause: Option<T>,
+    ) -> Self {
+        let error = Self::PayloadTooLarge(message.to_string());
+
+        if let Some(cause) = cause {
+            tracing::error!(PAYLOAD_TOO_LARGE, %message, %cause);
+        } else {
+            tracing::error!(PAYLOAD_TOO_LARGE, %message);
+        }
+
+        error
+    }
+
+    pub fn bad_gateway<T: std::fmt::Display + std::fmt::Debug>(
+        message: &str,
+        cause: Option<T>,
+    ) -> Self {
+        let error = Self::BadGateway(message.to_string());
+
+        if let Some(cause) = cause {
+            tracing::error!(BAD_GATEWAY, %message, %cause);
+        } else {
+            tracing::error!(BAD_GATEWAY, %message);
+        }
+
+        error
+    }
+
+    pub fn gateway_timeout<T: std::fmt::Display + std::fmt::Debug>(
+        message: &str,
+        cause: Option<T>,
+    ) -> Self {
+        let error = Self::GatewayTimeout(message.to_string());
+
+        if let Some(cause) = cause {
+            tracing::error!(GATEWAY_TIMEOUT, %message, %cause);
+        } else {
+            tracing::error!(GATEWAY_TIMEOUT, %message);
+        }
+
+        error
+    }
+
+    pub fn service_unavailable<T: std::fmt::Display + std::fmt::Debug>(
+        message: &str,
+        cause: Option<T>,
+    ) -> Self {
+        let error = Self::ServiceUnavailable(message.to_string());
+
+        if let Some(cause) = cause {
+            tracing::error!(SERVICE_UNAVAILABLE, %message, %cause);
+        } else {
+            tracing::error!(SERVICE_UNAVAILABLE, %message);
+        }
+
+        error
+    }
+}
+
+/// Named after the column of the violated index, the constraint stays in the logs.
+fn unique_violation_message(constraint: &str) -> &'static str {
+    if constraint.contains("email") {
+        "Email already in use"
+    } else if constraint.contains("username") {
+        "Username already taken"
+    } else {
+        "Already exists"
+    }
 }
 
 impl From<DbErr> for ServiceError {
     fn from(value: DbErr) -> Self {
+        // A write that raced the application checks, or skipped them
+        if let Some(SqlErr::UniqueConstraintViolation(message)) = value.sql_err() {
+            tracing::warn!("Database unique constraint violation: {}", message);
+            return Self::Conflict(unique_violation_message(&message).to_string(), None);
+        }
+
         match value {
             DbErr::AttrNotSet(err) => {
                 tracing::error!("Database attribute not set error: {}", err);
@@ -225,10 +380,15 @@ impl From<DbErr> for ServiceError {
 pub enum GraphQLError {
     InternalServerError(String),
     BadRequest(String),
+    Validation(Vec<FieldError>),
     Unauthorized(String),
     NotFound(String),
     Forbidden(String),
-    Conflict(String),
+    Conflict(String, Option<Value>),
+    PayloadTooLarge(String),
+    BadGateway(String),
+    GatewayTimeout(String),
+    ServiceUnavailable(String),
 }
 
 impl From<ServiceError> for GraphQLError {
@@ -238,43 +398,123 @@ impl From<ServiceError> for GraphQLError {
                 GraphQLError::InternalServerError(message)
             }
             ServiceError::BadRequest(message) => GraphQLError::BadRequest(message),
+            ServiceError::Validation(errors) => GraphQLError::Validation(errors.0),
             ServiceError::Unauthorized(message) => GraphQLError::Unauthorized(message),
             ServiceError::NotFound(message) => GraphQLError::NotFound(message),
             ServiceError::Forbidden(message) => GraphQLError::Forbidden(message),
-            ServiceError::Conflict(message) => GraphQLError::Conflict(message),
+            ServiceError::Conflict(message, details) => GraphQLError::Conflict(message, details),
+            ServiceError::PayloadTooLarge(message) => GraphQLError::PayloadTooLarge(message),
+            ServiceError::BadGateway(message) => GraphQLError::BadGateway(message),
+            ServiceError::GatewayTimeout(message) => GraphQLError::GatewayTimeout(message),
+            ServiceError::ServiceUnavailable(message) => GraphQLError::ServiceUnavailable(message),
         }
     }
 }
 
+/// JSON body of every REST error.
+#[derive(Serialize)]
+pub struct ErrorBody<'a> {
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl<'a> ErrorBody<'a> {
+    fn new(message: &'a str) -> Self {
+        Self {
+            message,
+            details: None,
+            request_id: RequestId::current(),
+        }
+    }
+
+    fn with_details(mut self, details: Option<&'a Value>) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+impl ApiSchema for ErrorBody<'_> {
+    const NAME: &'static str = "ErrorBody";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["message"],
+            "properties": {
+                "message": { "type": "string" },
+                "details": {
+                    "type": "object",
+                    "description": "Machine readable specifics of some errors, e.g. a `reason`",
+                },
+                "request_id": {
+                    "type": "string",
+                    "description": "Same id as the `X-Request-Id` response header",
+                },
+            },
+        })
+    }
+}
+
 impl error::ResponseError for ServiceError {
     fn status_code(&self) -> StatusCode {
         match *self {
             ServiceError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::BadRequest(_) | ServiceError::Validation(_) => StatusCode::BAD_REQUEST,
             ServiceError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
             ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
             ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
-            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::Conflict(..) => StatusCode::CONFLICT,
+            ServiceError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ServiceError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ServiceError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
         match *self {
             ServiceError::InternalServerError(ref message) => {
-                HttpResponse::InternalServerError().json(message)
+                HttpResponse::InternalServerError().json(ErrorBody::new(message))
+            }
+            ServiceError::BadRequest(ref message) => {
+                HttpResponse::BadRequest().json(ErrorBody::new(message))
+            }
+            ServiceError::Validation(ref errors) => {
+                HttpResponse::BadRequest().json(ErrorBody::new(&errors.to_string()))
+            }
+            ServiceError::Unauthorized(ref message) => {
+                HttpResponse::Unauthorized().json(ErrorBody::new(message))
+            }
+            ServiceError::NotFound(ref message) => {
+                HttpResponse::NotFound().json(ErrorBody::new(message))
+            }
+            ServiceError::Forbidden(ref message) => {
+                HttpResponse::Forbidden().json(ErrorBody::new(message))
+            }
+            ServiceError::Conflict(ref message, ref details) => HttpResponse::Conflict()
+                .json(ErrorBody::new(message).with_details(details.as_ref())),
+            ServiceError::PayloadTooLarge(ref message) => {
+                HttpResponse::PayloadTooLarge().json(ErrorBody::new(message))
+            }
+            ServiceError::BadGateway(ref message) => {
+                HttpResponse::BadGateway().json(ErrorBody::new(message))
+            }
+            ServiceError::GatewayTimeout(ref message) => {
+                HttpResponse::GatewayTimeout().json(ErrorBody::new(message))
+            }
+            ServiceError::ServiceUnavailable(ref message) => {
+                HttpResponse::ServiceUnavailable().json(ErrorBody::new(message))
             }
-            ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
-            ServiceError::Unauthorized(ref message) => HttpResponse::Unauthorized().json(message),
-            ServiceError::NotFound(ref message) => HttpResponse::NotFound().json(message),
-            ServiceError::Forbidden(ref message) => HttpResponse::Forbidden().json(message),
-            ServiceError::Conflict(ref message) => HttpResponse::Conflict().json(message),
         }
     }
 }
 
-impl Into<Error> for GraphQLError {
-    fn into(self) -> Error {
-        match self {
+impl From<GraphQLError> for Error {
+    fn from(val: GraphQLError) -> Self {
+        let error: Error = match val {
             GraphQLError::InternalServerError(message) => {
                 Error::new(message).extend_with(|_, e| {
                     e.set("type", "Internal Server Error");
@@ -285,6 +525,22 @@ impl Into<Error> for GraphQLError {
                 e.set("type", "Bad Request");
                 e.set("code", "400");
             }),
+            GraphQLError::Validation(errors) => {
+                let message = errors
+                    .first()
+                    .map(|error| error.message.clone())
+                    .unwrap_or_else(|| BAD_REQUEST.to_string());
+                let field = errors.first().map(|error| error.field.clone());
+                let errors = to_value(&errors).unwrap_or_default();
+                Error::new(message).extend_with(|_, e| {
+                    e.set("type", "Bad Request");
+                    e.set("code", "400");
+                    if let Some(field) = field {
+                        e.set("field", field);
+                    }
+                    e.set(VALIDATION_ERRORS_EXTENSION, errors);
+                })
+            }
             GraphQLError::Unauthorized(message) => Error::new(message).extend_with(|_, e| {
                 e.set("type", "Unauthorized");
                 e.set("code", "401");
@@ -297,10 +553,63 @@ impl Into<Error> for GraphQLError {
                 e.set("type", "Forbidden");
                 e.set("code", "403");
             }),
-            GraphQLError::Conflict(message) => Error::new(message).extend_with(|_, e| {
-                e.set("type", "Conflict");
-                e.set("code", "409");
+            GraphQLError::Conflict(message, details) => {
+                let details = details.and_then(|details| to_value(details).ok());
+                Error::new(message).extend_with(|_, e| {
+                    e.set("type", "Conflict");
+                    e.set("code", "409");
+                    if let Some(details) = details {
+                        e.set("details", details);
+                    }
+                })
+            }
+            GraphQLError::PayloadTooLarge(message) => Error::new(message).extend_with(|_, e| {
+                e.set("type", "Payload Too Large");
+                e.set("code", "413");
+            }),
+            GraphQLError::BadGateway(message) => Error::new(message).extend_with(|_, e| {
+                e.set("type", "Bad Gateway");
+                e.set("code", "502");
             }),
+            GraphQLError::GatewayTimeout(message) => Error::new(message).extend_with(|_, e| {
+                e.set("type", "Gateway Timeout");
+                e.set("code", "504");
+            }),
+            GraphQLError::ServiceUnavailable(message) => Error::new(message).extend_with(|_, e| {
+                e.set("type", "Service Unavailable");
+                e.set("code", "503");
+            }),
+        };
+
+        match RequestId::current() {
+            Some(request_id) => error.extend_with(|_, e| e.set("requestId", request_id)),
+            None => error,
         }
     }
 }
+
+/// Stands in for `From<ServiceError> for Error`, which the blanket `From<Display>`
+/// implementation of async-graphql rules out.
+impl ErrorExtensions for ServiceError {
+    fn extend(&self) -> Error {
+        GraphQLError::from(self.clone()).into()
+    }
+}
+
+/// Lets custom input validators keep the type, code and field errors.
+impl<T: InputType> From<GraphQLError> for InputValueError<T> {
+    fn from(val: GraphQLError) -> Self {
+        let error = Error::from(val);
+        let mut input_error = InputValueError::custom(&error.message);
+
+        if let Some(extensions) = error.extensions {
+            for name in ["type", "code", "field", VALIDATION_ERRORS_EXTENSION] {
+                if let Some(value) = extensions.get(name) {
+                    input_error = input_error.with_extension(name, value.clone());
+                }
+            }
+        }
+
+        input_error
+    }
+}
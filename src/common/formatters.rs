@@ -4,11 +4,30 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::sync::OnceLock;
+
 use super::error_handling::ServiceError;
 use super::regexes::{multi_spaces_regex, new_line_regex};
 use slug::slugify;
 use uuid::Uuid;
 
+pub const DEFAULT_EMAIL_ALIAS_DOMAINS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+static ALIAS_DOMAINS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Sets the domains from `Config::email_alias_domains` once, at startup before any
+/// email is normalized. Without it the defaults apply.
+pub fn set_email_alias_domains(domains: &[String]) {
+    if ALIAS_DOMAINS.set(domains.to_vec()).is_err() {
+        tracing::debug!("Email alias domains are already set");
+    }
+}
+
+/// Domains that ignore dots and `+tag` suffixes in the local part.
+fn get_alias_domains() -> &'static [String] {
+    ALIAS_DOMAINS.get_or_init(|| DEFAULT_EMAIL_ALIAS_DOMAINS.map(str::to_string).to_vec())
+}
+
 pub fn format_name(name: &str) -> Result<String, ServiceError> {
     let mut title = name.trim().to_lowercase();
     title = new_line_regex()?.replace_all(&title, " ").to_string();
@@ -45,3 +64,43 @@ pub fn format_point_slug(value: &str) -> String {
 
     slug.replace("-", ".")
 }
+
+pub fn format_csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+
+    value.to_string()
+}
+
+/// Key shared by every address that reaches the same inbox, the address itself is
+/// still the one emails are sent to and displayed.
+pub fn normalize_email(email: &str) -> String {
+    let email = email.trim().to_lowercase();
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return email;
+    };
+
+    if !get_alias_domains().iter().any(|alias| alias == domain) {
+        return email;
+    }
+
+    let local = local.split('+').next().unwrap_or(local).replace('.', "");
+    format!("{}@{}", local, domain)
+}
+
+/// Hint of an address safe to show to someone who may not own it, `j***@g***.com`.
+pub fn mask_email(email: &str) -> String {
+    let mask = |value: &str| match value.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => String::new(),
+    };
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return mask(email);
+    };
+
+    match domain.rsplit_once('.') {
+        Some((name, tld)) => format!("{}@{}.{}", mask(local), mask(name), tld),
+        None => format!("{}@{}", mask(local), mask(domain)),
+    }
+}
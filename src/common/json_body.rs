@@ -0,0 +1,74 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{future::Future, pin::Pin};
+
+use actix_web::{dev::Payload, error::JsonPayloadError, web, Error, FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::ServiceError;
+
+pub fn body_too_large(limit: usize) -> ServiceError {
+    ServiceError::payload_too_large::<ServiceError>(
+        &format!("Request body is larger than {} bytes", limit),
+        None,
+    )
+}
+
+/// Renders the `web::JsonConfig` errors with the same JSON shape as `ServiceError`.
+pub fn json_error_handler(error: JsonPayloadError, _req: &HttpRequest) -> Error {
+    match error {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => body_too_large(limit),
+        JsonPayloadError::ContentType => {
+            ServiceError::bad_request("Content type must be application/json", Some(error))
+        }
+        JsonPayloadError::Deserialize(ref e) if e.is_data() => {
+            ServiceError::bad_request(&format!("Invalid request body: {}", e), Some(error))
+        }
+        JsonPayloadError::Deserialize(_) => {
+            ServiceError::bad_request("Request body is not valid JSON", Some(error))
+        }
+        _ => ServiceError::bad_request("Failed to read the request body", Some(error)),
+    }
+    .into()
+}
+
+/// JSON body extractor that names the offending field when a value has the wrong type.
+/// The body is read through `web::Json`, so the `web::JsonConfig` limit still applies.
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(value: Value) -> Result<T, ServiceError> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let message = match e.path().to_string().as_str() {
+            "." => format!("Invalid request body: {}", e.inner()),
+            path => format!("Invalid value for field {}: {}", path, e.inner()),
+        };
+        ServiceError::bad_request(&message, Some(e))
+    })
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonBody<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let value = web::Json::<Value>::from_request(req, payload);
+
+        Box::pin(async move {
+            let value = value.await?.into_inner();
+            Ok(Self(deserialize(value)?))
+        })
+    }
+}
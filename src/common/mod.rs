@@ -5,13 +5,26 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use auth_tokens::*;
+pub use client_info::*;
+pub use csv_reader::*;
 pub use error_handling::*;
 pub use formatters::*;
+pub use json_body::*;
+pub use openapi::*;
+pub use request_id::*;
 // pub use regexes::*;
 pub use validators::*;
 
 pub mod auth_tokens;
+pub mod client_info;
+pub mod csv_reader;
 pub mod error_handling;
 pub mod formatters;
+pub mod json_body;
+pub mod openapi;
 pub mod regexes;
+pub mod request_id;
 pub mod validators;
+
+#[cfg(test)]
+mod tests;
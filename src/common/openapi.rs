@@ -0,0 +1,322 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde_json::{json, Map, Value};
+
+pub const OPENAPI_VERSION: &str = "3.0.3";
+pub const BEARER_AUTH: &str = "bearerAuth";
+pub const REFRESH_COOKIE: &str = "refreshCookie";
+
+type SchemaFn = fn() -> Value;
+
+/// JSON schema of a REST body, registered under `NAME` in the document components.
+pub trait ApiSchema {
+    const NAME: &'static str;
+
+    fn schema() -> Value;
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn error_description(status: u16) -> &'static str {
+    match status {
+        400 => "Invalid body or request",
+        401 => "Missing, invalid or expired credentials",
+        403 => "The account is not allowed to do this",
+        404 => "Not found",
+        409 => "Conflicts with an existing resource",
+        413 => "Body over the size limit",
+        503 => "Maintenance mode, with a `Retry-After` header when it has an end",
+        _ => "Unexpected error",
+    }
+}
+
+struct ApiResponse {
+    status: u16,
+    description: &'static str,
+    schemas: Vec<&'static str>,
+    redirect: bool,
+}
+
+/// A documented route. Schemas of the bodies are kept with it so the document only
+/// lists the ones in use.
+pub struct ApiOperation {
+    method: &'static str,
+    path: String,
+    summary: &'static str,
+    tag: &'static str,
+    body: Option<(&'static str, bool)>,
+    security: Vec<&'static str>,
+    optional_security: bool,
+    refresh_cookie: bool,
+    responses: Vec<ApiResponse>,
+    schemas: Vec<(&'static str, SchemaFn)>,
+}
+
+impl ApiOperation {
+    fn new(method: &'static str, path: &str, summary: &'static str) -> Self {
+        Self {
+            method,
+            path: path.to_string(),
+            summary,
+            tag: "default",
+            body: None,
+            security: Vec::new(),
+            optional_security: false,
+            refresh_cookie: false,
+            responses: Vec::new(),
+            schemas: Vec::new(),
+        }
+    }
+
+    pub fn get(path: &str, summary: &'static str) -> Self {
+        Self::new("get", path, summary)
+    }
+
+    pub fn post(path: &str, summary: &'static str) -> Self {
+        Self::new("post", path, summary)
+    }
+
+    pub fn tag(mut self, tag: &'static str) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    fn with_schema<T: ApiSchema>(mut self) -> Self {
+        if !self.schemas.iter().any(|(name, _)| *name == T::NAME) {
+            self.schemas.push((T::NAME, T::schema));
+        }
+
+        self
+    }
+
+    pub fn body<T: ApiSchema>(mut self) -> Self {
+        self.body = Some((T::NAME, true));
+        self.with_schema::<T>()
+    }
+
+    /// A body that may be left out, e.g. when the token comes from the refresh cookie.
+    pub fn optional_body<T: ApiSchema>(mut self) -> Self {
+        self.body = Some((T::NAME, false));
+        self.with_schema::<T>()
+    }
+
+    pub fn security(mut self, scheme: &'static str) -> Self {
+        self.security.push(scheme);
+        self
+    }
+
+    /// Credentials from `security` are used when sent, anonymous calls are also valid.
+    pub fn optional_security(mut self) -> Self {
+        self.optional_security = true;
+        self
+    }
+
+    /// Successful responses set, or clear, the HTTP only refresh token cookie.
+    pub fn refresh_cookie(mut self) -> Self {
+        self.refresh_cookie = true;
+        self
+    }
+
+    pub fn response<T: ApiSchema>(mut self, status: u16, description: &'static str) -> Self {
+        self.responses.push(ApiResponse {
+            status,
+            description,
+            schemas: vec![T::NAME],
+            redirect: false,
+        });
+        self.with_schema::<T>()
+    }
+
+    /// A status answered with either of two bodies.
+    pub fn response_one_of<T: ApiSchema, U: ApiSchema>(
+        mut self,
+        status: u16,
+        description: &'static str,
+    ) -> Self {
+        self.responses.push(ApiResponse {
+            status,
+            description,
+            schemas: vec![T::NAME, U::NAME],
+            redirect: false,
+        });
+        self.with_schema::<T>().with_schema::<U>()
+    }
+
+    pub fn empty_response(mut self, status: u16, description: &'static str) -> Self {
+        self.responses.push(ApiResponse {
+            status,
+            description,
+            schemas: Vec::new(),
+            redirect: false,
+        });
+        self
+    }
+
+    pub fn redirect(mut self, status: u16, description: &'static str) -> Self {
+        self.responses.push(ApiResponse {
+            status,
+            description,
+            schemas: Vec::new(),
+            redirect: true,
+        });
+        self
+    }
+
+    /// Error statuses answered with the `ServiceError` body.
+    pub fn errors<T: ApiSchema>(mut self, statuses: &[u16]) -> Self {
+        for status in statuses {
+            self.responses.push(ApiResponse {
+                status: *status,
+                description: error_description(*status),
+                schemas: vec![T::NAME],
+                redirect: false,
+            });
+        }
+
+        self.with_schema::<T>()
+    }
+
+    fn response_json(&self, response: &ApiResponse) -> Value {
+        let mut value = json!({ "description": response.description });
+        let mut headers = Map::new();
+
+        let schema = match response.schemas.as_slice() {
+            [] => None,
+            [schema] => Some(schema_ref(schema)),
+            schemas => Some(json!({
+                "oneOf": schemas.iter().map(|schema| schema_ref(schema)).collect::<Vec<Value>>(),
+            })),
+        };
+        if let Some(schema) = schema {
+            value["content"] = json!({ "application/json": { "schema": schema } });
+        }
+        if response.redirect {
+            headers.insert(
+                "Location".to_string(),
+                json!({ "schema": { "type": "string", "format": "uri" } }),
+            );
+        }
+        if self.refresh_cookie && (200..400).contains(&response.status) {
+            headers.insert(
+                "Set-Cookie".to_string(),
+                json!({
+                    "description": "HTTP only refresh token cookie scoped to `/api/auth`",
+                    "schema": { "type": "string" },
+                }),
+            );
+        }
+        if !headers.is_empty() {
+            value["headers"] = Value::Object(headers);
+        }
+
+        value
+    }
+
+    fn to_json(&self) -> Value {
+        let mut operation = json!({
+            "summary": self.summary,
+            "tags": [self.tag],
+            "responses": self
+                .responses
+                .iter()
+                .map(|response| (response.status.to_string(), self.response_json(response)))
+                .collect::<Map<String, Value>>(),
+        });
+
+        if let Some((schema, required)) = self.body {
+            operation["requestBody"] = json!({
+                "required": required,
+                "content": { "application/json": { "schema": schema_ref(schema) } },
+            });
+        }
+        if !self.security.is_empty() {
+            let mut security = self
+                .security
+                .iter()
+                .map(|scheme| json!({ *scheme: [] }))
+                .collect::<Vec<Value>>();
+
+            if self.optional_security {
+                security.push(json!({}));
+            }
+
+            operation["security"] = Value::Array(security);
+        }
+
+        operation
+    }
+}
+
+/// OpenAPI 3 document of the REST endpoints, built from the operations each controller
+/// describes next to its router.
+pub struct OpenApi {
+    title: String,
+    version: String,
+    description: Option<String>,
+    security_schemes: Map<String, Value>,
+    operations: Vec<ApiOperation>,
+}
+
+impl OpenApi {
+    pub fn new(title: &str, version: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            version: version.to_string(),
+            description: None,
+            security_schemes: Map::new(),
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn security_scheme(mut self, name: &str, scheme: Value) -> Self {
+        self.security_schemes.insert(name.to_string(), scheme);
+        self
+    }
+
+    pub fn operations(mut self, operations: Vec<ApiOperation>) -> Self {
+        self.operations.extend(operations);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut paths = Map::new();
+        let mut schemas = Map::new();
+
+        for operation in &self.operations {
+            let path = paths
+                .entry(operation.path.clone())
+                .or_insert_with(|| json!({}));
+            path[operation.method] = operation.to_json();
+
+            for (name, schema) in &operation.schemas {
+                schemas.entry(name.to_string()).or_insert_with(*schema);
+            }
+        }
+
+        let mut info = json!({ "title": self.title, "version": self.version });
+        if let Some(description) = &self.description {
+            info["description"] = json!(description);
+        }
+
+        json!({
+            "openapi": OPENAPI_VERSION,
+            "info": info,
+            "paths": paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": self.security_schemes,
+            },
+        })
+    }
+}
@@ -31,6 +31,16 @@ pub fn name_regex() -> Result<Regex, ServiceError> {
     }
 }
 
+pub fn point_slug_regex() -> Result<Regex, ServiceError> {
+    match Regex::new(r"^[a-z0-9]+(\.[a-z0-9]+)*$") {
+        Ok(value) => Ok(value),
+        Err(e) => Err(ServiceError::internal_server_error(
+            INTERNAL_SERVER_ERROR,
+            Some(e),
+        )),
+    }
+}
+
 pub fn jwt_regex() -> Result<Regex, ServiceError> {
     match Regex::new(r"^[A-Za-z0-9-_=]+\.[A-Za-z0-9-_=]+\.?[A-Za-z0-9-_.+/=]*$") {
         Ok(value) => Ok(value),
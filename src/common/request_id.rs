@@ -0,0 +1,61 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::future::Future;
+
+use actix_web::{dev::ServiceRequest, HttpMessage};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: String;
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Reuses the id already assigned to the request, then the client's header,
+    /// and only generates a new one as a last resort.
+    pub fn from_request(request: &ServiceRequest) -> Self {
+        if let Some(request_id) = request.extensions().get::<Self>() {
+            return request_id.clone();
+        }
+
+        let request_id = Self(
+            request
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| Self::is_valid(value))
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        );
+        request.extensions_mut().insert(request_id.clone());
+        request_id
+    }
+
+    // Client ids end up in logs and headers, so keep them short and printable
+    fn is_valid(value: &str) -> bool {
+        !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LENGTH
+            && value.chars().all(|c| c.is_ascii_graphic())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_REQUEST_ID.scope(self.0, future).await
+    }
+
+    pub fn current() -> Option<String> {
+        CURRENT_REQUEST_ID.try_with(|id| id.clone()).ok()
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_web::{http::header::ContentType, test::TestRequest, web, FromRequest};
+use serde::Deserialize;
+
+use super::{
+    canonical_locale, json_error_handler, mask_email, normalize_email, validate_locale,
+    validate_timezone, CsvReader, CsvRecord, JsonBody, ValidatorEnum,
+};
+
+#[test]
+fn test_validate_locale() {
+    for locale in ["en", "pt", "pt-BR", "pt-br", "EN-us"] {
+        assert!(
+            matches!(validate_locale(locale), ValidatorEnum::Valid),
+            "{}",
+            locale
+        );
+    }
+
+    for locale in [
+        "",
+        "english",
+        "fr",
+        "pt_BR",
+        "en-",
+        "en-US-x-private",
+        "../en",
+    ] {
+        assert!(
+            matches!(validate_locale(locale), ValidatorEnum::Invalid(_)),
+            "{}",
+            locale
+        );
+    }
+
+    assert_eq!(canonical_locale("pt-br"), Some("pt-BR"));
+    assert_eq!(canonical_locale("fr-FR"), None);
+}
+
+#[test]
+fn test_validate_timezone() {
+    for timezone in ["UTC", "Europe/Lisbon", "America/Sao_Paulo", "Asia/Kolkata"] {
+        assert!(
+            matches!(validate_timezone(timezone), ValidatorEnum::Valid),
+            "{}",
+            timezone
+        );
+    }
+
+    for timezone in ["", "Mars/Olympus_Mons", "europe/lisbon", "GMT+25", "Lisbon"] {
+        assert!(
+            matches!(validate_timezone(timezone), ValidatorEnum::Invalid(_)),
+            "{}",
+            timezone
+        );
+    }
+}
+
+#[test]
+fn test_normalize_email() {
+    for (email, normalized) in [
+        (" John.Doe+shop@Gmail.com ", "johndoe@gmail.com"),
+        ("j.o.h.n.doe@googlemail.com", "johndoe@googlemail.com"),
+        ("johndoe+a+b@gmail.com", "johndoe@gmail.com"),
+        // Other providers may treat dots and tags as part of the address
+        ("John.Doe+shop@example.com", "john.doe+shop@example.com"),
+        ("not_an_email", "not_an_email"),
+    ] {
+        assert_eq!(normalize_email(email), normalized, "{}", email);
+    }
+}
+
+#[test]
+fn test_mask_email() {
+    for (email, masked) in [
+        ("johndoe@gmail.com", "j***@g***.com"),
+        ("j@mail.example.co", "j***@m***.co"),
+        ("john@localhost", "j***@l***"),
+        ("not_an_email", "n***"),
+    ] {
+        assert_eq!(mask_email(email), masked, "{}", email);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonBodyTest {
+    email: String,
+}
+
+#[actix_web::test]
+async fn test_json_body() {
+    let extract = |payload: &'static str, limit: usize| async move {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header(ContentType::json())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(limit)
+                    .error_handler(json_error_handler),
+            )
+            .set_payload(payload)
+            .to_http_parts();
+        JsonBody::<JsonBodyTest>::from_request(&req, &mut payload)
+            .await
+            .map_err(|e| {
+                let response = e.error_response();
+                (response.status().as_u16(), e.to_string())
+            })
+    };
+
+    let body = extract(r#"{"email": "valid@gmail.com"}"#, 64)
+        .await
+        .unwrap();
+    assert_eq!(body.into_inner().email, "valid@gmail.com");
+
+    let (status, message) = extract(r#"{"email": "valid@gmail.com"}"#, 16)
+        .await
+        .unwrap_err();
+    assert_eq!(status, 413);
+    assert_eq!(message, "Request body is larger than 16 bytes");
+
+    let (status, message) = extract(r#"{"email": "#, 64).await.unwrap_err();
+    assert_eq!(status, 400);
+    assert_eq!(message, "Request body is not valid JSON");
+
+    let (status, message) = extract(r#"{"email": 42}"#, 64).await.unwrap_err();
+    assert_eq!(status, 400);
+    assert!(
+        message.starts_with("Invalid value for field email:"),
+        "{}",
+        message
+    );
+
+    let (status, message) = extract("{}", 64).await.unwrap_err();
+    assert_eq!(status, 400);
+    assert_eq!(message, "Invalid request body: missing field `email`");
+}
+
+#[test]
+fn test_csv_reader_across_chunks() {
+    let csv = "email,first_name\r\n\"john@example.com\",\"Smith, \"\"John\"\"\"\r\n\n\"a\nb\",c\nlast,row";
+    let mut reader = CsvReader::default();
+    let mut records = Vec::new();
+
+    // One byte at a time, so every record is cut by a chunk
+    for byte in csv.as_bytes() {
+        records.extend(reader.push(&[*byte]));
+    }
+    records.extend(reader.finish());
+
+    let record = |line: usize, fields: &[&str]| CsvRecord {
+        line,
+        fields: fields.iter().map(|field| field.to_string()).collect(),
+    };
+    assert_eq!(
+        records,
+        vec![
+            record(1, &["email", "first_name"]),
+            record(2, &["john@example.com", "Smith, \"John\""]),
+            record(4, &["a\nb", "c"]),
+            record(6, &["last", "row"]),
+        ]
+    );
+}
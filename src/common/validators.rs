@@ -4,16 +4,29 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::sync::OnceLock;
+
 use anyhow::Error;
-use chrono::NaiveDate;
+use chrono::{NaiveDate, Utc};
+use chrono_tz::TZ_VARIANTS;
 use unicode_segmentation::UnicodeSegmentation;
 
 use super::{
-    error_handling::ServiceError,
-    regexes::{email_regex, jwt_regex, name_regex},
+    error_handling::{FieldError, ServiceError},
+    regexes::{email_regex, jwt_regex, name_regex, point_slug_regex},
     INTERNAL_SERVER_ERROR,
 };
 
+pub const DEFAULT_LOCALE: &str = "en";
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+pub const DEFAULT_MINIMUM_AGE: u32 = 13;
+const MAXIMUM_AGE: u32 = 130;
+
+static MINIMUM_AGE: OnceLock<u32> = OnceLock::new();
+
+/// BCP-47 tags users can pick, templates fall back to the primary language subtag.
+pub const SUPPORTED_LOCALES: [&str; 6] = ["en", "en-GB", "en-US", "pt", "pt-BR", "pt-PT"];
+
 #[derive(Default)]
 struct PasswordValidity {
     has_lowercase: bool,
@@ -76,7 +89,7 @@ pub fn password_characters_validation(password: &str) -> ValidatorEnum {
 pub fn validate_password(password: &str) -> ValidatorEnum {
     let len = password.graphemes(true).count();
 
-    if len < 8 || len > 40 {
+    if !(8..=40).contains(&len) {
         return ValidatorEnum::Invalid(
             "Password needs to be between 8 and 40 characters.".to_string(),
         );
@@ -88,7 +101,7 @@ pub fn validate_password(password: &str) -> ValidatorEnum {
 pub fn validate_email(email: &str) -> Result<ValidatorEnum, ServiceError> {
     let len = email.graphemes(true).count();
 
-    if len < 5 || len > 200 {
+    if !(5..=200).contains(&len) {
         return Ok(ValidatorEnum::Invalid(
             "Email needs to be between 5 and 200 characters".to_string(),
         ));
@@ -103,7 +116,7 @@ pub fn validate_email(email: &str) -> Result<ValidatorEnum, ServiceError> {
 pub fn validate_name(name: &str, value: &str) -> Result<ValidatorEnum, ServiceError> {
     let len = value.graphemes(true).count();
 
-    if len < 3 || len > 50 {
+    if !(3..=50).contains(&len) {
         return Ok(ValidatorEnum::Invalid(format!(
             "{} needs to be between 3 and 50 characters.",
             name
@@ -116,6 +129,23 @@ pub fn validate_name(name: &str, value: &str) -> Result<ValidatorEnum, ServiceEr
     Ok(ValidatorEnum::Valid)
 }
 
+pub fn validate_username(username: &str) -> Result<ValidatorEnum, ServiceError> {
+    let len = username.graphemes(true).count();
+
+    if !(3..=109).contains(&len) {
+        return Ok(ValidatorEnum::Invalid(
+            "Username needs to be between 3 and 109 characters.".to_string(),
+        ));
+    }
+    if !point_slug_regex()?.is_match(username) {
+        return Ok(ValidatorEnum::Invalid(
+            "Username can only contain lowercase letters, numbers and single dots.".to_string(),
+        ));
+    }
+
+    Ok(ValidatorEnum::Valid)
+}
+
 pub fn validate_date(date: &str) -> ValidatorEnum {
     let len = date.graphemes(true).count();
 
@@ -129,6 +159,40 @@ pub fn validate_date(date: &str) -> ValidatorEnum {
     }
 }
 
+/// Sets the age from `Config::minimum_age` once, at startup before any date of birth
+/// is validated. Without it the default applies.
+pub fn set_minimum_age(age: u32) {
+    if MINIMUM_AGE.set(age).is_err() {
+        tracing::debug!("Minimum age is already set");
+    }
+}
+
+pub fn get_minimum_age() -> u32 {
+    *MINIMUM_AGE.get_or_init(|| DEFAULT_MINIMUM_AGE)
+}
+
+/// A `YYYY-MM-DD` date in the past, at most 130 years and at least the minimum age ago.
+pub fn validate_date_of_birth(date: &str) -> ValidatorEnum {
+    let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") else {
+        return validate_date(date);
+    };
+    let today = Utc::now().date_naive();
+
+    if date >= today {
+        return ValidatorEnum::Invalid("Date of birth needs to be in the past.".to_string());
+    }
+
+    let min = get_minimum_age();
+    match today.years_since(date) {
+        Some(age) if age > MAXIMUM_AGE => ValidatorEnum::Invalid(format!(
+            "Date of birth can not be more than {} years ago.",
+            MAXIMUM_AGE
+        )),
+        Some(age) if age >= min => ValidatorEnum::Valid,
+        _ => ValidatorEnum::Invalid(format!("You must be at least {} years old.", min)),
+    }
+}
+
 pub fn validate_passwords(password1: &str, password2: &str) -> ValidatorEnum {
     if password1.is_empty() {
         return ValidatorEnum::Invalid("Password is required".to_string());
@@ -146,7 +210,7 @@ pub fn validate_passwords(password1: &str, password2: &str) -> ValidatorEnum {
 pub fn validate_jwt(name: &str, jwt: &str) -> Result<ValidatorEnum, ServiceError> {
     let len = jwt.chars().count();
 
-    if len < 20 || len > 500 {
+    if !(20..=500).contains(&len) {
         return Ok(ValidatorEnum::Invalid(format!(
             "{} needs to be between 20 and 500 characters.",
             name
@@ -160,6 +224,32 @@ pub fn validate_jwt(name: &str, jwt: &str) -> Result<ValidatorEnum, ServiceError
     Ok(ValidatorEnum::Valid)
 }
 
+/// Case-insensitive lookup of the whitelisted tag, e.g. "pt-br" resolves to "pt-BR".
+pub fn canonical_locale(locale: &str) -> Option<&'static str> {
+    SUPPORTED_LOCALES
+        .iter()
+        .find(|supported| supported.eq_ignore_ascii_case(locale))
+        .copied()
+}
+
+pub fn validate_locale(locale: &str) -> ValidatorEnum {
+    match canonical_locale(locale) {
+        Some(_) => ValidatorEnum::Valid,
+        None => ValidatorEnum::Invalid(format!(
+            "Locale must be one of {}.",
+            SUPPORTED_LOCALES.join(", ")
+        )),
+    }
+}
+
+pub fn validate_timezone(timezone: &str) -> ValidatorEnum {
+    if TZ_VARIANTS.iter().any(|tz| tz.name() == timezone) {
+        ValidatorEnum::Valid
+    } else {
+        ValidatorEnum::Invalid("Timezone must be a valid IANA timezone name.".to_string())
+    }
+}
+
 pub fn validate_not_empty(name: &str, value: &str) -> ValidatorEnum {
     if value.is_empty() {
         return ValidatorEnum::Invalid(format!("{} is required", name));
@@ -188,3 +278,25 @@ pub fn validations_handler(validations: &[ValidatorEnum]) -> Result<(), ServiceE
         .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
     Err(ServiceError::bad_request::<Error>(&errors_json, None))
 }
+
+/// Same as `validations_handler`, but keeps every failure next to the field it came from.
+pub fn field_validations_handler(
+    validations: &[(&str, ValidatorEnum)],
+) -> Result<(), ServiceError> {
+    let errors = validations
+        .iter()
+        .filter_map(|(field, validator)| {
+            if let ValidatorEnum::Invalid(message) = validator {
+                Some(FieldError::new(field, message))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<FieldError>>();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(ServiceError::validation(errors))
+}
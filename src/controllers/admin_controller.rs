@@ -0,0 +1,122 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{
+    http::header::{
+        ContentDisposition, DispositionParam, DispositionType, CONTENT_LENGTH, CONTENT_TYPE,
+    },
+    web, Error, HttpRequest, HttpResponse, Scope,
+};
+use entities::enums::RoleEnum;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::common::{body_too_large, ServiceError};
+use crate::dtos::queries;
+use crate::helpers::AccessUser;
+use crate::providers::{BodyLimitsConfig, Cache, Database, Jwt, Mailer};
+use crate::services::users_service;
+
+const IMPORT_FIELD: &str = "file";
+
+async fn export_users(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    cache: web::Data<Cache>,
+    jwt: web::Data<Jwt>,
+    query: web::Query<queries::Export>,
+) -> Result<HttpResponse, ServiceError> {
+    AccessUser::require_role(
+        jwt.get_ref(),
+        db.get_ref(),
+        cache.get_ref(),
+        &req,
+        RoleEnum::Admin,
+    )
+    .await?;
+    let format = query.into_inner().format;
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, format.content_type()))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format.file_name().to_string())],
+        })
+        .streaming(users_service::export_confirmed(db.get_ref(), format).map_err(Error::from)))
+}
+
+/// Imports the CSV sent in the `file` field of a multipart body, rows are parsed and
+/// inserted while the upload is still being read.
+#[allow(clippy::too_many_arguments)]
+async fn import_users(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    cache: web::Data<Cache>,
+    jwt: web::Data<Jwt>,
+    mailer: web::Data<Mailer>,
+    body_limits: web::Data<BodyLimitsConfig>,
+    query: web::Query<queries::Import>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, ServiceError> {
+    AccessUser::require_role(
+        jwt.get_ref(),
+        db.get_ref(),
+        cache.get_ref(),
+        &req,
+        RoleEnum::Admin,
+    )
+    .await?;
+    let limit = body_limits.import;
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if content_length.is_some_and(|content_length| content_length > limit) {
+        return Err(body_too_large(limit));
+    }
+
+    let invalid_body =
+        |e: MultipartError| ServiceError::bad_request("Invalid multipart body", Some(e));
+
+    while let Some(field) = payload.try_next().await.map_err(invalid_body)? {
+        if field.content_disposition().get_name() != Some(IMPORT_FIELD) {
+            continue;
+        }
+
+        let mut size = 0;
+        let chunks = field.map(move |chunk| {
+            let chunk = chunk.map_err(invalid_body)?;
+            size += chunk.len();
+
+            if size > limit {
+                return Err(body_too_large(limit));
+            }
+
+            Ok(chunk)
+        });
+        let report = users_service::import_users(
+            &db.session(),
+            jwt.get_ref(),
+            mailer.get_ref(),
+            chunks,
+            query.send_reset_emails,
+        )
+        .await?;
+        return Ok(HttpResponse::Ok().json(report));
+    }
+
+    Err(ServiceError::bad_request::<ServiceError>(
+        "Missing the file field with the CSV",
+        None,
+    ))
+}
+
+pub fn admin_router() -> Scope {
+    web::scope("/api/admin")
+        .route("/users/export", web::get().to(export_users))
+        .route("/users/import", web::post().to(import_users))
+}
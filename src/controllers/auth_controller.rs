@@ -5,96 +5,235 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use actix_web::{
-    cookie::{time::Duration, Cookie},
+    cookie::{time::Duration, Cookie, SameSite},
     http::header::LOCATION,
-    web, HttpResponse, Scope,
+    web, HttpRequest, HttpResponse, HttpResponseBuilder, Scope,
 };
 
-use crate::common::{AuthTokens, InternalCause, ServiceError, UNAUTHORIZED};
+use crate::common::{
+    ApiOperation, AuthTokens, ClientInfo, ErrorBody, InternalCause, JsonBody, ServiceError,
+    BEARER_AUTH, CSRF_COOKIE, REFRESH_COOKIE, UNAUTHORIZED,
+};
 use crate::dtos::{bodies, queries, responses};
-use crate::providers::{Cache, Database, ExternalProvider, Jwt, Mailer, OAuth, TokenType};
-use crate::services::auth_service;
+use crate::providers::{
+    BreachChecker, Cache, CaptchaVerifier, Database, DeviceAlerts, Environment, ExternalProvider,
+    Jwt, Lockout, Mailer, OAuth, SignUpMode, TermsVersion, TokenType, Webhooks, OAUTH_STATE_TIME,
+};
+use crate::services::{auth_service, helpers::random_string};
+
+const OAUTH_INVALID_REQUEST: &str = "invalid_request";
+const OAUTH_ERROR_CODE_MAX_LENGTH: usize = 64;
+const AUTH_TAG: &str = "auth";
+const GRAPHQL_PATH: &str = "/api/graphql";
+const CSRF_TOKEN_LENGTH: usize = 32;
+const OAUTH_LINK_COOKIE: &str = "oauth_link";
+const OAUTH_LINK_PATH: &str = "/api/auth/ext";
+
+fn build_refresh_cookie<'a>(
+    jwt: &'a Jwt,
+    environment: &Environment,
+    value: String,
+    max_age: i64,
+) -> Cookie<'a> {
+    let mut cookie = build_cookie(
+        environment,
+        jwt.get_refresh_name(),
+        "/api/auth",
+        value,
+        max_age,
+    );
+    cookie.set_http_only(true);
+    cookie
+}
+
+fn set_refresh_token<'a>(
+    response: &'a mut HttpResponseBuilder,
+    jwt: &Jwt,
+    environment: &Environment,
+    refresh_token: &str,
+) -> &'a mut HttpResponseBuilder {
+    response.cookie(build_refresh_cookie(
+        jwt,
+        environment,
+        refresh_token.to_string(),
+        jwt.get_email_token_time(TokenType::Refresh),
+    ))
+}
+
+fn build_cookie<'a>(
+    environment: &Environment,
+    name: &'a str,
+    path: &'a str,
+    value: String,
+    max_age: i64,
+) -> Cookie<'a> {
+    let mut builder = Cookie::build(name, value)
+        .path(path)
+        .max_age(Duration::seconds(max_age));
+
+    if environment.is_production() {
+        builder = builder.secure(true).same_site(SameSite::Lax);
+    }
+
+    builder.finish()
+}
+
+/// In cookie mode the access token is also set in an HTTP only cookie scoped to the
+/// GraphQL endpoint, with a readable CSRF token that lasts as long as the session.
+fn set_access_cookies<'a>(
+    response: &'a mut HttpResponseBuilder,
+    jwt: &Jwt,
+    environment: &Environment,
+    access_token: &str,
+) -> &'a mut HttpResponseBuilder {
+    if let Some(name) = jwt.get_access_cookie_name() {
+        let mut access_cookie = build_cookie(
+            environment,
+            name,
+            GRAPHQL_PATH,
+            access_token.to_string(),
+            jwt.get_access_token_time(),
+        );
+        access_cookie.set_http_only(true);
+        response.cookie(access_cookie).cookie(build_cookie(
+            environment,
+            CSRF_COOKIE,
+            "/",
+            random_string(CSRF_TOKEN_LENGTH),
+            jwt.get_email_token_time(TokenType::Refresh),
+        ));
+    }
+
+    response
+}
 
 fn save_refresh_token(
-    cookie_name: &str,
-    cookie_expiration: i64,
+    jwt: &Jwt,
+    environment: &Environment,
     auth_response: responses::Auth,
 ) -> HttpResponse {
-    HttpResponse::Ok()
-        .cookie(
-            Cookie::build(cookie_name, &auth_response.refresh_token)
-                .path("/api/auth")
-                .http_only(true)
-                .max_age(Duration::seconds(cookie_expiration))
-                .finish(),
-        )
+    let mut response = HttpResponse::Ok();
+    set_refresh_token(
+        &mut response,
+        jwt,
+        environment,
+        &auth_response.refresh_token,
+    );
+    set_access_cookies(&mut response, jwt, environment, &auth_response.access_token)
         .json(auth_response)
 }
 
-fn remove_refresh_token(cookie_name: &str) -> HttpResponse {
-    let mut cookie = Cookie::build(cookie_name, "")
-        .path("/api/auth")
-        .http_only(true)
-        .max_age(Duration::seconds(0))
-        .finish();
+fn remove_refresh_token(jwt: &Jwt, environment: &Environment) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    let mut cookie = build_refresh_cookie(jwt, environment, String::new(), 0);
     cookie.make_removal();
-    HttpResponse::Ok().cookie(cookie).finish()
+    response.cookie(cookie);
+
+    if let Some(name) = jwt.get_access_cookie_name() {
+        for (name, path) in [(name, GRAPHQL_PATH), (CSRF_COOKIE, "/")] {
+            let mut cookie = build_cookie(environment, name, path, String::new(), 0);
+            cookie.make_removal();
+            response.cookie(cookie);
+        }
+    }
+
+    response.finish()
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn sign_up(
     db: web::Data<Database>,
+    cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
     mailer: web::Data<Mailer>,
-    body: web::Json<bodies::SignUp>,
+    webhooks: web::Data<Webhooks>,
+    sign_up_mode: web::Data<SignUpMode>,
+    terms_version: web::Data<TermsVersion>,
+    captcha: web::Data<CaptchaVerifier>,
+    breach_checker: web::Data<BreachChecker>,
+    body: JsonBody<bodies::SignUp>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
+    let body = body.into_inner().validate()?;
+    captcha
+        .check(body.captcha_token.as_deref(), client_info.ip.as_deref())
+        .await?;
+    breach_checker.check(&body.password1).await?;
     auth_service::sign_up(
-        db.get_ref(),
+        &db.session(),
+        cache.get_ref(),
         jwt.get_ref(),
         mailer.get_ref(),
-        body.into_inner().validate()?,
+        webhooks.get_ref(),
+        *sign_up_mode.get_ref(),
+        terms_version.get_ref(),
+        body,
     )
     .await?;
     Ok(HttpResponse::Ok().json(responses::Message::new("User created successfully")))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn confirm_email(
     db: web::Data<Database>,
+    cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: web::Json<bodies::ConfirmEmail>,
+    environment: web::Data<Environment>,
+    webhooks: web::Data<Webhooks>,
+    device_alerts: web::Data<DeviceAlerts>,
+    body: JsonBody<bodies::ConfirmEmail>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
     let jwt_ref = jwt.get_ref();
     Ok(save_refresh_token(
-        jwt_ref.get_refresh_name(),
-        jwt_ref.get_email_token_time(TokenType::Refresh),
+        jwt_ref,
+        environment.get_ref(),
         auth_service::confirm_email(
-            db.get_ref(),
+            &db.session(),
+            cache.get_ref(),
             jwt_ref,
+            webhooks.get_ref(),
+            device_alerts.get_ref(),
             &body.into_inner().validate()?.confirmation_token,
+            &client_info,
         )
         .await?,
     ))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn sign_in(
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
+    environment: web::Data<Environment>,
     mailer: web::Data<Mailer>,
-    body: web::Json<bodies::SignIn>,
+    lockout: web::Data<Lockout>,
+    captcha: web::Data<CaptchaVerifier>,
+    device_alerts: web::Data<DeviceAlerts>,
+    body: JsonBody<bodies::SignIn>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
+    let body = body.into_inner().validate()?;
+    captcha
+        .check(body.captcha_token.as_deref(), client_info.ip.as_deref())
+        .await?;
     let jwt_ref = jwt.get_ref();
     match auth_service::sign_in(
-        db.get_ref(),
+        &db.session(),
         cache.get_ref(),
         jwt_ref,
         mailer.get_ref(),
-        body.into_inner().validate()?,
+        lockout.get_ref(),
+        device_alerts.get_ref(),
+        body,
+        &client_info,
     )
     .await?
     {
         responses::SignIn::Auth(auth_response) => Ok(save_refresh_token(
-            jwt_ref.get_refresh_name(),
-            jwt_ref.get_email_token_time(TokenType::Refresh),
+            jwt_ref,
+            environment.get_ref(),
             auth_response,
         )),
         responses::SignIn::Mfa => Ok(HttpResponse::Ok().json(responses::Message::new(
@@ -103,57 +242,123 @@ async fn sign_in(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn confirm_sign_in(
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: web::Json<bodies::ConfirmSignIn>,
+    environment: web::Data<Environment>,
+    mailer: web::Data<Mailer>,
+    device_alerts: web::Data<DeviceAlerts>,
+    body: JsonBody<bodies::ConfirmSignIn>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
     let jwt_ref = jwt.get_ref();
     Ok(save_refresh_token(
-        jwt_ref.get_refresh_name(),
-        jwt_ref.get_email_token_time(TokenType::Refresh),
+        jwt_ref,
+        environment.get_ref(),
         auth_service::confirm_sign_in(
-            db.get_ref(),
+            &db.session(),
             cache.get_ref(),
             jwt_ref,
+            mailer.get_ref(),
+            device_alerts.get_ref(),
             body.into_inner().validate()?,
+            &client_info,
         )
         .await?,
     ))
 }
 
-async fn forgot_password(
+async fn resend_confirmation(
     db: web::Data<Database>,
+    cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
     mailer: web::Data<Mailer>,
-    body: web::Json<bodies::Email>,
+    body: JsonBody<bodies::Email>,
 ) -> Result<HttpResponse, ServiceError> {
-    auth_service::forgot_password(
-        db.get_ref(),
+    auth_service::resend_confirmation_email(
+        &db.session(),
+        cache.get_ref(),
         jwt.get_ref(),
         mailer.get_ref(),
         &body.into_inner().validate()?.email,
     )
     .await?;
+    Ok(HttpResponse::Ok().json(responses::Message::new("Confirmation email sent")))
+}
+
+async fn forgot_password(
+    db: web::Data<Database>,
+    jwt: web::Data<Jwt>,
+    mailer: web::Data<Mailer>,
+    captcha: web::Data<CaptchaVerifier>,
+    body: JsonBody<bodies::Email>,
+    client_info: ClientInfo,
+) -> Result<HttpResponse, ServiceError> {
+    let body = body.into_inner().validate()?;
+    captcha
+        .check(body.captcha_token.as_deref(), client_info.ip.as_deref())
+        .await?;
+    auth_service::forgot_password(&db.session(), jwt.get_ref(), mailer.get_ref(), &body.email)
+        .await?;
     Ok(HttpResponse::Ok().json(responses::Message::new("Password reset link sent")))
 }
 
+async fn password_strength(
+    body: JsonBody<bodies::PasswordStrength>,
+) -> Result<HttpResponse, ServiceError> {
+    let body = body.into_inner().validate()?;
+    Ok(HttpResponse::Ok().json(auth_service::password_strength(&body.password)))
+}
+
 async fn reset_password(
     db: web::Data<Database>,
+    cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: web::Json<bodies::ResetPassword>,
+    mailer: web::Data<Mailer>,
+    breach_checker: web::Data<BreachChecker>,
+    body: JsonBody<bodies::ResetPassword>,
 ) -> Result<HttpResponse, ServiceError> {
-    auth_service::reset_password(db.get_ref(), jwt.get_ref(), body.into_inner().validate()?)
-        .await?;
+    let body = body.into_inner().validate()?;
+    breach_checker.check(&body.password1).await?;
+    auth_service::reset_password(
+        &db.session(),
+        cache.get_ref(),
+        jwt.get_ref(),
+        mailer.get_ref(),
+        body,
+    )
+    .await?;
     Ok(HttpResponse::Ok().json(responses::Message::new("Password reset successfully")))
 }
 
+async fn revert_email(
+    db: web::Data<Database>,
+    cache: web::Data<Cache>,
+    jwt: web::Data<Jwt>,
+    webhooks: web::Data<Webhooks>,
+    body: JsonBody<bodies::RevertEmail>,
+) -> Result<HttpResponse, ServiceError> {
+    auth_service::revert_email(
+        &db.session(),
+        cache.get_ref(),
+        jwt.get_ref(),
+        webhooks.get_ref(),
+        &body.into_inner().validate()?.revert_token,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(responses::Message::new("Email change reverted")))
+}
+
 async fn sign_out(
     auth_tokens: AuthTokens,
+    db: web::Data<Database>,
     cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: Option<web::Json<bodies::RefreshToken>>,
+    environment: web::Data<Environment>,
+    body: Option<JsonBody<bodies::RefreshToken>>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
     let refresh_token = match body {
         Some(body) => body.into_inner().validate()?.refresh_token,
@@ -169,8 +374,15 @@ async fn sign_out(
         }
     };
     let jwt_ref = jwt.get_ref();
-    auth_service::sign_out(cache.get_ref(), jwt_ref, &refresh_token).await?;
-    Ok(remove_refresh_token(jwt_ref.get_refresh_name()))
+    auth_service::sign_out(
+        &db.session(),
+        cache.get_ref(),
+        jwt_ref,
+        &refresh_token,
+        &client_info,
+    )
+    .await?;
+    Ok(remove_refresh_token(jwt_ref, environment.get_ref()))
 }
 
 async fn refresh_token(
@@ -178,7 +390,9 @@ async fn refresh_token(
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: Option<web::Json<bodies::RefreshToken>>,
+    environment: web::Data<Environment>,
+    body: Option<JsonBody<bodies::RefreshToken>>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
     let jwt_ref = jwt.get_ref();
     let token = match body {
@@ -194,18 +408,29 @@ async fn refresh_token(
         },
     };
     Ok(save_refresh_token(
-        jwt_ref.get_refresh_name(),
-        jwt_ref.get_email_token_time(TokenType::Refresh),
-        auth_service::refresh_token(db.get_ref(), cache.get_ref(), jwt_ref, &token).await?,
+        jwt_ref,
+        environment.get_ref(),
+        auth_service::refresh_token(
+            &db.session(),
+            cache.get_ref(),
+            jwt_ref,
+            &token,
+            &client_info,
+        )
+        .await?,
     ))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn update_password(
     auth_tokens: AuthTokens,
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: web::Json<bodies::ChangePassword>,
+    environment: web::Data<Environment>,
+    breach_checker: web::Data<BreachChecker>,
+    body: JsonBody<bodies::ChangePassword>,
+    client_info: ClientInfo,
 ) -> Result<HttpResponse, ServiceError> {
     let access_token = match auth_tokens.access_token {
         Some(access_token) => access_token,
@@ -216,18 +441,21 @@ async fn update_password(
             ));
         }
     };
+    let body = body.into_inner().validate()?;
+    breach_checker.check(&body.password1).await?;
 
     let jwt_ref = jwt.get_ref();
     Ok(save_refresh_token(
-        jwt_ref.get_refresh_name(),
-        jwt_ref.get_email_token_time(TokenType::Refresh),
+        jwt_ref,
+        environment.get_ref(),
         auth_service::update_password(
-            db.get_ref(),
+            &db.session(),
             cache.get_ref(),
             jwt_ref,
-            body.into_inner().validate()?,
+            body,
             &access_token,
             &auth_tokens.refresh_token,
+            &client_info,
         )
         .await?,
     ))
@@ -236,8 +464,10 @@ async fn update_password(
 async fn update_two_factor(
     auth_tokens: AuthTokens,
     db: web::Data<Database>,
+    cache: web::Data<Cache>,
     jwt: web::Data<Jwt>,
-    body: web::Json<bodies::ChangeTwoFactor>,
+    mailer: web::Data<Mailer>,
+    body: JsonBody<bodies::ChangeTwoFactor>,
 ) -> Result<HttpResponse, ServiceError> {
     let access_token = match auth_tokens.access_token {
         Some(access_token) => access_token,
@@ -248,92 +478,541 @@ async fn update_two_factor(
             ));
         }
     };
-    auth_service::update_two_factor(
-        db.get_ref(),
+    match auth_service::update_two_factor(
+        &db.session(),
+        cache.get_ref(),
         jwt.get_ref(),
-        body.into_inner(),
+        mailer.get_ref(),
+        body.into_inner().validate()?,
         &access_token,
     )
-    .await?;
-    Ok(HttpResponse::Ok().json(responses::Message::new("Two factor updated successfully")))
+    .await?
+    {
+        responses::TwoFactor::Updated => {
+            Ok(HttpResponse::Ok().json(responses::Message::new("Two factor updated successfully")))
+        }
+        responses::TwoFactor::CodeSent => Ok(HttpResponse::Accepted().json(
+            responses::Message::new("Confirmation code sent, check your email"),
+        )),
+    }
 }
 
-async fn facebook_sign_in(
-    cache: web::Data<Cache>,
+fn redirect(location: String) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// Holds the nonce of a pending provider link, so only the browser that started it
+/// can finish it. Lax so it is sent on the provider's redirect back.
+fn build_link_cookie<'a>(environment: &Environment, nonce: String, max_age: i64) -> Cookie<'a> {
+    let mut cookie = build_cookie(
+        environment,
+        OAUTH_LINK_COOKIE,
+        OAUTH_LINK_PATH,
+        nonce,
+        max_age,
+    );
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie
+}
+
+fn link_nonce(req: &HttpRequest) -> Option<String> {
+    req.cookie(OAUTH_LINK_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// Provider error codes are forwarded when they look like the OAuth2 ones.
+fn provider_error_code(error: &str) -> &str {
+    if !error.is_empty()
+        && error.len() <= OAUTH_ERROR_CODE_MAX_LENGTH
+        && error.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+    {
+        error
+    } else {
+        OAUTH_INVALID_REQUEST
+    }
+}
+
+/// The browser lands here straight from the provider, so every outcome is a
+/// redirect to the frontend rather than a JSON body.
+#[allow(clippy::too_many_arguments)]
+async fn oauth_callback(
+    db: &Database,
+    cache: &Cache,
+    oauth: &OAuth,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    webhooks: &Webhooks,
+    terms_version: &TermsVersion,
+    device_alerts: &DeviceAlerts,
+    sign_up_mode: SignUpMode,
+    environment: &Environment,
+    provider: ExternalProvider,
+    query: queries::OAuth,
+    link_nonce: Option<String>,
+    client_info: &ClientInfo,
+) -> HttpResponse {
+    let mut response = oauth_callback_response(
+        db,
+        cache,
+        oauth,
+        jwt,
+        mailer,
+        webhooks,
+        terms_version,
+        device_alerts,
+        sign_up_mode,
+        environment,
+        provider,
+        query,
+        link_nonce.as_deref(),
+        client_info,
+    )
+    .await;
+
+    // The nonce is single use, whatever the outcome
+    if link_nonce.is_some() {
+        let mut cookie = build_link_cookie(environment, String::new(), 0);
+        cookie.make_removal();
+        if let Err(e) = response.add_cookie(&cookie) {
+            tracing::error!(error = %e, "Failed to remove the OAuth link cookie");
+        }
+    }
+
+    response
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn oauth_callback_response(
+    db: &Database,
+    cache: &Cache,
+    oauth: &OAuth,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    webhooks: &Webhooks,
+    terms_version: &TermsVersion,
+    device_alerts: &DeviceAlerts,
+    sign_up_mode: SignUpMode,
+    environment: &Environment,
+    provider: ExternalProvider,
+    query: queries::OAuth,
+    link_nonce: Option<&str>,
+    client_info: &ClientInfo,
+) -> HttpResponse {
+    if let Some(error) = &query.error {
+        tracing::warn!(
+            provider = provider.to_str(),
+            error = %error,
+            description = ?query.error_description,
+            "OAuth provider returned an error"
+        );
+        return redirect(oauth.get_error_redirect(provider_error_code(error)));
+    }
+
+    let link_redirect = oauth.get_link_redirect(&provider);
+    let result = match query.validate() {
+        Ok(query) => {
+            auth_service::oauth_callback(
+                &db.session(),
+                cache,
+                oauth,
+                jwt,
+                mailer,
+                webhooks,
+                terms_version,
+                device_alerts,
+                sign_up_mode,
+                provider,
+                query,
+                link_nonce,
+                client_info,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(responses::OAuthCallback::Linked) => redirect(link_redirect),
+        Ok(responses::OAuthCallback::Mfa(email)) => redirect(oauth.get_mfa_redirect(&email)),
+        Ok(responses::OAuthCallback::Auth(auth_response)) => {
+            let mut response = HttpResponse::Found();
+            set_refresh_token(
+                &mut response,
+                jwt,
+                environment,
+                &auth_response.refresh_token,
+            );
+            set_access_cookies(&mut response, jwt, environment, &auth_response.access_token)
+                .insert_header((
+                    LOCATION,
+                    oauth.get_success_redirect(
+                        &auth_response.access_token,
+                        auth_response.expires_in,
+                    ),
+                ))
+                .finish()
+        }
+        Err(e) => {
+            redirect(oauth.get_error_redirect(&e.to_str_name().to_lowercase().replace(' ', "_")))
+        }
+    }
+}
+
+/// The access token comes in a header, which a browser navigation can not send, so the
+/// frontend fetches the provider URL, with credentials so the link cookie is kept, and
+/// navigates to it.
+async fn oauth_link(
+    auth_tokens: AuthTokens,
+    db: &Database,
+    oauth: &OAuth,
+    jwt: &Jwt,
+    environment: &Environment,
+    provider: ExternalProvider,
+) -> Result<HttpResponse, ServiceError> {
+    let access_token = auth_tokens.access_token.ok_or_else(|| {
+        ServiceError::unauthorized(
+            UNAUTHORIZED,
+            Some(InternalCause::new("Access token not found")),
+        )
+    })?;
+    let (url, nonce) =
+        auth_service::oauth_link(&db.session(), oauth, jwt, provider, &access_token).await?;
+    Ok(HttpResponse::Ok()
+        .cookie(build_link_cookie(environment, nonce, OAUTH_STATE_TIME))
+        .json(responses::OAuthLink { url }))
+}
+
+async fn facebook_link(
+    auth_tokens: AuthTokens,
+    db: web::Data<Database>,
     oauth: web::Data<OAuth>,
+    jwt: web::Data<Jwt>,
+    environment: web::Data<Environment>,
 ) -> Result<HttpResponse, ServiceError> {
-    let url =
-        auth_service::oauth_sign_in(cache.get_ref(), oauth.get_ref(), ExternalProvider::Facebook)
-            .await?;
+    oauth_link(
+        auth_tokens,
+        db.get_ref(),
+        oauth.get_ref(),
+        jwt.get_ref(),
+        environment.get_ref(),
+        ExternalProvider::Facebook,
+    )
+    .await
+}
+
+async fn facebook_sign_in(oauth: web::Data<OAuth>) -> Result<HttpResponse, ServiceError> {
+    let url = auth_service::oauth_sign_in(oauth.get_ref(), ExternalProvider::Facebook)?;
     Ok(HttpResponse::TemporaryRedirect()
         .insert_header((LOCATION, url))
         .finish())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn facebook_callback(
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     oauth: web::Data<OAuth>,
     jwt: web::Data<Jwt>,
+    mailer: web::Data<Mailer>,
+    webhooks: web::Data<Webhooks>,
+    terms_version: web::Data<TermsVersion>,
+    device_alerts: web::Data<DeviceAlerts>,
+    sign_up_mode: web::Data<SignUpMode>,
+    environment: web::Data<Environment>,
     query: web::Query<queries::OAuth>,
-) -> Result<HttpResponse, ServiceError> {
-    let data = auth_service::oauth_callback(
+    client_info: ClientInfo,
+    req: HttpRequest,
+) -> HttpResponse {
+    oauth_callback(
         db.get_ref(),
         cache.get_ref(),
         oauth.get_ref(),
         jwt.get_ref(),
+        mailer.get_ref(),
+        webhooks.get_ref(),
+        terms_version.get_ref(),
+        device_alerts.get_ref(),
+        *sign_up_mode.get_ref(),
+        environment.get_ref(),
         ExternalProvider::Facebook,
-        query.into_inner().validate()?,
+        query.into_inner(),
+        link_nonce(&req),
+        &client_info,
     )
-    .await?;
-    Ok(HttpResponse::Ok().json(data))
+    .await
 }
 
-async fn google_sign_in(
-    cache: web::Data<Cache>,
+async fn google_link(
+    auth_tokens: AuthTokens,
+    db: web::Data<Database>,
     oauth: web::Data<OAuth>,
+    jwt: web::Data<Jwt>,
+    environment: web::Data<Environment>,
 ) -> Result<HttpResponse, ServiceError> {
-    let url =
-        auth_service::oauth_sign_in(cache.get_ref(), oauth.get_ref(), ExternalProvider::Google)
-            .await?;
+    oauth_link(
+        auth_tokens,
+        db.get_ref(),
+        oauth.get_ref(),
+        jwt.get_ref(),
+        environment.get_ref(),
+        ExternalProvider::Google,
+    )
+    .await
+}
+
+async fn google_sign_in(oauth: web::Data<OAuth>) -> Result<HttpResponse, ServiceError> {
+    let url = auth_service::oauth_sign_in(oauth.get_ref(), ExternalProvider::Google)?;
     Ok(HttpResponse::TemporaryRedirect()
         .insert_header((LOCATION, url))
         .finish())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn google_callback(
     db: web::Data<Database>,
     cache: web::Data<Cache>,
     oauth: web::Data<OAuth>,
     jwt: web::Data<Jwt>,
+    mailer: web::Data<Mailer>,
+    webhooks: web::Data<Webhooks>,
+    terms_version: web::Data<TermsVersion>,
+    device_alerts: web::Data<DeviceAlerts>,
+    sign_up_mode: web::Data<SignUpMode>,
+    environment: web::Data<Environment>,
     query: web::Query<queries::OAuth>,
-) -> Result<HttpResponse, ServiceError> {
-    let data = auth_service::oauth_callback(
+    client_info: ClientInfo,
+    req: HttpRequest,
+) -> HttpResponse {
+    oauth_callback(
         db.get_ref(),
         cache.get_ref(),
         oauth.get_ref(),
         jwt.get_ref(),
+        mailer.get_ref(),
+        webhooks.get_ref(),
+        terms_version.get_ref(),
+        device_alerts.get_ref(),
+        *sign_up_mode.get_ref(),
+        environment.get_ref(),
         ExternalProvider::Google,
-        query.into_inner().validate()?,
+        query.into_inner(),
+        link_nonce(&req),
+        &client_info,
     )
-    .await?;
-    Ok(HttpResponse::Ok().json(data))
+    .await
+}
+
+async fn github_link(
+    auth_tokens: AuthTokens,
+    db: web::Data<Database>,
+    oauth: web::Data<OAuth>,
+    jwt: web::Data<Jwt>,
+    environment: web::Data<Environment>,
+) -> Result<HttpResponse, ServiceError> {
+    oauth_link(
+        auth_tokens,
+        db.get_ref(),
+        oauth.get_ref(),
+        jwt.get_ref(),
+        environment.get_ref(),
+        ExternalProvider::Github,
+    )
+    .await
+}
+
+async fn github_sign_in(oauth: web::Data<OAuth>) -> Result<HttpResponse, ServiceError> {
+    let url = auth_service::oauth_sign_in(oauth.get_ref(), ExternalProvider::Github)?;
+    Ok(HttpResponse::TemporaryRedirect()
+        .insert_header((LOCATION, url))
+        .finish())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn github_callback(
+    db: web::Data<Database>,
+    cache: web::Data<Cache>,
+    oauth: web::Data<OAuth>,
+    jwt: web::Data<Jwt>,
+    mailer: web::Data<Mailer>,
+    webhooks: web::Data<Webhooks>,
+    terms_version: web::Data<TermsVersion>,
+    device_alerts: web::Data<DeviceAlerts>,
+    sign_up_mode: web::Data<SignUpMode>,
+    environment: web::Data<Environment>,
+    query: web::Query<queries::OAuth>,
+    client_info: ClientInfo,
+    req: HttpRequest,
+) -> HttpResponse {
+    oauth_callback(
+        db.get_ref(),
+        cache.get_ref(),
+        oauth.get_ref(),
+        jwt.get_ref(),
+        mailer.get_ref(),
+        webhooks.get_ref(),
+        terms_version.get_ref(),
+        device_alerts.get_ref(),
+        *sign_up_mode.get_ref(),
+        environment.get_ref(),
+        ExternalProvider::Github,
+        query.into_inner(),
+        link_nonce(&req),
+        &client_info,
+    )
+    .await
 }
 
 pub fn auth_router() -> Scope {
     web::scope("/api/auth")
         .route("/sign-up", web::post().to(sign_up))
         .route("/confirm-email", web::post().to(confirm_email))
+        .route("/resend-confirmation", web::post().to(resend_confirmation))
         .route("/sign-in", web::post().to(sign_in))
         .route("/confirm-sign-in", web::post().to(confirm_sign_in))
         .route("/sign-out", web::post().to(sign_out))
         .route("/refresh-token", web::post().to(refresh_token))
         .route("/forgot-password", web::post().to(forgot_password))
+        .route("/password-strength", web::post().to(password_strength))
         .route("/reset-password", web::post().to(reset_password))
+        .route("/revert-email", web::post().to(revert_email))
         .route("/update-password", web::post().to(update_password))
         .route("/update-two-factor", web::post().to(update_two_factor))
         .route("/ext/facebook", web::get().to(facebook_sign_in))
+        .route("/ext/facebook/link", web::get().to(facebook_link))
         .route("/ext/facebook/callback", web::get().to(facebook_callback))
         .route("/ext/google", web::get().to(google_sign_in))
+        .route("/ext/google/link", web::get().to(google_link))
         .route("/ext/google/callback", web::get().to(google_callback))
+        .route("/ext/github", web::get().to(github_sign_in))
+        .route("/ext/github/link", web::get().to(github_link))
+        .route("/ext/github/callback", web::get().to(github_callback))
+}
+
+/// OpenAPI description of `auth_router`, kept next to it so both change together.
+pub fn auth_operations() -> Vec<ApiOperation> {
+    let mut operations = vec![
+        ApiOperation::post("/api/auth/sign-up", "Creates a local account")
+            .body::<bodies::SignUp>()
+            .response::<responses::Message>(200, "Account created, confirmation email sent")
+            .errors::<ErrorBody>(&[400, 403, 503]),
+        ApiOperation::post("/api/auth/confirm-email", "Confirms the email and signs in")
+            .body::<bodies::ConfirmEmail>()
+            .refresh_cookie()
+            .response::<responses::Auth>(200, "Signed in")
+            .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post(
+            "/api/auth/resend-confirmation",
+            "Sends the confirmation email again",
+        )
+        .body::<bodies::Email>()
+        .response::<responses::Message>(200, "Confirmation email sent")
+        .errors::<ErrorBody>(&[400, 503]),
+        ApiOperation::post("/api/auth/sign-in", "Signs in with email and password")
+            .body::<bodies::SignIn>()
+            .refresh_cookie()
+            .response_one_of::<responses::Auth, responses::Message>(
+                200,
+                "Signed in, or a `Message` when two-factor sent a code to confirm the sign in",
+            )
+            .errors::<ErrorBody>(&[400, 401, 403, 503]),
+        ApiOperation::post(
+            "/api/auth/confirm-sign-in",
+            "Finishes a two-factor sign in with the emailed or a recovery code",
+        )
+        .body::<bodies::ConfirmSignIn>()
+        .refresh_cookie()
+        .response::<responses::Auth>(200, "Signed in")
+        .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post("/api/auth/sign-out", "Revokes the refresh token")
+            .optional_body::<bodies::RefreshToken>()
+            .security(REFRESH_COOKIE)
+            .optional_security()
+            .refresh_cookie()
+            .empty_response(200, "Signed out, the refresh token cookie is cleared")
+            .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post(
+            "/api/auth/refresh-token",
+            "Rotates the refresh token, read from the body or the cookie",
+        )
+        .optional_body::<bodies::RefreshToken>()
+        .security(REFRESH_COOKIE)
+        .optional_security()
+        .refresh_cookie()
+        .response::<responses::Auth>(200, "New access and refresh tokens")
+        .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post("/api/auth/forgot-password", "Emails a password reset link")
+            .body::<bodies::Email>()
+            .response::<responses::Message>(200, "Reset link sent if the account exists")
+            .errors::<ErrorBody>(&[400, 503]),
+        ApiOperation::post(
+            "/api/auth/password-strength",
+            "Estimates the strength of a password",
+        )
+        .body::<bodies::PasswordStrength>()
+        .response::<responses::PasswordStrength>(200, "Score from 0 to 4 with feedback")
+        .errors::<ErrorBody>(&[400, 503]),
+        ApiOperation::post("/api/auth/reset-password", "Sets a new password")
+            .body::<bodies::ResetPassword>()
+            .response::<responses::Message>(200, "Password reset")
+            .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post(
+            "/api/auth/revert-email",
+            "Restores the previous email after an unwanted change",
+        )
+        .body::<bodies::RevertEmail>()
+        .response::<responses::Message>(200, "Email change reverted")
+        .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post("/api/auth/update-password", "Changes the password")
+            .body::<bodies::ChangePassword>()
+            .security(BEARER_AUTH)
+            .refresh_cookie()
+            .response::<responses::Auth>(200, "Password changed, with new tokens")
+            .errors::<ErrorBody>(&[400, 401, 503]),
+        ApiOperation::post(
+            "/api/auth/update-two-factor",
+            "Turns two-factor authentication on or off",
+        )
+        .body::<bodies::ChangeTwoFactor>()
+        .security(BEARER_AUTH)
+        .response::<responses::Message>(200, "Two-factor updated")
+        .response::<responses::Message>(202, "Confirmation code sent, send it back as `code`")
+        .errors::<ErrorBody>(&[400, 401, 503]),
+    ];
+
+    for provider in ["facebook", "google", "github"] {
+        operations.extend(oauth_operations(provider));
+    }
+
+    operations
+        .into_iter()
+        .map(|operation| operation.tag(AUTH_TAG))
+        .collect()
+}
+
+fn oauth_operations(provider: &str) -> [ApiOperation; 3] {
+    let path = format!("/api/auth/ext/{}", provider);
+
+    [
+        ApiOperation::get(&path, "Starts the OAuth2 sign in")
+            .redirect(307, "Redirect to the provider")
+            .errors::<ErrorBody>(&[503]),
+        ApiOperation::get(
+            &format!("{}/link", &path),
+            "Provider URL linking it to the signed in account",
+        )
+        .security(BEARER_AUTH)
+        .response::<responses::OAuthLink>(200, "Navigate the browser to `url`")
+        .errors::<ErrorBody>(&[401, 503]),
+        ApiOperation::get(
+            &format!("{}/callback", &path),
+            "Provider callback, never called by clients",
+        )
+        .refresh_cookie()
+        .redirect(
+            302,
+            "Redirect to the frontend with the access token in the URL fragment, or an `error`",
+        ),
+    ]
 }
@@ -0,0 +1,79 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_web::{web, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::common::{OpenApi, BEARER_AUTH, REFRESH_COOKIE};
+use crate::providers::Jwt;
+
+use super::auth_controller::auth_operations;
+use super::health_controller::health_operations;
+
+pub const OPENAPI_PATH: &str = "/api/openapi.json";
+pub const DOCS_PATH: &str = "/api/docs";
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <title>API Docs</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+    <script>
+      window.onload = () => {
+        window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+      };
+    </script>
+  </body>
+</html>
+"##;
+
+/// The REST endpoints only, GraphQL describes itself through introspection.
+pub fn openapi_document(refresh_name: &str) -> Value {
+    OpenApi::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        .description("REST endpoints of the API, the GraphQL schema is served at `/api/graphql`.")
+        .security_scheme(
+            BEARER_AUTH,
+            json!({
+                "type": "http",
+                "scheme": "bearer",
+                "bearerFormat": "JWT",
+                "description": "`access_token` of an `Auth` response",
+            }),
+        )
+        .security_scheme(
+            REFRESH_COOKIE,
+            json!({
+                "type": "apiKey",
+                "in": "cookie",
+                "name": refresh_name,
+                "description": "HTTP only cookie set with every `Auth` response, only sent to `/api/auth`",
+            }),
+        )
+        .operations(auth_operations())
+        .operations(health_operations())
+        .to_json()
+}
+
+async fn openapi(jwt: web::Data<Jwt>) -> HttpResponse {
+    HttpResponse::Ok().json(openapi_document(jwt.get_refresh_name()))
+}
+
+async fn swagger_ui() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI)
+}
+
+/// Plain routes rather than a scope, `/api` itself belongs to the health router.
+pub fn docs_router(cfg: &mut web::ServiceConfig) {
+    cfg.route(OPENAPI_PATH, web::get().to(openapi))
+        .route(DOCS_PATH, web::get().to(swagger_ui));
+}
@@ -6,10 +6,114 @@
 
 use actix_web::{web, HttpResponse, Scope};
 
-async fn health_check() -> HttpResponse {
-    HttpResponse::Ok().finish()
+use crate::common::ApiOperation;
+use crate::dtos::responses;
+use crate::providers::{Cache, Database, Mailer, ReadinessConfig};
+use crate::startup::StartupReport;
+
+const READY: &str = "ok";
+const UNREACHABLE: &str = "unreachable";
+
+fn status(healthy: bool, failure: &str) -> String {
+    let status = if healthy { READY } else { failure };
+    status.to_string()
+}
+
+async fn health_check(
+    cache: web::Data<Cache>,
+    startup_report: Option<web::Data<StartupReport>>,
+) -> HttpResponse {
+    if let Err(e) = cache.ping().await {
+        tracing::error!("Cache health check failed: {:?}", e);
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+
+    match startup_report.as_ref().and_then(|report| report.recent()) {
+        Some(startup_report) => HttpResponse::Ok().json(responses::Health {
+            startup_report: startup_report.clone(),
+        }),
+        None => HttpResponse::Ok().finish(),
+    }
+}
+
+/// Unlike the health check, also fails while the schema is behind the binary, so a
+/// deploy that skipped its migrations never receives traffic.
+async fn readiness(
+    db: web::Data<Database>,
+    cache: web::Data<Cache>,
+    mailer: web::Data<Mailer>,
+    config: web::Data<ReadinessConfig>,
+) -> HttpResponse {
+    let (database, cache, pending, mailer) = tokio::join!(
+        db.get_connection().ping(),
+        cache.ping(),
+        db.pending_migrations(),
+        mailer.verify(),
+    );
+
+    if let Err(e) = &database {
+        tracing::error!("Database readiness check failed: {:?}", e);
+    }
+    if let Err(e) = &cache {
+        tracing::error!("Cache readiness check failed: {:?}", e);
+    }
+    let migrations = match pending {
+        Ok(pending) if pending.is_empty() => READY.to_string(),
+        Ok(pending) => {
+            tracing::error!("The database is missing {} migration(s)", pending.len());
+            format!("behind({})", pending.len())
+        }
+        Err(e) => {
+            tracing::error!("Migrations readiness check failed: {:?}", e);
+            "unknown".to_string()
+        }
+    };
+
+    let ready = database.is_ok()
+        && cache.is_ok()
+        && migrations == READY
+        && (mailer || !config.mailer_fatal);
+    let body = responses::Readiness {
+        database: status(database.is_ok(), UNREACHABLE),
+        cache: status(cache.is_ok(), UNREACHABLE),
+        migrations,
+        mailer: status(mailer, "failing"),
+    };
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
 }
 
 pub fn health_router() -> Scope {
-    web::scope("/api").route("/health-check", web::get().to(health_check))
+    web::scope("/api")
+        .route("/health-check", web::get().to(health_check))
+        .route("/ready", web::get().to(readiness))
+}
+
+pub fn health_operations() -> Vec<ApiOperation> {
+    vec![
+        ApiOperation::get(
+            "/api/health-check",
+            "Checks the API and its Redis connection",
+        )
+        .tag("health")
+        .response::<responses::Health>(
+            200,
+            "Healthy, also during maintenance, with the startup checks during the first five minutes",
+        )
+        .empty_response(503, "Redis is unreachable"),
+        ApiOperation::get(
+            "/api/ready",
+            "Checks the database, Redis, pending migrations and the mailer",
+        )
+        .tag("health")
+        .response::<responses::Readiness>(200, "Ready to receive traffic")
+        .response::<responses::Readiness>(
+            503,
+            "A dependency is down, migrations are behind or, with READINESS_MAILER_FATAL, the mailer fails",
+        ),
+    ]
 }
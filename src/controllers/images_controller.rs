@@ -0,0 +1,86 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_web::{
+    http::header::{CacheControl, CacheDirective, CONTENT_TYPE},
+    web, HttpResponse, Scope,
+};
+use uuid::Uuid;
+
+use crate::common::{AuthTokens, ServiceError};
+use crate::dtos::queries;
+use crate::providers::{Database, Jwt, ObjectStorage};
+use crate::services::uploader_service::{self, ImageAccess, IMAGES_PATH};
+use crate::services::users_service;
+
+/// Resized variants never change for a file id, so they are cached for a year.
+const MAX_AGE: u32 = 31_536_000;
+
+/// The signed in user behind the request, `None` when there is none or the token no
+/// longer holds.
+async fn find_viewer(db: &Database, jwt: &Jwt, auth_tokens: &AuthTokens) -> Option<i32> {
+    let (id, _, version, _) = jwt
+        .verify_access_token(auth_tokens.access_token.as_deref()?)
+        .ok()?;
+    let user = users_service::find_one_by_id(&db.session(), id)
+        .await
+        .ok()?;
+    users_service::check_token_user(&user, version).ok()?;
+    Some(user.id)
+}
+
+async fn resized_image(
+    auth_tokens: AuthTokens,
+    db: web::Data<Database>,
+    jwt: web::Data<Jwt>,
+    object_storage: web::Data<ObjectStorage>,
+    path: web::Path<Uuid>,
+    query: web::Query<queries::ImageWidth>,
+) -> Result<HttpResponse, ServiceError> {
+    let query = query.into_inner();
+    let width = query
+        .w
+        .as_deref()
+        .and_then(|width| width.parse::<i64>().ok())
+        .unwrap_or_default();
+    let width = uploader_service::snap_image_width(width)?;
+    let public = object_storage.is_public();
+    let viewer_id = if public || query.sig.is_some() {
+        None
+    } else {
+        find_viewer(db.get_ref(), jwt.get_ref(), &auth_tokens).await
+    };
+    let image_data = uploader_service::resized_image(
+        db.get_ref(),
+        object_storage.get_ref(),
+        path.into_inner(),
+        width,
+        &ImageAccess {
+            viewer_id,
+            exp: query.exp,
+            sig: query.sig,
+        },
+    )
+    .await?;
+    // Private images must not outlive their signature in a shared cache
+    let cache_control = if public {
+        vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(MAX_AGE),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ]
+    } else {
+        vec![CacheDirective::Private, CacheDirective::NoStore]
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "image/jpeg"))
+        .insert_header(CacheControl(cache_control))
+        .body(image_data))
+}
+
+pub fn images_router() -> Scope {
+    web::scope(IMAGES_PATH).route("/{file_id}", web::get().to(resized_image))
+}
@@ -0,0 +1,33 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_web::{web, HttpRequest, HttpResponse, Scope};
+use anyhow::Error;
+use prometheus::TEXT_FORMAT;
+
+use crate::common::{get_access_token_from_headers, ServiceError, UNAUTHORIZED};
+use crate::providers::Metrics;
+
+async fn metrics(
+    req: HttpRequest,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ServiceError> {
+    let metrics = metrics.get_ref();
+
+    if let Some(token) = metrics.get_token() {
+        if get_access_token_from_headers(req.headers()).as_deref() != Some(token) {
+            return Err(ServiceError::unauthorized::<Error>(UNAUTHORIZED, None));
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .body(metrics.encode()?))
+}
+
+pub fn metrics_router() -> Scope {
+    web::scope("/metrics").route("", web::get().to(metrics))
+}
@@ -4,8 +4,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub mod admin_controller;
 pub mod auth_controller;
+pub mod docs_controller;
 pub mod health_controller;
+pub mod images_controller;
+pub mod metrics_controller;
+pub mod uploads_controller;
 
 #[cfg(test)]
 mod tests;
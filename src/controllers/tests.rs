@@ -4,14 +4,46 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::services::users_service;
-use actix_web::{body::to_bytes, test, web::Bytes, App};
-use bcrypt::hash;
-use entities::{enums, oauth_provider, user};
+use crate::common::{
+    format_point_slug, mask_email, normalize_email, ClientInfo, ServiceError, CLIENT_ID_HEADER,
+    CSRF_COOKIE, CSRF_HEADER,
+};
+use crate::dtos::{bodies, responses};
+use crate::guards::TERMS_OUTDATED;
+use crate::services::{
+    auth_service, helpers::hash_code, outbox_service, recovery_codes_service,
+    token_blacklist_service, unconfirmed_accounts_service, users_service,
+};
+use actix_web::{
+    body::to_bytes,
+    cookie::{Cookie, SameSite},
+    http::header::ContentType,
+    test,
+    web::{self, Bytes},
+    App,
+};
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use entities::{blacklisted_token, email_outbox, enums, invitation, oauth_provider, user};
 use fake::{faker::name::raw::*, locales::EN, Fake};
+use migrations::{Migrator, MigratorTrait};
+use oauth2::url::Url;
 use redis::AsyncCommands;
-use sea_orm::{ActiveModelTrait, ModelTrait, Set};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, PaginatorTrait, QueryFilter, Set,
+    TransactionTrait,
+};
+use secrecy::Secret;
 use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, HashSet},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tracing_actix_web::TracingLogger;
 use uuid::Uuid;
 
@@ -25,39 +57,50 @@ impl BodyTest for Bytes {
     }
 }
 
-use crate::providers::{Cache, Environment, TokenType};
+use crate::providers::{
+    captured_emails, BreachChecker, Cache, CaptchaProviderKind, Config, DbSession, DeviceAlerts,
+    EmailTransport, EnabledModules, Environment, ExternalProvider, HttpClient, HttpClientConfig,
+    Mailer, Maintenance, Metrics, OAuth, ObjectStorage, PasswordBreachConfig, PwnedRange,
+    SignUpMode, StartupChecks, TermsVersion, TokenBinding, TokenType, Webhooks, BREACHED_PASSWORD,
+    CAPTCHA_FAILED,
+};
 use crate::{
     providers::{Database, Jwt},
-    startup::ActixApp,
+    startup::{
+        run_preflight, ActixApp, HttpMetrics, Providers, IDEMPOTENCY_KEY_HEADER, PLAYGROUND_CSP,
+    },
+    tests::{TestApp, GRAPHQL_PATH, VALID_PASSWORD},
 };
 
-const PORT: u16 = 5000;
-const VALID_PASSWORD: &'static str = "Valid_Password12";
-
-async fn create_base_config() -> (Environment, Database, Jwt, Cache) {
+async fn create_base_config() -> (Config, Database, Jwt, Cache) {
     dotenvy::dotenv().expect("Failed to load .env file");
-    let environment = Environment::Development;
-    let db = Database::new()
+    let config =
+        Config::try_with_environment(Environment::Development).expect("Invalid test configuration");
+    let db = Database::new(&config.database)
         .await
         .expect("Failed to connect to database");
-    let jwt = Jwt::new(&environment, &Uuid::new_v4().to_string());
-    let cache = Cache::new();
-    (environment, db, jwt, cache)
+    // Tokens are only accepted by the app when issuer and audience match its own
+    let jwt = Jwt::new(&config.jwt);
+    let cache = Cache::new(&config.cache, &Metrics::new());
+    (config, db, jwt, cache)
 }
 
 async fn create_user(db: &Database, confirm: bool) -> user::Model {
-    let email = format!("{}@gmail.com", Uuid::new_v4().to_string());
+    let email = format!("{}@gmail.com", Uuid::new_v4());
     let first_name: String = Name(EN).fake();
     let last_name: String = Name(EN).fake();
     let date_of_birth = "1990-01-01".to_string();
     let user = users_service::create_user(
-        &db,
+        &db.session(),
+        &Webhooks::disabled(),
         first_name,
         last_name,
-        date_of_birth,
+        Some(date_of_birth),
         email,
         VALID_PASSWORD.to_string(),
         enums::OAuthProviderEnum::Local,
+        users_service::UserPreferences::default(),
+        None,
     )
     .await
     .unwrap();
@@ -75,7 +118,7 @@ async fn create_user(db: &Database, confirm: bool) -> user::Model {
 
 async fn create_token(jwt: &Jwt, user: &user::Model, token_type: Option<TokenType>) -> String {
     if let Some(token_type) = token_type {
-        jwt.generate_email_token(token_type, &user).unwrap()
+        jwt.generate_email_token(token_type, user).unwrap()
     } else {
         jwt.generate_access_token(user).unwrap()
     }
@@ -92,14 +135,24 @@ async fn delete_user(db: &Database, user: user::Model) {
     user.delete(db.get_connection()).await.unwrap();
 }
 
+async fn set_created_hours_ago(db: &Database, user: user::Model, hours: i64) -> user::Model {
+    let mut user: user::ActiveModel = user.into();
+    user.created_at = Set((Utc::now() - Duration::hours(hours)).naive_utc());
+    user.update(db.get_connection()).await.unwrap()
+}
+
+async fn set_two_factor(db: &Database, user: &user::Model, two_factor: bool) -> user::Model {
+    let mut user: user::ActiveModel = user.clone().into();
+    user.two_factor = Set(two_factor);
+    user.update(db.get_connection()).await.unwrap()
+}
+
 #[actix_web::test]
 async fn test_health_check() {
-    let (environment, db, _, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let req = test::TestRequest::get()
         .uri("/api/health-check")
@@ -108,14 +161,100 @@ async fn test_health_check() {
     assert!(resp.status().is_success());
 }
 
+#[actix_web::test]
+async fn test_readiness_migrations() {
+    let app = TestApp::new().await;
+    let ready = || test::TestRequest::get().uri("/api/ready").to_request();
+    let resp = app.call(ready()).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert_eq!(
+        test::read_body_json::<serde_json::Value, _>(resp).await,
+        json!({
+            "database": "ok",
+            "cache": "ok",
+            "migrations": "ok",
+            "mailer": "ok",
+        })
+    );
+
+    // A deploy ahead of its schema is kept out of rotation, the instance stays alive
+    Migrator::down(app.db.get_connection(), Some(1))
+        .await
+        .unwrap();
+    let resp = app.call(ready()).await;
+    assert_eq!(&resp.status().as_u16(), &503);
+    let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+    assert_eq!(body["migrations"], json!("behind(1)"));
+    assert_eq!(body["database"], json!("ok"));
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/health-check")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[derive(Clone, Default)]
+struct UnverifiedTransport(Arc<AtomicUsize>);
+
+#[async_trait]
+impl EmailTransport for UnverifiedTransport {
+    async fn send(&self, _: &str, _: &str, _: &str, _: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn verify(&self) -> anyhow::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err(anyhow::anyhow!("Invalid SMTP credentials"))
+    }
+}
+
+#[actix_web::test]
+async fn test_readiness_mailer() {
+    let (mut config, db, _, _) = create_base_config().await;
+    let transport = UnverifiedTransport::default();
+    let init_app = |config: &Config| {
+        let metrics = Metrics::new();
+        let providers = Providers::new(config, &metrics).with_mailer(Mailer::with_transport(
+            &config.environment,
+            &config.mailer,
+            &metrics,
+            transport.clone(),
+        ));
+        test::init_service(
+            App::new().configure(ActixApp::build_app_config(config, &db, &providers)),
+        )
+    };
+    let ready = || test::TestRequest::get().uri("/api/ready").to_request();
+
+    // Only reported by default, and checked once within the cache window
+    let app = init_app(&config).await;
+    for _ in 0..3 {
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(&resp.status().as_u16(), &200);
+        let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+        assert_eq!(body["mailer"], json!("failing"));
+    }
+    assert_eq!(transport.0.load(Ordering::SeqCst), 1);
+
+    config.readiness.mailer_fatal = true;
+    let app = init_app(&config).await;
+    let resp = test::call_service(&app, ready()).await;
+    assert_eq!(&resp.status().as_u16(), &503);
+    let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+    assert_eq!(body["mailer"], json!("failing"));
+    assert_eq!(body["migrations"], json!("ok"));
+    assert_eq!(transport.0.load(Ordering::SeqCst), 2);
+}
+
 #[actix_web::test]
 async fn test_sign_up() {
-    let (environment, db, _, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
 
     // Success sign in
@@ -134,6 +273,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }))
         .to_request();
     let resp = test::call_service(&app, req).await;
@@ -144,6 +284,34 @@ async fn test_sign_up() {
         .as_str()
         .contains("User created successfully"));
 
+    // Development marks the confirmation email as sent right away
+    let queued = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.eq(email.to_lowercase()))
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(queued.status, enums::EmailStatusEnum::Sent);
+    queued.delete(db.get_connection()).await.unwrap();
+
+    // The console transport captured the email, its link carries a valid token
+    let sent = captured_emails(&email.to_lowercase());
+    assert_eq!(sent.len(), 1);
+    let link = format!("{}/confirmation/", config.urls.frontend_url);
+    let token = sent[0].body.split(&link).nth(1).unwrap();
+    let token = token
+        .split(|char: char| !(char.is_ascii_alphanumeric() || "._-".contains(char)))
+        .next()
+        .unwrap();
+    let (id, _, _, _) = jwt
+        .verify_email_token(TokenType::Confirmation, token)
+        .unwrap();
+    let user = users_service::find_one_by_email(&db.session(), &email.to_lowercase())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(id, user.id);
+
     let invalid_payloads = [
         json!({
             "email": "not_an_email",
@@ -152,6 +320,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }),
         json!({
             "email": &email,
@@ -160,6 +329,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }),
         json!({
             "email": &email,
@@ -168,6 +338,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }),
         json!({
             "email": &email,
@@ -176,6 +347,7 @@ async fn test_sign_up() {
             "date_of_birth": "01-01-1990",
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }),
         json!({
             "email": &email,
@@ -184,6 +356,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": "not_valid_password",
             "password2": "not_valid_password",
+            "accepted_terms": true,
         }),
         json!({
             "email": &email,
@@ -192,6 +365,7 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": format!("{}_e", &password2),
+            "accepted_terms": true,
         }),
     ];
 
@@ -215,500 +389,4895 @@ async fn test_sign_up() {
             "date_of_birth": &date_of_birth,
             "password1": &password1,
             "password2": &password2,
+            "accepted_terms": true,
         }))
         .to_request();
     let resp = test::call_service(&app, req).await;
     assert!(&resp.status().is_client_error());
     assert_eq!(&resp.status().as_u16(), &409);
+    let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+    assert_eq!(body["message"], json!("User already exists"));
+    assert_eq!(body["details"], json!({ "reason": "exists_unconfirmed" }));
+
+    // Under the minimum age
+    let under_age_email = format!("{}@gmail.com", Uuid::new_v4());
+    let under_age_date = (Utc::now().date_naive() - Duration::days(365 * 10))
+        .format("%Y-%m-%d")
+        .to_string();
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-up")
+        .set_json(json!({
+            "email": &under_age_email,
+            "first_name": &first_name,
+            "last_name": &last_name,
+            "date_of_birth": &under_age_date,
+            "password1": &password1,
+            "password2": &password2,
+            "accepted_terms": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("You must be at least 13 years old."));
+    assert!(
+        users_service::find_one_by_email(&db.session(), &under_age_email)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // OAuth sign ups go through the same check
+    let oauth_result = users_service::find_or_create(
+        &db.session(),
+        &Webhooks::disabled(),
+        &TermsVersion::default(),
+        SignUpMode::Open,
+        enums::OAuthProviderEnum::Google,
+        first_name.clone(),
+        last_name.clone(),
+        Some(under_age_date),
+        under_age_email,
+    )
+    .await;
+    assert_eq!(oauth_result.unwrap_err().get_status_code(), 400);
+
+    // Dates of birth in the future or too far back are rejected
+    let future_date = (Utc::now().date_naive() + Duration::days(1))
+        .format("%Y-%m-%d")
+        .to_string();
+    for (date_of_birth, message) in [
+        (
+            future_date.as_str(),
+            "Date of birth needs to be in the past.",
+        ),
+        (
+            "0001-01-01",
+            "Date of birth can not be more than 130 years ago.",
+        ),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/auth/sign-up")
+            .set_json(json!({
+                "email": format!("{}@gmail.com", Uuid::new_v4()),
+                "first_name": &first_name,
+                "last_name": &last_name,
+                "date_of_birth": date_of_birth,
+                "password1": &password1,
+                "password2": &password2,
+                "accepted_terms": true,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(&resp.status().as_u16(), &400);
+        assert!(to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .as_str()
+            .contains(message));
+    }
+
+    // Facebook birthdays are stored like any other date of birth
+    let facebook: responses::FacebookUserInfoResponse = serde_json::from_value(json!({
+        "id": "10158",
+        "first_name": &first_name,
+        "last_name": &last_name,
+        "email": format!("{}@gmail.com", Uuid::new_v4()),
+        "birthday": "01/31/1990",
+    }))
+    .unwrap();
+    let user_info: responses::UserInfo = responses::OAuthUserInfo::Facebook(facebook)
+        .try_into()
+        .unwrap();
+    let facebook_user = users_service::find_or_create(
+        &db.session(),
+        &Webhooks::disabled(),
+        &TermsVersion::default(),
+        SignUpMode::Open,
+        enums::OAuthProviderEnum::Facebook,
+        user_info.first_name,
+        user_info.last_name,
+        user_info.date_of_birth,
+        user_info.email,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        facebook_user.date_of_birth,
+        chrono::NaiveDate::from_ymd_opt(1990, 1, 31)
+    );
+    delete_user(&db, facebook_user).await;
 
     // clean user
-    let user = users_service::find_one_by_email(&db, &email.to_lowercase())
+    let user = users_service::find_one_by_email(&db.session(), &email.to_lowercase())
         .await
+        .unwrap()
         .unwrap();
     delete_user(&db, user).await;
 }
 
 #[actix_web::test]
-async fn test_confirm_email() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let user = create_user(&db, false).await;
-    let token = create_token(&jwt, &user, Some(TokenType::Confirmation)).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+async fn test_sign_up_conflict_details() {
+    let app = TestApp::new().await;
+    let local = app.create_user(true).await;
+    let oauth = users_service::create_user(
+        &app.db.session(),
+        &Webhooks::disabled(),
+        Name(EN).fake(),
+        Name(EN).fake(),
+        None,
+        format!("{}@gmail.com", Uuid::new_v4()),
+        user::NO_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Google,
+        users_service::UserPreferences::default(),
+        None,
     )
+    .await
+    .unwrap();
+    let sign_up = |email: &str| {
+        json!({
+            "email": email,
+            "first_name": "Conflict",
+            "last_name": "Details",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+        })
+    };
+
+    // Only the reason is sent, never who the account belongs to
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(&local.email))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &409);
+    let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+    assert_eq!(body["message"], json!("User already exists"));
+    assert_eq!(body["details"], json!({ "reason": "exists" }));
+
+    // Aliases resolve to the same account
+    let alias = oauth.email.replacen('@', "+alias@", 1);
+    let resp = app.post_json("/api/auth/sign-up", sign_up(&alias)).await;
+    assert_eq!(&resp.status().as_u16(), &409);
+    let body = test::read_body_json::<serde_json::Value, _>(resp).await;
+    assert_eq!(
+        body["details"],
+        json!({ "reason": "exists_oauth", "providers": ["GOOGLE"] })
+    );
+    assert!(!body.to_string().contains(&oauth.email));
+
+    // clean users
+    for email in [&local.email, &oauth.email] {
+        email_outbox::Entity::delete_many()
+            .filter(email_outbox::Column::Recipient.eq(email.clone()))
+            .exec(app.db.get_connection())
+            .await
+            .unwrap();
+    }
+    delete_user(&app.db, local).await;
+    delete_user(&app.db, oauth).await;
+}
+
+#[actix_web::test]
+async fn test_idempotent_sign_up() {
+    let app = TestApp::new().await;
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let body = json!({
+        "email": &email,
+        "first_name": "Ada",
+        "last_name": "Lovelace",
+        "date_of_birth": "1990-01-01",
+        "password1": VALID_PASSWORD,
+        "password2": VALID_PASSWORD,
+        "accepted_terms": true,
+    });
+    let sign_up = |key: &str, body: &serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/api/auth/sign-up")
+            .insert_header((IDEMPOTENCY_KEY_HEADER, key))
+            .set_json(body)
+            .to_request()
+    };
+    let key = Uuid::new_v4().to_string();
+
+    // A retry with the same key replays the first response
+    let first = app.call(sign_up(&key, &body)).await;
+    assert_eq!(first.status().as_u16(), 200);
+    let first = test::read_body(first).await;
+    let retry = app.call(sign_up(&key, &body)).await;
+    assert_eq!(retry.status().as_u16(), 200);
+    assert_eq!(test::read_body(retry).await, first);
+    assert_eq!(captured_emails(&email).len(), 1);
+    let users = user::Entity::find()
+        .filter(user::Column::Email.eq(&email))
+        .all(app.db.get_connection())
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 1);
+
+    // The key can not be reused for another request
+    let mut other_body = body.clone();
+    other_body["first_name"] = json!("Grace");
+    let resp = app.call(sign_up(&key, &other_body)).await;
+    assert_eq!(resp.status().as_u16(), 400);
+
+    // Another key runs the request again
+    let resp = app.call(sign_up(&Uuid::new_v4().to_string(), &body)).await;
+    assert_eq!(resp.status().as_u16(), 409);
+    let resp = app.call(sign_up("not-a-uuid", &body)).await;
+    assert_eq!(resp.status().as_u16(), 400);
+
+    email_outbox::Entity::delete_many()
+        .filter(email_outbox::Column::Recipient.eq(&email))
+        .exec(app.db.get_connection())
+        .await
+        .unwrap();
+    for user in users {
+        user.delete(app.db.get_connection()).await.unwrap();
+    }
+}
+
+#[actix_web::test]
+async fn test_idempotent_sign_in_keeps_no_tokens() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let user = set_two_factor(&app.db, &user, false).await;
+    let key = Uuid::new_v4().to_string();
+    let sign_in = || {
+        test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .insert_header((IDEMPOTENCY_KEY_HEADER, key.as_str()))
+            .set_json(json!({ "email": &user.email, "password": VALID_PASSWORD }))
+            .to_request()
+    };
+
+    // Token responses are not stored, a retry signs in again with fresh tokens
+    let first = app.call(sign_in()).await;
+    assert_eq!(first.status().as_u16(), 200);
+    assert!(first.response().cookies().next().is_some());
+    let first: serde_json::Value = test::read_body_json(first).await;
+    assert!(first["access_token"].is_string());
+    let stored = app
+        .cache
+        .get_json::<serde_json::Value>(&format!("idem:sign-in:{}", key))
+        .await
+        .unwrap();
+    assert!(stored.is_none());
+    let retry = app.call(sign_in()).await;
+    assert_eq!(retry.status().as_u16(), 200);
+    let retry: serde_json::Value = test::read_body_json(retry).await;
+    assert_ne!(retry["refresh_token"], first["refresh_token"]);
+
+    delete_user(&app.db, user).await;
+}
+
+#[actix_web::test]
+async fn test_sign_up_preferences() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let first_name: String = Name(EN).fake();
+    let last_name: String = Name(EN).fake();
+    let sign_up = |locale: &str, timezone: &str| {
+        json!({
+            "email": &email,
+            "first_name": &first_name,
+            "last_name": &last_name,
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+            "locale": locale,
+            "timezone": timezone,
+        })
+    };
+
+    // Unsupported locales and unknown timezones are rejected
+    for body in [
+        sign_up("fr-FR", "Europe/Lisbon"),
+        sign_up("pt-BR", "Europe/Atlantis"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/auth/sign-up")
+            .set_json(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(&resp.status().as_u16(), &400);
+    }
 
-    // Success confirm email
     let req = test::TestRequest::post()
-        .uri("/api/auth/confirm-email")
-        .set_json(json!({
-            "confirmation_token": &token,
-        }))
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up("pt-br", "Europe/Lisbon"))
         .to_request();
     let resp = test::call_service(&app, req).await;
     assert!(&resp.status().is_success());
-    assert_eq!(&resp.status().as_u16(), &200);
-    check_is_auth_response(
-        to_bytes(resp.into_body())
-            .await
-            .unwrap()
-            .as_str()
-            .to_owned(),
-    );
+    let user = users_service::find_one_by_email(&db.session(), &email)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.preferred_locale, "pt-BR");
+    assert_eq!(user.timezone, "Europe/Lisbon");
+    email_outbox::Entity::delete_many()
+        .filter(email_outbox::Column::Recipient.eq(&email))
+        .exec(db.get_connection())
+        .await
+        .unwrap();
 
-    // User already confirmed
+    // Confirm the email to read the preferences back through the API
+    let token = create_token(&jwt, &user, Some(TokenType::Confirmation)).await;
     let req = test::TestRequest::post()
         .uri("/api/auth/confirm-email")
+        .set_json(json!({ "confirmation_token": &token }))
+        .to_request();
+    let auth: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let access_token = auth["access_token"].as_str().unwrap();
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
+        .set_json(json!({ "query": "query { me { locale timezone } }" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["me"]["locale"], "pt-BR");
+    assert_eq!(body["data"]["me"]["timezone"], "Europe/Lisbon");
+
+    // Omitted fields keep their value
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
         .set_json(json!({
-            "confirmation_token": &token,
+            "query": "mutation { updateUserPreferences(input: { timezone: \"America/Sao_Paulo\" }) { locale timezone } }",
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &401);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["updateUserPreferences"]["locale"], "pt-BR");
+    assert_eq!(
+        body["data"]["updateUserPreferences"]["timezone"],
+        "America/Sao_Paulo"
+    );
 
-    // Invalid token
     let req = test::TestRequest::post()
-        .uri("/api/auth/confirm-email")
+        .uri("/api/graphql")
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
         .set_json(json!({
-            "confirmation_token": "invalid_token",
+            "query": "mutation { updateUserPreferences(input: { locale: \"xx\" }) { locale } }",
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_array());
 
     // clean user
+    let user = users_service::find_one_by_email(&db.session(), &email)
+        .await
+        .unwrap()
+        .unwrap();
     delete_user(&db, user).await;
 }
 
 #[actix_web::test]
-async fn test_sign_in() {
-    let (environment, db, _, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+async fn test_sign_up_email_aliases() {
+    let app = TestApp::new().await;
+    let id = Uuid::new_v4().simple().to_string();
+    let alias = format!("John.{}+shop@gmail.com", id);
+    let sign_up = |email: &str| {
+        json!({
+            "email": email,
+            "first_name": "John",
+            "last_name": "Doe",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+        })
+    };
+
+    // The alias is kept as the address of the account
+    let resp = app.post_json("/api/auth/sign-up", sign_up(&alias)).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let user =
+        users_service::find_one_by_email(&app.db.session(), &format!("john{}@gmail.com", id))
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(user.email, alias.to_lowercase());
+    let mut active_user: user::ActiveModel = user.into();
+    active_user.confirmed = Set(true);
+    active_user.update(app.db.get_connection()).await.unwrap();
+
+    // Sign in without the alias
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": format!("john{}@gmail.com", id),
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("Confirmation code sent, check your email"));
+
+    // A dot variant reaches the same inbox
+    let resp = app
+        .post_json(
+            "/api/auth/sign-up",
+            sign_up(&format!("j.o.h.n{}@gmail.com", id)),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &409);
+}
+
+#[actix_web::test]
+async fn test_sign_up_invite_only() {
+    let (mut config, db, jwt, _) = create_base_config().await;
+    config.sign_up_mode = SignUpMode::InviteOnly;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
+    let admin = create_user(&db, true).await;
+    let mut admin: user::ActiveModel = admin.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let sign_up_body = |email: &str, invitation_token: Option<&str>| {
+        json!({
+            "email": email,
+            "first_name": "Invited",
+            "last_name": "User",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+            "invitation_token": invitation_token,
+        })
+    };
 
-    // Success sign in MFA
+    // Admins invite through GraphQL, the token only travels in the email
     let req = test::TestRequest::post()
-        .uri("/api/auth/sign-in")
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
         .set_json(json!({
-            "email": &user.email.to_uppercase(),
-            "password": VALID_PASSWORD,
+            "query": format!(
+                r#"mutation {{ inviteUser(email: "{}") {{ id email invitedBy }} }}"#,
+                email
+            ),
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
-    assert_eq!(&resp.status().as_u16(), &200);
-    assert!(to_bytes(resp.into_body())
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["inviteUser"]["email"], email.as_str());
+    assert_eq!(body["data"]["inviteUser"]["invitedBy"], admin.id);
+    let invitation_id = body["data"]["inviteUser"]["id"].as_i64().unwrap() as i32;
+    let invitation_email = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.eq(&email))
+        .one(db.get_connection())
         .await
         .unwrap()
-        .as_str()
-        .contains("Confirmation code sent, check your email"));
+        .unwrap();
+    // Sent emails keep no body, the link is only in the delivered one
+    assert_eq!(invitation_email.status, enums::EmailStatusEnum::Sent);
+    assert!(invitation_email.body.is_empty());
+    let sent = captured_emails(&email);
+    let token = sent[0]
+        .body
+        .split("/invitation/")
+        .nth(1)
+        .and_then(|link| link.split('\'').next())
+        .unwrap()
+        .to_string();
+    assert!(token.starts_with(&format!("{}.", invitation_id)));
 
-    // Success sign in no MFA
-    // set two_factor to false
-    let oauth_provider = oauth_provider::Entity::find_by_email_and_provider(
-        &user.email,
-        enums::OAuthProviderEnum::Local,
-    )
-    .one(db.get_connection())
-    .await
-    .unwrap()
-    .unwrap();
-    let mut oauth_provider: oauth_provider::ActiveModel = oauth_provider.into();
-    oauth_provider.two_factor = Set(false);
-    oauth_provider.update(db.get_connection()).await.unwrap();
-    // run test
+    // Without a token
     let req = test::TestRequest::post()
-        .uri("/api/auth/sign-in")
-        .set_json(json!({
-            "email": &user.email.to_uppercase(),
-            "password": VALID_PASSWORD,
-        }))
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up_body(&email, None))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
-    assert_eq!(&resp.status().as_u16(), &200);
-    check_is_auth_response(
-        to_bytes(resp.into_body())
+    assert_eq!(resp.status().as_u16(), 403);
+
+    // The token belongs to another email
+    let other_email = format!("{}@gmail.com", Uuid::new_v4());
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up_body(&other_email, Some(&token)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 403);
+    assert!(
+        users_service::find_one_by_email(&db.session(), &other_email)
             .await
             .unwrap()
-            .as_str()
-            .to_owned(),
+            .is_none()
     );
 
-    // Invalid password
+    // Success sign up
     let req = test::TestRequest::post()
-        .uri("/api/auth/sign-in")
-        .set_json(json!({
-            "email": &user.email,
-            "password": "invalid_password",
-        }))
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up_body(&email, Some(&token)))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &401);
-
-    // clean user
-    delete_user(&db, user).await;
-}
-
-#[actix_web::test]
-async fn test_confirm_sign_in() {
-    let (environment, db, _, cache) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
-    .await;
-
-    // Generate code
-    let code = "123456";
-    let code_hash = hash(code, 5).unwrap();
-    let key = format!("access_code:{}", &user.email);
-    let mut connection = cache.get_connection().await.unwrap();
-    connection
-        .set_ex::<&str, &str, ()>(&key, &code_hash, 600)
+    assert_eq!(resp.status().as_u16(), 200);
+    let invited_user = users_service::find_one_by_email(&db.session(), &email)
         .await
+        .unwrap()
+        .unwrap();
+    let invitation = invitation::Entity::find_by_id(invitation_id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
         .unwrap();
+    assert!(invitation.accepted_at.is_some());
 
-    // Success confirm sign in
+    // Accepted invitations can't be reused
     let req = test::TestRequest::post()
-        .uri("/api/auth/confirm-sign-in")
-        .set_json(json!({
-            "email": &user.email,
-            "code": &code,
-        }))
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up_body(&email, Some(&token)))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
-    assert_eq!(&resp.status().as_u16(), &200);
-    check_is_auth_response(
-        to_bytes(resp.into_body())
-            .await
-            .unwrap()
-            .as_str()
-            .to_owned(),
-    );
+    assert_eq!(resp.status().as_u16(), 403);
 
-    // Invalid code
+    // Revoked invitations leave the pending list
+    let revoked_email = format!("{}@gmail.com", Uuid::new_v4());
     let req = test::TestRequest::post()
-        .uri("/api/auth/confirm-sign-in")
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
         .set_json(json!({
-            "email": &user.email,
-            "code": "654321",
+            "query": format!(r#"mutation {{ inviteUser(email: "{}") {{ id }} }}"#, revoked_email),
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &401);
-
-    // Invalid email
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let revoked_id = body["data"]["inviteUser"]["id"].as_i64().unwrap();
+    let pending_query = json!({ "query": "query { pendingInvitations { id } }" });
+    let pending_ids = |body: serde_json::Value| {
+        body["data"]["pendingInvitations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|invitation| invitation["id"].as_i64().unwrap())
+            .collect::<Vec<i64>>()
+    };
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(&pending_query)
+        .to_request();
+    let ids = pending_ids(test::call_and_read_body_json(&app, req).await);
+    assert!(ids.contains(&revoked_id));
+    assert!(!ids.contains(&i64::from(invitation_id)));
     let req = test::TestRequest::post()
-        .uri("/api/auth/confirm-sign-in")
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
         .set_json(json!({
-            "email": "not_an_email",
-            "code": &code,
+            "query": format!(r#"mutation {{ revokeInvitation(id: {}) {{ message }} }}"#, revoked_id),
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
-
-    // clean user
-    delete_user(&db, user).await;
-}
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(&pending_query)
+        .to_request();
+    let ids = pending_ids(test::call_and_read_body_json(&app, req).await);
+    assert!(!ids.contains(&revoked_id));
 
-#[actix_web::test]
-async fn test_sign_out() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let token = create_token(&jwt, &user, Some(TokenType::Refresh)).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+    // Provider sign ups need a pending invitation for the address they come back with
+    let provider_email = format!("{}@gmail.com", Uuid::new_v4());
+    let error = users_service::find_or_create(
+        &db.session(),
+        &Webhooks::disabled(),
+        &TermsVersion::default(),
+        SignUpMode::InviteOnly,
+        enums::OAuthProviderEnum::Google,
+        "Ada".to_string(),
+        "Lovelace".to_string(),
+        None,
+        provider_email.clone(),
     )
-    .await;
-
-    // Success sign out
+    .await
+    .unwrap_err();
+    assert_eq!(error.get_status_code(), 403);
+    assert!(
+        users_service::find_one_by_email(&db.session(), &provider_email)
+            .await
+            .unwrap()
+            .is_none()
+    );
     let req = test::TestRequest::post()
-        .uri("/api/auth/sign-out")
+        .uri("/api/graphql")
+        .insert_header(("Authorization", admin_token.as_str()))
         .set_json(json!({
-            "refresh_token": &token,
+            "query": format!(r#"mutation {{ inviteUser(email: "{}") {{ id }} }}"#, provider_email),
         }))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
-    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let provider_invitation_id = body["data"]["inviteUser"]["id"].as_i64().unwrap() as i32;
+    let provider_user = users_service::find_or_create(
+        &db.session(),
+        &Webhooks::disabled(),
+        &TermsVersion::default(),
+        SignUpMode::InviteOnly,
+        enums::OAuthProviderEnum::Google,
+        "Ada".to_string(),
+        "Lovelace".to_string(),
+        None,
+        provider_email.clone(),
+    )
+    .await
+    .unwrap();
+    let provider_invitation = invitation::Entity::find_by_id(provider_invitation_id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(provider_invitation.accepted_at.is_some());
 
-    // Invalid refresh token
+    // Open mode ignores the token, as before
+    let config = Config {
+        sign_up_mode: SignUpMode::Open,
+        ..config
+    };
+    let open_app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let open_email = format!("{}@gmail.com", Uuid::new_v4());
     let req = test::TestRequest::post()
-        .uri("/api/auth/sign-out")
-        .set_json(json!({
-            "refresh_token": "invalid_token",
-        }))
+        .uri("/api/auth/sign-up")
+        .set_json(sign_up_body(&open_email, Some("not.a-token")))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    let resp = test::call_service(&open_app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let open_user = users_service::find_one_by_email(&db.session(), &open_email)
+        .await
+        .unwrap()
+        .unwrap();
 
-    // clean user
-    delete_user(&db, user).await;
+    // clean up
+    let emails = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.is_in([
+            email.clone(),
+            revoked_email,
+            open_email,
+            provider_email,
+        ]))
+        .all(db.get_connection())
+        .await
+        .unwrap();
+    for email in emails {
+        email.delete(db.get_connection()).await.unwrap();
+    }
+    delete_user(&db, invited_user).await;
+    delete_user(&db, provider_user).await;
+    delete_user(&db, open_user).await;
+    delete_user(&db, admin).await;
 }
 
 #[actix_web::test]
-async fn test_refresh_token() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let token = create_token(&jwt, &user, Some(TokenType::Refresh)).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
-    .await;
+async fn test_confirm_email() {
+    let app = TestApp::new().await;
+    let user = app.create_user(false).await;
+    let token = app.token_for(&user, TokenType::Confirmation);
 
-    // Success refresh token
-    let req = test::TestRequest::post()
-        .uri("/api/auth/refresh-token")
-        .set_json(json!({
-            "refresh_token": &token,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
+    // Success confirm email
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-email",
+            json!({
+                "confirmation_token": &token,
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
 
-    // clean user
-    delete_user(&db, user).await;
+    // User already confirmed
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-email",
+            json!({
+                "confirmation_token": &token,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Invalid token
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-email",
+            json!({
+                "confirmation_token": "invalid_token",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
 }
 
 #[actix_web::test]
-async fn test_forgot_password() {
-    let (environment, db, _, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
-    .await;
+async fn test_sign_in() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
 
-    // Success forgot password
-    let req = test::TestRequest::post()
-        .uri("/api/auth/forgot-password")
-        .set_json(json!({
-            "email": &user.email,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
+    // Success sign in MFA
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": &user.email.to_uppercase(),
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &200);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("Confirmation code sent, check your email"));
 
-    // Should succed for a random email
-    let req = test::TestRequest::post()
-        .uri("/api/auth/forgot-password")
-        .set_json(json!({
-            "email": format!("{}@gmail.com", Uuid::new_v4().to_string()),
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
+    // Success sign in no MFA
+    // set two_factor to false
+    set_two_factor(&app.db, &user, false).await;
+    // run test
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": &user.email.to_uppercase(),
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
 
-    // Invalid email
-    let req = test::TestRequest::post()
-        .uri("/api/auth/forgot-password")
-        .set_json(json!({
-            "email": "not_an_email",
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
-
-    // clean user
-    delete_user(&db, user).await;
+    // Invalid password
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": &user.email,
+                "password": "invalid_password",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
 }
 
 #[actix_web::test]
-async fn test_reset_password() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let token = create_token(&jwt, &user, Some(TokenType::Reset)).await;
-    let new_password = "New_Password12".to_string();
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+async fn test_case_insensitive_email() {
+    let app = TestApp::new().await;
+    let mixed_email = format!("Mixed.{}@Example.com", Uuid::new_v4().simple());
+    let user = users_service::create_user(
+        &app.db.session(),
+        &Webhooks::disabled(),
+        Name(EN).fake(),
+        Name(EN).fake(),
+        Some("1990-01-01".to_string()),
+        mixed_email.clone(),
+        VALID_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Local,
+        users_service::UserPreferences::default(),
+        None,
     )
-    .await;
-
-    // Invalid password
-    let req = test::TestRequest::post()
-        .uri("/api/auth/reset-password")
-        .set_json(json!({
-            "reset_token": &token,
-            "password1": "invalid_password",
-            "password2": "invalid_password",
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    .await
+    .unwrap();
+    let mut confirmed: user::ActiveModel = user.into();
+    confirmed.confirmed = Set(true);
+    let user = confirmed.update(app.db.get_connection()).await.unwrap();
+    assert_eq!(user.email, mixed_email.to_lowercase());
 
-    // Success reset password
-    let req = test::TestRequest::post()
-        .uri("/api/auth/reset-password")
-        .set_json(json!({
-            "reset_token": &token,
-            "password1": &new_password,
-            "password2": &new_password,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
+    // Lookups and sign in ignore the casing
+    let found = user::Entity::find_by_email(&mixed_email.to_uppercase())
+        .one(app.db.get_connection())
+        .await
+        .unwrap();
+    assert_eq!(found.map(|found| found.id), Some(user.id));
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": mixed_email.to_uppercase(),
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &200);
 
-    // Invalid token
-    let req = test::TestRequest::post()
-        .uri("/api/auth/reset-password")
-        .set_json(json!({
-            "reset_token": "invalid_token",
-            "password1": &new_password,
-            "password2": &new_password,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    let other = app.create_user(true).await;
+    let body = app
+        .graphql_as(
+            &other,
+            &format!(
+                r#"mutation {{ updateUserEmail(email: "{}") {{ id }} }}"#,
+                mixed_email.to_uppercase()
+            ),
+        )
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], json!("409"));
 
-    // clean user
-    delete_user(&db, user).await;
+    // A write skipping the application checks is still a conflict, not a server error
+    let mut changes: user::ActiveModel = other.into();
+    changes.email = Set(mixed_email.to_uppercase());
+    changes.normalized_email = Set(format!("{}@example.com", Uuid::new_v4()));
+    let error = changes.update(app.db.get_connection()).await.unwrap_err();
+    assert!(matches!(
+        ServiceError::from(error),
+        ServiceError::Conflict(message, None) if message == "Email already in use"
+    ));
 }
 
 #[actix_web::test]
-async fn test_update_password() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let user = create_user(&db, true).await;
-    let token = create_token(&jwt, &user, None).await;
-    let bearer_token = format!("Bearer {}", &token);
-    let authorization_header = ("Authorization", bearer_token.as_str());
-    let new_password = "New_Password12".to_string();
-    let new_password2 = new_password.clone();
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+async fn test_sign_in_social_login_account() {
+    let app = TestApp::new().await;
+    let user = users_service::create_user(
+        &app.db.session(),
+        &Webhooks::disabled(),
+        Name(EN).fake(),
+        Name(EN).fake(),
+        None,
+        format!("{}@gmail.com", Uuid::new_v4()),
+        "none".to_string(),
+        enums::OAuthProviderEnum::Google,
+        users_service::UserPreferences::default(),
+        None,
     )
-    .await;
-
-    // Invalid password
-    let req = test::TestRequest::post()
-        .uri("/api/auth/update-password")
-        .insert_header(authorization_header)
-        .set_json(json!({
-            "old_password": VALID_PASSWORD,
-            "password1": "invalid_password",
-            "password2": "invalid_password",
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    .await
+    .unwrap();
 
-    // Passwords do not match
-    let req = test::TestRequest::post()
-        .uri("/api/auth/update-password")
-        .insert_header(authorization_header)
-        .set_json(json!({
-            "old_password": VALID_PASSWORD,
-            "password1": &new_password,
-            "password2": &format!("{}_e", &new_password2),
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
-    assert_eq!(&resp.status().as_u16(), &400);
+    // Never locked, the password is not even checked
+    for _ in 0..app.config.sign_in_max_attempts + 1 {
+        let resp = app
+            .post_json(
+                "/api/auth/sign-in",
+                json!({
+                    "email": &user.email,
+                    "password": VALID_PASSWORD,
+                }),
+            )
+            .await;
+        assert_eq!(&resp.status().as_u16(), &401);
+        assert!(test::read_body(resp)
+            .await
+            .as_str()
+            .contains("This account uses social login"));
+    }
+}
 
-    // Success update password
-    let req = test::TestRequest::post()
-        .uri("/api/auth/update-password")
-        .insert_header(authorization_header)
-        .set_json(json!({
-            "old_password": VALID_PASSWORD,
-            "password1": &new_password,
-            "password2": &new_password2,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
+#[actix_web::test]
+async fn test_sign_in_two_factor_parity() {
+    let app = TestApp::new().await;
+
+    for two_factor in [true, false] {
+        let user = app.create_user(false).await;
+        let user = set_two_factor(&app.db, &user, two_factor).await;
+
+        // Unconfirmed, even with an invalid password
+        let resp = app
+            .post_json(
+                "/api/auth/sign-in",
+                json!({
+                    "email": &user.email,
+                    "password": "invalid_password",
+                }),
+            )
+            .await;
+        assert_eq!(&resp.status().as_u16(), &401);
+        assert!(test::read_body(resp)
+            .await
+            .as_str()
+            .contains("Please confirm your email"));
+
+        // Suspended, even with an invalid password
+        let mut active_user: user::ActiveModel = user.into();
+        active_user.confirmed = Set(true);
+        active_user.suspended = Set(true);
+        let user = active_user.update(app.db.get_connection()).await.unwrap();
+        let resp = app
+            .post_json(
+                "/api/auth/sign-in",
+                json!({
+                    "email": &user.email,
+                    "password": "invalid_password",
+                }),
+            )
+            .await;
+        assert_eq!(&resp.status().as_u16(), &403);
+
+        // Invalid password
+        let mut active_user: user::ActiveModel = user.into();
+        active_user.suspended = Set(false);
+        let user = active_user.update(app.db.get_connection()).await.unwrap();
+        let resp = app
+            .post_json(
+                "/api/auth/sign-in",
+                json!({
+                    "email": &user.email,
+                    "password": "invalid_password",
+                }),
+            )
+            .await;
+        assert_eq!(&resp.status().as_u16(), &401);
+        assert!(test::read_body(resp)
+            .await
+            .as_str()
+            .contains("Invalid credentials"));
+
+        // Valid password
+        let resp = app
+            .post_json(
+                "/api/auth/sign-in",
+                json!({
+                    "email": &user.email,
+                    "password": VALID_PASSWORD,
+                }),
+            )
+            .await;
+        assert_eq!(&resp.status().as_u16(), &200);
+        let body = test::read_body(resp).await.as_str().to_owned();
+        if two_factor {
+            assert!(body.contains("Confirmation code sent, check your email"));
+        } else {
+            check_is_auth_response(body);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn test_oauth_sign_in_two_factor() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    assert!(user.two_factor);
+    let mailer = Mailer::new(&app.config.environment, &app.config.mailer, &Metrics::new());
+    let session = app.db.session();
+    let webhooks = Webhooks::disabled();
+    let device_alerts = DeviceAlerts::disabled();
+    let client_info = ClientInfo::default();
+    let oauth_sign_in = || {
+        auth_service::oauth_sign_in_callback(
+            &session,
+            &app.cache,
+            &app.jwt,
+            &mailer,
+            &webhooks,
+            &app.config.terms_version,
+            &device_alerts,
+            SignUpMode::Open,
+            ExternalProvider::Google,
+            responses::UserInfo {
+                first_name: user.first_name.clone(),
+                last_name: user.last_name.clone(),
+                email: user.email.clone(),
+                date_of_birth: None,
+                picture: None,
+            },
+            &client_info,
+        )
+    };
+
+    // Google asks for the emailed code like the password does
+    match oauth_sign_in().await.unwrap() {
+        responses::OAuthCallback::Mfa(email) => assert_eq!(email, user.email),
+        callback => panic!("Expected a code to be required, got {:?}", callback),
+    }
+    let redirect =
+        OAuth::new(&app.config.oauth, &app.config.http_client).get_mfa_redirect(&user.email);
+    assert!(redirect.contains("#mfa=true&email="));
+    let code = "123456";
+    let key = format!("access_code:{}", &user.email);
+    let mut connection = app.cache.get_connection().await.unwrap();
+    connection
+        .set_ex::<&str, &str, ()>(&key, &hash_code(code), 600)
+        .await
+        .unwrap();
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": &user.email,
+                "code": code,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
+
+    // The local path reads the same flag
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": &user.email,
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("Confirmation code sent, check your email"));
+
+    // Turned off, both sign in straight away
+    set_two_factor(&app.db, &user, false).await;
+    assert!(matches!(
+        oauth_sign_in().await.unwrap(),
+        responses::OAuthCallback::Auth(_)
+    ));
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({
+                "email": &user.email,
+                "password": VALID_PASSWORD,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
+}
+
+#[actix_web::test]
+async fn test_sign_in_upgrades_bcrypt_password() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let mut bcrypt_user: user::ActiveModel = user.into();
+    bcrypt_user.password = Set(bcrypt::hash(VALID_PASSWORD, 4).unwrap());
+    let user = bcrypt_user.update(app.db.get_connection()).await.unwrap();
+    assert!(user.password.starts_with("$2b$"));
+
+    // Success sign in with legacy hash
+    let body = json!({
+        "email": &user.email,
+        "password": VALID_PASSWORD,
+    });
+    let resp = app.post_json("/api/auth/sign-in", body.clone()).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Password was re-hashed with argon2id
+    let user = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(user.password.starts_with("$argon2id$"));
+
+    // Sign in still works with the new hash
+    let resp = app.post_json("/api/auth/sign-in", body).await;
     assert!(&resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_confirm_sign_in() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+
+    // Generate code
+    let code = "123456";
+    let code_hash = hash_code(code);
+    let key = format!("access_code:{}", &user.email);
+    let mut connection = app.cache.get_connection().await.unwrap();
+    connection
+        .set_ex::<&str, &str, ()>(&key, &code_hash, 600)
+        .await
+        .unwrap();
+
+    // Success confirm sign in
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": &user.email,
+                "code": &code,
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
 
-    // Invalid token
-    let req = test::TestRequest::post()
-        .uri("/api/auth/update-password")
-        .insert_header(("Authorization", "invalid_token"))
-        .set_json(json!({
-            "old_password":&new_password,
-            "password1": VALID_PASSWORD,
-            "password2": VALID_PASSWORD,
-        }))
-        .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
+    // Invalid code
+    connection
+        .set_ex::<&str, &str, ()>(&key, &code_hash, 600)
+        .await
+        .unwrap();
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": &user.email,
+                "code": "654321",
+            }),
+        )
+        .await;
     assert_eq!(&resp.status().as_u16(), &401);
+    let wrong_code: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(wrong_code["message"], "Invalid code");
 
-    // clean user
-    delete_user(&db, user).await;
+    // An unknown email fails the same way as a wrong code
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": format!("{}@gmail.com", Uuid::new_v4()),
+                "code": "654321",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    let unknown_email: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(unknown_email["message"], wrong_code["message"]);
+
+    // So does an existing email without a pending code
+    connection.del::<&str, ()>(&key).await.unwrap();
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": &user.email,
+                "code": &code,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    let no_code: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(no_code["message"], wrong_code["message"]);
+
+    // Invalid email
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-sign-in",
+            json!({
+                "email": "not_an_email",
+                "code": &code,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+}
+
+/// The email is sent in the background, after the sign in answered.
+async fn new_device_alerts(email: &str) -> usize {
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+    captured_emails(email)
+        .iter()
+        .filter(|email| email.subject.starts_with("New sign in to your account"))
+        .count()
 }
 
 #[actix_web::test]
-async fn test_update_two_factor() {
-    let (environment, db, jwt, _) = create_base_config().await;
+async fn test_new_device_alerts() {
+    let app = TestApp::new().await;
+    let user = set_two_factor(&app.db, &app.create_user(true).await, false).await;
+    let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+    let sign_in = |ip: &str| {
+        test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .insert_header(("User-Agent", firefox))
+            .insert_header(("X-Forwarded-For", ip))
+            .set_json(json!({
+                "email": &user.email,
+                "password": VALID_PASSWORD,
+            }))
+            .to_request()
+    };
+
+    // The first sign in from a device is new
+    assert_eq!(
+        app.call(sign_in("203.0.113.7")).await.status().as_u16(),
+        200
+    );
+    assert_eq!(new_device_alerts(&user.email).await, 1);
+    let sent = captured_emails(&user.email);
+    assert!(sent.iter().any(|email| email.body.contains("203.0.113.7")));
+
+    // Another address on the same network is the same device
+    assert_eq!(
+        app.call(sign_in("203.0.113.42")).await.status().as_u16(),
+        200
+    );
+    assert_eq!(new_device_alerts(&user.email).await, 1);
+    let device_key = "Firefox|203.0.113.0/24";
+    let body = app
+        .graphql_as(
+            &user,
+            "{ mySecurity { knownDevices { deviceKey userAgent network } } }",
+        )
+        .await;
+    assert_eq!(
+        body["data"]["mySecurity"]["knownDevices"],
+        json!([{
+            "deviceKey": device_key,
+            "userAgent": "Firefox",
+            "network": "203.0.113.0/24",
+        }])
+    );
+
+    // A forgotten device alerts again
+    let forget = format!(
+        r#"mutation {{ forgetDevice(deviceKey: "{}") {{ message }} }}"#,
+        device_key
+    );
+    let body = app.graphql_as(&user, &forget).await;
+    assert_eq!(
+        body["data"]["forgetDevice"]["message"],
+        "Device forgotten successfully"
+    );
+    let body = app.graphql_as(&user, &forget).await;
+    assert_eq!(body["errors"][0]["message"], "Device not found");
+    assert_eq!(
+        app.call(sign_in("203.0.113.7")).await.status().as_u16(),
+        200
+    );
+    assert_eq!(new_device_alerts(&user.email).await, 2);
+}
+
+#[actix_web::test]
+async fn test_recovery_codes() {
+    let (config, db, jwt, cache) = create_base_config().await;
     let user = create_user(&db, true).await;
-    let token = create_token(&jwt, &user, None).await;
-    let bearer_token = format!("Bearer {}", &token);
-    let authorization_header = ("Authorization", bearer_token.as_str());
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+    let access_token = create_token(&jwt, &user, None).await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let graphql = |query: &str| {
+        test::TestRequest::post()
+            .uri("/api/graphql")
+            .insert_header(("Authorization", format!("Bearer {}", &access_token)))
+            .set_json(json!({ "query": query }))
+            .to_request()
+    };
+    let remaining_codes = || async {
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            graphql("query { mySecurity { remainingRecoveryCodes } }"),
+        )
+        .await;
+        body["data"]["mySecurity"]["remainingRecoveryCodes"]
+            .as_u64()
+            .unwrap()
+    };
+    let mut connection = cache.get_connection().await.unwrap();
+    let key = format!("access_code:{}", &user.email);
+    let confirm_sign_in = |recovery_code: &str| {
+        test::TestRequest::post()
+            .uri("/api/auth/confirm-sign-in")
+            .set_json(json!({
+                "email": &user.email,
+                "recovery_code": recovery_code,
+            }))
+            .to_request()
+    };
+
+    // Generating again replaces the previous set
+    let body: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        graphql("mutation { generateRecoveryCodes { codes } }"),
+    )
+    .await;
+    let previous_code = body["data"]["generateRecoveryCodes"]["codes"][0]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let body: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        graphql("mutation { generateRecoveryCodes { codes } }"),
     )
     .await;
+    let codes = body["data"]["generateRecoveryCodes"]["codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code.as_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(codes.len(), recovery_codes_service::RECOVERY_CODES_COUNT);
+    assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+    assert_eq!(remaining_codes().await, 10);
 
-    // Success update two factor
+    // A recovery code needs a pending sign in
+    let resp = test::call_service(&app, confirm_sign_in(&codes[0])).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Success sign in, codes are accepted regardless of casing
+    connection
+        .set_ex::<&str, &str, ()>(&key, &hash_code("123456"), 600)
+        .await
+        .unwrap();
+    let resp = test::call_service(&app, confirm_sign_in(&codes[0].to_uppercase())).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(
+        to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .as_str()
+            .to_owned(),
+    );
+    assert_eq!(remaining_codes().await, 9);
+
+    // Used and replaced codes are rejected
+    for code in [&codes[0], &previous_code] {
+        connection
+            .set_ex::<&str, &str, ()>(&key, &hash_code("123456"), 600)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, confirm_sign_in(code)).await;
+        assert_eq!(&resp.status().as_u16(), &401);
+    }
+    assert_eq!(remaining_codes().await, 9);
+
+    // Only one of two concurrent uses succeeds
+    let (first, second) = futures::join!(
+        recovery_codes_service::use_code(&db, user.id, &codes[1]),
+        recovery_codes_service::use_code(&db, user.id, &codes[1]),
+    );
+    assert!(first.is_ok() ^ second.is_ok());
+    assert_eq!(remaining_codes().await, 8);
+
+    // clean user
+    connection.del::<&str, ()>(&key).await.unwrap();
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_sign_out() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let token = app.token_for(&user, TokenType::Refresh);
+
+    // Success sign out
+    let resp = app
+        .post_json(
+            "/api/auth/sign-out",
+            json!({
+                "refresh_token": &token,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Invalid refresh token
+    let resp = app
+        .post_json(
+            "/api/auth/sign-out",
+            json!({
+                "refresh_token": "invalid_token",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+}
+
+#[actix_web::test]
+async fn test_sign_out_with_custom_refresh_name() {
+    let refresh_name = "custom_refresh";
+    let app =
+        TestApp::with_config(|config| config.jwt.refresh_name = refresh_name.to_string()).await;
+    let user = app.create_user(true).await;
+    let token = app.token_for(&user, TokenType::Refresh);
+
+    // Cookie with the default name is ignored
     let req = test::TestRequest::post()
-        .uri("/api/auth/update-two-factor")
-        .insert_header(authorization_header)
-        .set_json(json!({
-            "two_factor": false,
-        }))
+        .uri("/api/auth/sign-out")
+        .cookie(Cookie::new("refresh_token", token.clone()))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_success());
+    let resp = app.call(req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
 
-    // Invalid token
+    // Success sign out with cookie only
     let req = test::TestRequest::post()
-        .uri("/api/auth/update-two-factor")
-        .insert_header(("Authorization", "invalid_token"))
-        .set_json(json!({
-            "two_factor": false,
-        }))
+        .uri("/api/auth/sign-out")
+        .cookie(Cookie::new(refresh_name, token))
         .to_request();
-    let resp = test::call_service(&app, req).await;
-    assert!(&resp.status().is_client_error());
+    let resp = app.call(req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let removal_cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == refresh_name)
+        .unwrap();
+    assert!(removal_cookie.value().is_empty());
+}
 
-    // clean user
-    delete_user(&db, user).await;
+#[actix_web::test]
+async fn test_auth_cookie_mode() {
+    let app =
+        TestApp::with_config(|config| config.jwt.access_cookie = Some("access".to_string())).await;
+    let user = app.create_user(true).await;
+    let token = app.token_for(&user, TokenType::Refresh);
+
+    let resp = app
+        .post_json(
+            "/api/auth/refresh-token",
+            json!({ "refresh_token": &token }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let access_cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "access")
+        .unwrap()
+        .into_owned();
+    assert_eq!(access_cookie.http_only(), Some(true));
+    assert_eq!(access_cookie.path(), Some("/api/graphql"));
+    let csrf_cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == CSRF_COOKIE)
+        .unwrap()
+        .into_owned();
+    assert_ne!(csrf_cookie.http_only(), Some(true));
+    let cookie_request = |query: &str| {
+        test::TestRequest::post()
+            .uri("/api/graphql")
+            .cookie(Cookie::new("access", access_cookie.value().to_string()))
+            .cookie(Cookie::new(CSRF_COOKIE, csrf_cookie.value().to_string()))
+            .set_json(json!({ "query": query }))
+    };
+    let mutation = r#"mutation { updateUserName(input: { firstName: "Cookie", lastName: "User" }) { firstName } }"#;
+
+    // Queries only need the cookie
+    let resp = app
+        .call(cookie_request("{ me { firstName } }").to_request())
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["me"]["firstName"], json!(user.first_name));
+
+    // Mutations must echo the CSRF cookie
+    let resp = app.call(cookie_request(mutation).to_request()).await;
+    assert_eq!(&resp.status().as_u16(), &403);
+    let resp = app
+        .call(
+            cookie_request(mutation)
+                .insert_header((CSRF_HEADER, "forged"))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &403);
+    let resp = app
+        .call(
+            cookie_request(mutation)
+                .insert_header((CSRF_HEADER, csrf_cookie.value()))
+                .to_request(),
+        )
+        .await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["updateUserName"]["firstName"], json!("Cookie"));
+
+    // Header clients are not asked for the CSRF token
+    let body = app.graphql_as(&user, mutation).await;
+    assert!(body["errors"].is_null());
+
+    // Signing out clears both cookies
+    let refresh_token = app.token_for(&user, TokenType::Refresh);
+    let resp = app
+        .post_json(
+            "/api/auth/sign-out",
+            json!({ "refresh_token": &refresh_token }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let removed = resp
+        .response()
+        .cookies()
+        .filter(|cookie| cookie.value().is_empty())
+        .map(|cookie| cookie.name().to_string())
+        .collect::<HashSet<String>>();
+    assert!(removed.contains("access"));
+    assert!(removed.contains(CSRF_COOKIE));
+}
+
+#[actix_web::test]
+async fn test_terms_acceptance() {
+    let app = TestApp::with_config(|config| {
+        config.terms_version = TermsVersion::new(Some("2024-01".to_string()))
+    })
+    .await;
+    let sign_up = |email: &str, accepted_terms: bool| {
+        json!({
+            "email": email,
+            "first_name": "Terms",
+            "last_name": "User",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": accepted_terms,
+        })
+    };
+
+    // Sign up without accepting is rejected, accepting records the current version
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(&email, false))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(&email, true))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let signed_up = users_service::find_one_by_email(&app.db.session(), &email)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(signed_up.terms_version.as_deref(), Some("2024-01"));
+
+    // Users behind on the terms can only read their account and accept them
+    let user = app.create_user(true).await;
+    let mutation = r#"mutation { updateUserName(input: { firstName: "Consent", lastName: "User" }) { firstName } }"#;
+    let body = app.graphql_as(&user, mutation).await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], TERMS_OUTDATED);
+    let body = app.graphql_as(&user, "{ me { firstName } }").await;
+    assert_eq!(body["data"]["me"]["firstName"], json!(user.first_name));
+
+    let body = app
+        .graphql_as(&user, "mutation { acceptTerms { firstName } }")
+        .await;
+    assert!(body["errors"].is_null());
+    let body = app.graphql_as(&user, mutation).await;
+    assert!(body["errors"].is_null());
+    assert_eq!(body["data"]["updateUserName"]["firstName"], "Consent");
+}
+
+#[actix_web::test]
+async fn test_refresh_token() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let token = app.token_for(&user, TokenType::Refresh);
+
+    // Success refresh token
+    let resp = app
+        .post_json(
+            "/api/auth/refresh-token",
+            json!({
+                "refresh_token": &token,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[actix_web::test]
+async fn test_refresh_token_session_lifetime() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let auth_time = Utc::now().timestamp() - 3600;
+    let token = app
+        .jwt
+        .generate_refresh_token(&user, auth_time, None)
+        .unwrap();
+
+    // Rotation carries the sign in time over to the new token
+    let resp = app
+        .post_json(
+            "/api/auth/refresh-token",
+            json!({ "refresh_token": &token }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let rotated = body["refresh_token"].as_str().unwrap();
+    let (_, _, _, _, rotated_auth_time, _) = app.jwt.verify_refresh_token(rotated).unwrap();
+    assert_eq!(rotated_auth_time, auth_time);
+
+    // A session past its lifetime must sign in again, however fresh the token
+    let auth_time = Utc::now().timestamp() - app.jwt.get_session_max_lifetime() - 60;
+    let token = app
+        .jwt
+        .generate_refresh_token(&user, auth_time, None)
+        .unwrap();
+    let resp = app
+        .post_json(
+            "/api/auth/refresh-token",
+            json!({ "refresh_token": &token }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], "Session expired, please sign in again");
+
+    // Signing in starts a new session
+    set_two_factor(&app.db, &user, false).await;
+    let resp = app
+        .post_json(
+            "/api/auth/sign-in",
+            json!({ "email": &user.email, "password": VALID_PASSWORD }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let (_, _, _, _, auth_time, _) = app
+        .jwt
+        .verify_refresh_token(body["refresh_token"].as_str().unwrap())
+        .unwrap();
+    assert!(Utc::now().timestamp() - auth_time < 60);
+}
+
+#[actix_web::test]
+async fn test_refresh_token_binding_enforced() {
+    let app = TestApp::with_config(|config| config.jwt.binding = TokenBinding::Enforce).await;
+    let user = set_two_factor(&app.db, &app.create_user(true).await, false).await;
+    let sign_in = |header: (&'static str, &'static str)| {
+        test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .insert_header(header)
+            .set_json(json!({ "email": &user.email, "password": VALID_PASSWORD }))
+            .to_request()
+    };
+    let refresh = |header: (&'static str, &'static str), token: &str| {
+        test::TestRequest::post()
+            .uri("/api/auth/refresh-token")
+            .insert_header(header)
+            .set_json(json!({ "refresh_token": token }))
+            .to_request()
+    };
+    let resp = app.call(sign_in((CLIENT_ID_HEADER, "laptop"))).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+    let (_, _, _, _, _, fingerprint) = app.jwt.verify_refresh_token(&token).unwrap();
+    assert!(fingerprint.is_some());
+
+    // The client the token was issued to refreshes it
+    let resp = app
+        .call(refresh((CLIENT_ID_HEADER, "laptop"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+
+    // Another client is rejected and burns the token for the legitimate one too
+    let resp = app
+        .call(refresh((CLIENT_ID_HEADER, "stolen"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    let (_, _, token_id, _, _, _) = app.jwt.verify_refresh_token(&token).unwrap();
+    assert!(
+        token_blacklist_service::is_blacklisted(&app.db, &app.cache, &token_id)
+            .await
+            .unwrap()
+    );
+    let resp = app
+        .call(refresh((CLIENT_ID_HEADER, "laptop"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Without a client id the user agent family is the fingerprint
+    let resp = app
+        .call(sign_in(("User-Agent", "Mozilla/5.0 Firefox/120.0")))
+        .await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+    let resp = app
+        .call(refresh(("User-Agent", "Mozilla/5.0 Firefox/121.0"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+
+    // A client id sent later still matches the user agent, the rotation binds to it
+    let req = test::TestRequest::post()
+        .uri("/api/auth/refresh-token")
+        .insert_header(("User-Agent", "Mozilla/5.0 Firefox/121.0"))
+        .insert_header((CLIENT_ID_HEADER, "laptop"))
+        .set_json(json!({ "refresh_token": &token }))
+        .to_request();
+    let resp = app.call(req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+    let resp = app
+        .call(refresh(("User-Agent", "curl/8.4.0"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Tokens issued before binding are not bound
+    let token = app.token_for(&user, TokenType::Refresh);
+    let resp = app
+        .call(refresh((CLIENT_ID_HEADER, "anything"), &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[actix_web::test]
+async fn test_refresh_token_binding_warn() {
+    let app = TestApp::with_config(|config| config.jwt.binding = TokenBinding::Warn).await;
+    let user = set_two_factor(&app.db, &app.create_user(true).await, false).await;
+    let sign_in = || {
+        test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .insert_header((CLIENT_ID_HEADER, "laptop"))
+            .set_json(json!({ "email": &user.email, "password": VALID_PASSWORD }))
+            .to_request()
+    };
+    let from_other_client = |path: &str, token: &str| {
+        test::TestRequest::post()
+            .uri(path)
+            .insert_header((CLIENT_ID_HEADER, "stolen"))
+            .set_json(json!({ "refresh_token": token }))
+            .to_request()
+    };
+
+    // The mismatch is only logged
+    let body: serde_json::Value = test::read_body_json(app.call(sign_in()).await).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+    let resp = app
+        .call(from_other_client("/api/auth/refresh-token", &token))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Signing out from another client still revokes the token
+    let body: serde_json::Value = test::read_body_json(app.call(sign_in()).await).await;
+    let token = body["refresh_token"].as_str().unwrap().to_string();
+    let req = from_other_client("/api/auth/sign-out", &token);
+    assert_eq!(app.call(req).await.status().as_u16(), 200);
+    let (_, _, token_id, _, _, _) = app.jwt.verify_refresh_token(&token).unwrap();
+    assert!(
+        token_blacklist_service::is_blacklisted(&app.db, &app.cache, &token_id)
+            .await
+            .unwrap()
+    );
+}
+
+#[actix_web::test]
+async fn test_blacklist_survives_cache_flush() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let token = create_token(&jwt, &user, Some(TokenType::Refresh)).await;
+    let (_, _, token_id, _) = jwt.verify_email_token(TokenType::Refresh, &token).unwrap();
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-out")
+        .set_json(json!({
+            "refresh_token": &token,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Losing the Redis key must not un-revoke the token
+    let key = format!("blacklist_token:{}", &token_id);
+    let key = key.as_str();
+    cache
+        .execute(|mut connection| async move { connection.del::<&str, ()>(key).await })
+        .await
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri("/api/auth/refresh-token")
+        .set_json(json!({
+            "refresh_token": &token,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // The database hit warms the cache again
+    let cached = cache
+        .execute(|mut connection| async move { connection.exists::<&str, bool>(key).await })
+        .await
+        .unwrap();
+    assert!(cached);
+
+    // Expired rows are purged and the gauge only counts the live ones
+    let expired_id = Uuid::new_v4().to_string();
+    blacklisted_token::ActiveModel {
+        token_id: Set(expired_id.clone()),
+        user_id: Set(user.id),
+        expires_at: Set((Utc::now() - Duration::minutes(1)).naive_utc()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let metrics = Metrics::new();
+    assert!(
+        token_blacklist_service::purge_expired(&db, &metrics)
+            .await
+            .unwrap()
+            >= 1
+    );
+    assert!(blacklisted_token::Entity::find_by_id(expired_id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(blacklisted_token::Entity::find_by_id(token_id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(metrics.encode().unwrap().contains("blacklist_size"));
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_expire_unconfirmed_accounts() {
+    let app = TestApp::new().await;
+    let metrics = Metrics::new();
+    let mailer = Mailer::new(&app.config.environment, &app.config.mailer, &metrics);
+    let object_storage = ObjectStorage::new(&app.config.environment, &app.config.object_storage);
+    let expiry_hours = app.config.unconfirmed_expiry.days * 24;
+    let expire = || {
+        unconfirmed_accounts_service::expire_unconfirmed_accounts(
+            &app.db,
+            &app.cache,
+            &app.jwt,
+            &mailer,
+            &object_storage,
+            &metrics,
+            &app.config.unconfirmed_expiry,
+        )
+    };
+    let find_user = |id: i32| user::Entity::find_by_id(id).one(app.db.get_connection());
+    let age_warning = |id: i32| {
+        let key = format!("unconfirmed_expiry:warned:{}", id);
+        let warned_at = (Utc::now() - Duration::hours(25)).timestamp();
+        let cache = app.cache.clone();
+        async move { cache.set_json(&key, &warned_at, 3600).await.unwrap() }
+    };
+    let expiring = app.create_user(false).await;
+    let expiring = set_created_hours_ago(&app.db, expiring, expiry_hours - 12).await;
+    let expired = app.create_user(false).await;
+    let expired = set_created_hours_ago(&app.db, expired, expiry_hours + 1).await;
+    let confirmed = app.create_user(true).await;
+    let confirmed = set_created_hours_ago(&app.db, confirmed, expiry_hours * 2).await;
+    let fresh = app.create_user(false).await;
+    let mut admin: user::ActiveModel = app.create_user(true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(app.db.get_connection()).await.unwrap();
+
+    let body = app
+        .graphql_as(
+            &admin,
+            "{ unconfirmedAccountStats { expiryDays unconfirmed expiring expired } }",
+        )
+        .await;
+    let stats = &body["data"]["unconfirmedAccountStats"];
+    assert_eq!(stats["expiryDays"], json!(expiry_hours / 24));
+    assert!(stats["unconfirmed"].as_u64().unwrap() >= 3);
+    assert!(stats["expiring"].as_u64().unwrap() >= 1);
+    assert!(stats["expired"].as_u64().unwrap() >= 1);
+    let body = app
+        .graphql_as(&fresh, "{ unconfirmedAccountStats { unconfirmed } }")
+        .await;
+    assert!(body["errors"].is_array());
+
+    // Both get the warning, an account that expired unwarned still gets its day
+    let run = expire().await.unwrap().unwrap();
+    assert!(run.warned >= 2);
+    for user in [&expiring, &expired] {
+        let sent = captured_emails(&user.email);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]
+            .subject
+            .starts_with("Your account will be removed soon"));
+        assert!(find_user(user.id).await.unwrap().is_some());
+    }
+    assert!(captured_emails(&confirmed.email).is_empty());
+    assert!(captured_emails(&fresh.email).is_empty());
+
+    // The warning is sent once
+    expire().await.unwrap().unwrap();
+    assert_eq!(captured_emails(&expiring.email).len(), 1);
+    assert_eq!(captured_emails(&expired.email).len(), 1);
+
+    // Another instance holding the lock skips the run
+    app.cache
+        .set_json("unconfirmed_expiry:lock", &0, 60)
+        .await
+        .unwrap();
+    assert!(expire().await.unwrap().is_none());
+    app.cache.del("unconfirmed_expiry:lock").await.unwrap();
+
+    // Only accounts past the deadline with a day old warning are deleted
+    age_warning(expiring.id).await;
+    age_warning(expired.id).await;
+    let run = expire().await.unwrap().unwrap();
+    assert!(run.deleted >= 1);
+    assert!(find_user(expired.id).await.unwrap().is_none());
+    assert!(oauth_provider::Entity::find_by_email(&expired.email)
+        .one(app.db.get_connection())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(find_user(expiring.id).await.unwrap().is_some());
+
+    let expiring = set_created_hours_ago(&app.db, expiring, expiry_hours + 1).await;
+    expire().await.unwrap().unwrap();
+    assert!(find_user(expiring.id).await.unwrap().is_none());
+    assert!(find_user(confirmed.id).await.unwrap().is_some());
+    assert!(find_user(fresh.id).await.unwrap().is_some());
+    assert!(metrics
+        .encode()
+        .unwrap()
+        .contains("unconfirmed_accounts_expiry_total{action=\"deleted\"}"));
+
+    // An account confirmed after the batch was read is kept
+    let deadline = Utc::now().naive_utc();
+    let purged = users_service::purge_user(
+        &app.db.session(),
+        &object_storage,
+        confirmed.id,
+        user::Entity::unconfirmed_before(deadline),
+    )
+    .await
+    .unwrap();
+    assert!(!purged);
+    assert!(find_user(confirmed.id).await.unwrap().is_some());
+
+    // clean users
+    for email in [&expiring.email, &expired.email] {
+        email_outbox::Entity::delete_many()
+            .filter(email_outbox::Column::Recipient.eq(email.clone()))
+            .exec(app.db.get_connection())
+            .await
+            .unwrap();
+    }
+    delete_user(&app.db, confirmed).await;
+    delete_user(&app.db, fresh).await;
+    delete_user(&app.db, admin).await;
+}
+
+#[actix_web::test]
+async fn test_sessions() {
+    let (config, db, _, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let user = set_two_factor(&db, &user, false).await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Sign in from two different clients
+    let mut auth_responses = Vec::<serde_json::Value>::new();
+    for user_agent in ["firefox", "safari"] {
+        let req = test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .insert_header(("User-Agent", user_agent))
+            .set_json(json!({
+                "email": &user.email,
+                "password": VALID_PASSWORD,
+            }))
+            .to_request();
+        auth_responses.push(test::call_and_read_body_json(&app, req).await);
+    }
+    let access_token = auth_responses[1]["access_token"].as_str().unwrap();
+
+    // List both sessions, the last login is only visible to the owner
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
+        .set_json(json!({
+            "query": "query { mySessions { tokenId userAgent } me { lastLoginAt } }",
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let sessions = body["data"]["mySessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(body["data"]["me"]["lastLoginAt"].is_i64());
+    let user_agents = sessions
+        .iter()
+        .map(|session| session["userAgent"].as_str().unwrap())
+        .collect::<HashSet<_>>();
+    assert_eq!(user_agents, HashSet::from(["firefox", "safari"]));
+    let revoked = sessions
+        .iter()
+        .find(|session| session["userAgent"] == "firefox")
+        .unwrap()["tokenId"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Revoke the first client
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
+        .set_json(json!({
+            "query": format!(r#"mutation {{ revokeSession(tokenId: "{}") {{ message }} }}"#, revoked),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"]["revokeSession"]["message"],
+        "Session revoked successfully"
+    );
+
+    // The revoked refresh token no longer works
+    let req = test::TestRequest::post()
+        .uri("/api/auth/refresh-token")
+        .set_json(json!({
+            "refresh_token": auth_responses[0]["refresh_token"],
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 401);
+
+    // The other one still does
+    let req = test::TestRequest::post()
+        .uri("/api/auth/refresh-token")
+        .set_json(json!({
+            "refresh_token": auth_responses[1]["refresh_token"],
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_forgot_password() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+
+    // Success forgot password
+    let resp = app
+        .post_json(
+            "/api/auth/forgot-password",
+            json!({
+                "email": &user.email,
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Should succed for a random email
+    let resp = app
+        .post_json(
+            "/api/auth/forgot-password",
+            json!({
+                "email": format!("{}@gmail.com", Uuid::new_v4()),
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Invalid email
+    let resp = app
+        .post_json(
+            "/api/auth/forgot-password",
+            json!({
+                "email": "not_an_email",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+}
+
+#[actix_web::test]
+async fn test_reset_password() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let token = create_token(&jwt, &user, Some(TokenType::Reset)).await;
+    let new_password = "New_Password12".to_string();
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Invalid password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/reset-password")
+        .set_json(json!({
+            "reset_token": &token,
+            "password1": "invalid_password",
+            "password2": "invalid_password",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // Success reset password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/reset-password")
+        .set_json(json!({
+            "reset_token": &token,
+            "password1": &new_password,
+            "password2": &new_password,
+            "sign_out_everywhere": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    assert_eq!(&resp.status().as_u16(), &200);
+    let reset_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(reset_user.version, user.version + 2);
+
+    // Invalid token
+    let req = test::TestRequest::post()
+        .uri("/api/auth/reset-password")
+        .set_json(json!({
+            "reset_token": "invalid_token",
+            "password1": &new_password,
+            "password2": &new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_sign_in_lockout() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let max_attempts = config.sign_in_max_attempts;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Failed attempts before the lock
+    for _ in 1..max_attempts {
+        let req = test::TestRequest::post()
+            .uri("/api/auth/sign-in")
+            .set_json(json!({
+                "email": &user.email,
+                "password": "invalid_password",
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(&resp.status().as_u16(), &401);
+    }
+
+    // Last failed attempt locks the account
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({
+            "email": &user.email,
+            "password": "invalid_password",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &403);
+
+    // Locked even with the valid password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({
+            "email": &user.email,
+            "password": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &403);
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("Your account is locked"));
+
+    // Reset password clears the lock
+    let token = create_token(&jwt, &user, Some(TokenType::Reset)).await;
+    let new_password = "New_Password12".to_string();
+    let req = test::TestRequest::post()
+        .uri("/api/auth/reset-password")
+        .set_json(json!({
+            "reset_token": &token,
+            "password1": &new_password,
+            "password2": &new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({
+            "email": &user.email,
+            "password": &new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_update_password() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &token);
+    let authorization_header = ("Authorization", bearer_token.as_str());
+    let new_password = "New_Password12".to_string();
+    let new_password2 = new_password.clone();
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Invalid password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": "invalid_password",
+            "password2": "invalid_password",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // Passwords do not match
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": &new_password,
+            "password2": &format!("{}_e", &new_password2),
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // Success update password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": &new_password,
+            "password2": &new_password2,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // Invalid token
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", "invalid_token"))
+        .set_json(json!({
+            "old_password":&new_password,
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_update_password_with_stale_refresh_token() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let refresh_token = create_token(&jwt, &user, Some(TokenType::Refresh)).await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // The version moved on after the refresh token was issued
+    let mut stale_user: user::ActiveModel = user.clone().into();
+    stale_user.version = Set(user.version + 1);
+    let user = stale_user.update(db.get_connection()).await.unwrap();
+    let access_token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &access_token);
+
+    // Another user's refresh token can not be blacklisted under this account
+    let new_password = "New_Password12".to_string();
+    let other_user = create_named_user(&db, "Other", "User").await;
+    let other_refresh_token = create_token(&jwt, &other_user, Some(TokenType::Refresh)).await;
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .cookie(Cookie::new("refresh_token", other_refresh_token))
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": &new_password,
+            "password2": &new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    delete_user(&db, other_user).await;
+
+    // Used to fail with 401 on the version mismatch
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .cookie(Cookie::new("refresh_token", refresh_token.clone()))
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": &new_password,
+            "password2": &new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(
+        to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .as_str()
+            .to_owned(),
+    );
+    let updated_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated_user.version, user.version + 1);
+
+    // The stale token was blacklisted
+    let req = test::TestRequest::post()
+        .uri("/api/auth/refresh-token")
+        .cookie(Cookie::new("refresh_token", refresh_token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Signing out everywhere skips a version
+    let access_token = create_token(&jwt, &updated_user, None).await;
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", format!("Bearer {}", &access_token)))
+        .set_json(json!({
+            "old_password": &new_password,
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "signOutEverywhere": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let updated_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated_user.version, user.version + 3);
+
+    // clean user
+    delete_user(&db, updated_user).await;
+}
+
+#[actix_web::test]
+async fn test_database_transaction() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let exp = Utc::now().timestamp() + 3600;
+
+    // The second update finds the user already changed and fails the transaction
+    let read = user.clone();
+    let error = app
+        .db
+        .transaction(|txn| {
+            Box::pin(async move {
+                let mut changes: user::ActiveModel = read.clone().into();
+                changes.first_name = Set("Changed".to_string());
+                users_service::update_versioned(txn, &read, changes).await?;
+                token_blacklist_service::insert_token(txn, read.id, "rolled_back", exp).await?;
+                let mut changes: user::ActiveModel = read.clone().into();
+                changes.last_name = Set("Changed".to_string());
+                users_service::update_versioned(txn, &read, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)
+            })
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.get_status_code(), 409);
+    let unchanged = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(unchanged.first_name, user.first_name);
+    assert_eq!(unchanged.version, user.version);
+    assert!(
+        blacklisted_token::Entity::find_active_by_token_id("rolled_back")
+            .one(app.db.get_connection())
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let read = user.clone();
+    let updated = app
+        .db
+        .transaction(|txn| {
+            Box::pin(async move {
+                let mut changes: user::ActiveModel = read.clone().into();
+                changes.first_name = Set("Changed".to_string());
+                users_service::update_versioned(txn, &read, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)
+            })
+        })
+        .await
+        .unwrap();
+    assert_eq!(updated.first_name, "Changed");
+    assert_eq!(updated.version, user.version);
+}
+
+async fn create_user_in(session: &DbSession<'_>, email: &str) -> Result<user::Model, ServiceError> {
+    users_service::create_user(
+        session,
+        &Webhooks::disabled(),
+        "Ada".to_string(),
+        "Lovelace".to_string(),
+        Some("1990-01-01".to_string()),
+        email.to_string(),
+        VALID_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Local,
+        users_service::UserPreferences::default(),
+        None,
+    )
+    .await
+}
+
+#[actix_web::test]
+async fn test_db_session_rollback() {
+    let app = TestApp::new().await;
+
+    // Services see their own writes inside the session, the pool does not
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let txn = app.db.get_connection().begin().await.unwrap();
+    let session = DbSession::Tx(&app.db, &txn);
+    let user = create_user_in(&session, &email).await.unwrap();
+    let found = users_service::find_one_by_id(&session, user.id)
+        .await
+        .unwrap();
+    assert_eq!(found.email, email);
+    assert!(users_service::find_one_by_email(&app.db.session(), &email)
+        .await
+        .unwrap()
+        .is_none());
+    txn.rollback().await.unwrap();
+    assert!(users_service::find_one_by_email(&app.db.session(), &email)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(oauth_provider::Entity::find_by_email(&email)
+        .one(app.db.get_connection())
+        .await
+        .unwrap()
+        .is_none());
+
+    // A failing transaction rolls back what the services wrote in it
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let (cache, new_email) = (app.cache.clone(), email.clone());
+    let error = app
+        .db
+        .transaction(|txn| {
+            Box::pin(async move {
+                let user = create_user_in(txn, &new_email).await?;
+                users_service::update_last_login(txn, &cache, user).await?;
+                Err::<(), _>(ServiceError::conflict::<ServiceError>("Rolled back", None))
+            })
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.get_status_code(), 409);
+    assert!(users_service::find_one_by_email(&app.db.session(), &email)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn test_auth_flows_in_transactions() {
+    let app = TestApp::new().await;
+
+    // Confirming bumps the version, so the token only works once
+    let user = app.create_user(false).await;
+    let token = app.token_for(&user, TokenType::Confirmation);
+    let resp = app
+        .post_json(
+            "/api/auth/confirm-email",
+            json!({ "confirmation_token": &token }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let confirmed = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(confirmed.confirmed);
+    assert_eq!(confirmed.version, user.version + 1);
+
+    // The password and its notice are written together
+    let token = app.token_for(&confirmed, TokenType::Reset);
+    let resp = app
+        .post_json(
+            "/api/auth/reset-password",
+            json!({
+                "reset_token": &token,
+                "password1": "New_Password12",
+                "password2": "New_Password12",
+            }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let reset = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(reset.version, confirmed.version + 1);
+    let notices = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.eq(user.email.clone()))
+        .all(app.db.get_connection())
+        .await
+        .unwrap();
+    assert_eq!(
+        notices
+            .iter()
+            .filter(|email| email.subject.starts_with("Your password was changed"))
+            .count(),
+        1
+    );
+
+    // The refresh token is blacklisted in the same transaction as the update
+    let refresh_token = app.token_for(&reset, TokenType::Refresh);
+    let (_, _, token_id, _) = app
+        .jwt
+        .verify_email_token(TokenType::Refresh, &refresh_token)
+        .unwrap();
+    let resp = app
+        .call(
+            test::TestRequest::post()
+                .uri("/api/auth/update-password")
+                .insert_header(("Authorization", app.bearer_for(&reset)))
+                .cookie(Cookie::new("refresh_token", refresh_token))
+                .set_json(json!({
+                    "old_password": "New_Password12",
+                    "password1": VALID_PASSWORD,
+                    "password2": VALID_PASSWORD,
+                }))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let updated = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated.version, reset.version + 1);
+    assert!(
+        blacklisted_token::Entity::find_active_by_token_id(&token_id)
+            .one(app.db.get_connection())
+            .await
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        token_blacklist_service::is_blacklisted(&app.db, &app.cache, &token_id)
+            .await
+            .unwrap()
+    );
+}
+
+#[actix_web::test]
+async fn test_update_two_factor() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &token);
+    let authorization_header = ("Authorization", bearer_token.as_str());
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // A bearer token alone is not enough
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": false,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // Wrong password
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": false,
+            "password": "Wrong_Password12",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Success update two factor
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": false,
+            "password": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let updated = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(!updated.two_factor);
+
+    // Disabling it notifies the user
+    let notifications = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.eq(user.email.clone()))
+        .all(db.get_connection())
+        .await
+        .unwrap();
+    assert!(notifications.iter().any(|email| email
+        .subject
+        .starts_with("Two-factor authentication was disabled")));
+
+    // Invalid token
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(("Authorization", "invalid_token"))
+        .set_json(json!({
+            "two_factor": false,
+            "password": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_client_error());
+
+    // clean user
+    for email in notifications {
+        email.delete(db.get_connection()).await.unwrap();
+    }
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_update_two_factor_without_password() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = users_service::create_user(
+        &db.session(),
+        &Webhooks::disabled(),
+        Name(EN).fake(),
+        Name(EN).fake(),
+        None,
+        format!("{}@gmail.com", Uuid::new_v4()),
+        VALID_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Github,
+        users_service::UserPreferences::default(),
+        None,
+    )
+    .await
+    .unwrap();
+    let token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &token);
+    let authorization_header = ("Authorization", bearer_token.as_str());
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Without a code one is emailed and nothing changes
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &202);
+    let updated = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(!updated.two_factor);
+
+    // A password does not stand in for the code
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": true,
+            "password": VALID_PASSWORD,
+            "code": "000000",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    let code = "123456";
+    let key = format!("access_code:{}", &user.email);
+    let mut connection = cache.get_connection().await.unwrap();
+    connection
+        .set_ex::<&str, &str, ()>(&key, &hash_code(code), 600)
+        .await
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-two-factor")
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "two_factor": true,
+            "code": code,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let updated = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(updated.two_factor);
+
+    // clean user
+    email_outbox::Entity::delete_many()
+        .filter(email_outbox::Column::Recipient.eq(user.email.clone()))
+        .exec(db.get_connection())
+        .await
+        .unwrap();
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_set_password() {
+    let app = TestApp::new().await;
+    let user = users_service::create_user(
+        &app.db.session(),
+        &Webhooks::disabled(),
+        Name(EN).fake(),
+        Name(EN).fake(),
+        None,
+        format!("{}@gmail.com", Uuid::new_v4()),
+        user::NO_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Google,
+        users_service::UserPreferences::default(),
+        None,
+    )
+    .await
+    .unwrap();
+    let sign_in = json!({
+        "email": &user.email,
+        "password": VALID_PASSWORD,
+    });
+    let me = "{ me { registrationProvider hasPassword } }";
+    let body = app.graphql_as(&user, me).await;
+    assert_eq!(
+        body["data"]["me"],
+        json!({ "registrationProvider": "GOOGLE", "hasPassword": false })
+    );
+    let resp = app.post_json("/api/auth/sign-in", sign_in.clone()).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // Without a code one is emailed and nothing changes
+    let set_password = |code: Option<&str>| {
+        let code = code.map_or(String::new(), |code| format!(r#", code: "{}""#, code));
+        format!(
+            r#"mutation {{ setPassword(input: {{ password1: "{0}", password2: "{0}"{1} }}) {{ message }} }}"#,
+            VALID_PASSWORD, code
+        )
+    };
+    let body = app.graphql_as(&user, &set_password(None)).await;
+    assert_eq!(
+        body["data"]["setPassword"]["message"],
+        "Confirmation code sent, check your email"
+    );
+    let queued = email_outbox::Entity::find()
+        .filter(email_outbox::Column::Recipient.eq(user.email.clone()))
+        .count(app.db.get_connection())
+        .await
+        .unwrap();
+    assert_eq!(queued, 1);
+    let body = app.graphql_as(&user, &set_password(Some("000000"))).await;
+    assert_eq!(body["errors"][0]["message"], "Invalid code");
+
+    let code = "123456";
+    let key = format!("access_code:{}", &user.email);
+    let mut connection = app.cache.get_connection().await.unwrap();
+    connection
+        .set_ex::<&str, &str, ()>(&key, &hash_code(code), 600)
+        .await
+        .unwrap();
+    let body = app.graphql_as(&user, &set_password(Some(code))).await;
+    assert_eq!(
+        body["data"]["setPassword"]["message"],
+        "Password set successfully"
+    );
+    let body = app.graphql_as(&user, me).await;
+    assert_eq!(
+        body["data"]["me"],
+        json!({ "registrationProvider": "GOOGLE", "hasPassword": true })
+    );
+
+    // The password now signs in, and can only be changed from here on
+    let resp = app.post_json("/api/auth/sign-in", sign_in).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    check_is_auth_response(test::read_body(resp).await.as_str().to_owned());
+    let body = app.graphql_as(&user, &set_password(None)).await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "User already has a password, change it instead"
+    );
+
+    // clean user
+    email_outbox::Entity::delete_many()
+        .filter(email_outbox::Column::Recipient.eq(user.email.clone()))
+        .exec(app.db.get_connection())
+        .await
+        .unwrap();
+    delete_user(&app.db, user).await;
+}
+
+async fn create_named_user(db: &Database, first_name: &str, last_name: &str) -> user::Model {
+    let user = users_service::create_user(
+        &db.session(),
+        &Webhooks::disabled(),
+        first_name.to_string(),
+        last_name.to_string(),
+        None,
+        format!("{}@gmail.com", Uuid::new_v4()),
+        VALID_PASSWORD.to_string(),
+        enums::OAuthProviderEnum::Local,
+        users_service::UserPreferences::default(),
+        None,
+    )
+    .await
+    .unwrap();
+    let mut user: user::ActiveModel = user.into();
+    user.confirmed = Set(true);
+    user.version = Set(1);
+    user.update(db.get_connection()).await.unwrap()
+}
+
+#[actix_web::test]
+async fn test_reserved_usernames() {
+    let app = TestApp::new().await;
+    let mut admin: user::ActiveModel = app.create_user(true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(app.db.get_connection()).await.unwrap();
+    let body = app
+        .graphql_as(
+            &admin,
+            r#"mutation { reserveUsername(username: "brand.official") { message } }"#,
+        )
+        .await;
+    assert_eq!(
+        body["data"]["reserveUsername"]["message"],
+        json!("Username reserved successfully")
+    );
+
+    // Derived usernames move off the reserved name and its numbered variants
+    let user = create_named_user(&app.db, "Brand", "Official").await;
+    assert_eq!(user.username, "brand.official.user");
+    let user = create_named_user(&app.db, "Brand", "Official").await;
+    assert_eq!(user.username, "brand.official.user.2");
+
+    // Claiming one is a conflict, configured names included
+    for username in ["brand.official", "brand.official.2", "admin", "support.7"] {
+        let body = app
+            .graphql_as(
+                &user,
+                &format!(
+                    r#"mutation {{ updateUsername(username: "{}") {{ username }} }}"#,
+                    username
+                ),
+            )
+            .await;
+        assert_eq!(
+            body["errors"][0]["message"],
+            json!("Username unavailable"),
+            "{}",
+            username
+        );
+        assert_eq!(body["errors"][0]["extensions"]["code"], json!("409"));
+    }
+}
+
+#[actix_web::test]
+async fn test_regenerate_username() {
+    let app = TestApp::new().await;
+    let user = create_named_user(&app.db, "Ada", "Lovelace").await;
+    assert_eq!(user.username, "ada.lovelace");
+    let body = app
+        .graphql_as(
+            &user,
+            r#"mutation { updateUsername(username: "countess") { username } }"#,
+        )
+        .await;
+    assert_eq!(
+        body["data"]["updateUsername"]["username"],
+        json!("countess")
+    );
+
+    // The customized username is replaced by the one derived from the name
+    let regenerate = "mutation { regenerateUsername { username } }";
+    let body = app.graphql_as(&user, regenerate).await;
+    assert_eq!(
+        body["data"]["regenerateUsername"]["username"],
+        json!("ada.lovelace")
+    );
+    let user = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(!user.username_customized);
+
+    // Regenerating again keeps it instead of counting the user against itself
+    let body = app.graphql_as(&user, regenerate).await;
+    assert_eq!(
+        body["data"]["regenerateUsername"]["username"],
+        json!("ada.lovelace")
+    );
+}
+
+#[actix_web::test]
+async fn test_admin_export_users() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let mut user_vec = Vec::<user::Model>::new();
+
+    for _ in 0..50 {
+        user_vec.push(create_user(&db, true).await);
+    }
+
+    let emails = user_vec
+        .iter()
+        .map(|user| user.email.clone())
+        .collect::<HashSet<String>>();
+    let admin = create_user(&db, true).await;
+    let mut admin: user::ActiveModel = admin.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let user_token = format!(
+        "Bearer {}",
+        create_token(&jwt, user_vec.first().unwrap(), None).await
+    );
+
+    // Non admin users are rejected
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users/export?format=csv")
+        .insert_header(("Authorization", user_token.as_str()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &403);
+
+    // CSV export
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users/export?format=csv")
+        .insert_header(("Authorization", admin_token.as_str()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let headers = resp.headers();
+    assert_eq!(
+        headers.get("content-type").unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    assert!(headers
+        .get("content-disposition")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("attachment; filename=\"users.csv\""));
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    let mut lines = body.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "id,email,first_name,last_name,created_at"
+    );
+    let count = lines
+        .filter(|line| emails.contains(line.split(',').nth(1).unwrap()))
+        .count();
+    assert_eq!(count, 50);
+
+    // NDJSON export
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users/export?format=json")
+        .insert_header(("Authorization", admin_token.as_str()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let headers = resp.headers();
+    assert_eq!(headers.get("content-type").unwrap(), "application/x-ndjson");
+    assert!(headers
+        .get("content-disposition")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("attachment; filename=\"users.ndjson\""));
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    let count = body
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|row| emails.contains(row["email"].as_str().unwrap()))
+        .count();
+    assert_eq!(count, 50);
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+    delete_user(&db, admin).await;
+}
+
+#[actix_web::test]
+async fn test_metrics_endpoint() {
+    let (mut config, mut db, _, _) = create_base_config().await;
+    config.metrics_token = Some(Secret::new("metrics_token".to_string()));
+    let metrics = Metrics::new();
+    db.set_metrics(&metrics);
+    let app = test::init_service(
+        App::new()
+            .wrap(TracingLogger::default())
+            .wrap(HttpMetrics::new(&metrics))
+            .configure(ActixApp::build_app_config(
+                &config,
+                &db,
+                &Providers::new(&config, &metrics),
+            )),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/health-check")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .set_json(json!({ "query": "query Health { healthCheck { message } }" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    // Transactions record how long they waited for a pooled connection
+    db.begin().await.unwrap().rollback().await.unwrap();
+
+    // Scrapes without the configured token are rejected
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics")
+        .insert_header(("Authorization", "Bearer metrics_token"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("# TYPE http_requests_total counter"));
+    assert!(body.contains("# TYPE http_request_duration_seconds histogram"));
+    assert!(body.contains("route=\"/api/health-check\""));
+    assert!(body.contains("graphql_operations_total{operation=\"Health\"} 1"));
+    assert!(body.contains("mailer_sends_total{status=\"failure\"} 0"));
+    assert!(body.contains("# TYPE object_storage_upload_duration_seconds histogram"));
+    assert!(body.contains("# TYPE cache_connection_acquire_duration_seconds histogram"));
+    assert!(body.contains("database_connection_acquire_duration_seconds_count 1"));
+}
+
+#[actix_web::test]
+async fn test_sign_in_soft_deleted_user() {
+    let (config, db, _, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    users_service::delete_user(&db.session(), &cache, &Webhooks::disabled(), user.id)
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({
+            "email": &user.email,
+            "password": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &401);
+
+    // The email stays reserved during the grace period
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-up")
+        .set_json(json!({
+            "email": &user.email,
+            "first_name": &user.first_name,
+            "last_name": &user.last_name,
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &409);
+
+    delete_user(&db, user).await;
+}
+
+struct FailingTransport;
+
+#[async_trait]
+impl EmailTransport for FailingTransport {
+    async fn send(&self, _: &str, _: &str, _: &str, _: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("SMTP unavailable"))
+    }
+}
+
+#[actix_web::test]
+async fn test_email_outbox_retries() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let mailer = Mailer::with_transport(
+        &Environment::Production,
+        &config.mailer,
+        &Metrics::new(),
+        FailingTransport,
+    );
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    auth_service::sign_up(
+        &db.session(),
+        &cache,
+        &jwt,
+        &mailer,
+        &Webhooks::disabled(),
+        SignUpMode::Open,
+        &TermsVersion::default(),
+        bodies::SignUp {
+            email: email.clone(),
+            first_name: Name(EN).fake(),
+            last_name: Name(EN).fake(),
+            date_of_birth: "1990-01-01".to_string(),
+            password1: VALID_PASSWORD.to_string(),
+            password2: VALID_PASSWORD.to_string(),
+            locale: None,
+            timezone: None,
+            accepted_terms: true,
+            invitation_token: None,
+            captcha_token: None,
+        },
+    )
+    .await
+    .unwrap();
+    let find_queued = || async {
+        email_outbox::Entity::find()
+            .filter(email_outbox::Column::Recipient.eq(&email))
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .unwrap()
+    };
+
+    // The confirmation email is committed with the user, waiting for the worker
+    let queued = find_queued().await;
+    assert_eq!(queued.status, enums::EmailStatusEnum::Pending);
+    assert_eq!(queued.attempts, 0);
+
+    // A failed delivery is rescheduled with backoff
+    let before = Utc::now().naive_utc();
+    outbox_service::process_email_outbox(&db, &mailer)
+        .await
+        .unwrap();
+    let retried = find_queued().await;
+    assert_eq!(retried.status, enums::EmailStatusEnum::Pending);
+    assert_eq!(retried.attempts, 1);
+    assert_eq!(retried.last_error.as_deref(), Some("SMTP unavailable"));
+    assert!(retried.next_attempt_at >= before + outbox_service::retry_delay(1));
+    assert!(outbox_service::retry_delay(2) > outbox_service::retry_delay(1));
+
+    // Rows are only retried once due, until the attempts run out
+    outbox_service::process_email_outbox(&db, &mailer)
+        .await
+        .unwrap();
+    assert_eq!(find_queued().await.attempts, 1);
+    let mut failed = find_queued().await;
+    for _ in 0..20 {
+        if failed.status != enums::EmailStatusEnum::Pending {
+            break;
+        }
+
+        let mut due: email_outbox::ActiveModel = failed.into();
+        due.next_attempt_at = Set(Utc::now().naive_utc() - Duration::seconds(1));
+        due.update(db.get_connection()).await.unwrap();
+        outbox_service::process_email_outbox(&db, &mailer)
+            .await
+            .unwrap();
+        failed = find_queued().await;
+    }
+    assert_eq!(failed.status, enums::EmailStatusEnum::Failed);
+    assert!(failed.attempts > 1);
+    assert!(outbox_service::find_failed_emails(&db, None)
+        .await
+        .unwrap()
+        .iter()
+        .any(|email| email.id == failed.id));
+
+    // A claim left by a dead worker is taken again, a live one is not
+    let claimed_at = |seconds: i64| {
+        let mut claimed: email_outbox::ActiveModel = failed.clone().into();
+        claimed.status = Set(enums::EmailStatusEnum::Sending);
+        claimed.claimed_at = Set(Some(Utc::now().naive_utc() - Duration::seconds(seconds)));
+        claimed.update(db.get_connection())
+    };
+    claimed_at(60).await.unwrap();
+    outbox_service::process_email_outbox(&db, &mailer)
+        .await
+        .unwrap();
+    assert_eq!(find_queued().await.status, enums::EmailStatusEnum::Sending);
+    claimed_at(3600).await.unwrap();
+    outbox_service::process_email_outbox(&db, &mailer)
+        .await
+        .unwrap();
+    let reclaimed = find_queued().await;
+    assert_eq!(reclaimed.status, enums::EmailStatusEnum::Failed);
+    assert_eq!(reclaimed.attempts, failed.attempts + 1);
+    assert_eq!(reclaimed.claimed_at, None);
+    assert!(!reclaimed.body.is_empty());
+
+    let user = users_service::find_one_by_email(&db.session(), &email)
+        .await
+        .unwrap()
+        .unwrap();
+    failed.delete(db.get_connection()).await.unwrap();
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_oauth_callback_redirects_errors() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let error_redirect = format!("{}/auth/error?code=", config.urls.frontend_url);
+
+    for (uri, code) in [
+        // Consent denied on the provider's screen
+        (
+            "/api/auth/ext/google/callback?error=access_denied&error_description=denied&state=abc",
+            "access_denied",
+        ),
+        // A state that was never issued by this api
+        (
+            "/api/auth/ext/facebook/callback?code=abc&state=unknown",
+            "unauthorized",
+        ),
+        ("/api/auth/ext/github/callback", "bad_request"),
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 302);
+        assert_eq!(
+            resp.headers().get("location").unwrap().to_str().unwrap(),
+            format!("{}{}", error_redirect, code)
+        );
+        assert!(resp.response().cookies().next().is_none());
+    }
+}
+
+#[actix_web::test]
+async fn test_oauth_state_is_stateless() {
+    let (config, db, _, cache) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/api/auth/ext/google")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 307);
+    let location = resp.headers().get("location").unwrap().to_str().unwrap();
+    let state = Url::parse(location)
+        .unwrap()
+        .query_pairs()
+        .find(|(name, _)| name == "state")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    // The verifier travels inside the state, nothing is written for the provider
+    let key = format!("google:{}", state);
+    let key = key.as_str();
+    let stored = cache
+        .execute(|mut connection| async move { connection.exists::<&str, bool>(key).await })
+        .await
+        .unwrap();
+    assert!(!stored);
+
+    let oauth = OAuth::new(&config.oauth, &config.http_client);
+    let (_, state_id, _, exp) = oauth
+        .verify_state(&ExternalProvider::Google, &state)
+        .unwrap();
+    assert!(oauth
+        .verify_state(&ExternalProvider::Facebook, &state)
+        .is_err());
+
+    // A state is only good for one callback
+    auth_service::consume_oauth_state(&cache, &state_id, exp)
+        .await
+        .unwrap();
+    let error = auth_service::consume_oauth_state(&cache, &state_id, exp)
+        .await
+        .unwrap_err();
+    assert_eq!(error.get_status_code(), 401);
+}
+
+#[actix_web::test]
+async fn test_concurrent_user_updates() {
+    let (_, db, _, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+
+    // Writers sharing a stale read: exactly one wins, the other conflicts
+    let mut first: user::ActiveModel = user.clone().into();
+    first.first_name = Set("First".to_string());
+    let mut second: user::ActiveModel = user.clone().into();
+    second.last_name = Set("Second".to_string());
+    let (first, second) = tokio::join!(
+        users_service::update_versioned(db.get_connection(), &user, first),
+        users_service::update_versioned(db.get_connection(), &user, second),
+    );
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert!(first.is_some() != second.is_some());
+    let updated_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated_user.version, user.version);
+    assert_eq!(
+        updated_user.first_name == "First",
+        updated_user.last_name != "Second"
+    );
+
+    // Profile updates retry on a fresh read, so neither write is lost
+    let session = db.session();
+    let (first, second) = tokio::join!(
+        users_service::update_name(
+            &session,
+            &cache,
+            user.id,
+            "Concurrent".to_string(),
+            "First".to_string()
+        ),
+        users_service::update_privacy_settings(&session, &cache, user.id, true),
+    );
+    first.unwrap();
+    second.unwrap();
+    let renamed_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(renamed_user.full_name(), "Concurrent First");
+    assert!(renamed_user.show_age);
+
+    // Benign updates, like every sign in, leave the version alone
+    assert_eq!(renamed_user.version, updated_user.version);
+    let signed_in = users_service::update_last_login(&db.session(), &cache, renamed_user.clone())
+        .await
+        .unwrap();
+    assert_eq!(signed_in.version, renamed_user.version);
+    assert!(users_service::next_version(i32::MAX, 1).is_err());
+
+    // Tokens survive profile updates but not credential changes
+    assert!(
+        users_service::find_one_by_version(&db.session(), user.id, user.version)
+            .await
+            .is_ok()
+    );
+    users_service::delete_user(&db.session(), &cache, &Webhooks::disabled(), user.id)
+        .await
+        .unwrap();
+    let deleted_user = user::Entity::find_deleted_by_id(user.id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted_user.min_token_version, deleted_user.version);
+    assert!(
+        users_service::find_one_by_version(&db.session(), user.id, renamed_user.version)
+            .await
+            .is_err()
+    );
+
+    delete_user(&db, deleted_user).await;
+}
+
+#[actix_web::test]
+async fn test_concurrent_user_creation() {
+    let (_, db, _, _) = create_base_config().await;
+    let first_name = "Concurrent".to_string();
+    let last_name = format!("Smith{}", Uuid::new_v4().simple());
+    let point_slug = format_point_slug(&format!("{} {}", first_name, last_name));
+    let session = db.session();
+    let webhooks = Webhooks::disabled();
+    let create = |last_name: String| {
+        users_service::create_user(
+            &session,
+            &webhooks,
+            first_name.clone(),
+            last_name,
+            None,
+            format!("{}@gmail.com", Uuid::new_v4()),
+            VALID_PASSWORD.to_string(),
+            enums::OAuthProviderEnum::Local,
+            users_service::UserPreferences::default(),
+            None,
+        )
+    };
+
+    // A longer name sharing the prefix does not count towards the username
+    let prefixed_user = create(format!("{}son", last_name)).await.unwrap();
+    assert_eq!(prefixed_user.username, format!("{}son", point_slug));
+
+    // Simultaneous sign ups with the same name all get a distinct username
+    let users = futures::future::join_all((0..5).map(|_| create(last_name.clone())))
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect::<Vec<user::Model>>();
+    let usernames = users
+        .iter()
+        .map(|user| user.username.as_str())
+        .collect::<HashSet<&str>>();
+    assert_eq!(usernames.len(), users.len());
+    assert!(usernames.contains(point_slug.as_str()));
+    assert!(usernames
+        .iter()
+        .all(|username| username.starts_with(&point_slug)));
+
+    for user in users {
+        delete_user(&db, user).await;
+    }
+    delete_user(&db, prefixed_user).await;
+}
+
+#[actix_web::test]
+async fn test_json_body_errors() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Oversized body
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-up")
+        .set_json(json!({
+            "email": "oversized@gmail.com",
+            "first_name": "a".repeat(config.body_limits.json + 1),
+            "last_name": "Oversized",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &413);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        body["message"],
+        format!(
+            "Request body is larger than {} bytes",
+            config.body_limits.json
+        )
+    );
+
+    // Syntactically invalid JSON
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .insert_header(ContentType::json())
+        .set_payload(r#"{"email": "invalid@gmail.com", "password": "#)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], "Request body is not valid JSON");
+
+    // Wrong type
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({
+            "email": 42,
+            "password": VALID_PASSWORD,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid value for field email"));
+
+    // Missing field
+    let req = test::TestRequest::post()
+        .uri("/api/auth/sign-in")
+        .set_json(json!({ "password": VALID_PASSWORD }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["message"].as_str().unwrap().contains("email"));
+}
+
+#[actix_web::test]
+async fn test_graphql_body_limit() {
+    let (mut config, db, _, _) = create_base_config().await;
+    config.body_limits.graphql = 1024;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Own limit, higher than the JSON one
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .set_json(json!({ "query": "{ __typename }" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .set_json(json!({
+            "query": format!("{{ __typename }} # {}", "a".repeat(1024)),
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &413);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], "Request body is larger than 1024 bytes");
+
+    // Uploads count against the same limit
+    let boundary = "body-limit-boundary";
+    let operations = r#"{"query": "mutation ($file: Upload!) { uploadDocument(file: $file) { databaseId } }", "variables": {"file": null}}"#;
+    let payload = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"operations\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"map\"\r\n\r\n{{\"0\": [\"variables.file\"]}}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"0\"; filename=\"big.txt\"\r\nContent-Type: text/plain\r\n\r\n{}\r\n\
+         --{b}--\r\n",
+        operations,
+        "a".repeat(2048),
+        b = boundary,
+    );
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(payload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &413);
+
+    // The body itself is counted, whatever its length header says
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(
+            json!({ "query": format!("{{ __typename }} # {}", "a".repeat(2048)) }).to_string(),
+        )
+        .insert_header(("content-length", "64"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &413);
+
+    // Only one file per request
+    let payload = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"operations\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"map\"\r\n\r\n{{\"0\": [\"variables.file\"], \"1\": [\"variables.file\"]}}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"0\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\na\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"1\"; filename=\"b.txt\"\r\nContent-Type: text/plain\r\n\r\nb\r\n\
+         --{b}--\r\n",
+        operations,
+        b = boundary,
+    );
+    let req = test::TestRequest::post()
+        .uri("/api/graphql")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(payload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+}
+
+#[actix_web::test]
+async fn test_resend_confirmation() {
+    let (config, db, _, cache) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, false).await;
+    let confirmed_user = create_user(&db, true).await;
+
+    for email in [
+        user.email.as_str(),
+        confirmed_user.email.as_str(),
+        "unknown@gmail.com",
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/auth/resend-confirmation")
+            .set_json(json!({ "email": email }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(&resp.status().as_u16(), &200);
+        assert!(to_bytes(resp.into_body())
+            .await
+            .unwrap()
+            .as_str()
+            .contains("Confirmation email sent"));
+    }
+
+    // Only the unconfirmed user gets an email
+    assert_eq!(captured_emails(&user.email).len(), 1);
+
+    // Resending within the cooldown answers the same without sending another
+    let req = test::TestRequest::post()
+        .uri("/api/auth/resend-confirmation")
+        .set_json(json!({ "email": &user.email }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("Confirmation email sent"));
+    assert_eq!(captured_emails(&user.email).len(), 1);
+    assert!(captured_emails(&confirmed_user.email).is_empty());
+    assert!(auth_service::get_confirmation_sent_at(&cache, user.id)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(
+        auth_service::get_confirmation_sent_at(&cache, confirmed_user.id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    delete_user(&db, user).await;
+    delete_user(&db, confirmed_user).await;
+}
+
+#[derive(Clone, Default)]
+struct CountingTransport(Arc<AtomicUsize>);
+
+#[async_trait]
+impl EmailTransport for CountingTransport {
+    async fn send(&self, _: &str, _: &str, _: &str, _: &str) -> anyhow::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+async fn jwt_address(jwt: web::Data<Jwt>) -> String {
+    format!("{:p}", jwt.get_ref())
+}
+
+#[actix_web::test]
+async fn test_providers_shared_across_workers() {
+    let (config, db, _, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let metrics = Metrics::new();
+    let transport = CountingTransport::default();
+    let providers = Providers::new(&config, &metrics).with_mailer(Mailer::with_transport(
+        &config.environment,
+        &config.mailer,
+        &metrics,
+        transport.clone(),
+    ));
+    let app_config = ActixApp::build_app_config(&config, &db, &providers);
+
+    // Each service stands for a worker configured with the same closure
+    let mut addresses = Vec::new();
+    for _ in 0..2 {
+        let app = test::init_service(
+            App::new()
+                .configure(app_config.clone())
+                .route("/jwt-address", web::get().to(jwt_address)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/jwt-address").to_request();
+        addresses.push(test::call_and_read_body(&app, req).await);
+        let req = test::TestRequest::post()
+            .uri("/api/auth/forgot-password")
+            .set_json(json!({
+                "email": &user.email,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(&resp.status().as_u16(), &200);
+    }
+    assert_eq!(addresses[0], addresses[1]);
+    assert_eq!(
+        addresses[0].as_str(),
+        format!("{:p}", providers.jwt.get_ref())
+    );
+    assert_eq!(transport.0.load(Ordering::SeqCst), 2);
+
+    // clean user
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_app_starts_from_config() {
+    let (config, _, _, _) = create_base_config().await;
+    let config = Config { port: 0, ..config };
+    let app = ActixApp::from_config(&config).await.unwrap();
+    assert_ne!(app.port(), 0);
+}
+
+#[actix_web::test]
+async fn test_startup_checks() {
+    let (config, db, _, _) = create_base_config().await;
+    let config = Config { port: 0, ..config };
+    let metrics = Metrics::new();
+    let unreachable_redis = || Providers {
+        cache: web::Data::new(Cache::with_url("redis://127.0.0.1:1", &metrics)),
+        ..Providers::new(&config, &metrics)
+    };
+
+    // Production refuses to serve without its dependencies
+    let production = Config {
+        environment: Environment::Production,
+        ..config.clone()
+    };
+    let error = ActixApp::start(&production, &db, unreachable_redis())
+        .await
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("redis"));
+
+    // Unless the checks are off, development only warns
+    let unchecked = Config {
+        startup_checks: StartupChecks::Off,
+        ..production
+    };
+    assert!(ActixApp::start(&unchecked, &db, unreachable_redis())
+        .await
+        .is_ok());
+    let app = ActixApp::start(&config, &db, unreachable_redis())
+        .await
+        .unwrap();
+    assert_ne!(app.port(), 0);
+
+    // The health check reports the checks of a fresh boot
+    let providers = Providers::new(&config, &metrics);
+    let report = run_preflight(&config, &db, &providers).await.unwrap();
+    let app = test::init_service(App::new().configure(ActixApp::build_app_config(
+        &config,
+        &db,
+        &providers.with_startup_report(report),
+    )))
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/api/health-check")
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["startupReport"]["database"], json!("ok"));
+    assert_eq!(body["startupReport"]["cache"], json!("ok"));
+}
+
+#[actix_web::test]
+async fn test_enabled_modules() {
+    let sign_up = |email: String| {
+        json!({
+            "email": email,
+            "first_name": "Module",
+            "last_name": "Tester",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+        })
+    };
+    let graphql = || {
+        test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(json!({ "query": "{ __typename }" }))
+            .to_request()
+    };
+
+    // A REST only auth instance has no GraphQL API nor storage client
+    let app = TestApp::with_config(|config| {
+        config.modules = EnabledModules {
+            graphql: false,
+            auth: true,
+            health: false,
+        }
+    })
+    .await;
+    let providers = Providers::new(&app.config, &Metrics::new());
+    assert!(providers.object_storage.is_none());
+    assert!(providers.oauth.is_some());
+    assert_eq!(app.call(graphql()).await.status().as_u16(), 404);
+    let resp = app
+        .post_json(
+            "/api/auth/sign-up",
+            sign_up(format!("{}@gmail.com", Uuid::new_v4())),
+        )
+        .await;
+    assert!(resp.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/api/health-check")
+        .to_request();
+    assert_eq!(app.call(req).await.status().as_u16(), 404);
+
+    // And a GraphQL only one has no auth controllers nor OAuth clients
+    let app = TestApp::with_config(|config| {
+        config.modules = EnabledModules {
+            graphql: true,
+            auth: false,
+            health: true,
+        }
+    })
+    .await;
+    let providers = Providers::new(&app.config, &Metrics::new());
+    assert!(providers.oauth.is_none());
+    assert!(providers.object_storage.is_some());
+    let resp = app.call(graphql()).await;
+    assert!(resp.status().is_success());
+    let resp = app
+        .post_json(
+            "/api/auth/sign-up",
+            sign_up(format!("{}@gmail.com", Uuid::new_v4())),
+        )
+        .await;
+    assert_eq!(resp.status().as_u16(), 404);
+    let req = test::TestRequest::get()
+        .uri("/api/health-check")
+        .to_request();
+    assert!(app.call(req).await.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_revert_email() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let previous_email = user.email.clone();
+    let email = format!("{}@gmail.com", Uuid::new_v4());
+    let body = app
+        .graphql_as(
+            &user,
+            &format!(
+                r#"mutation {{ updateUserEmail(email: "{}") {{ email }} }}"#,
+                email
+            ),
+        )
+        .await;
+    assert_eq!(body["data"]["updateUserEmail"]["email"], json!(email));
+
+    // Only the previous address is notified, with a hint of the new one
+    assert!(captured_emails(&email).is_empty());
+    let sent = captured_emails(&previous_email);
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].body.contains(&mask_email(&email)));
+    assert!(!sent[0].body.contains(&email));
+    let link = format!("{}/revert-email/", app.config.urls.frontend_url);
+    let token = sent[0].body.split(&link).nth(1).unwrap();
+    let token = token
+        .split(|char: char| !(char.is_ascii_alphanumeric() || "._-".contains(char)))
+        .next()
+        .unwrap()
+        .to_string();
+
+    let resp = app
+        .post_json("/api/auth/revert-email", json!({ "revert_token": &token }))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let reverted = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(reverted.email, previous_email);
+    // Every token issued before the revert is rejected
+    assert!(reverted.min_token_version > user.version + 1);
+
+    // The link works once
+    let resp = app
+        .post_json("/api/auth/revert-email", json!({ "revert_token": &token }))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+
+    // Links of later changes can not undo the revert of an earlier one
+    let mailer = Mailer::new(&app.config.environment, &app.config.mailer, &Metrics::new());
+    let mut revert_tokens = Vec::new();
+    for _ in 0..2 {
+        let from = users_service::find_one_by_id(&app.db.session(), user.id)
+            .await
+            .unwrap();
+        let changed = users_service::update_email(
+            &app.db.session(),
+            &app.cache,
+            &app.jwt,
+            &mailer,
+            &Webhooks::disabled(),
+            user.id,
+            &format!("{}@gmail.com", Uuid::new_v4()),
+        )
+        .await
+        .unwrap();
+        revert_tokens.push(
+            app.jwt
+                .generate_revert_token(&changed, &from.email)
+                .unwrap(),
+        );
+    }
+    let resp = app
+        .post_json(
+            "/api/auth/revert-email",
+            json!({ "revert_token": &revert_tokens[0] }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let resp = app
+        .post_json(
+            "/api/auth/revert-email",
+            json!({ "revert_token": &revert_tokens[1] }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+    let reverted = users_service::find_one_by_id(&app.db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(reverted.email, previous_email);
+
+    // The previous address was taken in the meantime
+    let changed = users_service::update_email(
+        &app.db.session(),
+        &app.cache,
+        &app.jwt,
+        &mailer,
+        &Webhooks::disabled(),
+        user.id,
+        &email,
+    )
+    .await
+    .unwrap();
+    let token = app
+        .jwt
+        .generate_revert_token(&changed, &previous_email)
+        .unwrap();
+    let other_user = app.create_user(true).await;
+    let mut active_user: user::ActiveModel = other_user.into();
+    active_user.email = Set(previous_email.clone());
+    active_user.normalized_email = Set(normalize_email(&previous_email));
+    active_user.update(app.db.get_connection()).await.unwrap();
+    let resp = app
+        .post_json("/api/auth/revert-email", json!({ "revert_token": &token }))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &409);
+
+    // Expired links are rejected
+    let app = TestApp::with_config(|config| config.jwt.revert.exp = -3600).await;
+    let user = app.create_user(true).await;
+    let token = app
+        .jwt
+        .generate_revert_token(&user, &format!("{}@gmail.com", Uuid::new_v4()))
+        .unwrap();
+    let resp = app
+        .post_json("/api/auth/revert-email", json!({ "revert_token": &token }))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &401);
+}
+
+/// Stands in for the provider's siteverify endpoint, answering every call the same way.
+fn spawn_captcha_server(success: bool, delay_ms: u64) -> (String, actix_web::dev::ServerHandle) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+    let server = actix_web::HttpServer::new(move || {
+        App::new().route(
+            "/siteverify",
+            web::post().to(move |form: web::Form<HashMap<String, String>>| async move {
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+                let valid = form.get("secret").map(String::as_str) == Some("captcha_secret")
+                    && form.get("response").map(String::as_str) == Some("human");
+                actix_web::HttpResponse::Ok().json(json!({ "success": success && valid }))
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    (url, handle)
+}
+
+#[actix_web::test]
+async fn test_sign_up_captcha() {
+    let sign_up = |captcha_token: Option<&str>| {
+        json!({
+            "email": format!("{}@gmail.com", Uuid::new_v4()),
+            "first_name": "John",
+            "last_name": "Doe",
+            "date_of_birth": "1990-01-01",
+            "password1": VALID_PASSWORD,
+            "password2": VALID_PASSWORD,
+            "accepted_terms": true,
+            "captcha_token": captcha_token,
+        })
+    };
+    let captcha_app = |url: String, fail_open: bool| {
+        TestApp::with_config(move |config| {
+            config.captcha.provider = CaptchaProviderKind::Turnstile;
+            config.captcha.secret = Secret::new("captcha_secret".to_string());
+            config.captcha.verify_url = url;
+            config.captcha.timeout_ms = 200;
+            config.captcha.fail_open = fail_open;
+        })
+    };
+    let assert_captcha_failed = |body: Bytes| {
+        assert!(body.as_str().contains(CAPTCHA_FAILED));
+    };
+
+    // Without a provider the token is not needed
+    let app = TestApp::new().await;
+    let resp = app.post_json("/api/auth/sign-up", sign_up(None)).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // The provider accepts the token
+    let (url, handle) = spawn_captcha_server(true, 0);
+    let app = captcha_app(url, false).await;
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(Some("human")))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let resp = app.post_json("/api/auth/sign-up", sign_up(None)).await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    assert_captcha_failed(test::read_body(resp).await);
+    handle.stop(false).await;
+
+    // The provider rejects the token
+    let (url, handle) = spawn_captcha_server(false, 0);
+    let app = captcha_app(url, false).await;
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(Some("human")))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    assert_captcha_failed(test::read_body(resp).await);
+    handle.stop(false).await;
+
+    // The provider does not answer in time, closed by default
+    let (url, handle) = spawn_captcha_server(true, 1000);
+    let app = captcha_app(url.clone(), false).await;
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(Some("human")))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    assert_captcha_failed(test::read_body(resp).await);
+    let app = captcha_app(url, true).await;
+    let resp = app
+        .post_json("/api/auth/sign-up", sign_up(Some("human")))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn test_password_strength() {
+    let app = TestApp::new().await;
+    let strength = |password: &'static str| {
+        let app = &app;
+        async move {
+            let resp = app
+                .post_json(
+                    "/api/auth/password-strength",
+                    json!({ "password": password }),
+                )
+                .await;
+            assert_eq!(&resp.status().as_u16(), &200);
+            serde_json::from_slice::<responses::PasswordStrength>(&test::read_body(resp).await)
+                .unwrap()
+        }
+    };
+
+    // Passes the character classes, but is one of the most common passwords
+    let weak = strength("Password1!").await;
+    assert!(weak.valid);
+    assert!(weak.score <= 1);
+    assert!(!weak.feedback.is_empty());
+
+    let invalid = strength("password").await;
+    assert!(!invalid.valid);
+    assert_eq!(invalid.score, 0);
+    assert!(invalid.feedback[0].starts_with("Password must contain at least one number"));
+
+    let strong = strength("tangerine-Velvet-Orbit-93").await;
+    assert!(strong.valid);
+    assert!(strong.score >= 3);
+
+    let resp = app
+        .post_json("/api/auth/password-strength", json!({ "password": "" }))
+        .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+}
+
+/// Answers every range with the given body, after a delay.
+struct MockPwnedRange {
+    body: String,
+    delay_ms: u64,
+}
+
+#[async_trait]
+impl PwnedRange for MockPwnedRange {
+    async fn range(&self, _: &str) -> anyhow::Result<String> {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(self.delay_ms)).await;
+        Ok(self.body.clone())
+    }
+}
+
+#[actix_web::test]
+async fn test_sign_up_breached_password() {
+    let (config, db, _, _) = create_base_config().await;
+    let breach_config = PasswordBreachConfig {
+        enabled: true,
+        url: String::new(),
+        timeout_ms: 200,
+    };
+    let hash = format!("{:X}", Sha1::digest(VALID_PASSWORD.as_bytes()));
+    let breached_body = format!(
+        "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n{}:3861493",
+        &hash[5..]
+    );
+    let padded_body = format!("{}:0", &hash[5..]);
+    let sign_up = |breaches: MockPwnedRange| {
+        let app = App::new().configure(ActixApp::build_app_config(
+            &config,
+            &db,
+            &Providers::new(&config, &Metrics::new())
+                .with_breach_checker(BreachChecker::with_range(&breach_config, breaches)),
+        ));
+        async move {
+            let app = test::init_service(app).await;
+            let req = test::TestRequest::post()
+                .uri("/api/auth/sign-up")
+                .set_json(json!({
+                    "email": format!("{}@gmail.com", Uuid::new_v4()),
+                    "first_name": "John",
+                    "last_name": "Doe",
+                    "date_of_birth": "1990-01-01",
+                    "password1": VALID_PASSWORD,
+                    "password2": VALID_PASSWORD,
+                    "accepted_terms": true,
+                }))
+                .to_request();
+            test::call_service(&app, req).await
+        }
+    };
+
+    let resp = sign_up(MockPwnedRange {
+        body: breached_body.clone(),
+        delay_ms: 0,
+    })
+    .await;
+    assert_eq!(&resp.status().as_u16(), &400);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains(BREACHED_PASSWORD));
+
+    // Padding entries are not breaches
+    let resp = sign_up(MockPwnedRange {
+        body: padded_body,
+        delay_ms: 0,
+    })
+    .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // The range does not come back in time, the sign up goes on
+    let resp = sign_up(MockPwnedRange {
+        body: breached_body,
+        delay_ms: 1000,
+    })
+    .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[actix_web::test]
+async fn test_oauth_link() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+
+    // Linking needs a session, the state remembers whose it is
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/auth/ext/google/link")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(resp.status().as_u16(), 401);
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/auth/ext/google/link")
+                .insert_header(("Authorization", app.bearer_for(&user)))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let link_cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "oauth_link")
+        .unwrap()
+        .into_owned();
+    assert_eq!(link_cookie.http_only(), Some(true));
+    assert_eq!(link_cookie.same_site(), Some(SameSite::Lax));
+    assert_eq!(link_cookie.path(), Some("/api/auth/ext"));
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let state = Url::parse(body["url"].as_str().unwrap())
+        .unwrap()
+        .query_pairs()
+        .find(|(name, _)| name == "state")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    let (_, _, link, _) = OAuth::new(&app.config.oauth, &app.config.http_client)
+        .verify_state(&ExternalProvider::Google, &state)
+        .unwrap();
+    assert_eq!(link, Some((user.id, link_cookie.value().to_string())));
+
+    // A link callback only completes in the browser that started it
+    let error_redirect = format!(
+        "{}/auth/error?code=unauthorized",
+        app.config.urls.frontend_url
+    );
+    let callback_uri = format!("/api/auth/ext/google/callback?code=abc&state={}", state);
+    let resp = app
+        .call(test::TestRequest::get().uri(&callback_uri).to_request())
+        .await;
+    assert_eq!(resp.status().as_u16(), 302);
+    assert_eq!(
+        resp.headers().get("location").unwrap().to_str().unwrap(),
+        error_redirect
+    );
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri(&callback_uri)
+                .cookie(Cookie::new("oauth_link", "another_browser"))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(resp.status().as_u16(), 302);
+    assert_eq!(
+        resp.headers().get("location").unwrap().to_str().unwrap(),
+        error_redirect
+    );
+    let removal = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "oauth_link")
+        .unwrap();
+    assert_eq!(removal.value(), "");
+
+    // The provider reports another address than the account's
+    let provider_email = format!("{}@gmail.com", Uuid::new_v4());
+    let user_info = |email: &str| responses::UserInfo {
+        first_name: "John".to_string(),
+        last_name: "Doe".to_string(),
+        email: email.to_string(),
+        date_of_birth: None,
+        picture: None,
+    };
+    auth_service::oauth_link_callback(
+        &app.db.session(),
+        ExternalProvider::Google,
+        user.id,
+        user_info(&provider_email),
+    )
+    .await
+    .unwrap();
+    let link = oauth_provider::Entity::find_by_email_and_provider(
+        &user.email,
+        enums::OAuthProviderEnum::Google,
+    )
+    .one(app.db.get_connection())
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(link.provider_email, Some(provider_email.clone()));
+
+    // Signing in with the linked identity reaches the same account
+    let signed_in = users_service::find_or_create(
+        &app.db.session(),
+        &Webhooks::disabled(),
+        &TermsVersion::default(),
+        SignUpMode::Open,
+        enums::OAuthProviderEnum::Google,
+        "John".to_string(),
+        "Doe".to_string(),
+        None,
+        provider_email.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(signed_in.id, user.id);
+
+    // An identity belongs to one account
+    let other_user = app.create_user(true).await;
+    let error = auth_service::oauth_link_callback(
+        &app.db.session(),
+        ExternalProvider::Google,
+        other_user.id,
+        user_info(&provider_email),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.get_status_code(), 409);
+    let error = auth_service::oauth_link_callback(
+        &app.db.session(),
+        ExternalProvider::Github,
+        other_user.id,
+        user_info(&user.email),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.get_status_code(), 409);
+}
+
+#[actix_web::test]
+async fn test_maintenance_mode() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let mut admin: user::ActiveModel = app.create_user(true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(app.db.get_connection()).await.unwrap();
+    let sign_in = json!({
+        "email": &user.email,
+        "password": VALID_PASSWORD,
+    });
+
+    // Only admins can toggle it, and only until a date still to come
+    let body = app
+        .graphql_as(
+            &user,
+            "mutation { setMaintenanceMode(enabled: true) { enabled } }",
+        )
+        .await;
+    assert_eq!(body["errors"][0]["message"], json!("Forbidden"));
+    let body = app
+        .graphql_as(
+            &admin,
+            r#"mutation { setMaintenanceMode(enabled: true, until: "2000-01-01T00:00:00Z") { enabled } }"#,
+        )
+        .await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        json!("Maintenance must end in the future")
+    );
+    assert!(app.maintenance.status().await.is_none());
+
+    let until = Utc::now() + Duration::hours(1);
+    let body = app
+        .graphql_as(
+            &admin,
+            &format!(
+                r#"mutation {{ setMaintenanceMode(enabled: true, message: "Upgrading the database", until: "{}") {{ enabled message until }} }}"#,
+                until.to_rfc3339()
+            ),
+        )
+        .await;
+    assert_eq!(body["data"]["setMaintenanceMode"]["enabled"], json!(true));
+    assert_eq!(
+        body["data"]["setMaintenanceMode"]["until"],
+        json!(until.timestamp())
+    );
+
+    let resp = app.post_json("/api/auth/sign-in", sign_in.clone()).await;
+    assert_eq!(&resp.status().as_u16(), &503);
+    let retry_after = resp
+        .headers()
+        .get("retry-after")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse::<i64>()
+        .unwrap();
+    assert!(retry_after > 3500 && retry_after <= 3600);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("Upgrading the database"));
+
+    // The health check stays up
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/health-check")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+
+    // GraphQL answers with a structured error instead
+    let body = app.graphql_as(&user, "query { me { id } }").await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        json!("Upgrading the database")
+    );
+    assert_eq!(body["errors"][0]["extensions"]["code"], json!("503"));
+    assert!(body["errors"][0]["extensions"]["retryAfter"].is_number());
+    let body = app
+        .graphql_as(
+            &admin,
+            "mutation { setMaintenanceMode(enabled: false) { enabled } }",
+        )
+        .await;
+    assert_eq!(body["data"]["setMaintenanceMode"]["enabled"], json!(false));
+
+    let resp = app.post_json("/api/auth/sign-in", sign_in).await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let body = app.graphql_as(&user, "query { me { id } }").await;
+    assert!(body["errors"].is_null());
+
+    // Redis being down lets every request through
+    let cache = Cache::with_url("redis://127.0.0.1:1", &Metrics::new());
+    assert!(Maintenance::new(&cache).status().await.is_none());
+}
+
+#[actix_web::test]
+async fn test_openapi_document() {
+    let refresh_name = "docs_refresh";
+    let app =
+        TestApp::with_config(|config| config.jwt.refresh_name = refresh_name.to_string()).await;
+
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/openapi.json")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let document: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(document["openapi"], json!("3.0.3"));
+
+    let sign_in = &document["paths"]["/api/auth/sign-in"]["post"];
+    assert_eq!(
+        sign_in["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        json!("#/components/schemas/SignIn")
+    );
+    let unauthorized = &sign_in["responses"]["401"]["content"]["application/json"]["schema"];
+    assert_eq!(
+        unauthorized["$ref"],
+        json!("#/components/schemas/ErrorBody")
+    );
+    let error_body = &document["components"]["schemas"]["ErrorBody"];
+    assert_eq!(error_body["required"], json!(["message"]));
+    assert!(error_body["properties"]["request_id"].is_object());
+    assert!(sign_in["responses"]["200"]["headers"]["Set-Cookie"].is_object());
+
+    // The refresh flow takes the cookie or the body, the settings routes a bearer token
+    let refresh = &document["paths"]["/api/auth/refresh-token"]["post"];
+    assert_eq!(refresh["security"], json!([{ "refreshCookie": [] }, {}]));
+    assert_eq!(refresh["requestBody"]["required"], json!(false));
+    assert_eq!(
+        document["paths"]["/api/auth/update-password"]["post"]["security"],
+        json!([{ "bearerAuth": [] }])
+    );
+    let schemes = &document["components"]["securitySchemes"];
+    assert_eq!(schemes["refreshCookie"]["in"], json!("cookie"));
+    assert_eq!(schemes["refreshCookie"]["name"], json!(refresh_name));
+    assert_eq!(schemes["bearerAuth"]["scheme"], json!("bearer"));
+    assert!(document["paths"]["/api/auth/ext/github/callback"]["get"].is_object());
+    assert!(document["paths"]["/api/health-check"]["get"].is_object());
+
+    let resp = app
+        .call(test::TestRequest::get().uri("/api/docs").to_request())
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("/api/openapi.json"));
+
+    // Turned off, the routes do not exist and the health check is unaffected
+    let app = TestApp::with_config(|config| config.api_docs = false).await;
+    for path in ["/api/openapi.json", "/api/docs"] {
+        let resp = app
+            .call(test::TestRequest::get().uri(path).to_request())
+            .await;
+        assert_eq!(&resp.status().as_u16(), &404);
+    }
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/health-check")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[actix_web::test]
+async fn test_playground_and_security_headers() {
+    let app = TestApp::new().await;
+
+    let resp = app
+        .call(test::TestRequest::get().uri("/api/graphql").to_request())
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    assert_eq!(
+        resp.headers().get("content-security-policy").unwrap(),
+        PLAYGROUND_CSP
+    );
+    assert_eq!(resp.headers().get("x-frame-options").unwrap(), "DENY");
+    assert!(test::read_body(resp)
+        .await
+        .as_str()
+        .contains("GraphQL Playground"));
+
+    let resp = app
+        .post_json(
+            "/api/graphql",
+            json!({ "query": "query { healthCheck { message } }" }),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+    let headers = resp.headers();
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(headers.get("x-frame-options").unwrap(), "DENY");
+    assert_eq!(headers.get("referrer-policy").unwrap(), "no-referrer");
+    assert!(headers.get("content-security-policy").is_none());
+
+    // Production leaves it off, GraphQL GETs still go through
+    let app = TestApp::with_config(|config| config.playground = false).await;
+    let resp = app
+        .call(test::TestRequest::get().uri("/api/graphql").to_request())
+        .await;
+    assert_eq!(&resp.status().as_u16(), &404);
+    assert_eq!(
+        resp.headers().get("x-content-type-options").unwrap(),
+        "nosniff"
+    );
+    let resp = app
+        .call(
+            test::TestRequest::get()
+                .uri("/api/graphql?query=%7BhealthCheck%7Bmessage%7D%7D")
+                .to_request(),
+        )
+        .await;
+    assert_eq!(&resp.status().as_u16(), &200);
+}
+
+#[actix_web::test]
+async fn test_user_info_error_statuses() {
+    let unavailable = auth_service::user_info_error(
+        &ExternalProvider::Google,
+        responses::UserInfoError::Unavailable("connection refused".to_string()),
+    );
+    assert_eq!(unavailable.get_status_code(), 502);
+
+    let missing = auth_service::user_info_error(
+        &ExternalProvider::Facebook,
+        responses::UserInfoError::MissingField {
+            field: "email",
+            permission: "email",
+        },
+    );
+    assert_eq!(missing.get_status_code(), 400);
+    assert_eq!(
+        missing.to_string(),
+        "Facebook did not share your email, grant the \"email\" permission and try again"
+    );
+
+    let malformed = auth_service::user_info_error(
+        &ExternalProvider::Github,
+        responses::UserInfoError::Malformed {
+            error: "expected a string".to_string(),
+            body: "{}".to_string(),
+        },
+    );
+    assert_eq!(malformed.get_status_code(), 500);
+}
+
+#[actix_web::test]
+async fn test_user_info_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/userinfo", listener.local_addr().unwrap());
+    let server = actix_web::HttpServer::new(|| {
+        App::new().route(
+            "/userinfo",
+            web::get().to(|| async {
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(500)).await;
+                actix_web::HttpResponse::Ok().json(json!({ "email": "john@gmail.com" }))
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let http_client = HttpClient::new(&HttpClientConfig {
+        connect_timeout_ms: 100,
+        timeout_ms: 100,
+        proxy: None,
+    });
+
+    let error = auth_service::get_external_user_info::<responses::GoogleUserInfoResponse>(
+        &http_client,
+        &url,
+        "Bearer token",
+    )
+    .await
+    .unwrap_err();
+    let error = auth_service::user_info_error(&ExternalProvider::Google, error);
+    assert_eq!(error.get_status_code(), 502);
+    assert_eq!(
+        error.to_string(),
+        "Google is unavailable, please try again later"
+    );
+    handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn test_admin_import_users() {
+    let app = TestApp::new().await;
+    let existing = app.create_user(true).await;
+    let user = app.create_user(true).await;
+    let mut admin: user::ActiveModel = app.create_user(true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(app.db.get_connection()).await.unwrap();
+    let emails = (0..3)
+        .map(|_| format!("{}@example.com", Uuid::new_v4()))
+        .collect::<Vec<String>>();
+    let csv = format!(
+        "email,first_name,last_name,date_of_birth,role\n\
+         {},Jane,Doe,1990-01-31,\n\
+         invalid-date@example.com,John,Doe,1990-13-45,user\n\
+         {},John,Smith,,\n\
+         {},\"Ann\",\"Lee\",1985-06-15,staff\n\
+         {},Mary,Jones,,admin\n",
+        emails[0], existing.email, emails[1], emails[2],
+    );
+    let boundary = "import-boundary";
+    let payload = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"users.csv\"\r\n\
+         Content-Type: text/csv\r\n\r\n{}\r\n--{b}--\r\n",
+        csv,
+        b = boundary,
+    );
+    let request = |bearer: &str| {
+        test::TestRequest::post()
+            .uri("/api/admin/users/import?send_reset_emails=true")
+            .insert_header(("Authorization", bearer.to_string()))
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(payload.clone())
+            .to_request()
+    };
+
+    let resp = app.call(request(&app.bearer_for(&user))).await;
+    assert_eq!(resp.status().as_u16(), 403);
+
+    // Impersonated sessions are refused even with the admin role
+    let impersonation_token = app
+        .jwt
+        .generate_impersonation_token(&admin, user.id)
+        .unwrap();
+    let resp = app
+        .call(request(&format!("Bearer {}", impersonation_token)))
+        .await;
+    assert_eq!(resp.status().as_u16(), 403);
+
+    let resp = app.call(request(&app.bearer_for(&admin))).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let report: responses::ImportReport = test::read_body_json(resp).await;
+    assert_eq!(report.created, 3);
+    let failures = report
+        .failed
+        .iter()
+        .map(|failure| (failure.line, failure.status))
+        .collect::<Vec<_>>();
+    assert_eq!(failures, vec![(3, 400), (4, 409)]);
+    assert_eq!(report.failed[0].errors[0].field, "date_of_birth");
+    assert_eq!(
+        report.failed[1].email.as_deref(),
+        Some(existing.email.as_str())
+    );
+
+    for (email, role) in emails.iter().zip([
+        enums::RoleEnum::User,
+        enums::RoleEnum::Staff,
+        enums::RoleEnum::Admin,
+    ]) {
+        let imported = user::Entity::find_by_email(email)
+            .one(app.db.get_connection())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(imported.confirmed);
+        assert_eq!(imported.role, role);
+        assert!(oauth_provider::Entity::find_by_email_and_provider(
+            email,
+            enums::OAuthProviderEnum::Local
+        )
+        .one(app.db.get_connection())
+        .await
+        .unwrap()
+        .is_some());
+        let reset_emails = email_outbox::Entity::find()
+            .filter(email_outbox::Column::Recipient.eq(email.as_str()))
+            .count(app.db.get_connection())
+            .await
+            .unwrap();
+        assert_eq!(reset_emails, 1);
+    }
+}
+
+async fn set_last_active_hours_ago(
+    db: &Database,
+    user: &user::Model,
+    hours: Option<i64>,
+) -> user::Model {
+    let mut user: user::ActiveModel = user.clone().into();
+    user.last_active_at = Set(hours.map(|hours| (Utc::now() - Duration::hours(hours)).naive_utc()));
+    user.update(db.get_connection()).await.unwrap()
+}
+
+#[actix_web::test]
+async fn test_online_status() {
+    let app = TestApp::new().await;
+    let user = app.create_user(true).await;
+    let viewer = app.create_user(true).await;
+    let status_query = format!(
+        r#"query {{ userByUsername(username: "{}") {{ onlineStatus }} }}"#,
+        user.username
+    );
+    let online_status =
+        |body: serde_json::Value| body["data"]["userByUsername"]["onlineStatus"].clone();
+
+    // Never active
+    let body = app.graphql_as(&viewer, &status_query).await;
+    assert_eq!(online_status(body), json!("OFFLINE"));
+
+    // Any authenticated request marks the user online and stores the activity
+    let body = app.graphql_as(&user, "query { me { id } }").await;
+    assert!(body["errors"].is_null());
+    let body = app.graphql_as(&viewer, &status_query).await;
+    assert_eq!(online_status(body), json!("ONLINE"));
+    let stored = user::Entity::find_by_id(user.id)
+        .one(app.db.get_connection())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.last_active_at.is_some());
+
+    // Once the presence expires the stored activity decides
+    app.cache
+        .del(&format!("presence:{}", user.id))
+        .await
+        .unwrap();
+    let body = app.graphql_as(&viewer, &status_query).await;
+    assert_eq!(online_status(body), json!("RECENTLY_ACTIVE"));
+    set_last_active_hours_ago(&app.db, &stored, Some(48)).await;
+    let body = app.graphql_as(&viewer, &status_query).await;
+    assert_eq!(online_status(body), json!("OFFLINE"));
+
+    // Within the minute the next request is throttled, nothing is written
+    app.graphql_as(&user, "query { me { id } }").await;
+    let body = app.graphql_as(&viewer, &status_query).await;
+    assert_eq!(online_status(body), json!("OFFLINE"));
+}
+
+#[actix_web::test]
+async fn test_users_by_activity() {
+    let app = TestApp::new().await;
+    let never = app.create_user(true).await;
+    let recent = app.create_user(true).await;
+    let recent = set_last_active_hours_ago(&app.db, &recent, Some(1)).await;
+    let older = app.create_user(true).await;
+    let older = set_last_active_hours_ago(&app.db, &older, Some(5)).await;
+    // Same activity as `older`, the id keeps them apart
+    let tied = app.create_user(true).await;
+    let mut tied: user::ActiveModel = tied.into();
+    tied.last_active_at = Set(older.last_active_at);
+    let tied = tied.update(app.db.get_connection()).await.unwrap();
+    let page = |after: Option<String>| {
+        format!(
+            r#"query {{
+                users(order: DESC, cursor: ACTIVITY, limit: 2{}) {{
+                    edges {{ cursor node {{ username onlineStatus }} }}
+                    pageInfo {{ hasNextPage endCursor }}
+                }}
+            }}"#,
+            after
+                .map(|after| format!(r#", after: "{}""#, after))
+                .unwrap_or_default()
+        )
+    };
+    let usernames = |body: &serde_json::Value| {
+        body["data"]["users"]["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| edge["node"]["username"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let body = app.graphql(&page(None)).await;
+    assert_eq!(usernames(&body), vec![recent.username, tied.username]);
+    assert_eq!(
+        body["data"]["users"]["edges"][0]["node"]["onlineStatus"],
+        json!("RECENTLY_ACTIVE")
+    );
+    assert_eq!(
+        body["data"]["users"]["pageInfo"]["hasNextPage"],
+        json!(true)
+    );
+    let end_cursor = body["data"]["users"]["pageInfo"]["endCursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Never active users come last
+    let body = app.graphql(&page(Some(end_cursor))).await;
+    assert_eq!(usernames(&body), vec![older.username, never.username]);
+    assert_eq!(
+        body["data"]["users"]["edges"][1]["node"]["onlineStatus"],
+        json!("OFFLINE")
+    );
+    assert_eq!(
+        body["data"]["users"]["pageInfo"]["hasNextPage"],
+        json!(false)
+    );
 }
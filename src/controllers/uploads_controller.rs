@@ -0,0 +1,38 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use actix_web::{http::header::CONTENT_TYPE, web, HttpResponse, Scope};
+use uuid::Uuid;
+
+use crate::common::ServiceError;
+use crate::providers::{content_type_for, ObjectStorage, UPLOADS_PATH};
+
+async fn uploaded_file(
+    object_storage: web::Data<ObjectStorage>,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, ServiceError> {
+    let (user_prefix, file_name) = path.into_inner();
+
+    // Only the files of a user prefix, never hidden entries such as multipart parts
+    if file_name.starts_with('.') || file_name.contains(['/', '\\']) {
+        return Err(ServiceError::not_found::<ServiceError>(
+            "File not found",
+            None,
+        ));
+    }
+
+    let contents = object_storage
+        .get_file(&format!("{}/{}", user_prefix, file_name))
+        .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, content_type_for(&file_name)))
+        .body(contents))
+}
+
+/// Only registered for the filesystem backend, S3 serves its own objects.
+pub fn uploads_router() -> Scope {
+    web::scope(UPLOADS_PATH).route("/{user_prefix}/{file_name}", web::get().to(uploaded_file))
+}
@@ -11,13 +11,19 @@ use async_graphql::*;
 
 use file_loader::load_files;
 pub use file_loader::FileId;
+use presence_loader::load_presences;
+pub use presence_loader::PresenceId;
+use provider_loader::load_providers;
+pub use provider_loader::UserEmail;
 use user_loader::load_users;
 pub use user_loader::UserId;
 
-use crate::dtos::objects::{UploadedFile, User};
-use crate::providers::Database;
+use crate::dtos::objects::{LinkedProvider, UploadedFile, User};
+use crate::providers::{Cache, Database};
 
 pub mod file_loader;
+pub mod presence_loader;
+pub mod provider_loader;
 pub mod user_loader;
 
 pub struct SeaOrmLoader {
@@ -36,7 +42,7 @@ impl Loader<FileId> for SeaOrmLoader {
     type Error = Error;
 
     async fn load(&self, keys: &[FileId]) -> Result<HashMap<FileId, Self::Value>, Self::Error> {
-        load_files(self.db.get_connection(), keys).await
+        load_files(self.db.get_read_connection(), keys).await
     }
 }
 
@@ -46,6 +52,45 @@ impl Loader<UserId> for SeaOrmLoader {
     type Error = Error;
 
     async fn load(&self, keys: &[UserId]) -> Result<HashMap<UserId, Self::Value>, Self::Error> {
-        load_users(self.db.get_connection(), keys).await
+        load_users(self.db.get_read_connection(), keys).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader<UserEmail> for SeaOrmLoader {
+    type Value = Vec<LinkedProvider>;
+    type Error = Error;
+
+    async fn load(
+        &self,
+        keys: &[UserEmail],
+    ) -> Result<HashMap<UserEmail, Self::Value>, Self::Error> {
+        load_providers(self.db.get_read_connection(), keys).await
+    }
+}
+
+/// Loads from Redis, so list queries read every presence in one round trip.
+pub struct CacheLoader {
+    cache: Cache,
+}
+
+impl CacheLoader {
+    pub fn new(cache: &Cache) -> Self {
+        Self {
+            cache: cache.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader<PresenceId> for CacheLoader {
+    type Value = i64;
+    type Error = Error;
+
+    async fn load(
+        &self,
+        keys: &[PresenceId],
+    ) -> Result<HashMap<PresenceId, Self::Value>, Self::Error> {
+        load_presences(&self.cache, keys).await
     }
 }
@@ -0,0 +1,28 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use async_graphql::Result;
+
+use crate::providers::Cache;
+use crate::services::presence_service;
+
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug)]
+pub struct PresenceId(pub i32);
+
+/// Only online users are in the map, with the timestamp of their last request.
+pub async fn load_presences(
+    cache: &Cache,
+    keys: &[PresenceId],
+) -> Result<HashMap<PresenceId, i64>> {
+    let ids = keys.iter().map(|key| key.0).collect::<Vec<i32>>();
+    Ok(presence_service::find_online(cache, &ids)
+        .await?
+        .into_iter()
+        .map(|(id, last_seen)| (PresenceId(id), last_seen))
+        .collect())
+}
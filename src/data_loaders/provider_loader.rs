@@ -0,0 +1,45 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use async_graphql::{Error, Result};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
+
+use entities::oauth_provider::{Column, Entity};
+
+use crate::dtos::objects::LinkedProvider;
+
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub struct UserEmail(pub String);
+
+/// Every user has at least their sign up provider, a missing key only means the user
+/// was deleted in the meantime.
+pub async fn load_providers(
+    connection: &DatabaseConnection,
+    keys: &[UserEmail],
+) -> Result<HashMap<UserEmail, Vec<LinkedProvider>>> {
+    let emails = keys
+        .iter()
+        .map(|key| key.0.clone())
+        .collect::<Vec<String>>();
+    let providers = Entity::find()
+        .filter(Column::UserEmail.is_in(emails))
+        .order_by_asc(Column::CreatedAt)
+        .all(connection)
+        .await
+        .map_err(|_| Error::from("Error loading providers"))?;
+
+    let mut linked = HashMap::<UserEmail, Vec<LinkedProvider>>::new();
+    for provider in providers {
+        linked
+            .entry(UserEmail(provider.user_email.clone()))
+            .or_default()
+            .push(provider.into());
+    }
+
+    Ok(linked)
+}
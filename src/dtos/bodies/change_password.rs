@@ -5,14 +5,19 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_not_empty, validate_passwords, validations_handler, ServiceError};
+use crate::common::{
+    validate_not_empty, validate_passwords, validations_handler, ApiSchema, ServiceError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChangePassword {
     pub old_password: String,
     pub password1: String,
     pub password2: String,
+    #[serde(default, alias = "signOutEverywhere")]
+    pub sign_out_everywhere: bool,
 }
 
 impl ChangePassword {
@@ -25,3 +30,24 @@ impl ChangePassword {
         Ok(self)
     }
 }
+
+impl ApiSchema for ChangePassword {
+    const NAME: &'static str = "ChangePassword";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["old_password", "password1", "password2"],
+            "properties": {
+                "old_password": { "type": "string", "format": "password" },
+                "password1": { "type": "string", "format": "password" },
+                "password2": { "type": "string", "format": "password" },
+                "sign_out_everywhere": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Revokes every other session",
+                },
+            },
+        })
+    }
+}
@@ -5,8 +5,59 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::{
+    validate_not_empty, validations_handler, ApiSchema, ServiceError, ValidatorEnum,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChangeTwoFactor {
     pub two_factor: bool,
+    /// Required when the account has a password.
+    pub password: Option<String>,
+    /// Emailed code confirming the change on accounts that only use external providers.
+    pub code: Option<String>,
+}
+
+impl ChangeTwoFactor {
+    pub fn validate(self) -> Result<Self, ServiceError> {
+        let validations = [
+            self.password
+                .as_deref()
+                .map_or(ValidatorEnum::Valid, |password| {
+                    validate_not_empty("Password", password)
+                }),
+            self.code.as_deref().map_or(ValidatorEnum::Valid, |code| {
+                validate_not_empty("Code", code)
+            }),
+        ];
+        validations_handler(&validations)?;
+        Ok(self)
+    }
+}
+
+impl ApiSchema for ChangeTwoFactor {
+    const NAME: &'static str = "ChangeTwoFactor";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["two_factor"],
+            "properties": {
+                "two_factor": { "type": "boolean" },
+                "password": {
+                    "type": "string",
+                    "format": "password",
+                    "nullable": true,
+                    "description": "Required when the account has a password",
+                },
+                "code": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Emailed code confirming the change on accounts without a password",
+                },
+            },
+        })
+    }
 }
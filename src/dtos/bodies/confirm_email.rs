@@ -5,8 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_jwt, validations_handler, ServiceError};
+use crate::common::{validate_jwt, validations_handler, ApiSchema, ServiceError};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfirmEmail {
@@ -23,3 +24,17 @@ impl ConfirmEmail {
         Ok(self)
     }
 }
+
+impl ApiSchema for ConfirmEmail {
+    const NAME: &'static str = "ConfirmEmail";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["confirmation_token"],
+            "properties": {
+                "confirmation_token": { "type": "string" },
+            },
+        })
+    }
+}
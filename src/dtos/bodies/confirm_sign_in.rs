@@ -5,22 +5,51 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_email, validate_not_empty, validations_handler, ServiceError};
+use crate::common::{
+    validate_email, validate_not_empty, validations_handler, ApiSchema, ServiceError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfirmSignIn {
     pub email: String,
+    #[serde(default)]
     pub code: String,
+    /// Used instead of the emailed code when present.
+    pub recovery_code: Option<String>,
 }
 
 impl ConfirmSignIn {
     pub fn validate(self) -> Result<Self, ServiceError> {
         let validations = [
             validate_email(&self.email)?,
-            validate_not_empty("Code", &self.code),
+            match &self.recovery_code {
+                Some(recovery_code) => validate_not_empty("Recovery code", recovery_code),
+                None => validate_not_empty("Code", &self.code),
+            },
         ];
         validations_handler(&validations)?;
         Ok(self)
     }
 }
+
+impl ApiSchema for ConfirmSignIn {
+    const NAME: &'static str = "ConfirmSignIn";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["email"],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+                "code": { "type": "string", "default": "" },
+                "recovery_code": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Used instead of the emailed code when present",
+                },
+            },
+        })
+    }
+}
@@ -5,12 +5,15 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_email, validations_handler, ServiceError};
+use crate::common::{validate_email, validations_handler, ApiSchema, ServiceError};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Email {
     pub email: String,
+    /// Only checked on forgot password, when a captcha provider is configured.
+    pub captcha_token: Option<String>,
 }
 
 impl Email {
@@ -20,3 +23,22 @@ impl Email {
         Ok(self)
     }
 }
+
+impl ApiSchema for Email {
+    const NAME: &'static str = "Email";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["email"],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+                "captcha_token": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Only checked on forgot password, when a captcha provider is configured",
+                },
+            },
+        })
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use anyhow::Error;
+use entities::enums::RoleEnum;
+
+use crate::common::{
+    field_validations_handler, validate_date_of_birth, validate_email, validate_name, ServiceError,
+    ValidatorEnum,
+};
+
+pub const IMPORT_COLUMNS: [&str; 5] = ["email", "first_name", "last_name", "date_of_birth", "role"];
+
+/// A user row of an admin CSV import.
+#[derive(Debug)]
+pub struct ImportedUser {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub date_of_birth: Option<String>,
+    pub role: RoleEnum,
+}
+
+fn parse_role(role: &str) -> Option<RoleEnum> {
+    match role.to_lowercase().as_str() {
+        "" | "user" => Some(RoleEnum::User),
+        "staff" => Some(RoleEnum::Staff),
+        "admin" => Some(RoleEnum::Admin),
+        _ => None,
+    }
+}
+
+/// Where each column is in the header, `date_of_birth` and `role` may be left out.
+#[derive(Debug)]
+pub struct ImportedUserColumns {
+    email: usize,
+    first_name: usize,
+    last_name: usize,
+    date_of_birth: Option<usize>,
+    role: Option<usize>,
+}
+
+impl ImportedUserColumns {
+    pub fn from_header(header: &[String]) -> Result<Self, ServiceError> {
+        let position = |name: &str| {
+            header
+                .iter()
+                .position(|column| column.trim().eq_ignore_ascii_case(name))
+        };
+        let required = |name: &str| {
+            position(name).ok_or_else(|| {
+                ServiceError::bad_request::<Error>(
+                    &format!(
+                        "The CSV header is missing the {} column, expected {}",
+                        name,
+                        IMPORT_COLUMNS.join(",")
+                    ),
+                    None,
+                )
+            })
+        };
+
+        Ok(Self {
+            email: required("email")?,
+            first_name: required("first_name")?,
+            last_name: required("last_name")?,
+            date_of_birth: position("date_of_birth"),
+            role: position("role"),
+        })
+    }
+
+    fn get(fields: &[String], index: usize) -> &str {
+        fields.get(index).map_or("", |value| value.trim())
+    }
+
+    pub fn email<'a>(&self, fields: &'a [String]) -> &'a str {
+        Self::get(fields, self.email)
+    }
+
+    /// Validates the row with the same rules as sign up, one error per invalid column.
+    pub fn read(&self, fields: &[String]) -> Result<ImportedUser, ServiceError> {
+        let optional = |index: Option<usize>| {
+            index
+                .map(|index| Self::get(fields, index))
+                .filter(|value| !value.is_empty())
+        };
+        let email = self.email(fields);
+        let first_name = Self::get(fields, self.first_name);
+        let last_name = Self::get(fields, self.last_name);
+        let date_of_birth = optional(self.date_of_birth);
+        let role = optional(self.role).unwrap_or_default();
+        let validations = [
+            ("email", validate_email(email)?),
+            ("first_name", validate_name("First name", first_name)?),
+            ("last_name", validate_name("Last name", last_name)?),
+            (
+                "date_of_birth",
+                date_of_birth.map_or(ValidatorEnum::Valid, validate_date_of_birth),
+            ),
+            (
+                "role",
+                match parse_role(role) {
+                    Some(_) => ValidatorEnum::Valid,
+                    None => ValidatorEnum::Invalid(
+                        "Role needs to be one of user, staff or admin.".to_string(),
+                    ),
+                },
+            ),
+        ];
+        field_validations_handler(&validations)?;
+        Ok(ImportedUser {
+            email: email.to_string(),
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            date_of_birth: date_of_birth.map(str::to_string),
+            role: parse_role(role).unwrap_or(RoleEnum::User),
+        })
+    }
+}
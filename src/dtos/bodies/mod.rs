@@ -9,8 +9,11 @@ pub use change_two_factor::*;
 pub use confirm_email::*;
 pub use confirm_sign_in::*;
 pub use email::*;
+pub use imported_user::*;
+pub use password_strength::*;
 pub use refresh_token::*;
 pub use reset_password::*;
+pub use revert_email::*;
 pub use sign_in::*;
 pub use sign_up::*;
 
@@ -19,7 +22,10 @@ pub mod change_two_factor;
 pub mod confirm_email;
 pub mod confirm_sign_in;
 pub mod email;
+pub mod imported_user;
+pub mod password_strength;
 pub mod refresh_token;
 pub mod reset_password;
+pub mod revert_email;
 pub mod sign_in;
 pub mod sign_up;
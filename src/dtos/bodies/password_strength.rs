@@ -0,0 +1,50 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::{
+    validate_not_empty, validations_handler, ApiSchema, ServiceError, ValidatorEnum,
+};
+
+/// The estimate gets slow on long inputs, far longer than any accepted password.
+const MAX_LENGTH: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasswordStrength {
+    pub password: String,
+}
+
+impl PasswordStrength {
+    pub fn validate(self) -> Result<Self, ServiceError> {
+        let length = if self.password.len() > MAX_LENGTH {
+            ValidatorEnum::Invalid(format!(
+                "Password needs to be at most {} characters.",
+                MAX_LENGTH
+            ))
+        } else {
+            ValidatorEnum::Valid
+        };
+        let validations = [validate_not_empty("Password", &self.password), length];
+        validations_handler(&validations)?;
+        Ok(self)
+    }
+}
+
+impl ApiSchema for PasswordStrength {
+    const NAME: &'static str = "PasswordStrength";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["password"],
+            "properties": {
+                "password": { "type": "string", "format": "password", "maxLength": MAX_LENGTH },
+            },
+        })
+    }
+}
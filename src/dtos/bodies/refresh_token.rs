@@ -5,8 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_jwt, validations_handler, ServiceError};
+use crate::common::{validate_jwt, validations_handler, ApiSchema, ServiceError};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RefreshToken {
@@ -20,3 +21,17 @@ impl RefreshToken {
         Ok(self)
     }
 }
+
+impl ApiSchema for RefreshToken {
+    const NAME: &'static str = "RefreshToken";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["refresh_token"],
+            "properties": {
+                "refresh_token": { "type": "string" },
+            },
+        })
+    }
+}
@@ -5,14 +5,19 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_jwt, validate_passwords, validations_handler, ServiceError};
+use crate::common::{
+    validate_jwt, validate_passwords, validations_handler, ApiSchema, ServiceError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResetPassword {
     pub reset_token: String,
     pub password1: String,
     pub password2: String,
+    #[serde(default, alias = "signOutEverywhere")]
+    pub sign_out_everywhere: bool,
 }
 
 impl ResetPassword {
@@ -25,3 +30,24 @@ impl ResetPassword {
         Ok(self)
     }
 }
+
+impl ApiSchema for ResetPassword {
+    const NAME: &'static str = "ResetPassword";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["reset_token", "password1", "password2"],
+            "properties": {
+                "reset_token": { "type": "string" },
+                "password1": { "type": "string", "format": "password" },
+                "password2": { "type": "string", "format": "password" },
+                "sign_out_everywhere": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Revokes every session",
+                },
+            },
+        })
+    }
+}
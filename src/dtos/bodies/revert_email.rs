@@ -0,0 +1,37 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::{validate_jwt, validations_handler, ApiSchema, ServiceError};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevertEmail {
+    pub revert_token: String,
+}
+
+impl RevertEmail {
+    pub fn validate(self) -> Result<Self, ServiceError> {
+        let validations = [validate_jwt("Revert token", &self.revert_token)?];
+        validations_handler(&validations)?;
+        Ok(self)
+    }
+}
+
+impl ApiSchema for RevertEmail {
+    const NAME: &'static str = "RevertEmail";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["revert_token"],
+            "properties": {
+                "revert_token": { "type": "string" },
+            },
+        })
+    }
+}
@@ -5,13 +5,18 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::common::{validate_email, validate_not_empty, validations_handler, ServiceError};
+use crate::common::{
+    validate_email, validate_not_empty, validations_handler, ApiSchema, ServiceError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SignIn {
     pub email: String,
     pub password: String,
+    /// Required when a captcha provider is configured, ignored otherwise.
+    pub captcha_token: Option<String>,
 }
 
 impl SignIn {
@@ -24,3 +29,23 @@ impl SignIn {
         Ok(self)
     }
 }
+
+impl ApiSchema for SignIn {
+    const NAME: &'static str = "SignIn";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["email", "password"],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+                "password": { "type": "string", "format": "password" },
+                "captcha_token": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Required when a captcha provider is configured",
+                },
+            },
+        })
+    }
+}
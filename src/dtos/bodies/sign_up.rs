@@ -5,10 +5,11 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::common::{
-    validate_date, validate_email, validate_name, validate_passwords, validations_handler,
-    ServiceError,
+    validate_date_of_birth, validate_email, validate_locale, validate_name, validate_passwords,
+    validate_timezone, validations_handler, ApiSchema, ServiceError, ValidatorEnum,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,6 +20,15 @@ pub struct SignUp {
     pub date_of_birth: String,
     pub password1: String,
     pub password2: String,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    /// Sign up is rejected unless the current terms are accepted.
+    #[serde(default)]
+    pub accepted_terms: bool,
+    /// Required when sign up is invite only, ignored otherwise.
+    pub invitation_token: Option<String>,
+    /// Required when a captcha provider is configured, ignored otherwise.
+    pub captcha_token: Option<String>,
 }
 
 impl SignUp {
@@ -27,10 +37,68 @@ impl SignUp {
             validate_email(&self.email)?,
             validate_name("First name", &self.first_name)?,
             validate_name("Last name", &self.last_name)?,
-            validate_date(&self.date_of_birth),
+            validate_date_of_birth(&self.date_of_birth),
             validate_passwords(&self.password1, &self.password2),
+            self.locale
+                .as_deref()
+                .map_or(ValidatorEnum::Valid, validate_locale),
+            self.timezone
+                .as_deref()
+                .map_or(ValidatorEnum::Valid, validate_timezone),
+            if self.accepted_terms {
+                ValidatorEnum::Valid
+            } else {
+                ValidatorEnum::Invalid("You need to accept the terms of service.".to_string())
+            },
         ];
         validations_handler(&validations)?;
         Ok(self)
     }
 }
+
+impl ApiSchema for SignUp {
+    const NAME: &'static str = "SignUp";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": [
+                "email",
+                "first_name",
+                "last_name",
+                "date_of_birth",
+                "password1",
+                "password2",
+                "accepted_terms",
+            ],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+                "first_name": { "type": "string" },
+                "last_name": { "type": "string" },
+                "date_of_birth": { "type": "string", "format": "date" },
+                "password1": { "type": "string", "format": "password" },
+                "password2": { "type": "string", "format": "password" },
+                "accepted_terms": {
+                    "type": "boolean",
+                    "description": "Needs to be true, the current terms version is recorded",
+                },
+                "locale": { "type": "string", "nullable": true },
+                "timezone": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "IANA timezone name",
+                },
+                "invitation_token": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Required when sign up is invite only",
+                },
+                "captcha_token": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "Required when a captcha provider is configured",
+                },
+            },
+        })
+    }
+}
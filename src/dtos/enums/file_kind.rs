@@ -0,0 +1,26 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::Enum;
+
+#[derive(Enum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Image,
+    Document,
+    Other,
+}
+
+impl FileKind {
+    pub fn from_extension(extension: &str) -> Self {
+        match extension.to_lowercase().as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "bmp" | "tiff" | "webp" | "ico" => FileKind::Image,
+            "pdf" | "txt" | "text" | "md" | "csv" | "doc" | "docx" | "odt" | "rtf" => {
+                FileKind::Document
+            }
+            _ => FileKind::Other,
+        }
+    }
+}
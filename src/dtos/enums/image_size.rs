@@ -0,0 +1,35 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::Enum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Enum, Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ImageSize {
+    Small,
+    Medium,
+    #[default]
+    Original,
+}
+
+impl ImageSize {
+    pub const RENDITIONS: [ImageSize; 3] =
+        [ImageSize::Small, ImageSize::Medium, ImageSize::Original];
+
+    /// Side of the square rendition in pixels, `None` keeps the cropped image as is.
+    pub fn dimension(&self) -> Option<u32> {
+        match self {
+            ImageSize::Small => Some(64),
+            ImageSize::Medium => Some(256),
+            ImageSize::Original => None,
+        }
+    }
+
+    pub fn suffix(&self) -> Option<String> {
+        self.dimension().map(|dimension| dimension.to_string())
+    }
+}
@@ -4,6 +4,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use data_export_status::*;
+pub use file_kind::*;
+pub use image_size::*;
+pub use online_status::*;
 pub use ratio::*;
 
+pub mod data_export_status;
+pub mod file_kind;
+pub mod image_size;
+pub mod online_status;
 pub mod ratio;
@@ -4,6 +4,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use privacy_settings::*;
+pub use set_password::*;
 pub use update_name::*;
+pub use update_preferences::*;
+pub use update_profile::*;
+pub use username::*;
 
+pub mod privacy_settings;
+pub mod set_password;
 pub mod update_name;
+pub mod update_preferences;
+pub mod update_profile;
+pub mod username;
@@ -0,0 +1,30 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{CustomValidator, InputObject, InputValueError};
+
+use crate::common::{field_validations_handler, validate_passwords, GraphQLError};
+
+#[derive(InputObject, Debug)]
+pub struct SetPassword {
+    pub password1: String,
+    pub password2: String,
+    /// Emailed by a first call without it.
+    pub code: Option<String>,
+}
+
+pub struct SetPasswordValidator;
+
+impl CustomValidator<SetPassword> for SetPasswordValidator {
+    fn check(&self, value: &SetPassword) -> Result<(), InputValueError<SetPassword>> {
+        let validations = [(
+            "password1",
+            validate_passwords(&value.password1, &value.password2),
+        )];
+        field_validations_handler(&validations).map_err(GraphQLError::from)?;
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@
 
 use async_graphql::{CustomValidator, InputObject, InputValueError};
 
-use crate::common::{validate_name, validations_handler};
+use crate::common::{field_validations_handler, validate_name, GraphQLError};
 
 #[derive(InputObject, Debug)]
 pub struct UpdateName {
@@ -19,10 +19,10 @@ pub struct UpdateNameValidator;
 impl CustomValidator<UpdateName> for UpdateNameValidator {
     fn check(&self, value: &UpdateName) -> Result<(), InputValueError<UpdateName>> {
         let validations = [
-            validate_name("First name", &value.first_name)?,
-            validate_name("Last name", &value.last_name)?,
+            ("firstName", validate_name("First name", &value.first_name)?),
+            ("lastName", validate_name("Last name", &value.last_name)?),
         ];
-        validations_handler(&validations)?;
+        field_validations_handler(&validations).map_err(GraphQLError::from)?;
         Ok(())
     }
 }
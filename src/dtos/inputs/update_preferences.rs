@@ -0,0 +1,43 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{CustomValidator, InputObject, InputValueError};
+
+use crate::common::{
+    field_validations_handler, validate_locale, validate_timezone, GraphQLError, ValidatorEnum,
+};
+
+/// Omitted fields keep their current value.
+#[derive(InputObject, Debug)]
+pub struct UpdatePreferences {
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+pub struct UpdatePreferencesValidator;
+
+impl CustomValidator<UpdatePreferences> for UpdatePreferencesValidator {
+    fn check(&self, value: &UpdatePreferences) -> Result<(), InputValueError<UpdatePreferences>> {
+        let validations = [
+            (
+                "locale",
+                value
+                    .locale
+                    .as_deref()
+                    .map_or(ValidatorEnum::Valid, validate_locale),
+            ),
+            (
+                "timezone",
+                value
+                    .timezone
+                    .as_deref()
+                    .map_or(ValidatorEnum::Valid, validate_timezone),
+            ),
+        ];
+        field_validations_handler(&validations).map_err(GraphQLError::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{CustomValidator, InputObject, InputValueError};
+
+use crate::common::{
+    field_validations_handler, validate_date_of_birth, validate_locale, validate_name,
+    validate_timezone, GraphQLError, ServiceError, ValidatorEnum,
+};
+use crate::services::users_service::ProfileChanges;
+
+/// Email changes are left out, they go through their own confirmation flow.
+#[derive(InputObject, Debug, Default)]
+#[graphql(name = "UpdateProfileInput")]
+pub struct UpdateProfile {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub date_of_birth: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl From<UpdateProfile> for ProfileChanges {
+    fn from(value: UpdateProfile) -> Self {
+        Self {
+            first_name: value.first_name,
+            last_name: value.last_name,
+            date_of_birth: value.date_of_birth,
+            locale: value.locale,
+            timezone: value.timezone,
+        }
+    }
+}
+
+fn validate_optional_name(
+    name: &str,
+    value: &Option<String>,
+) -> Result<ValidatorEnum, ServiceError> {
+    value
+        .as_deref()
+        .map_or(Ok(ValidatorEnum::Valid), |value| validate_name(name, value))
+}
+
+pub struct UpdateProfileValidator;
+
+impl CustomValidator<UpdateProfile> for UpdateProfileValidator {
+    fn check(&self, value: &UpdateProfile) -> Result<(), InputValueError<UpdateProfile>> {
+        // Only the provided fields are validated, an empty input is left to the service
+        let validations = [
+            (
+                "firstName",
+                validate_optional_name("First name", &value.first_name)?,
+            ),
+            (
+                "lastName",
+                validate_optional_name("Last name", &value.last_name)?,
+            ),
+            (
+                "dateOfBirth",
+                value
+                    .date_of_birth
+                    .as_deref()
+                    .map_or(ValidatorEnum::Valid, validate_date_of_birth),
+            ),
+            (
+                "locale",
+                value
+                    .locale
+                    .as_deref()
+                    .map_or(ValidatorEnum::Valid, validate_locale),
+            ),
+            (
+                "timezone",
+                value
+                    .timezone
+                    .as_deref()
+                    .map_or(ValidatorEnum::Valid, validate_timezone),
+            ),
+        ];
+        field_validations_handler(&validations).map_err(GraphQLError::from)?;
+        Ok(())
+    }
+}
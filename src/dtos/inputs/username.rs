@@ -0,0 +1,19 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{CustomValidator, InputValueError};
+
+use crate::common::{field_validations_handler, validate_username, GraphQLError};
+
+pub struct UsernameValidator;
+
+impl CustomValidator<String> for UsernameValidator {
+    fn check(&self, value: &String) -> Result<(), InputValueError<String>> {
+        field_validations_handler(&[("username", validate_username(value)?)])
+            .map_err(GraphQLError::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::api_key::Model;
+
+#[derive(SimpleObject, Debug)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub last_used_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+
+impl From<Model> for ApiKey {
+    fn from(value: Model) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            prefix: value.prefix,
+            last_used_at: value.last_used_at.map(|date| date.timestamp()),
+            expires_at: value.expires_at.map(|date| date.timestamp()),
+            created_at: value.created_at.timestamp(),
+        }
+    }
+}
+
+/// Only returned on creation, the plaintext key can't be retrieved again.
+#[derive(SimpleObject, Debug)]
+pub struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
@@ -0,0 +1,61 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+#[derive(SimpleObject, Debug, Clone)]
+pub struct BulkDeleteSkip {
+    pub id: i32,
+    pub reason: String,
+}
+
+#[derive(SimpleObject, Debug, Clone)]
+pub struct BulkDeleteResult {
+    pub id: i32,
+    pub deleted: bool,
+    pub error: Option<String>,
+}
+
+#[derive(SimpleObject, Debug, Clone, Default)]
+pub struct BulkDeleteReport {
+    pub dry_run: bool,
+    /// Ids of active users, skipped ones included.
+    pub found: Vec<i32>,
+    /// Ids without an active user, already deleted ones included.
+    pub missing: Vec<i32>,
+    pub skipped: Vec<BulkDeleteSkip>,
+    /// Uploaded files of the users to delete.
+    pub uploaded_files: u64,
+    /// Sign in methods of the users to delete.
+    pub oauth_providers: u64,
+    /// One entry per user to delete, empty on dry runs.
+    pub results: Vec<BulkDeleteResult>,
+}
+
+impl BulkDeleteReport {
+    pub fn skip(&mut self, id: i32, reason: &str) {
+        self.skipped.push(BulkDeleteSkip {
+            id,
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn succeed(&mut self, id: i32) {
+        self.results.push(BulkDeleteResult {
+            id,
+            deleted: true,
+            error: None,
+        });
+    }
+
+    pub fn fail(&mut self, id: i32, error: String) {
+        self.results.push(BulkDeleteResult {
+            id,
+            deleted: false,
+            error: Some(error),
+        });
+    }
+}
@@ -0,0 +1,17 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use crate::dtos::DataExportStatus;
+
+#[derive(SimpleObject, Debug)]
+pub struct DataExport {
+    pub status: DataExportStatus,
+    pub requested_at: i64,
+    /// Signed link to the archive, only set once it is ready.
+    pub url: Option<String>,
+}
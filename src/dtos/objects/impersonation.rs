@@ -0,0 +1,25 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+/// Access token of an impersonated session, which has no refresh token.
+#[derive(SimpleObject, Debug)]
+pub struct Impersonation {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+impl Impersonation {
+    pub fn new(access_token: String, expires_in: i64) -> Self {
+        Self {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in,
+        }
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::invitation::Model;
+
+#[derive(SimpleObject, Debug)]
+pub struct Invitation {
+    pub id: i32,
+    pub email: String,
+    pub invited_by: i32,
+    pub expires_at: i64,
+    pub created_at: i64,
+}
+
+impl From<Model> for Invitation {
+    fn from(value: Model) -> Self {
+        Self {
+            id: value.id,
+            email: value.email,
+            invited_by: value.invited_by,
+            expires_at: value.expires_at.timestamp(),
+            created_at: value.created_at.timestamp(),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::{enums::OAuthProviderEnum, oauth_provider};
+
+/// A way the user can sign in, local or through an external provider.
+#[derive(SimpleObject, Debug, Clone)]
+pub struct LinkedProvider {
+    pub provider: OAuthProviderEnum,
+    pub created_at: i64,
+}
+
+impl From<oauth_provider::Model> for LinkedProvider {
+    fn from(value: oauth_provider::Model) -> Self {
+        Self {
+            provider: value.provider,
+            created_at: value.created_at.timestamp(),
+        }
+    }
+}
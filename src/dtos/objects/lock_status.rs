@@ -0,0 +1,26 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+#[derive(SimpleObject, Debug)]
+pub struct LockStatus {
+    pub email: String,
+    pub locked: bool,
+    pub failed_attempts: i64,
+    pub remaining_seconds: i64,
+}
+
+impl LockStatus {
+    pub fn new(email: String, failed_attempts: i64, lock_time: Option<i64>) -> Self {
+        Self {
+            email,
+            locked: lock_time.is_some(),
+            failed_attempts,
+            remaining_seconds: lock_time.unwrap_or(0),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use crate::providers::MaintenanceStatus;
+
+#[derive(SimpleObject, Debug)]
+pub struct MaintenanceMode {
+    pub enabled: bool,
+    pub message: Option<String>,
+    pub until: Option<i64>,
+}
+
+impl From<Option<MaintenanceStatus>> for MaintenanceMode {
+    fn from(status: Option<MaintenanceStatus>) -> Self {
+        match status {
+            Some(status) => Self {
+                enabled: true,
+                message: Some(status.message),
+                until: status.until,
+            },
+            None => Self {
+                enabled: false,
+                message: None,
+                until: None,
+            },
+        }
+    }
+}
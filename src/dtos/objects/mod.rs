@@ -4,12 +4,48 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use api_key::*;
+pub use bulk_delete::*;
+pub use data_export::*;
+pub use impersonation::*;
+pub use invitation::*;
+pub use linked_provider::*;
+pub use lock_status::*;
+pub use maintenance_mode::*;
 pub use message::*;
+pub use node::*;
+pub use outbox_email::*;
+pub use provider_count::*;
+pub use provider_signup::*;
+pub use recovery_codes::*;
+pub use security::*;
+pub use session::*;
+pub use storage_gc::*;
+pub use storage_usage::*;
 pub use total_count::*;
+pub use unconfirmed_account_stats::*;
 pub use uploaded_file::*;
 pub use user::*;
 
+pub mod api_key;
+pub mod bulk_delete;
+pub mod data_export;
+pub mod impersonation;
+pub mod invitation;
+pub mod linked_provider;
+pub mod lock_status;
+pub mod maintenance_mode;
 pub mod message;
+pub mod node;
+pub mod outbox_email;
+pub mod provider_count;
+pub mod provider_signup;
+pub mod recovery_codes;
+pub mod security;
+pub mod session;
+pub mod storage_gc;
+pub mod storage_usage;
 pub mod total_count;
+pub mod unconfirmed_account_stats;
 pub mod uploaded_file;
 pub mod user;
@@ -0,0 +1,17 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Interface, ID};
+
+use super::{UploadedFile, User};
+
+/// Relay object identification, refetched through the root `node` query.
+#[derive(Interface)]
+#[graphql(field(name = "id", ty = "ID"))]
+pub enum Node {
+    User(User),
+    UploadedFile(UploadedFile),
+}
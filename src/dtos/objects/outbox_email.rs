@@ -0,0 +1,38 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::{email_outbox::Model, enums::EmailStatusEnum};
+
+#[derive(SimpleObject, Debug, Clone)]
+pub struct OutboxEmail {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub status: EmailStatusEnum,
+    pub attempts: i16,
+    pub last_error: Option<String>,
+    pub next_attempt_at: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl From<Model> for OutboxEmail {
+    fn from(model: Model) -> Self {
+        Self {
+            id: model.id,
+            recipient: model.recipient,
+            subject: model.subject,
+            status: model.status,
+            attempts: model.attempts,
+            last_error: model.last_error,
+            next_attempt_at: model.next_attempt_at.timestamp(),
+            created_at: model.created_at.timestamp(),
+            updated_at: model.updated_at.timestamp(),
+        }
+    }
+}
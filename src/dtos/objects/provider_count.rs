@@ -0,0 +1,21 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::enums::OAuthProviderEnum;
+
+#[derive(SimpleObject, Debug, Clone)]
+pub struct ProviderCount {
+    pub provider: OAuthProviderEnum,
+    pub count: u64,
+}
+
+impl ProviderCount {
+    pub fn new(provider: OAuthProviderEnum, count: u64) -> Self {
+        Self { provider, count }
+    }
+}
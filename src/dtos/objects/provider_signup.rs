@@ -0,0 +1,29 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+use entities::{enums::OAuthProviderEnum, oauth_provider, user};
+
+use super::User;
+
+/// A user together with the provider link they signed up through.
+#[derive(SimpleObject, Debug, Clone)]
+pub struct ProviderSignup {
+    pub user: User,
+    pub provider: OAuthProviderEnum,
+    pub created_at: i64,
+}
+
+impl From<(oauth_provider::Model, user::Model)> for ProviderSignup {
+    fn from((provider, user): (oauth_provider::Model, user::Model)) -> Self {
+        Self {
+            user: user.into(),
+            provider: provider.provider,
+            created_at: provider.created_at.timestamp(),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+/// Plaintext codes, only ever returned when they are generated.
+#[derive(SimpleObject, Debug)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
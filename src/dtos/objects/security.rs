@@ -0,0 +1,22 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+#[derive(SimpleObject, Debug)]
+pub struct Security {
+    pub remaining_recovery_codes: u64,
+    pub known_devices: Vec<KnownDevice>,
+}
+
+/// A browser family on a network, signing in from anything else sends an alert.
+#[derive(SimpleObject, Debug, Clone, PartialEq, Eq)]
+pub struct KnownDevice {
+    /// Passed to `forgetDevice` to get alerted again.
+    pub device_key: String,
+    pub user_agent: String,
+    pub network: String,
+}
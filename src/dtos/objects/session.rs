@@ -0,0 +1,36 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::common::ClientInfo;
+
+#[derive(SimpleObject, Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub token_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+impl Session {
+    pub fn new(token_id: &str, expires_at: i64, client: &ClientInfo) -> Self {
+        Self {
+            token_id: token_id.to_string(),
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+            created_at: Utc::now().timestamp(),
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now().timestamp()
+    }
+}
@@ -0,0 +1,18 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+#[derive(SimpleObject, Debug, Clone, Default)]
+pub struct StorageGc {
+    pub dry_run: bool,
+    /// Unreferenced objects old enough to be collected.
+    pub orphaned_objects: u64,
+    /// Uploaded files whose object is gone from the bucket.
+    pub missing_files: u64,
+    pub deleted_objects: u64,
+    pub deleted_files: u64,
+}
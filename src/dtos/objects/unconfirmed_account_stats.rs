@@ -0,0 +1,18 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::SimpleObject;
+
+#[derive(SimpleObject, Debug, Clone, Default)]
+pub struct UnconfirmedAccountStats {
+    /// Days an account has to confirm its email before it is removed.
+    pub expiry_days: i64,
+    pub unconfirmed: u64,
+    /// Accounts in their last day, due for the removal warning.
+    pub expiring: u64,
+    /// Accounts past the deadline, removed once their warning is a day old.
+    pub expired: u64,
+}
@@ -4,23 +4,41 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::collections::HashMap;
+
+use anyhow::Error;
 use async_graphql::dataloader::DataLoader;
-use async_graphql::{ComplexObject, Context, Result, SimpleObject};
+use async_graphql::{ComplexObject, Context, Result, SimpleObject, ID};
+use uuid::Uuid;
 
 use entities::uploaded_file::Model;
 
 use crate::common::{InternalCause, ServiceError, NOT_FOUND};
 use crate::data_loaders::{SeaOrmLoader, UserId};
-use crate::dtos::objects::User;
+use crate::dtos::{objects::User, FileKind, ImageSize};
+use crate::helpers::GlobalId;
+use crate::providers::{Cache, ObjectStorage};
+use crate::services::uploader_service;
 
 #[derive(SimpleObject, Clone, Debug)]
 #[graphql(complex)]
 pub struct UploadedFile {
-    pub id: String,
-    pub url: String,
+    #[graphql(skip)]
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub location: String,
+    #[graphql(skip)]
+    pub sizes: HashMap<ImageSize, String>,
+    #[graphql(skip)]
+    pub default_size: ImageSize,
     #[graphql(skip)]
     pub user_id: i32,
     pub extension: String,
+    pub kind: FileKind,
+    /// SHA-256 of the stored image, unchanged for as long as the content is.
+    pub etag: Option<String>,
+    /// Bytes stored for the file, every rendition included, 0 for older uploads.
+    pub size_bytes: i64,
     pub created_at: i64,
     pub updated_at: i64,
 }
@@ -28,18 +46,73 @@ pub struct UploadedFile {
 impl From<Model> for UploadedFile {
     fn from(value: Model) -> Self {
         Self {
-            id: value.id.to_string(),
-            url: value.url,
+            id: value.id,
+            sizes: uploader_service::file_sizes(&value),
+            default_size: ImageSize::default(),
+            location: value.url,
             user_id: value.user_id,
+            kind: FileKind::from_extension(&value.extension),
             extension: value.extension,
+            etag: value.content_hash,
+            size_bytes: value.size_bytes,
             created_at: value.created_at.timestamp(),
             updated_at: value.updated_at.timestamp(),
         }
     }
 }
 
+impl UploadedFile {
+    pub fn with_default_size(mut self, size: ImageSize) -> Self {
+        self.default_size = size;
+        self
+    }
+}
+
 #[ComplexObject]
 impl UploadedFile {
+    /// Relay global id, use `databaseId` for the UUID.
+    pub async fn id(&self, _ctx: &Context<'_>) -> ID {
+        GlobalId::UploadedFile(self.id).into()
+    }
+
+    pub async fn database_id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// A `width` takes precedence over `size`, serving an image resized on demand.
+    pub async fn url(
+        &self,
+        ctx: &Context<'_>,
+        size: Option<ImageSize>,
+        width: Option<i32>,
+    ) -> Result<String> {
+        if let Some(width) = width {
+            if self.kind != FileKind::Image {
+                return Err(
+                    ServiceError::bad_request::<Error>("Only images can be resized", None).into(),
+                );
+            }
+
+            return Ok(uploader_service::resized_image_url(
+                ctx.data::<ObjectStorage>()?,
+                &self.id,
+                uploader_service::snap_image_width(width.into())?,
+            ));
+        }
+
+        // Files uploaded before renditions existed only have the original
+        let location = self
+            .sizes
+            .get(&size.unwrap_or(self.default_size))
+            .unwrap_or(&self.location);
+        Ok(uploader_service::get_file_url(
+            ctx.data::<Cache>()?,
+            ctx.data::<ObjectStorage>()?,
+            location,
+        )
+        .await?)
+    }
+
     pub async fn user(&self, ctx: &Context<'_>) -> Result<User> {
         if let Some(user) = ctx
             .data::<DataLoader<SeaOrmLoader>>()?
@@ -5,21 +5,28 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use async_graphql::dataloader::DataLoader;
-use async_graphql::{ComplexObject, Context, Error, Result, SimpleObject};
+use async_graphql::{ComplexObject, Context, Result, SimpleObject, ID};
 use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
 
-use entities::enums::RoleEnum;
+use entities::enums::{OAuthProviderEnum, RoleEnum};
 use entities::user::Model;
 use uuid::Uuid;
 
-use crate::data_loaders::{FileId, SeaOrmLoader};
-use crate::helpers::AccessUser;
+use crate::data_loaders::{CacheLoader, FileId, PresenceId, SeaOrmLoader, UserEmail};
+use crate::dtos::{ImageSize, OnlineStatus};
+use crate::helpers::{AccessUser, GlobalId};
+use crate::providers::{Cache, Database};
+use crate::services::{auth_service, presence_service, users_service};
 
-use super::UploadedFile;
+use super::{LinkedProvider, UploadedFile};
 
-#[derive(SimpleObject, Debug, Clone)]
+/// Also what the user cache holds, so it never carries the password hash and leaves the
+/// two factor setting out.
+#[derive(SimpleObject, Serialize, Deserialize, Debug, Clone)]
 #[graphql(complex)]
 pub struct User {
+    #[graphql(skip)]
     pub id: i32,
     pub name: String,
     #[graphql(skip)]
@@ -30,14 +37,37 @@ pub struct User {
     pub first_name: String,
     pub last_name: String,
     #[graphql(skip)]
-    pub date_of_birth: String,
+    pub date_of_birth: Option<String>,
     pub role: RoleEnum,
+    #[graphql(skip)]
+    pub last_login_at: Option<i64>,
+    #[graphql(skip)]
+    pub last_active_at: Option<i64>,
+    #[graphql(skip)]
+    pub show_age: bool,
+    #[graphql(skip)]
+    pub locale: String,
+    #[graphql(skip)]
+    pub timezone: String,
+    #[graphql(skip)]
+    pub confirmed: bool,
+    /// Not cached, `None` until read from the database.
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub two_factor: Option<bool>,
+    #[graphql(skip)]
+    pub registration_provider: OAuthProviderEnum,
+    #[graphql(skip)]
+    pub has_password: bool,
+    #[graphql(skip)]
+    pub terms_version: Option<String>,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
 impl From<Model> for User {
     fn from(value: Model) -> Self {
+        let has_password = value.has_password();
         Self {
             id: value.id,
             name: value.full_name(),
@@ -46,16 +76,43 @@ impl From<Model> for User {
             username: value.username,
             first_name: value.first_name,
             last_name: value.last_name,
-            date_of_birth: value.date_of_birth.to_string(),
+            date_of_birth: value.date_of_birth.map(|date| date.to_string()),
             role: value.role,
+            last_login_at: value.last_login_at.map(|date| date.timestamp()),
+            last_active_at: value.last_active_at.map(|date| date.timestamp()),
+            show_age: value.show_age,
+            locale: value.preferred_locale,
+            timezone: value.timezone,
+            confirmed: value.confirmed,
+            two_factor: Some(value.two_factor),
+            registration_provider: value.registration_provider,
+            has_password,
+            terms_version: value.terms_version,
             created_at: value.created_at.timestamp(),
             updated_at: value.updated_at.timestamp(),
         }
     }
 }
 
+/// Owner or admin, for fields that should not show on other users' profiles.
+fn can_manage(ctx: &Context<'_>, id: i32) -> Result<bool> {
+    Ok(ctx
+        .data::<Option<AccessUser>>()?
+        .as_ref()
+        .is_some_and(|user| user.id == id || user.has_role(RoleEnum::Admin)))
+}
+
 #[ComplexObject]
 impl User {
+    /// Relay global id, use `databaseId` for the numeric one.
+    pub async fn id(&self, _ctx: &Context<'_>) -> ID {
+        GlobalId::User(self.id).into()
+    }
+
+    pub async fn database_id(&self) -> i32 {
+        self.id
+    }
+
     pub async fn email(&self, ctx: &Context<'_>) -> Result<Option<&str>> {
         let user = match ctx.data::<Option<AccessUser>>()?.as_ref() {
             Some(user) => user,
@@ -69,22 +126,187 @@ impl User {
         }
     }
 
-    pub async fn age(&self) -> Result<u32> {
-        let date_of_birth = NaiveDate::parse_from_str(&self.date_of_birth, "%Y-%m-%d")
-            .map_err(|_| Error::from("Invalid date of birth"))?;
+    pub async fn last_login_at(&self, ctx: &Context<'_>) -> Result<Option<i64>> {
+        let user = match ctx.data::<Option<AccessUser>>()?.as_ref() {
+            Some(user) => user,
+            None => return Ok(None),
+        };
 
-        if let Some(age) = Utc::now().date_naive().years_since(date_of_birth) {
-            Ok(age)
+        if user.id == self.id {
+            Ok(self.last_login_at)
         } else {
-            Err(Error::from("Invalid date of birth"))
+            Ok(None)
         }
     }
 
-    pub async fn picture(&self, ctx: &Context<'_>) -> Result<Option<UploadedFile>> {
-        if let Some(picture) = &self.picture {
+    pub async fn show_age(&self, ctx: &Context<'_>) -> Result<Option<bool>> {
+        let user = match ctx.data::<Option<AccessUser>>()?.as_ref() {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        if user.id == self.id {
+            Ok(Some(self.show_age))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn locale(&self, ctx: &Context<'_>) -> Result<Option<&str>> {
+        let user = match ctx.data::<Option<AccessUser>>()?.as_ref() {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        if user.id == self.id {
+            Ok(Some(&self.locale))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn timezone(&self, ctx: &Context<'_>) -> Result<Option<&str>> {
+        let user = match ctx.data::<Option<AccessUser>>()?.as_ref() {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        if user.id == self.id {
+            Ok(Some(&self.timezone))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether the email is verified, for the owner and admins.
+    pub async fn confirmed(&self, ctx: &Context<'_>) -> Result<Option<bool>> {
+        if can_manage(ctx, self.id)? {
+            Ok(Some(self.confirmed))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether signing in, with a password or a provider, asks for an emailed code. For
+    /// the owner and admins.
+    pub async fn two_factor(&self, ctx: &Context<'_>) -> Result<Option<bool>> {
+        if !can_manage(ctx, self.id)? {
+            return Ok(None);
+        }
+
+        match self.two_factor {
+            Some(two_factor) => Ok(Some(two_factor)),
+            None => {
+                let db = ctx.data::<Database>()?;
+                let user = users_service::find_one_by_id(&db.session(), self.id).await?;
+                Ok(Some(user.two_factor))
+            }
+        }
+    }
+
+    /// How the account was created, for the owner and admins.
+    pub async fn registration_provider(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<Option<OAuthProviderEnum>> {
+        if can_manage(ctx, self.id)? {
+            Ok(Some(self.registration_provider))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// False for accounts that only sign in through a provider, they can `setPassword`.
+    /// For the owner and admins.
+    pub async fn has_password(&self, ctx: &Context<'_>) -> Result<Option<bool>> {
+        if can_manage(ctx, self.id)? {
+            Ok(Some(self.has_password))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Ways the user can sign in, batched per request. Only the owner and admins see
+    /// them, anyone else gets null.
+    #[graphql(complexity = 5)]
+    pub async fn providers(&self, ctx: &Context<'_>) -> Result<Option<Vec<LinkedProvider>>> {
+        if !can_manage(ctx, self.id)? {
+            return Ok(None);
+        }
+
+        Ok(Some(
             ctx.data::<DataLoader<SeaOrmLoader>>()?
+                .load_one(UserEmail(self.email.clone()))
+                .await?
+                .unwrap_or_default(),
+        ))
+    }
+
+    /// Unix timestamp of the last confirmation email, while its link is still valid.
+    pub async fn confirmation_email_sent_at(&self, ctx: &Context<'_>) -> Result<Option<i64>> {
+        match ctx.data::<Option<AccessUser>>()?.as_ref() {
+            Some(user) if user.id == self.id && !self.confirmed => (),
+            _ => return Ok(None),
+        }
+
+        Ok(auth_service::get_confirmation_sent_at(ctx.data::<Cache>()?, self.id).await?)
+    }
+
+    pub async fn age(&self, ctx: &Context<'_>) -> Result<Option<u32>> {
+        if !self.show_age {
+            match ctx.data::<Option<AccessUser>>()?.as_ref() {
+                Some(user) if user.id == self.id => (),
+                _ => return Ok(None),
+            }
+        }
+
+        // Some external providers (e.g. GitHub) do not share a date of birth, and rows
+        // older than the date checks may hold one in the future, neither fails the query
+        let Some(date_of_birth) = &self.date_of_birth else {
+            return Ok(None);
+        };
+        let age = NaiveDate::parse_from_str(date_of_birth, "%Y-%m-%d")
+            .ok()
+            .and_then(|date_of_birth| Utc::now().date_naive().years_since(date_of_birth));
+
+        if age.is_none() {
+            tracing::warn!("User {} has an invalid date of birth", self.id);
+        }
+
+        Ok(age)
+    }
+
+    /// ONLINE after a request in the last five minutes, RECENTLY_ACTIVE within a day,
+    /// presences are batched per request.
+    pub async fn online_status(&self, ctx: &Context<'_>) -> Result<OnlineStatus> {
+        // Without Redis the stored activity still tells recently active users apart
+        let online = match ctx
+            .data::<DataLoader<CacheLoader>>()?
+            .load_one(PresenceId(self.id))
+            .await
+        {
+            Ok(last_seen) => last_seen.is_some(),
+            Err(e) => {
+                tracing::warn!("Failed to load the presence of user {}: {:?}", self.id, e);
+                false
+            }
+        };
+
+        Ok(presence_service::online_status(online, self.last_active_at))
+    }
+
+    #[graphql(complexity = 5)]
+    pub async fn picture(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] size: ImageSize,
+    ) -> Result<Option<UploadedFile>> {
+        if let Some(picture) = &self.picture {
+            Ok(ctx
+                .data::<DataLoader<SeaOrmLoader>>()?
                 .load_one(FileId(picture.to_owned()))
-                .await
+                .await?
+                .map(|file| file.with_default_size(size)))
         } else {
             Ok(None)
         }
@@ -0,0 +1,37 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/x-ndjson",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "users.csv",
+            ExportFormat::Json => "users.ndjson",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Export {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
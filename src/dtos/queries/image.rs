@@ -0,0 +1,16 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::Deserialize;
+
+/// Kept as text, so a malformed width gets the same error as one out of range. Images
+/// in a private bucket also carry the expiry and signature of their URL.
+#[derive(Debug, Deserialize)]
+pub struct ImageWidth {
+    pub w: Option<String>,
+    pub exp: Option<i64>,
+    pub sig: Option<String>,
+}
@@ -4,6 +4,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use export::*;
+pub use image::*;
+pub use import::*;
 pub use oauth::*;
 
+pub mod export;
+pub mod image;
+pub mod import;
 pub mod oauth;
@@ -10,8 +10,13 @@ use crate::common::{validate_not_empty, validations_handler, ServiceError};
 
 #[derive(Debug, Deserialize)]
 pub struct OAuth {
+    #[serde(default)]
     pub code: String,
+    #[serde(default)]
     pub state: String,
+    // Set by the provider instead of the code, e.g. when consent is denied
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
 
 impl OAuth {
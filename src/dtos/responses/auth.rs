@@ -5,6 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::ApiSchema;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Auth {
@@ -24,3 +27,27 @@ impl Auth {
         }
     }
 }
+
+impl ApiSchema for Auth {
+    const NAME: &'static str = "Auth";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["access_token", "token_type", "expires_in", "refresh_token"],
+            "properties": {
+                "access_token": { "type": "string" },
+                "token_type": { "type": "string", "enum": ["Bearer"] },
+                "expires_in": {
+                    "type": "integer",
+                    "format": "int64",
+                    "description": "Seconds until the access token expires",
+                },
+                "refresh_token": {
+                    "type": "string",
+                    "description": "Also set in the refresh token cookie",
+                },
+            },
+        })
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use entities::user::Model;
+
+use crate::common::format_csv_field;
+
+pub const EXPORTED_USER_CSV_HEADER: &str = "id,email,first_name,last_name,created_at\n";
+
+#[derive(Serialize, Debug)]
+pub struct ExportedUser {
+    pub id: i32,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl ExportedUser {
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{}\n",
+            self.id,
+            format_csv_field(&self.email),
+            format_csv_field(&self.first_name),
+            format_csv_field(&self.last_name),
+            self.created_at.format("%Y-%m-%dT%H:%M:%S"),
+        )
+    }
+}
+
+impl From<Model> for ExportedUser {
+    fn from(user: Model) -> Self {
+        Self {
+            id: user.id,
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            created_at: user.created_at,
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::ApiSchema;
+
+/// Dependencies checked before the server started, `skipped` when they were not.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartupReport {
+    pub database: String,
+    pub cache: String,
+    pub object_storage: String,
+    pub mailer: String,
+}
+
+/// Only sent in the first minutes after boot, the health check has no body afterwards.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub startup_report: StartupReport,
+}
+
+impl ApiSchema for Health {
+    const NAME: &'static str = "HealthResponse";
+
+    fn schema() -> Value {
+        let status = json!({ "type": "string", "enum": ["ok", "failed", "skipped"] });
+        json!({
+            "type": "object",
+            "required": ["startupReport"],
+            "properties": {
+                "startupReport": {
+                    "type": "object",
+                    "required": ["database", "cache", "objectStorage", "mailer"],
+                    "properties": {
+                        "database": status,
+                        "cache": status,
+                        "objectStorage": status,
+                        "mailer": status,
+                    },
+                },
+            },
+        })
+    }
+}
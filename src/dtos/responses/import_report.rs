@@ -0,0 +1,43 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{FieldError, ServiceError};
+
+/// A CSV row that was not imported, `errors` lists the invalid columns.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportFailure {
+    pub line: usize,
+    pub email: Option<String>,
+    pub status: u16,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub failed: Vec<ImportFailure>,
+}
+
+impl ImportReport {
+    pub fn fail(&mut self, line: usize, email: &str, error: &ServiceError) {
+        let errors = match error {
+            ServiceError::Validation(errors) => errors.0.clone(),
+            _ => Vec::new(),
+        };
+
+        self.failed.push(ImportFailure {
+            line,
+            email: Some(email.to_string()).filter(|email| !email.is_empty()),
+            status: error.get_status_code(),
+            message: error.to_string(),
+            errors,
+        });
+    }
+}
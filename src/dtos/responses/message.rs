@@ -5,8 +5,11 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use uuid::Uuid;
 
+use crate::common::ApiSchema;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
     pub id: String,
@@ -21,3 +24,18 @@ impl Message {
         }
     }
 }
+
+impl ApiSchema for Message {
+    const NAME: &'static str = "Message";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "message"],
+            "properties": {
+                "id": { "type": "string", "format": "uuid" },
+                "message": { "type": "string" },
+            },
+        })
+    }
+}
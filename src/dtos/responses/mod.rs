@@ -5,11 +5,30 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use auth::*;
+pub use exported_user::*;
+pub use health::*;
+pub use import_report::*;
+pub use message::*;
 pub use oauth::*;
+pub use password_strength::*;
+pub use readiness::*;
+pub use set_password::*;
 pub use sign_in::*;
-pub use message::*;
+pub use two_factor::*;
+pub use webhooks::*;
 
 pub mod auth;
+pub mod exported_user;
+pub mod health;
+pub mod import_report;
+pub mod message;
 pub mod oauth;
+pub mod password_strength;
+pub mod readiness;
+pub mod set_password;
 pub mod sign_in;
-pub mod message;
+pub mod two_factor;
+pub mod webhooks;
+
+#[cfg(test)]
+mod tests;
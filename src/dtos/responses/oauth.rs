@@ -4,68 +4,114 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use anyhow::Error;
-use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use crate::common::ServiceError;
+use chrono::NaiveDate;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::ApiSchema;
+
+use super::Auth;
+
+/// Why a provider's user info did not become a [`UserInfo`], the service maps it to an
+/// error since only it knows which provider answered.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserInfoError {
+    /// The provider could not be reached or answered with an error status.
+    Unavailable(String),
+    /// A field the provider only shares once the user grants `permission`.
+    MissingField {
+        field: &'static str,
+        permission: &'static str,
+    },
+    /// The body is not what the provider documents.
+    Malformed { error: String, body: String },
+}
+
+impl fmt::Display for UserInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserInfoError::Unavailable(cause) => write!(f, "Provider unavailable: {}", cause),
+            UserInfoError::MissingField { field, permission } => {
+                write!(f, "Missing {}, granted with {}", field, permission)
+            }
+            UserInfoError::Malformed { error, .. } => write!(f, "Malformed user info: {}", error),
+        }
+    }
+}
+
+/// Reads a user info response, any status but a success means the provider is not
+/// answering as it should.
+pub fn parse_user_info<T: DeserializeOwned>(status: u16, body: &str) -> Result<T, UserInfoError> {
+    if !(200..300).contains(&status) {
+        return Err(UserInfoError::Unavailable(format!(
+            "User info responded with {}",
+            status
+        )));
+    }
+
+    serde_json::from_str(body).map_err(|e| UserInfoError::Malformed {
+        error: e.to_string(),
+        body: body.to_string(),
+    })
+}
+
+fn missing(field: &'static str, permission: &'static str) -> UserInfoError {
+    UserInfoError::MissingField { field, permission }
+}
 
 pub struct UserInfo {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
-    pub date_of_birth: String,
+    pub date_of_birth: Option<String>,
+    #[allow(dead_code)]
     pub picture: Option<String>,
 }
 
+/// Google sends `0000-MM-DD` when the year is hidden, without it the age is unknown.
+fn google_date_of_birth(birthdate: String) -> Option<String> {
+    (!birthdate.starts_with("0000")).then_some(birthdate)
+}
+
+/// Facebook sends `MM/DD/YYYY`, or only `MM/DD` or `YYYY` depending on what the user
+/// shares, partial birthdays are dropped as the age can not be told from them.
+fn facebook_date_of_birth(birthday: String) -> Option<String> {
+    NaiveDate::parse_from_str(&birthday, "%m/%d/%Y")
+        .ok()
+        .map(|date| date.format("%Y-%m-%d").to_string())
+}
+
+// The birthday is only shared with an extra permission users often untick, accounts
+// are created without a date of birth instead
 impl TryFrom<GoogleUserInfoResponse> for UserInfo {
-    type Error = ServiceError;
+    type Error = UserInfoError;
 
     fn try_from(value: GoogleUserInfoResponse) -> Result<Self, Self::Error> {
-        let first_name = value.given_name.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing given name", None)
-        })?;
-        let last_name = value.family_name.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing family name", None)
-        })?;
-        let email = value
-            .email
-            .ok_or_else(|| ServiceError::internal_server_error::<Error>("Missing email", None))?;
-        let date_of_birth = value.birthdate.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing birthdate", None)
-        })?;
-
         Ok(Self {
-            first_name,
-            last_name,
-            email,
-            date_of_birth,
+            first_name: value.given_name.ok_or(missing("first name", "profile"))?,
+            last_name: value.family_name.ok_or(missing("last name", "profile"))?,
+            email: value.email.ok_or(missing("email", "email"))?,
+            date_of_birth: value.birthdate.and_then(google_date_of_birth),
             picture: value.picture,
         })
     }
 }
 
 impl TryFrom<FacebookUserInfoResponse> for UserInfo {
-    type Error = ServiceError;
+    type Error = UserInfoError;
 
     fn try_from(value: FacebookUserInfoResponse) -> Result<Self, Self::Error> {
-        let first_name = value.first_name.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing first name", None)
-        })?;
-        let last_name = value.last_name.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing last name", None)
-        })?;
-        let email = value
-            .email
-            .ok_or_else(|| ServiceError::internal_server_error::<Error>("Missing email", None))?;
-        let birth_date = value.birthday.ok_or_else(|| {
-            ServiceError::internal_server_error::<Error>("Missing birth date", None)
-        })?;
-
         Ok(Self {
-            first_name,
-            last_name,
-            email,
-            date_of_birth: birth_date,
+            first_name: value
+                .first_name
+                .ok_or(missing("first name", "public_profile"))?,
+            last_name: value
+                .last_name
+                .ok_or(missing("last name", "public_profile"))?,
+            email: value.email.ok_or(missing("email", "email"))?,
+            date_of_birth: value.birthday.and_then(facebook_date_of_birth),
             picture: value.picture.and_then(|p| p.data).and_then(|d| d.url),
         })
     }
@@ -110,19 +156,105 @@ pub struct FacebookUserInfoResponse {
     pub locale: Option<String>,
 }
 
+impl TryFrom<GithubUserInfoResponse> for UserInfo {
+    type Error = UserInfoError;
+
+    fn try_from(value: GithubUserInfoResponse) -> Result<Self, Self::Error> {
+        let email = value.email.ok_or(missing("email", "user:email"))?;
+        // GitHub only has a single display name, the login is used when it is not set
+        let (first_name, last_name) = match value.name.as_deref().map(str::trim) {
+            Some(name) if !name.is_empty() => match name.split_once(char::is_whitespace) {
+                Some((first_name, last_name)) => (first_name.to_string(), last_name.to_string()),
+                None => (name.to_string(), String::new()),
+            },
+            _ => (value.login, String::new()),
+        };
+
+        Ok(Self {
+            first_name,
+            last_name,
+            email,
+            date_of_birth: None,
+            picture: value.avatar_url,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GithubUserInfoResponse {
+    pub id: i64,
+    pub login: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+impl GithubUserInfoResponse {
+    pub fn with_primary_email(mut self, emails: Vec<GithubEmailResponse>) -> Self {
+        if self.email.is_none() {
+            self.email = emails
+                .into_iter()
+                .find(|email| email.primary && email.verified)
+                .map(|email| email.email);
+        }
+
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GithubEmailResponse {
+    pub email: String,
+    pub primary: bool,
+    pub verified: bool,
+    pub visibility: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum OAuthUserInfo {
     Google(GoogleUserInfoResponse),
     Facebook(FacebookUserInfoResponse),
+    Github(GithubUserInfoResponse),
 }
 
 impl TryInto<UserInfo> for OAuthUserInfo {
-    type Error = ServiceError;
+    type Error = UserInfoError;
 
     fn try_into(self) -> Result<UserInfo, Self::Error> {
         match self {
             OAuthUserInfo::Google(google) => google.try_into(),
             OAuthUserInfo::Facebook(facebook) => facebook.try_into(),
+            OAuthUserInfo::Github(github) => github.try_into(),
         }
     }
 }
+
+/// Provider URL the frontend sends the user to when linking from the settings.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthLink {
+    pub url: String,
+}
+
+impl ApiSchema for OAuthLink {
+    const NAME: &'static str = "OAuthLink";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string", "format": "uri" },
+            },
+        })
+    }
+}
+
+/// A callback either signs in or, for states issued from the settings, links the
+/// provider to the signed in user. With two factor on, signing in waits for the code
+/// emailed to the address it holds.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum OAuthCallback {
+    Auth(Auth),
+    Mfa(String),
+    Linked,
+}
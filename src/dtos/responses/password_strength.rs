@@ -0,0 +1,39 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::ApiSchema;
+
+/// Strength meter of a password, `valid` tells whether it passes the sign up rules.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasswordStrength {
+    pub score: u8,
+    pub valid: bool,
+    pub feedback: Vec<String>,
+}
+
+impl ApiSchema for PasswordStrength {
+    const NAME: &'static str = "PasswordStrengthResponse";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["score", "valid", "feedback"],
+            "properties": {
+                "score": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 4,
+                    "description": "From 0, guessable in a few tries, to 4, very unguessable",
+                },
+                "valid": { "type": "boolean" },
+                "feedback": { "type": "array", "items": { "type": "string" } },
+            },
+        })
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::ApiSchema;
+
+/// State of each dependency, `ok` when it is healthy.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Readiness {
+    pub database: String,
+    pub cache: String,
+    pub migrations: String,
+    pub mailer: String,
+}
+
+impl ApiSchema for Readiness {
+    const NAME: &'static str = "ReadinessResponse";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["database", "cache", "migrations", "mailer"],
+            "properties": {
+                "database": { "type": "string", "enum": ["ok", "unreachable"] },
+                "cache": { "type": "string", "enum": ["ok", "unreachable"] },
+                "migrations": {
+                    "type": "string",
+                    "description": "`behind(n)` with n pending migrations, `unknown` if they could not be read",
+                },
+                "mailer": { "type": "string", "enum": ["ok", "failing"] },
+            },
+        })
+    }
+}
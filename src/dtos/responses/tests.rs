@@ -0,0 +1,196 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{
+    parse_user_info, FacebookUserInfoResponse, GithubEmailResponse, GithubUserInfoResponse,
+    GoogleUserInfoResponse, OAuthUserInfo, UserInfo, UserInfoError,
+};
+
+const GITHUB_EMAILS: &str = r#"[
+    {
+        "email": "secondary@example.com",
+        "primary": false,
+        "verified": true,
+        "visibility": null
+    },
+    {
+        "email": "unverified@example.com",
+        "primary": true,
+        "verified": false,
+        "visibility": "private"
+    },
+    {
+        "email": "octocat@example.com",
+        "primary": true,
+        "verified": true,
+        "visibility": "private"
+    }
+]"#;
+
+fn github_user(email: Option<&str>, name: Option<&str>) -> GithubUserInfoResponse {
+    serde_json::from_value(serde_json::json!({
+        "id": 583231,
+        "login": "octocat",
+        "name": name,
+        "email": email,
+        "avatar_url": "https://avatars.githubusercontent.com/u/583231?v=4",
+        "bio": null,
+        "public_repos": 8
+    }))
+    .unwrap()
+}
+
+fn github_emails(payload: &str) -> Vec<GithubEmailResponse> {
+    serde_json::from_str(payload).unwrap()
+}
+
+#[test]
+fn test_github_missing_public_email_uses_primary_verified() {
+    let user =
+        github_user(None, Some("The Octocat")).with_primary_email(github_emails(GITHUB_EMAILS));
+    let user_info: UserInfo = OAuthUserInfo::Github(user).try_into().unwrap();
+    assert_eq!(user_info.email, "octocat@example.com");
+    assert_eq!(user_info.first_name, "The");
+    assert_eq!(user_info.last_name, "Octocat");
+    assert!(user_info.date_of_birth.is_none());
+}
+
+#[test]
+fn test_github_public_email_is_kept() {
+    let user = github_user(Some("public@example.com"), Some("The Octocat"))
+        .with_primary_email(github_emails(GITHUB_EMAILS));
+    let user_info: UserInfo = OAuthUserInfo::Github(user).try_into().unwrap();
+    assert_eq!(user_info.email, "public@example.com");
+}
+
+#[test]
+fn test_github_without_name_uses_login() {
+    let user = github_user(Some("public@example.com"), None);
+    let user_info: UserInfo = OAuthUserInfo::Github(user).try_into().unwrap();
+    assert_eq!(user_info.first_name, "octocat");
+    assert_eq!(user_info.last_name, "");
+}
+
+#[test]
+fn test_github_without_verified_primary_email_fails() {
+    let emails = github_emails(
+        r#"[
+            { "email": "unverified@example.com", "primary": true, "verified": false, "visibility": null },
+            { "email": "secondary@example.com", "primary": false, "verified": true, "visibility": null }
+        ]"#,
+    );
+    let user = github_user(None, Some("The Octocat")).with_primary_email(emails);
+    assert!(user.email.is_none());
+    let user_info: Result<UserInfo, _> = OAuthUserInfo::Github(user).try_into();
+    assert_eq!(
+        user_info.err(),
+        Some(UserInfoError::MissingField {
+            field: "email",
+            permission: "user:email"
+        })
+    );
+}
+
+#[test]
+fn test_facebook_without_birthday_has_no_date_of_birth() {
+    let user: FacebookUserInfoResponse = parse_user_info(
+        200,
+        r#"{ "id": "10158", "first_name": "Jane", "last_name": "Doe", "email": "jane@example.com" }"#,
+    )
+    .unwrap();
+    let user_info: UserInfo = OAuthUserInfo::Facebook(user).try_into().unwrap();
+    assert_eq!(user_info.email, "jane@example.com");
+    assert!(user_info.date_of_birth.is_none());
+}
+
+#[test]
+fn test_birthdays_are_normalized() {
+    let facebook = |birthday: &str| -> UserInfo {
+        let user: FacebookUserInfoResponse = serde_json::from_value(serde_json::json!({
+            "id": "10158",
+            "first_name": "Jane",
+            "last_name": "Doe",
+            "email": "jane@example.com",
+            "birthday": birthday,
+        }))
+        .unwrap();
+        OAuthUserInfo::Facebook(user).try_into().unwrap()
+    };
+    assert_eq!(
+        facebook("01/31/1990").date_of_birth.as_deref(),
+        Some("1990-01-31")
+    );
+    assert!(facebook("01/31").date_of_birth.is_none());
+    assert!(facebook("1990").date_of_birth.is_none());
+
+    let google = |birthdate: &str| -> UserInfo {
+        let user: GoogleUserInfoResponse = serde_json::from_value(serde_json::json!({
+            "sub": "1097",
+            "given_name": "Jane",
+            "family_name": "Doe",
+            "email": "jane@example.com",
+            "birthdate": birthdate,
+        }))
+        .unwrap();
+        OAuthUserInfo::Google(user).try_into().unwrap()
+    };
+    assert_eq!(
+        google("1990-01-31").date_of_birth.as_deref(),
+        Some("1990-01-31")
+    );
+    assert!(google("0000-01-31").date_of_birth.is_none());
+}
+
+#[test]
+fn test_facebook_without_email_names_the_permission() {
+    let user: FacebookUserInfoResponse = parse_user_info(
+        200,
+        r#"{ "id": "10158", "first_name": "Jane", "last_name": "Doe", "birthday": "01/31/1990" }"#,
+    )
+    .unwrap();
+    let user_info: Result<UserInfo, _> = OAuthUserInfo::Facebook(user).try_into();
+    assert_eq!(
+        user_info.err(),
+        Some(UserInfoError::MissingField {
+            field: "email",
+            permission: "email"
+        })
+    );
+}
+
+#[test]
+fn test_google_without_names_names_the_permission() {
+    let user: GoogleUserInfoResponse = parse_user_info(
+        200,
+        r#"{ "sub": "1097", "email": "jane@example.com", "email_verified": true }"#,
+    )
+    .unwrap();
+    let user_info: Result<UserInfo, _> = OAuthUserInfo::Google(user).try_into();
+    assert_eq!(
+        user_info.err(),
+        Some(UserInfoError::MissingField {
+            field: "first name",
+            permission: "profile"
+        })
+    );
+}
+
+#[test]
+fn test_malformed_user_info_keeps_the_body() {
+    let body = r#"{ "sub": 1097, "email": "jane@example.com" }"#;
+    let user_info = parse_user_info::<GoogleUserInfoResponse>(200, body);
+    assert!(matches!(
+        user_info,
+        Err(UserInfoError::Malformed { body: ref malformed, .. }) if malformed == body
+    ));
+}
+
+#[test]
+fn test_error_status_is_unavailable() {
+    let user_info =
+        parse_user_info::<GoogleUserInfoResponse>(503, "<html>Service Unavailable</html>");
+    assert!(matches!(user_info, Err(UserInfoError::Unavailable(_))));
+}
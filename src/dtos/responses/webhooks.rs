@@ -0,0 +1,50 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use entities::user::Model;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    UserRegistered,
+    UserConfirmed,
+    EmailChanged,
+    UserDeleted,
+}
+
+impl WebhookEventType {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Self::UserRegistered => "user_registered",
+            Self::UserConfirmed => "user_confirmed",
+            Self::EmailChanged => "email_changed",
+            Self::UserDeleted => "user_deleted",
+        }
+    }
+}
+
+/// Body POSTed to every webhook endpoint, `timestamp` is when the event happened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEvent {
+    pub event: WebhookEventType,
+    pub user_id: i32,
+    pub email: String,
+    pub timestamp: i64,
+}
+
+impl WebhookEvent {
+    pub fn new(event: WebhookEventType, user: &Model) -> Self {
+        Self {
+            event,
+            user_id: user.id,
+            email: user.email.clone(),
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+}
@@ -4,21 +4,76 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use async_graphql::{async_trait, Context, Error, Guard, Result};
+use async_graphql::{
+    async_trait, parser::types::OperationType, Context, Error, ErrorExtensions, Guard, Result,
+};
 
+use crate::common::GraphQLError;
 use crate::helpers::AccessUser;
+use crate::providers::{Cache, Database, Jwt, TermsVersion};
+use crate::services::users_service;
+
+pub const TERMS_OUTDATED: &str = "TERMS_OUTDATED";
+
+/// Root fields left to users that have not accepted the current terms, so they can
+/// read their account and accept them.
+const OUTDATED_TERMS_FIELDS: [&str; 2] = ["me", "acceptTerms"];
 
 pub struct AuthGuard;
 
+/// Only root fields are checked, the selections of an allowed field are allowed with it.
+async fn check_terms(ctx: &Context<'_>, user: &AccessUser) -> Result<()> {
+    let terms_version = match ctx.data_opt::<TermsVersion>() {
+        Some(terms_version) if terms_version.current().is_some() => terms_version,
+        _ => return Ok(()),
+    };
+    let is_root = ctx.path_node.is_some_and(|node| node.parent.is_none());
+
+    if !is_root || OUTDATED_TERMS_FIELDS.contains(&ctx.field().name()) {
+        return Ok(());
+    }
+
+    let stored = users_service::cached_find_one_by_id(
+        &ctx.data::<Database>()?.session(),
+        ctx.data::<Cache>()?,
+        user.id,
+    )
+    .await
+    .map_err(GraphQLError::from)?;
+
+    if terms_version.is_outdated(stored.terms_version.as_deref()) {
+        return Err(Error::from(GraphQLError::Forbidden(
+            "You need to accept the updated terms of service".to_string(),
+        ))
+        .extend_with(|_, e| e.set("code", TERMS_OUTDATED)));
+    }
+
+    Ok(())
+}
+
+/// Queries only check the token signature unless strict access tokens are on,
+/// mutations are always checked against the stored user. Users that have not accepted
+/// the current terms get `TERMS_OUTDATED` everywhere but `me` and `acceptTerms`.
+pub async fn check_access_user(ctx: &Context<'_>, user: &AccessUser) -> Result<()> {
+    let is_mutation = ctx.query_env.operation.node.ty == OperationType::Mutation;
+
+    if is_mutation || ctx.data::<Jwt>()?.is_strict() {
+        user.verify(ctx.data::<Database>()?)
+            .await
+            .map_err(GraphQLError::from)?;
+    }
+
+    check_terms(ctx, user).await
+}
+
 #[async_trait::async_trait]
 impl Guard for AuthGuard {
     async fn check(&self, ctx: &Context<'_>) -> Result<()> {
-        let user = ctx.data::<Option<AccessUser>>()?;
-
-        if user.is_none() {
-            return Err(Error::new("Unauthorized"));
-        }
+        let user = match ctx.data::<Option<AccessUser>>()? {
+            Some(user) => user,
+            None => return Err(Error::new("Unauthorized")),
+        };
 
-        Ok(())
+        check_access_user(ctx, user).await
     }
 }
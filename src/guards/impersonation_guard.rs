@@ -0,0 +1,29 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{async_trait, Context, Error, Guard, Result};
+
+use crate::helpers::AccessUser;
+
+/// Keeps impersonated sessions read-only, it is meant to be combined with `AuthGuard`
+/// or `RoleGuard`.
+pub struct NoImpersonationGuard;
+
+#[async_trait::async_trait]
+impl Guard for NoImpersonationGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let impersonated = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .is_some_and(AccessUser::is_impersonated);
+
+        if impersonated {
+            return Err(Error::new("Forbidden"));
+        }
+
+        Ok(())
+    }
+}
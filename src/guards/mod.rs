@@ -5,5 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use auth_guard::*;
+pub use impersonation_guard::*;
+pub use role_guard::*;
 
 pub mod auth_guard;
+pub mod impersonation_guard;
+pub mod role_guard;
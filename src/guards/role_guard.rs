@@ -0,0 +1,38 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{async_trait, Context, Error, Guard, Result};
+use entities::enums::RoleEnum;
+
+use crate::helpers::AccessUser;
+
+use super::check_access_user;
+
+pub struct RoleGuard {
+    role: RoleEnum,
+}
+
+impl RoleGuard {
+    pub fn new(role: RoleEnum) -> Self {
+        Self { role }
+    }
+}
+
+#[async_trait::async_trait]
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let user = match ctx.data::<Option<AccessUser>>()? {
+            Some(user) => user,
+            None => return Err(Error::new("Unauthorized")),
+        };
+
+        if !user.has_role(self.role) {
+            return Err(Error::new("Forbidden"));
+        }
+
+        check_access_user(ctx, user).await
+    }
+}
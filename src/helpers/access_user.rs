@@ -6,26 +6,74 @@
 
 use actix_web::HttpRequest;
 use entities::enums::RoleEnum;
+use serde_json::Value;
 
-use crate::common::AuthTokens;
-use crate::providers::Jwt;
+use crate::common::{get_bearer_token, AuthTokens, ServiceError, FORBIDDEN, UNAUTHORIZED};
+use crate::providers::{Cache, Database, Jwt};
+use crate::services::{api_keys_service, presence_service, users_service};
 
 #[derive(Debug, Clone)]
 pub struct AccessUser {
     pub id: i32,
     pub role: RoleEnum,
+    /// Admin acting as this user, their sessions are read-only.
+    pub impersonator: Option<i32>,
+    /// User version the access token was issued for, API keys don't have one.
+    pub version: Option<i32>,
 }
 
 impl AccessUser {
     pub fn new(id: i32, role: RoleEnum) -> Self {
-        Self { id, role }
+        Self {
+            id,
+            role,
+            impersonator: None,
+            version: None,
+        }
+    }
+
+    pub fn with_impersonator(mut self, impersonator: Option<i32>) -> Self {
+        self.impersonator = impersonator;
+        self
+    }
+
+    pub fn with_version(mut self, version: i32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Authenticated users are marked online, unless an admin is impersonating them.
+    pub async fn from_request(
+        jwt: &Jwt,
+        db: &Database,
+        cache: &Cache,
+        req: &HttpRequest,
+    ) -> Option<Self> {
+        let user = Self::authenticate(jwt, db, req).await?;
+
+        if !user.is_impersonated() {
+            if let Err(e) = presence_service::touch(db, cache, user.id).await {
+                tracing::warn!("Failed to record the presence of user {}: {:?}", user.id, e);
+            }
+        }
+
+        Some(user)
     }
 
-    pub fn from_request(jwt: &Jwt, req: &HttpRequest) -> Option<Self> {
-        let tokens = AuthTokens::new(req);
+    async fn authenticate(jwt: &Jwt, db: &Database, req: &HttpRequest) -> Option<Self> {
+        let tokens = AuthTokens::new(req, jwt);
 
         if let Some(access_token) = tokens.access_token {
             match jwt.verify_access_token(&access_token) {
+                Ok((id, role, version, impersonator)) => Some(
+                    Self::new(id, role)
+                        .with_impersonator(impersonator)
+                        .with_version(version),
+                ),
+                Err(_) => None,
+            }
+        } else if let Some(api_key) = tokens.api_key {
+            match api_keys_service::authenticate(db, &api_key).await {
                 Ok((id, role)) => Some(Self::new(id, role)),
                 Err(_) => None,
             }
@@ -33,4 +81,84 @@ impl AccessUser {
             None
         }
     }
+
+    /// WebSocket upgrades can't carry an `Authorization` header, so GraphQL clients
+    /// send `{ "authorization": "Bearer <token>" }` in the `connection_init` payload.
+    /// Returns the user with the token expiry, or `None` when no token was sent.
+    pub fn from_connection_params(
+        jwt: &Jwt,
+        params: &Value,
+    ) -> Result<Option<(Self, i64)>, ServiceError> {
+        let auth_header = match params
+            .get("authorization")
+            .or_else(|| params.get("Authorization"))
+        {
+            Some(auth_header) => auth_header,
+            None => return Ok(None),
+        };
+        let access_token = auth_header
+            .as_str()
+            .and_then(get_bearer_token)
+            .ok_or_else(|| ServiceError::unauthorized::<ServiceError>(UNAUTHORIZED, None))?;
+        let (id, role, version, impersonator, exp) =
+            jwt.verify_access_token_with_expiry(&access_token)?;
+        Ok(Some((
+            Self::new(id, role)
+                .with_impersonator(impersonator)
+                .with_version(version),
+            exp,
+        )))
+    }
+
+    pub fn has_role(&self, role: RoleEnum) -> bool {
+        self.role == role
+    }
+
+    pub fn is_impersonated(&self) -> bool {
+        self.impersonator.is_some()
+    }
+
+    /// Strict check against the stored user, deleted users and revoked tokens are
+    /// unauthorized and suspended accounts forbidden. Read from the primary and never
+    /// the cache, which may hold a row from before the suspension. API keys are already
+    /// checked against the database when they are authenticated.
+    pub async fn verify(&self, db: &Database) -> Result<(), ServiceError> {
+        let version = match self.version {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        let user = users_service::find_one_by_id(&db.session(), self.id)
+            .await
+            .map_err(|e| match e {
+                ServiceError::NotFound(_) => {
+                    ServiceError::unauthorized::<ServiceError>(UNAUTHORIZED, None)
+                }
+                e => e,
+            })?;
+        users_service::check_token_user(&user, version)
+    }
+
+    /// Role check for REST controllers, the GraphQL equivalent is `RoleGuard` with
+    /// `NoImpersonationGuard`, impersonated sessions never pass it. Verified against the
+    /// stored user when strict access tokens are on.
+    pub async fn require_role(
+        jwt: &Jwt,
+        db: &Database,
+        cache: &Cache,
+        req: &HttpRequest,
+        role: RoleEnum,
+    ) -> Result<Self, ServiceError> {
+        let user = Self::from_request(jwt, db, cache, req)
+            .await
+            .ok_or_else(|| ServiceError::unauthorized::<ServiceError>(UNAUTHORIZED, None))?;
+
+        if jwt.is_strict() {
+            user.verify(db).await?;
+        }
+        if user.is_impersonated() || !user.has_role(role) {
+            return Err(ServiceError::forbidden::<ServiceError>(FORBIDDEN, None));
+        }
+
+        Ok(user)
+    }
 }
@@ -0,0 +1,47 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::ID;
+use uuid::Uuid;
+
+use entities::helpers::{decode_cursor, encode_cursor};
+
+const USER_TYPE: &str = "User";
+const UPLOADED_FILE_TYPE: &str = "UploadedFile";
+
+/// Relay global id, the base64 encoding of `<type>:<database id>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GlobalId {
+    User(i32),
+    UploadedFile(Uuid),
+}
+
+impl GlobalId {
+    /// Returns `None` for malformed ids and unknown type tags.
+    pub fn parse(id: &str) -> Option<Self> {
+        let decoded = decode_cursor(id)?;
+        let (type_name, database_id) = decoded.split_once(':')?;
+
+        match type_name {
+            USER_TYPE => database_id.parse::<i32>().ok().map(Self::User),
+            UPLOADED_FILE_TYPE => Uuid::parse_str(database_id).ok().map(Self::UploadedFile),
+            _ => None,
+        }
+    }
+
+    pub fn format(&self) -> String {
+        match self {
+            Self::User(id) => encode_cursor(&format!("{}:{}", USER_TYPE, id)),
+            Self::UploadedFile(id) => encode_cursor(&format!("{}:{}", UPLOADED_FILE_TYPE, id)),
+        }
+    }
+}
+
+impl From<GlobalId> for ID {
+    fn from(value: GlobalId) -> Self {
+        ID(value.format())
+    }
+}
@@ -5,5 +5,10 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use access_user::*;
+pub use global_id::*;
 
 pub mod access_user;
+pub mod global_id;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,41 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use uuid::Uuid;
+
+use super::GlobalId;
+
+#[test]
+fn test_global_id_round_trip() {
+    let user_id = GlobalId::User(42);
+    assert_eq!(user_id.format(), STANDARD.encode("User:42"));
+    assert_eq!(GlobalId::parse(&user_id.format()), Some(user_id));
+
+    let file_uuid = Uuid::new_v4();
+    let file_id = GlobalId::UploadedFile(file_uuid);
+    assert_eq!(
+        file_id.format(),
+        STANDARD.encode(format!("UploadedFile:{}", file_uuid))
+    );
+    assert_eq!(GlobalId::parse(&file_id.format()), Some(file_id));
+}
+
+#[test]
+fn test_global_id_invalid() {
+    for id in [
+        "",
+        "42",
+        "not base64!",
+        &STANDARD.encode("User"),
+        &STANDARD.encode("User:abc"),
+        &STANDARD.encode("UploadedFile:42"),
+        &STANDARD.encode("ApiKey:42"),
+        &STANDARD.encode("user:42"),
+    ] {
+        assert_eq!(GlobalId::parse(id), None, "{}", id);
+    }
+}
@@ -14,3 +14,6 @@ mod providers;
 mod resolvers;
 mod services;
 pub mod startup;
+
+#[cfg(test)]
+pub mod tests;
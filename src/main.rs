@@ -8,13 +8,27 @@ use std::fmt::{Debug, Display};
 
 use tokio::task::JoinError;
 
-use rust_graphql_template::startup::{ActixApp, Telemetry};
+use rust_graphql_template::startup::{schema_sdl, ActixApp, Telemetry};
+
+const PRINT_SCHEMA_FLAG: &str = "--print-schema";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Needs no environment, so codegen can run without a database or Redis
+    if std::env::args().any(|arg| arg == PRINT_SCHEMA_FLAG) {
+        print!("{}", schema_sdl());
+        return Ok(());
+    }
+
     let subscriber = Telemetry::get_subscriber("rust_graphql_template", "info");
     Telemetry::init_subscriber(subscriber);
-    let application = ActixApp::new().await?;
+    let application = match ActixApp::new().await {
+        Ok(application) => application,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     let application_task = tokio::spawn(application.start_server());
 
     tokio::select! {
@@ -0,0 +1,91 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Error};
+
+/// SHA-256 hashes of the operations the frontend ships, read from a JSON object of
+/// operation name to hash. Without a path every operation is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct QueryAllowlist {
+    path: Option<PathBuf>,
+    hashes: Arc<RwLock<HashSet<String>>>,
+}
+
+fn read_hashes(path: &Path) -> Result<HashSet<String>, Error> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the query allow-list {}", path.display()))?;
+    let operations = serde_json::from_str::<HashMap<String, String>>(&content)
+        .with_context(|| format!("Invalid query allow-list {}", path.display()))?;
+    Ok(operations
+        .into_values()
+        .map(|hash| hash.to_lowercase())
+        .collect())
+}
+
+impl QueryAllowlist {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn from_path(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let hashes = read_hashes(&path)?;
+        Ok(Self {
+            path: Some(path),
+            hashes: Arc::new(RwLock::new(hashes)),
+        })
+    }
+
+    /// Built once from `GRAPHQL_ALLOWLIST_PATH` and cloned into every worker, so a reload
+    /// reaches all of them. The file is checked with the configuration, one that can no
+    /// longer be read rejects every operation until it is fixed and reloaded.
+    pub fn new(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return Self::disabled();
+        };
+
+        Self::from_path(path).unwrap_or_else(|e| {
+            tracing::error!("{:?}", e);
+            Self {
+                path: Some(path.to_path_buf()),
+                hashes: Arc::default(),
+            }
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes
+            .read()
+            .map(|hashes| hashes.contains(&hash.to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    /// Re-reads the file, keeping the current hashes when it can not be read.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let hashes = read_hashes(path)?;
+        let len = hashes.len();
+        *self
+            .hashes
+            .write()
+            .map_err(|_| anyhow::anyhow!("Query allow-list lock poisoned"))? = hashes;
+        tracing::info!("Loaded {} operations into the query allow-list", len);
+        Ok(len)
+    }
+}
@@ -4,32 +4,188 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use redis::{aio::Connection, Client};
-use std::env;
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::timeout;
+use redis::{aio::ConnectionManager, AsyncCommands, Client, RedisError, RedisResult};
+use secrecy::ExposeSecret;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::OnceCell;
 
 use crate::common::{ServiceError, INTERNAL_SERVER_ERROR};
 
+use super::{CacheConfig, Metrics};
+
+fn timed_out() -> RedisError {
+    RedisError::from(io::Error::from(io::ErrorKind::TimedOut))
+}
+
+fn is_transient(err: &RedisError) -> bool {
+    err.is_io_error()
+        || err.is_connection_refusal()
+        || err.is_connection_dropped()
+        || err.is_timeout()
+}
+
 #[derive(Clone)]
 pub struct Cache {
     client: Client,
+    pool: Arc<[OnceCell<ConnectionManager>]>,
+    next: Arc<AtomicUsize>,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+    ttl: u64,
+    metrics: Metrics,
 }
 
 impl Cache {
-    pub fn new() -> Self {
-        let redis_url = env::var("REDIS_URL").expect("Missing the REDIS_URL environment variable.");
-        let client = Client::open(redis_url).expect("Failed to create Redis client.");
-        Self { client }
+    pub fn new(config: &CacheConfig, metrics: &Metrics) -> Self {
+        // The URL was checked with the rest of the configuration
+        let client = Client::open(config.url.expose_secret().as_str())
+            .expect("Failed to create Redis client.");
+        Self {
+            client,
+            // Connections are opened on first use so the app can boot while Redis is down
+            pool: (0..config.pool_size.max(1))
+                .map(|_| OnceCell::new())
+                .collect(),
+            next: Arc::new(AtomicUsize::new(0)),
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            response_timeout: Duration::from_millis(config.response_timeout_ms),
+            ttl: config.ttl,
+            metrics: metrics.clone(),
+        }
     }
 
-    pub async fn get_connection(&self) -> Result<Connection, ServiceError> {
-        let con = self.client.get_tokio_connection().await;
+    /// A cache with the default pool and timeouts.
+    pub fn with_url(redis_url: &str, metrics: &Metrics) -> Self {
+        Self::new(&CacheConfig::new(redis_url), metrics)
+    }
+
+    pub fn get_ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    async fn acquire(&self) -> RedisResult<ConnectionManager> {
+        let start = Instant::now();
+        let slot = &self.pool[self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len()];
+        // A failed connect leaves the slot empty, so the next caller tries again
+        let connection = slot
+            .get_or_try_init(|| async {
+                timeout(
+                    self.connect_timeout,
+                    ConnectionManager::new(self.client.clone()),
+                )
+                .await
+                .unwrap_or_else(|_| Err(timed_out()))
+            })
+            .await
+            .cloned();
+        self.metrics
+            .observe_cache_acquire(start.elapsed().as_secs_f64());
+        connection
+    }
+
+    pub async fn get_connection(&self) -> Result<ConnectionManager, ServiceError> {
+        self.acquire()
+            .await
+            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))
+    }
 
-        match con {
-            Ok(con) => Ok(con),
-            Err(err) => Err(ServiceError::internal_server_error(
-                INTERNAL_SERVER_ERROR,
-                Some(err),
-            )),
+    async fn try_execute<T, F, Fut>(&self, command: &F) -> RedisResult<T>
+    where
+        F: Fn(ConnectionManager) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let connection = self.acquire().await?;
+        timeout(self.response_timeout, command(connection))
+            .await
+            .unwrap_or_else(|_| Err(timed_out()))
+    }
+
+    /// Runs a command on a pooled connection, retrying once on a transient failure.
+    pub async fn execute<T, F, Fut>(&self, command: F) -> Result<T, ServiceError>
+    where
+        F: Fn(ConnectionManager) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let result = match self.try_execute(&command).await {
+            Err(err) if is_transient(&err) => {
+                tracing::warn!("Retrying Redis command after transient error: {}", err);
+                self.try_execute(&command).await
+            }
+            result => result,
+        };
+        result.map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))
+    }
+
+    pub async fn ping(&self) -> Result<(), ServiceError> {
+        self.execute(|mut connection| async move {
+            redis::cmd("PING")
+                .query_async::<_, ()>(&mut connection)
+                .await
+        })
+        .await
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, ServiceError> {
+        let value = self
+            .execute(
+                |mut connection| async move { connection.get::<&str, Option<String>>(key).await },
+            )
+            .await?;
+
+        match value {
+            Some(value) => Ok(serde_json::from_str(&value).ok()),
+            None => Ok(None),
         }
     }
+
+    pub async fn set_json<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: u64,
+    ) -> Result<(), ServiceError> {
+        let value = serde_json::to_string(value)
+            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
+        let value = value.as_str();
+        self.execute(|mut connection| async move {
+            connection.set_ex::<&str, &str, ()>(key, value, ttl).await
+        })
+        .await
+    }
+
+    /// Sets the key unless it exists, `false` when another caller got there first.
+    pub async fn set_once(&self, key: &str, value: i64, ttl: u64) -> Result<bool, ServiceError> {
+        Ok(self
+            .execute(|mut connection| async move {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(ttl)
+                    .query_async::<_, Option<String>>(&mut connection)
+                    .await
+            })
+            .await?
+            .is_some())
+    }
+
+    pub async fn del(&self, key: &str) -> Result<(), ServiceError> {
+        self.execute(|mut connection| async move { connection.del::<&str, ()>(key).await })
+            .await
+    }
 }
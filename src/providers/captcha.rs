@@ -0,0 +1,201 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::rt::time::timeout;
+use anyhow::{anyhow, Error, Result as AnyResult};
+use async_trait::async_trait;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+
+use crate::common::ServiceError;
+
+use super::{CaptchaConfig, CaptchaProviderKind};
+
+pub const CAPTCHA_FAILED: &str = "Captcha failed";
+
+/// Asks a captcha provider whether a token was issued to a human. Errors are calls that
+/// could not complete, a rejected token is `Ok(false)`.
+#[async_trait]
+pub trait Captcha: Send + Sync {
+    async fn verify(&self, token: &str, remote_ip: Option<&str>) -> AnyResult<bool>;
+}
+
+/// Both providers answer the siteverify form with the same shape, only reCAPTCHA v3
+/// adds a score.
+#[derive(Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(default)]
+    score: Option<f64>,
+}
+
+async fn site_verify(
+    client: &Client,
+    url: &str,
+    secret: &Secret<String>,
+    token: &str,
+    remote_ip: Option<&str>,
+) -> AnyResult<VerifyResponse> {
+    let mut form = vec![
+        ("secret", secret.expose_secret().as_str()),
+        ("response", token),
+    ];
+
+    if let Some(remote_ip) = remote_ip {
+        form.push(("remoteip", remote_ip));
+    }
+
+    let response = client.post(url).form(&form).send().await?;
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(anyhow!("{} responded with {}", url, status));
+    }
+
+    Ok(response.json::<VerifyResponse>().await?)
+}
+
+pub struct Turnstile {
+    client: Client,
+    url: String,
+    secret: Secret<String>,
+}
+
+impl Turnstile {
+    pub fn new(client: Client, url: &str, secret: Secret<String>) -> Self {
+        Self {
+            client,
+            url: url.to_string(),
+            secret,
+        }
+    }
+}
+
+#[async_trait]
+impl Captcha for Turnstile {
+    async fn verify(&self, token: &str, remote_ip: Option<&str>) -> AnyResult<bool> {
+        let response = site_verify(&self.client, &self.url, &self.secret, token, remote_ip).await?;
+        Ok(response.success)
+    }
+}
+
+/// reCAPTCHA v3 never shows a challenge, the score tells how likely the user is a bot.
+pub struct Recaptcha {
+    client: Client,
+    url: String,
+    secret: Secret<String>,
+    score_threshold: f64,
+}
+
+impl Recaptcha {
+    pub fn new(client: Client, url: &str, secret: Secret<String>, score_threshold: f64) -> Self {
+        Self {
+            client,
+            url: url.to_string(),
+            secret,
+            score_threshold,
+        }
+    }
+}
+
+#[async_trait]
+impl Captcha for Recaptcha {
+    async fn verify(&self, token: &str, remote_ip: Option<&str>) -> AnyResult<bool> {
+        let response = site_verify(&self.client, &self.url, &self.secret, token, remote_ip).await?;
+        Ok(response.success
+            && response
+                .score
+                .is_some_and(|score| score >= self.score_threshold))
+    }
+}
+
+/// Checks the captcha tokens of the public auth endpoints. Without a provider every
+/// request passes, so deployments that do not need it are unaffected.
+#[derive(Clone)]
+pub struct CaptchaVerifier {
+    captcha: Option<Arc<dyn Captcha>>,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl CaptchaVerifier {
+    pub fn new(config: &CaptchaConfig) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap_or_default();
+
+        match config.provider {
+            CaptchaProviderKind::None => Self::disabled(),
+            CaptchaProviderKind::Turnstile => Self::with_captcha(
+                config,
+                Turnstile::new(client, &config.verify_url, config.secret.clone()),
+            ),
+            CaptchaProviderKind::Recaptcha => Self::with_captcha(
+                config,
+                Recaptcha::new(
+                    client,
+                    &config.verify_url,
+                    config.secret.clone(),
+                    config.score_threshold,
+                ),
+            ),
+        }
+    }
+
+    pub fn with_captcha(config: &CaptchaConfig, captcha: impl Captcha + 'static) -> Self {
+        Self {
+            captcha: Some(Arc::new(captcha)),
+            timeout: Duration::from_millis(config.timeout_ms),
+            fail_open: config.fail_open,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            captcha: None,
+            timeout: Duration::ZERO,
+            fail_open: true,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.captcha.is_some()
+    }
+
+    /// A missing or rejected token always fails, a provider that errors or does not
+    /// answer in time only fails when the verifier is not set to fail open.
+    pub async fn check(
+        &self,
+        token: Option<&str>,
+        remote_ip: Option<&str>,
+    ) -> Result<(), ServiceError> {
+        let Some(captcha) = &self.captcha else {
+            return Ok(());
+        };
+        let Some(token) = token.filter(|token| !token.is_empty()) else {
+            return Err(ServiceError::bad_request::<Error>(CAPTCHA_FAILED, None));
+        };
+        let error = match timeout(self.timeout, captcha.verify(token, remote_ip)).await {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => return Err(ServiceError::bad_request::<Error>(CAPTCHA_FAILED, None)),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("Captcha verification timed out after {:?}", self.timeout),
+        };
+
+        if self.fail_open {
+            tracing::warn!("Letting request through without captcha: {:?}", error);
+            return Ok(());
+        }
+
+        tracing::error!("Failed to verify captcha: {:?}", error);
+        Err(ServiceError::bad_request(CAPTCHA_FAILED, Some(error)))
+    }
+}
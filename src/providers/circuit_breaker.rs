@@ -0,0 +1,94 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    half_open: bool,
+}
+
+/// Fails fast once `threshold` calls in a row have failed, for `cooldown`. The next
+/// call after it is a trial: success closes the breaker, failure opens it again.
+/// Clones share the state, so every worker sees the same breaker.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            name,
+            threshold: threshold.max(1),
+            cooldown,
+            state: Arc::new(Mutex::new(BreakerState::default())),
+        }
+    }
+
+    /// Whether calls may go through, a poisoned lock never blocks them.
+    pub fn allows(&self) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return true,
+        };
+
+        match state.open_until {
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                if !state.half_open {
+                    state.half_open = true;
+                    tracing::info!(breaker = self.name, "Circuit breaker half open");
+                }
+
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+
+        if state.open_until.take().is_some() {
+            tracing::info!(breaker = self.name, "Circuit breaker closed");
+        }
+
+        state.failures = 0;
+        state.half_open = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.failures += 1;
+
+        // A failed trial opens it again right away
+        if state.open_until.is_some() || state.failures >= self.threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.half_open = false;
+            tracing::warn!(
+                breaker = self.name,
+                failures = state.failures,
+                cooldown_seconds = self.cooldown.as_secs(),
+                "Circuit breaker opened"
+            );
+        }
+    }
+}
@@ -0,0 +1,1461 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{env, fmt, path::PathBuf, str::FromStr};
+
+use argon2::Params;
+use redis::IntoConnectionInfo;
+use secrecy::Secret;
+use uuid::Uuid;
+
+use crate::common::{DEFAULT_EMAIL_ALIAS_DOMAINS, DEFAULT_MINIMUM_AGE};
+
+use super::{ApiURLs, Environment, QueryAllowlist, DEFAULT_UPLOAD_ALLOWED_TYPES, UPLOADS_PATH};
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_MULTIPART_THRESHOLD: usize = 8 * 1024 * 1024;
+const DEFAULT_STORAGE_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_STORAGE_RETRY_DELAY_MS: u64 = 200;
+const DEFAULT_STORAGE_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_STORAGE_BREAKER_COOLDOWN_SECONDS: u64 = 30;
+const DEFAULT_EMAIL_PORT: u16 = 587;
+const DEFAULT_EMAIL_MAX_ATTEMPTS: i16 = 5;
+const DEFAULT_SENDGRID_URL: &str = "https://api.sendgrid.com/v3/mail/send";
+const DEFAULT_JSON_BODY_LIMIT: usize = 64 * 1024;
+const DEFAULT_GRAPHQL_BODY_LIMIT: usize = 16 * 1024 * 1024;
+const DEFAULT_IMPORT_BODY_LIMIT: usize = 8 * 1024 * 1024;
+const DEFAULT_TURNSTILE_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const DEFAULT_RECAPTCHA_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+const DEFAULT_CAPTCHA_SCORE_THRESHOLD: f64 = 0.5;
+const DEFAULT_CAPTCHA_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_GRAPHQL_MAX_DEPTH: usize = 8;
+const DEFAULT_GRAPHQL_MAX_COMPLEXITY: usize = 200;
+const DEFAULT_GRAPHQL_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_GRAPHQL_SLOW_QUERY_MS: u64 = 1000;
+const DEFAULT_PERSISTED_QUERY_TTL: u64 = 86400;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_HTTP_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_PASSWORD_BREACH_URL: &str = "https://api.pwnedpasswords.com/range";
+const DEFAULT_PASSWORD_BREACH_TIMEOUT_MS: u64 = 1500;
+pub const DEFAULT_RESERVED_USERNAMES: [&str; 8] = [
+    "admin",
+    "administrator",
+    "root",
+    "support",
+    "help",
+    "official",
+    "staff",
+    "system",
+];
+const DEFAULT_DATABASE_MIN_CONNECTIONS: u32 = 1;
+const DEFAULT_DATABASE_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_REDIS_POOL_SIZE: usize = 4;
+const DEFAULT_REDIS_CONNECT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_REDIS_RESPONSE_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_CACHE_TTL: u64 = 60;
+const DEFAULT_SIGN_IN_MAX_ATTEMPTS: i64 = 5;
+const DEFAULT_UNCONFIRMED_EXPIRY_DAYS: i64 = 7;
+const DEFAULT_UNCONFIRMED_EXPIRY_INTERVAL: u64 = 3600;
+
+#[derive(Clone, Debug)]
+pub struct ConfigProblem {
+    pub name: String,
+    pub expectation: String,
+}
+
+/// Every missing or invalid variable found while reading the configuration.
+#[derive(Debug)]
+pub struct ConfigError {
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigError {
+    pub fn problems(&self) -> &[ConfigProblem] {
+        &self.problems
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid configuration, {} problem(s) found:",
+            self.problems.len()
+        )?;
+
+        for problem in &self.problems {
+            write!(f, "\n  - {}: {}", problem.name, problem.expectation)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+struct EnvReader<F: Fn(&str) -> Option<String>> {
+    lookup: F,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvReader<F> {
+    fn new(lookup: F) -> Self {
+        Self {
+            lookup,
+            problems: Vec::new(),
+        }
+    }
+
+    fn problem(&mut self, name: &str, expectation: String) {
+        self.problems.push(ConfigProblem {
+            name: name.to_string(),
+            expectation,
+        });
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        (self.lookup)(name).filter(|value| !value.is_empty())
+    }
+
+    fn optional(&self, name: &str, default: &str) -> String {
+        self.get(name).unwrap_or_else(|| default.to_string())
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        self.get(name).unwrap_or_else(|| {
+            self.problem(name, "is required".to_string());
+            String::new()
+        })
+    }
+
+    /// Development falls back to the given default, production requires the variable.
+    fn required_in_production(
+        &mut self,
+        environment: &Environment,
+        name: &str,
+        development: impl FnOnce() -> String,
+    ) -> String {
+        match environment {
+            Environment::Development => self.get(name).unwrap_or_else(development),
+            Environment::Production => self.required(name),
+        }
+    }
+
+    fn parse<T: FromStr>(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        expectation: &str,
+    ) -> Option<T> {
+        let value = value?;
+
+        match value.parse::<T>() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.problem(name, format!("must be {}, got \"{}\"", expectation, value));
+                None
+            }
+        }
+    }
+
+    fn parse_optional<T: FromStr>(&mut self, name: &str, default: T, expectation: &str) -> T {
+        let value = self.get(name);
+        self.parse(name, value, expectation).unwrap_or(default)
+    }
+
+    fn parse_required<T: FromStr + Default>(&mut self, name: &str, expectation: &str) -> T {
+        let value = Some(self.required(name)).filter(|value| !value.is_empty());
+        self.parse(name, value, expectation).unwrap_or_default()
+    }
+
+    fn finish<T>(self, value: T) -> Result<T, ConfigError> {
+        if self.problems.is_empty() {
+            Ok(value)
+        } else {
+            Err(ConfigError {
+                problems: self.problems,
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    pub secret: Secret<String>,
+    pub exp: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub access: TokenConfig,
+    pub reset: TokenConfig,
+    pub confirmation: TokenConfig,
+    pub refresh: TokenConfig,
+    /// Signs the links that undo an email change, its expiry is the revert window.
+    pub revert: TokenConfig,
+    pub refresh_name: String,
+    /// Name of the HTTP only access token cookie, set when the cookie mode is on.
+    pub access_cookie: Option<String>,
+    pub iss: Uuid,
+    pub aud: String,
+    /// Check access tokens against the stored user on every guarded request, mutations
+    /// are always checked.
+    pub strict: bool,
+    /// Seconds since the sign in after which refresh tokens stop rotating.
+    pub session_max_lifetime: i64,
+    pub binding: TokenBinding,
+}
+
+/// How refresh tokens presented by another client than the one they were issued to
+/// are treated, warn only logs the mismatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenBinding {
+    Off,
+    Warn,
+    Enforce,
+}
+
+impl FromStr for TokenBinding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "enforce" => Ok(Self::Enforce),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmailTransportKind {
+    Smtp,
+    Console,
+    SendGrid,
+}
+
+impl FromStr for EmailTransportKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "smtp" => Ok(Self::Smtp),
+            "console" => Ok(Self::Console),
+            "http-sendgrid" => Ok(Self::SendGrid),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptchaProviderKind {
+    None,
+    Turnstile,
+    Recaptcha,
+}
+
+impl FromStr for CaptchaProviderKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "turnstile" => Ok(Self::Turnstile),
+            "recaptcha" => Ok(Self::Recaptcha),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The filesystem backend keeps uploads in a local directory, for development without S3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectStorageBackend {
+    S3,
+    Filesystem,
+}
+
+impl FromStr for ObjectStorageBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "s3" => Ok(Self::S3),
+            "filesystem" => Ok(Self::Filesystem),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Invite only deployments need an admin invitation for every new local account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignUpMode {
+    Open,
+    InviteOnly,
+}
+
+impl FromStr for SignUpMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "open" => Ok(Self::Open),
+            "invite_only" => Ok(Self::InviteOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Version of the terms users need to have accepted, re-consent is only enforced when
+/// one is configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TermsVersion(Option<String>);
+
+impl TermsVersion {
+    pub fn new(version: Option<String>) -> Self {
+        Self(version)
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+
+    pub fn is_outdated(&self, accepted: Option<&str>) -> bool {
+        self.current()
+            .is_some_and(|current| accepted != Some(current))
+    }
+}
+
+/// Emails users when tokens are issued to a device they have not signed in from lately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceAlerts(bool);
+
+impl DeviceAlerts {
+    pub fn new(enabled: bool) -> Self {
+        Self(enabled)
+    }
+
+    pub fn disabled() -> Self {
+        Self(false)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MailerConfig {
+    pub transport: EmailTransportKind,
+    pub host: String,
+    pub port: u16,
+    /// Sender address, also the SMTP user.
+    pub user: String,
+    pub password: Secret<String>,
+    pub sendgrid_url: String,
+    pub sendgrid_api_key: Secret<String>,
+    pub company_name: String,
+    pub frontend_url: String,
+    /// Deliveries the outbox tries before marking an email as failed.
+    pub max_attempts: i16,
+}
+
+#[derive(Clone, Debug)]
+pub struct OAuthClientConfig {
+    pub client_id: String,
+    pub client_secret: Secret<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OAuthConfig {
+    pub backend_url: String,
+    pub state_secret: Secret<String>,
+    pub success_redirect: String,
+    pub error_redirect: String,
+    pub link_redirect: String,
+    pub google: OAuthClientConfig,
+    pub facebook: OAuthClientConfig,
+    pub github: OAuthClientConfig,
+}
+
+/// Bot protection of the public auth endpoints, off with the `None` provider.
+#[derive(Clone, Debug)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProviderKind,
+    pub secret: Secret<String>,
+    pub verify_url: String,
+    /// Lowest reCAPTCHA v3 score accepted, Turnstile has no score.
+    pub score_threshold: f64,
+    pub timeout_ms: u64,
+    /// Whether a verification that could not complete lets the request through.
+    pub fail_open: bool,
+}
+
+/// HaveIBeenPwned range check of new passwords, off by default.
+#[derive(Clone, Debug)]
+pub struct PasswordBreachConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhooksConfig {
+    pub urls: Vec<String>,
+    pub secret: Secret<String>,
+}
+
+/// Largest accepted request bodies in bytes, GraphQL and CSV imports need more room
+/// for uploads.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimitsConfig {
+    pub json: usize,
+    pub graphql: usize,
+    pub import: usize,
+}
+
+/// What else than the database, Redis and migrations `/api/ready` requires.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadinessConfig {
+    /// Reports the instance unready while the mailer fails, otherwise it is only shown.
+    pub mailer_fatal: bool,
+}
+
+/// Whether startup checks its dependencies before serving, development only warns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupChecks {
+    On,
+    Off,
+}
+
+impl FromStr for StartupChecks {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "on" => Ok(Self::On),
+            "off" => Ok(Self::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parts of the API an instance serves, providers only they use are not built without them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnabledModules {
+    /// GraphQL API, playground, images and local uploads.
+    pub graphql: bool,
+    /// REST auth, admin and docs controllers.
+    pub auth: bool,
+    /// Health checks and metrics.
+    pub health: bool,
+}
+
+impl EnabledModules {
+    pub const ALL: Self = Self {
+        graphql: true,
+        auth: true,
+        health: true,
+    };
+}
+
+impl FromStr for EnabledModules {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modules = Self {
+            graphql: false,
+            auth: false,
+            health: false,
+        };
+
+        for module in s
+            .split(',')
+            .map(str::trim)
+            .filter(|module| !module.is_empty())
+        {
+            match module.to_lowercase().as_str() {
+                "graphql" => modules.graphql = true,
+                "auth" => modules.auth = true,
+                "health" => modules.health = true,
+                "all" => modules = Self::ALL,
+                _ => return Err(()),
+            }
+        }
+
+        if modules.graphql || modules.auth || modules.health {
+            Ok(modules)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// How deep and how complex a GraphQL operation may be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GraphQLLimits {
+    pub max_depth: usize,
+    pub max_complexity: usize,
+}
+
+impl Default for GraphQLLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_GRAPHQL_MAX_DEPTH,
+            max_complexity: DEFAULT_GRAPHQL_MAX_COMPLEXITY,
+        }
+    }
+}
+
+/// How long a GraphQL operation may run, and from when it is logged as slow.
+#[derive(Clone, Copy, Debug)]
+pub struct GraphQLExecutionConfig {
+    pub timeout_seconds: u64,
+    pub slow_query_ms: u64,
+    /// Seconds a persisted query's text stays registered.
+    pub persisted_query_ttl: u64,
+}
+
+/// Connections each database pool keeps open and may open, the replica's included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DatabasePoolConfig {
+    pub min_connections: u32,
+    pub max_connections: u32,
+}
+
+impl Default for DatabasePoolConfig {
+    fn default() -> Self {
+        Self {
+            min_connections: DEFAULT_DATABASE_MIN_CONNECTIONS,
+            max_connections: DEFAULT_DATABASE_MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// The primary database, and the replica reads that tolerate lag go to when there is one.
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    pub url: Secret<String>,
+    pub read_url: Option<Secret<String>>,
+    pub pool: DatabasePoolConfig,
+}
+
+/// Redis behind the cache, connections are opened on first use up to `pool_size`.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    pub url: Secret<String>,
+    pub pool_size: usize,
+    pub connect_timeout_ms: u64,
+    pub response_timeout_ms: u64,
+    /// Seconds cached entries live.
+    pub ttl: u64,
+}
+
+impl CacheConfig {
+    /// The default pool and timeouts for the given URL.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: Secret::new(url.to_string()),
+            pool_size: DEFAULT_REDIS_POOL_SIZE,
+            connect_timeout_ms: DEFAULT_REDIS_CONNECT_TIMEOUT_MS,
+            response_timeout_ms: DEFAULT_REDIS_RESPONSE_TIMEOUT_MS,
+            ttl: DEFAULT_CACHE_TTL,
+        }
+    }
+}
+
+/// Unconfirmed accounts are deleted `days` after signing up, the job checking them runs
+/// every `interval_seconds`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnconfirmedExpiryConfig {
+    pub days: i64,
+    pub interval_seconds: u64,
+}
+
+/// Outbound calls to the OAuth providers, the proxy is only used for HTTPS.
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64,
+    pub proxy: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectStorageConfig {
+    pub backend: ObjectStorageBackend,
+    /// Root directory of the filesystem backend.
+    pub local_path: PathBuf,
+    /// Base URL the filesystem backend serves its files from.
+    pub local_url: String,
+    pub host: String,
+    pub access_key: String,
+    pub secret_key: Secret<String>,
+    pub bucket: String,
+    pub region: String,
+    pub namespace: Uuid,
+    pub multipart_threshold: usize,
+    pub public: bool,
+    pub deduplicate: bool,
+    pub retry_attempts: u32,
+    pub retry_delay_ms: u64,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_seconds: u64,
+    /// Bytes each user may store, 0 for no limit.
+    pub user_quota_bytes: u64,
+    /// MIME types accepted for documents, images have their own pipeline.
+    pub upload_allowed_types: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub environment: Environment,
+    pub host: String,
+    pub port: u16,
+    pub urls: ApiURLs,
+    pub jwt: JwtConfig,
+    pub mailer: MailerConfig,
+    pub oauth: OAuthConfig,
+    pub object_storage: ObjectStorageConfig,
+    pub webhooks: WebhooksConfig,
+    pub captcha: CaptchaConfig,
+    pub http_client: HttpClientConfig,
+    pub password_breach: PasswordBreachConfig,
+    pub body_limits: BodyLimitsConfig,
+    pub graphql_limits: GraphQLLimits,
+    pub graphql_execution: GraphQLExecutionConfig,
+    pub readiness: ReadinessConfig,
+    pub startup_checks: StartupChecks,
+    pub modules: EnabledModules,
+    pub sign_up_mode: SignUpMode,
+    pub terms_version: TermsVersion,
+    pub device_alerts: DeviceAlerts,
+    /// Failed sign ins before the account is locked for a while.
+    pub sign_in_max_attempts: i64,
+    pub run_migrations: bool,
+    /// Serves the OpenAPI document and Swagger UI, off in production unless asked for.
+    pub api_docs: bool,
+    /// Serves the GraphQL playground on bare GETs, off in production unless asked for.
+    pub playground: bool,
+    /// Bearer token `/metrics` scrapes need, served to anyone without one.
+    pub metrics_token: Option<Secret<String>>,
+    pub unconfirmed_expiry: UnconfirmedExpiryConfig,
+    /// Seconds between scheduled storage collections, none when unset or 0.
+    pub storage_gc_interval: Option<u64>,
+    /// JSON file of the operation hashes GraphQL accepts, every operation without one.
+    pub graphql_allowlist_path: Option<PathBuf>,
+    /// Domains ignoring dots and `+tag` suffixes, addresses differing only by them are
+    /// the same account.
+    pub email_alias_domains: Vec<String>,
+    /// Usernames nobody can claim, lowercase, checked with any `.N` suffix.
+    pub reserved_usernames: Vec<String>,
+    /// Youngest age, in years, a date of birth is accepted for.
+    pub minimum_age: u32,
+    pub database: DatabaseConfig,
+    pub cache: CacheConfig,
+    /// Key behind the HMACs of API keys, recovery codes, invitations and signed image URLs.
+    pub code_secret: Secret<String>,
+    /// Argon2id costs for new password hashes, older hashes are upgraded on sign in.
+    pub password_hash: Params,
+}
+
+impl Config {
+    /// Panicking wrapper around [`Config::try_new`], the panic lists every problem.
+    pub fn new() -> Self {
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new() -> Result<Self, ConfigError> {
+        Self::try_from_lookup(|name| env::var(name).ok())
+    }
+
+    pub fn try_from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut reader = EnvReader::new(lookup);
+        let environment = reader.parse_optional(
+            "ENVIRONMENT",
+            Environment::Development,
+            "one of development, dev, production or prod",
+        );
+        Self::read(reader, environment)
+    }
+
+    /// Reads the configuration for a fixed environment, ignoring `ENVIRONMENT`.
+    pub fn try_with_environment(environment: Environment) -> Result<Self, ConfigError> {
+        Self::read(EnvReader::new(|name| env::var(name).ok()), environment)
+    }
+
+    fn read<F: Fn(&str) -> Option<String>>(
+        mut reader: EnvReader<F>,
+        environment: Environment,
+    ) -> Result<Self, ConfigError> {
+        let modules = reader.parse_optional(
+            "ENABLED_MODULES",
+            EnabledModules::ALL,
+            "a comma separated list of graphql, auth, health or all",
+        );
+        let host = reader.optional("HOST", DEFAULT_HOST);
+        let port = reader.parse_optional("PORT", DEFAULT_PORT, "a port number");
+        let urls = Self::read_urls(&mut reader, &environment, port);
+        let jwt = Self::read_jwt(&mut reader, &environment, &urls);
+        let mailer = Self::read_mailer(&mut reader, &environment, &urls);
+        let oauth = Self::read_oauth(&mut reader, &environment, &urls, modules.auth);
+        let object_storage =
+            Self::read_object_storage(&mut reader, &environment, &urls, modules.graphql);
+        let webhooks = Self::read_webhooks(&mut reader);
+        let captcha = Self::read_captcha(&mut reader);
+        let http_client = Self::read_http_client(&mut reader);
+        let password_breach = PasswordBreachConfig {
+            enabled: reader.parse_optional("PASSWORD_BREACH_CHECK", false, "true or false"),
+            url: reader.optional("PASSWORD_BREACH_URL", DEFAULT_PASSWORD_BREACH_URL),
+            timeout_ms: reader.parse_optional(
+                "PASSWORD_BREACH_TIMEOUT_MS",
+                DEFAULT_PASSWORD_BREACH_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+        };
+        let body_limits = BodyLimitsConfig {
+            json: reader.parse_optional(
+                "JSON_BODY_LIMIT",
+                DEFAULT_JSON_BODY_LIMIT,
+                "a number of bytes",
+            ),
+            graphql: reader.parse_optional(
+                "GRAPHQL_BODY_LIMIT",
+                DEFAULT_GRAPHQL_BODY_LIMIT,
+                "a number of bytes",
+            ),
+            import: reader.parse_optional(
+                "IMPORT_BODY_LIMIT",
+                DEFAULT_IMPORT_BODY_LIMIT,
+                "a number of bytes",
+            ),
+        };
+        let graphql_limits = Self::read_graphql_limits(&mut reader);
+        let graphql_execution = GraphQLExecutionConfig {
+            timeout_seconds: reader.parse_optional(
+                "GRAPHQL_TIMEOUT_SECONDS",
+                DEFAULT_GRAPHQL_TIMEOUT_SECONDS,
+                "a number of seconds",
+            ),
+            slow_query_ms: reader.parse_optional(
+                "GRAPHQL_SLOW_QUERY_MS",
+                DEFAULT_GRAPHQL_SLOW_QUERY_MS,
+                "a number of milliseconds",
+            ),
+            persisted_query_ttl: reader.parse_optional(
+                "PERSISTED_QUERY_TTL",
+                DEFAULT_PERSISTED_QUERY_TTL,
+                "a number of seconds",
+            ),
+        };
+        let readiness = ReadinessConfig {
+            mailer_fatal: reader.parse_optional("READINESS_MAILER_FATAL", false, "true or false"),
+        };
+        let startup_checks =
+            reader.parse_optional("STARTUP_CHECKS", StartupChecks::On, "on or off");
+        let sign_up_mode = reader.parse_optional(
+            "SIGNUP_MODE",
+            SignUpMode::Open,
+            "one of open or invite_only",
+        );
+        let terms_version = TermsVersion::new(reader.get("CURRENT_TERMS_VERSION"));
+        let device_alerts =
+            DeviceAlerts::new(reader.parse_optional("NEW_DEVICE_ALERTS", true, "true or false"));
+        let sign_in_max_attempts = reader.parse_optional(
+            "SIGN_IN_MAX_ATTEMPTS",
+            DEFAULT_SIGN_IN_MAX_ATTEMPTS,
+            "a number of attempts",
+        );
+        if sign_in_max_attempts <= 0 {
+            reader.problem(
+                "SIGN_IN_MAX_ATTEMPTS",
+                format!(
+                    "must be a positive number of attempts, got {}",
+                    sign_in_max_attempts
+                ),
+            );
+        }
+        let run_migrations = reader.parse_optional("RUN_MIGRATIONS", false, "true or false");
+        let api_docs =
+            reader.parse_optional("API_DOCS", !environment.is_production(), "true or false");
+        let playground = reader.parse_optional(
+            "ENABLE_PLAYGROUND",
+            !environment.is_production(),
+            "true or false",
+        );
+        let metrics_token = reader.get("METRICS_TOKEN").map(Secret::new);
+        let unconfirmed_expiry = Self::read_unconfirmed_expiry(&mut reader);
+        let storage_gc_interval =
+            Some(reader.parse_optional("STORAGE_GC_INTERVAL", 0, "a number of seconds"))
+                .filter(|seconds| *seconds > 0);
+        let graphql_allowlist_path = reader.get("GRAPHQL_ALLOWLIST_PATH").map(PathBuf::from);
+        if let Some(Err(e)) = graphql_allowlist_path
+            .as_ref()
+            .map(QueryAllowlist::from_path)
+        {
+            reader.problem(
+                "GRAPHQL_ALLOWLIST_PATH",
+                format!("must be a readable allow-list file, {:#}", e),
+            );
+        }
+        let email_alias_domains = Self::read_email_alias_domains(&mut reader);
+        let reserved_usernames = Self::read_reserved_usernames(&mut reader);
+        let minimum_age =
+            reader.parse_optional("MINIMUM_AGE", DEFAULT_MINIMUM_AGE, "a number of years");
+        let database = Self::read_database(&mut reader);
+        let cache = Self::read_cache(&mut reader);
+        let code_secret = Secret::new(reader.required_in_production(
+            &environment,
+            "CODE_SECRET",
+            || Uuid::new_v4().to_string(),
+        ));
+        let password_hash = Self::read_password_hash(&mut reader);
+        reader.finish(Self {
+            environment,
+            host,
+            port,
+            urls,
+            jwt,
+            mailer,
+            oauth,
+            object_storage,
+            webhooks,
+            captcha,
+            http_client,
+            password_breach,
+            body_limits,
+            graphql_limits,
+            graphql_execution,
+            readiness,
+            startup_checks,
+            modules,
+            sign_up_mode,
+            terms_version,
+            device_alerts,
+            sign_in_max_attempts,
+            run_migrations,
+            api_docs,
+            playground,
+            metrics_token,
+            unconfirmed_expiry,
+            storage_gc_interval,
+            graphql_allowlist_path,
+            email_alias_domains,
+            reserved_usernames,
+            minimum_age,
+            database,
+            cache,
+            code_secret,
+            password_hash,
+        })
+    }
+
+    fn read_urls<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+        environment: &Environment,
+        port: u16,
+    ) -> ApiURLs {
+        ApiURLs {
+            api_id: reader
+                .required_in_production(environment, "API_ID", || Uuid::new_v4().to_string()),
+            backend_url: reader.required_in_production(environment, "BACKEND_URL", || {
+                format!("http://localhost:{}", port)
+            }),
+            frontend_url: reader.required("FRONTEND_URL"),
+        }
+    }
+
+    fn read_jwt<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+        environment: &Environment,
+        urls: &ApiURLs,
+    ) -> JwtConfig {
+        let mut token = |secret: &str, exp: &str, default_exp: i64| TokenConfig {
+            secret: Secret::new(
+                reader.required_in_production(environment, secret, || Uuid::new_v4().to_string()),
+            ),
+            exp: reader.parse_optional(exp, default_exp, "a number of seconds"),
+        };
+        let access = token("ACCESS_SECRET", "ACCESS_EXPIRATION", 600);
+        let reset = token("RESET_SECRET", "RESET_EXPIRATION", 1800);
+        let confirmation = token("CONFIRMATION_SECRET", "CONFIRMATION_EXPIRATION", 86400);
+        let refresh = token("REFRESH_SECRET", "REFRESH_EXPIRATION", 259200);
+        let revert = token("REVERT_SECRET", "REVERT_EXPIRATION", 259200);
+        let refresh_name =
+            reader.required_in_production(environment, "REFRESH_NAME", || "refresh".to_string());
+        let access_cookie = reader
+            .parse_optional("AUTH_COOKIE_MODE", false, "true or false")
+            .then(|| reader.optional("ACCESS_COOKIE_NAME", "access"));
+        let iss = reader
+            .parse(
+                "API_ID",
+                Some(urls.api_id.clone()).filter(|api_id| !api_id.is_empty()),
+                "a UUID",
+            )
+            .unwrap_or_default();
+        let strict = reader.parse_optional("STRICT_ACCESS_TOKENS", false, "true or false");
+        let session_max_lifetime =
+            reader.parse_optional("SESSION_MAX_LIFETIME", 2592000, "a number of seconds");
+        let binding =
+            reader.parse_optional("TOKEN_BINDING", TokenBinding::Off, "off, warn or enforce");
+
+        JwtConfig {
+            access,
+            reset,
+            confirmation,
+            refresh,
+            revert,
+            refresh_name,
+            access_cookie,
+            iss,
+            aud: urls.frontend_url.clone(),
+            strict,
+            session_max_lifetime,
+            binding,
+        }
+    }
+
+    fn read_mailer<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+        environment: &Environment,
+        urls: &ApiURLs,
+    ) -> MailerConfig {
+        let default_transport = match environment {
+            Environment::Development => EmailTransportKind::Console,
+            Environment::Production => EmailTransportKind::Smtp,
+        };
+        let transport = reader.parse_optional(
+            "EMAIL_TRANSPORT",
+            default_transport,
+            "one of smtp, console or http-sendgrid",
+        );
+        let smtp = transport == EmailTransportKind::Smtp;
+        let port = if smtp {
+            reader.parse_required("EMAIL_PORT", "a port number")
+        } else {
+            reader.parse_optional("EMAIL_PORT", DEFAULT_EMAIL_PORT, "a port number")
+        };
+        let password = if smtp {
+            reader.required("EMAIL_PASSWORD")
+        } else {
+            reader.optional("EMAIL_PASSWORD", "")
+        };
+        let sendgrid_api_key = if transport == EmailTransportKind::SendGrid {
+            reader.required("SENDGRID_API_KEY")
+        } else {
+            reader.optional("SENDGRID_API_KEY", "")
+        };
+        let max_attempts = reader.parse_optional(
+            "EMAIL_MAX_ATTEMPTS",
+            DEFAULT_EMAIL_MAX_ATTEMPTS,
+            "a number of attempts",
+        );
+        if max_attempts <= 0 {
+            reader.problem(
+                "EMAIL_MAX_ATTEMPTS",
+                format!(
+                    "must be a positive number of attempts, got {}",
+                    max_attempts
+                ),
+            );
+        }
+
+        MailerConfig {
+            transport,
+            host: reader.required_in_production(environment, "EMAIL_HOST", || {
+                "smtp.mailtrap.io".to_string()
+            }),
+            port,
+            user: reader.required("EMAIL_USER"),
+            password: Secret::new(password),
+            sendgrid_url: reader.optional("SENDGRID_API_URL", DEFAULT_SENDGRID_URL),
+            sendgrid_api_key: Secret::new(sendgrid_api_key),
+            company_name: reader.optional("COMPANY_NAME", "Your Company"),
+            frontend_url: urls.frontend_url.clone(),
+            max_attempts,
+        }
+    }
+
+    fn read_oauth<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+        environment: &Environment,
+        urls: &ApiURLs,
+        enabled: bool,
+    ) -> OAuthConfig {
+        // Only the auth controllers sign in with the providers
+        let state_secret = if enabled {
+            reader.required_in_production(environment, "OAUTH_STATE_SECRET", || {
+                Uuid::new_v4().to_string()
+            })
+        } else {
+            reader.optional("OAUTH_STATE_SECRET", "")
+        };
+        let mut setting = |name: &str| {
+            if enabled {
+                reader.required(name)
+            } else {
+                reader.optional(name, "")
+            }
+        };
+        let mut client = |id: &str, secret: &str| OAuthClientConfig {
+            client_id: setting(id),
+            client_secret: Secret::new(setting(secret)),
+        };
+        let google = client("GOOGLE_CLIENT_ID", "GOOGLE_CLIENT_SECRET");
+        let facebook = client("FACEBOOK_CLIENT_ID", "FACEBOOK_CLIENT_SECRET");
+        let github = client("GITHUB_CLIENT_ID", "GITHUB_CLIENT_SECRET");
+        let success_redirect = reader.optional(
+            "OAUTH_SUCCESS_REDIRECT",
+            &format!("{}/auth/callback", urls.frontend_url),
+        );
+        let error_redirect = reader.optional(
+            "OAUTH_ERROR_REDIRECT",
+            &format!("{}/auth/error", urls.frontend_url),
+        );
+        let link_redirect = reader.optional(
+            "OAUTH_LINK_REDIRECT",
+            &format!("{}/settings/connections", urls.frontend_url),
+        );
+
+        OAuthConfig {
+            backend_url: urls.backend_url.clone(),
+            state_secret: Secret::new(state_secret),
+            success_redirect,
+            error_redirect,
+            link_redirect,
+            google,
+            facebook,
+            github,
+        }
+    }
+
+    fn read_object_storage<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+        environment: &Environment,
+        urls: &ApiURLs,
+        enabled: bool,
+    ) -> ObjectStorageConfig {
+        // Development without S3 settings keeps uploads on disk instead of failing
+        let default_backend = match environment {
+            Environment::Development if reader.get("OBJECT_STORAGE_HOST").is_none() => {
+                ObjectStorageBackend::Filesystem
+            }
+            _ => ObjectStorageBackend::S3,
+        };
+        let backend = reader.parse_optional(
+            "OBJECT_STORAGE_BACKEND",
+            default_backend,
+            "one of s3 or filesystem",
+        );
+        // Only GraphQL instances build the storage client
+        let s3 = enabled && backend == ObjectStorageBackend::S3;
+        let mut s3_setting = |name: &str| {
+            if s3 {
+                reader.required(name)
+            } else {
+                reader.optional(name, "")
+            }
+        };
+        let host = s3_setting("OBJECT_STORAGE_HOST");
+        let access_key = s3_setting("OBJECT_STORAGE_ACCESS_KEY");
+        let secret_key = s3_setting("OBJECT_STORAGE_SECRET_KEY");
+        let bucket = s3_setting("OBJECT_STORAGE_BUCKET");
+        let region = s3_setting("OBJECT_STORAGE_REGION");
+        let namespace = if enabled {
+            reader.required_in_production(environment, "OBJECT_STORAGE_NAMESPACE", || {
+                Uuid::new_v4().to_string()
+            })
+        } else {
+            reader.optional("OBJECT_STORAGE_NAMESPACE", "")
+        };
+        let namespace = reader
+            .parse(
+                "OBJECT_STORAGE_NAMESPACE",
+                Some(namespace).filter(|namespace| !namespace.is_empty()),
+                "a UUID",
+            )
+            .unwrap_or_default();
+        let upload_allowed_types = reader
+            .get("UPLOAD_ALLOWED_TYPES")
+            .map(|types| {
+                types
+                    .split(',')
+                    .map(|content_type| content_type.trim().to_lowercase())
+                    .filter(|content_type| !content_type.is_empty())
+                    .collect::<Vec<String>>()
+            })
+            .filter(|types| !types.is_empty())
+            .unwrap_or_else(|| DEFAULT_UPLOAD_ALLOWED_TYPES.map(str::to_string).to_vec());
+        if let Some(content_type) = upload_allowed_types
+            .iter()
+            .find(|content_type| content_type.split('/').count() != 2)
+        {
+            reader.problem(
+                "UPLOAD_ALLOWED_TYPES",
+                format!(
+                    "must be comma separated MIME types, got \"{}\"",
+                    content_type
+                ),
+            );
+        }
+
+        ObjectStorageConfig {
+            backend,
+            local_path: PathBuf::from(reader.optional("LOCAL_STORAGE_PATH", "./uploads")),
+            local_url: format!("{}{}", urls.backend_url.trim_end_matches('/'), UPLOADS_PATH),
+            host,
+            access_key,
+            secret_key: Secret::new(secret_key),
+            bucket,
+            region,
+            namespace,
+            multipart_threshold: reader.parse_optional(
+                "OBJECT_STORAGE_MULTIPART_THRESHOLD",
+                DEFAULT_MULTIPART_THRESHOLD,
+                "a number of bytes",
+            ),
+            public: reader.parse_optional("OBJECT_STORAGE_PUBLIC", true, "true or false"),
+            deduplicate: reader.parse_optional("OBJECT_STORAGE_DEDUPLICATE", true, "true or false"),
+            retry_attempts: reader.parse_optional(
+                "OBJECT_STORAGE_RETRY_ATTEMPTS",
+                DEFAULT_STORAGE_RETRY_ATTEMPTS,
+                "a number of attempts",
+            ),
+            retry_delay_ms: reader.parse_optional(
+                "OBJECT_STORAGE_RETRY_DELAY_MS",
+                DEFAULT_STORAGE_RETRY_DELAY_MS,
+                "a number of milliseconds",
+            ),
+            breaker_threshold: reader.parse_optional(
+                "OBJECT_STORAGE_BREAKER_THRESHOLD",
+                DEFAULT_STORAGE_BREAKER_THRESHOLD,
+                "a number of failures",
+            ),
+            breaker_cooldown_seconds: reader.parse_optional(
+                "OBJECT_STORAGE_BREAKER_COOLDOWN_SECONDS",
+                DEFAULT_STORAGE_BREAKER_COOLDOWN_SECONDS,
+                "a number of seconds",
+            ),
+            user_quota_bytes: reader.parse_optional(
+                "USER_STORAGE_QUOTA_BYTES",
+                0,
+                "a number of bytes",
+            ),
+            upload_allowed_types,
+        }
+    }
+
+    /// Webhooks are off without `WEBHOOK_URLS`, the secret is only required with them.
+    fn read_webhooks<F: Fn(&str) -> Option<String>>(reader: &mut EnvReader<F>) -> WebhooksConfig {
+        let urls = reader
+            .optional("WEBHOOK_URLS", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+        if let Some(url) = urls
+            .iter()
+            .find(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            reader.problem(
+                "WEBHOOK_URLS",
+                format!("must be comma separated http(s) URLs, got \"{}\"", url),
+            );
+        }
+        let secret = if urls.is_empty() {
+            reader.optional("WEBHOOK_SECRET", "")
+        } else {
+            reader.required("WEBHOOK_SECRET")
+        };
+
+        WebhooksConfig {
+            urls,
+            secret: Secret::new(secret),
+        }
+    }
+
+    /// The secret is only required once a provider is picked, the verify URL defaults
+    /// to the provider's own endpoint.
+    fn read_captcha<F: Fn(&str) -> Option<String>>(reader: &mut EnvReader<F>) -> CaptchaConfig {
+        let provider = reader.parse_optional(
+            "CAPTCHA_PROVIDER",
+            CaptchaProviderKind::None,
+            "one of none, turnstile or recaptcha",
+        );
+        let (secret, default_url) = match provider {
+            CaptchaProviderKind::None => (reader.optional("CAPTCHA_SECRET", ""), ""),
+            CaptchaProviderKind::Turnstile => {
+                (reader.required("CAPTCHA_SECRET"), DEFAULT_TURNSTILE_URL)
+            }
+            CaptchaProviderKind::Recaptcha => {
+                (reader.required("CAPTCHA_SECRET"), DEFAULT_RECAPTCHA_URL)
+            }
+        };
+        let score_threshold = reader.parse_optional(
+            "CAPTCHA_SCORE_THRESHOLD",
+            DEFAULT_CAPTCHA_SCORE_THRESHOLD,
+            "a number between 0 and 1",
+        );
+        if !(0.0..=1.0).contains(&score_threshold) {
+            reader.problem(
+                "CAPTCHA_SCORE_THRESHOLD",
+                format!("must be a number between 0 and 1, got {}", score_threshold),
+            );
+        }
+
+        CaptchaConfig {
+            provider,
+            secret: Secret::new(secret),
+            verify_url: reader.optional("CAPTCHA_VERIFY_URL", default_url),
+            score_threshold,
+            timeout_ms: reader.parse_optional(
+                "CAPTCHA_TIMEOUT_MS",
+                DEFAULT_CAPTCHA_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+            fail_open: reader.parse_optional("CAPTCHA_FAIL_OPEN", false, "true or false"),
+        }
+    }
+
+    /// An empty list turns the aliasing off, only an unset variable keeps the defaults.
+    fn read_email_alias_domains<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> Vec<String> {
+        let Some(domains) = (reader.lookup)("EMAIL_ALIAS_DOMAINS") else {
+            return DEFAULT_EMAIL_ALIAS_DOMAINS.map(str::to_string).to_vec();
+        };
+        let domains = domains
+            .split(',')
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect::<Vec<String>>();
+        if let Some(domain) = domains
+            .iter()
+            .find(|domain| domain.contains('@') || !domain.contains('.'))
+        {
+            reader.problem(
+                "EMAIL_ALIAS_DOMAINS",
+                format!("must be comma separated domains, got \"{}\"", domain),
+            );
+        }
+
+        domains
+    }
+
+    /// An empty list only leaves the usernames admins reserve, an unset variable keeps
+    /// the defaults.
+    fn read_reserved_usernames<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> Vec<String> {
+        let Some(usernames) = (reader.lookup)("RESERVED_USERNAMES") else {
+            return DEFAULT_RESERVED_USERNAMES.map(str::to_string).to_vec();
+        };
+        let usernames = usernames
+            .split(',')
+            .map(|username| username.trim().to_lowercase())
+            .filter(|username| !username.is_empty())
+            .collect::<Vec<String>>();
+        if let Some(username) = usernames
+            .iter()
+            .find(|username| username.chars().any(char::is_whitespace))
+        {
+            reader.problem(
+                "RESERVED_USERNAMES",
+                format!("must be comma separated usernames, got \"{}\"", username),
+            );
+        }
+
+        usernames
+    }
+
+    fn read_database<F: Fn(&str) -> Option<String>>(reader: &mut EnvReader<F>) -> DatabaseConfig {
+        DatabaseConfig {
+            url: Secret::new(reader.required("DATABASE_URL")),
+            read_url: reader.get("DATABASE_READ_URL").map(Secret::new),
+            pool: Self::read_database_pool(reader),
+        }
+    }
+
+    fn read_cache<F: Fn(&str) -> Option<String>>(reader: &mut EnvReader<F>) -> CacheConfig {
+        let url = reader.required("REDIS_URL");
+        if !url.is_empty() {
+            if let Err(e) = url.as_str().into_connection_info() {
+                reader.problem("REDIS_URL", format!("must be a valid Redis URL, {}", e));
+            }
+        }
+        let pool_size = reader.parse_optional(
+            "REDIS_POOL_SIZE",
+            DEFAULT_REDIS_POOL_SIZE,
+            "a number of connections",
+        );
+        if pool_size == 0 {
+            reader.problem(
+                "REDIS_POOL_SIZE",
+                "must be a positive number of connections, got 0".to_string(),
+            );
+        }
+
+        CacheConfig {
+            url: Secret::new(url),
+            pool_size,
+            connect_timeout_ms: reader.parse_optional(
+                "REDIS_CONNECT_TIMEOUT_MS",
+                DEFAULT_REDIS_CONNECT_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+            response_timeout_ms: reader.parse_optional(
+                "REDIS_RESPONSE_TIMEOUT_MS",
+                DEFAULT_REDIS_RESPONSE_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+            ttl: reader.parse_optional("CACHE_TTL", DEFAULT_CACHE_TTL, "a number of seconds"),
+        }
+    }
+
+    fn read_graphql_limits<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> GraphQLLimits {
+        let mut limit = |name: &str, default: usize| {
+            let value = reader.parse_optional(name, default, "a positive number");
+            if value == 0 {
+                reader.problem(name, "must be a positive number, got 0".to_string());
+            }
+            value
+        };
+
+        GraphQLLimits {
+            max_depth: limit("GRAPHQL_MAX_DEPTH", DEFAULT_GRAPHQL_MAX_DEPTH),
+            max_complexity: limit("GRAPHQL_MAX_COMPLEXITY", DEFAULT_GRAPHQL_MAX_COMPLEXITY),
+        }
+    }
+
+    fn read_password_hash<F: Fn(&str) -> Option<String>>(reader: &mut EnvReader<F>) -> Params {
+        let memory = reader.parse_optional(
+            "PASSWORD_HASH_MEMORY",
+            Params::DEFAULT_M_COST,
+            "a number of KiB",
+        );
+        let iterations = reader.parse_optional(
+            "PASSWORD_HASH_ITERATIONS",
+            Params::DEFAULT_T_COST,
+            "a number of passes",
+        );
+        let parallelism = reader.parse_optional(
+            "PASSWORD_HASH_PARALLELISM",
+            Params::DEFAULT_P_COST,
+            "a number of lanes",
+        );
+
+        Params::new(memory, iterations, parallelism, None).unwrap_or_else(|e| {
+            let name = match e {
+                argon2::Error::TimeTooSmall => "PASSWORD_HASH_ITERATIONS",
+                argon2::Error::ThreadsTooFew | argon2::Error::ThreadsTooMany => {
+                    "PASSWORD_HASH_PARALLELISM"
+                }
+                _ => "PASSWORD_HASH_MEMORY",
+            };
+            reader.problem(name, format!("must be valid argon2 parameters, {}", e));
+            Params::default()
+        })
+    }
+
+    fn read_database_pool<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> DatabasePoolConfig {
+        let min_connections = reader.parse_optional(
+            "DATABASE_MIN_CONNECTIONS",
+            DEFAULT_DATABASE_MIN_CONNECTIONS,
+            "a number of connections",
+        );
+        let max_connections = reader.parse_optional(
+            "DATABASE_MAX_CONNECTIONS",
+            DEFAULT_DATABASE_MAX_CONNECTIONS,
+            "a number of connections",
+        );
+        if max_connections == 0 {
+            reader.problem(
+                "DATABASE_MAX_CONNECTIONS",
+                "must be a positive number of connections, got 0".to_string(),
+            );
+        }
+        if min_connections > max_connections {
+            reader.problem(
+                "DATABASE_MIN_CONNECTIONS",
+                format!(
+                    "must be at most DATABASE_MAX_CONNECTIONS ({}), got {}",
+                    max_connections, min_connections
+                ),
+            );
+        }
+
+        DatabasePoolConfig {
+            min_connections,
+            max_connections,
+        }
+    }
+
+    fn read_unconfirmed_expiry<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> UnconfirmedExpiryConfig {
+        let days = reader.parse_optional(
+            "UNCONFIRMED_ACCOUNT_EXPIRY_DAYS",
+            DEFAULT_UNCONFIRMED_EXPIRY_DAYS,
+            "a number of days",
+        );
+        if days <= 0 {
+            reader.problem(
+                "UNCONFIRMED_ACCOUNT_EXPIRY_DAYS",
+                format!("must be a positive number of days, got {}", days),
+            );
+        }
+        let interval_seconds = reader.parse_optional(
+            "UNCONFIRMED_ACCOUNT_EXPIRY_INTERVAL",
+            DEFAULT_UNCONFIRMED_EXPIRY_INTERVAL,
+            "a number of seconds",
+        );
+        if interval_seconds == 0 {
+            reader.problem(
+                "UNCONFIRMED_ACCOUNT_EXPIRY_INTERVAL",
+                "must be a positive number of seconds, got 0".to_string(),
+            );
+        }
+
+        UnconfirmedExpiryConfig {
+            days,
+            interval_seconds,
+        }
+    }
+
+    fn read_http_client<F: Fn(&str) -> Option<String>>(
+        reader: &mut EnvReader<F>,
+    ) -> HttpClientConfig {
+        let proxy = reader.get("HTTP_CLIENT_PROXY");
+        if let Some(proxy) = proxy
+            .as_deref()
+            .filter(|proxy| reqwest::Proxy::https(*proxy).is_err())
+        {
+            reader.problem(
+                "HTTP_CLIENT_PROXY",
+                format!("must be a proxy URL, got \"{}\"", proxy),
+            );
+        }
+
+        HttpClientConfig {
+            connect_timeout_ms: reader.parse_optional(
+                "HTTP_CLIENT_CONNECT_TIMEOUT_MS",
+                DEFAULT_HTTP_CONNECT_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+            timeout_ms: reader.parse_optional(
+                "HTTP_CLIENT_TIMEOUT_MS",
+                DEFAULT_HTTP_TIMEOUT_MS,
+                "a number of milliseconds",
+            ),
+            proxy,
+        }
+    }
+}
@@ -4,26 +4,335 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
+use std::{
+    collections::HashSet,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use anyhow::Result;
-use sea_orm::DatabaseConnection;
+use futures::future::try_join_all;
+use migrations::{Migrator, MigratorTrait};
+use sea_orm::{
+    ConnectOptions, ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbBackend, DbErr,
+    ExecResult, QueryResult, Statement, TransactionTrait,
+};
+use secrecy::ExposeSecret;
+
+use crate::common::ServiceError;
+
+use super::{DatabaseConfig, DatabasePoolConfig, Metrics};
+
+pub type TransactionFuture<'c, T> =
+    Pin<Box<dyn Future<Output = Result<T, ServiceError>> + Send + 'c>>;
+
+/// Where a service's queries run, chosen by the caller: the primary pool, the read
+/// replica, or an open transaction. Passed to SeaORM like any other connection.
+#[derive(Clone, Copy)]
+pub enum DbSession<'a> {
+    Pooled(&'a Database),
+    /// Reads that tolerate replica lag, see `Database::get_read_connection`.
+    ReadOnly(&'a Database),
+    /// Every query, reads included, runs inside the transaction.
+    Tx(&'a Database, &'a DatabaseTransaction),
+}
+
+impl<'a> DbSession<'a> {
+    /// The pools behind the session, for services still taking a `Database` and for
+    /// work that outlives it. Queries on them never see an open transaction.
+    pub fn database(&self) -> &'a Database {
+        match *self {
+            Self::Pooled(db) | Self::ReadOnly(db) | Self::Tx(db, _) => db,
+        }
+    }
+
+    /// Sends reads of a pooled session to the replica, a transaction keeps them.
+    pub fn read_only(&self) -> DbSession<'a> {
+        match *self {
+            Self::Pooled(db) => Self::ReadOnly(db),
+            session => session,
+        }
+    }
+
+    /// A savepoint inside a transaction, a new transaction on the primary otherwise.
+    pub async fn begin(&self) -> Result<DatabaseTransaction, DbErr> {
+        match self {
+            Self::Pooled(db) | Self::ReadOnly(db) => db.begin().await,
+            Self::Tx(_, txn) => txn.begin().await,
+        }
+    }
+
+    /// Runs `f` on a `Tx` session, committed when it returns `Ok` and rolled back
+    /// otherwise. Nested in a transaction it only commits or rolls back a savepoint.
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T, ServiceError>
+    where
+        F: for<'c> FnOnce(&'c DbSession<'c>) -> TransactionFuture<'c, T> + Send,
+        T: Send,
+    {
+        let db = self.database();
+        let txn = self.begin().await?;
+        db.record_write();
+        let result = f(&DbSession::Tx(db, &txn)).await;
+
+        match result {
+            Ok(value) => {
+                txn.commit().await?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(rollback_error) = txn.rollback().await {
+                    tracing::warn!("Failed to roll back transaction: {:?}", rollback_error);
+                }
+
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ConnectionTrait for DbSession<'_> {
+    fn get_database_backend(&self) -> DbBackend {
+        match self {
+            Self::Pooled(db) => db.get_connection().get_database_backend(),
+            Self::ReadOnly(db) => db.get_read_connection().get_database_backend(),
+            Self::Tx(_, txn) => txn.get_database_backend(),
+        }
+    }
+
+    async fn execute(&self, stmt: Statement) -> Result<ExecResult, DbErr> {
+        match self {
+            Self::Pooled(db) => db.get_connection().execute(stmt).await,
+            Self::ReadOnly(db) => db.get_read_connection().execute(stmt).await,
+            Self::Tx(_, txn) => txn.execute(stmt).await,
+        }
+    }
+
+    async fn execute_unprepared(&self, sql: &str) -> Result<ExecResult, DbErr> {
+        match self {
+            Self::Pooled(db) => db.get_connection().execute_unprepared(sql).await,
+            Self::ReadOnly(db) => db.get_read_connection().execute_unprepared(sql).await,
+            Self::Tx(_, txn) => txn.execute_unprepared(sql).await,
+        }
+    }
+
+    async fn query_one(&self, stmt: Statement) -> Result<Option<QueryResult>, DbErr> {
+        match self {
+            Self::Pooled(db) => db.get_connection().query_one(stmt).await,
+            Self::ReadOnly(db) => db.get_read_connection().query_one(stmt).await,
+            Self::Tx(_, txn) => txn.query_one(stmt).await,
+        }
+    }
+
+    async fn query_all(&self, stmt: Statement) -> Result<Vec<QueryResult>, DbErr> {
+        match self {
+            Self::Pooled(db) => db.get_connection().query_all(stmt).await,
+            Self::ReadOnly(db) => db.get_read_connection().query_all(stmt).await,
+            Self::Tx(_, txn) => txn.query_all(stmt).await,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Database {
     connection: DatabaseConnection,
+    read_connection: Option<DatabaseConnection>,
+    // Only request scoped copies track writes, see `for_request`
+    wrote: Option<Arc<AtomicBool>>,
+    min_connections: u32,
+    metrics: Option<Metrics>,
 }
 
 impl Database {
-    pub async fn new() -> Result<Self> {
-        let database_url =
-            env::var("DATABASE_URL").expect("Missing the DATABASE_URL environment variable.");
-        let connection = sea_orm::Database::connect(&database_url).await?;
+    /// Reads go to the replica when one is configured, everything else to the primary.
+    pub async fn new(config: &DatabaseConfig) -> Result<Self> {
+        Self::connect_with_pool(
+            config.url.expose_secret(),
+            config
+                .read_url
+                .as_ref()
+                .map(|url| url.expose_secret().as_str()),
+            &config.pool,
+        )
+        .await
+    }
+
+    pub async fn connect(database_url: &str, read_url: Option<&str>) -> Result<Self> {
+        Self::connect_with_pool(database_url, read_url, &DatabasePoolConfig::default()).await
+    }
+
+    /// Both pools are sized by `pool` and keep its minimum open, see `Database::warm_up`.
+    pub async fn connect_with_pool(
+        database_url: &str,
+        read_url: Option<&str>,
+        pool: &DatabasePoolConfig,
+    ) -> Result<Self> {
+        let min_connections = pool.min_connections;
+        let options = |url: &str| {
+            let mut options = ConnectOptions::new(url);
+            options
+                .min_connections(min_connections)
+                .max_connections(pool.max_connections);
+            options
+        };
+        let connection = sea_orm::Database::connect(options(database_url)).await?;
+        let read_connection = match read_url {
+            Some(read_url) => Some(sea_orm::Database::connect(options(read_url)).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            connection,
+            read_connection,
+            wrote: None,
+            min_connections,
+            metrics: None,
+        })
+    }
 
-        Ok(Self { connection })
+    /// Opens the minimum connections of each pool at once, so the first requests do not
+    /// pay for the connection setup.
+    pub async fn warm_up(&self) -> Result<(), DbErr> {
+        let connections = [Some(&self.connection), self.read_connection.as_ref()];
+
+        for connection in connections.into_iter().flatten() {
+            // Open transactions hold their connections, so each one opens a new one
+            let transactions =
+                try_join_all((0..self.min_connections).map(|_| connection.begin())).await?;
+
+            for transaction in transactions {
+                transaction.rollback().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts `schema` first in the search path of every pooled connection, unqualified
+    /// tables then resolve to it while extensions installed in `public` stay visible.
+    pub async fn connect_with_schema(database_url: &str, schema: &str) -> Result<Self> {
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        let database_url = format!(
+            "{}{}options[search_path]={},public",
+            database_url, separator, schema
+        );
+        Self::connect(&database_url, None).await
+    }
+
+    /// Copy sharing the pools whose reads stay on the primary once it records a write,
+    /// so a request never reads from a replica that has not caught up with it yet.
+    pub fn for_request(&self) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            read_connection: self.read_connection.clone(),
+            wrote: Some(Arc::new(AtomicBool::new(false))),
+            min_connections: self.min_connections,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    pub fn record_write(&self) {
+        if let Some(wrote) = &self.wrote {
+            wrote.store(true, Ordering::Release);
+        }
+    }
+
+    /// Checks a connection out of the primary pool for a transaction, the wait is
+    /// recorded once `set_metrics` was called.
+    pub async fn begin(&self) -> Result<DatabaseTransaction, DbErr> {
+        let start = Instant::now();
+        let transaction = self.connection.begin().await?;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_database_acquire(start.elapsed().as_secs_f64());
+        }
+
+        Ok(transaction)
     }
 
     pub fn get_connection(&self) -> &DatabaseConnection {
         &self.connection
     }
+
+    /// Only for reads that tolerate replica lag, falls back to the primary without a
+    /// replica or after a write in the same request.
+    pub fn get_read_connection(&self) -> &DatabaseConnection {
+        let wrote = self
+            .wrote
+            .as_ref()
+            .is_some_and(|wrote| wrote.load(Ordering::Acquire));
+
+        match &self.read_connection {
+            Some(read_connection) if !wrote => read_connection,
+            _ => &self.connection,
+        }
+    }
+
+    /// The default session of services, on the primary pool.
+    pub fn session(&self) -> DbSession<'_> {
+        DbSession::Pooled(self)
+    }
+
+    /// Runs `f` in a transaction on the primary, see `DbSession::transaction`. Side
+    /// effects outside the database belong after it, so they only happen once the
+    /// changes are in.
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T, ServiceError>
+    where
+        F: for<'c> FnOnce(&'c DbSession<'c>) -> TransactionFuture<'c, T> + Send,
+        T: Send,
+    {
+        self.session().transaction(f).await
+    }
+
+    pub fn has_read_replica(&self) -> bool {
+        self.read_connection.is_some()
+    }
+
+    pub fn set_metrics(&mut self, metrics: &Metrics) {
+        self.metrics = Some(metrics.clone());
+    }
+
+    /// Primary connection only, the replica has its own callback.
+    pub fn set_metric_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&sea_orm::metric::Info<'_>) + Send + Sync + 'static,
+    {
+        self.connection.set_metric_callback(callback);
+    }
+
+    pub fn set_read_metric_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&sea_orm::metric::Info<'_>) + Send + Sync + 'static,
+    {
+        if let Some(read_connection) = &mut self.read_connection {
+            read_connection.set_metric_callback(callback);
+        }
+    }
+
+    /// Names of the migrations missing from the primary, in the order they would run.
+    pub async fn pending_migrations(&self) -> Result<Vec<String>> {
+        let applied = Migrator::get_applied_migrations(&self.connection)
+            .await?
+            .iter()
+            .map(|migration| migration.name().to_string())
+            .collect::<HashSet<_>>();
+
+        Ok(Migrator::migrations()
+            .iter()
+            .map(|migration| migration.name().to_string())
+            .filter(|name| !applied.contains(name))
+            .collect())
+    }
+
+    /// Applies every pending migration and returns their names.
+    pub async fn run_migrations(&self) -> Result<Vec<String>> {
+        let pending = self.pending_migrations().await?;
+        Migrator::up(&self.connection, None).await?;
+        Ok(pending)
+    }
 }
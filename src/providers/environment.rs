@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
+use std::{env, str::FromStr};
 
 #[derive(Clone, Debug)]
 pub enum Environment {
@@ -15,13 +15,7 @@ pub enum Environment {
 impl Environment {
     pub fn new() -> Self {
         match env::var("ENVIRONMENT") {
-            Ok(environment) => match environment.to_lowercase().as_str() {
-                "development" => Environment::Development,
-                "production" => Environment::Production,
-                "dev" => Environment::Development,
-                "prod" => Environment::Production,
-                _ => panic!("Invalid environment."),
-            },
+            Ok(environment) => environment.parse().expect("Invalid environment."),
             Err(_) => Environment::Development,
         }
     }
@@ -33,3 +27,15 @@ impl Environment {
         }
     }
 }
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "development" | "dev" => Ok(Environment::Development),
+            "production" | "prod" => Ok(Environment::Production),
+            _ => Err(format!("Invalid environment: {}", value)),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use actix_web::rt::task::spawn_blocking;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusoto_s3::CompletedPart;
+use uuid::Uuid;
+
+use crate::common::{InternalCause, ServiceError, INTERNAL_SERVER_ERROR};
+
+use super::{ListedObject, ObjectPage, ObjectStorageClient};
+
+/// Route serving the files of the filesystem backend.
+pub const UPLOADS_PATH: &str = "/api/uploads";
+
+/// Multipart parts wait here until the upload completes, outside every user prefix.
+const MULTIPART_DIR: &str = ".multipart";
+
+/// Stores objects as files under a root directory, the bucket is ignored. Meant for
+/// development, every file is served by `UPLOADS_PATH` and "signed" URLs are plain.
+#[derive(Clone, Debug)]
+pub struct FilesystemStorageClient {
+    root: PathBuf,
+    url: String,
+}
+
+impl FilesystemStorageClient {
+    pub fn new(root: impl Into<PathBuf>, url: &str) -> Self {
+        Self {
+            root: root.into(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Keys are relative paths, anything that could step out of the root is refused.
+    fn path(&self, key: &str) -> Result<PathBuf, ServiceError> {
+        let relative = Path::new(key);
+        let is_safe = !key.is_empty()
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+
+        if !is_safe {
+            return Err(ServiceError::bad_request(
+                "Invalid object key",
+                Some(InternalCause::new(&format!(
+                    "Key leaves the storage root: {}",
+                    key
+                ))),
+            ));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn multipart_path(&self, upload_id: &str) -> Result<PathBuf, ServiceError> {
+        self.path(&format!("{}/{}", MULTIPART_DIR, upload_id))
+    }
+}
+
+fn storage_error(error: io::Error) -> ServiceError {
+    if error.kind() == ErrorKind::NotFound {
+        return ServiceError::not_found("Object not found", Some(error));
+    }
+
+    ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(error))
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, ServiceError> {
+    spawn_blocking(f)
+        .await
+        .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?
+        .map_err(storage_error)
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, contents)
+}
+
+/// Every file under `dir` as a key relative to `root`, skipping the multipart parts.
+fn list_dir(root: &Path, dir: &Path, objects: &mut Vec<ListedObject>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            if entry.file_name() != MULTIPART_DIR {
+                list_dir(root, &path, objects)?;
+            }
+            continue;
+        }
+
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let key = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let last_modified = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+        objects.push(ListedObject { key, last_modified });
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl ObjectStorageClient for FilesystemStorageClient {
+    async fn put_object(
+        &self,
+        _: &str,
+        key: &str,
+        _: &str,
+        _: bool,
+        body: Vec<u8>,
+    ) -> Result<(), ServiceError> {
+        let path = self.path(key)?;
+        blocking(move || write_file(&path, &body)).await
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: bool,
+    ) -> Result<String, ServiceError> {
+        let upload_id = Uuid::new_v4().to_string();
+        let path = self.multipart_path(&upload_id)?;
+        blocking(move || fs::create_dir_all(path)).await?;
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        _: &str,
+        _: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> Result<CompletedPart, ServiceError> {
+        let path = self
+            .multipart_path(upload_id)?
+            .join(part_number.to_string());
+        blocking(move || write_file(&path, &body)).await?;
+        Ok(CompletedPart {
+            e_tag: None,
+            part_number: Some(part_number),
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        _: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), ServiceError> {
+        let path = self.path(key)?;
+        let parts_path = self.multipart_path(upload_id)?;
+        blocking(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut file = fs::File::create(&path)?;
+            for part in parts {
+                let part_number = part.part_number.unwrap_or_default();
+                file.write_all(&fs::read(parts_path.join(part_number.to_string()))?)?;
+            }
+
+            file.flush()?;
+            fs::remove_dir_all(&parts_path)
+        })
+        .await
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        _: &str,
+        _: &str,
+        upload_id: &str,
+    ) -> Result<(), ServiceError> {
+        let path = self.multipart_path(upload_id)?;
+        blocking(move || match fs::remove_dir_all(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    async fn get_object(&self, _: &str, key: &str) -> Result<Vec<u8>, ServiceError> {
+        let path = self.path(key)?;
+        blocking(move || fs::read(path)).await
+    }
+
+    /// Same as S3, deleting a missing object is not an error.
+    async fn delete_object(&self, _: &str, key: &str) -> Result<(), ServiceError> {
+        let path = self.path(key)?;
+        blocking(move || match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    /// The whole listing fits in one page.
+    async fn list_objects(
+        &self,
+        _: &str,
+        prefix: &str,
+        _: Option<String>,
+    ) -> Result<ObjectPage, ServiceError> {
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        let mut objects = blocking(move || {
+            let mut objects = Vec::<ListedObject>::new();
+            list_dir(&root, &root, &mut objects)?;
+            Ok(objects)
+        })
+        .await?;
+        objects.retain(|object| object.key.starts_with(&prefix));
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(ObjectPage {
+            objects,
+            next_token: None,
+        })
+    }
+
+    fn presign_get_object(&self, _: &str, key: &str, _: Duration) -> String {
+        format!("{}/{}", self.url, key)
+    }
+}
+
+/// Served files carry no metadata, the type comes from the extension.
+pub fn content_type_for(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "txt" | "text" | "md" | "csv" | "log" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
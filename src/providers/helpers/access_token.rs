@@ -6,14 +6,21 @@
 
 use chrono::{Duration, Utc};
 use entities::{enums::role_enum::RoleEnum, user::Model};
-use jsonwebtoken::{decode, encode, errors::Result, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, encode, errors::Result, DecodingKey, EncodingKey, Header};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::token_validation::{build_validation, TOKEN_ALGORITHM};
+
+const ACCESS_SUBJECT: &str = "access";
+
 #[derive(Debug, Serialize, Deserialize)]
 struct AccessToken {
     id: i32,
     role: RoleEnum,
+    /// Tokens issued before the claim existed decode as version 0.
+    #[serde(default)]
+    version: i32,
 }
 
 impl From<&Model> for AccessToken {
@@ -21,6 +28,7 @@ impl From<&Model> for AccessToken {
         Self {
             id: model.id.to_owned(),
             role: model.role.to_owned(),
+            version: model.version,
         }
     }
 }
@@ -28,37 +36,94 @@ impl From<&Model> for AccessToken {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     iss: String,
+    aud: String,
     sub: String,
     jti: String,
     iat: i64,
     exp: i64,
     user: AccessToken,
+    /// Admin acting as `user`, only set on impersonation tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    impersonator_id: Option<i32>,
 }
 
 impl Claims {
-    pub fn create_token(user: &Model, secret: &str, exp: i64, iss: &str) -> Result<String> {
+    pub fn create_token(
+        user: &Model,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+    ) -> Result<String> {
+        Self::encode_token(user, None, secret, exp, iss, aud)
+    }
+
+    pub fn create_impersonation_token(
+        user: &Model,
+        impersonator_id: i32,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+    ) -> Result<String> {
+        Self::encode_token(user, Some(impersonator_id), secret, exp, iss, aud)
+    }
+
+    fn encode_token(
+        user: &Model,
+        impersonator_id: Option<i32>,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+    ) -> Result<String> {
         let now = Utc::now();
         let claims = Claims {
-            sub: "access".to_string(),
+            sub: ACCESS_SUBJECT.to_string(),
             iss: iss.to_string(),
+            aud: aud.to_string(),
             iat: now.timestamp(),
             jti: Uuid::new_v4().to_string(),
             exp: (now + Duration::seconds(exp)).timestamp(),
             user: AccessToken::from(user),
+            impersonator_id,
         };
         encode(
-            &Header::default(),
+            &Header::new(TOKEN_ALGORITHM),
             &claims,
             &EncodingKey::from_secret(secret.as_bytes()),
         )
     }
 
-    pub fn decode_token(secret: &str, token: &str) -> Result<(i32, RoleEnum)> {
+    pub fn decode_token(
+        secret: &str,
+        token: &str,
+        iss: &str,
+        aud: &str,
+    ) -> Result<(i32, RoleEnum, i32, Option<i32>)> {
+        let (id, role, version, impersonator_id, _) =
+            Self::decode_token_with_expiry(secret, token, iss, aud)?;
+        Ok((id, role, version, impersonator_id))
+    }
+
+    /// Same as `decode_token`, also returning the `exp` timestamp.
+    pub fn decode_token_with_expiry(
+        secret: &str,
+        token: &str,
+        iss: &str,
+        aud: &str,
+    ) -> Result<(i32, RoleEnum, i32, Option<i32>, i64)> {
         let token_data = decode::<Claims>(
             token,
             &DecodingKey::from_secret(secret.as_bytes()),
-            &Validation::default(),
+            &build_validation(iss, aud, ACCESS_SUBJECT),
         )?;
-        Ok((token_data.claims.user.id, token_data.claims.user.role))
+        Ok((
+            token_data.claims.user.id,
+            token_data.claims.user.role,
+            token_data.claims.user.version,
+            token_data.claims.impersonator_id,
+            token_data.claims.exp,
+        ))
     }
 }
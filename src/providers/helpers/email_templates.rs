@@ -0,0 +1,168 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use serde_json::{Map, Value};
+
+use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+
+pub const CONFIRMATION_TEMPLATE: &str = "confirmation";
+pub const ACCESS_TEMPLATE: &str = "access";
+pub const PASSWORD_RESET_TEMPLATE: &str = "password_reset";
+pub const PASSWORD_CHANGED_TEMPLATE: &str = "password_changed";
+pub const SECURITY_ALERT_TEMPLATE: &str = "security_alert";
+pub const TWO_FACTOR_DISABLED_TEMPLATE: &str = "two_factor_disabled";
+pub const INVITATION_TEMPLATE: &str = "invitation";
+pub const EMAIL_CHANGED_TEMPLATE: &str = "email_changed";
+pub const DATA_EXPORT_TEMPLATE: &str = "data_export";
+pub const NEW_DEVICE_TEMPLATE: &str = "new_device";
+pub const ACCOUNT_EXPIRY_TEMPLATE: &str = "account_expiry";
+
+const DEFAULT_LOCALE: &str = "en";
+
+macro_rules! template {
+    ($locale:literal, $name:literal) => {
+        (
+            concat!($locale, "/", $name),
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/templates/",
+                $locale,
+                "/",
+                $name,
+                ".hbs"
+            )),
+        )
+    };
+}
+
+const TEMPLATES: [(&str, &str); 44] = [
+    template!("en", "confirmation.subject"),
+    template!("en", "confirmation.html"),
+    template!("en", "access.subject"),
+    template!("en", "access.html"),
+    template!("en", "password_reset.subject"),
+    template!("en", "password_reset.html"),
+    template!("en", "password_changed.subject"),
+    template!("en", "password_changed.html"),
+    template!("en", "security_alert.subject"),
+    template!("en", "security_alert.html"),
+    template!("en", "two_factor_disabled.subject"),
+    template!("en", "two_factor_disabled.html"),
+    template!("en", "invitation.subject"),
+    template!("en", "invitation.html"),
+    template!("en", "email_changed.subject"),
+    template!("en", "email_changed.html"),
+    template!("en", "data_export.subject"),
+    template!("en", "data_export.html"),
+    template!("en", "new_device.subject"),
+    template!("en", "new_device.html"),
+    template!("en", "account_expiry.subject"),
+    template!("en", "account_expiry.html"),
+    template!("pt", "confirmation.subject"),
+    template!("pt", "confirmation.html"),
+    template!("pt", "access.subject"),
+    template!("pt", "access.html"),
+    template!("pt", "password_reset.subject"),
+    template!("pt", "password_reset.html"),
+    template!("pt", "password_changed.subject"),
+    template!("pt", "password_changed.html"),
+    template!("pt", "security_alert.subject"),
+    template!("pt", "security_alert.html"),
+    template!("pt", "two_factor_disabled.subject"),
+    template!("pt", "two_factor_disabled.html"),
+    template!("pt", "invitation.subject"),
+    template!("pt", "invitation.html"),
+    template!("pt", "email_changed.subject"),
+    template!("pt", "email_changed.html"),
+    template!("pt", "data_export.subject"),
+    template!("pt", "data_export.html"),
+    template!("pt", "new_device.subject"),
+    template!("pt", "new_device.html"),
+    template!("pt", "account_expiry.subject"),
+    template!("pt", "account_expiry.html"),
+];
+
+pub struct RenderedEmail {
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct EmailTemplates {
+    registry: Arc<Handlebars<'static>>,
+    company_name: String,
+    frontend_url: String,
+}
+
+impl EmailTemplates {
+    pub fn new(company_name: &str, frontend_url: &str) -> Self {
+        let mut registry = Handlebars::new();
+        registry.set_strict_mode(true);
+
+        for (name, template) in TEMPLATES {
+            registry
+                .register_template_string(name, template)
+                .expect("Failed to register the email templates.");
+        }
+
+        Self {
+            registry: Arc::new(registry),
+            company_name: company_name.to_string(),
+            frontend_url: frontend_url.to_string(),
+        }
+    }
+
+    pub fn get_frontend_url(&self) -> &str {
+        &self.frontend_url
+    }
+
+    fn resolve_locale<'a>(&self, locale: &'a str, name: &str) -> &'a str {
+        // "pt-PT" and "pt_BR" both fall back to the "pt" templates
+        let language = locale.split(['-', '_']).next().unwrap_or(DEFAULT_LOCALE);
+
+        if self
+            .registry
+            .has_template(&format!("{}/{}.html", language, name))
+        {
+            language
+        } else {
+            DEFAULT_LOCALE
+        }
+    }
+
+    pub fn render(
+        &self,
+        locale: &str,
+        name: &str,
+        mut data: Map<String, Value>,
+    ) -> Result<RenderedEmail, ServiceError> {
+        let locale = locale.to_lowercase();
+        let locale = self.resolve_locale(&locale, name);
+        data.insert(
+            "company_name".to_string(),
+            Value::String(self.company_name.clone()),
+        );
+        data.insert(
+            "frontend_url".to_string(),
+            Value::String(self.frontend_url.clone()),
+        );
+        let subject = self
+            .registry
+            .render(&format!("{}/{}.subject", locale, name), &data)
+            .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+        let body = self
+            .registry
+            .render(&format!("{}/{}.html", locale, name), &data)
+            .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+        Ok(RenderedEmail {
+            subject: subject.trim().to_string(),
+            body,
+        })
+    }
+}
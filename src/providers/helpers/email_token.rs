@@ -5,16 +5,22 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, errors::Result, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, encode, errors::Result, DecodingKey, EncodingKey, Header};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use entities::user::Model;
 
+use super::token_validation::{build_validation, TOKEN_ALGORITHM};
+
+/// The user id and version, the token id, the `exp` and authentication timestamps, and
+/// the fingerprint of the client the token is bound to, if any.
+pub type RefreshClaims = (i32, i32, String, i64, i64, Option<String>);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmailToken {
     id: i32,
-    version: i16,
+    version: i32,
 }
 
 impl From<&Model> for EmailToken {
@@ -29,11 +35,20 @@ impl From<&Model> for EmailToken {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     iss: String,
+    aud: String,
     sub: String,
     jti: String,
     iat: i64,
     exp: i64,
     user: EmailToken,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// When the session started, refresh tokens carry it across rotations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_time: Option<i64>,
+    /// Client the refresh token was issued to, tokens without it are not bound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_fingerprint: Option<String>,
 }
 
 impl Claims {
@@ -42,35 +57,130 @@ impl Claims {
         secret: &str,
         exp: i64,
         iss: &str,
-        sub: String,
+        aud: &str,
+        sub: &str,
+    ) -> Result<String> {
+        Self::create_token_with_email(user, None, secret, exp, iss, aud, sub)
+    }
+
+    /// Same as `create_token`, also signing the email the token acts on.
+    pub fn create_token_with_email(
+        user: &Model,
+        email: Option<&str>,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+        sub: &str,
+    ) -> Result<String> {
+        Self::encode_claims(user, email, None, None, secret, exp, iss, aud, sub)
+    }
+
+    /// Same as `create_token`, also signing when the session started and the client
+    /// the token is bound to.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_refresh_token(
+        user: &Model,
+        auth_time: i64,
+        client_fingerprint: Option<&str>,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+        sub: &str,
+    ) -> Result<String> {
+        Self::encode_claims(
+            user,
+            None,
+            Some(auth_time),
+            client_fingerprint,
+            secret,
+            exp,
+            iss,
+            aud,
+            sub,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_claims(
+        user: &Model,
+        email: Option<&str>,
+        auth_time: Option<i64>,
+        client_fingerprint: Option<&str>,
+        secret: &str,
+        exp: i64,
+        iss: &str,
+        aud: &str,
+        sub: &str,
     ) -> Result<String> {
         let now = Utc::now();
         let claims = Claims {
-            sub,
+            sub: sub.to_string(),
             iss: iss.to_string(),
+            aud: aud.to_string(),
             jti: Uuid::new_v4().to_string(),
             iat: now.timestamp(),
             exp: (now + Duration::seconds(exp)).timestamp(),
             user: EmailToken::from(user),
+            email: email.map(str::to_string),
+            auth_time,
+            client_fingerprint: client_fingerprint.map(str::to_string),
         };
         encode(
-            &Header::default(),
+            &Header::new(TOKEN_ALGORITHM),
             &claims,
             &EncodingKey::from_secret(secret.as_bytes()),
         )
     }
 
-    pub fn decode_token(secret: &str, token: &str) -> Result<(i32, i16, String, i64)> {
+    pub fn decode_token(
+        secret: &str,
+        token: &str,
+        iss: &str,
+        aud: &str,
+        sub: &str,
+    ) -> Result<(i32, i32, String, i64)> {
+        let claims = Self::decode_claims(secret, token, iss, aud, sub)?;
+        Ok((claims.user.id, claims.user.version, claims.jti, claims.exp))
+    }
+
+    pub fn decode_token_with_email(
+        secret: &str,
+        token: &str,
+        iss: &str,
+        aud: &str,
+        sub: &str,
+    ) -> Result<(i32, i32, Option<String>)> {
+        let claims = Self::decode_claims(secret, token, iss, aud, sub)?;
+        Ok((claims.user.id, claims.user.version, claims.email))
+    }
+
+    /// Tokens issued before `auth_time` existed count from their own issue time.
+    pub fn decode_refresh_token(
+        secret: &str,
+        token: &str,
+        iss: &str,
+        aud: &str,
+        sub: &str,
+    ) -> Result<RefreshClaims> {
+        let claims = Self::decode_claims(secret, token, iss, aud, sub)?;
+        Ok((
+            claims.user.id,
+            claims.user.version,
+            claims.jti,
+            claims.exp,
+            claims.auth_time.unwrap_or(claims.iat),
+            claims.client_fingerprint,
+        ))
+    }
+
+    fn decode_claims(secret: &str, token: &str, iss: &str, aud: &str, sub: &str) -> Result<Self> {
         let token_data = decode::<Claims>(
             token,
             &DecodingKey::from_secret(secret.as_bytes()),
-            &Validation::default(),
+            &build_validation(iss, aud, sub),
         )?;
-        Ok((
-            token_data.claims.user.id,
-            token_data.claims.user.version,
-            token_data.claims.jti,
-            token_data.claims.exp,
-        ))
+        Ok(token_data.claims)
     }
 }
@@ -5,4 +5,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub mod access_token;
+pub mod email_templates;
 pub mod email_token;
+pub mod oauth_state;
+pub mod token_validation;
@@ -0,0 +1,129 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{Duration, Utc};
+use derive_more::Display;
+use rand::Rng;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const KEY_SALT: &[u8] = b"oauth-state";
+const KEY_INFO: &[u8] = b"aes-256-gcm";
+
+/// The PKCE verifier, the state id, the linking user id with the nonce of its browser,
+/// and the `exp` timestamp.
+pub type DecodedState = (String, String, Option<(i32, String)>, i64);
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum StateError {
+    #[display(fmt = "OAuth state is malformed or was tampered with")]
+    Malformed,
+    #[display(fmt = "OAuth state expired")]
+    Expired,
+    #[display(fmt = "OAuth state was issued for another provider")]
+    WrongProvider,
+}
+
+/// Derives the AES-GCM key from the configured secret, so any length of secret works.
+pub fn derive_key(secret: &str) -> LessSafeKey {
+    let prk = Salt::new(HKDF_SHA256, KEY_SALT).extract(secret.as_bytes());
+    let okm = prk
+        .expand(&[KEY_INFO], &AES_256_GCM)
+        .expect("AES-256-GCM keys are within the HKDF output limit");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+/// Contents of the OAuth `state` parameter, encrypted so the PKCE verifier never
+/// travels in the clear and authenticated so it can not be forged. States issued to
+/// link a provider carry the id of the signed in user and the nonce of the browser
+/// that started the link.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    jti: String,
+    provider: String,
+    verifier: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_nonce: Option<String>,
+    exp: i64,
+}
+
+impl Claims {
+    pub fn create_token(
+        key: &LessSafeKey,
+        provider: &str,
+        verifier: &str,
+        link: Option<(i32, &str)>,
+        exp: i64,
+    ) -> Result<String, StateError> {
+        let claims = Claims {
+            jti: Uuid::new_v4().to_string(),
+            provider: provider.to_string(),
+            verifier: verifier.to_string(),
+            user_id: link.map(|(user_id, _)| user_id),
+            link_nonce: link.map(|(_, nonce)| nonce.to_string()),
+            exp: (Utc::now() + Duration::seconds(exp)).timestamp(),
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let mut in_out = serde_json::to_vec(&claims).map_err(|_| StateError::Malformed)?;
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(provider.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| StateError::Malformed)?;
+
+        let mut token = nonce.to_vec();
+        token.extend(in_out);
+        Ok(URL_SAFE_NO_PAD.encode(token))
+    }
+
+    /// Returns the state's contents, see `DecodedState`.
+    pub fn decode_token(
+        key: &LessSafeKey,
+        provider: &str,
+        token: &str,
+    ) -> Result<DecodedState, StateError> {
+        let mut token = URL_SAFE_NO_PAD
+            .decode(token)
+            .map_err(|_| StateError::Malformed)?;
+
+        if token.len() <= NONCE_LEN {
+            return Err(StateError::Malformed);
+        }
+
+        let mut in_out = token.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&token).map_err(|_| StateError::Malformed)?;
+        // The provider is authenticated data, so a state replayed on another callback fails here
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(provider.as_bytes()), &mut in_out)
+            .map_err(|_| StateError::Malformed)?;
+        let claims =
+            serde_json::from_slice::<Claims>(plaintext).map_err(|_| StateError::Malformed)?;
+
+        if claims.provider != provider {
+            return Err(StateError::WrongProvider);
+        }
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(StateError::Expired);
+        }
+
+        let link = match (claims.user_id, claims.link_nonce) {
+            (Some(user_id), Some(nonce)) => Some((user_id, nonce)),
+            (None, None) => None,
+            // A link state is only valid with the nonce of the browser that started it
+            _ => return Err(StateError::Malformed),
+        };
+        Ok((claims.verifier, claims.jti, link, claims.exp))
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use jsonwebtoken::{
+    errors::{Error, ErrorKind},
+    Algorithm, Validation,
+};
+
+pub const TOKEN_ALGORITHM: Algorithm = Algorithm::HS256;
+
+pub fn build_validation(iss: &str, aud: &str, sub: &str) -> Validation {
+    let mut validation = Validation::new(TOKEN_ALGORITHM);
+    validation.set_issuer(&[iss]);
+    validation.set_audience(&[aud]);
+    validation.sub = Some(sub.to_string());
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    validation
+}
+
+pub fn describe_error(error: &Error) -> &'static str {
+    match error.kind() {
+        ErrorKind::InvalidIssuer => "Token issuer does not match",
+        ErrorKind::InvalidAudience => "Token audience does not match",
+        ErrorKind::InvalidSubject => "Token type does not match",
+        ErrorKind::InvalidAlgorithm | ErrorKind::InvalidAlgorithmName => {
+            "Token algorithm is not allowed"
+        }
+        ErrorKind::ExpiredSignature => "Token has expired",
+        ErrorKind::InvalidSignature => "Token signature is invalid",
+        ErrorKind::MissingRequiredClaim(_) => "Token is missing a required claim",
+        _ => "Token is malformed",
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use oauth2::{reqwest::Error as OAuth2RequestError, HttpRequest, HttpResponse};
+use reqwest::{redirect::Policy, Client, Proxy};
+
+use super::HttpClientConfig;
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Client for the calls to the OAuth providers, built on first use. Clones share it, so
+/// the token exchange and the user info fetch reuse the same connection pool.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    config: HttpClientConfig,
+    client: Arc<OnceLock<Client>>,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpClientConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: Arc::default(),
+        }
+    }
+
+    /// Redirects are not followed, a token endpoint must answer itself.
+    pub fn client(&self) -> &Client {
+        self.client.get_or_init(|| {
+            let mut builder = Client::builder()
+                .connect_timeout(Duration::from_millis(self.config.connect_timeout_ms))
+                .timeout(Duration::from_millis(self.config.timeout_ms))
+                .user_agent(USER_AGENT)
+                .redirect(Policy::none());
+
+            if let Some(proxy) = &self.config.proxy {
+                match Proxy::https(proxy) {
+                    Ok(proxy) => builder = builder.proxy(proxy),
+                    Err(e) => tracing::error!("Ignoring invalid HTTP client proxy: {}", e),
+                }
+            }
+
+            builder.build().unwrap_or_else(|e| {
+                tracing::error!("Failed to build the HTTP client: {}", e);
+                Client::default()
+            })
+        })
+    }
+
+    /// Same as oauth2's `async_http_client`, on the shared client.
+    pub async fn oauth2_request(
+        &self,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, OAuth2RequestError<reqwest::Error>> {
+        let client = self.client();
+        let mut builder = client
+            .request(request.method, request.url.as_str())
+            .body(request.body);
+
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+
+        let response = client
+            .execute(builder.build().map_err(OAuth2RequestError::Reqwest)?)
+            .await
+            .map_err(OAuth2RequestError::Reqwest)?;
+        let status_code = response.status();
+        let headers = response.headers().to_owned();
+        let body = response
+            .bytes()
+            .await
+            .map_err(OAuth2RequestError::Reqwest)?;
+        Ok(HttpResponse {
+            status_code,
+            headers,
+            body: body.to_vec(),
+        })
+    }
+}
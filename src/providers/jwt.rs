@@ -4,31 +4,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
+use std::fmt;
 
 use secrecy::{ExposeSecret, Secret};
 use uuid::Uuid;
 
 use entities::{enums::role_enum::RoleEnum, user::Model};
 
-use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+use crate::common::{InternalCause, ServiceError, SOMETHING_WENT_WRONG};
 
 use super::{
-    helpers::{access_token, email_token},
-    Environment,
+    helpers::{access_token, email_token, token_validation},
+    JwtConfig, TokenBinding, TokenConfig,
 };
 
+/// Impersonation tokens last at most ten minutes, whatever the access token time.
+const IMPERSONATION_TIME: i64 = 600;
+
 #[derive(Clone, Debug)]
 struct SingleJwt {
     secret: Secret<String>,
     exp: i64,
 }
 
-impl SingleJwt {
-    fn new(secret: String, exp: i64) -> Self {
+impl From<&TokenConfig> for SingleJwt {
+    fn from(config: &TokenConfig) -> Self {
         Self {
-            secret: Secret::new(secret),
-            exp,
+            secret: config.secret.clone(),
+            exp: config.exp,
         }
     }
 }
@@ -37,14 +40,16 @@ pub enum TokenType {
     Reset,
     Confirmation,
     Refresh,
+    Revert,
 }
 
-impl TokenType {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            TokenType::Reset => "reset".to_string(),
-            TokenType::Confirmation => "confirmation".to_string(),
-            TokenType::Refresh => "refresh".to_string(),
+            TokenType::Reset => write!(f, "reset"),
+            TokenType::Confirmation => write!(f, "confirmation"),
+            TokenType::Refresh => write!(f, "refresh"),
+            TokenType::Revert => write!(f, "revert"),
         }
     }
 }
@@ -55,72 +60,62 @@ pub struct Jwt {
     reset: SingleJwt,
     confirmation: SingleJwt,
     refresh: SingleJwt,
+    revert: SingleJwt,
     refresh_name: Secret<String>,
+    access_cookie_name: Option<String>,
     iss: Uuid,
+    aud: String,
+    strict: bool,
+    session_max_lifetime: i64,
+    binding: TokenBinding,
 }
 
 impl Jwt {
-    pub fn new(environment: &Environment, api_id: &str) -> Self {
-        let jwt_access_secret = env::var("ACCESS_SECRET").unwrap_or_else(|_| match environment {
-            Environment::Development => Uuid::new_v4().to_string(),
-            Environment::Production => {
-                panic!("Missing the JWT_ACCESS_SECRET environment variable.")
-            }
-        });
-        let jwt_refresh_secret = env::var("REFRESH_SECRET").unwrap_or_else(|_| match environment {
-            Environment::Development => Uuid::new_v4().to_string(),
-            Environment::Production => {
-                panic!("Missing the JWT_REFRESH_SECRET environment variable.")
-            }
-        });
-        let jwt_confirmation_secret =
-            env::var("CONFIRMATION_SECRET").unwrap_or_else(|_| match environment {
-                Environment::Development => Uuid::new_v4().to_string(),
-                Environment::Production => {
-                    panic!("Missing the JWT_CONFIRMATION_SECRET environment variable.")
-                }
-            });
-        let jwt_reset_secret = env::var("RESET_SECRET").unwrap_or_else(|_| match environment {
-            Environment::Development => Uuid::new_v4().to_string(),
-            Environment::Production => panic!("Missing the JWT_RESET_SECRET environment variable."),
-        });
-        let jwt_access_expiration = env::var("ACCESS_EXPIRATION")
-            .unwrap_or_else(|_| "600".to_string())
-            .parse::<i64>()
-            .unwrap_or(600);
-        let jwt_refresh_expiration = env::var("REFRESH_EXPIRATION")
-            .unwrap_or_else(|_| "259200".to_string())
-            .parse::<i64>()
-            .unwrap_or(259200);
-        let jwt_confirmation_expiration = env::var("CONFIRMATION_EXPIRATION")
-            .unwrap_or_else(|_| "86400".to_string())
-            .parse::<i64>()
-            .unwrap_or(86400);
-        let jwt_reset_expiration = env::var("RESET_EXPIRATION")
-            .unwrap_or_else(|_| "1800".to_string())
-            .parse::<i64>()
-            .unwrap_or(1800);
-        let refresh_name = env::var("REFRESH_NAME").unwrap_or_else(|_| match environment {
-            Environment::Development => "refresh".to_string(),
-            Environment::Production => panic!("Missing the REFRESH_NAME environment variable."),
-        });
-
+    pub fn new(config: &JwtConfig) -> Self {
         Self {
-            access: SingleJwt::new(jwt_access_secret, jwt_access_expiration),
-            reset: SingleJwt::new(jwt_reset_secret, jwt_reset_expiration),
-            confirmation: SingleJwt::new(jwt_confirmation_secret, jwt_confirmation_expiration),
-            refresh: SingleJwt::new(jwt_refresh_secret, jwt_refresh_expiration),
-            refresh_name: Secret::new(refresh_name),
-            iss: Uuid::parse_str(api_id).unwrap(),
+            access: SingleJwt::from(&config.access),
+            reset: SingleJwt::from(&config.reset),
+            confirmation: SingleJwt::from(&config.confirmation),
+            refresh: SingleJwt::from(&config.refresh),
+            revert: SingleJwt::from(&config.revert),
+            refresh_name: Secret::new(config.refresh_name.clone()),
+            access_cookie_name: config.access_cookie.clone(),
+            iss: config.iss,
+            aud: config.aud.clone(),
+            strict: config.strict,
+            session_max_lifetime: config.session_max_lifetime,
+            binding: config.binding,
         }
     }
 
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
     pub fn generate_access_token(&self, user: &Model) -> Result<String, ServiceError> {
         access_token::Claims::create_token(
             user,
-            &self.access.secret.expose_secret(),
+            self.access.secret.expose_secret(),
             self.access.exp,
             &self.iss.to_string(),
+            &self.aud,
+        )
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
+    }
+
+    /// Access token for an admin acting as `user`, never outliving `IMPERSONATION_TIME`.
+    pub fn generate_impersonation_token(
+        &self,
+        user: &Model,
+        impersonator_id: i32,
+    ) -> Result<String, ServiceError> {
+        access_token::Claims::create_impersonation_token(
+            user,
+            impersonator_id,
+            self.access.secret.expose_secret(),
+            self.get_impersonation_token_time(),
+            &self.iss.to_string(),
+            &self.aud,
         )
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
     }
@@ -133,62 +128,192 @@ impl Jwt {
         email_token::Claims::create_token(
             user,
             match token_type {
-                TokenType::Confirmation => &self.confirmation.secret.expose_secret(),
-                TokenType::Reset => &self.reset.secret.expose_secret(),
-                TokenType::Refresh => &self.refresh.secret.expose_secret(),
+                TokenType::Confirmation => self.confirmation.secret.expose_secret(),
+                TokenType::Reset => self.reset.secret.expose_secret(),
+                TokenType::Refresh => self.refresh.secret.expose_secret(),
+                TokenType::Revert => self.revert.secret.expose_secret(),
             },
             self.confirmation.exp,
             &self.iss.to_string(),
-            token_type.to_string(),
+            &self.aud,
+            &token_type.to_string(),
         )
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
     }
 
-    pub fn verify_access_token(&self, token: &str) -> Result<(i32, RoleEnum), ServiceError> {
-        match access_token::Claims::decode_token(&self.access.secret.expose_secret(), token) {
-            Ok((id, role)) => Ok((id, role)),
-            Err(e) => Err(ServiceError::unauthorized("Invalid token", Some(e))),
-        }
+    /// Refresh token for a session started at `auth_time`, rotations keep it unchanged.
+    pub fn generate_refresh_token(
+        &self,
+        user: &Model,
+        auth_time: i64,
+        client_fingerprint: Option<&str>,
+    ) -> Result<String, ServiceError> {
+        email_token::Claims::create_refresh_token(
+            user,
+            auth_time,
+            client_fingerprint,
+            self.refresh.secret.expose_secret(),
+            self.refresh.exp,
+            &self.iss.to_string(),
+            &self.aud,
+            &TokenType::Refresh.to_string(),
+        )
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
+    }
+
+    /// Returns the user id, role and the impersonator id, if any.
+    pub fn verify_access_token(
+        &self,
+        token: &str,
+    ) -> Result<(i32, RoleEnum, i32, Option<i32>), ServiceError> {
+        access_token::Claims::decode_token(
+            self.access.secret.expose_secret(),
+            token,
+            &self.iss.to_string(),
+            &self.aud,
+        )
+        .map_err(|e| Self::invalid_token(&e))
+    }
+
+    /// Long lived connections need the expiry to drop the user once the token lapses.
+    pub fn verify_access_token_with_expiry(
+        &self,
+        token: &str,
+    ) -> Result<(i32, RoleEnum, i32, Option<i32>, i64), ServiceError> {
+        access_token::Claims::decode_token_with_expiry(
+            self.access.secret.expose_secret(),
+            token,
+            &self.iss.to_string(),
+            &self.aud,
+        )
+        .map_err(|e| Self::invalid_token(&e))
     }
 
     pub fn verify_email_token(
         &self,
         token_type: TokenType,
         token: &str,
-    ) -> Result<(i32, i16, String, i64), ServiceError> {
-        match email_token::Claims::decode_token(
+    ) -> Result<(i32, i32, String, i64), ServiceError> {
+        email_token::Claims::decode_token(
             match token_type {
-                TokenType::Reset => &self.reset.secret.expose_secret(),
-                TokenType::Confirmation => &self.confirmation.secret.expose_secret(),
-                TokenType::Refresh => &self.refresh.secret.expose_secret(),
+                TokenType::Reset => self.reset.secret.expose_secret(),
+                TokenType::Confirmation => self.confirmation.secret.expose_secret(),
+                TokenType::Refresh => self.refresh.secret.expose_secret(),
+                TokenType::Revert => self.revert.secret.expose_secret(),
             },
             token,
-        ) {
-            Ok((id, version, token_id, exp)) => Ok((id, version, token_id, exp)),
-            Err(e) => Err(ServiceError::unauthorized("Invalid token", Some(e))),
-        }
+            &self.iss.to_string(),
+            &self.aud,
+            // The subject holds the token type, so a token of another type is rejected
+            &token_type.to_string(),
+        )
+        .map_err(|e| Self::invalid_token(&e))
+    }
+
+    /// Returns the user id, version, token id, expiry, when the session started and the
+    /// client the token is bound to, if any.
+    pub fn verify_refresh_token(
+        &self,
+        token: &str,
+    ) -> Result<email_token::RefreshClaims, ServiceError> {
+        email_token::Claims::decode_refresh_token(
+            self.refresh.secret.expose_secret(),
+            token,
+            &self.iss.to_string(),
+            &self.aud,
+            &TokenType::Refresh.to_string(),
+        )
+        .map_err(|e| Self::invalid_token(&e))
+    }
+
+    /// Link sent to the address `user` had before an email change, it restores
+    /// `previous_email` until the revert window closes.
+    pub fn generate_revert_token(
+        &self,
+        user: &Model,
+        previous_email: &str,
+    ) -> Result<String, ServiceError> {
+        email_token::Claims::create_token_with_email(
+            user,
+            Some(previous_email),
+            self.revert.secret.expose_secret(),
+            self.revert.exp,
+            &self.iss.to_string(),
+            &self.aud,
+            &TokenType::Revert.to_string(),
+        )
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
+    }
+
+    /// Returns the user id, version and the email the token restores.
+    pub fn verify_revert_token(&self, token: &str) -> Result<(i32, i32, String), ServiceError> {
+        let (id, version, email) = email_token::Claims::decode_token_with_email(
+            self.revert.secret.expose_secret(),
+            token,
+            &self.iss.to_string(),
+            &self.aud,
+            &TokenType::Revert.to_string(),
+        )
+        .map_err(|e| Self::invalid_token(&e))?;
+        let email = email.ok_or_else(|| {
+            ServiceError::unauthorized(
+                "Invalid token",
+                Some(InternalCause::new("Revert token is missing the email")),
+            )
+        })?;
+        Ok((id, version, email))
+    }
+
+    fn invalid_token(error: &jsonwebtoken::errors::Error) -> ServiceError {
+        let cause = token_validation::describe_error(error);
+        tracing::warn!("Token validation failed: {}", cause);
+        ServiceError::unauthorized("Invalid token", Some(InternalCause::new(cause)))
     }
 
     pub fn get_refresh_name(&self) -> &str {
-        &self.refresh_name.expose_secret()
+        self.refresh_name.expose_secret()
+    }
+
+    /// Set when access tokens are also issued in an HTTP only cookie.
+    pub fn get_access_cookie_name(&self) -> Option<&str> {
+        self.access_cookie_name.as_deref()
     }
 
     pub fn get_access_token_time(&self) -> i64 {
         self.access.exp
     }
 
+    pub fn get_impersonation_token_time(&self) -> i64 {
+        self.access.exp.min(IMPERSONATION_TIME)
+    }
+
     pub fn get_email_token_time(&self, token_type: TokenType) -> i64 {
         match token_type {
             TokenType::Reset => self.reset.exp,
             TokenType::Confirmation => self.confirmation.exp,
             TokenType::Refresh => self.refresh.exp,
+            TokenType::Revert => self.revert.exp,
         }
     }
 
-    pub fn generate_auth_tokens(&self, user: &Model) -> Result<(String, String), ServiceError> {
+    /// Seconds a session may be refreshed for, counted from its sign in.
+    pub fn get_session_max_lifetime(&self) -> i64 {
+        self.session_max_lifetime
+    }
+
+    pub fn get_token_binding(&self) -> TokenBinding {
+        self.binding
+    }
+
+    pub fn generate_auth_tokens(
+        &self,
+        user: &Model,
+        auth_time: i64,
+        client_fingerprint: Option<&str>,
+    ) -> Result<(String, String), ServiceError> {
         tracing::trace_span!("Generating authentication tokens", id = %user.id);
         let access_token = self.generate_access_token(user)?;
-        let refresh_token = self.generate_email_token(TokenType::Refresh, user)?;
+        let refresh_token = self.generate_refresh_token(user, auth_time, client_fingerprint)?;
         Ok((access_token, refresh_token))
     }
 }
@@ -0,0 +1,29 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+const LOCK_DURATIONS: [u64; 3] = [300, 900, 3600];
+
+#[derive(Clone, Debug)]
+pub struct Lockout {
+    max_attempts: i64,
+}
+
+impl Lockout {
+    pub fn new(max_attempts: i64) -> Self {
+        Self { max_attempts }
+    }
+
+    pub fn get_max_attempts(&self) -> i64 {
+        self.max_attempts
+    }
+
+    pub fn get_lock_duration(&self, lockouts: i64) -> u64 {
+        let index = usize::try_from(lockouts - 1)
+            .unwrap_or(0)
+            .min(LOCK_DURATIONS.len() - 1);
+        LOCK_DURATIONS[index]
+    }
+}
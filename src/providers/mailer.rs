@@ -4,177 +4,543 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
+use actix_web::rt::time::timeout;
+use anyhow::{anyhow, Result as AnyResult};
+use async_trait::async_trait;
+use chrono::Utc;
 use lettre::{
-    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
-    Tokio1Executor,
+    message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Client;
+use sea_orm::{ActiveModelTrait, ConnectionTrait, Set};
+use secrecy::{ExposeSecret, Secret};
+use serde_json::{json, Map, Value};
+
+use entities::{email_outbox, enums::EmailStatusEnum};
+
+use crate::common::{ServiceError, DEFAULT_LOCALE};
+
+use super::helpers::email_templates::{
+    EmailTemplates, ACCESS_TEMPLATE, ACCOUNT_EXPIRY_TEMPLATE, CONFIRMATION_TEMPLATE,
+    DATA_EXPORT_TEMPLATE, EMAIL_CHANGED_TEMPLATE, INVITATION_TEMPLATE, NEW_DEVICE_TEMPLATE,
+    PASSWORD_CHANGED_TEMPLATE, PASSWORD_RESET_TEMPLATE, SECURITY_ALERT_TEMPLATE,
+    TWO_FACTOR_DISABLED_TEMPLATE,
 };
+use super::{EmailTransportKind, Environment, MailerConfig, Metrics};
+
+// Old emails are dropped first so a long running development server stays bounded
+const MAX_CAPTURED_EMAILS: usize = 500;
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Readiness probes within it reuse the last result instead of reaching the server.
+const VERIFY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+static CAPTURED_EMAILS: OnceLock<Arc<Mutex<Vec<SentEmail>>>> = OnceLock::new();
+
+fn get_captured_emails() -> &'static Arc<Mutex<Vec<SentEmail>>> {
+    CAPTURED_EMAILS.get_or_init(Default::default)
+}
+
+/// Emails sent to `to` through the console transports of this process, oldest first.
+#[cfg(test)]
+pub fn captured_emails(to: &str) -> Vec<SentEmail> {
+    get_captured_emails()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|email| email.to == to)
+        .cloned()
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentEmail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send(&self, from: &str, to: &str, subject: &str, body: &str) -> AnyResult<()>;
+
+    /// Checks the transport can deliver without sending anything, transports without a
+    /// connection to test are always healthy.
+    async fn verify(&self) -> AnyResult<()> {
+        Ok(())
+    }
+}
 
-use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+struct SmtpTransport(AsyncSmtpTransport<Tokio1Executor>);
 
-use super::Environment;
+#[async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, from: &str, to: &str, subject: &str, body: &str) -> AnyResult<()> {
+        let message = Message::builder()
+            .from(from.parse()?)
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_HTML)
+            .body(body.to_string())?;
+        self.0.send(message).await?;
+        Ok(())
+    }
+
+    /// Connects and sends a NOOP, rotated credentials fail here.
+    async fn verify(&self) -> AnyResult<()> {
+        if self.0.test_connection().await? {
+            Ok(())
+        } else {
+            Err(anyhow!("The SMTP server did not accept the connection"))
+        }
+    }
+}
+
+/// Prints every email, keeping the latest ones in memory so tests can inspect them.
+pub struct ConsoleTransport {
+    sent: Arc<Mutex<Vec<SentEmail>>>,
+}
+
+impl ConsoleTransport {
+    /// Captures into the buffer read by [`captured_emails`].
+    pub fn new() -> Self {
+        Self::with_buffer(get_captured_emails().clone())
+    }
+
+    pub fn with_buffer(sent: Arc<Mutex<Vec<SentEmail>>>) -> Self {
+        Self { sent }
+    }
+}
+
+impl Default for ConsoleTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-#[derive(Clone, Debug)]
+#[async_trait]
+impl EmailTransport for ConsoleTransport {
+    async fn send(&self, from: &str, to: &str, subject: &str, body: &str) -> AnyResult<()> {
+        println!("Subject: {}\n\n{}", subject, body);
+        let mut sent = self.sent.lock().unwrap();
+
+        if sent.len() >= MAX_CAPTURED_EMAILS {
+            sent.remove(0);
+        }
+
+        sent.push(SentEmail {
+            from: from.to_string(),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+        });
+        Ok(())
+    }
+}
+
+pub struct SendGridTransport {
+    client: Client,
+    url: String,
+    api_key: Secret<String>,
+}
+
+impl SendGridTransport {
+    pub fn new(url: &str, api_key: Secret<String>) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.to_string(),
+            api_key,
+        }
+    }
+
+    /// Body of the v3 mail send endpoint for a single html email.
+    pub fn request_body(from: &str, to: &str, subject: &str, body: &str) -> Value {
+        json!({
+            "personalizations": [{ "to": [{ "email": to }] }],
+            "from": { "email": from },
+            "subject": subject,
+            "content": [{ "type": "text/html", "value": body }],
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for SendGridTransport {
+    async fn send(&self, from: &str, to: &str, subject: &str, body: &str) -> AnyResult<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(self.api_key.expose_secret())
+            .json(&Self::request_body(from, to, subject, body))
+            .send()
+            .await?;
+        let status = response.status();
+
+        if !status.is_success() {
+            let cause = response.text().await.unwrap_or_default();
+            return Err(anyhow!(ServiceError::internal_server_error(
+                &format!("SendGrid responded with {}", status),
+                Some(cause),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Emails are written to the `email_outbox` table and delivered by the outbox worker,
+/// so they can share the transaction of the operation that triggers them.
+#[derive(Clone)]
 pub struct Mailer {
     email: String,
-    frontend_url: String,
-    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    templates: EmailTemplates,
+    transport: Arc<dyn EmailTransport>,
     environment: Environment,
+    metrics: Metrics,
+    max_attempts: i16,
+    verified: Arc<Mutex<Option<(Instant, bool)>>>,
 }
 
 impl Mailer {
-    pub fn new(environment: &Environment, frontend_url: String) -> Self {
-        let email_host = env::var("EMAIL_HOST").unwrap_or_else(|_| match environment {
-            Environment::Development => "smtp.mailtrap.io".to_string(),
-            Environment::Production => panic!("Missing the EMAIL_HOST environment variable."),
-        });
-        let email_port = env::var("EMAIL_PORT")
-            .expect("Missing the EMAIL_PORT environment variable.")
-            .parse::<u16>()
-            .expect("EMAIL_PORT must be a number.");
-        let email_user =
-            env::var("EMAIL_USER").expect("Missing the EMAIL_USER environment variable.");
-        let email_password =
-            env::var("EMAIL_PASSWORD").expect("Missing the EMAIL_PASSWORD environment variable.");
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&email_host)
-            .unwrap()
-            .port(email_port)
-            .credentials(Credentials::new(email_user.clone(), email_password))
-            .build();
+    pub fn new(environment: &Environment, config: &MailerConfig, metrics: &Metrics) -> Self {
+        match config.transport {
+            EmailTransportKind::Smtp => {
+                let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+                    .unwrap()
+                    .port(config.port)
+                    .credentials(Credentials::new(
+                        config.user.clone(),
+                        config.password.expose_secret().clone(),
+                    ))
+                    .build();
+                Self::with_transport(environment, config, metrics, SmtpTransport(transport))
+            }
+            EmailTransportKind::Console => {
+                Self::with_transport(environment, config, metrics, ConsoleTransport::new())
+            }
+            EmailTransportKind::SendGrid => Self::with_transport(
+                environment,
+                config,
+                metrics,
+                SendGridTransport::new(&config.sendgrid_url, config.sendgrid_api_key.clone()),
+            ),
+        }
+    }
 
+    pub fn with_transport(
+        environment: &Environment,
+        config: &MailerConfig,
+        metrics: &Metrics,
+        transport: impl EmailTransport + 'static,
+    ) -> Self {
         Self {
             environment: environment.clone(),
-            email: email_user,
-            frontend_url,
-            mailer,
+            email: config.user.clone(),
+            templates: EmailTemplates::new(&config.company_name, &config.frontend_url),
+            transport: Arc::new(transport),
+            metrics: metrics.clone(),
+            max_attempts: config.max_attempts,
+            verified: Default::default(),
         }
     }
 
-    fn send_email(&self, to: String, subject: String, body: String) -> Result<(), ServiceError> {
-        if !self.environment.is_production() {
-            println!("Subject: {}\n\n{}", subject, body);
-            return Ok(());
+    /// Deliveries the outbox tries before giving up on an email.
+    pub fn max_attempts(&self) -> i16 {
+        self.max_attempts
+    }
+
+    /// Whether the transport accepts connections, checked at most once a minute and
+    /// given up on after two seconds.
+    pub async fn verify(&self) -> bool {
+        if let Some((checked_at, healthy)) = *self.verified.lock().unwrap() {
+            if checked_at.elapsed() < VERIFY_CACHE_TTL {
+                return healthy;
+            }
         }
 
-        let message = Message::builder()
-            .from(self.email.parse().unwrap())
-            .to(to.parse().unwrap())
-            .subject(subject)
-            .body(body);
-
-        match message {
-            Ok(msg) => {
-                let master_mailer = self.mailer.clone();
-                tokio::spawn(async move {
-                    match master_mailer.send(msg).await {
-                        Err(_) => eprintln!("Error sending the email"),
-                        _ => (),
-                    }
-                });
-                Ok(())
+        let healthy = match timeout(VERIFY_TIMEOUT, self.transport.verify()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                tracing::warn!("Mailer verification failed: {:?}", e);
+                false
             }
-            Err(e) => Err(ServiceError::internal_server_error(
-                SOMETHING_WENT_WRONG,
-                Some(e),
-            )),
+            Err(_) => {
+                tracing::warn!("Mailer verification timed out");
+                false
+            }
+        };
+        *self.verified.lock().unwrap() = Some((Instant::now(), healthy));
+        healthy
+    }
+
+    async fn queue_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        to: &str,
+        subject: String,
+        body: String,
+    ) -> Result<(), ServiceError> {
+        // Development sends right away, the row is only kept as a record
+        let (status, attempts, last_error, body) = if self.environment.is_production() {
+            (EmailStatusEnum::Pending, 0, None, body)
+        } else {
+            let result = self.transport.send(&self.email, to, &subject, &body).await;
+            self.metrics.observe_mailer_send(result.is_ok());
+
+            match result {
+                Ok(()) => (EmailStatusEnum::Sent, 1, None, String::new()),
+                Err(e) => (EmailStatusEnum::Failed, 1, Some(e.to_string()), body),
+            }
+        };
+
+        email_outbox::ActiveModel {
+            recipient: Set(to.to_string()),
+            subject: Set(subject),
+            body: Set(body),
+            attempts: Set(attempts),
+            status: Set(status),
+            last_error: Set(last_error),
+            next_attempt_at: Set(Utc::now().naive_utc()),
+            ..Default::default()
         }
+        .insert(conn)
+        .await?;
+        Ok(())
     }
 
-    pub fn send_confirmation_email(
+    async fn send_template<C: ConnectionTrait>(
         &self,
+        conn: &C,
+        to: &str,
+        locale: &str,
+        template: &str,
+        data: Map<String, Value>,
+    ) -> Result<(), ServiceError> {
+        let email = self.templates.render(locale, template, data)?;
+        self.queue_email(conn, to, email.subject, email.body).await
+    }
+
+    /// Sends a queued email through the transport, only called by the outbox worker.
+    pub async fn deliver(&self, email: &email_outbox::Model) -> AnyResult<()> {
+        let result = self
+            .transport
+            .send(&self.email, &email.recipient, &email.subject, &email.body)
+            .await;
+        self.metrics.observe_mailer_send(result.is_ok());
+        result
+    }
+
+    pub async fn send_confirmation_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
         email: &str,
         full_name: &str,
+        locale: &str,
         jwt: &str,
     ) -> Result<(), ServiceError> {
         tracing::trace_span!("Sending confirmation email");
-        let link = format!("{}/confirmation/{}", self.frontend_url, &jwt);
-
-        self.send_email(
-            email.to_owned(),
-            format!("Email confirmation, {}", full_name),
-            format!(
-                r#"
-            <body>
-              <p>Hello {},</p>
-              <br />
-              <p>Welcome to Your Company,</p>
-              <p>
-                Click
-                <b>
-                  <a href='{}' target='_blank'>here</a>
-                </b>
-                to activate your acount or go to this link:
-                {}
-              </p>
-              <p><small>This link will expire in an hour.</small></p>
-              <br />
-              <p>Best regards,</p>
-              <p>Your Company Team</p>
-            </body>
-          "#,
-                full_name, &link, &link,
-            ),
-        )
+        let link = format!(
+            "{}/confirmation/{}",
+            self.templates.get_frontend_url(),
+            &jwt
+        );
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, CONFIRMATION_TEMPLATE, data)
+            .await
     }
 
-    pub fn send_access_email(
+    pub async fn send_access_email<C: ConnectionTrait>(
         &self,
+        conn: &C,
         email: &str,
         full_name: &str,
+        locale: &str,
         code: &str,
     ) -> Result<(), ServiceError> {
-        self.send_email(
-            email.to_owned(),
-            format!("Your access code, {}", full_name),
-            format!(
-                r#"
-                <body>
-                    <p>Hello {},</p>
-                    <br />
-                    <p>Welcome to Your Company,</p>
-                    <p>
-                        Your access code is
-                        <b>{}</b>
-                    </p>
-                    <p><small>This code will expire in 15 minutes.</small></p>
-                    <br />
-                    <p>Best regards,</p>
-                    <p>Your Company Team</p>
-                </body> 
-            "#,
-                full_name, code
-            ),
-        )
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("code".to_string(), json!(code));
+        self.send_template(conn, email, locale, ACCESS_TEMPLATE, data)
+            .await
     }
 
-    pub fn send_password_reset_email(
+    pub async fn send_password_reset_email<C: ConnectionTrait>(
         &self,
+        conn: &C,
         email: &str,
         full_name: &str,
+        locale: &str,
         token: &str,
     ) -> Result<(), ServiceError> {
-        let link = format!("{}/confirmation/{}", self.frontend_url, &token);
-
-        self.send_email(
-            email.to_owned(),
-            format!("Email confirmation, {}", full_name),
-            format!(
-                r#"
-                <body>
-                    <p>Hello {},</p>
-                    <br />
-                    <p>Your password reset link:
-                    <b><a href='{}' target='_blank'>here</a></b></p>
-                    <p>Or go to this link: {}</p>
-                    <p><small>This link will expire in 30 minutes.</small></p>
-                    <br />
-                    <p>Best regards,</p>
-                    <p>Your Company Team</p>
-                </body>
-                "#,
-                &full_name, &link, &link,
-            ),
-        )
+        let link = format!(
+            "{}/confirmation/{}",
+            self.templates.get_frontend_url(),
+            &token
+        );
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, PASSWORD_RESET_TEMPLATE, data)
+            .await
+    }
+
+    pub async fn send_password_changed_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/forgot-password", self.templates.get_frontend_url());
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, PASSWORD_CHANGED_TEMPLATE, data)
+            .await
+    }
+
+    pub async fn send_security_alert_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+        lock_minutes: u64,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/forgot-password", self.templates.get_frontend_url());
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("lock_minutes".to_string(), json!(lock_minutes));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, SECURITY_ALERT_TEMPLATE, data)
+            .await
+    }
+
+    /// The time is preformatted, the email is sent after the sign in went through.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_new_device_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+        signed_in_at: &str,
+        user_agent: &str,
+        ip: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/forgot-password", self.templates.get_frontend_url());
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("signed_in_at".to_string(), json!(signed_in_at));
+        data.insert("user_agent".to_string(), json!(user_agent));
+        data.insert("ip".to_string(), json!(ip));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, NEW_DEVICE_TEMPLATE, data)
+            .await
+    }
+
+    pub async fn send_two_factor_disabled_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/forgot-password", self.templates.get_frontend_url());
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, TWO_FACTOR_DISABLED_TEMPLATE, data)
+            .await
+    }
+
+    /// Sent to the previous address, which only sees a masked hint of the new one.
+    pub async fn send_email_changed_notification<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+        new_email_hint: &str,
+        token: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!(
+            "{}/revert-email/{}",
+            self.templates.get_frontend_url(),
+            token
+        );
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("new_email".to_string(), json!(new_email_hint));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, EMAIL_CHANGED_TEMPLATE, data)
+            .await
+    }
+
+    /// The invitee has no account yet, so the email uses the default locale.
+    pub async fn send_invitation_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        inviter_name: &str,
+        expiration_days: i64,
+        token: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!("{}/invitation/{}", self.templates.get_frontend_url(), token);
+        let mut data = Map::new();
+        data.insert("inviter_name".to_string(), json!(inviter_name));
+        data.insert("expiration_days".to_string(), json!(expiration_days));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, DEFAULT_LOCALE, INVITATION_TEMPLATE, data)
+            .await
+    }
+
+    /// The link is already signed, it goes out as is.
+    pub async fn send_data_export_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+        link: &str,
+        expiration_hours: u64,
+    ) -> Result<(), ServiceError> {
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("expiration_hours".to_string(), json!(expiration_hours));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, DATA_EXPORT_TEMPLATE, data)
+            .await
+    }
+
+    /// Last call before an unconfirmed account is removed, with a fresh confirmation link.
+    pub async fn send_account_expiry_email<C: ConnectionTrait>(
+        &self,
+        conn: &C,
+        email: &str,
+        full_name: &str,
+        locale: &str,
+        hours: i64,
+        jwt: &str,
+    ) -> Result<(), ServiceError> {
+        let link = format!(
+            "{}/confirmation/{}",
+            self.templates.get_frontend_url(),
+            &jwt
+        );
+        let mut data = Map::new();
+        data.insert("full_name".to_string(), json!(full_name));
+        data.insert("hours".to_string(), json!(hours));
+        data.insert("link".to_string(), json!(link));
+        self.send_template(conn, email, locale, ACCOUNT_EXPIRY_TEMPLATE, data)
+            .await
     }
 }
@@ -0,0 +1,127 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::common::ServiceError;
+
+use super::Cache;
+
+pub const MAINTENANCE_KEY: &str = "maintenance";
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "The service is under maintenance, please try again later";
+/// Maintenance without an end still expires, so a forgotten flag cannot lock the API forever.
+const MAX_MAINTENANCE_TTL: u64 = 86400;
+const LOCAL_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MaintenanceStatus {
+    pub message: String,
+    pub until: Option<i64>,
+}
+
+impl MaintenanceStatus {
+    /// Seconds left until the maintenance ends, never less than one.
+    pub fn retry_after(&self) -> Option<i64> {
+        self.until
+            .map(|until| (until - Utc::now().timestamp()).max(1))
+    }
+}
+
+type LocalStatus = Option<(Instant, Option<MaintenanceStatus>)>;
+
+/// The maintenance flag lives in Redis so every instance sees it, each process keeps the
+/// last answer for a few seconds so requests do not all hit Redis.
+#[derive(Clone)]
+pub struct Maintenance {
+    cache: Cache,
+    key: String,
+    local: Arc<Mutex<LocalStatus>>,
+}
+
+impl Maintenance {
+    pub fn new(cache: &Cache) -> Self {
+        Self::with_key(cache, MAINTENANCE_KEY)
+    }
+
+    pub fn with_key(cache: &Cache, key: &str) -> Self {
+        Self {
+            cache: cache.clone(),
+            key: key.to_string(),
+            local: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn remember(&self, status: Option<MaintenanceStatus>) {
+        if let Ok(mut local) = self.local.lock() {
+            *local = Some((Instant::now(), status));
+        }
+    }
+
+    /// The current maintenance, if any. Redis being unreachable counts as no maintenance.
+    pub async fn status(&self) -> Option<MaintenanceStatus> {
+        let cached = self.local.lock().ok().and_then(|local| match &*local {
+            Some((checked_at, status)) if checked_at.elapsed() < LOCAL_TTL => Some(status.clone()),
+            _ => None,
+        });
+        let status = match cached {
+            Some(status) => status,
+            None => {
+                let status = match self.cache.get_json::<MaintenanceStatus>(&self.key).await {
+                    Ok(status) => status,
+                    Err(e) => {
+                        tracing::warn!("Failed to read the maintenance flag: {:?}", e);
+                        None
+                    }
+                };
+                self.remember(status.clone());
+                status
+            }
+        };
+
+        let now = Utc::now().timestamp();
+        status.filter(|status| !matches!(status.until, Some(until) if until <= now))
+    }
+
+    pub async fn enable(
+        &self,
+        message: Option<String>,
+        until: Option<i64>,
+    ) -> Result<MaintenanceStatus, ServiceError> {
+        let now = Utc::now().timestamp();
+        let ttl = match until {
+            Some(until) if until <= now => {
+                return Err(ServiceError::bad_request::<ServiceError>(
+                    "Maintenance must end in the future",
+                    None,
+                ));
+            }
+            Some(until) => (until - now) as u64,
+            None => MAX_MAINTENANCE_TTL,
+        };
+        let message = message
+            .map(|message| message.trim().to_string())
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_string());
+        let status = MaintenanceStatus { message, until };
+
+        self.cache.set_json(&self.key, &status, ttl).await?;
+        self.remember(Some(status.clone()));
+        Ok(status)
+    }
+
+    pub async fn disable(&self) -> Result<(), ServiceError> {
+        self.cache.del(&self.key).await?;
+        self.remember(None);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use secrecy::{ExposeSecret, Secret};
+
+use crate::common::{ServiceError, INTERNAL_SERVER_ERROR};
+
+const SUCCESS: &str = "success";
+const FAILURE: &str = "failure";
+/// Operation names are picked by clients, past this many new ones are counted as other.
+const MAX_GRAPHQL_OPERATIONS: usize = 100;
+const MAX_OPERATION_NAME_LENGTH: usize = 64;
+const OTHER_OPERATION: &str = "other";
+
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    token: Option<Secret<String>>,
+    operations: Arc<Mutex<HashSet<String>>>,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    graphql_operations: IntCounterVec,
+    graphql_errors: IntCounterVec,
+    mailer_sends: IntCounterVec,
+    object_storage_upload_duration: Histogram,
+    cache_acquire_duration: Histogram,
+    database_acquire_duration: Histogram,
+    blacklist_size: IntGauge,
+    unconfirmed_expiry: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Total number of HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .expect("Failed to create http_requests_total metric.");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request duration in seconds",
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("Failed to create http_request_duration_seconds metric.");
+        let graphql_operations = IntCounterVec::new(
+            Opts::new(
+                "graphql_operations_total",
+                "Total number of GraphQL operations",
+            ),
+            &["operation"],
+        )
+        .expect("Failed to create graphql_operations_total metric.");
+        let graphql_errors = IntCounterVec::new(
+            Opts::new(
+                "graphql_errors_total",
+                "Total number of errors returned by GraphQL operations",
+            ),
+            &["operation"],
+        )
+        .expect("Failed to create graphql_errors_total metric.");
+        let mailer_sends = IntCounterVec::new(
+            Opts::new("mailer_sends_total", "Total number of emails sent"),
+            &["status"],
+        )
+        .expect("Failed to create mailer_sends_total metric.");
+        let object_storage_upload_duration = Histogram::with_opts(HistogramOpts::new(
+            "object_storage_upload_duration_seconds",
+            "Object storage upload duration in seconds",
+        ))
+        .expect("Failed to create object_storage_upload_duration_seconds metric.");
+        let cache_acquire_duration = Histogram::with_opts(HistogramOpts::new(
+            "cache_connection_acquire_duration_seconds",
+            "Time taken to acquire a Redis connection in seconds",
+        ))
+        .expect("Failed to create cache_connection_acquire_duration_seconds metric.");
+        let database_acquire_duration = Histogram::with_opts(HistogramOpts::new(
+            "database_connection_acquire_duration_seconds",
+            "Time taken to acquire a database pool connection in seconds",
+        ))
+        .expect("Failed to create database_connection_acquire_duration_seconds metric.");
+        let blacklist_size = IntGauge::new(
+            "blacklist_size",
+            "Number of blacklisted refresh tokens that have not expired",
+        )
+        .expect("Failed to create blacklist_size metric.");
+        let unconfirmed_expiry = IntCounterVec::new(
+            Opts::new(
+                "unconfirmed_accounts_expiry_total",
+                "Total number of unconfirmed accounts warned about or deleted on expiry",
+            ),
+            &["action"],
+        )
+        .expect("Failed to create unconfirmed_accounts_expiry_total metric.");
+
+        registry
+            .register(Box::new(http_requests.clone()))
+            .and_then(|_| registry.register(Box::new(http_request_duration.clone())))
+            .and_then(|_| registry.register(Box::new(graphql_operations.clone())))
+            .and_then(|_| registry.register(Box::new(graphql_errors.clone())))
+            .and_then(|_| registry.register(Box::new(mailer_sends.clone())))
+            .and_then(|_| registry.register(Box::new(object_storage_upload_duration.clone())))
+            .and_then(|_| registry.register(Box::new(cache_acquire_duration.clone())))
+            .and_then(|_| registry.register(Box::new(database_acquire_duration.clone())))
+            .and_then(|_| registry.register(Box::new(blacklist_size.clone())))
+            .and_then(|_| registry.register(Box::new(unconfirmed_expiry.clone())))
+            .expect("Failed to register metrics.");
+
+        // Export both mailer series from the start so failure rates can be computed
+        mailer_sends.with_label_values(&[SUCCESS]);
+        mailer_sends.with_label_values(&[FAILURE]);
+
+        Self {
+            registry,
+            token: None,
+            operations: Arc::default(),
+            http_requests,
+            http_request_duration,
+            graphql_operations,
+            graphql_errors,
+            mailer_sends,
+            object_storage_upload_duration,
+            cache_acquire_duration,
+            database_acquire_duration,
+            blacklist_size,
+            unconfirmed_expiry,
+        }
+    }
+
+    /// Scrapes of `/metrics` then need the token as a bearer token.
+    pub fn with_token(mut self, token: Option<Secret<String>>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn get_token(&self) -> Option<&str> {
+        self.token
+            .as_ref()
+            .map(|token| token.expose_secret().as_str())
+    }
+
+    /// The name as a label while it is one of the first `MAX_GRAPHQL_OPERATIONS`
+    /// seen, so clients can not grow the series without bound.
+    fn operation_label<'a>(&self, operation: &'a str) -> &'a str {
+        let valid = !operation.is_empty()
+            && operation.len() <= MAX_OPERATION_NAME_LENGTH
+            && operation
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        if !valid {
+            return OTHER_OPERATION;
+        }
+
+        let Ok(mut operations) = self.operations.lock() else {
+            return OTHER_OPERATION;
+        };
+
+        if operations.contains(operation) {
+            operation
+        } else if operations.len() < MAX_GRAPHQL_OPERATIONS {
+            operations.insert(operation.to_string());
+            operation
+        } else {
+            OTHER_OPERATION
+        }
+    }
+
+    pub fn observe_http_request(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(seconds);
+    }
+
+    pub fn observe_graphql_operation(&self, operation: &str, errors: usize) {
+        let operation = self.operation_label(operation);
+        self.graphql_operations
+            .with_label_values(&[operation])
+            .inc();
+
+        if errors > 0 {
+            self.graphql_errors
+                .with_label_values(&[operation])
+                .inc_by(errors as u64);
+        }
+    }
+
+    pub fn observe_mailer_send(&self, success: bool) {
+        let status = if success { SUCCESS } else { FAILURE };
+        self.mailer_sends.with_label_values(&[status]).inc();
+    }
+
+    pub fn observe_object_storage_upload(&self, seconds: f64) {
+        self.object_storage_upload_duration.observe(seconds);
+    }
+
+    pub fn observe_cache_acquire(&self, seconds: f64) {
+        self.cache_acquire_duration.observe(seconds);
+    }
+
+    pub fn observe_database_acquire(&self, seconds: f64) {
+        self.database_acquire_duration.observe(seconds);
+    }
+
+    pub fn set_blacklist_size(&self, size: u64) {
+        self.blacklist_size.set(size as i64);
+    }
+
+    pub fn observe_unconfirmed_expiry(&self, warned: u64, deleted: u64) {
+        self.unconfirmed_expiry
+            .with_label_values(&["warned"])
+            .inc_by(warned);
+        self.unconfirmed_expiry
+            .with_label_values(&["deleted"])
+            .inc_by(deleted);
+    }
+
+    pub fn encode(&self) -> Result<String, ServiceError> {
+        let mut buffer = Vec::<u8>::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
+        String::from_utf8(buffer)
+            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))
+    }
+}
@@ -4,21 +4,46 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use allowlist::*;
 pub use cache::*;
+pub use captcha::*;
+pub use circuit_breaker::*;
+pub use config::*;
 pub use database::*;
 pub use environment::*;
+pub use filesystem_storage::*;
+pub use http_client::*;
 pub use jwt::*;
+pub use lockout::*;
 pub use mailer::*;
+pub use maintenance::*;
+pub use metrics::*;
 pub use oauth::*;
 pub use object_storage::*;
+pub use password_breaches::*;
 pub use server_config::*;
+pub use webhooks::*;
 
+pub mod allowlist;
 pub mod cache;
+pub mod captcha;
+pub mod circuit_breaker;
+pub mod config;
 pub mod database;
 pub mod environment;
+pub mod filesystem_storage;
 mod helpers;
+pub mod http_client;
 pub mod jwt;
+pub mod lockout;
 pub mod mailer;
+pub mod maintenance;
+pub mod metrics;
 pub mod oauth;
 pub mod object_storage;
+pub mod password_breaches;
 pub mod server_config;
+pub mod webhooks;
+
+#[cfg(test)]
+mod tests;
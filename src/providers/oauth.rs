@@ -4,28 +4,40 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
-
-use oauth2::{basic::BasicClient, AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
+use oauth2::{
+    basic::BasicClient, url::form_urlencoded::Serializer, AuthUrl, ClientId, ClientSecret,
+    RedirectUrl, TokenUrl,
+};
+use ring::aead::LessSafeKey;
 
 use entities::enums::OAuthProviderEnum;
 
-use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+use secrecy::ExposeSecret;
+
+use crate::common::{InternalCause, ServiceError, INVALID_CREDENTIALS, SOMETHING_WENT_WRONG};
+
+use super::{helpers::oauth_state, HttpClient, HttpClientConfig, OAuthClientConfig, OAuthConfig};
+
+/// Time a user has to go through the provider's consent screen.
+pub const OAUTH_STATE_TIME: i64 = 600;
 
 #[derive(Debug)]
 pub enum ExternalProvider {
     Google,
     Facebook,
+    Github,
 }
 
-const GOOGLE: &'static str = "google";
-const FACEBOOK: &'static str = "facebook";
+const GOOGLE: &str = "google";
+const FACEBOOK: &str = "facebook";
+const GITHUB: &str = "github";
 
 impl ExternalProvider {
     pub fn to_str(&self) -> &str {
         match self {
             ExternalProvider::Google => GOOGLE,
             ExternalProvider::Facebook => FACEBOOK,
+            ExternalProvider::Github => GITHUB,
         }
     }
 
@@ -33,6 +45,7 @@ impl ExternalProvider {
         match self {
             ExternalProvider::Google => OAuthProviderEnum::Google,
             ExternalProvider::Facebook => OAuthProviderEnum::Facebook,
+            ExternalProvider::Github => OAuthProviderEnum::Github,
         }
     }
 }
@@ -43,36 +56,141 @@ struct ClientCredentials {
     client_secret: ClientSecret,
 }
 
+impl From<&OAuthClientConfig> for ClientCredentials {
+    fn from(config: &OAuthClientConfig) -> Self {
+        Self {
+            client_id: ClientId::new(config.client_id.clone()),
+            client_secret: ClientSecret::new(config.client_secret.expose_secret().clone()),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct OAuth {
     google: ClientCredentials,
     facebook: ClientCredentials,
+    github: ClientCredentials,
     url: String,
+    success_redirect: String,
+    error_redirect: String,
+    link_redirect: String,
+    state_key: LessSafeKey,
+    http_client: HttpClient,
 }
 
 impl OAuth {
-    pub fn new(backend_url: String) -> Self {
-        let google_client_id = env::var("GOOGLE_CLIENT_ID")
-            .expect("Missing the GOOGLE_CLIENT_ID environment variable.");
-        let google_client_secret = env::var("GOOGLE_CLIENT_SECRET")
-            .expect("Missing the GOOGLE_CLIENT_SECRET environment variable.");
-        let facebook_client_id = env::var("FACEBOOK_CLIENT_ID")
-            .expect("Missing the FACEBOOK_CLIENT_ID environment variable.");
-        let facebook_client_secret = env::var("FACEBOOK_CLIENT_SECRET")
-            .expect("Missing the FACEBOOK_CLIENT_SECRET environment variable.");
+    pub fn new(config: &OAuthConfig, http_client: &HttpClientConfig) -> Self {
         Self {
-            google: Self::build_client_credentials(google_client_id, google_client_secret),
-            facebook: Self::build_client_credentials(facebook_client_id, facebook_client_secret),
-            url: format!("{}/api/auth/ext", backend_url),
+            google: ClientCredentials::from(&config.google),
+            facebook: ClientCredentials::from(&config.facebook),
+            github: ClientCredentials::from(&config.github),
+            url: format!("{}/api/auth/ext", config.backend_url),
+            success_redirect: config.success_redirect.clone(),
+            error_redirect: config.error_redirect.clone(),
+            link_redirect: config.link_redirect.clone(),
+            state_key: oauth_state::derive_key(config.state_secret.expose_secret()),
+            http_client: HttpClient::new(http_client),
         }
     }
 
+    pub fn http_client(&self) -> &HttpClient {
+        &self.http_client
+    }
+
+    /// Carries the PKCE verifier in the `state` parameter, so no server side storage is
+    /// needed while the user is on the provider's consent screen.
+    pub fn generate_state(
+        &self,
+        provider: &ExternalProvider,
+        verifier: &str,
+    ) -> Result<String, ServiceError> {
+        self.create_state(provider, verifier, None)
+    }
+
+    /// Same as `generate_state`, the callback links the provider to the user instead of
+    /// signing in, only from the browser holding `nonce` in its link cookie.
+    pub fn generate_link_state(
+        &self,
+        provider: &ExternalProvider,
+        verifier: &str,
+        user_id: i32,
+        nonce: &str,
+    ) -> Result<String, ServiceError> {
+        self.create_state(provider, verifier, Some((user_id, nonce)))
+    }
+
+    fn create_state(
+        &self,
+        provider: &ExternalProvider,
+        verifier: &str,
+        link: Option<(i32, &str)>,
+    ) -> Result<String, ServiceError> {
+        oauth_state::Claims::create_token(
+            &self.state_key,
+            provider.to_str(),
+            verifier,
+            link,
+            OAUTH_STATE_TIME,
+        )
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))
+    }
+
+    /// Returns the PKCE verifier, the state id, the linking user id with its browser
+    /// nonce, and the `exp` timestamp.
+    pub fn verify_state(
+        &self,
+        provider: &ExternalProvider,
+        state: &str,
+    ) -> Result<oauth_state::DecodedState, ServiceError> {
+        oauth_state::Claims::decode_token(&self.state_key, provider.to_str(), state).map_err(|e| {
+            tracing::warn!("OAuth state validation failed: {}", e);
+            ServiceError::unauthorized(
+                INVALID_CREDENTIALS,
+                Some(InternalCause::new(&e.to_string())),
+            )
+        })
+    }
+
+    /// The access token goes in the fragment so it never reaches server logs.
+    pub fn get_success_redirect(&self, access_token: &str, expires_in: i64) -> String {
+        let fragment = Serializer::new(String::new())
+            .append_pair("access_token", access_token)
+            .append_pair("token_type", "Bearer")
+            .append_pair("expires_in", &expires_in.to_string())
+            .finish();
+        format!("{}#{}", self.success_redirect, fragment)
+    }
+
+    /// The frontend asks for the emailed code and confirms the sign in with this email.
+    pub fn get_mfa_redirect(&self, email: &str) -> String {
+        let fragment = Serializer::new(String::new())
+            .append_pair("mfa", "true")
+            .append_pair("email", email)
+            .finish();
+        format!("{}#{}", self.success_redirect, fragment)
+    }
+
+    /// Linking happens from the settings, so the browser goes back there.
+    pub fn get_link_redirect(&self, provider: &ExternalProvider) -> String {
+        let query = Serializer::new(String::new())
+            .append_pair("linked", provider.to_str())
+            .finish();
+        format!("{}?{}", self.link_redirect, query)
+    }
+
+    pub fn get_error_redirect(&self, code: &str) -> String {
+        let query = Serializer::new(String::new())
+            .append_pair("code", code)
+            .finish();
+        format!("{}?{}", self.error_redirect, query)
+    }
+
     pub fn get_external_client(
         &self,
         provider: &ExternalProvider,
     ) -> Result<BasicClient, ServiceError> {
-        match provider {
-            &ExternalProvider::Google => {
+        match *provider {
+            ExternalProvider::Google => {
                 let auth_url =
                     AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
                         .map_err(|e| {
@@ -95,7 +213,7 @@ impl OAuth {
                 )
                 .set_redirect_uri(redirect_url))
             }
-            &ExternalProvider::Facebook => {
+            ExternalProvider::Facebook => {
                 let auth_url =
                     AuthUrl::new("https://www.facebook.com/v18.0/dialog/oauth".to_string())
                         .map_err(|e| {
@@ -118,17 +236,41 @@ impl OAuth {
                 )
                 .set_redirect_uri(redirect_url))
             }
+            ExternalProvider::Github => {
+                let auth_url = AuthUrl::new("https://github.com/login/oauth/authorize".to_string())
+                    .map_err(|e| {
+                        ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e))
+                    })?;
+                let token_url =
+                    TokenUrl::new("https://github.com/login/oauth/access_token".to_string())
+                        .map_err(|e| {
+                            ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e))
+                        })?;
+                let redirect_url = RedirectUrl::new(format!("{}/github/callback", &self.url))
+                    .map_err(|e| {
+                        ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e))
+                    })?;
+
+                Ok(BasicClient::new(
+                    self.github.client_id.clone(),
+                    Some(self.github.client_secret.clone()),
+                    auth_url,
+                    Some(token_url),
+                )
+                .set_redirect_uri(redirect_url))
+            }
         }
     }
 
-    pub fn get_external_client_scopes(&self, provider: &ExternalProvider) -> [&str; 3] {
+    pub fn get_external_client_scopes(&self, provider: &ExternalProvider) -> &[&str] {
         match provider {
-            ExternalProvider::Google => [
+            ExternalProvider::Google => &[
                 "https://www.googleapis.com/auth/userinfo.email",
                 "https://www.googleapis.com/auth/userinfo.profile",
                 "https://www.googleapis.com/auth/user.birthday.read",
             ],
-            ExternalProvider::Facebook => ["email", "public_profile", "user_birthday"],
+            ExternalProvider::Facebook => &["email", "public_profile", "user_birthday"],
+            ExternalProvider::Github => &["read:user", "user:email"],
         }
     }
 
@@ -136,13 +278,15 @@ impl OAuth {
         match provider {
             ExternalProvider::Google => "https://www.googleapis.com/oauth2/v3/userinfo",
             ExternalProvider::Facebook => "https://graph.facebook.com/v18.0/me",
+            ExternalProvider::Github => "https://api.github.com/user",
         }
     }
 
-    fn build_client_credentials(id: String, secret: String) -> ClientCredentials {
-        ClientCredentials {
-            client_id: ClientId::new(id),
-            client_secret: ClientSecret::new(secret),
+    /// GitHub omits private emails from the user info, they have to be listed separately
+    pub fn get_external_client_emails_url(&self, provider: &ExternalProvider) -> Option<&str> {
+        match provider {
+            ExternalProvider::Github => Some("https://api.github.com/user/emails"),
+            _ => None,
         }
     }
 }
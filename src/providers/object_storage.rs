@@ -4,122 +4,902 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::env;
+use std::{fmt::Display, future::Future, io::Read, sync::Arc, time::Duration};
 
-use rusoto_core::{credential::StaticProvider, HttpClient, Region};
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use actix_web::rt;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use rand::Rng;
+use rusoto_core::{
+    credential::{AwsCredentials, StaticProvider},
+    HttpClient, Region, RusotoError,
+};
+use rusoto_s3::{
+    util::{PreSignedRequest, PreSignedRequestOption},
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectRequest,
+    HeadBucketRequest, ListObjectsV2Request, PutObjectRequest, S3Client, UploadPartRequest, S3,
+};
+use secrecy::ExposeSecret;
 use uuid::Uuid;
 
-use crate::common::{ServiceError, INTERNAL_SERVER_ERROR};
+use crate::common::{InternalCause, ServiceError, INTERNAL_SERVER_ERROR};
 
-use super::Environment;
+use super::{
+    CircuitBreaker, Environment, FilesystemStorageClient, ObjectStorageBackend, ObjectStorageConfig,
+};
+
+const PUBLIC_READ_ACL: &str = "public-read";
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+const STORAGE_BREAKER: &str = "object_storage";
+const STORAGE_UNAVAILABLE: &str = "Storage temporarily unavailable";
+const STORAGE_REQUEST_FAILED: &str = "Storage request failed";
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(200);
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+pub const DEFAULT_UPLOAD_ALLOWED_TYPES: [&str; 2] = ["application/pdf", "text/plain"];
+
+#[derive(Clone, Debug)]
+pub struct ListedObject {
+    pub key: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// A page of objects, with the token for the next one when the listing was truncated.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectPage {
+    pub objects: Vec<ListedObject>,
+    pub next_token: Option<String>,
+}
+
+#[async_trait]
+pub trait ObjectStorageClient: Send + Sync {
+    async fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        content_type: &str,
+        public: bool,
+        body: Vec<u8>,
+    ) -> Result<(), ServiceError>;
+
+    async fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        content_type: &str,
+        public: bool,
+    ) -> Result<String, ServiceError>;
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> Result<CompletedPart, ServiceError>;
+
+    async fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), ServiceError>;
+
+    async fn abort_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), ServiceError>;
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, ServiceError>;
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), ServiceError>;
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ObjectPage, ServiceError>;
+
+    fn presign_get_object(&self, bucket: &str, key: &str, expires_in: Duration) -> String;
+
+    /// Checks the bucket exists and is reachable, backends without one are always fine.
+    async fn head_bucket(&self, _bucket: &str) -> Result<(), ServiceError> {
+        Ok(())
+    }
+}
+
+/// Failed connections and server errors are worth retrying, other errors would fail
+/// the same way again.
+fn storage_error<E: std::error::Error + 'static>(error: RusotoError<E>) -> ServiceError {
+    match &error {
+        RusotoError::HttpDispatch(_) => {
+            ServiceError::gateway_timeout(STORAGE_REQUEST_FAILED, Some(error))
+        }
+        RusotoError::Unknown(response) if response.status.is_server_error() => {
+            ServiceError::bad_gateway(STORAGE_REQUEST_FAILED, Some(error))
+        }
+        _ => ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(error)),
+    }
+}
+
+fn is_retryable(error: &ServiceError) -> bool {
+    matches!(
+        error,
+        ServiceError::BadGateway(_)
+            | ServiceError::GatewayTimeout(_)
+            | ServiceError::ServiceUnavailable(_)
+    )
+}
+
+/// Attempts per storage request, retries wait `delay` doubled each time, with up to
+/// half of it taken off at random so workers do not retry in step.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.delay * 2u32.pow(retry.min(16));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+fn get_acl(public: bool) -> Option<String> {
+    if public {
+        return Some(PUBLIC_READ_ACL.to_string());
+    }
+
+    None
+}
+
+pub struct S3StorageClient {
+    client: S3Client,
+    region: Region,
+    credentials: AwsCredentials,
+}
+
+#[async_trait]
+impl ObjectStorageClient for S3StorageClient {
+    async fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        content_type: &str,
+        public: bool,
+        body: Vec<u8>,
+    ) -> Result<(), ServiceError> {
+        let request = PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            body: Some(body.into()),
+            content_type: Some(content_type.to_string()),
+            acl: get_acl(public),
+            ..Default::default()
+        };
+        self.client
+            .put_object(request)
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        content_type: &str,
+        public: bool,
+    ) -> Result<String, ServiceError> {
+        let request = CreateMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: Some(content_type.to_string()),
+            acl: get_acl(public),
+            ..Default::default()
+        };
+        self.client
+            .create_multipart_upload(request)
+            .await
+            .map_err(storage_error)?
+            .upload_id
+            .ok_or_else(|| {
+                ServiceError::internal_server_error(
+                    INTERNAL_SERVER_ERROR,
+                    Some(InternalCause::new("Multipart upload has no upload id")),
+                )
+            })
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> Result<CompletedPart, ServiceError> {
+        let request = UploadPartRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            part_number,
+            content_length: Some(body.len() as i64),
+            body: Some(body.into()),
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .upload_part(request)
+            .await
+            .map_err(storage_error)?;
+        Ok(CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), ServiceError> {
+        let request = CompleteMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+        self.client
+            .complete_multipart_upload(request)
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), ServiceError> {
+        let request = AbortMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.client
+            .abort_multipart_upload(request)
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, ServiceError> {
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        let body = self
+            .client
+            .get_object(request)
+            .await
+            .map_err(storage_error)?
+            .body
+            .ok_or_else(|| {
+                ServiceError::internal_server_error(
+                    INTERNAL_SERVER_ERROR,
+                    Some(InternalCause::new("Object has no body")),
+                )
+            })?;
+        body.map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))
+    }
+
+    async fn head_bucket(&self, bucket: &str) -> Result<(), ServiceError> {
+        let request = HeadBucketRequest {
+            bucket: bucket.to_string(),
+            ..Default::default()
+        };
+        self.client
+            .head_bucket(request)
+            .await
+            .map_err(storage_error)
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), ServiceError> {
+        let request = DeleteObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        self.client
+            .delete_object(request)
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ObjectPage, ServiceError> {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_string(),
+            prefix: Some(prefix.to_string()),
+            continuation_token,
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .list_objects_v2(request)
+            .await
+            .map_err(storage_error)?;
+        let objects = output
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| {
+                // Undated objects count as just uploaded, so they are never collected
+                let last_modified = object
+                    .last_modified
+                    .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                    .map_or_else(Utc::now, |date| date.with_timezone(&Utc));
+                object.key.map(|key| ListedObject { key, last_modified })
+            })
+            .collect();
+        Ok(ObjectPage {
+            objects,
+            next_token: output
+                .next_continuation_token
+                .filter(|_| output.is_truncated.unwrap_or(false)),
+        })
+    }
+
+    fn presign_get_object(&self, bucket: &str, key: &str, expires_in: Duration) -> String {
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        request.get_presigned_url(
+            &self.region,
+            &self.credentials,
+            &PreSignedRequestOption { expires_in },
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredObject {
+    pub key: String,
+    pub url: String,
+}
 
 #[derive(Clone)]
 pub struct ObjectStorage {
-    client: S3Client,
+    client: Arc<dyn ObjectStorageClient>,
     bucket: String,
     endpoint: String,
     namespace: Uuid,
+    multipart_threshold: usize,
+    public: bool,
+    deduplicate: bool,
+    user_quota: u64,
+    allowed_types: Vec<String>,
+    backend_url: String,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
 }
 
 impl ObjectStorage {
-    pub fn new(environment: &Environment) -> Self {
-        let object_storage_host = env::var("OBJECT_STORAGE_HOST")
-            .expect("Missing the OBJECT_STORAGE_HOST environment variable.");
-        let object_storage_access_key = env::var("OBJECT_STORAGE_ACCESS_KEY")
-            .expect("Missing the OBJECT_STORAGE_ACCESS_KEY environment variable.");
-        let object_storage_secret_key = env::var("OBJECT_STORAGE_SECRET_KEY")
-            .expect("Missing the OBJECT_STORAGE_SECRET_KEY environment variable.");
-        let object_storage_bucket = env::var("OBJECT_STORAGE_BUCKET")
-            .expect("Missing the OBJECT_STORAGE_BUCKET environment variable.");
-        let object_storage_region = env::var("OBJECT_STORAGE_REGION")
-            .expect("Missing the OBJECT_STORAGE_REGION environment variable.");
-        let object_storage_namespace =
-            env::var("OBJECT_STORAGE_NAMESPACE").unwrap_or_else(|_| match environment {
-                &Environment::Development => Uuid::new_v4().to_string(),
-                &Environment::Production => {
-                    panic!("Missing the OBJECT_STORAGE_HOST environment variable.")
-                }
-            });
-        let domain = match environment {
-            &Environment::Development => object_storage_host,
-            &Environment::Production => {
-                format!("{}.{}", object_storage_region, object_storage_host)
+    pub fn new(environment: &Environment, config: &ObjectStorageConfig) -> Self {
+        let (client, endpoint): (Arc<dyn ObjectStorageClient>, String) = match config.backend {
+            ObjectStorageBackend::S3 => {
+                let (client, endpoint) = Self::s3_client(environment, config);
+                (Arc::new(client), endpoint)
+            }
+            ObjectStorageBackend::Filesystem => {
+                tracing::info!(
+                    "Storing uploads under {}",
+                    config.local_path.to_string_lossy()
+                );
+                (
+                    Arc::new(FilesystemStorageClient::new(
+                        &config.local_path,
+                        &config.local_url,
+                    )),
+                    config.local_url.clone(),
+                )
             }
         };
+        Self {
+            client,
+            endpoint,
+            bucket: config.bucket.clone(),
+            namespace: config.namespace,
+            multipart_threshold: config.multipart_threshold.max(MIN_PART_SIZE),
+            public: config.public,
+            deduplicate: config.deduplicate,
+            user_quota: config.user_quota_bytes,
+            allowed_types: config.upload_allowed_types.clone(),
+            backend_url: String::new(),
+            retry: RetryPolicy {
+                attempts: config.retry_attempts,
+                delay: Duration::from_millis(config.retry_delay_ms),
+            },
+            breaker: CircuitBreaker::new(
+                STORAGE_BREAKER,
+                config.breaker_threshold,
+                Duration::from_secs(config.breaker_cooldown_seconds),
+            ),
+        }
+    }
+
+    /// The client for the configured S3 host and the endpoint public objects are read at.
+    fn s3_client(
+        environment: &Environment,
+        config: &ObjectStorageConfig,
+    ) -> (S3StorageClient, String) {
+        let domain = match *environment {
+            Environment::Development => config.host.clone(),
+            Environment::Production => format!("{}.{}", &config.region, &config.host),
+        };
 
-        let namespace = Uuid::parse_str(&object_storage_namespace).unwrap();
         let region = Region::Custom {
-            name: object_storage_region,
-            endpoint: match environment {
-                &Environment::Development => format!("http://{}", &domain),
-                &Environment::Production => format!("https://{}", &domain),
+            name: config.region.clone(),
+            endpoint: match *environment {
+                Environment::Development => format!("http://{}", &domain),
+                Environment::Production => format!("https://{}", &domain),
             },
         };
         let client = S3Client::new_with(
             HttpClient::new().expect("Failed to create HTTP client"),
             StaticProvider::new(
-                object_storage_access_key,
-                object_storage_secret_key,
+                config.access_key.clone(),
+                config.secret_key.expose_secret().clone(),
                 None,
                 None,
             ),
-            region,
+            region.clone(),
         );
-        Self {
+        let client = S3StorageClient {
             client,
-            endpoint: match environment {
-                &Environment::Development => {
-                    format!("http://{}/{}", domain, &object_storage_bucket)
+            region,
+            credentials: AwsCredentials::new(
+                config.access_key.clone(),
+                config.secret_key.expose_secret().clone(),
+                None,
+                None,
+            ),
+        };
+        let endpoint = match *environment {
+            Environment::Development => format!("http://{}/{}", domain, &config.bucket),
+            Environment::Production => format!("https://{}.{}", &config.bucket, domain),
+        };
+        (client, endpoint)
+    }
+
+    pub fn with_client(
+        client: impl ObjectStorageClient + 'static,
+        bucket: &str,
+        endpoint: &str,
+        namespace: Uuid,
+        multipart_threshold: usize,
+        public: bool,
+    ) -> Self {
+        Self {
+            client: Arc::new(client),
+            bucket: bucket.to_string(),
+            endpoint: endpoint.to_string(),
+            namespace,
+            multipart_threshold,
+            public,
+            deduplicate: true,
+            user_quota: 0,
+            allowed_types: DEFAULT_UPLOAD_ALLOWED_TYPES.map(str::to_string).to_vec(),
+            backend_url: String::new(),
+            retry: RetryPolicy::default(),
+            breaker: CircuitBreaker::new(
+                STORAGE_BREAKER,
+                DEFAULT_BREAKER_THRESHOLD,
+                DEFAULT_BREAKER_COOLDOWN,
+            ),
+        }
+    }
+
+    /// Whether the configured bucket is reachable, without retries so startup fails fast.
+    pub async fn head_bucket(&self) -> Result<(), ServiceError> {
+        self.client.head_bucket(&self.bucket).await
+    }
+
+    pub fn with_deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    pub fn with_user_quota(mut self, user_quota: u64) -> Self {
+        self.user_quota = user_quota;
+        self
+    }
+
+    pub fn with_allowed_types(mut self, allowed_types: Vec<String>) -> Self {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /// Origin of the API routes serving stored files, relative URLs are built without it.
+    pub fn with_backend_url(mut self, backend_url: &str) -> Self {
+        self.backend_url = backend_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_resilience(mut self, retry: RetryPolicy, breaker: CircuitBreaker) -> Self {
+        self.retry = retry;
+        self.breaker = breaker;
+        self
+    }
+
+    /// Retries failed connections and server errors, failing fast while the breaker is
+    /// open. Only those errors count towards opening it, a 403 means storage is up.
+    async fn with_retries<T, F, Fut>(&self, operation: &str, request: F) -> Result<T, ServiceError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ServiceError>>,
+    {
+        if !self.breaker.allows() {
+            return Err(ServiceError::internal_server_error(
+                STORAGE_UNAVAILABLE,
+                Some(InternalCause::new("Object storage circuit breaker is open")),
+            ));
+        }
+
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Ok(value) => {
+                    self.breaker.record_success();
+                    return Ok(value);
                 }
-                &Environment::Production => {
-                    format!("https://{}.{}", &object_storage_bucket, domain)
+                Err(e) if is_retryable(&e) && attempt < self.retry.attempts => {
+                    let delay = self.retry.backoff(attempt - 1);
+                    tracing::warn!(
+                        operation,
+                        attempt,
+                        delay_ms = delay.as_millis() as u64,
+                        "Retrying object storage request"
+                    );
+                    rt::time::sleep(delay).await;
+                    attempt += 1;
                 }
-            },
-            bucket: object_storage_bucket,
-            namespace,
+                Err(e) => {
+                    if is_retryable(&e) {
+                        self.breaker.record_failure();
+                    } else {
+                        self.breaker.record_success();
+                    }
+
+                    return Err(e);
+                }
+            }
         }
     }
 
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    /// Whether images are stored under their content hash and reused per user.
+    pub fn deduplicates(&self) -> bool {
+        self.deduplicate
+    }
+
+    /// Bytes each user may store, `None` when unlimited.
+    pub fn user_quota(&self) -> Option<u64> {
+        Some(self.user_quota).filter(|quota| *quota > 0)
+    }
+
+    /// MIME types accepted for documents, lowercase.
+    pub fn allowed_types(&self) -> &[String] {
+        &self.allowed_types
+    }
+
+    pub fn get_backend_url(&self) -> &str {
+        &self.backend_url
+    }
+
     pub async fn upload_file(
         &self,
         user_id: i32,
-        file_key: &Uuid,
+        file_key: &impl Display,
+        file_extension: &str,
+        content_type: &str,
+        file_contents: Vec<u8>,
+    ) -> Result<StoredObject, ServiceError> {
+        let key = self.build_key(user_id, file_key, file_extension);
+        self.upload_key(key, content_type, self.public, file_contents)
+            .await
+    }
+
+    /// Renditions share the key of the original file with a suffix, e.g. `<key>_64.jpg`.
+    pub async fn upload_file_rendition(
+        &self,
+        user_id: i32,
+        file_key: &impl Display,
+        suffix: &str,
+        file_extension: &str,
+        content_type: &str,
+        file_contents: Vec<u8>,
+    ) -> Result<StoredObject, ServiceError> {
+        let key = self.build_key(user_id, &format!("{}_{}", file_key, suffix), file_extension);
+        self.upload_key(key, content_type, self.public, file_contents)
+            .await
+    }
+
+    /// Variants derived from a stored object go under a key built from the original one.
+    pub async fn upload_derived_file(
+        &self,
+        key: &str,
+        content_type: &str,
+        file_contents: Vec<u8>,
+    ) -> Result<StoredObject, ServiceError> {
+        self.upload_key(key.to_string(), content_type, self.public, file_contents)
+            .await
+    }
+
+    /// Never public, even in a public bucket, only ever shared through signed URLs.
+    pub async fn upload_private_file(
+        &self,
+        user_id: i32,
+        file_key: &impl Display,
         file_extension: &str,
+        content_type: &str,
         file_contents: Vec<u8>,
     ) -> Result<String, ServiceError> {
-        let user_prefix = Uuid::new_v5(&self.namespace, user_id.to_string().as_bytes()).to_string();
-        let combined_key = format!(
-            "{}/{}.{}",
-            &user_prefix,
-            file_key.to_string(),
-            file_extension
-        );
-        let request = PutObjectRequest {
-            bucket: self.bucket.to_string(),
-            key: combined_key.clone(),
-            body: Some(file_contents.into()),
-            acl: Some("public-read".to_string()),
-            ..Default::default()
-        };
-        self.client
-            .put_object(request)
+        let key = self.build_key(user_id, file_key, file_extension);
+        let object = self
+            .upload_key(key, content_type, false, file_contents)
+            .await?;
+        Ok(object.key)
+    }
+
+    async fn upload_key(
+        &self,
+        key: String,
+        content_type: &str,
+        public: bool,
+        file_contents: Vec<u8>,
+    ) -> Result<StoredObject, ServiceError> {
+        if file_contents.len() > self.multipart_threshold {
+            return self
+                .upload_key_multipart(key, content_type, public, file_contents.as_slice())
+                .await;
+        }
+
+        let (client, bucket, object_key) = (&self.client, self.bucket.as_str(), key.as_str());
+        self.with_retries("put_object", move || {
+            client.put_object(
+                bucket,
+                object_key,
+                content_type,
+                public,
+                file_contents.clone(),
+            )
+        })
+        .await?;
+        Ok(self.build_stored_object(key))
+    }
+
+    pub async fn upload_file_multipart(
+        &self,
+        user_id: i32,
+        file_key: &Uuid,
+        file_extension: &str,
+        content_type: &str,
+        stream: impl Read + Send,
+    ) -> Result<StoredObject, ServiceError> {
+        let key = self.build_key(user_id, file_key, file_extension);
+        self.upload_key_multipart(key, content_type, self.public, stream)
             .await
+    }
+
+    /// Streams files above the multipart threshold, smaller ones are sent in a single put.
+    pub async fn upload_file_stream(
+        &self,
+        user_id: i32,
+        file_key: &Uuid,
+        file_extension: &str,
+        content_type: &str,
+        size: usize,
+        mut stream: impl Read + Send,
+    ) -> Result<StoredObject, ServiceError> {
+        let key = self.build_key(user_id, file_key, file_extension);
+
+        if size > self.multipart_threshold {
+            return self
+                .upload_key_multipart(key, content_type, self.public, stream)
+                .await;
+        }
+
+        let mut file_contents = Vec::with_capacity(size);
+        stream
+            .read_to_end(&mut file_contents)
             .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
-        Ok(format!("{}/{}", self.endpoint, combined_key))
+        self.upload_key(key, content_type, self.public, file_contents)
+            .await
     }
 
-    pub async fn delete_file(&self, file_key: &str) -> Result<(), ServiceError> {
-        let request = rusoto_s3::DeleteObjectRequest {
-            bucket: self.bucket.to_string(),
-            key: file_key.to_string(),
-            ..Default::default()
+    async fn upload_key_multipart(
+        &self,
+        key: String,
+        content_type: &str,
+        public: bool,
+        stream: impl Read + Send,
+    ) -> Result<StoredObject, ServiceError> {
+        let upload_id = self
+            .client
+            .create_multipart_upload(&self.bucket, &key, content_type, public)
+            .await?;
+
+        let parts = match self.upload_parts(&key, &upload_id, stream).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                tracing::warn!("Aborting multipart upload {}", &upload_id);
+                self.client
+                    .abort_multipart_upload(&self.bucket, &key, &upload_id)
+                    .await?;
+                return Err(e);
+            }
         };
-        self.client
-            .delete_object(request)
+
+        if let Err(e) = self
+            .client
+            .complete_multipart_upload(&self.bucket, &key, &upload_id, parts)
             .await
-            .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
-        Ok(())
+        {
+            tracing::warn!("Aborting multipart upload {}", &upload_id);
+            self.client
+                .abort_multipart_upload(&self.bucket, &key, &upload_id)
+                .await?;
+            return Err(e);
+        }
+
+        Ok(self.build_stored_object(key))
+    }
+
+    pub fn get_signed_url(&self, file_key: &str, expires_in: Duration) -> String {
+        self.client
+            .presign_get_object(&self.bucket, file_key, expires_in)
+    }
+
+    /// Stored locations are either the object key or its public URL.
+    /// The location a public object stores, the inverse of [`ObjectStorage::get_file_key`].
+    pub fn get_file_url(&self, file_key: &str) -> String {
+        format!("{}/{}", self.endpoint, file_key)
+    }
+
+    pub fn get_file_key(&self, location: &str) -> String {
+        location
+            .strip_prefix(&format!("{}/", self.endpoint))
+            .unwrap_or(location)
+            .to_string()
+    }
+
+    pub async fn get_file(&self, file_key: &str) -> Result<Vec<u8>, ServiceError> {
+        self.client.get_object(&self.bucket, file_key).await
+    }
+
+    pub async fn delete_file(&self, file_key: &str) -> Result<(), ServiceError> {
+        let (client, bucket) = (&self.client, self.bucket.as_str());
+        self.with_retries("delete_object", move || {
+            client.delete_object(bucket, file_key)
+        })
+        .await
+    }
+
+    /// Every object under the prefix, following the listing across pages.
+    pub async fn list_files(&self, prefix: &str) -> Result<Vec<ListedObject>, ServiceError> {
+        let mut files = Vec::<ListedObject>::new();
+        let mut token = None;
+
+        loop {
+            let page = self.list_files_page(prefix, token).await?;
+            files.extend(page.objects);
+
+            match page.next_token {
+                Some(next_token) => token = Some(next_token),
+                None => break,
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// A single page of the listing under the prefix, in key order.
+    pub async fn list_files_page(
+        &self,
+        prefix: &str,
+        token: Option<String>,
+    ) -> Result<ObjectPage, ServiceError> {
+        self.client.list_objects(&self.bucket, prefix, token).await
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut stream: impl Read + Send,
+    ) -> Result<Vec<CompletedPart>, ServiceError> {
+        let mut parts = Vec::<CompletedPart>::new();
+        let mut part_number = 1;
+
+        loop {
+            let mut chunk = Vec::<u8>::with_capacity(self.multipart_threshold);
+            (&mut stream)
+                .take(self.multipart_threshold as u64)
+                .read_to_end(&mut chunk)
+                .map_err(|e| ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(e)))?;
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            parts.push(
+                self.client
+                    .upload_part(&self.bucket, key, upload_id, part_number, chunk)
+                    .await?,
+            );
+            part_number += 1;
+        }
+
+        Ok(parts)
+    }
+
+    fn build_key(&self, user_id: i32, file_key: &impl Display, file_extension: &str) -> String {
+        format!(
+            "{}/{}.{}",
+            self.get_user_prefix(user_id),
+            file_key,
+            file_extension
+        )
+    }
+
+    /// Public objects store their URL, private ones only store the key and are
+    /// signed on demand.
+    fn build_stored_object(&self, key: String) -> StoredObject {
+        let url = if self.public {
+            self.get_file_url(&key)
+        } else {
+            key.clone()
+        };
+        StoredObject { url, key }
     }
 
     pub fn get_user_prefix(&self, user_id: i32) -> String {
@@ -0,0 +1,135 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::rt::time::timeout;
+use anyhow::{anyhow, Error, Result as AnyResult};
+use async_trait::async_trait;
+use reqwest::Client;
+use sha1::{Digest, Sha1};
+
+use crate::common::ServiceError;
+
+use super::PasswordBreachConfig;
+
+pub const BREACHED_PASSWORD: &str =
+    "This password has appeared in a data breach, please choose a different one.";
+
+/// Length of the SHA-1 prefix sent to the range API, the rest of the hash never leaves.
+const PREFIX_LENGTH: usize = 5;
+
+/// Lists the breached password hashes starting with a SHA-1 prefix, as `SUFFIX:COUNT`
+/// lines. Errors are calls that could not complete.
+#[async_trait]
+pub trait PwnedRange: Send + Sync {
+    async fn range(&self, prefix: &str) -> AnyResult<String>;
+}
+
+/// HaveIBeenPwned's k-anonymity range API, padded so the response size does not tell
+/// how many hashes share the prefix.
+pub struct PwnedPasswordsApi {
+    client: Client,
+    url: String,
+}
+
+impl PwnedPasswordsApi {
+    pub fn new(client: Client, url: &str) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl PwnedRange for PwnedPasswordsApi {
+    async fn range(&self, prefix: &str) -> AnyResult<String> {
+        let url = format!("{}/{}", self.url, prefix);
+        let response = self
+            .client
+            .get(&url)
+            .header("Add-Padding", "true")
+            .send()
+            .await?;
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(anyhow!("{} responded with {}", url, status));
+        }
+
+        Ok(response.text().await?)
+    }
+}
+
+/// Rejects passwords seen in breaches. It fails open, a range that can not be fetched
+/// in time lets the password through, and without the check every password passes.
+#[derive(Clone)]
+pub struct BreachChecker {
+    range: Option<Arc<dyn PwnedRange>>,
+    timeout: Duration,
+}
+
+impl BreachChecker {
+    pub fn new(config: &PasswordBreachConfig) -> Self {
+        if !config.enabled {
+            return Self::disabled();
+        }
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .unwrap_or_default();
+        Self::with_range(config, PwnedPasswordsApi::new(client, &config.url))
+    }
+
+    pub fn with_range(config: &PasswordBreachConfig, range: impl PwnedRange + 'static) -> Self {
+        Self {
+            range: Some(Arc::new(range)),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            range: None,
+            timeout: Duration::ZERO,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.range.is_some()
+    }
+
+    pub async fn check(&self, password: &str) -> Result<(), ServiceError> {
+        let Some(range) = &self.range else {
+            return Ok(());
+        };
+        let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+        let error = match timeout(self.timeout, range.range(prefix)).await {
+            Ok(Ok(body)) if is_breached(&body, suffix) => {
+                return Err(ServiceError::bad_request::<Error>(BREACHED_PASSWORD, None));
+            }
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("Breach check timed out after {:?}", self.timeout),
+        };
+
+        tracing::warn!("Letting password through without breach check: {:?}", error);
+        Ok(())
+    }
+}
+
+/// Padding lines have a count of 0, they are not breaches.
+fn is_breached(body: &str, suffix: &str) -> bool {
+    body.lines().any(|line| {
+        line.split_once(':').is_some_and(|(hash, count)| {
+            hash.trim().eq_ignore_ascii_case(suffix)
+                && count.trim().parse::<u64>().is_ok_and(|count| count > 0)
+        })
+    })
+}
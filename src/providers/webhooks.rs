@@ -0,0 +1,152 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::rt;
+use anyhow::{anyhow, Result as AnyResult};
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+use crate::dtos::responses::WebhookEvent;
+
+use super::WebhooksConfig;
+
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const WEBHOOK_EVENT_HEADER: &str = "X-Webhook-Event";
+const MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Notifies the configured endpoints of account lifecycle events. Deliveries run in
+/// their own task, so a slow or failing endpoint never affects the request.
+#[derive(Clone)]
+pub struct Webhooks {
+    client: Client,
+    urls: Arc<Vec<String>>,
+    secret: Secret<String>,
+    retry_delay: Duration,
+}
+
+/// Server errors and failed connections are retried with exponential backoff, client
+/// errors are not as the same request would fail again.
+async fn deliver(
+    client: &Client,
+    url: &str,
+    event: &str,
+    body: &[u8],
+    signature: &str,
+    retry_delay: Duration,
+) -> AnyResult<()> {
+    let mut retries = 0;
+
+    loop {
+        let result = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(WEBHOOK_EVENT_HEADER, event)
+            .header(WEBHOOK_SIGNATURE_HEADER, signature)
+            .body(body.to_vec())
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status().is_server_error() => {
+                anyhow!("{} responded with {}", url, response.status())
+            }
+            Ok(response) => return Err(anyhow!("{} responded with {}", url, response.status())),
+            Err(e) => anyhow!(e),
+        };
+
+        if retries == MAX_RETRIES {
+            return Err(error);
+        }
+
+        tracing::warn!("Retrying webhook delivery: {:?}", error);
+        rt::time::sleep(retry_delay * 2u32.pow(retries)).await;
+        retries += 1;
+    }
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig) -> Self {
+        Self {
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            urls: Arc::new(config.urls.clone()),
+            secret: config.secret.clone(),
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(&WebhooksConfig {
+            urls: Vec::new(),
+            secret: Secret::new(String::new()),
+        })
+    }
+
+    /// Delay before the first retry, doubled on every following one.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.urls.is_empty()
+    }
+
+    /// Value of the signature header, the hex HMAC-SHA256 of the raw body.
+    pub fn sign(&self, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(body);
+        format!("sha256={:x}", mac.finalize().into_bytes())
+    }
+
+    /// Delivers the event to every endpoint in a spawned task and returns at once,
+    /// the handle is only there for callers that need to wait for the deliveries.
+    pub fn dispatch(&self, event: WebhookEvent) -> Option<rt::task::JoinHandle<()>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Failed to serialize webhook event: {:?}", e);
+                return None;
+            }
+        };
+        let webhooks = self.clone();
+        Some(rt::spawn(async move {
+            let signature = webhooks.sign(&body);
+            let deliveries = webhooks.urls.iter().map(|url| {
+                deliver(
+                    &webhooks.client,
+                    url,
+                    event.event.to_str(),
+                    &body,
+                    &signature,
+                    webhooks.retry_delay,
+                )
+            });
+
+            for result in join_all(deliveries).await {
+                if let Err(e) = result {
+                    tracing::error!("Failed to deliver webhook: {:?}", e);
+                }
+            }
+        }))
+    }
+}
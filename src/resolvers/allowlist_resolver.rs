@@ -0,0 +1,42 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Object, Result};
+
+use entities::enums::RoleEnum;
+
+use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+use crate::dtos::objects::Message;
+use crate::guards::{NoImpersonationGuard, RoleGuard};
+use crate::providers::QueryAllowlist;
+
+#[derive(Default)]
+pub struct AllowlistMutation;
+
+#[Object]
+impl AllowlistMutation {
+    /// Re-reads the allow-list file, the current operations stay allowed if it fails.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn reload_query_allowlist(&self, ctx: &Context<'_>) -> Result<Message> {
+        let allowlist = ctx.data::<QueryAllowlist>()?;
+
+        if !allowlist.is_enabled() {
+            return Err(ServiceError::bad_request::<ServiceError>(
+                "The query allow-list is disabled",
+                None,
+            )
+            .into());
+        }
+
+        let operations = allowlist
+            .reload()
+            .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+        Ok(Message::new(&format!(
+            "Query allow-list reloaded with {} operations",
+            operations
+        )))
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Error, Object, Result};
+
+use crate::dtos::objects::{ApiKey, CreatedApiKey, Message};
+use crate::guards::{AuthGuard, NoImpersonationGuard};
+use crate::helpers::AccessUser;
+use crate::providers::Database;
+use crate::services::api_keys_service;
+
+#[derive(Default)]
+pub struct ApiKeysQuery;
+
+#[derive(Default)]
+pub struct ApiKeysMutation;
+
+#[Object]
+impl ApiKeysQuery {
+    #[graphql(guard = "AuthGuard")]
+    async fn my_api_keys(&self, ctx: &Context<'_>) -> Result<Vec<ApiKey>> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(
+            api_keys_service::find_api_keys(ctx.data::<Database>()?, user.id)
+                .await?
+                .into_iter()
+                .map(ApiKey::from)
+                .collect(),
+        )
+    }
+}
+
+#[Object]
+impl ApiKeysMutation {
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn create_api_key(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(min_length = 1, max_length = 50))] name: String,
+        #[graphql(validator(minimum = 1, maximum = 365))] expires_in_days: Option<i32>,
+    ) -> Result<CreatedApiKey> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        let (api_key, key) = api_keys_service::create_api_key(
+            ctx.data::<Database>()?,
+            user.id,
+            &name,
+            expires_in_days.map(i64::from),
+        )
+        .await?;
+        Ok(CreatedApiKey {
+            api_key: api_key.into(),
+            key,
+        })
+    }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn revoke_api_key(&self, ctx: &Context<'_>, id: i32) -> Result<Message> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        api_keys_service::revoke_api_key(ctx.data::<Database>()?, user.id, id).await?;
+        Ok(Message::new("API key revoked successfully"))
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Error, Object, Result};
+
+use crate::dtos::objects::DataExport;
+use crate::guards::{AuthGuard, NoImpersonationGuard};
+use crate::helpers::AccessUser;
+use crate::providers::{Cache, Database, Mailer, ObjectStorage};
+use crate::services::data_export_service;
+
+#[derive(Default)]
+pub struct DataExportQuery;
+
+#[derive(Default)]
+pub struct DataExportMutation;
+
+#[Object]
+impl DataExportQuery {
+    /// The last export of the past 24 hours, with the download link once it is ready.
+    #[graphql(guard = "AuthGuard")]
+    async fn my_data_export(&self, ctx: &Context<'_>) -> Result<Option<DataExport>> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(data_export_service::find_data_export(
+            ctx.data::<Cache>()?,
+            ctx.data::<ObjectStorage>()?,
+            user.id,
+        )
+        .await?)
+    }
+}
+
+#[Object]
+impl DataExportMutation {
+    /// Builds an archive of the account data in the background and emails a link to
+    /// it, once every 24 hours.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn export_my_data(&self, ctx: &Context<'_>) -> Result<DataExport> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(data_export_service::request_data_export(
+            ctx.data::<Database>()?,
+            ctx.data::<Cache>()?,
+            ctx.data::<ObjectStorage>()?,
+            ctx.data::<Mailer>()?,
+            user.id,
+        )
+        .await?)
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Error, Object, Result};
+
+use entities::enums::RoleEnum;
+
+use crate::dtos::objects::{Invitation, Message};
+use crate::guards::{NoImpersonationGuard, RoleGuard};
+use crate::helpers::AccessUser;
+use crate::providers::{Database, Mailer};
+use crate::services::invitations_service;
+
+#[derive(Default)]
+pub struct InvitationsQuery;
+
+#[derive(Default)]
+pub struct InvitationsMutation;
+
+#[Object]
+impl InvitationsQuery {
+    /// Invitations that were neither accepted nor expired yet.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin)")]
+    async fn pending_invitations(&self, ctx: &Context<'_>) -> Result<Vec<Invitation>> {
+        Ok(
+            invitations_service::find_pending_invitations(ctx.data::<Database>()?)
+                .await?
+                .into_iter()
+                .map(Invitation::from)
+                .collect(),
+        )
+    }
+}
+
+#[Object]
+impl InvitationsMutation {
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn invite_user(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(email, min_length = 5, max_length = 200))] email: String,
+    ) -> Result<Invitation> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(invitations_service::invite_user(
+            ctx.data::<Database>()?,
+            ctx.data::<Mailer>()?,
+            user.id,
+            &email,
+        )
+        .await?
+        .into())
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn revoke_invitation(&self, ctx: &Context<'_>, id: i32) -> Result<Message> {
+        invitations_service::revoke_invitation(ctx.data::<Database>()?, id).await?;
+        Ok(Message::new("Invitation revoked successfully"))
+    }
+}
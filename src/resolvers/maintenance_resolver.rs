@@ -0,0 +1,42 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Object, Result};
+use chrono::{DateTime, Utc};
+
+use entities::enums::RoleEnum;
+
+use crate::dtos::objects::MaintenanceMode;
+use crate::guards::{NoImpersonationGuard, RoleGuard};
+use crate::providers::Maintenance;
+
+#[derive(Default)]
+pub struct MaintenanceMutation;
+
+#[Object]
+impl MaintenanceMutation {
+    /// Every other request gets a 503 until maintenance is turned off or `until` passes.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn set_maintenance_mode(
+        &self,
+        ctx: &Context<'_>,
+        enabled: bool,
+        #[graphql(validator(max_length = 500))] message: Option<String>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<MaintenanceMode> {
+        let maintenance = ctx.data::<Maintenance>()?;
+
+        if !enabled {
+            maintenance.disable().await?;
+            return Ok(MaintenanceMode::from(None));
+        }
+
+        let status = maintenance
+            .enable(message, until.map(|until| until.timestamp()))
+            .await?;
+        Ok(MaintenanceMode::from(Some(status)))
+    }
+}
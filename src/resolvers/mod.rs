@@ -4,7 +4,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub mod allowlist_resolver;
+pub mod api_keys_resolver;
+pub mod data_export_resolver;
 pub mod health_resolver;
+pub mod invitations_resolver;
+pub mod maintenance_resolver;
+pub mod node_resolver;
+pub mod oauth_providers_resolver;
+pub mod outbox_resolver;
+pub mod storage_resolver;
 pub mod uploader_resolver;
 pub mod users_resolver;
 
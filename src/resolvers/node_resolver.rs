@@ -0,0 +1,54 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{Context, Object, Result, ID};
+
+use crate::common::{InternalCause, ServiceError};
+use crate::data_loaders::{FileId, SeaOrmLoader};
+use crate::dtos::objects::Node;
+use crate::helpers::GlobalId;
+use crate::providers::{Cache, Database};
+use crate::services::users_service;
+
+use super::users_resolver::check_confirmation;
+
+#[derive(Default)]
+pub struct NodeQuery;
+
+#[Object]
+impl NodeQuery {
+    /// Refetches any object by its global id, ids with an unknown type resolve to null.
+    async fn node(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Node>> {
+        match GlobalId::parse(&id) {
+            Some(GlobalId::User(id)) => {
+                let user = users_service::cached_find_one_by_id(
+                    &ctx.data::<Database>()?.session(),
+                    ctx.data::<Cache>()?,
+                    id,
+                )
+                .await?;
+                Ok(Some(Node::User(check_confirmation(ctx, user)?)))
+            }
+            Some(GlobalId::UploadedFile(id)) => {
+                if let Some(file) = ctx
+                    .data::<DataLoader<SeaOrmLoader>>()?
+                    .load_one(FileId(id))
+                    .await?
+                {
+                    return Ok(Some(Node::UploadedFile(file)));
+                }
+
+                Err(ServiceError::not_found(
+                    "File not found",
+                    Some(InternalCause::new("File not found on dataloader")),
+                )
+                .into())
+            }
+            None => Ok(None),
+        }
+    }
+}
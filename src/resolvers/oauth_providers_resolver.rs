@@ -0,0 +1,69 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{
+    connection::{Connection, Edge, EmptyFields},
+    Context, Object, Result,
+};
+
+use entities::enums::{OAuthProviderEnum, RoleEnum};
+
+use crate::dtos::objects::{ProviderCount, ProviderSignup};
+use crate::guards::RoleGuard;
+use crate::providers::Database;
+use crate::services::oauth_providers_service;
+
+const DEFAULT_SIGNUPS_LIMIT: u64 = 20;
+
+#[derive(Default)]
+pub struct OAuthProvidersQuery;
+
+#[Object]
+impl OAuthProvidersQuery {
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin)")]
+    async fn provider_stats(&self, ctx: &Context<'_>) -> Result<Vec<ProviderCount>> {
+        Ok(
+            oauth_providers_service::count_by_provider(ctx.data::<Database>()?)
+                .await?
+                .into_iter()
+                .map(|(provider, count)| ProviderCount::new(provider, count))
+                .collect(),
+        )
+    }
+
+    /// Newest first, `after` takes the cursor of the last edge read.
+    #[graphql(
+        guard = "RoleGuard::new(RoleEnum::Admin)",
+        complexity = "limit.unwrap_or(DEFAULT_SIGNUPS_LIMIT) as usize * child_complexity"
+    )]
+    async fn recent_provider_signups(
+        &self,
+        ctx: &Context<'_>,
+        provider: OAuthProviderEnum,
+        #[graphql(validator(minimum = 1, maximum = 100))] limit: Option<u64>,
+        #[graphql(validator(
+            min_length = 1,
+            regex = r"^(?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/]{2}==|[A-Za-z0-9+/]{3}=)?$",
+        ))]
+        after: Option<String>,
+    ) -> Result<Connection<String, ProviderSignup, EmptyFields, EmptyFields>> {
+        let has_previous_page = after.is_some();
+        let page = oauth_providers_service::find_recent_signups(
+            ctx.data::<Database>()?,
+            provider,
+            limit.unwrap_or(DEFAULT_SIGNUPS_LIMIT),
+            after,
+        )
+        .await?;
+        let mut connection = Connection::new(has_previous_page, page.has_next_page);
+        connection.edges.extend(
+            page.signups
+                .into_iter()
+                .map(|signup| Edge::new(signup.0.after(), signup.into())),
+        );
+        Ok(connection)
+    }
+}
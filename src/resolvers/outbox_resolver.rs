@@ -0,0 +1,35 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Object, Result};
+
+use entities::enums::RoleEnum;
+
+use crate::dtos::objects::OutboxEmail;
+use crate::guards::RoleGuard;
+use crate::providers::Database;
+use crate::services::outbox_service;
+
+#[derive(Default)]
+pub struct OutboxQuery;
+
+#[Object]
+impl OutboxQuery {
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin)")]
+    async fn failed_emails(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(minimum = 1, maximum = 100))] limit: Option<u64>,
+    ) -> Result<Vec<OutboxEmail>> {
+        Ok(
+            outbox_service::find_failed_emails(ctx.data::<Database>()?, limit)
+                .await?
+                .into_iter()
+                .map(OutboxEmail::from)
+                .collect(),
+        )
+    }
+}
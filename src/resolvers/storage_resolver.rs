@@ -0,0 +1,37 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use async_graphql::{Context, Object, Result};
+
+use entities::enums::RoleEnum;
+
+use crate::common::ServiceError;
+use crate::dtos::objects::StorageGc;
+use crate::guards::{NoImpersonationGuard, RoleGuard};
+use crate::providers::{Cache, Database, ObjectStorage};
+use crate::services::storage_gc_service;
+
+#[derive(Default)]
+pub struct StorageMutation;
+
+#[Object]
+impl StorageMutation {
+    /// Collects orphaned objects and files, a dry run only counts them.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn run_storage_gc(&self, ctx: &Context<'_>, dry_run: bool) -> Result<StorageGc> {
+        storage_gc_service::purge(
+            ctx.data::<Database>()?,
+            ctx.data::<Cache>()?,
+            ctx.data::<ObjectStorage>()?,
+            dry_run,
+        )
+        .await?
+        .ok_or_else(|| {
+            ServiceError::conflict::<ServiceError>("Storage is already being collected", None)
+                .into()
+        })
+    }
+}
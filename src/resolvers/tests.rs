@@ -4,18 +4,44 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::common::format_name;
-use crate::services::users_service;
-use actix_web::{body::to_bytes, test, web::Bytes, App};
-use entities::{enums, user};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use crate::common::{format_name, ServiceError};
+use crate::dtos::{objects, Ratio};
+use crate::helpers::{AccessUser, GlobalId};
+use crate::services::{auth_service, uploader_service, users_service};
+use actix_codec::Framed;
+use actix_http::{
+    body::BodySize,
+    h1::ClientCodec,
+    header::{self, HeaderValue},
+    ws, ConnectionType, Method, RequestHead, RequestHeadType, StatusCode, Uri,
+};
+use actix_web::{body::to_bytes, rt, test, web::Bytes, App, HttpServer};
+use async_graphql::{Request, UploadValue, Variables};
+use async_trait::async_trait;
+use entities::{
+    enums,
+    helpers::{decode_filtered_cursor, encode_cursor},
+    oauth_provider, uploaded_file, user,
+};
 use fake::{faker::name::raw::*, locales::EN, Fake};
+use futures::{SinkExt, StreamExt};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+use rusoto_s3::CompletedPart;
 use sea_orm::{ActiveModelTrait, ModelTrait, Set};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tracing_actix_web::TracingLogger;
 use uuid::Uuid;
 
-const PORT: u16 = 5000;
-const GRAPHQL_PATH: &'static str = "/api/graphql";
+const GRAPHQL_PATH: &str = "/api/graphql";
 
 trait BodyTest {
     fn as_str(&self) -> &str;
@@ -27,38 +53,50 @@ impl BodyTest for Bytes {
     }
 }
 
-use crate::providers::{Cache, Environment, TokenType};
+use crate::providers::{
+    captured_emails, Cache, Config, Environment, GraphQLLimits, Mailer, Maintenance, Metrics,
+    ObjectPage, ObjectStorage, ObjectStorageBackend, ObjectStorageClient, QueryAllowlist,
+    TokenType, Webhooks, UPLOADS_PATH,
+};
 use crate::{
     providers::{Database, Jwt},
-    startup::ActixApp,
+    startup::{
+        build_schema, ActixApp, Providers, PERSISTED_QUERY_HASH_MISMATCH,
+        PERSISTED_QUERY_NOT_FOUND, UNAUTHORIZED_CLOSE_CODE,
+    },
 };
 
-const VALID_PASSWORD: &'static str = "Valid_Password12";
+const VALID_PASSWORD: &str = "Valid_Password12";
 
-async fn create_base_config() -> (Environment, Database, Jwt, Cache) {
+async fn create_base_config() -> (Config, Database, Jwt, Cache) {
     dotenvy::dotenv().expect("Failed to load .env file");
-    let environment = Environment::Development;
-    let db = Database::new()
+    let config =
+        Config::try_with_environment(Environment::Development).expect("Invalid test configuration");
+    let db = Database::new(&config.database)
         .await
         .expect("Failed to connect to database");
-    let jwt = Jwt::new(&environment, &Uuid::new_v4().to_string());
-    let cache = Cache::new();
-    (environment, db, jwt, cache)
+    // Tokens are only accepted by the app when issuer and audience match its own
+    let jwt = Jwt::new(&config.jwt);
+    let cache = Cache::new(&config.cache, &Metrics::new());
+    (config, db, jwt, cache)
 }
 
 async fn create_user(db: &Database, confirm: bool) -> user::Model {
-    let email = format!("{}@gmail.com", Uuid::new_v4().to_string());
+    let email = format!("{}@gmail.com", Uuid::new_v4());
     let first_name: String = Name(EN).fake();
     let last_name: String = Name(EN).fake();
     let date_of_birth = "1990-01-01".to_string();
     let user = users_service::create_user(
-        &db,
+        &db.session(),
+        &Webhooks::disabled(),
         first_name,
         last_name,
-        date_of_birth,
+        Some(date_of_birth),
         email,
         VALID_PASSWORD.to_string(),
         enums::OAuthProviderEnum::Local,
+        users_service::UserPreferences::default(),
+        None,
     )
     .await
     .unwrap();
@@ -76,7 +114,7 @@ async fn create_user(db: &Database, confirm: bool) -> user::Model {
 
 async fn create_token(jwt: &Jwt, user: &user::Model, token_type: Option<TokenType>) -> String {
     if let Some(token_type) = token_type {
-        jwt.generate_email_token(token_type, &user).unwrap()
+        jwt.generate_email_token(token_type, user).unwrap()
     } else {
         jwt.generate_access_token(user).unwrap()
     }
@@ -88,17 +126,15 @@ async fn delete_user(db: &Database, user: user::Model) {
 
 #[actix_web::test]
 async fn test_resolver_health_check() {
-    let (environment, db, _, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": r#"
                 query { 
                     healthCheck { 
@@ -120,12 +156,10 @@ async fn test_resolver_health_check() {
 
 #[actix_web::test]
 async fn test_resolver_users() {
-    let (environment, db, _, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let mut user_vec = Vec::<user::Model>::new();
 
@@ -135,7 +169,7 @@ async fn test_resolver_users() {
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": r#"
                 query { 
                     users(order: ASC, cursor: DATE, limit: 10) {
@@ -181,7 +215,7 @@ async fn test_resolver_users() {
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
                 query {{ 
                     users(order: ASC, cursor: DATE, limit: 10, after: "{}") {{
@@ -233,20 +267,679 @@ async fn test_resolver_users() {
     }
 }
 
+fn users_page_query(search: &str, after: Option<&str>, fields: &str) -> serde_json::Value {
+    let after = after
+        .map(|after| format!(r#", after: "{}""#, after))
+        .unwrap_or_default();
+    json!({
+        "query": format!(r#"
+            query {{
+                users(order: ASC, cursor: DATE, limit: 10, search: "{}"{}) {{
+                    edges {{
+                        cursor
+                    }}
+                    pageInfo {{
+                        hasNextPage
+                        hasPreviousPage
+                        endCursor
+                    }}
+                    {}
+                }}
+            }}
+        "#, search, after, fields),
+    })
+}
+
+fn get_end_cursor(body: &serde_json::Value) -> String {
+    body["data"]["users"]["pageInfo"]["endCursor"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn create_search_users(db: &Database, search: &str, amount: usize) -> Vec<user::Model> {
+    let mut user_vec = Vec::<user::Model>::with_capacity(amount);
+
+    for _ in 0..amount {
+        let mut user: user::ActiveModel = create_user(db, true).await.into();
+        user.last_name = Set(search.to_string());
+        user_vec.push(user.update(db.get_connection()).await.unwrap());
+    }
+
+    user_vec
+}
+
 #[actix_web::test]
-async fn test_resolver_user_by_id() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
+async fn test_resolver_users_pages() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let search = Uuid::new_v4().simple().to_string();
+    let user_vec = create_search_users(&db, &search, 25).await;
+    let mut after = None::<String>;
+
+    // (edges, hasNextPage, hasPreviousPage, totalCount, previousCount) per page
+    for (edges, has_next, has_previous, total_count, previous_count) in [
+        (10, true, false, 25, 0),
+        (10, true, true, 15, 10),
+        (5, false, true, 5, 20),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(users_page_query(
+                &search,
+                after.as_deref(),
+                "totalCount\npreviousCount",
+            ))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let users = &body["data"]["users"];
+        assert_eq!(users["edges"].as_array().unwrap().len(), edges);
+        assert_eq!(users["pageInfo"]["hasNextPage"], has_next);
+        assert_eq!(users["pageInfo"]["hasPreviousPage"], has_previous);
+        assert_eq!(users["totalCount"], total_count);
+        assert_eq!(users["previousCount"], previous_count);
+
+        // Without the counts the page info stays the same
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(users_page_query(&search, after.as_deref(), ""))
+            .to_request();
+        let uncounted_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            uncounted_body["data"]["users"]["edges"],
+            body["data"]["users"]["edges"]
+        );
+        assert_eq!(
+            uncounted_body["data"]["users"]["pageInfo"],
+            body["data"]["users"]["pageInfo"]
+        );
+
+        after = Some(get_end_cursor(&body));
+    }
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+}
+
+#[actix_web::test]
+async fn test_resolver_users_filtered_cursors() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let search = Uuid::new_v4().simple().to_string();
+    let user_vec = create_search_users(&db, &search, 12).await;
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(&search, None, "totalCount"))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let after = get_end_cursor(&body);
+
+    // The cursor belongs to the search it was paged with
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(
+            &Uuid::new_v4().simple().to_string(),
+            Some(&after),
+            "totalCount",
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Cursor does not match the current filters, restart pagination without it"
+    );
+    assert_eq!(body["errors"][0]["extensions"]["code"], "400");
+
+    // Plain cursors from before filter hashes still page
+    let legacy_cursor = encode_cursor(&user_vec[9].id.to_string());
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(
+            &search,
+            Some(&legacy_cursor),
+            "totalCount",
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["users"]["edges"].as_array().unwrap().len(), 2);
+    assert_eq!(body["data"]["users"]["totalCount"], 2);
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+}
+
+fn filtered_users_query(search: &str, filters: &str, after: Option<&str>) -> serde_json::Value {
+    let after = after
+        .map(|after| format!(r#", after: "{}""#, after))
+        .unwrap_or_default();
+    json!({
+        "query": format!(r#"
+            query {{
+                users(order: ASC, cursor: DATE, limit: 2, search: "{}"{}{}) {{
+                    edges {{
+                        node {{
+                            username
+                        }}
+                    }}
+                    pageInfo {{
+                        hasNextPage
+                        endCursor
+                    }}
+                    totalCount
+                }}
+            }}
+        "#, search, filters, after),
+    })
+}
+
+fn usernames(body: &serde_json::Value) -> Vec<String> {
+    body["data"]["users"]["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|edge| edge["node"]["username"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_resolver_users_filters() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let search = Uuid::new_v4().simple().to_string();
+    let march = chrono::NaiveDate::from_ymd_opt(2021, 3, 10)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let june = march + chrono::Duration::days(90);
+    let mut user_vec = Vec::<user::Model>::new();
+
+    // Even users are admins, the first six signed up in March and the rest in June
+    for (i, user) in create_search_users(&db, &search, 8)
+        .await
+        .into_iter()
+        .enumerate()
+    {
+        let mut user: user::ActiveModel = user.into();
+        user.role = Set(if i % 2 == 0 {
+            enums::RoleEnum::Admin
+        } else {
+            enums::RoleEnum::User
+        });
+        user.created_at = Set(if i < 6 { march } else { june });
+        user_vec.push(user.update(db.get_connection()).await.unwrap());
+    }
+    let users_page = |filters: &str, after: Option<&str>| {
+        test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(filtered_users_query(&search, filters, after))
+            .to_request()
+    };
+    let in_march =
+        r#", createdAfter: "2021-03-01T00:00:00Z", createdBefore: "2021-04-01T00:00:00Z""#;
+
+    for (filters, total_count) in [
+        ("", 8),
+        (", role: ADMIN", 4),
+        (", role: USER", 4),
+        (in_march, 6),
+        (r#", createdAfter: "2021-04-01T00:00:00Z""#, 2),
+        (r#", createdBefore: "2021-03-10T12:00:00Z""#, 0),
+    ] {
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, users_page(filters, None)).await;
+        assert_eq!(
+            body["data"]["users"]["totalCount"], total_count,
+            "{}",
+            filters
+        );
+    }
+
+    // Combined filters hold across the page boundary, the cursor can not leave them
+    let filters = format!(", role: ADMIN{}", in_march);
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, users_page(&filters, None)).await;
+    assert_eq!(body["data"]["users"]["totalCount"], 3);
+    assert_eq!(body["data"]["users"]["pageInfo"]["hasNextPage"], true);
+    let mut page_usernames = usernames(&body);
+    let after = get_end_cursor(&body);
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, users_page(&filters, Some(&after))).await;
+    assert_eq!(body["data"]["users"]["totalCount"], 1);
+    assert_eq!(body["data"]["users"]["pageInfo"]["hasNextPage"], false);
+    page_usernames.extend(usernames(&body));
+    let expected = user_vec
+        .iter()
+        .step_by(2)
+        .take(3)
+        .map(|user| user.username.clone())
+        .collect::<Vec<String>>();
+    assert_eq!(page_usernames, expected);
+
+    // An empty or reversed date range is rejected
+    let body: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        users_page(
+            r#", createdAfter: "2021-04-01T00:00:00Z", createdBefore: "2021-03-01T00:00:00Z""#,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "createdAfter must be before createdBefore"
+    );
+    assert_eq!(body["errors"][0]["extensions"]["code"], "400");
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+}
+
+fn users_backward_query(
+    search: &str,
+    before: Option<&str>,
+    last: u64,
+    fields: &str,
+) -> serde_json::Value {
+    let before = before
+        .map(|before| format!(r#", before: "{}""#, before))
+        .unwrap_or_default();
+    json!({
+        "query": format!(r#"
+            query {{
+                users(order: ASC, cursor: DATE, last: {}, search: "{}"{}) {{
+                    edges {{
+                        cursor
+                    }}
+                    pageInfo {{
+                        hasNextPage
+                        hasPreviousPage
+                        startCursor
+                    }}
+                    {}
+                }}
+            }}
+        "#, last, search, before, fields),
+    })
+}
+
+fn get_cursors(body: &serde_json::Value) -> Vec<String> {
+    body["data"]["users"]["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|edge| edge["cursor"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_resolver_users_backward_pages() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let search = Uuid::new_v4().simple().to_string();
+    let user_vec = create_search_users(&db, &search, 25).await;
+    let mut cursors = Vec::<String>::with_capacity(20);
+    let mut after = None::<String>;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(users_page_query(&search, after.as_deref(), ""))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        cursors.extend(get_cursors(&body));
+        after = Some(get_end_cursor(&body));
+    }
+
+    // (before, edges, hasNextPage, hasPreviousPage, totalCount, previousCount) per page,
+    // starting from the middle of the second forward page
+    let mut before = Some(cursors[15].clone());
+    for (expected, has_next, has_previous, total_count, previous_count) in [
+        (&cursors[5..15], true, true, 20, 5),
+        (&cursors[..5], true, false, 25, 0),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(users_backward_query(
+                &search,
+                before.as_deref(),
+                10,
+                "totalCount\npreviousCount",
+            ))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let users = &body["data"]["users"];
+        assert_eq!(get_cursors(&body), expected);
+        assert_eq!(users["pageInfo"]["hasNextPage"], has_next);
+        assert_eq!(users["pageInfo"]["hasPreviousPage"], has_previous);
+        assert_eq!(users["totalCount"], total_count);
+        assert_eq!(users["previousCount"], previous_count);
+
+        // Without the counts the page info stays the same
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(users_backward_query(&search, before.as_deref(), 10, ""))
+            .to_request();
+        let uncounted_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(uncounted_body["data"]["users"], body["data"]["users"]);
+
+        before = users["pageInfo"]["startCursor"]
+            .as_str()
+            .map(|cursor| cursor.to_string());
+    }
+
+    // Without a cursor the last rows are returned
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_backward_query(
+            &search,
+            None,
+            5,
+            "totalCount\npreviousCount",
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let users = &body["data"]["users"];
+    assert_eq!(get_cursors(&body).len(), 5);
+    assert!(!get_cursors(&body)
+        .iter()
+        .any(|cursor| cursors.contains(cursor)));
+    assert_eq!(users["pageInfo"]["hasNextPage"], false);
+    assert_eq!(users["pageInfo"]["hasPreviousPage"], true);
+    assert_eq!(users["totalCount"], 5);
+    assert_eq!(users["previousCount"], 20);
+
+    // Forward and backward arguments can not be mixed
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": format!(
+                r#"query {{ users(order: ASC, cursor: DATE, limit: 10, before: "{}") {{ totalCount }} }}"#,
+                &cursors[15]
+            ),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Use either limit with after or last with before"
+    );
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+}
+
+#[actix_web::test]
+async fn test_resolver_users_query_count() {
+    let (config, mut db, _, _) = create_base_config().await;
+    let queries = Arc::new(AtomicUsize::new(0));
+    let callback_queries = queries.clone();
+    db.set_metric_callback(move |info| {
+        if info.statement.sql.contains("\"users\"") {
+            callback_queries.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    // Counted on both pools in case a replica is configured
+    let callback_queries = queries.clone();
+    db.set_read_metric_callback(move |info| {
+        if info.statement.sql.contains("\"users\"") {
+            callback_queries.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let search = Uuid::new_v4().simple().to_string();
+    let user_vec = create_search_users(&db, &search, 15).await;
+
+    // Only the page select runs without totalCount
+    queries.store(0, Ordering::SeqCst);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(&search, None, ""))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+    // Selecting totalCount adds the count
+    queries.store(0, Ordering::SeqCst);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(&search, None, "totalCount"))
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+    // Later pages check for a previous row instead of counting them
+    queries.store(0, Ordering::SeqCst);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(users_page_query(&search, Some(&get_end_cursor(&body)), ""))
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+    for user in user_vec {
+        delete_user(&db, user).await;
+    }
+}
+
+fn search_users_query(query: &str, limit: u64) -> serde_json::Value {
+    json!({
+        "query": format!(r#"
+            query {{
+                searchUsers(query: "{}", limit: {}) {{
+                    databaseId
+                }}
+            }}
+        "#, query, limit),
+    })
+}
+
+#[actix_web::test]
+async fn test_resolver_search_users() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let base = Uuid::new_v4().simple().to_string()[..12].to_string();
+    let unrelated = Uuid::new_v4().simple().to_string()[..12].to_string();
+    // Closest first: exact, one extra suffix, one misspelled character
+    let names = [
+        base.clone(),
+        format!("{}ab", base),
+        format!("{}x{}", &base[..6], &base[7..]),
+        unrelated,
+    ];
+    let mut users = Vec::<user::Model>::with_capacity(names.len());
+    for name in names {
+        let mut user: user::ActiveModel = create_user(&db, true).await.into();
+        user.last_name = Set(name);
+        users.push(user.update(db.get_connection()).await.unwrap());
+    }
+    let ids = |body: &serde_json::Value| {
+        body["data"]["searchUsers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["databaseId"].as_i64().unwrap() as i32)
+            .collect::<Vec<i32>>()
+    };
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(search_users_query(&base, 10))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(ids(&body), vec![users[0].id, users[1].id, users[2].id]);
+
+    // The limit keeps only the best matches
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(search_users_query(&base, 2))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(ids(&body), vec![users[0].id, users[1].id]);
+
+    // Misspelled queries still find the closest user
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(search_users_query(
+            &format!("{}y{}", &base[..3], &base[4..]),
+            1,
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(ids(&body), vec![users[0].id]);
+
+    for user in users {
+        delete_user(&db, user).await;
+    }
+}
+
+fn persisted_query_uri(hash: &str) -> String {
+    let extensions = json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+    let url = reqwest::Url::parse_with_params(
+        "http://localhost/api/graphql",
+        &[("extensions", extensions.to_string())],
     )
+    .unwrap();
+    format!("{}?{}", GRAPHQL_PATH, url.query().unwrap())
+}
+
+#[actix_web::test]
+async fn test_resolver_persisted_queries() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    // A fresh alias keeps earlier runs from registering the same hash
+    let query = format!(
+        "query {{ h{}: healthCheck {{ message }} }}",
+        Uuid::new_v4().simple()
+    );
+    let hash = format!("{:x}", Sha256::digest(query.as_bytes()));
+
+    // Unknown hashes ask the client for the full query
+    let req = test::TestRequest::get()
+        .uri(&persisted_query_uri(&hash))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["errors"][0]["message"], "PersistedQueryNotFound");
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        PERSISTED_QUERY_NOT_FOUND
+    );
+
+    // Sending the hash with the query registers it
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": &query,
+            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": &hash } },
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["errors"].is_null());
+
+    // Both GET and POST can now send the hash alone
+    let req = test::TestRequest::get()
+        .uri(&persisted_query_uri(&hash))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let get_body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(get_body["errors"].is_null());
+    assert_eq!(get_body, body);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": &hash } },
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let post_body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(post_body, body);
+
+    // The playground is still served on bare GETs
+    let req = test::TestRequest::get().uri(GRAPHQL_PATH).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("GraphQL Playground"));
+}
+
+#[actix_web::test]
+async fn test_resolver_persisted_query_hash_mismatch() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let query = "query { healthCheck { message } }";
+    let hash = format!("{:x}", Sha256::digest(b"query { somethingElse }"));
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": query,
+            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": &hash } },
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        PERSISTED_QUERY_HASH_MISMATCH
+    );
+    assert!(body["data"].is_null());
+
+    // The mismatched hash was not registered
+    let req = test::TestRequest::get()
+        .uri(&persisted_query_uri(&hash))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        PERSISTED_QUERY_NOT_FOUND
+    );
+}
+
+#[actix_web::test]
+async fn test_resolver_user_by_id() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
                 query {{ 
                     userById(id: {}) {{
@@ -285,7 +978,7 @@ async fn test_resolver_user_by_id() {
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
                 query {{ 
                     userById(id: {}) {{
@@ -311,20 +1004,111 @@ async fn test_resolver_user_by_id() {
     delete_user(&db, user).await;
 }
 
+#[actix_web::test]
+async fn test_resolver_user_confirmation() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let mailer = Mailer::new(&config.environment, &config.mailer, &Metrics::new());
+    let user = create_user(&db, false).await;
+    let other_user = create_user(&db, true).await;
+    let query = |id: i32| {
+        json!({
+            "query": format!(r#"
+                query {{
+                    userById(id: {}) {{
+                        databaseId
+                        confirmed
+                        confirmationEmailSentAt
+                    }}
+                }}
+            "#, id),
+        })
+    };
+    let request = |token: &str, id: i32| {
+        test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(query(id))
+            .to_request()
+    };
+    let user_token = create_token(&jwt, &user, None).await;
+    let other_token = create_token(&jwt, &other_user, None).await;
+
+    // The owner sees their own unconfirmed profile, no email sent yet
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, request(&user_token, user.id)).await;
+    assert_eq!(body["data"]["userById"]["databaseId"], user.id);
+    assert_eq!(body["data"]["userById"]["confirmed"], false);
+    assert!(body["data"]["userById"]["confirmationEmailSentAt"].is_null());
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", format!("Bearer {}", user_token)))
+        .set_json(json!({ "query": "query { me { confirmed } }" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["me"]["confirmed"], false);
+
+    // Other users and anonymous callers do not
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, request(&other_token, user.id)).await;
+    assert!(body["data"]["userById"].is_null());
+    assert_eq!(body["errors"][0]["message"], "User not found");
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(query(user.id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "User not found");
+
+    // Confirmation fields stay private on confirmed users too
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, request(&user_token, other_user.id)).await;
+    assert_eq!(body["data"]["userById"]["databaseId"], other_user.id);
+    assert!(body["data"]["userById"]["confirmed"].is_null());
+    assert!(body["data"]["userById"]["confirmationEmailSentAt"].is_null());
+
+    // A resend sets the timestamp, another one within the cooldown leaves it
+    auth_service::resend_confirmation_email(&db.session(), &cache, &jwt, &mailer, &user.email)
+        .await
+        .unwrap();
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, request(&user_token, user.id)).await;
+    let first_sent_at = body["data"]["userById"]["confirmationEmailSentAt"]
+        .as_i64()
+        .unwrap();
+    assert!(first_sent_at <= chrono::Utc::now().timestamp());
+
+    rt::time::sleep(Duration::from_secs(1)).await;
+    auth_service::resend_confirmation_email(&db.session(), &cache, &jwt, &mailer, &user.email)
+        .await
+        .unwrap();
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, request(&user_token, user.id)).await;
+    let second_sent_at = body["data"]["userById"]["confirmationEmailSentAt"]
+        .as_i64()
+        .unwrap();
+    assert_eq!(second_sent_at, first_sent_at);
+
+    delete_user(&db, user).await;
+    delete_user(&db, other_user).await;
+}
+
 #[actix_web::test]
 async fn test_resolver_user_by_username() {
-    let (environment, db, _, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
                 query {{ 
                     userByUsername(username: "{}") {{
@@ -360,18 +1144,16 @@ async fn test_resolver_user_by_username() {
 
 #[actix_web::test]
 async fn test_resolver_me() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
-        .set_json(&json!({
+        .set_json(json!({
             "query": r#"
                 query { 
                     me {
@@ -403,7 +1185,7 @@ async fn test_resolver_me() {
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": r#"
                 query { 
                     me {
@@ -430,42 +1212,134 @@ async fn test_resolver_me() {
 }
 
 #[actix_web::test]
-async fn test_resolver_update_user_name() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+async fn test_resolver_me_cache() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
     let access_token = create_token(&jwt, &user, None).await;
     let bearer_token = format!("Bearer {}", &access_token);
     let authorization_header = ("Authorization", bearer_token.as_str());
+    let me_query = json!({
+        "query": r#"
+            query {
+                me {
+                    id
+                    firstName
+                    lastName
+                    twoFactor
+                }
+            }
+        "#,
+    });
 
-    let first_name: String = Name(EN).fake();
-    let last_name: String = Name(EN).fake();
-
+    // First lookup caches the user, without credentials
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
-            "query": format!(r#"
-                mutation {{
-                    updateUserName(input: {{ firstName: "{}", lastName: "{}" }}) {{
-                        id
-                        firstName
-                        lastName
-                        age
-                        email
-                        createdAt
-                        updatedAt
-                    }}
-                }}
-            "#, &first_name, &last_name),
-        }))
+        .set_json(&me_query)
         .to_request();
-    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["me"]["firstName"], user.first_name.as_str());
+    assert_eq!(body["data"]["me"]["twoFactor"], user.two_factor);
+    let cached = cache
+        .get_json::<serde_json::Value>(&format!("user:{}", user.id))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(cached["first_name"], user.first_name.as_str());
+    assert!(cached.get("password").is_none());
+    assert!(cached.get("two_factor").is_none());
+    assert!(!cached.to_string().contains(&user.password));
+
+    // Change the row behind the cache, second lookup returns the stale value
+    let mut stale_user: user::ActiveModel = user.clone().into();
+    stale_user.first_name = Set("Uncached".to_string());
+    stale_user.update(db.get_connection()).await.unwrap();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(&me_query)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains(&user.first_name));
+    assert!(!body.contains("Uncached"));
+
+    // Updates invalidate the cache
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "query": r#"
+                mutation {
+                    updateUserName(input: { firstName: "Cached", lastName: "User" }) {
+                        id
+                    }
+                }
+            "#,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(&me_query)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("\"firstName\":\"Cached\""));
+    assert!(body.contains("\"lastName\":\"User\""));
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_update_user_name() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let access_token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &access_token);
+    let authorization_header = ("Authorization", bearer_token.as_str());
+
+    let first_name: String = Name(EN).fake();
+    let last_name: String = Name(EN).fake();
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "query": format!(r#"
+                mutation {{
+                    updateUserName(input: {{ firstName: "{}", lastName: "{}" }}) {{
+                        id
+                        firstName
+                        lastName
+                        age
+                        email
+                        createdAt
+                        updatedAt
+                    }}
+                }}
+            "#, &first_name, &last_name),
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
     assert!(&resp.status().is_success());
     let body = to_bytes(resp.into_body())
         .await
@@ -483,7 +1357,7 @@ async fn test_resolver_update_user_name() {
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
             mutation {{
                 updateUserName(input: {{ firstName: "{}", lastName: "{}" }}) {{
@@ -511,26 +1385,119 @@ async fn test_resolver_update_user_name() {
     delete_user(&db, user).await;
 }
 
+#[actix_web::test]
+async fn test_resolver_update_username() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let other_user = create_user(&db, true).await;
+    let access_token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &access_token);
+    let authorization_header = ("Authorization", bearer_token.as_str());
+    let update_username = |username: &str| {
+        json!({
+            "query": format!(r#"
+                mutation {{
+                    updateUsername(username: "{}") {{
+                        id
+                        username
+                    }}
+                }}
+            "#, username),
+        })
+    };
+
+    // Taken usernames are rejected
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(update_username(&other_user.username))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("Username already taken"));
+
+    // Free usernames are set
+    let username = format!("custom.{}", user.id);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(update_username(&username))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains(&format!("\"username\":\"{}\"", &username)));
+
+    // Second change within the cooldown is rejected
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(update_username(&format!("another.{}", user.id)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("Username can only be changed once every 30 days"));
+
+    // Name updates keep the customized username
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(authorization_header)
+        .set_json(json!({
+            "query": r#"
+                mutation {
+                    updateUserName(input: { firstName: "Renamed", lastName: "User" }) {
+                        id
+                        username
+                    }
+                }
+            "#,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains(&format!("\"username\":\"{}\"", &username)));
+
+    delete_user(&db, other_user).await;
+    delete_user(&db, user).await;
+}
+
 #[actix_web::test]
 async fn test_resolver_update_user_email() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
     let access_token = create_token(&jwt, &user, None).await;
     let bearer_token = format!("Bearer {}", &access_token);
     let authorization_header = ("Authorization", bearer_token.as_str());
 
-    let email = format!("{}@gmail.com", Uuid::new_v4().to_string());
+    let email = format!("{}@gmail.com", Uuid::new_v4());
 
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
                 mutation {{
                     updateUserEmail(email: "{}") {{
@@ -565,7 +1532,7 @@ async fn test_resolver_update_user_email() {
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": format!(r#"
             mutation {{
                 updateUserEmail(email: "{}") {{
@@ -595,12 +1562,10 @@ async fn test_resolver_update_user_email() {
 
 #[actix_web::test]
 async fn test_delete_user() {
-    let (environment, db, jwt, _) = create_base_config().await;
-    let app = test::init_service(
-        App::new()
-            .wrap(TracingLogger::default())
-            .configure(ActixApp::build_app_config(environment, PORT, &db)),
-    )
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
     .await;
     let user = create_user(&db, true).await;
     let access_token = create_token(&jwt, &user, None).await;
@@ -610,7 +1575,7 @@ async fn test_delete_user() {
     let req = test::TestRequest::post()
         .uri(GRAPHQL_PATH)
         .insert_header(authorization_header)
-        .set_json(&json!({
+        .set_json(json!({
             "query": r#"
                 mutation {
                     deleteUser {
@@ -633,3 +1598,2681 @@ async fn test_delete_user() {
     assert!(body.contains("message"));
     assert!(body.contains("User deleted successfully"));
 }
+
+#[actix_web::test]
+async fn test_resolver_soft_delete_and_restore_user() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let admin = create_user(&db, true).await;
+    let mut admin: user::ActiveModel = admin.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let user_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let users_query = json!({
+        "query": format!(r#"
+            query {{
+                users(order: DESC, cursor: DATE, limit: 10, search: "{}") {{
+                    edges {{
+                        node {{
+                            id
+                        }}
+                    }}
+                }}
+            }}
+        "#, &user.username),
+    });
+    let user_node = format!("\"id\":{}", user.id);
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", user_token.as_str()))
+        .set_json(json!({ "query": "mutation { deleteUser { message } }" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("User deleted successfully"));
+
+    // The row is kept but hidden from the users connection
+    let deleted_user = user::Entity::find_deleted_by_id(user.id)
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted_user.version, user.version + 1);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(&users_query)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(!to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains(&user_node));
+
+    // Only admins can restore
+    let restore_user = json!({
+        "query": format!("mutation {{ restoreUser(id: {}) {{ id }} }}", user.id),
+    });
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", user_token.as_str()))
+        .set_json(&restore_user)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("\"data\":null"));
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(&restore_user)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains(&user_node));
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(&users_query)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains(&user_node));
+
+    // Users outside the grace period are no longer restorable
+    let mut expired_user: user::ActiveModel = deleted_user.into();
+    expired_user.deleted_at = Set(Some(
+        chrono::Utc::now().naive_utc() - chrono::Duration::days(31),
+    ));
+    let expired_user = expired_user.update(db.get_connection()).await.unwrap();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(&restore_user)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .contains("User not found"));
+
+    delete_user(&db, expired_user).await;
+    delete_user(&db, admin).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_bulk_delete_users() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let mut admins = Vec::new();
+    for _ in 0..2 {
+        let mut admin: user::ActiveModel = create_user(&db, true).await.into();
+        admin.role = Set(enums::RoleEnum::Admin);
+        admins.push(admin.update(db.get_connection()).await.unwrap());
+    }
+    let (admin, other_admin) = (&admins[0], &admins[1]);
+    let spammer = create_user(&db, true).await;
+    let other_spammer = create_user(&db, false).await;
+    let file = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(spammer.id),
+        url: Set(format!("{}.jpg", Uuid::new_v4())),
+        extension: Set("jpg".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let admin_token = format!("Bearer {}", create_token(&jwt, admin, None).await);
+    let missing_id = i32::MAX;
+    let bulk_delete = |dry_run: bool| {
+        json!({
+            "query": r#"
+                mutation BulkDelete($ids: [Int!]!, $dryRun: Boolean!) {
+                    bulkDeleteUsers(ids: $ids, dryRun: $dryRun) {
+                        dryRun
+                        found
+                        missing
+                        skipped { id reason }
+                        uploadedFiles
+                        oauthProviders
+                        results { id deleted error }
+                    }
+                }
+            "#,
+            "variables": {
+                "ids": [admin.id, other_admin.id, spammer.id, other_spammer.id, missing_id],
+                "dryRun": dry_run,
+            },
+        })
+    };
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(bulk_delete(true))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let report = &body["data"]["bulkDeleteUsers"];
+    let mut found = vec![admin.id, other_admin.id, spammer.id, other_spammer.id];
+    found.sort_unstable();
+    assert_eq!(report["dryRun"], json!(true));
+    assert_eq!(report["found"], json!(found));
+    assert_eq!(report["missing"], json!([missing_id]));
+    assert_eq!(report["skipped"].as_array().unwrap().len(), 2);
+    assert!(report["skipped"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|skip| skip["id"] == json!(admin.id) && skip["reason"] == "Cannot delete yourself"));
+    assert!(report["skipped"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|skip| skip["id"] == json!(other_admin.id)));
+    assert_eq!(report["uploadedFiles"], json!(1));
+    assert_eq!(report["oauthProviders"], json!(2));
+    assert_eq!(report["results"], json!([]));
+
+    // Nothing is touched by a dry run
+    for user in [&spammer, &other_spammer] {
+        assert!(user::Entity::find_by_id(user.id)
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .is_some());
+    }
+    assert!(uploaded_file::Entity::find_by_id(&file.id.to_string())
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .is_some());
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(bulk_delete(false))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let report = &body["data"]["bulkDeleteUsers"];
+    assert_eq!(report["skipped"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        report["results"],
+        json!([
+            { "id": spammer.id, "deleted": true, "error": null },
+            { "id": other_spammer.id, "deleted": true, "error": null },
+        ])
+    );
+
+    // Only the regular users are gone, their files and sign in methods wait for the purge
+    for user in [&spammer, &other_spammer] {
+        assert!(user::Entity::find_deleted_by_id(user.id)
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(oauth_provider::Entity::find_by_email(&user.email)
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .is_some());
+    }
+    for admin in &admins {
+        assert!(user::Entity::find_by_id(admin.id)
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .is_some());
+    }
+    assert!(uploaded_file::Entity::find_by_id(&file.id.to_string())
+        .one(db.get_connection())
+        .await
+        .unwrap()
+        .is_some());
+
+    for user in [spammer, other_spammer] {
+        let user = user::Entity::find_deleted_by_id(user.id)
+            .one(db.get_connection())
+            .await
+            .unwrap()
+            .unwrap();
+        delete_user(&db, user).await;
+    }
+    for admin in admins {
+        delete_user(&db, admin).await;
+    }
+}
+
+#[actix_web::test]
+async fn test_resolver_update_user_role() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let admin = create_user(&db, true).await;
+    let mut admin: user::ActiveModel = admin.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let update_role = |id: i32, role: &str| {
+        json!({
+            "query": format!(r#"
+                mutation {{
+                    updateUserRole(id: {}, role: {}) {{
+                        id
+                        role
+                    }}
+                }}
+            "#, id, role),
+        })
+    };
+
+    // USER role is rejected by the guard
+    let user_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", user_token.as_str()))
+        .set_json(update_role(user.id, "ADMIN"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("Forbidden"));
+    let unchanged = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(unchanged.role, enums::RoleEnum::User);
+
+    // ADMIN can change roles
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let admin_header = ("Authorization", admin_token.as_str());
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(admin_header)
+        .set_json(update_role(user.id, "STAFF"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("STAFF"));
+    let updated = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated.role, enums::RoleEnum::Staff);
+    assert_eq!(updated.version, unchanged.version + 1);
+
+    // The last admin cannot be demoted
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(admin_header)
+        .set_json(update_role(admin.id, "USER"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("Cannot demote the last admin"));
+    let admin = users_service::find_one_by_id(&db.session(), admin.id)
+        .await
+        .unwrap();
+    assert_eq!(admin.role, enums::RoleEnum::Admin);
+
+    delete_user(&db, updated).await;
+    delete_user(&db, admin).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_query_limits() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+
+    // Over-deep query
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": r#"
+                query {
+                    users(order: ASC, cursor: DATE, limit: 1) {
+                        edges {
+                            node {
+                                picture {
+                                    user {
+                                        picture {
+                                            user {
+                                                picture {
+                                                    id
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            "#
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("\"errors\""));
+    assert!(body.contains("Query is nested too deep."));
+
+    // Over-complex query
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": r#"
+                query {
+                    users(order: ASC, cursor: DATE, limit: 100) {
+                        edges {
+                            node {
+                                id
+                                firstName
+                                lastName
+                                picture {
+                                    id
+                                    url
+                                }
+                            }
+                            cursor
+                        }
+                    }
+                }
+            "#
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(&resp.status().is_success());
+    let body = to_bytes(resp.into_body())
+        .await
+        .unwrap()
+        .as_str()
+        .to_owned();
+    assert!(body.contains("\"errors\""));
+    assert!(body.contains("Query is too complex."));
+}
+
+#[actix_web::test]
+async fn test_resolver_introspection_disabled_in_production() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let introspection_query = r#"
+        query {
+            __schema {
+                queryType {
+                    name
+                }
+            }
+        }
+    "#;
+
+    let schema = build_schema(
+        &Environment::Production,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &config.object_storage),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let body = serde_json::to_string(&schema.execute(introspection_query).await).unwrap();
+    assert!(!body.contains("QueryRoot"));
+
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &config.object_storage),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let body = serde_json::to_string(&schema.execute(introspection_query).await).unwrap();
+    assert!(body.contains("QueryRoot"));
+}
+
+#[actix_web::test]
+async fn test_resolver_file_url_visibility() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let key = format!("{}/{}.jpg", Uuid::new_v4(), Uuid::new_v4());
+    let private_file = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(key.clone()),
+        extension: Set("jpg".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let public_url = format!("http://localhost:4566/test/{}", &key);
+    let public_file = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(public_url.clone()),
+        extension: Set("jpg".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let file_query = |id: Uuid| format!(r#"query {{ fileById(id: "{}") {{ url }} }}"#, id);
+
+    // Private buckets sign the stored key on demand
+    let mut object_storage = config.object_storage.clone();
+    object_storage.public = false;
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &object_storage),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let body = serde_json::to_string(&schema.execute(file_query(private_file.id)).await).unwrap();
+    assert!(body.contains(&key));
+    assert!(body.contains("X-Amz-Signature"));
+    assert!(body.contains("X-Amz-Expires=900"));
+    let memoized_body =
+        serde_json::to_string(&schema.execute(file_query(private_file.id)).await).unwrap();
+    assert_eq!(body, memoized_body);
+
+    // Public buckets return the stored url
+    object_storage.public = true;
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &object_storage),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let body = serde_json::to_string(&schema.execute(file_query(public_file.id)).await).unwrap();
+    assert!(body.contains(&format!("\"url\":\"{}\"", &public_url)));
+    assert!(!body.contains("X-Amz-Signature"));
+
+    delete_user(&db, user).await;
+}
+
+const STORAGE_ENDPOINT: &str = "http://localhost:4566/test";
+
+#[derive(Clone, Default)]
+struct RecordingClient {
+    calls: Arc<Mutex<Vec<String>>>,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl RecordingClient {
+    fn calls(&self, prefix: &str) -> Vec<String> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|call| call.strip_prefix(prefix).map(str::to_string))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ObjectStorageClient for RecordingClient {
+    async fn put_object(
+        &self,
+        _: &str,
+        key: &str,
+        content_type: &str,
+        _: bool,
+        body: Vec<u8>,
+    ) -> Result<(), ServiceError> {
+        let mut calls = self.calls.lock().unwrap();
+        calls.push(format!("put:{}", key));
+        calls.push(format!("type:{} {}", key, content_type));
+        self.objects.lock().unwrap().insert(key.to_string(), body);
+        Ok(())
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: bool,
+    ) -> Result<String, ServiceError> {
+        unreachable!("renditions are below the multipart threshold")
+    }
+
+    async fn upload_part(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: i64,
+        _: Vec<u8>,
+    ) -> Result<CompletedPart, ServiceError> {
+        unreachable!("renditions are below the multipart threshold")
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: Vec<CompletedPart>,
+    ) -> Result<(), ServiceError> {
+        unreachable!("renditions are below the multipart threshold")
+    }
+
+    async fn abort_multipart_upload(&self, _: &str, _: &str, _: &str) -> Result<(), ServiceError> {
+        unreachable!("renditions are below the multipart threshold")
+    }
+
+    async fn get_object(&self, _: &str, key: &str) -> Result<Vec<u8>, ServiceError> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ServiceError::not_found::<ServiceError>("Object not found", None))
+    }
+
+    async fn delete_object(&self, _: &str, key: &str) -> Result<(), ServiceError> {
+        self.calls.lock().unwrap().push(format!("delete:{}", key));
+        Ok(())
+    }
+
+    async fn list_objects(
+        &self,
+        _: &str,
+        _: &str,
+        _: Option<String>,
+    ) -> Result<ObjectPage, ServiceError> {
+        Ok(ObjectPage::default())
+    }
+
+    fn presign_get_object(&self, _: &str, key: &str, _: Duration) -> String {
+        key.to_string()
+    }
+}
+
+#[actix_web::test]
+async fn test_resolver_picture_sizes() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    );
+
+    let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(400, 300, |x, y| {
+        Rgb([x as u8, y as u8, 128])
+    }));
+    let renditions = uploader_service::process_image(image, Ratio::Square).unwrap();
+    let dimensions = renditions
+        .iter()
+        .map(|(_, data)| image::load_from_memory(data).unwrap().dimensions())
+        .collect::<Vec<_>>();
+    assert_eq!(dimensions, vec![(64, 64), (256, 256), (300, 300)]);
+
+    let file =
+        uploader_service::store_image(&db, &object_storage, &Metrics::new(), user.id, renditions)
+            .await
+            .unwrap();
+    let prefix = object_storage.get_user_prefix(user.id);
+    let hash = file.content_hash.clone().unwrap();
+    let original_key = format!("{}/{}.jpg", &prefix, &hash);
+    let small_key = format!("{}/{}_64.jpg", &prefix, &hash);
+    let medium_key = format!("{}/{}_256.jpg", &prefix, &hash);
+    assert_eq!(
+        client.calls("put:"),
+        vec![small_key.clone(), medium_key.clone(), original_key.clone()]
+    );
+    assert_eq!(uploader_service::file_sizes(&file).len(), 2);
+
+    let mut active_user: user::ActiveModel = user.into();
+    active_user.picture = Set(Some(file.id));
+    let user = active_user.update(db.get_connection()).await.unwrap();
+
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage.clone(),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let query = format!(
+        r#"
+            query {{
+                fileById(id: "{}") {{
+                    original: url
+                    small: url(size: SMALL)
+                    medium: url(size: MEDIUM)
+                }}
+                userById(id: {}) {{
+                    picture(size: SMALL) {{
+                        url
+                        original: url(size: ORIGINAL)
+                    }}
+                }}
+            }}
+        "#,
+        file.id, user.id
+    );
+    let body = serde_json::to_value(schema.execute(query).await).unwrap();
+    let url = |key: &str| json!(format!("{}/{}", STORAGE_ENDPOINT, key));
+    assert_eq!(body["data"]["fileById"]["original"], url(&original_key));
+    assert_eq!(body["data"]["fileById"]["small"], url(&small_key));
+    assert_eq!(body["data"]["fileById"]["medium"], url(&medium_key));
+    assert_eq!(body["data"]["userById"]["picture"]["url"], url(&small_key));
+    assert_eq!(
+        body["data"]["userById"]["picture"]["original"],
+        url(&original_key)
+    );
+
+    uploader_service::delete_file_objects(&db, &object_storage, &file)
+        .await
+        .unwrap();
+    let mut deleted = client.calls("delete:");
+    deleted.sort();
+    let mut expected = vec![original_key, small_key, medium_key];
+    expected.sort();
+    assert_eq!(deleted, expected);
+
+    delete_user(&db, user).await;
+}
+
+fn gradient_renditions(blue: u8) -> uploader_service::Renditions {
+    let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(300, 300, |x, y| {
+        Rgb([x as u8, y as u8, blue])
+    }));
+    uploader_service::process_image(image, Ratio::Square).unwrap()
+}
+
+#[actix_web::test]
+async fn test_store_image_deduplication() {
+    let (_, db, _, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    );
+    let metrics = Metrics::new();
+
+    let first = uploader_service::store_image(
+        &db,
+        &object_storage,
+        &metrics,
+        user.id,
+        gradient_renditions(128),
+    )
+    .await
+    .unwrap();
+    let again = uploader_service::store_image(
+        &db,
+        &object_storage,
+        &metrics,
+        user.id,
+        gradient_renditions(128),
+    )
+    .await
+    .unwrap();
+    assert_eq!(again.id, first.id);
+    let hash = first.content_hash.clone().unwrap();
+    assert_eq!(hash.len(), 64);
+    let original_key = format!("{}/{}.jpg", object_storage.get_user_prefix(user.id), &hash);
+    let original_puts = client
+        .calls("put:")
+        .into_iter()
+        .filter(|key| key == &original_key)
+        .count();
+    assert_eq!(original_puts, 1);
+    assert_eq!(client.calls("put:").len(), 3);
+
+    let other = uploader_service::store_image(
+        &db,
+        &object_storage,
+        &metrics,
+        user.id,
+        gradient_renditions(0),
+    )
+    .await
+    .unwrap();
+    assert_ne!(other.id, first.id);
+    assert_ne!(other.content_hash, first.content_hash);
+    assert_eq!(client.calls("put:").len(), 6);
+
+    // A row sharing the objects keeps them until it is deleted as well
+    let shared = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(first.url.clone()),
+        extension: Set("jpg".to_string()),
+        sizes: Set(first.sizes.clone()),
+        content_hash: Set(first.content_hash.clone()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    uploader_service::delete_file_objects(&db, &object_storage, &first)
+        .await
+        .unwrap();
+    assert!(client.calls("delete:").is_empty());
+    first.delete(db.get_connection()).await.unwrap();
+    uploader_service::delete_file_objects(&db, &object_storage, &shared)
+        .await
+        .unwrap();
+    assert_eq!(client.calls("delete:").len(), 3);
+    assert!(client.calls("delete:").contains(&original_key));
+
+    let disabled = object_storage.with_deduplication(false);
+    let copy =
+        uploader_service::store_image(&db, &disabled, &metrics, user.id, gradient_renditions(0))
+            .await
+            .unwrap();
+    assert_ne!(copy.id, other.id);
+    assert_eq!(copy.content_hash, None);
+    assert!(copy.url.contains(&copy.id.to_string()));
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resized_images() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    );
+    let file = uploader_service::store_image(
+        &db,
+        &object_storage,
+        &Metrics::new(),
+        user.id,
+        gradient_renditions(64),
+    )
+    .await
+    .unwrap();
+    let hash = file.content_hash.clone().unwrap();
+    let prefix = object_storage.get_user_prefix(user.id);
+    let resized_key = format!("{}/{}_w128.jpg", &prefix, &hash);
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(
+            &config,
+            &db,
+            &Providers::new(&config, &Metrics::new()).with_object_storage(object_storage.clone()),
+        ),
+    ))
+    .await;
+    let get_image = |width: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/images/{}?w={}", file.id, width))
+            .to_request()
+    };
+
+    // The first request resizes and stores the snapped width
+    let resp = test::call_service(&app, get_image("100")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/jpeg"
+    );
+    assert!(resp
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("immutable"));
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(
+        image::load_from_memory(&body).unwrap().dimensions(),
+        (128, 128)
+    );
+    assert_eq!(client.calls("put:").len(), 4);
+    assert!(client.calls("put:").contains(&resized_key));
+
+    // The second one is served from the stored variant
+    let resp = test::call_service(&app, get_image("120")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), body);
+    assert_eq!(client.calls("put:").len(), 4);
+
+    for width in ["2000", "16", "abc"] {
+        let resp = test::call_service(&app, get_image(width)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(client.calls("put:").len(), 4);
+
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage.clone(),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let url_query = |width: i32| {
+        format!(
+            r#"query {{ fileById(id: "{}") {{ url(width: {}) }} }}"#,
+            file.id, width
+        )
+    };
+    let body = serde_json::to_value(schema.execute(url_query(100)).await).unwrap();
+    assert_eq!(
+        body["data"]["fileById"]["url"],
+        format!("/api/images/{}?w=128", file.id)
+    );
+    let body = serde_json::to_value(schema.execute(url_query(4096)).await).unwrap();
+    assert_eq!(body["errors"][0]["extensions"]["code"], "400");
+
+    // A private bucket only serves the owner or a signed URL, and nothing is cached
+    let private_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        false,
+    );
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(
+            &config,
+            &db,
+            &Providers::new(&config, &Metrics::new()).with_object_storage(private_storage.clone()),
+        ),
+    ))
+    .await;
+    let resp = test::call_service(&app, get_image("128")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let signed_url = uploader_service::resized_image_url(&private_storage, &file.id, 128);
+    assert!(signed_url.contains("&sig="));
+    let resp =
+        test::call_service(&app, test::TestRequest::get().uri(&signed_url).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cache_control = resp
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(cache_control.contains("private") && cache_control.contains("no-store"));
+    assert!(!cache_control.contains("public"));
+    for tampered in [
+        signed_url.replace("w=128", "w=256"),
+        signed_url.replace("&exp=", "&exp=1"),
+    ] {
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri(&tampered).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    let other_user = create_user(&db, true).await;
+    for (viewer, status) in [
+        (&user, StatusCode::OK),
+        (&other_user, StatusCode::NOT_FOUND),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/images/{}?w=128", file.id))
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", create_token(&jwt, viewer, None).await),
+            ))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), status);
+    }
+
+    delete_user(&db, other_user).await;
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_filesystem_storage_backend() {
+    let (mut config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let uploads = tempfile::tempdir().unwrap();
+    config.object_storage.backend = ObjectStorageBackend::Filesystem;
+    config.object_storage.local_path = uploads.path().to_path_buf();
+    config.object_storage.local_url = format!("http://localhost:5000{}", UPLOADS_PATH);
+    let object_storage = ObjectStorage::new(&config.environment, &config.object_storage);
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage.clone(),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+
+    let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(300, 300, |x, y| {
+        Rgb([x as u8, y as u8, 32])
+    }));
+    let mut picture = tempfile::tempfile().unwrap();
+    image
+        .write_to(&mut picture, image::ImageOutputFormat::Png)
+        .unwrap();
+    picture.seek(SeekFrom::Start(0)).unwrap();
+    let mut request = Request::new(
+        "mutation ($picture: Upload!) { updateUserPicture(picture: $picture) { picture(size: ORIGINAL) { databaseId url } } }",
+    )
+    .variables(Variables::from_json(json!({ "picture": null })))
+    .data(Some(AccessUser::new(user.id, user.role)));
+    request.set_upload(
+        "variables.picture",
+        UploadValue {
+            filename: "picture.png".to_string(),
+            content_type: Some("image/png".to_string()),
+            content: picture,
+        },
+    );
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert!(body["errors"].is_null(), "{}", body);
+    let picture = &body["data"]["updateUserPicture"]["picture"];
+    let url = picture["url"].as_str().unwrap();
+    let path = url.strip_prefix("http://localhost:5000").unwrap();
+    let prefix = object_storage.get_user_prefix(user.id);
+    assert!(path.starts_with(&format!("{}/{}/", UPLOADS_PATH, prefix)));
+    assert!(uploads
+        .path()
+        .join(&path[UPLOADS_PATH.len() + 1..])
+        .is_file());
+
+    // The static route serves the stored file back
+    let req = test::TestRequest::get().uri(path).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/jpeg"
+    );
+    let served = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(
+        image::load_from_memory(&served).unwrap().dimensions(),
+        (300, 300)
+    );
+    let req = test::TestRequest::get()
+        .uri(&format!("{}/{}/.multipart", UPLOADS_PATH, prefix))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Deleting removes every rendition from disk
+    let file = uploader_service::find_one_by_id(&db, picture["databaseId"].as_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(object_storage.list_files(&prefix).await.unwrap().len(), 3);
+    let mut active_user: user::ActiveModel = user.into();
+    active_user.picture = Set(None);
+    let user = active_user.update(db.get_connection()).await.unwrap();
+    uploader_service::delete_file_objects(&db, &object_storage, &file)
+        .await
+        .unwrap();
+    assert!(object_storage.list_files(&prefix).await.unwrap().is_empty());
+    let req = test::TestRequest::get().uri(path).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    file.delete(db.get_connection()).await.unwrap();
+    delete_user(&db, user).await;
+}
+
+fn document_request(
+    user: &user::Model,
+    filename: &str,
+    content_type: &str,
+    contents: &[u8],
+) -> Request {
+    let mut content = tempfile::tempfile().unwrap();
+    content.write_all(contents).unwrap();
+    content.seek(SeekFrom::Start(0)).unwrap();
+    let mut request = Request::new(
+        "mutation ($file: Upload!) { uploadDocument(file: $file) { databaseId extension kind url sizeBytes } }",
+    )
+    .variables(Variables::from_json(json!({ "file": null })))
+    .data(Some(AccessUser::new(user.id, user.role)));
+    request.set_upload(
+        "variables.file",
+        UploadValue {
+            filename: filename.to_string(),
+            content_type: Some(content_type.to_string()),
+            content,
+        },
+    );
+    request
+}
+
+#[actix_web::test]
+async fn test_resolver_upload_document() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    );
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage.clone(),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let prefix = object_storage.get_user_prefix(user.id);
+
+    // Documents keep their extension and content type
+    let pdf = [b"%PDF-1.4\n".as_slice(), &[b'%'; 100], b"\n%%EOF\n"].concat();
+    let request = document_request(&user, "cv.pdf", "application/pdf", &pdf);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    let document = &body["data"]["uploadDocument"];
+    assert_eq!(document["sizeBytes"], json!(pdf.len()));
+    let key = format!(
+        "{}/{}.pdf",
+        &prefix,
+        document["databaseId"].as_str().unwrap()
+    );
+    assert_eq!(document["extension"], "pdf");
+    assert_eq!(document["kind"], "DOCUMENT");
+    assert_eq!(document["url"], format!("{}/{}", STORAGE_ENDPOINT, &key));
+    assert_eq!(client.calls("put:"), vec![key.clone()]);
+    assert_eq!(
+        client.calls("type:"),
+        vec![format!("{} application/pdf", &key)]
+    );
+
+    // Extensions contradicting a known type are replaced
+    let request = document_request(
+        &user,
+        "notes.exe",
+        "text/plain; charset=utf-8",
+        &b"notes ".repeat(20),
+    );
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["data"]["uploadDocument"]["extension"], "txt");
+
+    let request = document_request(
+        &user,
+        "setup.exe",
+        "application/vnd.microsoft.portable-executable",
+        b"MZ",
+    );
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["errors"][0]["message"], "File type is not allowed");
+
+    let contents = vec![b'a'; uploader_service::document_max_size("text/plain") as usize + 1];
+    let request = document_request(&user, "large.txt", "text/plain", &contents);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["errors"][0]["message"], "File is too large");
+
+    // Empty and tiny payloads are rejected before reaching the bucket
+    let request = document_request(&user, "empty.txt", "text/plain", b"");
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["errors"][0]["message"], "File is empty");
+    let request = document_request(&user, "tiny.txt", "text/plain", b"hi");
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["errors"][0]["message"], "File is too small");
+
+    // Only the two accepted documents reached the bucket
+    assert_eq!(client.calls("put:").len(), 2);
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_storage_quota() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    )
+    .with_user_quota(1000);
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage,
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let usage = || {
+        let request = Request::new("{ myStorageUsage { usedBytes limitBytes } }")
+            .data(Some(AccessUser::new(user.id, user.role)));
+        let schema = schema.clone();
+        async move {
+            let body = serde_json::to_value(schema.execute(request).await).unwrap();
+            body["data"]["myStorageUsage"].clone()
+        }
+    };
+    assert_eq!(usage().await, json!({ "usedBytes": 0, "limitBytes": 1000 }));
+
+    // Files stored before sizes were recorded count as empty
+    uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(format!("{}/legacy.jpg", STORAGE_ENDPOINT)),
+        extension: Set("jpg".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let request = document_request(&user, "a.txt", "text/plain", &[b'a'; 600]);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert!(body["errors"].is_null());
+    let request = document_request(&user, "b.txt", "text/plain", &[b'b'; 300]);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert!(body["errors"].is_null());
+    assert_eq!(
+        usage().await,
+        json!({ "usedBytes": 900, "limitBytes": 1000 })
+    );
+
+    // The next file would cross the quota
+    let request = document_request(&user, "c.txt", "text/plain", &[b'c'; 101]);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert_eq!(body["errors"][0]["message"], "Storage quota exceeded");
+    assert_eq!(body["errors"][0]["extensions"]["code"], "403");
+    assert_eq!(client.calls("put:").len(), 2);
+    let request = document_request(&user, "d.txt", "text/plain", &[b'd'; 100]);
+    let body = serde_json::to_value(schema.execute(request).await).unwrap();
+    assert!(body["errors"].is_null());
+    assert_eq!(
+        usage().await,
+        json!({ "usedBytes": 1000, "limitBytes": 1000 })
+    );
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_age_privacy() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let owner = create_user(&db, true).await;
+    let other = create_user(&db, true).await;
+    let owner_token = format!("Bearer {}", create_token(&jwt, &owner, None).await);
+    let other_token = format!("Bearer {}", create_token(&jwt, &other, None).await);
+    let age_query = json!({
+        "query": format!("query {{ userById(id: {}) {{ age }} }}", owner.id),
+    });
+
+    // Hidden by default for everyone but the owner
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", other_token.as_str()))
+        .set_json(&age_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["userById"]["age"].is_null());
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", owner_token.as_str()))
+        .set_json(&age_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["userById"]["age"].is_u64());
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", owner_token.as_str()))
+        .set_json(json!({
+            "query": "mutation { updatePrivacySettings(input: { showAge: true }) { showAge } }",
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["updatePrivacySettings"]["showAge"], true);
+
+    // Visible to other users once shown
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", other_token.as_str()))
+        .set_json(&age_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["userById"]["age"].is_u64());
+
+    // A stored date of birth in the future is a null age, not an error
+    let mut future_owner: user::ActiveModel = owner.clone().into();
+    future_owner.date_of_birth = Set(chrono::NaiveDate::from_ymd_opt(2090, 1, 1));
+    future_owner.update(db.get_connection()).await.unwrap();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", other_token.as_str()))
+        .set_json(&age_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+    assert!(body["data"]["userById"]["age"].is_null());
+
+    delete_user(&db, owner).await;
+    delete_user(&db, other).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_api_keys() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let me_query = json!({ "query": "query { me { databaseId } }" });
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "query": r#"
+                mutation {
+                    createApiKey(name: "ci", expiresInDays: 30) {
+                        key
+                        apiKey {
+                            id
+                            prefix
+                        }
+                    }
+                }
+            "#,
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let created = &body["data"]["createApiKey"];
+    let key = created["key"].as_str().unwrap().to_string();
+    let id = created["apiKey"]["id"].as_i64().unwrap();
+    assert!(key.starts_with(&format!(
+        "rgt_{}_",
+        created["apiKey"]["prefix"].as_str().unwrap()
+    )));
+    let api_key_header = format!("ApiKey {}", &key);
+
+    // The key authenticates as its owner
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", api_key_header.as_str()))
+        .set_json(&me_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"]["me"]["databaseId"].as_i64().unwrap(),
+        user.id as i64
+    );
+
+    // Listed without the secret, with its last use recorded
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({ "query": "query { myApiKeys { id name lastUsedAt expiresAt } }" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let keys = body["data"]["myApiKeys"].as_array().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["id"].as_i64().unwrap(), id);
+    assert_eq!(keys[0]["name"], "ci");
+    assert!(keys[0]["lastUsedAt"].is_string());
+    assert!(keys[0]["expiresAt"].is_string());
+    assert!(!body.to_string().contains(&key));
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "query": format!("mutation {{ revokeApiKey(id: {}) {{ message }} }}", id),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+
+    // Revoked keys are rejected by both GraphQL and REST
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", api_key_header.as_str()))
+        .set_json(&me_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "Unauthorized");
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users/export")
+        .insert_header(("Authorization", api_key_header.as_str()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    delete_user(&db, user).await;
+}
+
+fn impersonate_user_query(id: i32) -> serde_json::Value {
+    json!({
+        "query": format!(
+            "mutation {{ impersonateUser(id: {}) {{ accessToken tokenType expiresIn }} }}",
+            id
+        ),
+    })
+}
+
+#[actix_web::test]
+async fn test_resolver_impersonate_user() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let mut admin: user::ActiveModel = create_user(&db, true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let target = create_user(&db, true).await;
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let target_token = format!("Bearer {}", create_token(&jwt, &target, None).await);
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(impersonate_user_query(target.id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let impersonation = &body["data"]["impersonateUser"];
+    assert_eq!(impersonation["tokenType"], "Bearer");
+    assert!(impersonation["expiresIn"].as_i64().unwrap() <= 600);
+    let impersonation_token = format!("Bearer {}", impersonation["accessToken"].as_str().unwrap());
+
+    // The session reads as the target user
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", impersonation_token.as_str()))
+        .set_json(json!({ "query": "query { me { databaseId } }" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"]["me"]["databaseId"].as_i64().unwrap(),
+        target.id as i64
+    );
+
+    // But can not write
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", impersonation_token.as_str()))
+        .set_json(json!({
+            "query": format!(
+                r#"mutation {{ updateUserEmail(email: "{}@gmail.com") {{ id }} }}"#,
+                Uuid::new_v4()
+            ),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "Forbidden");
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", impersonation_token.as_str()))
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": "Other_Password12",
+            "password2": "Other_Password12",
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Only admins can impersonate
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", target_token.as_str()))
+        .set_json(impersonate_user_query(admin.id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "Forbidden");
+    assert!(body["data"]["impersonateUser"].is_null());
+
+    // And never other admins
+    let mut other_admin: user::ActiveModel = create_user(&db, true).await.into();
+    other_admin.role = Set(enums::RoleEnum::Admin);
+    let other_admin = other_admin.update(db.get_connection()).await.unwrap();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(impersonate_user_query(other_admin.id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "You can not impersonate an admin"
+    );
+    assert!(body["data"]["impersonateUser"].is_null());
+
+    delete_user(&db, admin).await;
+    delete_user(&db, other_admin).await;
+    delete_user(&db, target).await;
+}
+
+type WsClient = Framed<rt::net::TcpStream, ws::Codec>;
+
+fn start_server(config: &Config, db: &Database) -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (config, db) = (config.clone(), db.clone());
+    let server = HttpServer::new(move || {
+        App::new().configure(ActixApp::build_app_config(
+            &config,
+            &db,
+            &Providers::new(&config, &Metrics::new()),
+        ))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    rt::spawn(server);
+    port
+}
+
+async fn connect_ws(port: u16) -> WsClient {
+    let stream = rt::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let mut framed = Framed::new(stream, ClientCodec::default());
+    let mut head = RequestHead::default();
+    head.method = Method::GET;
+    head.uri = Uri::from_static(GRAPHQL_PATH);
+    head.set_connection_type(ConnectionType::Upgrade);
+    for (name, value) in [
+        (header::HOST, "localhost"),
+        (header::UPGRADE, "websocket"),
+        (header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="),
+        (header::SEC_WEBSOCKET_VERSION, "13"),
+        (header::SEC_WEBSOCKET_PROTOCOL, "graphql-transport-ws"),
+    ] {
+        head.headers.insert(name, HeaderValue::from_static(value));
+    }
+    framed
+        .send((RequestHeadType::Owned(head), BodySize::None).into())
+        .await
+        .unwrap();
+    let response = framed.next().await.unwrap().unwrap();
+    assert_eq!(response.status, StatusCode::SWITCHING_PROTOCOLS);
+    framed.into_map_codec(|_| ws::Codec::new().client_mode())
+}
+
+async fn send_ws(client: &mut WsClient, message: serde_json::Value) {
+    client
+        .send(ws::Message::Text(message.to_string().into()))
+        .await
+        .unwrap();
+}
+
+/// Next text message as JSON, or the close code once the server closes the socket.
+async fn receive_ws(client: &mut WsClient) -> Result<serde_json::Value, u16> {
+    loop {
+        let frame = rt::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("Timed out waiting for a WebSocket frame")
+            .unwrap()
+            .unwrap();
+
+        match frame {
+            ws::Frame::Text(text) => return Ok(serde_json::from_slice(&text).unwrap()),
+            ws::Frame::Close(reason) => return Err(reason.map_or(1005, |r| r.code.into())),
+            _ => continue,
+        }
+    }
+}
+
+fn ws_me_query() -> serde_json::Value {
+    json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": { "query": "query { me { databaseId } }" }
+    })
+}
+
+async fn link_provider(db: &Database, user: &user::Model, provider: enums::OAuthProviderEnum) {
+    oauth_provider::ActiveModel {
+        user_email: Set(user.email.clone()),
+        provider: Set(provider),
+        two_factor: Set(false),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+}
+
+fn provider_counts(body: &serde_json::Value) -> Vec<(String, u64)> {
+    body["data"]["providerStats"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|stat| {
+            (
+                stat["provider"].as_str().unwrap().to_string(),
+                stat["count"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+fn recent_provider_signups_query(limit: u64, after: Option<&str>) -> serde_json::Value {
+    json!({
+        "query": r#"
+            query RecentSignups($limit: Int, $after: String) {
+                recentProviderSignups(provider: GITHUB, limit: $limit, after: $after) {
+                    edges {
+                        cursor
+                        node {
+                            provider
+                            createdAt
+                            user { databaseId }
+                        }
+                    }
+                    pageInfo { hasNextPage hasPreviousPage }
+                }
+            }
+        "#,
+        "variables": { "limit": limit, "after": after },
+    })
+}
+
+#[actix_web::test]
+async fn test_resolver_provider_stats() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let mut admin: user::ActiveModel = create_user(&db, true).await.into();
+    admin.role = Set(enums::RoleEnum::Admin);
+    let admin = admin.update(db.get_connection()).await.unwrap();
+    let admin_token = format!("Bearer {}", create_token(&jwt, &admin, None).await);
+    let stats_query = json!({ "query": "query { providerStats { provider count } }" });
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(stats_query.clone())
+        .to_request();
+    let before = provider_counts(&test::call_and_read_body_json(&app, req).await);
+    // Every provider is listed, even without sign ups
+    assert_eq!(before.len(), 4);
+
+    let mut github_users = Vec::new();
+    for _ in 0..3 {
+        let user = create_user(&db, true).await;
+        link_provider(&db, &user, enums::OAuthProviderEnum::Github).await;
+        github_users.push(user);
+    }
+    let facebook_user = create_user(&db, true).await;
+    link_provider(&db, &facebook_user, enums::OAuthProviderEnum::Facebook).await;
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(stats_query.clone())
+        .to_request();
+    let after = provider_counts(&test::call_and_read_body_json(&app, req).await);
+    for ((provider, before), (_, after)) in before.iter().zip(after.iter()) {
+        match provider.as_str() {
+            "GITHUB" => assert!(*after >= before + 3),
+            "FACEBOOK" => assert!(*after > *before),
+            _ => (),
+        }
+    }
+
+    // Newest first, the first page holds the last two linked users
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(recent_provider_signups_query(2, None))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let page = &body["data"]["recentProviderSignups"];
+    let edges = page["edges"].as_array().unwrap();
+    assert_eq!(edges.len(), 2);
+    assert_eq!(edges[0]["node"]["user"]["databaseId"], github_users[2].id);
+    assert_eq!(edges[1]["node"]["user"]["databaseId"], github_users[1].id);
+    assert_eq!(edges[0]["node"]["provider"], "GITHUB");
+    assert!(edges[0]["node"]["createdAt"].as_i64().unwrap() > 0);
+    assert_eq!(page["pageInfo"]["hasNextPage"], true);
+    assert_eq!(page["pageInfo"]["hasPreviousPage"], false);
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", admin_token.as_str()))
+        .set_json(recent_provider_signups_query(
+            1,
+            edges[1]["cursor"].as_str(),
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let page = &body["data"]["recentProviderSignups"];
+    assert_eq!(
+        page["edges"][0]["node"]["user"]["databaseId"],
+        github_users[0].id
+    );
+    assert_eq!(page["pageInfo"]["hasPreviousPage"], true);
+
+    // Admin only
+    let user_token = format!("Bearer {}", create_token(&jwt, &facebook_user, None).await);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", user_token.as_str()))
+        .set_json(stats_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "Forbidden");
+
+    delete_user(&db, admin).await;
+    delete_user(&db, facebook_user).await;
+    for user in github_users {
+        delete_user(&db, user).await;
+    }
+}
+
+#[actix_web::test]
+async fn test_resolver_ws_authentication() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let access_token = create_token(&jwt, &user, None).await;
+    let port = start_server(&config, &db);
+
+    // Authenticated connections see the same user as HTTP requests
+    let mut client = connect_ws(port).await;
+    send_ws(
+        &mut client,
+        json!({
+            "type": "connection_init",
+            "payload": { "authorization": format!("Bearer {}", &access_token) }
+        }),
+    )
+    .await;
+    assert_eq!(
+        receive_ws(&mut client).await.unwrap()["type"],
+        "connection_ack"
+    );
+    send_ws(&mut client, ws_me_query()).await;
+    let message = receive_ws(&mut client).await.unwrap();
+    assert_eq!(message["type"], "next");
+    assert_eq!(message["payload"]["data"]["me"]["databaseId"], user.id);
+
+    // Without params the connection is anonymous and guards reject it
+    let mut client = connect_ws(port).await;
+    send_ws(&mut client, json!({ "type": "connection_init" })).await;
+    assert_eq!(
+        receive_ws(&mut client).await.unwrap()["type"],
+        "connection_ack"
+    );
+    send_ws(&mut client, ws_me_query()).await;
+    let message = receive_ws(&mut client).await.unwrap();
+    assert_eq!(message["type"], "next");
+    assert!(message["payload"]["data"]["me"].is_null());
+    assert_eq!(message["payload"]["errors"][0]["message"], "Unauthorized");
+
+    // Invalid tokens close the socket
+    let mut client = connect_ws(port).await;
+    send_ws(
+        &mut client,
+        json!({
+            "type": "connection_init",
+            "payload": { "authorization": "Bearer invalid" }
+        }),
+    )
+    .await;
+    assert_eq!(
+        receive_ws(&mut client).await.unwrap_err(),
+        UNAUTHORIZED_CLOSE_CODE
+    );
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_ws_token_expiry() {
+    let (mut config, db, _, _) = create_base_config().await;
+    config.jwt.access.exp = 1;
+    let jwt = Jwt::new(&config.jwt);
+    let user = create_user(&db, true).await;
+    let access_token = create_token(&jwt, &user, None).await;
+    let port = start_server(&config, &db);
+
+    let mut client = connect_ws(port).await;
+    send_ws(
+        &mut client,
+        json!({
+            "type": "connection_init",
+            "payload": { "authorization": format!("Bearer {}", &access_token) }
+        }),
+    )
+    .await;
+    assert_eq!(
+        receive_ws(&mut client).await.unwrap()["type"],
+        "connection_ack"
+    );
+
+    // The connection is dropped once the token expires
+    assert_eq!(
+        receive_ws(&mut client).await.unwrap_err(),
+        UNAUTHORIZED_CLOSE_CODE
+    );
+
+    delete_user(&db, user).await;
+}
+
+fn write_allowlist(file: &mut tempfile::NamedTempFile, queries: &[&str]) {
+    let operations = queries
+        .iter()
+        .enumerate()
+        .map(|(i, query)| {
+            (
+                format!("Operation{}", i),
+                format!("{:x}", Sha256::digest(query.as_bytes())),
+            )
+        })
+        .collect::<std::collections::HashMap<String, String>>();
+    let file = file.as_file_mut();
+    file.set_len(0).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(serde_json::to_string(&operations).unwrap().as_bytes())
+        .unwrap();
+}
+
+#[actix_web::test]
+async fn test_resolver_query_allowlist() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let listed_query = "query { healthCheck { message } }";
+    let unlisted_query = "query { healthCheck { id message } }";
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write_allowlist(&mut file, &[listed_query]);
+    let allowlist = QueryAllowlist::from_path(file.path()).unwrap();
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &config.object_storage),
+        &allowlist,
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+
+    let response = schema
+        .execute(Request::new(listed_query).data(None::<AccessUser>))
+        .await;
+    assert!(response.errors.is_empty());
+
+    let body = serde_json::to_value(
+        schema
+            .execute(Request::new(unlisted_query).data(None::<AccessUser>))
+            .await,
+    )
+    .unwrap();
+    assert_eq!(body["errors"][0]["message"], "Operation is not allowed");
+    assert_eq!(body["errors"][0]["extensions"]["code"], "403");
+    assert!(body["data"].is_null());
+
+    // Admins are not bound to the allow-list
+    let response = schema
+        .execute(
+            Request::new(unlisted_query).data(Some(AccessUser::new(1, enums::RoleEnum::Admin))),
+        )
+        .await;
+    assert!(response.errors.is_empty());
+    let response = schema
+        .execute(Request::new(unlisted_query).data(Some(
+            AccessUser::new(1, enums::RoleEnum::Admin).with_impersonator(Some(2)),
+        )))
+        .await;
+    assert_eq!(response.errors[0].message, "Operation is not allowed");
+
+    // Reloading picks up the new operations without rebuilding the schema
+    write_allowlist(&mut file, &[listed_query, unlisted_query]);
+    assert_eq!(allowlist.reload().unwrap(), 2);
+    let response = schema
+        .execute(Request::new(unlisted_query).data(None::<AccessUser>))
+        .await;
+    assert!(response.errors.is_empty());
+}
+
+fn node_query(id: &str) -> serde_json::Value {
+    json!({
+        "query": format!(r#"
+            query {{
+                node(id: "{}") {{
+                    __typename
+                    id
+                    ... on User {{ databaseId username }}
+                    ... on UploadedFile {{ databaseId extension }}
+                }}
+            }}
+        "#, id),
+    })
+}
+
+#[actix_web::test]
+async fn test_resolver_node() {
+    let (config, db, _, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let file = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(format!("{}/{}.pdf", Uuid::new_v4(), Uuid::new_v4())),
+        extension: Set("pdf".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let user_id = GlobalId::User(user.id).format();
+    let file_id = GlobalId::UploadedFile(file.id).format();
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(node_query(&user_id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let node = &body["data"]["node"];
+    assert_eq!(node["__typename"], "User");
+    assert_eq!(node["id"], user_id.as_str());
+    assert_eq!(node["databaseId"], user.id);
+    assert_eq!(node["username"], user.username.as_str());
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(node_query(&file_id))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let node = &body["data"]["node"];
+    assert_eq!(node["__typename"], "UploadedFile");
+    assert_eq!(node["id"], file_id.as_str());
+    assert_eq!(node["databaseId"], file.id.to_string());
+    assert_eq!(node["extension"], "pdf");
+
+    // Unknown type tags and malformed ids are not errors
+    for id in [
+        encode_cursor(&format!("ApiKey:{}", user.id)),
+        user.id.to_string(),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(node_query(&id))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["data"]["node"].is_null());
+        assert!(body["errors"].is_null());
+    }
+
+    // userById takes both forms while clients migrate
+    for id in [format!("\"{}\"", &user_id), user.id.to_string()] {
+        let req = test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .set_json(json!({
+                "query": format!("query {{ userById(id: {}) {{ id databaseId }} }}", id),
+            }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["userById"]["id"], user_id.as_str());
+        assert_eq!(body["data"]["userById"]["databaseId"], user.id);
+    }
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": format!(r#"query {{ userById(id: "{}") {{ id }} }}"#, &file_id),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["errors"][0]["message"], "Invalid user id");
+
+    // Cursors still encode the sort key, not the global id
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": "query { users(order: DESC, cursor: DATE, limit: 5) { edges { cursor node { id databaseId } } } }",
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let edges = body["data"]["users"]["edges"].as_array().unwrap();
+    assert!(!edges.is_empty());
+    for edge in edges {
+        let database_id = edge["node"]["databaseId"].as_i64().unwrap() as i32;
+        let cursor = decode_filtered_cursor(edge["cursor"].as_str().unwrap()).unwrap();
+        assert_eq!(cursor.position, database_id.to_string());
+        assert!(cursor.filter_hash.is_some());
+        assert_eq!(edge["node"]["id"], GlobalId::User(database_id).format());
+    }
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_read_replica() {
+    let (config, _, jwt, _) = create_base_config().await;
+    let database_url = std::env::var("DATABASE_URL").unwrap();
+    // The same database behind both pools, the callbacks tell them apart
+    let mut db = Database::connect(&database_url, Some(&database_url))
+        .await
+        .unwrap();
+    assert!(db.has_read_replica());
+    let primary_queries = Arc::new(AtomicUsize::new(0));
+    let replica_queries = Arc::new(AtomicUsize::new(0));
+    let callback_queries = primary_queries.clone();
+    db.set_metric_callback(move |_| {
+        callback_queries.fetch_add(1, Ordering::SeqCst);
+    });
+    let callback_queries = replica_queries.clone();
+    db.set_read_metric_callback(move |_| {
+        callback_queries.fetch_add(1, Ordering::SeqCst);
+    });
+    let reset = || {
+        primary_queries.store(0, Ordering::SeqCst);
+        replica_queries.store(0, Ordering::SeqCst);
+    };
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let file = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(format!("{}/{}.jpg", Uuid::new_v4(), Uuid::new_v4())),
+        extension: Set("jpg".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let mut active_user: user::ActiveModel = user.into();
+    active_user.picture = Set(Some(file.id));
+    let user = active_user.update(db.get_connection()).await.unwrap();
+    let access_token = create_token(&jwt, &user, None).await;
+    let bearer_token = format!("Bearer {}", &access_token);
+
+    // Lookups and the dataloaders read from the replica
+    reset();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": format!(r#"
+                query {{
+                    userByUsername(username: "{}") {{
+                        picture {{
+                            databaseId
+                        }}
+                    }}
+                }}
+            "#, user.username),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"]["userByUsername"]["picture"]["databaseId"],
+        file.id.to_string()
+    );
+    assert_eq!(replica_queries.load(Ordering::SeqCst), 2);
+    assert_eq!(primary_queries.load(Ordering::SeqCst), 0);
+
+    // After a mutation the rest of the request reads from the primary
+    reset();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "query": r#"
+                mutation {
+                    updateUserName(input: { firstName: "Replica", lastName: "User" }) {
+                        picture {
+                            databaseId
+                        }
+                    }
+                }
+            "#,
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"]["updateUserName"]["picture"]["databaseId"],
+        file.id.to_string()
+    );
+    assert_eq!(replica_queries.load(Ordering::SeqCst), 0);
+    assert!(primary_queries.load(Ordering::SeqCst) > 0);
+
+    // The next request goes back to the replica
+    reset();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .set_json(json!({
+            "query": format!(r#"
+                query {{
+                    userByUsername(username: "{}") {{
+                        firstName
+                    }}
+                }}
+            "#, user.username),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["userByUsername"]["firstName"], "Replica");
+    assert_eq!(replica_queries.load(Ordering::SeqCst), 1);
+    assert_eq!(primary_queries.load(Ordering::SeqCst), 0);
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_suspended_user_mutations() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let update_name = json!({
+        "query": r#"
+            mutation {
+                updateUserName(input: { firstName: "Suspended", lastName: "User" }) {
+                    firstName
+                }
+            }
+        "#,
+    });
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(&update_name)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["updateUserName"]["firstName"], "Suspended");
+
+    // The token is still genuine, but the account behind it no longer is
+    let user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    let mut suspended_user: user::ActiveModel = user.clone().into();
+    suspended_user.suspended = Set(true);
+    let stale_user = user;
+    let user = suspended_user.update(db.get_connection()).await.unwrap();
+    // A lagging replica read cached again right after the invalidation
+    cache
+        .set_json(
+            &format!("user:{}", user.id),
+            &objects::User::from(stale_user),
+            cache.get_ttl(),
+        )
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(&update_name)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["updateUserName"].is_null());
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Your account has been suspended"
+    );
+    assert_eq!(body["errors"][0]["extensions"]["code"], "403");
+
+    // Queries only check the signature outside of strict mode
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({ "query": "query { me { id } }" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_strict_access_tokens() {
+    let (mut config, db, jwt, cache) = create_base_config().await;
+    config.jwt.strict = true;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let me_query = json!({ "query": "query { me { id } }" });
+
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(&me_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+
+    // Changing the password bumps the version and revokes the older tokens
+    let new_password = "New_Password12";
+    let req = test::TestRequest::post()
+        .uri("/api/auth/update-password")
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "old_password": VALID_PASSWORD,
+            "password1": new_password,
+            "password2": new_password,
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let updated_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert!(updated_user.version > user.version);
+
+    // Even with the row from before the change cached again, as a lagging replica would
+    cache
+        .set_json(
+            &format!("user:{}", user.id),
+            &objects::User::from(user.clone()),
+            cache.get_ttl(),
+        )
+        .await
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(&me_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["me"].is_null());
+    assert_eq!(body["errors"][0]["extensions"]["code"], "401");
+
+    // A token for the new version goes through
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &updated_user, None).await);
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(&me_query)
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["errors"].is_null());
+
+    delete_user(&db, updated_user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_validation_errors() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let long_name = "a".repeat(51);
+
+    // One error per invalid field instead of a stringified array
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "query": r#"
+                mutation UpdateName($lastName: String!) {
+                    updateUserName(input: { firstName: "J0hn_Doe!", lastName: $lastName }) {
+                        firstName
+                    }
+                }
+            "#,
+            "variables": { "lastName": &long_name },
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["message"], "Invalid First name");
+    assert_eq!(errors[0]["extensions"]["field"], "firstName");
+    assert_eq!(errors[0]["extensions"]["code"], "400");
+    assert_eq!(
+        errors[1]["message"],
+        "Last name needs to be between 3 and 50 characters."
+    );
+    assert_eq!(errors[1]["extensions"]["field"], "lastName");
+    assert_eq!(errors[1]["extensions"]["code"], "400");
+    assert!(errors[1]["extensions"]["validationErrors"].is_null());
+    assert!(body["data"]["updateUserName"].is_null());
+
+    // The field that failed does not take its siblings down with it
+    let req = test::TestRequest::post()
+        .uri(GRAPHQL_PATH)
+        .insert_header(("Authorization", bearer_token.as_str()))
+        .set_json(json!({
+            "query": format!(
+                r#"query {{ me {{ databaseId }} userById(id: {}) {{ databaseId }} }}"#,
+                i32::MAX
+            ),
+        }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["me"]["databaseId"], user.id);
+    assert!(body["data"]["userById"].is_null());
+    assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+    assert_eq!(body["errors"][0]["message"], "User not found");
+    assert_eq!(body["errors"][0]["path"][0], "userById");
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_update_profile() {
+    let (config, db, jwt, _) = create_base_config().await;
+    let app = test::init_service(App::new().wrap(TracingLogger::default()).configure(
+        ActixApp::build_app_config(&config, &db, &Providers::new(&config, &Metrics::new())),
+    ))
+    .await;
+    let user = create_user(&db, true).await;
+    let bearer_token = format!("Bearer {}", create_token(&jwt, &user, None).await);
+    let update_profile = |input: serde_json::Value| {
+        test::TestRequest::post()
+            .uri(GRAPHQL_PATH)
+            .insert_header(("Authorization", bearer_token.as_str()))
+            .set_json(json!({
+                "query": r#"
+                    mutation UpdateProfile($input: UpdateProfileInput!) {
+                        updateProfile(input: $input) {
+                            firstName
+                            lastName
+                        }
+                    }
+                "#,
+                "variables": { "input": input },
+            }))
+            .to_request()
+    };
+
+    // Only the provided field changes
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, update_profile(json!({ "lastName": "Partial" }))).await;
+    assert!(body["errors"].is_null());
+    assert_eq!(body["data"]["updateProfile"]["firstName"], user.first_name);
+    assert_eq!(body["data"]["updateProfile"]["lastName"], "Partial");
+    let updated_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(updated_user.first_name, user.first_name);
+    assert_eq!(updated_user.last_name, "Partial");
+    assert_eq!(updated_user.version, user.version + 1);
+
+    // An invalid date rejects the whole input
+    let body: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        update_profile(json!({ "firstName": "Rejected", "dateOfBirth": "01-01-1990" })),
+    )
+    .await;
+    assert_eq!(body["errors"][0]["extensions"]["field"], "dateOfBirth");
+    assert_eq!(body["errors"][0]["extensions"]["code"], "400");
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, update_profile(json!({ "dateOfBirth": "2090-01-01" })))
+            .await;
+    assert_eq!(body["errors"][0]["extensions"]["field"], "dateOfBirth");
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Date of birth needs to be in the past."
+    );
+    let unchanged_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(unchanged_user.first_name, user.first_name);
+    assert_eq!(unchanged_user.version, updated_user.version);
+
+    // At least one field is required
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, update_profile(json!({}))).await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "At least one profile field must be provided"
+    );
+    assert!(body["data"]["updateProfile"].is_null());
+    let unchanged_user = users_service::find_one_by_id(&db.session(), user.id)
+        .await
+        .unwrap();
+    assert_eq!(unchanged_user.version, updated_user.version);
+
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_export_my_data() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let client = RecordingClient::default();
+    let object_storage = ObjectStorage::with_client(
+        client.clone(),
+        "test",
+        STORAGE_ENDPOINT,
+        Uuid::new_v4(),
+        8 * 1024 * 1024,
+        true,
+    );
+    let prefix = object_storage.get_user_prefix(user.id);
+    let document_key = format!("{}/{}.pdf", &prefix, Uuid::new_v4());
+    client
+        .objects
+        .lock()
+        .unwrap()
+        .insert(document_key.clone(), b"document".to_vec());
+    let document = uploaded_file::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        user_id: Set(user.id),
+        url: Set(format!("{}/{}", STORAGE_ENDPOINT, &document_key)),
+        extension: Set("pdf".to_string()),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await
+    .unwrap();
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        object_storage,
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let execute = |query: &str| {
+        let request = Request::new(query).data(Some(AccessUser::new(user.id, user.role)));
+        let schema = schema.clone();
+        async move { serde_json::to_value(schema.execute(request).await).unwrap() }
+    };
+    let export_mutation = "mutation { exportMyData { status url } }";
+
+    let body = execute(export_mutation).await;
+    assert_eq!(body["data"]["exportMyData"]["status"], "PENDING");
+    assert!(body["data"]["exportMyData"]["url"].is_null());
+
+    // The archive is built in the background
+    let mut export = json!(null);
+    for _ in 0..50 {
+        export = execute("{ myDataExport { status url } }").await["data"]["myDataExport"].clone();
+        if export["status"] != "PENDING" {
+            break;
+        }
+        rt::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(export["status"], "READY");
+    let archive_key = export["url"].as_str().unwrap().to_string();
+    assert!(archive_key.starts_with(&format!("{}/exports/", &prefix)));
+    assert!(captured_emails(&user.email)
+        .iter()
+        .any(|email| email.subject.starts_with("Your data export is ready")));
+
+    let archive = client.objects.lock().unwrap()[&archive_key].clone();
+    let mut archive = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+    let mut account = String::new();
+    archive
+        .by_name("account.json")
+        .unwrap()
+        .read_to_string(&mut account)
+        .unwrap();
+    let account = serde_json::from_str::<serde_json::Value>(&account).unwrap();
+    let document_path = format!("files/{}.pdf", document.id);
+    assert_eq!(account["user"]["email"], json!(&user.email));
+    assert!(account["user"].get("password").is_none());
+    assert_eq!(
+        account["uploaded_files"][0]["archive_path"],
+        json!(&document_path)
+    );
+    let mut contents = Vec::new();
+    archive
+        .by_name(&document_path)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"document");
+
+    // One export per cooldown
+    let body = execute(export_mutation).await;
+    assert!(body["errors"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("once every 24 hours"));
+
+    cache
+        .del(&format!("data_export:{}", user.id))
+        .await
+        .unwrap();
+    delete_user(&db, user).await;
+}
+
+#[actix_web::test]
+async fn test_resolver_user_providers() {
+    let (config, db, jwt, cache) = create_base_config().await;
+    let user = create_user(&db, true).await;
+    let stranger = create_user(&db, true).await;
+    link_provider(&db, &user, enums::OAuthProviderEnum::Google).await;
+    let schema = build_schema(
+        &config.environment,
+        &GraphQLLimits::default(),
+        &config.graphql_execution,
+        &db,
+        &cache,
+        &jwt,
+        &Metrics::new(),
+        ObjectStorage::new(&config.environment, &config.object_storage),
+        &QueryAllowlist::disabled(),
+        &Webhooks::disabled(),
+        &Mailer::new(&config.environment, &config.mailer, &Metrics::new()),
+        &Maintenance::new(&cache),
+        &config.terms_version,
+        &config.unconfirmed_expiry,
+    );
+    let execute = |query: String, access_user: AccessUser| {
+        let request = Request::new(query).data(Some(access_user));
+        let schema = schema.clone();
+        async move { serde_json::to_value(schema.execute(request).await).unwrap() }
+    };
+    let providers = |providers: &serde_json::Value| {
+        providers
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|provider| provider["provider"].as_str().unwrap().to_string())
+            .collect::<Vec<String>>()
+    };
+
+    let body = execute(
+        "{ me { confirmed twoFactor providers { provider } } }".to_string(),
+        AccessUser::new(user.id, user.role),
+    )
+    .await;
+    assert!(body["errors"].is_null());
+    assert_eq!(body["data"]["me"]["confirmed"], json!(true));
+    assert_eq!(body["data"]["me"]["twoFactor"], json!(true));
+    assert_eq!(
+        providers(&body["data"]["me"]["providers"]),
+        vec!["LOCAL", "GOOGLE"]
+    );
+
+    // Strangers get null, admins the same list as the owner
+    let user_query = format!(
+        r#"{{ userByUsername(username: "{}") {{ confirmed twoFactor providers {{ provider }} }} }}"#,
+        &user.username
+    );
+    let body = execute(
+        user_query.clone(),
+        AccessUser::new(stranger.id, stranger.role),
+    )
+    .await;
+    assert!(body["errors"].is_null());
+    assert!(body["data"]["userByUsername"]["confirmed"].is_null());
+    assert!(body["data"]["userByUsername"]["twoFactor"].is_null());
+    assert!(body["data"]["userByUsername"]["providers"].is_null());
+    let body = execute(
+        user_query,
+        AccessUser::new(stranger.id, enums::RoleEnum::Admin),
+    )
+    .await;
+    assert_eq!(
+        providers(&body["data"]["userByUsername"]["providers"]),
+        vec!["LOCAL", "GOOGLE"]
+    );
+
+    delete_user(&db, user).await;
+    delete_user(&db, stranger).await;
+}
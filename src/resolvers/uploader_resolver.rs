@@ -4,8 +4,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::dtos::objects::{StorageUsage, UploadedFile};
+use crate::guards::AuthGuard;
+use crate::helpers::AccessUser;
+use crate::providers::{Database, ObjectStorage};
 use crate::services::uploader_service;
-use async_graphql::{Object, Result};
+use async_graphql::{Context, Error, Object, Result, Upload};
 
 #[derive(Default)]
 pub struct UploaderQuery;
@@ -20,4 +24,32 @@ impl UploaderQuery {
         let db = ctx.data::<crate::providers::Database>()?;
         Ok(uploader_service::find_one_by_id(db, &id).await?.into())
     }
+
+    /// Bytes stored by the current user, against the per-user quota.
+    #[graphql(guard = "AuthGuard")]
+    async fn my_storage_usage(&self, ctx: &Context<'_>) -> Result<StorageUsage> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(uploader_service::find_storage_usage(
+            ctx.data::<Database>()?,
+            ctx.data::<ObjectStorage>()?,
+            user.id,
+        )
+        .await?)
+    }
+}
+
+#[derive(Default)]
+pub struct UploaderMutation;
+
+#[Object]
+impl UploaderMutation {
+    /// Uploads a document from the `UPLOAD_ALLOWED_TYPES` allow-list, images go
+    /// through `updateUserPicture`.
+    #[graphql(guard = "AuthGuard")]
+    async fn upload_document(&self, ctx: &Context<'_>, file: Upload) -> Result<UploadedFile> {
+        Ok(uploader_service::upload_document(ctx, file).await?.into())
+    }
 }
@@ -5,19 +5,34 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use async_graphql::connection::{Connection, Edge, EmptyFields};
-use async_graphql::{Context, Error, Object, Result, Upload};
+use async_graphql::{Context, Error, Object, Result, Upload, ID};
+use chrono::{DateTime, Utc};
 
-use entities::enums::{CursorEnum, OrderEnum};
-use entities::helpers::GQLAfter;
-use entities::user::Model;
+use entities::enums::{CursorEnum, OrderEnum, RoleEnum};
+use entities::helpers::{GQLAfter, PageCursor};
 
 use crate::common::{InternalCause, ServiceError};
-use crate::dtos::inputs::{UpdateName, UpdateNameValidator};
-use crate::dtos::objects::{Message, TotalCount, User};
-use crate::guards::AuthGuard;
-use crate::helpers::AccessUser;
-use crate::providers::Database;
-use crate::services::users_service;
+use crate::dtos::inputs::{
+    PrivacySettings, SetPassword, SetPasswordValidator, UpdateName, UpdateNameValidator,
+    UpdatePreferences, UpdatePreferencesValidator, UpdateProfile, UpdateProfileValidator,
+    UsernameValidator,
+};
+use crate::dtos::objects::{
+    BulkDeleteReport, Impersonation, LockStatus, Message, RecoveryCodes, Security, Session,
+    TotalCount, UnconfirmedAccountStats, User,
+};
+use crate::dtos::responses;
+use crate::guards::{AuthGuard, NoImpersonationGuard, RoleGuard};
+use crate::helpers::{AccessUser, GlobalId};
+use crate::providers::{
+    Cache, Database, Jwt, Mailer, TermsVersion, UnconfirmedExpiryConfig, Webhooks,
+};
+use crate::services::{
+    auth_service, devices_service, recovery_codes_service, unconfirmed_accounts_service,
+    users_service,
+};
+
+const DEFAULT_SEARCH_LIMIT: u64 = 10;
 
 #[derive(Default)]
 pub struct UsersQuery;
@@ -25,56 +40,156 @@ pub struct UsersQuery;
 #[derive(Default)]
 pub struct UsersMutation;
 
-fn check_confirmation(user: Model) -> Result<User> {
-    if !user.confirmed {
+/// Unconfirmed users are only visible to themselves.
+pub fn check_confirmation(ctx: &Context<'_>, user: User) -> Result<User> {
+    let is_owner = ctx
+        .data::<Option<AccessUser>>()?
+        .as_ref()
+        .is_some_and(|access_user| access_user.id == user.id);
+
+    if !user.confirmed && !is_owner {
         return Err(ServiceError::not_found(
             "User not found",
             Some(InternalCause::new("User is not confirmed")),
         )
         .into());
     }
-    Ok(user.into())
+    Ok(user)
+}
+
+/// Accepts both the numeric database id and the global id while clients migrate.
+fn parse_user_id(id: &ID) -> Result<i32> {
+    if let Ok(id) = id.parse::<i32>() {
+        return Ok(id);
+    }
+
+    match GlobalId::parse(id) {
+        Some(GlobalId::User(id)) => Ok(id),
+        _ => Err(ServiceError::bad_request::<ServiceError>("Invalid user id", None).into()),
+    }
 }
 
 #[Object]
 impl UsersQuery {
+    /// Pages forward with `limit` and `after`, or backward with `last` and `before`.
+    /// `createdAfter` is inclusive and `createdBefore` exclusive. Cursors are rejected
+    /// once the filters, search, order or cursor type change. `ACTIVITY` lists the most
+    /// recently active users first with `DESC`.
+    #[graphql(complexity = "limit.or(last).unwrap_or_default() as usize * child_complexity")]
+    #[allow(clippy::too_many_arguments)]
     async fn users(
         &self,
         ctx: &Context<'_>,
         order: OrderEnum,
         cursor: CursorEnum,
-        #[graphql(validator(minimum = 1, maximum = 100))] limit: u64,
+        #[graphql(validator(minimum = 1, maximum = 100))] limit: Option<u64>,
         #[graphql(validator(
             min_length = 1,
             regex = r"^(?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/]{2}==|[A-Za-z0-9+/]{3}=)?$",
         ))]
         after: Option<String>,
+        #[graphql(validator(minimum = 1, maximum = 100))] last: Option<u64>,
+        #[graphql(validator(
+            min_length = 1,
+            regex = r"^(?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/]{2}==|[A-Za-z0-9+/]{3}=)?$",
+        ))]
+        before: Option<String>,
         #[graphql(validator(min_length = 3, max_length = 50, regex = r"(^[\p{L}0-9'\.\s]*$)"))]
         search: Option<String>,
+        role: Option<RoleEnum>,
+        created_after: Option<DateTime<Utc>>,
+        created_before: Option<DateTime<Utc>>,
     ) -> Result<Connection<String, User, TotalCount, EmptyFields>> {
+        let (limit, page_cursor) = match (limit, after, last, before) {
+            (Some(limit), after, None, None) => (limit, PageCursor::After(after)),
+            (None, None, Some(last), before) => (last, PageCursor::Before(before)),
+            _ => {
+                return Err(ServiceError::bad_request::<ServiceError>(
+                    "Use either limit with after or last with before",
+                    None,
+                )
+                .into())
+            }
+        };
+        let filter = users_service::UsersFilter {
+            search,
+            role,
+            created_after,
+            created_before,
+        };
+        filter.validate()?;
+        let filter_hash = filter.hash(order, cursor);
         let db = ctx.data::<Database>()?;
-        let (users, count, previous_count) =
-            users_service::query(db, order, cursor, limit, after, search).await?;
+        let look_ahead = ctx.look_ahead();
+        let page_info = look_ahead.field("pageInfo");
+        let selection = users_service::PageSelection {
+            total_count: look_ahead.field("totalCount").exists(),
+            previous_count: look_ahead.field("previousCount").exists(),
+            has_previous_page: page_info.field("hasPreviousPage").exists(),
+            has_next_page: page_info.field("hasNextPage").exists(),
+        };
+        let page = users_service::query(
+            &db.session(),
+            order,
+            cursor,
+            limit,
+            page_cursor,
+            filter,
+            selection,
+        )
+        .await?;
+        // Counts that were not selected are never read, so they default to zero
         let mut connection = Connection::with_additional_fields(
-            previous_count > 0,
-            count > limit,
-            TotalCount::new(count, previous_count),
+            page.has_previous_page,
+            page.has_next_page,
+            TotalCount::new(
+                page.total_count.unwrap_or_default(),
+                page.previous_count.unwrap_or_default(),
+            ),
         );
         connection.edges.extend(
-            users
+            page.users
                 .into_iter()
-                .map(|user| Edge::new(user.after(cursor), user.into())),
+                .map(|user| Edge::new(user.after(cursor, &filter_hash), user.into())),
         );
         Ok(connection)
     }
 
-    async fn user_by_id(&self, ctx: &Context<'_>, id: i32) -> Result<User> {
-        check_confirmation(users_service::find_one_by_id(ctx.data::<Database>()?, id).await?)
+    /// Best matches first, users below the similarity threshold are left out.
+    #[graphql(complexity = "limit.unwrap_or(DEFAULT_SEARCH_LIMIT) as usize * child_complexity")]
+    async fn search_users(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(min_length = 2, max_length = 50))] query: String,
+        #[graphql(validator(minimum = 1, maximum = 50))] limit: Option<u64>,
+    ) -> Result<Vec<User>> {
+        let users = users_service::search(
+            &ctx.data::<Database>()?.session(),
+            query.trim(),
+            limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+        )
+        .await?;
+        Ok(users.into_iter().map(User::from).collect())
+    }
+
+    async fn user_by_id(&self, ctx: &Context<'_>, id: ID) -> Result<User> {
+        check_confirmation(
+            ctx,
+            users_service::cached_find_one_by_id(
+                &ctx.data::<Database>()?.session(),
+                ctx.data::<Cache>()?,
+                parse_user_id(&id)?,
+            )
+            .await?,
+        )
     }
 
     async fn user_by_username(&self, ctx: &Context<'_>, username: String) -> Result<User> {
         check_confirmation(
-            users_service::find_one_by_username(ctx.data::<Database>()?, &username).await?,
+            ctx,
+            users_service::find_one_by_username(&ctx.data::<Database>()?.session(), &username)
+                .await?
+                .into(),
         )
     }
 
@@ -85,22 +200,117 @@ impl UsersQuery {
             .data::<Option<AccessUser>>()?
             .as_ref()
             .ok_or_else(|| Error::new("Unauthorized"))?;
-        Ok(users_service::find_one_by_id(db, user.id).await?.into())
+        Ok(
+            users_service::cached_find_one_by_id(&db.session(), ctx.data::<Cache>()?, user.id)
+                .await?,
+        )
+    }
+
+    #[graphql(guard = "AuthGuard")]
+    async fn my_sessions(&self, ctx: &Context<'_>) -> Result<Vec<Session>> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(auth_service::find_sessions(ctx.data::<Cache>()?, user.id).await?)
+    }
+
+    #[graphql(guard = "AuthGuard")]
+    async fn my_security(&self, ctx: &Context<'_>) -> Result<Security> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(Security {
+            remaining_recovery_codes: recovery_codes_service::count_remaining(
+                ctx.data::<Database>()?,
+                user.id,
+            )
+            .await?,
+            known_devices: devices_service::find_known_devices(ctx.data::<Cache>()?, user.id)
+                .await?,
+        })
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin)")]
+    async fn user_lock_status(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(email, min_length = 5, max_length = 200))] email: String,
+    ) -> Result<LockStatus> {
+        Ok(auth_service::user_lock_status(ctx.data::<Cache>()?, &email).await?)
+    }
+
+    /// Unconfirmed accounts and how many of them are about to be removed.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin)")]
+    async fn unconfirmed_account_stats(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<UnconfirmedAccountStats> {
+        Ok(unconfirmed_accounts_service::find_stats(
+            ctx.data::<Database>()?,
+            ctx.data::<UnconfirmedExpiryConfig>()?,
+        )
+        .await?)
     }
 }
 
 #[Object]
 impl UsersMutation {
-    #[graphql(guard = "AuthGuard")]
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
     async fn update_user_picture(&self, ctx: &Context<'_>, picture: Upload) -> Result<User> {
         Ok(users_service::update_picture(ctx, picture).await?.into())
     }
 
-    #[graphql(guard = "AuthGuard")]
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
     async fn update_user_name(
         &self,
         ctx: &Context<'_>,
         #[graphql(validator(custom = "UpdateNameValidator"))] input: UpdateName,
+    ) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::update_name(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            user.id,
+            input.first_name,
+            input.last_name,
+        )
+        .await?
+        .into())
+    }
+
+    /// Saves any subset of the profile fields at once, email changes excluded.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn update_profile(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(custom = "UpdateProfileValidator"))] input: UpdateProfile,
+    ) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::update_user_profile(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            user.id,
+            input.into(),
+        )
+        .await?
+        .into())
+    }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn update_username(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(custom = "UsernameValidator"))] username: String,
     ) -> Result<User> {
         let db = ctx.data::<Database>()?;
         let user = ctx
@@ -108,13 +318,89 @@ impl UsersMutation {
             .as_ref()
             .ok_or_else(|| Error::new("Unauthorized"))?;
         Ok(
-            users_service::update_name(db, user.id, input.first_name, input.last_name)
+            users_service::update_username(&db.session(), ctx.data::<Cache>()?, user.id, &username)
                 .await?
                 .into(),
         )
     }
 
-    #[graphql(guard = "AuthGuard")]
+    /// Derives the username from the current name again, like on sign up.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn regenerate_username(&self, ctx: &Context<'_>) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(
+            users_service::regenerate_username(&db.session(), ctx.data::<Cache>()?, user.id)
+                .await?
+                .into(),
+        )
+    }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn update_privacy_settings(
+        &self,
+        ctx: &Context<'_>,
+        input: PrivacySettings,
+    ) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::update_privacy_settings(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            user.id,
+            input.show_age,
+        )
+        .await?
+        .into())
+    }
+
+    /// Accepts the current terms of service, lifting the `TERMS_OUTDATED` restriction.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn accept_terms(&self, ctx: &Context<'_>) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::accept_terms(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            ctx.data::<TermsVersion>()?,
+            user.id,
+        )
+        .await?
+        .into())
+    }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn update_user_preferences(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(custom = "UpdatePreferencesValidator"))] input: UpdatePreferences,
+    ) -> Result<User> {
+        let db = ctx.data::<Database>()?;
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::update_preferences(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            user.id,
+            input.locale,
+            input.timezone,
+        )
+        .await?
+        .into())
+    }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
     async fn update_user_email(
         &self,
         ctx: &Context<'_>,
@@ -125,19 +411,182 @@ impl UsersMutation {
             .data::<Option<AccessUser>>()?
             .as_ref()
             .ok_or_else(|| Error::new("Unauthorized"))?;
-        Ok(users_service::update_email(db, user.id, &email)
-            .await?
-            .into())
+        Ok(users_service::update_email(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            ctx.data::<Jwt>()?,
+            ctx.data::<Mailer>()?,
+            ctx.data::<Webhooks>()?,
+            user.id,
+            &email,
+        )
+        .await?
+        .into())
     }
 
-    #[graphql(guard = "AuthGuard")]
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
     async fn delete_user(&self, ctx: &Context<'_>) -> Result<Message> {
         let db = ctx.data::<Database>()?;
         let user = ctx
             .data::<Option<AccessUser>>()?
             .as_ref()
             .ok_or_else(|| Error::new("Unauthorized"))?;
-        users_service::delete_user(db, user.id).await?;
+        users_service::delete_user(
+            &db.session(),
+            ctx.data::<Cache>()?,
+            ctx.data::<Webhooks>()?,
+            user.id,
+        )
+        .await?;
         Ok(Message::new("User deleted successfully"))
     }
+
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn revoke_session(&self, ctx: &Context<'_>, token_id: String) -> Result<Message> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        auth_service::revoke_session(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Cache>()?,
+            user.id,
+            &token_id,
+        )
+        .await?;
+        Ok(Message::new("Session revoked successfully"))
+    }
+
+    /// The next sign in from the device sends a new device alert again.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn forget_device(&self, ctx: &Context<'_>, device_key: String) -> Result<Message> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        devices_service::forget_device(ctx.data::<Cache>()?, user.id, &device_key).await?;
+        Ok(Message::new("Device forgotten successfully"))
+    }
+
+    /// Invalidates any previous set, the codes cannot be retrieved again.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn generate_recovery_codes(&self, ctx: &Context<'_>) -> Result<RecoveryCodes> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(RecoveryCodes {
+            codes: recovery_codes_service::generate_codes(ctx.data::<Database>()?, user.id).await?,
+        })
+    }
+
+    /// For accounts created through a provider, the first call emails the code to pass.
+    #[graphql(guard = "AuthGuard.and(NoImpersonationGuard)")]
+    async fn set_password(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(custom = "SetPasswordValidator"))] input: SetPassword,
+    ) -> Result<Message> {
+        let user = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        match auth_service::set_password(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Cache>()?,
+            ctx.data::<Jwt>()?,
+            ctx.data::<Mailer>()?,
+            user.id,
+            input,
+        )
+        .await?
+        {
+            responses::SetPassword::Updated => Ok(Message::new("Password set successfully")),
+            responses::SetPassword::CodeSent => {
+                Ok(Message::new("Confirmation code sent, check your email"))
+            }
+        }
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn unlock_user(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(email, min_length = 5, max_length = 200))] email: String,
+    ) -> Result<Message> {
+        auth_service::unlock_user(ctx.data::<Cache>()?, &email).await?;
+        Ok(Message::new("User unlocked successfully"))
+    }
+
+    /// Keeps `username` and its numbered variants from being claimed by anyone.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn reserve_username(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(custom = "UsernameValidator"))] username: String,
+    ) -> Result<Message> {
+        users_service::reserve_username(&ctx.data::<Database>()?.session(), &username).await?;
+        Ok(Message::new("Username reserved successfully"))
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn restore_user(&self, ctx: &Context<'_>, id: i32) -> Result<User> {
+        Ok(users_service::restore_user(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Cache>()?,
+            id,
+        )
+        .await?
+        .into())
+    }
+
+    /// Deletes up to 500 users, skipping admins and the caller, `dryRun` only reports.
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn bulk_delete_users(
+        &self,
+        ctx: &Context<'_>,
+        ids: Vec<i32>,
+        dry_run: bool,
+    ) -> Result<BulkDeleteReport> {
+        let admin = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(users_service::bulk_delete_users(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Cache>()?,
+            ctx.data::<Webhooks>()?,
+            admin.id,
+            ids,
+            dry_run,
+        )
+        .await?)
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn update_user_role(&self, ctx: &Context<'_>, id: i32, role: RoleEnum) -> Result<User> {
+        Ok(users_service::update_role(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Cache>()?,
+            id,
+            role,
+        )
+        .await?
+        .into())
+    }
+
+    #[graphql(guard = "RoleGuard::new(RoleEnum::Admin).and(NoImpersonationGuard)")]
+    async fn impersonate_user(&self, ctx: &Context<'_>, id: i32) -> Result<Impersonation> {
+        let admin = ctx
+            .data::<Option<AccessUser>>()?
+            .as_ref()
+            .ok_or_else(|| Error::new("Unauthorized"))?;
+        Ok(auth_service::impersonate_user(
+            &ctx.data::<Database>()?.session(),
+            ctx.data::<Jwt>()?,
+            admin.id,
+            id,
+        )
+        .await?)
+    }
 }
@@ -0,0 +1,133 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use anyhow::Error;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, EntityTrait, ModelTrait,
+    QueryFilter, Set,
+};
+
+use entities::{
+    api_key::{ActiveModel, Column, Entity, Model},
+    enums::RoleEnum,
+    user,
+};
+
+use crate::common::{ServiceError, UNAUTHORIZED};
+use crate::providers::Database;
+
+use super::helpers::{hash_code, random_string, verify_code};
+
+pub const API_KEY_PREFIX: &str = "rgt";
+const PREFIX_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 32;
+const LAST_USED_PRECISION_SECONDS: i64 = 60;
+const API_KEY_NOT_FOUND: &str = "API key not found";
+
+/// Splits `rgt_<prefix>_<secret>` into its prefix and secret.
+pub fn parse_api_key(api_key: &str) -> Option<(&str, &str)> {
+    let (prefix, secret) = api_key
+        .strip_prefix(API_KEY_PREFIX)?
+        .strip_prefix('_')?
+        .split_once('_')?;
+
+    if prefix.len() != PREFIX_LENGTH || secret.len() != SECRET_LENGTH {
+        return None;
+    }
+
+    Some((prefix, secret))
+}
+
+/// Returns the key with its plaintext, which is never stored nor shown again.
+pub async fn create_api_key(
+    db: &Database,
+    user_id: i32,
+    name: &str,
+    expires_in_days: Option<i64>,
+) -> Result<(Model, String), ServiceError> {
+    tracing::info_span!("api_keys_service::create_api_key", %user_id);
+    let prefix = random_string(PREFIX_LENGTH);
+    let secret = random_string(SECRET_LENGTH);
+    let api_key = ActiveModel {
+        user_id: Set(user_id),
+        name: Set(name.trim().to_string()),
+        prefix: Set(prefix.clone()),
+        key_hash: Set(hash_code(&secret)),
+        expires_at: Set(expires_in_days.map(|days| Utc::now().naive_utc() + Duration::days(days))),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await?;
+    tracing::info!("API key {} created", api_key.id);
+    Ok((api_key, format!("{}_{}_{}", API_KEY_PREFIX, prefix, secret)))
+}
+
+pub async fn find_api_keys(db: &Database, user_id: i32) -> Result<Vec<Model>, ServiceError> {
+    tracing::info_span!("api_keys_service::find_api_keys", %user_id);
+    Ok(Entity::find_by_user_id(user_id)
+        .all(db.get_connection())
+        .await?)
+}
+
+pub async fn revoke_api_key(db: &Database, user_id: i32, id: i32) -> Result<(), ServiceError> {
+    tracing::info_span!("api_keys_service::revoke_api_key", %user_id, %id);
+    let api_key = Entity::find_by_id_and_user_id(id, user_id)
+        .one(db.get_connection())
+        .await?
+        .ok_or_else(|| ServiceError::not_found::<Error>(API_KEY_NOT_FOUND, None))?;
+    api_key.delete(db.get_connection()).await?;
+    Ok(())
+}
+
+async fn touch_api_key(db: &Database, api_key: &Model) -> Result<(), ServiceError> {
+    let now = Utc::now().naive_utc();
+    let threshold = now - Duration::seconds(LAST_USED_PRECISION_SECONDS);
+
+    if api_key
+        .last_used_at
+        .is_some_and(|last_used_at| last_used_at > threshold)
+    {
+        return Ok(());
+    }
+
+    // The condition is repeated so concurrent requests write at most once
+    Entity::update_many()
+        .col_expr(Column::LastUsedAt, Expr::value(now))
+        .filter(
+            Condition::all().add(Column::Id.eq(api_key.id)).add(
+                Condition::any()
+                    .add(Column::LastUsedAt.is_null())
+                    .add(Column::LastUsedAt.lte(threshold)),
+            ),
+        )
+        .exec(db.get_connection())
+        .await?;
+    Ok(())
+}
+
+/// Resolves the owner of the key, comparing the secret hash in constant time.
+pub async fn authenticate(db: &Database, api_key: &str) -> Result<(i32, RoleEnum), ServiceError> {
+    tracing::info_span!("api_keys_service::authenticate");
+    let unauthorized = || ServiceError::unauthorized::<Error>(UNAUTHORIZED, None);
+    let (prefix, secret) = parse_api_key(api_key).ok_or_else(unauthorized)?;
+    let api_key = Entity::find_by_prefix(prefix)
+        .one(db.get_connection())
+        .await?
+        .ok_or_else(unauthorized)?;
+
+    if !verify_code(secret, &api_key.key_hash) || api_key.is_expired() {
+        return Err(unauthorized());
+    }
+
+    let user = user::Entity::find_by_id(api_key.user_id)
+        .one(db.get_connection())
+        .await?
+        .filter(|user| user.confirmed && !user.suspended)
+        .ok_or_else(unauthorized)?;
+    touch_api_key(db, &api_key).await?;
+    Ok((user.id, user.role))
+}
@@ -5,30 +5,55 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use anyhow::Error;
-use bcrypt::{hash, verify};
+use chrono::Utc;
 use oauth2::{
-    reqwest::async_http_client, AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
-    Scope, TokenResponse,
+    AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RequestTokenError, Scope,
+    TokenResponse,
 };
 use rand::Rng;
 use redis::AsyncCommands;
-use reqwest::Client;
-use sea_orm::ActiveModelTrait;
+use reqwest::header::AUTHORIZATION;
 use sea_orm::ActiveValue::Set;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter,
+};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 
-use entities::{enums::oauth_provider_enum::OAuthProviderEnum, oauth_provider, user};
+use entities::{
+    enums::{oauth_provider_enum::OAuthProviderEnum, RoleEnum},
+    oauth_provider, user,
+};
 
+use super::helpers::{
+    hash_code, hash_password, needs_rehash, random_string, verify_code, verify_password,
+};
+use super::{
+    devices_service, invitations_service, recovery_codes_service, sessions_service,
+    token_blacklist_service, users_service,
+};
 use crate::common::{
-    InternalCause, ServiceError, INVALID_CREDENTIALS, NOT_FOUND_STATUS_CODE, SOMETHING_WENT_WRONG,
-    UNAUTHORIZED_STATUS_CODE,
+    validate_password, ClientInfo, InternalCause, ServiceError, ValidatorEnum, FORBIDDEN,
+    INVALID_CREDENTIALS, SOMETHING_WENT_WRONG,
+};
+use crate::dtos::{bodies, inputs, objects, queries, responses};
+use crate::providers::{
+    Cache, DbSession, DeviceAlerts, ExternalProvider, HttpClient, Jwt, Lockout, Mailer, OAuth,
+    SignUpMode, TermsVersion, TokenBinding, TokenType, Webhooks,
 };
-use crate::dtos::{bodies, queries, responses};
-use crate::providers::{Cache, Database, ExternalProvider, Jwt, Mailer, OAuth, TokenType};
-use crate::services::helpers::hash_password;
-
-use super::{helpers::verify_password, users_service};
 
-const BLACKLIST_TOKEN: &'static str = "blacklist_token";
+const SIGN_IN_ATTEMPTS: &str = "sign_in_attempts";
+const SIGN_IN_LOCKOUTS: &str = "sign_in_lockouts";
+const SIGN_IN_LOCK: &str = "sign_in_lock";
+const SIGN_IN_ATTEMPTS_WINDOW: i64 = 86400;
+const OAUTH_STATE_PREFIX: &str = "oauth_state";
+const SOCIAL_LOGIN_ACCOUNT: &str = "This account uses social login";
+const CONFIRMATION_SENT_PREFIX: &str = "confirmation_sent";
+const CONFIRMATION_RESEND_COOLDOWN: i64 = 60;
+const SESSION_EXPIRED: &str = "Session expired, please sign in again";
+const INVALID_CODE: &str = "Invalid code";
+const INVALID_TOKEN: &str = "Invalid token";
+const OAUTH_LINK_NONCE_LENGTH: usize = 32;
 
 fn generate_random_code() -> String {
     let mut code = String::new();
@@ -39,39 +64,94 @@ fn generate_random_code() -> String {
     code
 }
 
-fn generate_email_code() -> Result<(String, String), ServiceError> {
+fn generate_email_code() -> (String, String) {
     tracing::info!("Generating random access code");
     let code = generate_random_code();
-    let code_hash = hash(&code, 5)
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    Ok((code, code_hash))
+    let code_hash = hash_code(&code);
+    (code, code_hash)
 }
 
-fn verify_code(code: &str, hashed_code: &str) -> bool {
-    tracing::info!("Verifying access code");
-    if let Ok(result) = verify(code, hashed_code) {
-        return result;
+/// `auth_time` is when the user last proved their credentials, `None` for a sign in now.
+async fn generate_session_tokens(
+    cache: &Cache,
+    jwt: &Jwt,
+    user: &user::Model,
+    client: &ClientInfo,
+    auth_time: Option<i64>,
+) -> Result<responses::Auth, ServiceError> {
+    let auth_time = auth_time.unwrap_or_else(|| Utc::now().timestamp());
+    let fingerprint =
+        (jwt.get_token_binding() != TokenBinding::Off).then(|| client_fingerprint(client));
+    let (access_token, refresh_token) =
+        jwt.generate_auth_tokens(user, auth_time, fingerprint.as_deref())?;
+    let (_, _, token_id, exp) = jwt.verify_email_token(TokenType::Refresh, &refresh_token)?;
+    sessions_service::create_session(cache, user.id, &token_id, exp, client).await?;
+    Ok(responses::Auth::new(
+        access_token,
+        refresh_token,
+        jwt.get_access_token_time(),
+    ))
+}
+
+/// The `X-Client-Id` of the client, or the family of its user agent when it sends none.
+fn client_fingerprint(client: &ClientInfo) -> String {
+    match &client.client_id {
+        Some(client_id) => hash_fingerprint(&format!("id:{}", client_id)),
+        None => user_agent_fingerprint(client),
     }
+}
 
-    false
+fn user_agent_fingerprint(client: &ClientInfo) -> String {
+    hash_fingerprint(&format!(
+        "ua:{}",
+        devices_service::user_agent_family(client.user_agent.as_deref())
+    ))
 }
 
-async fn find_oauth_provider(
-    db: &Database,
-    email: &str,
-    provider: OAuthProviderEnum,
-) -> Result<oauth_provider::Model, ServiceError> {
-    tracing::info!("Finding OAuth provider: {}", provider.to_str());
-    let provider = oauth_provider::Entity::find_by_email_and_provider(email, provider)
-        .one(db.get_connection())
-        .await?;
-    if let Some(provider) = provider {
-        Ok(provider)
-    } else {
-        Err(ServiceError::unauthorized(
-            "Invalid credentials",
-            Some(InternalCause::new("OAuth provider not found")),
-        ))
+fn hash_fingerprint(source: &str) -> String {
+    format!("{:x}", Sha256::digest(source.as_bytes()))
+}
+
+/// A refresh token presented by another client than the one it was issued to was
+/// most likely stolen, so when enforced it is blacklisted along with its session.
+#[allow(clippy::too_many_arguments)]
+async fn check_token_binding(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    client: &ClientInfo,
+    user_id: i32,
+    token_id: &str,
+    exp: i64,
+    bound_fingerprint: Option<&str>,
+) -> Result<(), ServiceError> {
+    let Some(bound_fingerprint) = bound_fingerprint else {
+        return Ok(());
+    };
+    // Browser navigations, like the OAuth callback, can not send the client id, so
+    // tokens they issued are bound to the user agent until their first rotation
+    if bound_fingerprint == client_fingerprint(client)
+        || bound_fingerprint == user_agent_fingerprint(client)
+    {
+        return Ok(());
+    }
+
+    match jwt.get_token_binding() {
+        TokenBinding::Off => Ok(()),
+        TokenBinding::Warn => {
+            tracing::warn!(%user_id, %token_id, "Refresh token presented by another client");
+            Ok(())
+        }
+        TokenBinding::Enforce => {
+            tracing::warn!(%user_id, %token_id, "Rejected refresh token of another client");
+            token_blacklist_service::blacklist_token(db.database(), cache, user_id, token_id, exp)
+                .await?;
+            sessions_service::remove_session(cache, user_id, token_id).await?;
+            Err(ServiceError::unauthorized(
+                INVALID_TOKEN,
+                Some(InternalCause::new("Refresh token bound to another client")),
+            ))
+        }
     }
 }
 
@@ -85,42 +165,211 @@ async fn create_code(
     let exp_usize = u64::try_from(exp)
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
     let key = format!("access_code:{}", email);
-    let mut connection = cache.get_connection().await?;
-    connection
-        .set_ex(&key, &code_hash, exp_usize)
+    let (key, code_hash) = (key.as_str(), code_hash.as_str());
+    cache
+        .execute(|mut connection| async move {
+            connection
+                .set_ex::<&str, &str, ()>(key, code_hash, exp_usize)
+                .await
+        })
         .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    Ok(())
 }
 
 async fn validate_code(cache: &Cache, email: &str, code: &str) -> Result<(), ServiceError> {
     tracing::info!("Validating two factor code");
     let key = format!("access_code:{}", email);
-    let mut connection = cache.get_connection().await?;
-    let hashed_code: Option<String> = connection
-        .get(&key)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let hashed_code = cache
+        .execute(|mut connection| {
+            let key = key.as_str();
+            async move { connection.get::<&str, Option<String>>(key).await }
+        })
+        .await?;
     if let Some(hashed_code) = hashed_code {
+        tracing::info!("Verifying access code");
         if verify_code(code, &hashed_code) {
-            connection
-                .del(&key)
-                .await
-                .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+            cache.del(&key).await?;
             return Ok(());
         }
 
-        return Err(ServiceError::unauthorized::<Error>("Invalid code", None));
+        return Err(ServiceError::unauthorized::<Error>(INVALID_CODE, None));
+    }
+    // Expired like wrong, an email without a pending sign in is not told apart
+    Err(ServiceError::unauthorized(
+        INVALID_CODE,
+        Some(InternalCause::new("No pending code")),
+    ))
+}
+
+/// Recovery codes stand in for the emailed code, so a sign in must still be pending.
+async fn validate_recovery_code(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user: &user::Model,
+    recovery_code: &str,
+) -> Result<(), ServiceError> {
+    tracing::info!("Validating recovery code");
+    let key = format!("access_code:{}", &user.email);
+    let pending = cache
+        .execute(|mut connection| {
+            let key = key.as_str();
+            async move { connection.exists::<&str, bool>(key).await }
+        })
+        .await?;
+
+    if !pending {
+        return Err(ServiceError::unauthorized(
+            INVALID_CODE,
+            Some(InternalCause::new("No pending code")),
+        ));
     }
-    Err(ServiceError::unauthorized::<Error>("Code expired", None))
+
+    recovery_codes_service::use_code(db.database(), user.id, recovery_code).await?;
+    cache.del(&key).await
+}
+
+async fn rehash_password(
+    db: &DbSession<'_>,
+    user: user::Model,
+    password: &str,
+) -> Result<user::Model, ServiceError> {
+    if !needs_rehash(&user.password) {
+        return Ok(user);
+    }
+
+    tracing::info!("Upgrading password hash of user with id {}", user.id);
+    let mut changes: user::ActiveModel = user.clone().into();
+    changes.password = Set(hash_password(password)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?);
+
+    // A concurrent update wins, the hash is upgraded on the next sign in instead
+    Ok(users_service::update_versioned(db, &user, changes)
+        .await?
+        .unwrap_or(user))
+}
+
+fn locked_error(remaining_seconds: i64) -> ServiceError {
+    let remaining_minutes = (remaining_seconds + 59) / 60;
+    ServiceError::forbidden::<Error>(
+        &format!(
+            "Your account is locked, try again in {} minute(s)",
+            remaining_minutes
+        ),
+        None,
+    )
+}
+
+async fn get_lock_time(cache: &Cache, email: &str) -> Result<Option<i64>, ServiceError> {
+    let key = format!("{}:{}", SIGN_IN_LOCK, email);
+    let key = key.as_str();
+    let ttl = cache
+        .execute(|mut connection| async move { connection.ttl::<&str, i64>(key).await })
+        .await?;
+
+    if ttl > 0 {
+        return Ok(Some(ttl));
+    }
+
+    Ok(None)
+}
+
+async fn increment_with_window(cache: &Cache, key: &str) -> Result<i64, ServiceError> {
+    let value = cache
+        .execute(|mut connection| async move { connection.incr::<&str, i64, i64>(key, 1).await })
+        .await?;
+    cache
+        .execute(|mut connection| async move {
+            connection
+                .expire::<&str, ()>(key, SIGN_IN_ATTEMPTS_WINDOW)
+                .await
+        })
+        .await?;
+    Ok(value)
+}
+
+async fn register_failed_sign_in(
+    cache: &Cache,
+    lockout: &Lockout,
+    email: &str,
+) -> Result<Option<u64>, ServiceError> {
+    tracing::info!("Registering failed sign in attempt");
+    let attempts_key = format!("{}:{}", SIGN_IN_ATTEMPTS, email);
+    let attempts = increment_with_window(cache, &attempts_key).await?;
+
+    if attempts < lockout.get_max_attempts() {
+        return Ok(None);
+    }
+
+    tracing::warn!("Too many failed sign in attempts, locking account");
+    let lockouts_key = format!("{}:{}", SIGN_IN_LOCKOUTS, email);
+    let lockouts = increment_with_window(cache, &lockouts_key).await?;
+    let lock_time = lockout.get_lock_duration(lockouts);
+    let lock_key = format!("{}:{}", SIGN_IN_LOCK, email);
+    let lock_key = lock_key.as_str();
+    cache
+        .execute(|mut connection| async move {
+            connection
+                .set_ex::<&str, i64, ()>(lock_key, lockouts, lock_time)
+                .await
+        })
+        .await?;
+    cache.del(&attempts_key).await?;
+    Ok(Some(lock_time))
+}
+
+async fn clear_failed_sign_ins(cache: &Cache, email: &str) -> Result<(), ServiceError> {
+    tracing::info!("Clearing failed sign in attempts");
+    let keys = [
+        format!("{}:{}", SIGN_IN_ATTEMPTS, email),
+        format!("{}:{}", SIGN_IN_LOCKOUTS, email),
+        format!("{}:{}", SIGN_IN_LOCK, email),
+    ];
+    let keys = &keys;
+    cache
+        .execute(|mut connection| async move { connection.del::<_, ()>(keys).await })
+        .await
+}
+
+/// Kept as long as the confirmation token it announces is valid.
+pub async fn record_confirmation_sent(
+    cache: &Cache,
+    jwt: &Jwt,
+    user_id: i32,
+) -> Result<(), ServiceError> {
+    let key = format!("{}:{}", CONFIRMATION_SENT_PREFIX, user_id);
+    let key = key.as_str();
+    let ttl = jwt.get_email_token_time(TokenType::Confirmation).max(1) as u64;
+    cache
+        .execute(|mut connection| async move {
+            connection
+                .set_ex::<&str, i64, ()>(key, Utc::now().timestamp(), ttl)
+                .await
+        })
+        .await
+}
+
+pub async fn get_confirmation_sent_at(
+    cache: &Cache,
+    user_id: i32,
+) -> Result<Option<i64>, ServiceError> {
+    tracing::info_span!("auth_service::get_confirmation_sent_at");
+    let key = format!("{}:{}", CONFIRMATION_SENT_PREFIX, user_id);
+    let key = key.as_str();
+    cache
+        .execute(|mut connection| async move { connection.get::<&str, Option<i64>>(key).await })
+        .await
 }
 
 // TODO: add traces to all pub fn
 
+#[allow(clippy::too_many_arguments)]
 pub async fn sign_up(
-    db: &Database,
+    db: &DbSession<'_>,
+    cache: &Cache,
     jwt: &Jwt,
     mailer: &Mailer,
+    webhooks: &Webhooks,
+    sign_up_mode: SignUpMode,
+    terms_version: &TermsVersion,
     body: bodies::SignUp,
 ) -> Result<(), ServiceError> {
     tracing::info_span!("auth_service::sign_up");
@@ -131,60 +380,145 @@ pub async fn sign_up(
         ));
     }
 
-    let user = users_service::create_user(
+    let defaults = users_service::UserPreferences::default();
+    let txn = db.begin().await?;
+    // Accepted in the same transaction, a failed sign up leaves the invitation usable
+    if sign_up_mode == SignUpMode::InviteOnly {
+        let token = body.invitation_token.as_deref().ok_or_else(|| {
+            ServiceError::forbidden::<Error>(invitations_service::INVITATION_REQUIRED, None)
+        })?;
+        invitations_service::accept_invitation(&txn, token, &body.email).await?;
+    }
+    let user = users_service::insert_user(
         db,
+        &txn,
         body.first_name,
         body.last_name,
-        body.date_of_birth,
+        Some(body.date_of_birth),
         body.email,
         body.password1,
         OAuthProviderEnum::Local,
+        users_service::UserPreferences {
+            locale: body.locale.unwrap_or(defaults.locale),
+            timezone: body.timezone.unwrap_or(defaults.timezone),
+        },
+        // Validation rejects a sign up that does not accept the terms
+        terms_version.current().map(str::to_string),
     )
     .await?;
     tracing::info!("User created");
     let confirmation_token = jwt.generate_email_token(TokenType::Confirmation, &user)?;
-    mailer.send_confirmation_email(&user.email, &user.full_name(), &confirmation_token)?;
+    // The user is only committed together with its confirmation email
+    mailer
+        .send_confirmation_email(
+            &txn,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &confirmation_token,
+        )
+        .await?;
+    txn.commit().await?;
+    record_confirmation_sent(cache, jwt, user.id).await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::UserRegistered,
+        &user,
+    ));
     tracing::info!("Successfully signed up user");
     Ok(())
 }
 
+/// The device confirming the email is remembered without an alert, it is the first.
 pub async fn confirm_email(
-    db: &Database,
+    db: &DbSession<'_>,
+    cache: &Cache,
     jwt: &Jwt,
+    webhooks: &Webhooks,
+    device_alerts: &DeviceAlerts,
     token: &str,
+    client: &ClientInfo,
 ) -> Result<responses::Auth, ServiceError> {
     tracing::info_span!("auth_service::confirm_email");
     let (id, version, _, _) = jwt.verify_email_token(TokenType::Confirmation, token)?;
     let user = users_service::find_one_by_version(db, id, version).await?;
     tracing::info!("User found with id {}", id);
-    let mut user: user::ActiveModel = user.into();
-    user.confirmed = Set(true);
-    user.version = Set(version + 1);
-    let user = user.update(db.get_connection()).await?;
+    let mut changes: user::ActiveModel = user.clone().into();
+    changes.confirmed = Set(true);
+    // Revoking older tokens makes the confirmation token single use
+    users_service::revoke_tokens(&mut changes, users_service::next_version(user.version, 1)?);
+    let user = db
+        .transaction(|txn| {
+            Box::pin(async move {
+                users_service::update_versioned(txn, &user, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)
+            })
+        })
+        .await?;
+    users_service::invalidate_cached_user(cache, id).await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::UserConfirmed,
+        &user,
+    ));
 
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
+    let auth = generate_session_tokens(cache, jwt, &user, client, None).await?;
+    devices_service::remember_device(cache, device_alerts, user.id, client).await?;
     tracing::info!("Successfully confirmed user with id {}", id);
-    Ok(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    ))
+    Ok(auth)
 }
 
+/// The revert token is not tied to a version, it stays usable after the address is
+/// changed again, but only until the email it restores is back in place.
+pub async fn revert_email(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    webhooks: &Webhooks,
+    token: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("auth_service::revert_email");
+    let (id, version, previous_email) = jwt.verify_revert_token(token)?;
+    let user =
+        users_service::restore_email(db, cache, webhooks, id, version, &previous_email).await?;
+    sessions_service::clear_sessions(cache, id).await?;
+    tracing::info!("Reverted email change of user with id {}", user.id);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn sign_in(
-    db: &Database,
+    db: &DbSession<'_>,
     cache: &Cache,
     jwt: &Jwt,
     mailer: &Mailer,
+    lockout: &Lockout,
+    device_alerts: &DeviceAlerts,
     body: bodies::SignIn,
+    client: &ClientInfo,
 ) -> Result<responses::SignIn, ServiceError> {
     tracing::info_span!("auth_service::sign_in");
-    let user = users_service::find_one_by_email(db, &body.email.to_lowercase()).await?;
+    let (user, provider) =
+        users_service::find_one_by_email_with_local_provider(db, &body.email.to_lowercase())
+            .await?;
+
+    if let Some(lock_time) = get_lock_time(cache, &user.email).await? {
+        tracing::warn!("User with id {} is locked", user.id);
+        return Err(locked_error(lock_time));
+    }
 
     if !user.confirmed {
         tracing::warn!("User with id {} not confirmed", user.id);
         let confirmation_token = jwt.generate_email_token(TokenType::Confirmation, &user)?;
-        mailer.send_confirmation_email(&user.email, &user.full_name(), &confirmation_token)?;
+        mailer
+            .send_confirmation_email(
+                db,
+                &user.email,
+                &user.full_name(),
+                &user.preferred_locale,
+                &confirmation_token,
+            )
+            .await?;
+        record_confirmation_sent(cache, jwt, user.id).await?;
         return Err(ServiceError::unauthorized::<ServiceError>(
             "Please confirm your email",
             None,
@@ -197,133 +531,272 @@ pub async fn sign_in(
             None,
         ));
     }
+    // Accounts created through a provider only store a placeholder password
+    if provider.is_none() {
+        tracing::warn!("User with id {} has no local provider", user.id);
+        return Err(ServiceError::unauthorized::<ServiceError>(
+            SOCIAL_LOGIN_ACCOUNT,
+            None,
+        ));
+    }
     if !verify_password(&body.password, &user.password) {
         tracing::warn!("User with id {} did not pass the correct password", user.id);
+
+        if let Some(lock_time) = register_failed_sign_in(cache, lockout, &user.email).await? {
+            mailer
+                .send_security_alert_email(
+                    db,
+                    &user.email,
+                    &user.full_name(),
+                    &user.preferred_locale,
+                    lock_time / 60,
+                )
+                .await?;
+            return Err(locked_error(lock_time as i64));
+        }
+
         return Err(ServiceError::unauthorized::<ServiceError>(
             INVALID_CREDENTIALS,
             None,
         ));
     }
 
-    let provider = find_oauth_provider(db, &user.email, OAuthProviderEnum::Local).await?;
-    if provider.two_factor {
-        tracing::info!("User with id {} has two factor enabled", user.id);
-        let (code, code_hash) = generate_email_code()?;
-        create_code(
-            cache,
-            &user.email,
-            code_hash,
-            jwt.get_email_token_time(TokenType::Confirmation),
-        )
-        .await?;
-        mailer.send_access_email(&user.email, &user.full_name(), &code)?;
+    clear_failed_sign_ins(cache, &user.email).await?;
+    let user = rehash_password(db, user, &body.password).await?;
+
+    if user.two_factor {
+        send_sign_in_code(db, cache, jwt, mailer, &user).await?;
         tracing::info!("User with id {} successfully sign in with MFA", user.id);
         return Ok(responses::SignIn::Mfa);
     }
 
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
+    let user = users_service::update_last_login(db, cache, user).await?;
+    let auth = generate_session_tokens(cache, jwt, &user, client, None).await?;
+    devices_service::check_device(db.database(), cache, mailer, device_alerts, &user, client)
+        .await?;
     tracing::info!("User with id {} successfully sign in without MFA", user.id);
-    Ok(responses::SignIn::Auth(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    )))
+    Ok(responses::SignIn::Auth(auth))
+}
+
+/// Tokens are only issued once the emailed code is passed to `confirm_sign_in`.
+async fn send_sign_in_code(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    user: &user::Model,
+) -> Result<(), ServiceError> {
+    tracing::info!("User with id {} has two factor enabled", user.id);
+    let (code, code_hash) = generate_email_code();
+    create_code(
+        cache,
+        &user.email,
+        code_hash,
+        jwt.get_email_token_time(TokenType::Confirmation),
+    )
+    .await?;
+    mailer
+        .send_access_email(
+            db,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &code,
+        )
+        .await
 }
 
 pub async fn confirm_sign_in(
-    db: &Database,
+    db: &DbSession<'_>,
     cache: &Cache,
     jwt: &Jwt,
+    mailer: &Mailer,
+    device_alerts: &DeviceAlerts,
     body: bodies::ConfirmSignIn,
+    client: &ClientInfo,
 ) -> Result<responses::Auth, ServiceError> {
     tracing::info_span!("auth_service::confirm_sign_in");
-    let email = body.email.to_lowercase();
-    let user = users_service::find_one_by_email(db, &email).await?;
-    validate_code(cache, &email, &body.code).await?;
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
-    Ok(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    ))
-}
+    // An unknown email fails like a wrong code, a typo does not tell the account apart
+    let user = users_service::find_one_by_email(db, &body.email)
+        .await?
+        .ok_or_else(|| ServiceError::unauthorized::<Error>(INVALID_CODE, None))?;
 
-async fn check_blacklist(cache: &Cache, token_id: &str) -> Result<bool, ServiceError> {
-    let mut connection = cache.get_connection().await?;
-    let key = format!("{}:{}", BLACKLIST_TOKEN, token_id);
-    let value: Option<i32> = connection
-        .get(&key)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    Ok(value.is_some())
+    // Codes are stored under the address of the account, not the alias signed in with
+    match &body.recovery_code {
+        Some(recovery_code) => validate_recovery_code(db, cache, &user, recovery_code).await?,
+        None => validate_code(cache, &user.email, &body.code).await?,
+    }
+
+    let user = users_service::update_last_login(db, cache, user).await?;
+    let auth = generate_session_tokens(cache, jwt, &user, client, None).await?;
+    devices_service::check_device(db.database(), cache, mailer, device_alerts, &user, client)
+        .await?;
+    Ok(auth)
 }
 
 pub async fn refresh_token(
-    db: &Database,
+    db: &DbSession<'_>,
     cache: &Cache,
     jwt: &Jwt,
     refresh_token: &str,
+    client: &ClientInfo,
 ) -> Result<responses::Auth, ServiceError> {
     tracing::info_span!("auth_service::refresh_token");
-    let (id, version, token_id, exp) =
-        jwt.verify_email_token(TokenType::Refresh, &refresh_token)?;
+    let (id, version, token_id, exp, auth_time, fingerprint) =
+        jwt.verify_refresh_token(refresh_token)?;
 
-    if check_blacklist(cache, &token_id).await? {
+    if token_blacklist_service::is_blacklisted(db.database(), cache, &token_id).await? {
         return Err(ServiceError::unauthorized(
-            "Invalid token",
+            INVALID_TOKEN,
             Some(InternalCause::new("Token is blacklisted")),
         ));
     }
+    check_token_binding(
+        db,
+        cache,
+        jwt,
+        client,
+        id,
+        &token_id,
+        exp,
+        fingerprint.as_deref(),
+    )
+    .await?;
+
+    // Rotation never extends a session past its lifetime, a stolen token dies with it
+    if Utc::now().timestamp() - auth_time > jwt.get_session_max_lifetime() {
+        return Err(ServiceError::unauthorized(
+            SESSION_EXPIRED,
+            Some(InternalCause::new("Session exceeded its maximum lifetime")),
+        ));
+    }
 
     let user = users_service::find_one_by_version(db, id, version).await?;
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
-    create_blacklisted_token(cache, id, &token_id, exp).await?;
-    return Ok(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    ));
+    let auth = generate_session_tokens(cache, jwt, &user, client, Some(auth_time)).await?;
+    token_blacklist_service::blacklist_token(db.database(), cache, id, &token_id, exp).await?;
+    sessions_service::remove_session(cache, id, &token_id).await?;
+    Ok(auth)
 }
 
-pub async fn forgot_password(
-    db: &Database,
+/// Unknown and already confirmed emails are ignored so they can not be enumerated, and so
+/// are resends within the cooldown, so the endpoint can not be used to flood an inbox.
+pub async fn resend_confirmation_email(
+    db: &DbSession<'_>,
+    cache: &Cache,
     jwt: &Jwt,
     mailer: &Mailer,
     email: &str,
 ) -> Result<(), ServiceError> {
-    tracing::info_span!("auth_service::forgot_password");
-    let email = email.to_lowercase();
+    tracing::info_span!("auth_service::resend_confirmation_email");
+    let Some(user) = users_service::find_one_by_email(db, &email.to_lowercase()).await? else {
+        tracing::trace_span!("Failed to find user");
+        return Ok(());
+    };
 
-    if let Err(err) = find_oauth_provider(db, &email, OAuthProviderEnum::Local).await {
-        if err.get_status_code() == UNAUTHORIZED_STATUS_CODE {
-            tracing::trace_span!("Failed to find user local OAuth provider");
+    if user.confirmed {
+        tracing::info!("User with id {} is already confirmed", user.id);
+        return Ok(());
+    }
+
+    if let Some(sent_at) = get_confirmation_sent_at(cache, user.id).await? {
+        if Utc::now().timestamp() - sent_at < CONFIRMATION_RESEND_COOLDOWN {
+            tracing::info!("Confirmation email of user {} sent too recently", user.id);
             return Ok(());
         }
-
-        return Err(err);
     }
 
-    let user = match users_service::find_one_by_email(db, &email).await {
-        Ok(user) => user,
-        Err(err) => {
-            if err.get_status_code() == NOT_FOUND_STATUS_CODE {
-                tracing::trace_span!("Failed to find user");
-                return Ok(());
+    let confirmation_token = jwt.generate_email_token(TokenType::Confirmation, &user)?;
+    mailer
+        .send_confirmation_email(
+            db,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &confirmation_token,
+        )
+        .await?;
+    record_confirmation_sent(cache, jwt, user.id).await?;
+    tracing::info!("Confirmation email sent to user with id {}", user.id);
+    Ok(())
+}
+
+/// The sign up rules come first in the feedback, followed by the zxcvbn warning and
+/// suggestions.
+pub fn password_strength(password: &str) -> responses::PasswordStrength {
+    let mut feedback = Vec::<String>::new();
+    let valid = match validate_password(password) {
+        ValidatorEnum::Valid => true,
+        ValidatorEnum::Invalid(message) => {
+            feedback.push(message);
+            false
+        }
+    };
+    let score = match zxcvbn::zxcvbn(password, &[]) {
+        Ok(entropy) => {
+            if let Some(estimate) = entropy.feedback() {
+                feedback.extend(estimate.warning().map(|warning| warning.to_string()));
+                feedback.extend(
+                    estimate
+                        .suggestions()
+                        .iter()
+                        .map(|suggestion| suggestion.to_string()),
+                );
             }
 
-            return Err(err);
+            entropy.score()
         }
+        Err(_) => 0,
+    };
+
+    responses::PasswordStrength {
+        score,
+        valid,
+        feedback,
+    }
+}
+
+pub async fn forgot_password(
+    db: &DbSession<'_>,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    email: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("auth_service::forgot_password");
+    let Some(user) = users_service::find_one_by_email(db, email).await? else {
+        tracing::trace_span!("Failed to find user");
+        return Ok(());
     };
+    let has_password =
+        oauth_provider::Entity::find_by_email_and_provider(&user.email, OAuthProviderEnum::Local)
+            .one(db)
+            .await?
+            .is_some();
+
+    // Social only accounts have no password to reset
+    if !has_password {
+        tracing::trace_span!("Failed to find user local OAuth provider");
+        return Ok(());
+    }
 
     let reset_token = jwt.generate_email_token(TokenType::Reset, &user)?;
-    mailer.send_password_reset_email(&email, &user.full_name(), &reset_token)?;
+    mailer
+        .send_password_reset_email(
+            db,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &reset_token,
+        )
+        .await?;
 
     Ok(())
 }
 
 pub async fn reset_password(
-    db: &Database,
+    db: &DbSession<'_>,
+    cache: &Cache,
     jwt: &Jwt,
+    mailer: &Mailer,
     body: bodies::ResetPassword,
 ) -> Result<(), ServiceError> {
     tracing::info_span!("auth_service::reset_password");
@@ -337,202 +810,705 @@ pub async fn reset_password(
     }
 
     let user = users_service::find_one_by_version(db, id, version).await?;
-    let mut user: user::ActiveModel = user.into();
-    user.password = Set(hash_password(&body.password1)
+    let mut changes: user::ActiveModel = user.clone().into();
+    changes.password = Set(hash_password(&body.password1)
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?);
-    user.version = Set(version + 1);
-    user.update(db.get_connection()).await?;
+    users_service::revoke_tokens(
+        &mut changes,
+        bump_version(user.version, body.sign_out_everywhere)?,
+    );
+    let mailer = mailer.clone();
+    let user = db
+        .transaction(|txn| {
+            Box::pin(async move {
+                let user = users_service::update_versioned(txn, &user, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)?;
+                // Queued with the change, a rolled back reset sends no notice
+                mailer
+                    .send_password_changed_email(
+                        txn,
+                        &user.email,
+                        &user.full_name(),
+                        &user.preferred_locale,
+                    )
+                    .await?;
+                Ok(user)
+            })
+        })
+        .await?;
+    users_service::invalidate_cached_user(cache, id).await?;
+    sessions_service::clear_sessions(cache, id).await?;
+    clear_failed_sign_ins(cache, &user.email).await
+}
+
+pub async fn user_lock_status(
+    cache: &Cache,
+    email: &str,
+) -> Result<objects::LockStatus, ServiceError> {
+    tracing::info_span!("auth_service::user_lock_status");
+    let email = email.to_lowercase();
+    let attempts_key = format!("{}:{}", SIGN_IN_ATTEMPTS, &email);
+    let attempts_key = attempts_key.as_str();
+    let failed_attempts = cache
+        .execute(
+            |mut connection| async move { connection.get::<&str, Option<i64>>(attempts_key).await },
+        )
+        .await?;
+    let lock_time = get_lock_time(cache, &email).await?;
+    Ok(objects::LockStatus::new(
+        email,
+        failed_attempts.unwrap_or(0),
+        lock_time,
+    ))
+}
+
+pub async fn unlock_user(cache: &Cache, email: &str) -> Result<(), ServiceError> {
+    tracing::info_span!("auth_service::unlock_user");
+    clear_failed_sign_ins(cache, &email.to_lowercase()).await
+}
+
+/// Impersonated sessions are read-only, so credentials are left to their owner.
+fn reject_impersonation(impersonator_id: Option<i32>) -> Result<(), ServiceError> {
+    if let Some(impersonator_id) = impersonator_id {
+        return Err(ServiceError::forbidden(
+            FORBIDDEN,
+            Some(InternalCause::new(&format!(
+                "Session impersonated by user {}",
+                impersonator_id
+            ))),
+        ));
+    }
+
     Ok(())
 }
 
+/// Short lived access token for an admin to act as another user. No refresh token is
+/// issued, so the session ends with the token.
+pub async fn impersonate_user(
+    db: &DbSession<'_>,
+    jwt: &Jwt,
+    impersonator_id: i32,
+    id: i32,
+) -> Result<objects::Impersonation, ServiceError> {
+    tracing::info_span!("auth_service::impersonate_user", %impersonator_id, %id);
+
+    if impersonator_id == id {
+        return Err(ServiceError::bad_request::<ServiceError>(
+            "You can not impersonate yourself",
+            None,
+        ));
+    }
+
+    let user = users_service::find_one_by_id(db, id).await?;
+
+    // An admin session would hand over the other admin's privileges
+    if user.role == RoleEnum::Admin {
+        return Err(ServiceError::forbidden::<ServiceError>(
+            "You can not impersonate an admin",
+            None,
+        ));
+    }
+
+    let access_token = jwt.generate_impersonation_token(&user, impersonator_id)?;
+    tracing::warn!(%impersonator_id, user_id = %id, "Impersonation token issued");
+    Ok(objects::Impersonation::new(
+        access_token,
+        jwt.get_impersonation_token_time(),
+    ))
+}
+
 pub async fn update_password(
-    db: &Database,
+    db: &DbSession<'_>,
     cache: &Cache,
     jwt: &Jwt,
     body: bodies::ChangePassword,
     access_token: &str,
     refresh_token: &Option<String>,
+    client: &ClientInfo,
 ) -> Result<responses::Auth, ServiceError> {
     tracing::info_span!("auth_service::update_password");
-    let (id, _) = jwt.verify_access_token(&access_token)?;
+    let (id, _, version, impersonator_id) = jwt.verify_access_token(access_token)?;
+    reject_impersonation(impersonator_id)?;
     let user = users_service::find_one_by_id(db, id).await?;
-    let user_version = user.version;
-
-    if let Some(refresh_token) = refresh_token {
-        let (_, version, token_id, exp) =
-            jwt.verify_email_token(TokenType::Refresh, refresh_token)?;
+    users_service::check_token_user(&user, version)?;
 
-        if user_version != version {
-            return Err(ServiceError::unauthorized(
-                "Invalid token",
-                Some(InternalCause::new(
-                    "Token version does not match user version",
-                )),
-            ));
+    // The token may predate the current version, e.g. when the client refreshed right
+    // before changing the password, it only has to be genuine to be blacklisted
+    let blacklisted = match refresh_token {
+        Some(refresh_token) => {
+            let (token_user_id, _, token_id, exp, _, fingerprint) =
+                jwt.verify_refresh_token(refresh_token)?;
+            // Blacklisting is keyed by the caller, so another user's token must not pass
+            if token_user_id != id {
+                return Err(ServiceError::unauthorized::<ServiceError>(
+                    "Invalid refresh token",
+                    None,
+                ));
+            }
+            check_token_binding(
+                db,
+                cache,
+                jwt,
+                client,
+                token_user_id,
+                &token_id,
+                exp,
+                fingerprint.as_deref(),
+            )
+            .await?;
+            Some((token_id, exp))
         }
+        None => None,
+    };
+
+    let mut changes: user::ActiveModel = user.clone().into();
+    changes.password = Set(hash_password(&body.password1)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?);
+    users_service::revoke_tokens(
+        &mut changes,
+        bump_version(user.version, body.sign_out_everywhere)?,
+    );
+    let token = blacklisted.clone();
+    let user = db
+        .transaction(|txn| {
+            Box::pin(async move {
+                if let Some((token_id, exp)) = &token {
+                    token_blacklist_service::insert_token(txn, id, token_id, *exp).await?;
+                }
+
+                users_service::update_versioned(txn, &user, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)
+            })
+        })
+        .await?;
 
-        create_blacklisted_token(cache, id, &token_id, exp).await?;
+    if let Some((token_id, exp)) = &blacklisted {
+        token_blacklist_service::cache_blacklisted_token(cache, id, token_id, *exp).await;
     }
 
-    let mut user: user::ActiveModel = user.into();
-    user.password = Set(hash_password(&body.password1)
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?);
-    user.version = Set(user_version + 1);
-    let user = user.update(db.get_connection()).await?;
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
-    Ok(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    ))
+    users_service::invalidate_cached_user(cache, id).await?;
+    // The version bump invalidated every refresh token, so their sessions go too
+    sessions_service::clear_sessions(cache, id).await?;
+    generate_session_tokens(cache, jwt, &user, client, None).await
 }
 
+/// Toggling requires the password, or an emailed code when the account has none, so a
+/// leaked access token alone can not turn two factor off.
 pub async fn update_two_factor(
-    db: &Database,
+    db: &DbSession<'_>,
+    cache: &Cache,
     jwt: &Jwt,
+    mailer: &Mailer,
     body: bodies::ChangeTwoFactor,
     access_token: &str,
-) -> Result<(), ServiceError> {
+) -> Result<responses::TwoFactor, ServiceError> {
     tracing::info_span!("auth_service::update_two_factor");
-    let (id, _) = jwt.verify_access_token(&access_token)?;
+    let (id, _, version, impersonator_id) = jwt.verify_access_token(access_token)?;
+    reject_impersonation(impersonator_id)?;
     let user = users_service::find_one_by_id(db, id).await?;
-    let oauth_provider = find_oauth_provider(db, &user.email, OAuthProviderEnum::Local).await?;
+    users_service::check_token_user(&user, version)?;
 
-    if oauth_provider.two_factor == body.two_factor {
-        return Ok(());
+    if user.two_factor == body.two_factor {
+        return Ok(responses::TwoFactor::Updated);
     }
 
-    let mut oauth_provider: oauth_provider::ActiveModel = oauth_provider.into();
-    oauth_provider.two_factor = Set(body.two_factor);
-    oauth_provider.update(db.get_connection()).await?;
-    Ok(())
+    let has_password =
+        oauth_provider::Entity::find_by_email_and_provider(&user.email, OAuthProviderEnum::Local)
+            .one(db)
+            .await?
+            .is_some();
+
+    if has_password {
+        let password = body.password.as_deref().ok_or_else(|| {
+            ServiceError::bad_request::<ServiceError>("Password is required", None)
+        })?;
+
+        if !verify_password(password, &user.password) {
+            tracing::warn!("User with id {} did not pass the correct password", user.id);
+            return Err(ServiceError::unauthorized::<ServiceError>(
+                INVALID_CREDENTIALS,
+                None,
+            ));
+        }
+    } else if let Some(code) = body.code.as_deref() {
+        validate_code(cache, &user.email, code).await?;
+    } else {
+        let (code, code_hash) = generate_email_code();
+        create_code(
+            cache,
+            &user.email,
+            code_hash,
+            jwt.get_email_token_time(TokenType::Confirmation),
+        )
+        .await?;
+        mailer
+            .send_access_email(
+                db,
+                &user.email,
+                &user.full_name(),
+                &user.preferred_locale,
+                &code,
+            )
+            .await?;
+        return Ok(responses::TwoFactor::CodeSent);
+    }
+
+    let txn = db.begin().await?;
+    let email = user.email.clone();
+    let mut user: user::ActiveModel = user.into();
+    user.two_factor = Set(body.two_factor);
+    let user = user.update(&txn).await?;
+    // No longer read, kept in sync so a rolled back release sees the same setting
+    oauth_provider::Entity::update_many()
+        .col_expr(
+            oauth_provider::Column::TwoFactor,
+            Expr::value(body.two_factor),
+        )
+        .filter(oauth_provider::Column::UserEmail.eq(email))
+        .exec(&txn)
+        .await?;
+
+    if !body.two_factor {
+        tracing::warn!("User with id {} disabled two factor", user.id);
+        mailer
+            .send_two_factor_disabled_email(
+                &txn,
+                &user.email,
+                &user.full_name(),
+                &user.preferred_locale,
+            )
+            .await?;
+    }
+
+    txn.commit().await?;
+    users_service::invalidate_cached_user(cache, user.id).await?;
+    Ok(responses::TwoFactor::Updated)
 }
 
-async fn create_blacklisted_token(
+/// Adds a local sign in to a user created through an external provider. The first call
+/// emails a code and the password is only set with it, as for `update_two_factor`.
+pub async fn set_password(
+    db: &DbSession<'_>,
     cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
     user_id: i32,
-    token_id: &str,
-    exp: i64,
-) -> Result<(), ServiceError> {
-    tracing::trace_span!("Creating blacklisted token", id = %user_id);
-    let exp_usize = u64::try_from(exp)
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    let mut connection = cache.get_connection().await?;
-    let key = format!("{}:{}", BLACKLIST_TOKEN, token_id);
-    connection
-        .set_ex(&key, user_id, exp_usize)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    Ok(())
+    input: inputs::SetPassword,
+) -> Result<responses::SetPassword, ServiceError> {
+    tracing::info_span!("auth_service::set_password");
+    let user = users_service::find_one_by_id(db, user_id).await?;
+
+    if user.has_password() {
+        return Err(ServiceError::bad_request::<ServiceError>(
+            "User already has a password, change it instead",
+            None,
+        ));
+    }
+
+    match input.code.as_deref() {
+        Some(code) => validate_code(cache, &user.email, code).await?,
+        None => {
+            let (code, code_hash) = generate_email_code();
+            create_code(
+                cache,
+                &user.email,
+                code_hash,
+                jwt.get_email_token_time(TokenType::Confirmation),
+            )
+            .await?;
+            mailer
+                .send_access_email(
+                    db,
+                    &user.email,
+                    &user.full_name(),
+                    &user.preferred_locale,
+                    &code,
+                )
+                .await?;
+            return Ok(responses::SetPassword::CodeSent);
+        }
+    }
+
+    let mut changes: user::ActiveModel = user.clone().into();
+    changes.password = Set(hash_password(&input.password1)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?);
+    // Nothing to revoke, the account had no password, but it is a credential change
+    changes.version = Set(users_service::next_version(user.version, 1)?);
+    let user = db
+        .transaction(|txn| {
+            Box::pin(async move {
+                let user = users_service::update_versioned(txn, &user, changes)
+                    .await?
+                    .ok_or_else(users_service::version_conflict)?;
+                // Imported users already have the local provider row
+                let count = oauth_provider::Entity::find_by_email_and_provider(
+                    &user.email,
+                    OAuthProviderEnum::Local,
+                )
+                .count(txn)
+                .await?;
+
+                if count == 0 {
+                    oauth_provider::ActiveModel {
+                        user_email: Set(user.email.clone()),
+                        provider: Set(OAuthProviderEnum::Local),
+                        two_factor: Set(user.two_factor),
+                        ..Default::default()
+                    }
+                    .insert(txn)
+                    .await?;
+                }
+
+                Ok(user)
+            })
+        })
+        .await?;
+
+    tracing::info!("User with id {} set a password", user.id);
+    users_service::invalidate_cached_user(cache, user.id).await?;
+    Ok(responses::SetPassword::Updated)
+}
+
+/// Signing out everywhere skips a version, so tokens issued concurrently with the
+/// password change for the next version are rejected as well.
+fn bump_version(version: i32, sign_out_everywhere: bool) -> Result<i32, ServiceError> {
+    users_service::next_version(version, if sign_out_everywhere { 2 } else { 1 })
 }
 
-pub async fn sign_out(cache: &Cache, jwt: &Jwt, refresh_token: &str) -> Result<(), ServiceError> {
+pub async fn sign_out(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    refresh_token: &str,
+    client: &ClientInfo,
+) -> Result<(), ServiceError> {
     tracing::info_span!("auth_service::sign_out");
-    let (id, _, token_id, exp) = jwt.verify_email_token(TokenType::Refresh, refresh_token)?;
+    let (id, _, token_id, exp, _, fingerprint) = jwt.verify_refresh_token(refresh_token)?;
 
-    if check_blacklist(cache, &token_id).await? {
+    if token_blacklist_service::is_blacklisted(db.database(), cache, &token_id).await? {
         return Ok(());
     }
-    create_blacklisted_token(cache, id, &token_id, exp).await?;
-    return Ok(());
+    check_token_binding(
+        db,
+        cache,
+        jwt,
+        client,
+        id,
+        &token_id,
+        exp,
+        fingerprint.as_deref(),
+    )
+    .await?;
+    token_blacklist_service::blacklist_token(db.database(), cache, id, &token_id, exp).await?;
+    sessions_service::remove_session(cache, id, &token_id).await
 }
 
-async fn save_csrf_token(
+pub async fn find_sessions(
     cache: &Cache,
-    provider: &ExternalProvider,
-    token: &str,
-    verifier: &str,
+    user_id: i32,
+) -> Result<Vec<objects::Session>, ServiceError> {
+    tracing::info_span!("auth_service::find_sessions", id = %user_id);
+    sessions_service::find_sessions(cache, user_id).await
+}
+
+pub async fn revoke_session(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
 ) -> Result<(), ServiceError> {
-    let mut connection = cache.get_connection().await?;
-    let key = format!("{}:{}", provider.to_str(), token);
-    connection
-        .set_ex(&key, verifier, 600)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    Ok(())
+    tracing::info_span!("auth_service::revoke_session", id = %user_id);
+    let session = sessions_service::find_session(cache, user_id, token_id).await?;
+    token_blacklist_service::blacklist_token(
+        db.database(),
+        cache,
+        user_id,
+        &session.token_id,
+        session.expires_at,
+    )
+    .await?;
+    sessions_service::remove_session(cache, user_id, &session.token_id).await
 }
 
-async fn get_csrf_token(
+/// States carry their own verifier, Redis only remembers the consumed ones until they
+/// expire so a state can not be used twice.
+pub async fn consume_oauth_state(
     cache: &Cache,
-    provider: &ExternalProvider,
-    token: &str,
-) -> Result<String, ServiceError> {
-    let mut connection = cache.get_connection().await?;
-    let key = format!("{}:{}", provider.to_str(), token);
-    let verifier: Option<String> = connection
-        .get(&key)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    state_id: &str,
+    exp: i64,
+) -> Result<(), ServiceError> {
+    let key = format!("{}:{}", OAUTH_STATE_PREFIX, state_id);
+    let key = key.as_str();
+    let ttl = (exp - Utc::now().timestamp()).max(1);
+    let consumed = cache
+        .execute(|mut connection| async move {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl)
+                .query_async::<_, Option<String>>(&mut connection)
+                .await
+        })
+        .await?
+        .is_none();
 
-    if let Some(verifier) = verifier {
-        return Ok(verifier);
+    if consumed {
+        return Err(ServiceError::unauthorized(
+            INVALID_CREDENTIALS,
+            Some(InternalCause::new("OAuth state already used")),
+        ));
     }
 
-    Err(ServiceError::unauthorized(
-        "Invalid credentials",
-        Some(InternalCause::new("Invalid CSRF token")),
-    ))
+    Ok(())
 }
 
-pub async fn oauth_sign_in(
-    cache: &Cache,
+fn external_authorize_url(
     oauth: &OAuth,
-    provider: ExternalProvider,
+    provider: &ExternalProvider,
+    link: Option<(i32, &str)>,
 ) -> Result<String, ServiceError> {
-    tracing::info_span!("auth_service::oauth_sign_in");
-    let scopes = oauth.get_external_client_scopes(&provider);
-    let client = oauth.get_external_client(&provider)?;
-    let mut request = client.authorize_url(CsrfToken::new_random);
+    let scopes = oauth.get_external_client_scopes(provider);
+    let client = oauth.get_external_client(provider)?;
     let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+    let state = match link {
+        Some((user_id, nonce)) => {
+            oauth.generate_link_state(provider, pkce_code_verifier.secret(), user_id, nonce)?
+        }
+        None => oauth.generate_state(provider, pkce_code_verifier.secret())?,
+    };
+    let mut request = client.authorize_url(|| CsrfToken::new(state));
 
     for scope in scopes {
         request = request.add_scope(Scope::new(scope.to_string()));
     }
 
-    let (url, token) = request.set_pkce_challenge(pkce_code_challenge).url();
-    save_csrf_token(
-        cache,
-        &provider,
-        token.secret(),
-        pkce_code_verifier.secret(),
-    )
-    .await?;
+    let (url, _) = request.set_pkce_challenge(pkce_code_challenge).url();
     Ok(url.to_string())
 }
 
-pub async fn oauth_callback(
-    db: &Database,
-    cache: &Cache,
+pub fn oauth_sign_in(oauth: &OAuth, provider: ExternalProvider) -> Result<String, ServiceError> {
+    tracing::info_span!("auth_service::oauth_sign_in");
+    external_authorize_url(oauth, &provider, None)
+}
+
+/// Starts linking the provider to the signed in user, the state remembers who asked.
+/// Returns the provider URL and the nonce the browser keeps in the link cookie, so a
+/// callback from another browser can not link the identity it signed in with.
+pub async fn oauth_link(
+    db: &DbSession<'_>,
     oauth: &OAuth,
     jwt: &Jwt,
     provider: ExternalProvider,
-    query: queries::OAuth,
-) -> Result<responses::Auth, ServiceError> {
-    tracing::info_span!("auth_service::oauth_callback");
-    let client = oauth.get_external_client(&provider)?;
-    let verifier = get_csrf_token(cache, &provider, &query.state).await?;
+    access_token: &str,
+) -> Result<(String, String), ServiceError> {
+    tracing::info_span!("auth_service::oauth_link");
+    let (id, _, version, impersonator_id) = jwt.verify_access_token(access_token)?;
+    reject_impersonation(impersonator_id)?;
+    let user = users_service::find_one_by_id(db, id).await?;
+    users_service::check_token_user(&user, version)?;
+    let nonce = random_string(OAUTH_LINK_NONCE_LENGTH);
+    let url = external_authorize_url(oauth, &provider, Some((user.id, &nonce)))?;
+    Ok((url, nonce))
+}
 
-    let token_response = client
-        .exchange_code(AuthorizationCode::new(query.code))
-        .set_pkce_verifier(PkceCodeVerifier::new(verifier))
-        .request_async(async_http_client)
-        .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    let url = oauth.get_external_client_info_url(&provider);
-    let auth_header = format!("Bearer {}", token_response.access_token().secret());
-    let result = Client::new()
+pub async fn get_external_user_info<T: DeserializeOwned>(
+    http_client: &HttpClient,
+    url: &str,
+    auth_header: &str,
+) -> Result<T, responses::UserInfoError> {
+    let response = http_client
+        .client()
         .get(url)
-        .header("Authorization", &auth_header)
+        .header(AUTHORIZATION, auth_header)
         .send()
         .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    let user_info: responses::UserInfo = result
-        .json::<responses::OAuthUserInfo>()
+        .map_err(|e| responses::UserInfoError::Unavailable(e.to_string()))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| responses::UserInfoError::Unavailable(e.to_string()))?;
+    responses::parse_user_info(status.as_u16(), &body)
+}
+
+/// Turns a failed user info into what the user can act on: try again later, grant the
+/// missing permission or, for a body we do not understand, a plain internal error.
+pub fn user_info_error(
+    provider: &ExternalProvider,
+    error: responses::UserInfoError,
+) -> ServiceError {
+    let name = match provider {
+        ExternalProvider::Google => "Google",
+        ExternalProvider::Facebook => "Facebook",
+        ExternalProvider::Github => "GitHub",
+    };
+
+    match error {
+        responses::UserInfoError::Unavailable(cause) => ServiceError::bad_gateway(
+            &format!("{} is unavailable, please try again later", name),
+            Some(InternalCause::new(&cause)),
+        ),
+        responses::UserInfoError::MissingField { field, permission } => {
+            ServiceError::bad_request::<Error>(
+                &format!(
+                    "{} did not share your {}, grant the \"{}\" permission and try again",
+                    name, field, permission
+                ),
+                None,
+            )
+        }
+        responses::UserInfoError::Malformed { error, body } => {
+            tracing::debug!(%body, "Malformed {} user info", name);
+            ServiceError::internal_server_error(
+                SOMETHING_WENT_WRONG,
+                Some(InternalCause::new(&error)),
+            )
+        }
+    }
+}
+
+async fn fetch_external_user_info(
+    oauth: &OAuth,
+    provider: &ExternalProvider,
+    code: String,
+    verifier: String,
+) -> Result<responses::UserInfo, ServiceError> {
+    let client = oauth.get_external_client(provider)?;
+    let http_client = oauth.http_client();
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(PkceCodeVerifier::new(verifier))
+        .request_async(|request| http_client.oauth2_request(request))
         .await
-        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?
-        .try_into()?;
+        .map_err(|e| match e {
+            // Timeouts and refused connections, not a rejected code
+            RequestTokenError::Request(e) => user_info_error(
+                provider,
+                responses::UserInfoError::Unavailable(e.to_string()),
+            ),
+            e => ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)),
+        })?;
+    let url = oauth.get_external_client_info_url(provider);
+    let auth_header = format!("Bearer {}", token_response.access_token().secret());
+    let user_info = match provider {
+        ExternalProvider::Google => responses::OAuthUserInfo::Google(
+            get_external_user_info(http_client, url, &auth_header)
+                .await
+                .map_err(|e| user_info_error(provider, e))?,
+        ),
+        ExternalProvider::Facebook => responses::OAuthUserInfo::Facebook(
+            get_external_user_info(http_client, url, &auth_header)
+                .await
+                .map_err(|e| user_info_error(provider, e))?,
+        ),
+        ExternalProvider::Github => {
+            let github_user: responses::GithubUserInfoResponse =
+                get_external_user_info(http_client, url, &auth_header)
+                    .await
+                    .map_err(|e| user_info_error(provider, e))?;
+
+            match (
+                &github_user.email,
+                oauth.get_external_client_emails_url(provider),
+            ) {
+                (None, Some(emails_url)) => {
+                    tracing::info!("GitHub user has no public email, fetching emails");
+                    let emails = get_external_user_info(http_client, emails_url, &auth_header)
+                        .await
+                        .map_err(|e| user_info_error(provider, e))?;
+                    responses::OAuthUserInfo::Github(github_user.with_primary_email(emails))
+                }
+                _ => responses::OAuthUserInfo::Github(github_user),
+            }
+        }
+    };
+    user_info
+        .try_into()
+        .map_err(|e| user_info_error(provider, e))
+}
+
+/// The state decides the flow, a state issued by `oauth_link` links the provider to
+/// its user instead of signing in.
+#[allow(clippy::too_many_arguments)]
+pub async fn oauth_callback(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    oauth: &OAuth,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    webhooks: &Webhooks,
+    terms_version: &TermsVersion,
+    device_alerts: &DeviceAlerts,
+    sign_up_mode: SignUpMode,
+    provider: ExternalProvider,
+    query: queries::OAuth,
+    link_nonce: Option<&str>,
+    client_info: &ClientInfo,
+) -> Result<responses::OAuthCallback, ServiceError> {
+    tracing::info_span!("auth_service::oauth_callback");
+    let (verifier, state_id, link, exp) = oauth.verify_state(&provider, &query.state)?;
+
+    if let Some((_, nonce)) = &link {
+        if link_nonce != Some(nonce.as_str()) {
+            tracing::warn!("OAuth link callback from another browser");
+            return Err(ServiceError::unauthorized(
+                INVALID_CREDENTIALS,
+                Some(InternalCause::new("OAuth link nonce does not match")),
+            ));
+        }
+    }
+
+    consume_oauth_state(cache, &state_id, exp).await?;
+    let user_info = fetch_external_user_info(oauth, &provider, query.code, verifier).await?;
+
+    match link {
+        Some((user_id, _)) => {
+            oauth_link_callback(db, provider, user_id, user_info).await?;
+            Ok(responses::OAuthCallback::Linked)
+        }
+        None => {
+            oauth_sign_in_callback(
+                db,
+                cache,
+                jwt,
+                mailer,
+                webhooks,
+                terms_version,
+                device_alerts,
+                sign_up_mode,
+                provider,
+                user_info,
+                client_info,
+            )
+            .await
+        }
+    }
+}
+
+/// Signs in the provider's user, creating the account on first use.
+#[allow(clippy::too_many_arguments)]
+pub async fn oauth_sign_in_callback(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    webhooks: &Webhooks,
+    terms_version: &TermsVersion,
+    device_alerts: &DeviceAlerts,
+    sign_up_mode: SignUpMode,
+    provider: ExternalProvider,
+    user_info: responses::UserInfo,
+    client_info: &ClientInfo,
+) -> Result<responses::OAuthCallback, ServiceError> {
     let user = users_service::find_or_create(
         db,
+        webhooks,
+        terms_version,
+        sign_up_mode,
         provider.to_oauth_provider(),
         user_info.first_name,
         user_info.last_name,
@@ -540,10 +1516,44 @@ pub async fn oauth_callback(
         user_info.email,
     )
     .await?;
-    let (access_token, refresh_token) = jwt.generate_auth_tokens(&user)?;
-    Ok(responses::Auth::new(
-        access_token,
-        refresh_token,
-        jwt.get_access_token_time(),
-    ))
+
+    // Same as a password sign in, the code is confirmed through `confirm_sign_in`
+    if user.two_factor {
+        send_sign_in_code(db, cache, jwt, mailer, &user).await?;
+        return Ok(responses::OAuthCallback::Mfa(user.email));
+    }
+
+    let user = users_service::update_last_login(db, cache, user).await?;
+    let auth = generate_session_tokens(cache, jwt, &user, client_info, None).await?;
+    devices_service::check_device(
+        db.database(),
+        cache,
+        mailer,
+        device_alerts,
+        &user,
+        client_info,
+    )
+    .await?;
+    Ok(responses::OAuthCallback::Auth(auth))
+}
+
+/// The user may have been suspended or deleted while on the consent screen.
+pub async fn oauth_link_callback(
+    db: &DbSession<'_>,
+    provider: ExternalProvider,
+    user_id: i32,
+    user_info: responses::UserInfo,
+) -> Result<(), ServiceError> {
+    let user = users_service::find_one_by_id(db, user_id).await?;
+
+    if user.suspended {
+        return Err(ServiceError::forbidden::<Error>(
+            "Your account has been suspended",
+            None,
+        ));
+    }
+
+    users_service::link_oauth_provider(db, user.id, provider.to_oauth_provider(), &user_info.email)
+        .await?;
+    Ok(())
 }
@@ -0,0 +1,274 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    io::{Cursor, Write},
+    time::Duration,
+};
+
+use actix_web::rt;
+use anyhow::Error;
+use chrono::Utc;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use zip::{write::FileOptions, ZipWriter};
+
+use entities::{oauth_provider, uploaded_file, user};
+
+use crate::common::{ServiceError, INTERNAL_SERVER_ERROR};
+use crate::dtos::{objects::DataExport, DataExportStatus};
+use crate::providers::{Cache, Database, Mailer, ObjectStorage};
+
+use super::users_service;
+
+const DATA_EXPORT_PREFIX: &str = "data_export";
+const EXPORT_COOLDOWN_HOURS: i64 = 24;
+// A failed export can be requested again sooner than a delivered one
+const FAILED_EXPORT_SECONDS: u64 = 3600;
+// Matches the storage GC grace period, which collects the archive afterwards
+const LINK_EXPIRATION_HOURS: u64 = 24;
+const ARCHIVE_CONTENT_TYPE: &str = "application/zip";
+const ACCOUNT_FILE: &str = "account.json";
+
+/// State of the last export, kept until the cooldown ends.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataExportJob {
+    pub status: DataExportStatus,
+    pub key: Option<String>,
+    pub requested_at: i64,
+}
+
+fn job_key(user_id: i32) -> String {
+    format!("{}:{}", DATA_EXPORT_PREFIX, user_id)
+}
+
+fn link_expiration() -> Duration {
+    Duration::from_secs(LINK_EXPIRATION_HOURS * 3600)
+}
+
+fn cooldown_left(requested_at: i64) -> u64 {
+    let ends_at = requested_at + EXPORT_COOLDOWN_HOURS * 3600;
+    (ends_at - Utc::now().timestamp()).max(1) as u64
+}
+
+fn archive_error(error: impl std::fmt::Display + std::fmt::Debug) -> ServiceError {
+    ServiceError::internal_server_error(INTERNAL_SERVER_ERROR, Some(error))
+}
+
+fn to_data_export(object_storage: &ObjectStorage, job: DataExportJob) -> DataExport {
+    let url = match (job.status, job.key) {
+        (DataExportStatus::Ready, Some(key)) => {
+            Some(object_storage.get_signed_url(&key, link_expiration()))
+        }
+        _ => None,
+    };
+
+    DataExport {
+        status: job.status,
+        requested_at: job.requested_at,
+        url,
+    }
+}
+
+pub async fn find_data_export(
+    cache: &Cache,
+    object_storage: &ObjectStorage,
+    user_id: i32,
+) -> Result<Option<DataExport>, ServiceError> {
+    tracing::info_span!("data_export_service::find_data_export", %user_id);
+    let job = cache.get_json::<DataExportJob>(&job_key(user_id)).await?;
+    Ok(job.map(|job| to_data_export(object_storage, job)))
+}
+
+/// Reserves the export for the cooldown and builds it in a spawned task, the user gets
+/// an email with the link once it is ready.
+pub async fn request_data_export(
+    db: &Database,
+    cache: &Cache,
+    object_storage: &ObjectStorage,
+    mailer: &Mailer,
+    user_id: i32,
+) -> Result<DataExport, ServiceError> {
+    tracing::info_span!("data_export_service::request_data_export", %user_id);
+    let job = DataExportJob {
+        status: DataExportStatus::Pending,
+        key: None,
+        requested_at: Utc::now().timestamp(),
+    };
+    let value = serde_json::to_string(&job).map_err(archive_error)?;
+    let key = job_key(user_id);
+    let (key, value) = (key.as_str(), value.as_str());
+    let ttl = cooldown_left(job.requested_at);
+    let reserved = cache
+        .execute(|mut connection| async move {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl)
+                .query_async::<_, Option<String>>(&mut connection)
+                .await
+        })
+        .await?
+        .is_some();
+
+    if !reserved {
+        return Err(ServiceError::bad_request::<Error>(
+            &format!(
+                "Your data can only be exported once every {} hours",
+                EXPORT_COOLDOWN_HOURS
+            ),
+            None,
+        ));
+    }
+
+    let (db, cache, object_storage, mailer) = (
+        db.clone(),
+        cache.clone(),
+        object_storage.clone(),
+        mailer.clone(),
+    );
+    let pending = to_data_export(&object_storage, job.clone());
+    rt::spawn(async move {
+        let mut job = job;
+        let ttl = match build_data_export(&db, &object_storage, &mailer, user_id).await {
+            Ok(key) => {
+                job.status = DataExportStatus::Ready;
+                job.key = Some(key);
+                cooldown_left(job.requested_at)
+            }
+            Err(e) => {
+                tracing::error!("Failed to export the data of user {}: {:?}", user_id, e);
+                job.status = DataExportStatus::Failed;
+                FAILED_EXPORT_SECONDS
+            }
+        };
+
+        if let Err(e) = cache.set_json(&job_key(user_id), &job, ttl).await {
+            tracing::error!(
+                "Failed to save the data export of user {}: {:?}",
+                user_id,
+                e
+            );
+        }
+    });
+    Ok(pending)
+}
+
+fn archive_path(file: &uploaded_file::Model) -> String {
+    format!("files/{}.{}", file.id, file.extension)
+}
+
+/// Everything stored about the user, the password hash left out.
+fn account_data(
+    user: &user::Model,
+    providers: &[oauth_provider::Model],
+    files: &[(uploaded_file::Model, bool)],
+) -> Value {
+    let mut user = serde_json::to_value(user).unwrap_or_default();
+    if let Some(user) = user.as_object_mut() {
+        user.remove("password");
+    }
+
+    json!({
+        "exported_at": Utc::now().timestamp(),
+        "user": user,
+        "oauth_providers": providers
+            .iter()
+            .map(|provider| json!({
+                "provider": provider.provider,
+                "provider_email": provider.provider_email,
+                "created_at": provider.created_at,
+                "updated_at": provider.updated_at,
+            }))
+            .collect::<Vec<Value>>(),
+        "uploaded_files": files
+            .iter()
+            .map(|(file, archived)| json!({
+                "id": file.id,
+                "extension": file.extension,
+                "content_hash": file.content_hash,
+                "size_bytes": file.size_bytes,
+                "created_at": file.created_at,
+                "archive_path": archived.then(|| archive_path(file)),
+            }))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+async fn build_data_export(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    mailer: &Mailer,
+    user_id: i32,
+) -> Result<String, ServiceError> {
+    let connection = db.get_connection();
+    let user = users_service::find_one_by_id(&db.session(), user_id).await?;
+    let providers = oauth_provider::Entity::find_by_email(&user.email)
+        .all(connection)
+        .await?;
+    let files = uploaded_file::Entity::find()
+        .filter(uploaded_file::Column::UserId.eq(user_id))
+        .all(connection)
+        .await?;
+
+    // Only the original of each file, renditions can be made from it
+    let mut contents = Vec::<(String, Vec<u8>)>::with_capacity(files.len());
+    let mut archived = Vec::<(uploaded_file::Model, bool)>::with_capacity(files.len());
+    for file in files {
+        match object_storage
+            .get_file(&object_storage.get_file_key(&file.url))
+            .await
+        {
+            Ok(content) => {
+                contents.push((archive_path(&file), content));
+                archived.push((file, true));
+            }
+            Err(e) => {
+                tracing::warn!("Leaving file {} out of the data export: {:?}", file.id, e);
+                archived.push((file, false));
+            }
+        }
+    }
+
+    let account = serde_json::to_vec_pretty(&account_data(&user, &providers, &archived))
+        .map_err(archive_error)?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::<u8>::new()));
+    for (path, content) in [(ACCOUNT_FILE.to_string(), account)]
+        .into_iter()
+        .chain(contents)
+    {
+        writer
+            .start_file(path, FileOptions::default())
+            .map_err(archive_error)?;
+        writer.write_all(&content).map_err(archive_error)?;
+    }
+    let archive = writer.finish().map_err(archive_error)?.into_inner();
+
+    let key = object_storage
+        .upload_private_file(
+            user_id,
+            &format!("exports/{}", Uuid::new_v4()),
+            "zip",
+            ARCHIVE_CONTENT_TYPE,
+            archive,
+        )
+        .await?;
+    mailer
+        .send_data_export_email(
+            connection,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &object_storage.get_signed_url(&key, link_expiration()),
+            LINK_EXPIRATION_HOURS,
+        )
+        .await?;
+    Ok(key)
+}
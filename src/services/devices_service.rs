@@ -0,0 +1,221 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::rt;
+use anyhow::Error;
+use chrono::Utc;
+use redis::AsyncCommands;
+
+use entities::user::Model;
+
+use crate::common::{ClientInfo, ServiceError};
+use crate::dtos::objects::KnownDevice;
+use crate::providers::{Cache, Database, DeviceAlerts, Mailer};
+
+const KNOWN_DEVICES: &str = "known_devices";
+const KNOWN_DEVICE_TTL: i64 = 90 * 86400;
+const UNKNOWN: &str = "Unknown";
+const MAX_FAMILY_LENGTH: usize = 64;
+
+/// Checked in order, Edge and Opera also claim to be Chrome, and Chrome to be Safari.
+const USER_AGENT_FAMILIES: [(&str, &str); 9] = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+    ("MSIE ", "Internet Explorer"),
+];
+
+fn get_devices_key(user_id: i32) -> String {
+    format!("{}:{}", KNOWN_DEVICES, user_id)
+}
+
+/// Known browsers by name, anything else by its first product token, like `curl`.
+pub fn user_agent_family(user_agent: Option<&str>) -> String {
+    let Some(user_agent) = user_agent.map(str::trim).filter(|value| !value.is_empty()) else {
+        return UNKNOWN.to_string();
+    };
+
+    if let Some((_, family)) = USER_AGENT_FAMILIES
+        .iter()
+        .find(|(token, _)| user_agent.contains(token))
+    {
+        return family.to_string();
+    }
+
+    let product = user_agent
+        .split(['/', ' '])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| *c != '|')
+        .take(MAX_FAMILY_LENGTH)
+        .collect::<String>();
+
+    if product.is_empty() {
+        UNKNOWN.to_string()
+    } else {
+        product
+    }
+}
+
+/// The /24 of IPv4 addresses and the /48 of IPv6 ones, so a new DHCP lease on the
+/// same network is not a new device.
+pub fn ip_network(ip: Option<&str>) -> String {
+    let ip = ip.and_then(|ip| {
+        ip.parse::<IpAddr>()
+            .or_else(|_| ip.parse::<SocketAddr>().map(|address| address.ip()))
+            .ok()
+    });
+    let Some(ip) = ip else {
+        return UNKNOWN.to_string();
+    };
+
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+        }
+    }
+}
+
+pub fn device_key(client: &ClientInfo) -> String {
+    format!(
+        "{}|{}",
+        user_agent_family(client.user_agent.as_deref()),
+        ip_network(client.ip.as_deref())
+    )
+}
+
+fn to_known_device(device_key: String) -> KnownDevice {
+    let (user_agent, network) = device_key
+        .split_once('|')
+        .map(|(user_agent, network)| (user_agent.to_string(), network.to_string()))
+        .unwrap_or_else(|| (device_key.clone(), UNKNOWN.to_string()));
+    KnownDevice {
+        device_key,
+        user_agent,
+        network,
+    }
+}
+
+/// Adds the client's device, whether it was new. Every sign in refreshes the set's
+/// expiry, so devices are forgotten after 90 days without any.
+pub async fn remember_device(
+    cache: &Cache,
+    device_alerts: &DeviceAlerts,
+    user_id: i32,
+    client: &ClientInfo,
+) -> Result<bool, ServiceError> {
+    if !device_alerts.is_enabled() {
+        return Ok(false);
+    }
+
+    tracing::info_span!("devices_service::remember_device", id = %user_id);
+    let key = get_devices_key(user_id);
+    let device = device_key(client);
+    let (key, device) = (key.as_str(), device.as_str());
+    let added = cache
+        .execute(
+            |mut connection| async move { connection.sadd::<&str, &str, i64>(key, device).await },
+        )
+        .await?;
+    cache
+        .execute(|mut connection| async move {
+            connection.expire::<&str, ()>(key, KNOWN_DEVICE_TTL).await
+        })
+        .await?;
+    Ok(added > 0)
+}
+
+/// Remembers the device tokens were just issued to, emailing the user in the
+/// background when it is new.
+pub async fn check_device(
+    db: &Database,
+    cache: &Cache,
+    mailer: &Mailer,
+    device_alerts: &DeviceAlerts,
+    user: &Model,
+    client: &ClientInfo,
+) -> Result<(), ServiceError> {
+    if !remember_device(cache, device_alerts, user.id, client).await? {
+        return Ok(());
+    }
+
+    tracing::info!("User with id {} signed in from a new device", user.id);
+    let signed_in_at = Utc::now().format("%Y-%m-%d %H:%M UTC").to_string();
+    let (db, mailer, user, client) = (db.clone(), mailer.clone(), user.clone(), client.clone());
+    rt::spawn(async move {
+        let result = mailer
+            .send_new_device_email(
+                db.get_connection(),
+                &user.email,
+                &user.full_name(),
+                &user.preferred_locale,
+                &signed_in_at,
+                client.user_agent.as_deref().unwrap_or(UNKNOWN),
+                client.ip.as_deref().unwrap_or(UNKNOWN),
+            )
+            .await;
+
+        if let Err(e) = result {
+            tracing::error!(
+                "Failed to send the new device email to user {}: {:?}",
+                user.id,
+                e
+            );
+        }
+    });
+    Ok(())
+}
+
+pub async fn find_known_devices(
+    cache: &Cache,
+    user_id: i32,
+) -> Result<Vec<KnownDevice>, ServiceError> {
+    tracing::info_span!("devices_service::find_known_devices", id = %user_id);
+    let key = get_devices_key(user_id);
+    let key = key.as_str();
+    let mut devices = cache
+        .execute(
+            |mut connection| async move { connection.smembers::<&str, Vec<String>>(key).await },
+        )
+        .await?;
+    devices.sort();
+    Ok(devices.into_iter().map(to_known_device).collect())
+}
+
+/// The next sign in from a forgotten device sends an alert again.
+pub async fn forget_device(
+    cache: &Cache,
+    user_id: i32,
+    device_key: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("devices_service::forget_device", id = %user_id);
+    let key = get_devices_key(user_id);
+    let key = key.as_str();
+    let removed = cache
+        .execute(|mut connection| async move {
+            connection.srem::<&str, &str, i64>(key, device_key).await
+        })
+        .await?;
+
+    if removed == 0 {
+        return Err(ServiceError::not_found::<Error>("Device not found", None));
+    }
+
+    Ok(())
+}
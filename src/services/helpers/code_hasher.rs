@@ -0,0 +1,71 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::OnceLock;
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static SECRET: OnceLock<String> = OnceLock::new();
+
+/// Sets the secret from `Config::code_secret` once, at startup before any code is
+/// hashed. Without it a random secret applies, hashes then do not outlive the process.
+pub fn set_code_secret(secret: &Secret<String>) {
+    if SECRET.set(secret.expose_secret().clone()).is_err() {
+        tracing::debug!("Code secret is already set");
+    }
+}
+
+fn get_secret() -> &'static str {
+    SECRET.get_or_init(|| Uuid::new_v4().to_string())
+}
+
+fn get_mac(code: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(get_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(code.as_bytes());
+    mac
+}
+
+pub fn hash_code(code: &str) -> String {
+    STANDARD.encode(get_mac(code).finalize().into_bytes())
+}
+
+pub fn verify_code(code: &str, code_hash: &str) -> bool {
+    match STANDARD.decode(code_hash) {
+        Ok(bytes) => get_mac(code).verify_slice(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Same MAC as `hash_code`, encoded to travel in a query string.
+pub fn sign_url(message: &str) -> String {
+    URL_SAFE_NO_PAD.encode(get_mac(message).finalize().into_bytes())
+}
+
+pub fn verify_url_signature(message: &str, signature: &str) -> bool {
+    match URL_SAFE_NO_PAD.decode(signature) {
+        Ok(bytes) => get_mac(message).verify_slice(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn random_string(length: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
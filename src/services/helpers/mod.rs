@@ -4,6 +4,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub use code_hasher::*;
 pub use password_hasher::*;
 
+pub mod code_hasher;
 pub mod password_hasher;
+
+#[cfg(test)]
+mod tests;
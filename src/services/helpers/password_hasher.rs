@@ -4,23 +4,82 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::sync::OnceLock;
+
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, Result, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version,
 };
 
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+
+static PARAMS: OnceLock<Params> = OnceLock::new();
+
+/// Sets the parameters from `Config::password_hash` once, at startup before any
+/// password is hashed. Without it the argon2 defaults apply.
+pub fn set_password_params(params: &Params) {
+    if PARAMS.set(params.clone()).is_err() {
+        tracing::debug!("Password hash parameters are already set");
+    }
+}
+
+fn get_params() -> &'static Params {
+    PARAMS.get_or_init(Params::default)
+}
+
+fn get_hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, get_params().clone())
+}
+
+fn is_bcrypt_hash(str_hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| str_hash.starts_with(prefix))
+}
+
 pub fn hash_password(password: &str) -> Result<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    let hash = get_hasher().hash_password(password.as_bytes(), &salt)?;
     Ok(hash.to_string())
 }
 
 pub fn verify_password<'a>(password: &'a str, str_hash: &'a str) -> bool {
-    if let Ok(value) = PasswordHash::new(&str_hash) {
-        return Argon2::default()
+    if is_bcrypt_hash(str_hash) {
+        return bcrypt::verify(password, str_hash).unwrap_or(false);
+    }
+
+    if let Ok(value) = PasswordHash::new(str_hash) {
+        return get_hasher()
             .verify_password(password.as_bytes(), &value)
             .is_ok();
     }
 
     false
 }
+
+/// Whether a stored hash is legacy bcrypt or was created with different
+/// Argon2id parameters, and should be re-hashed after a successful sign in.
+pub fn needs_rehash(str_hash: &str) -> bool {
+    if is_bcrypt_hash(str_hash) {
+        return true;
+    }
+
+    match PasswordHash::new(str_hash) {
+        Ok(value) => {
+            if value.algorithm != Algorithm::Argon2id.ident() {
+                return true;
+            }
+
+            match Params::try_from(&value) {
+                Ok(params) => {
+                    let current = get_params();
+                    params.m_cost() != current.m_cost()
+                        || params.t_cost() != current.t_cost()
+                        || params.p_cost() != current.p_cost()
+                }
+                Err(_) => true,
+            }
+        }
+        Err(_) => false,
+    }
+}
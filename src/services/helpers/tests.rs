@@ -0,0 +1,41 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{hash_code, hash_password, needs_rehash, verify_code, verify_password};
+
+const VALID_PASSWORD: &str = "Valid_Password12";
+
+#[test]
+fn test_verify_argon2id_password() {
+    let str_hash = hash_password(VALID_PASSWORD).unwrap();
+    assert!(str_hash.starts_with("$argon2id$"));
+    assert!(verify_password(VALID_PASSWORD, &str_hash));
+    assert!(!verify_password("Invalid_Password12", &str_hash));
+    assert!(!needs_rehash(&str_hash));
+}
+
+#[test]
+fn test_verify_bcrypt_password() {
+    let str_hash = bcrypt::hash(VALID_PASSWORD, 4).unwrap();
+    assert!(str_hash.starts_with("$2b$"));
+    assert!(verify_password(VALID_PASSWORD, &str_hash));
+    assert!(!verify_password("Invalid_Password12", &str_hash));
+    assert!(needs_rehash(&str_hash));
+}
+
+#[test]
+fn test_invalid_password_hash() {
+    assert!(!verify_password(VALID_PASSWORD, "none"));
+    assert!(!needs_rehash("none"));
+}
+
+#[test]
+fn test_verify_code() {
+    let code_hash = hash_code("123456");
+    assert!(verify_code("123456", &code_hash));
+    assert!(!verify_code("654321", &code_hash));
+    assert!(!verify_code("123456", "not_base64"));
+}
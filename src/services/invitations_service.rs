@@ -0,0 +1,172 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use anyhow::Error;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, ModelTrait,
+    PaginatorTrait, QueryFilter, Set,
+};
+
+use entities::{
+    invitation::{ActiveModel, Column, Entity, Model},
+    user,
+};
+
+use crate::common::{normalize_email, ServiceError};
+use crate::providers::{Database, Mailer};
+
+use super::helpers::{hash_code, random_string, verify_code};
+use super::users_service;
+
+const SECRET_LENGTH: usize = 32;
+const INVITATION_EXPIRATION_DAYS: i64 = 7;
+const INVITATION_NOT_FOUND: &str = "Invitation not found";
+const INVALID_INVITATION: &str = "Invalid or expired invitation";
+pub const INVITATION_REQUIRED: &str = "Sign up is by invitation only";
+
+/// Splits `<invitation id>.<secret>` into its id and secret.
+pub fn parse_invitation_token(token: &str) -> Option<(i32, &str)> {
+    let (id, secret) = token.split_once('.')?;
+
+    if secret.len() != SECRET_LENGTH {
+        return None;
+    }
+
+    Some((id.parse().ok()?, secret))
+}
+
+/// Accepts the pending invitation sent to `email`, for provider sign ups that come
+/// back with that verified address instead of the invitation link.
+pub async fn accept_invitation_for_email<C: ConnectionTrait>(
+    conn: &C,
+    email: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("invitations_service::accept_invitation_for_email");
+    let forbidden = || ServiceError::forbidden::<Error>(INVITATION_REQUIRED, None);
+    let invitation = Entity::find_pending_by_email(&email.trim().to_lowercase())
+        .one(conn)
+        .await?
+        .ok_or_else(forbidden)?;
+
+    // The condition is repeated so concurrent sign ups accept it at most once
+    let result = Entity::update_many()
+        .col_expr(Column::AcceptedAt, Expr::value(Utc::now().naive_utc()))
+        .filter(Column::Id.eq(invitation.id))
+        .filter(Column::AcceptedAt.is_null())
+        .exec(conn)
+        .await?;
+
+    if result.rows_affected == 0 {
+        return Err(forbidden());
+    }
+
+    tracing::info!("Invitation {} accepted", invitation.id);
+    Ok(())
+}
+
+/// Any pending invitation for the same email is replaced, so only the latest link works.
+pub async fn invite_user(
+    db: &Database,
+    mailer: &Mailer,
+    invited_by: i32,
+    email: &str,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("invitations_service::invite_user", %invited_by);
+    let email = email.trim().to_lowercase();
+    let count = user::Entity::find_by_normalized_email_with_deleted(&normalize_email(&email))
+        .count(db.get_connection())
+        .await?;
+
+    if count > 0 {
+        return Err(ServiceError::conflict::<Error>("User already exists", None));
+    }
+
+    let inviter = users_service::find_one_by_id(&db.session(), invited_by).await?;
+    let secret = random_string(SECRET_LENGTH);
+    let txn = db.begin().await?;
+    Entity::delete_many()
+        .filter(Column::Email.eq(&email))
+        .filter(Column::AcceptedAt.is_null())
+        .exec(&txn)
+        .await?;
+    let invitation = ActiveModel {
+        email: Set(email),
+        token_hash: Set(hash_code(&secret)),
+        invited_by: Set(invited_by),
+        expires_at: Set(Utc::now().naive_utc() + Duration::days(INVITATION_EXPIRATION_DAYS)),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    // The invitation is only committed together with its email
+    mailer
+        .send_invitation_email(
+            &txn,
+            &invitation.email,
+            &inviter.full_name(),
+            INVITATION_EXPIRATION_DAYS,
+            &format!("{}.{}", invitation.id, secret),
+        )
+        .await?;
+    txn.commit().await?;
+    tracing::info!("Invitation {} created", invitation.id);
+    Ok(invitation)
+}
+
+pub async fn find_pending_invitations(db: &Database) -> Result<Vec<Model>, ServiceError> {
+    tracing::info_span!("invitations_service::find_pending_invitations");
+    Ok(Entity::find_pending().all(db.get_connection()).await?)
+}
+
+pub async fn revoke_invitation(db: &Database, id: i32) -> Result<(), ServiceError> {
+    tracing::info_span!("invitations_service::revoke_invitation", %id);
+    let invitation = Entity::find_pending_by_id(id)
+        .one(db.get_connection())
+        .await?
+        .ok_or_else(|| ServiceError::not_found::<Error>(INVITATION_NOT_FOUND, None))?;
+    invitation.delete(db.get_connection()).await?;
+    Ok(())
+}
+
+/// Marks the invitation for `email` as accepted, meant to run in the sign up transaction.
+pub async fn accept_invitation<C: ConnectionTrait>(
+    conn: &C,
+    token: &str,
+    email: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("invitations_service::accept_invitation");
+    let invalid = || ServiceError::forbidden::<Error>(INVALID_INVITATION, None);
+    let (id, secret) = parse_invitation_token(token).ok_or_else(invalid)?;
+    let invitation = Entity::find_by_id(id)
+        .one(conn)
+        .await?
+        .ok_or_else(invalid)?;
+
+    if !verify_code(secret, &invitation.token_hash)
+        || invitation.email != email.trim().to_lowercase()
+        || invitation.is_expired()
+        || invitation.is_accepted()
+    {
+        tracing::warn!("Invitation {} rejected", id);
+        return Err(invalid());
+    }
+
+    // The condition is repeated so concurrent sign ups accept it at most once
+    let result = Entity::update_many()
+        .col_expr(Column::AcceptedAt, Expr::value(Utc::now().naive_utc()))
+        .filter(Column::Id.eq(id))
+        .filter(Column::AcceptedAt.is_null())
+        .exec(conn)
+        .await?;
+
+    if result.rows_affected == 0 {
+        return Err(invalid());
+    }
+
+    tracing::info!("Invitation {} accepted", id);
+    Ok(())
+}
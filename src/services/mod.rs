@@ -4,7 +4,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub mod api_keys_service;
 pub mod auth_service;
+pub mod data_export_service;
+pub mod devices_service;
 pub mod helpers;
+pub mod invitations_service;
+pub mod oauth_providers_service;
+pub mod outbox_service;
+pub mod presence_service;
+pub mod recovery_codes_service;
+pub mod sessions_service;
+pub mod storage_gc_service;
+pub mod token_blacklist_service;
+pub mod unconfirmed_accounts_service;
 pub mod uploader_service;
 pub mod users_service;
@@ -0,0 +1,66 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm::{ColumnTrait, EntityTrait, Iterable, QuerySelect};
+
+use entities::{enums::OAuthProviderEnum, oauth_provider, user};
+
+use crate::common::ServiceError;
+use crate::providers::Database;
+
+pub struct ProviderSignupsPage {
+    pub signups: Vec<(oauth_provider::Model, user::Model)>,
+    pub has_next_page: bool,
+}
+
+/// Counts users per provider in a single grouped query, providers nobody used count zero.
+pub async fn count_by_provider(
+    db: &Database,
+) -> Result<Vec<(OAuthProviderEnum, u64)>, ServiceError> {
+    tracing::info_span!("oauth_providers_service::count_by_provider");
+    let counts = oauth_provider::Entity::find()
+        .select_only()
+        .column(oauth_provider::Column::Provider)
+        .column_as(oauth_provider::Column::Id.count(), "count")
+        .group_by(oauth_provider::Column::Provider)
+        .into_tuple::<(OAuthProviderEnum, i64)>()
+        .all(db.get_connection())
+        .await?;
+
+    Ok(OAuthProviderEnum::iter()
+        .map(|provider| {
+            let count = counts
+                .iter()
+                .find(|(counted, _)| *counted == provider)
+                .map_or(0, |(_, count)| *count);
+            (provider, count as u64)
+        })
+        .collect())
+}
+
+/// Joins the users in the same query, so a page never goes through the loader.
+pub async fn find_recent_signups(
+    db: &Database,
+    provider: OAuthProviderEnum,
+    limit: u64,
+    after: Option<String>,
+) -> Result<ProviderSignupsPage, ServiceError> {
+    tracing::info_span!("oauth_providers_service::find_recent_signups");
+    let mut signups = oauth_provider::Entity::find_recent_by_provider(provider, after)
+        .find_also_related(user::Entity)
+        .limit(limit + 1)
+        .all(db.get_connection())
+        .await?
+        .into_iter()
+        .filter_map(|(provider, user)| user.map(|user| (provider, user)))
+        .collect::<Vec<(oauth_provider::Model, user::Model)>>();
+    let has_next_page = signups.len() as u64 > limit;
+    signups.truncate(limit as usize);
+    Ok(ProviderSignupsPage {
+        signups,
+        has_next_page,
+    })
+}
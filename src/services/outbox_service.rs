@@ -0,0 +1,114 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::{Duration, Utc};
+use sea_orm::{
+    sea_query::{Expr, LockBehavior, LockType},
+    ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QuerySelect, Set,
+};
+
+use entities::{
+    email_outbox::{ActiveModel, Column, Entity, Model},
+    enums::EmailStatusEnum,
+};
+
+use crate::common::ServiceError;
+use crate::providers::{Database, Mailer};
+
+const OUTBOX_BATCH_SIZE: u64 = 20;
+const BASE_RETRY_DELAY_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_SECONDS: i64 = 3600;
+const DEFAULT_FAILED_EMAILS_LIMIT: u64 = 50;
+/// Longer than a batch takes to send, claims older than this belong to a dead worker.
+const CLAIM_TIMEOUT_SECONDS: i64 = 15 * 60;
+
+/// Exponential backoff: 30s, 1m, 2m, 4m... capped at one hour.
+pub fn retry_delay(attempts: i16) -> Duration {
+    let exponent = u32::try_from(attempts.max(1) - 1).unwrap_or(0).min(16);
+    Duration::seconds((BASE_RETRY_DELAY_SECONDS << exponent).min(MAX_RETRY_DELAY_SECONDS))
+}
+
+/// Claims a batch of due rows, counting the attempt, and commits before anything is
+/// sent so no row lock is held while the transport works. Rows are locked with
+/// `SKIP LOCKED` so several instances can run the worker.
+async fn claim_due_emails(db: &Database) -> Result<Vec<Model>, ServiceError> {
+    let now = Utc::now().naive_utc();
+    let txn = db.begin().await?;
+    let mut due = Entity::find_due(now, now - Duration::seconds(CLAIM_TIMEOUT_SECONDS))
+        .limit(OUTBOX_BATCH_SIZE);
+    QuerySelect::query(&mut due).lock_with_behavior(LockType::Update, LockBehavior::SkipLocked);
+    let emails = due.all(&txn).await?;
+
+    if !emails.is_empty() {
+        Entity::update_many()
+            .col_expr(Column::Status, Expr::value(EmailStatusEnum::Sending))
+            .col_expr(Column::Attempts, Expr::col(Column::Attempts).add(1))
+            .col_expr(Column::ClaimedAt, Expr::value(now))
+            .col_expr(Column::UpdatedAt, Expr::value(now))
+            .filter(Column::Id.is_in(emails.iter().map(|email| email.id)))
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+    Ok(emails)
+}
+
+/// Sends every due email once, rescheduling or failing the ones the transport rejects.
+/// Each row is settled on its own once sent, and the body of a sent email, which may
+/// hold a token link, is cleared.
+pub async fn process_email_outbox(db: &Database, mailer: &Mailer) -> Result<usize, ServiceError> {
+    tracing::info_span!("outbox_service::process_email_outbox");
+    let emails = claim_due_emails(db).await?;
+    let processed = emails.len();
+
+    for email in emails {
+        let attempts = email.attempts + 1;
+        let result = mailer.deliver(&email).await;
+        let id = email.id;
+        let mut email: ActiveModel = email.into();
+        email.attempts = Set(attempts);
+        email.claimed_at = Set(None);
+
+        match result {
+            Ok(()) => {
+                tracing::info!("Sent email {}", id);
+                email.status = Set(EmailStatusEnum::Sent);
+                email.body = Set(String::new());
+                email.last_error = Set(None);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to send email {} (attempt {}): {}", id, attempts, e);
+                email.last_error = Set(Some(e.to_string()));
+
+                if attempts >= mailer.max_attempts() {
+                    email.status = Set(EmailStatusEnum::Failed);
+                } else {
+                    email.status = Set(EmailStatusEnum::Pending);
+                    email.next_attempt_at = Set(Utc::now().naive_utc() + retry_delay(attempts));
+                }
+            }
+        }
+
+        // A row left claimed is retried once its claim times out
+        if let Err(e) = email.update(db.get_connection()).await {
+            tracing::error!("Failed to settle email {}: {:?}", id, e);
+        }
+    }
+
+    Ok(processed)
+}
+
+pub async fn find_failed_emails(
+    db: &Database,
+    limit: Option<u64>,
+) -> Result<Vec<Model>, ServiceError> {
+    tracing::info_span!("outbox_service::find_failed_emails");
+    Ok(Entity::find_failed()
+        .limit(limit.unwrap_or(DEFAULT_FAILED_EMAILS_LIMIT))
+        .all(db.get_connection())
+        .await?)
+}
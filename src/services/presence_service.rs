@@ -0,0 +1,124 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use chrono::{Duration, Utc};
+use sea_orm::{sea_query::Expr, ColumnTrait, Condition, EntityTrait, QueryFilter};
+
+use entities::user;
+
+use crate::common::ServiceError;
+use crate::dtos::OnlineStatus;
+use crate::providers::{Cache, Database};
+
+const PRESENCE_PREFIX: &str = "presence";
+/// Users are online while they made a request this recently.
+const PRESENCE_TTL: u64 = 5 * 60;
+/// Requests refresh the presence at most this often per user.
+const TOUCH_INTERVAL: u64 = 60;
+/// The stored activity lags behind the presence by at most this many minutes.
+const STORED_ACTIVITY_MINUTES: i64 = 10;
+const RECENTLY_ACTIVE_HOURS: i64 = 24;
+
+fn presence_key(user_id: i32) -> String {
+    format!("{}:{}", PRESENCE_PREFIX, user_id)
+}
+
+fn touched_key(user_id: i32) -> String {
+    format!("{}:{}:touched", PRESENCE_PREFIX, user_id)
+}
+
+/// Marks the user online on an authenticated request, at most once a minute, and
+/// stores the activity when the stored one is more than ten minutes old.
+pub async fn touch(db: &Database, cache: &Cache, user_id: i32) -> Result<(), ServiceError> {
+    let now = Utc::now();
+    let timestamp = now.timestamp();
+    let key = touched_key(user_id);
+    let key = key.as_str();
+    let touched = cache
+        .execute(|mut connection| async move {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(timestamp)
+                .arg("NX")
+                .arg("EX")
+                .arg(TOUCH_INTERVAL)
+                .query_async::<_, Option<String>>(&mut connection)
+                .await
+        })
+        .await?
+        .is_some();
+
+    if !touched {
+        return Ok(());
+    }
+
+    cache
+        .set_json(&presence_key(user_id), &timestamp, PRESENCE_TTL)
+        .await?;
+    let now = now.naive_utc();
+    user::Entity::update_many()
+        .col_expr(user::Column::LastActiveAt, Expr::value(now))
+        .filter(user::Column::Id.eq(user_id))
+        .filter(
+            Condition::any()
+                .add(user::Column::LastActiveAt.is_null())
+                .add(
+                    user::Column::LastActiveAt.lt(now - Duration::minutes(STORED_ACTIVITY_MINUTES)),
+                ),
+        )
+        .exec(db.get_connection())
+        .await?;
+    Ok(())
+}
+
+/// Unix timestamps of the last request of the users that are online, in one `MGET`.
+pub async fn find_online(
+    cache: &Cache,
+    user_ids: &[i32],
+) -> Result<HashMap<i32, i64>, ServiceError> {
+    if user_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let keys = user_ids
+        .iter()
+        .map(|id| presence_key(*id))
+        .collect::<Vec<String>>();
+    let keys = &keys;
+    let values = cache
+        .execute(|mut connection| async move {
+            redis::cmd("MGET")
+                .arg(keys)
+                .query_async::<_, Vec<Option<String>>>(&mut connection)
+                .await
+        })
+        .await?;
+
+    Ok(user_ids
+        .iter()
+        .zip(values)
+        .filter_map(|(id, value)| Some((*id, value?.parse::<i64>().ok()?)))
+        .collect())
+}
+
+/// Online with a presence, otherwise recently active when the stored activity is less
+/// than a day old.
+pub fn online_status(online: bool, last_active_at: Option<i64>) -> OnlineStatus {
+    if online {
+        return OnlineStatus::Online;
+    }
+
+    match last_active_at {
+        Some(last_active_at)
+            if Utc::now().timestamp() - last_active_at < RECENTLY_ACTIVE_HOURS * 3600 =>
+        {
+            OnlineStatus::RecentlyActive
+        }
+        _ => OnlineStatus::Offline,
+    }
+}
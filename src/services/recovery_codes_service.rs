@@ -0,0 +1,93 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use anyhow::Error;
+use chrono::Utc;
+use rand::{seq::SliceRandom, thread_rng};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, EntityTrait, PaginatorTrait, QueryFilter, Set,
+};
+
+use entities::recovery_code::{ActiveModel, Column, Entity};
+
+use crate::common::ServiceError;
+use crate::providers::Database;
+
+use super::helpers::hash_code;
+
+pub const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+// Lowercase letters and digits without the easily confused 0, 1, i, l and o
+const RECOVERY_CODE_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+fn generate_recovery_code() -> String {
+    let mut rng = thread_rng();
+    let code = (0..RECOVERY_CODE_LENGTH)
+        .map(|_| *RECOVERY_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect::<String>();
+    let (first, last) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+    format!("{}-{}", first, last)
+}
+
+/// Codes are hashed without their separator and casing, as users retype them.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|char| char.to_ascii_lowercase())
+        .collect()
+}
+
+/// Replaces every previous code of the user, returning the plaintext codes once.
+pub async fn generate_codes(db: &Database, user_id: i32) -> Result<Vec<String>, ServiceError> {
+    tracing::info_span!("recovery_codes_service::generate_codes", %user_id);
+    let codes = (0..RECOVERY_CODES_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<String>>();
+    let models = codes.iter().map(|code| ActiveModel {
+        user_id: Set(user_id),
+        code_hash: Set(hash_code(&normalize_recovery_code(code))),
+        ..Default::default()
+    });
+    let txn = db.begin().await?;
+    Entity::delete_many()
+        .filter(Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await?;
+    Entity::insert_many(models).exec(&txn).await?;
+    txn.commit().await?;
+    tracing::info!("Generated recovery codes for user with id {}", user_id);
+    Ok(codes)
+}
+
+/// Marks the code as used in a single conditional update, so concurrent
+/// requests with the same code cannot both succeed.
+pub async fn use_code(db: &Database, user_id: i32, code: &str) -> Result<(), ServiceError> {
+    tracing::info_span!("recovery_codes_service::use_code", %user_id);
+    let result = Entity::update_many()
+        .col_expr(Column::UsedAt, Expr::value(Utc::now().naive_utc()))
+        .filter(
+            Condition::all()
+                .add(Column::UserId.eq(user_id))
+                .add(Column::CodeHash.eq(hash_code(&normalize_recovery_code(code))))
+                .add(Column::UsedAt.is_null()),
+        )
+        .exec(db.get_connection())
+        .await?;
+
+    if result.rows_affected == 0 {
+        tracing::warn!("User with id {} used an invalid recovery code", user_id);
+        return Err(ServiceError::unauthorized::<Error>("Invalid code", None));
+    }
+
+    Ok(())
+}
+
+pub async fn count_remaining(db: &Database, user_id: i32) -> Result<u64, ServiceError> {
+    tracing::info_span!("recovery_codes_service::count_remaining", %user_id);
+    Ok(Entity::find_unused_by_user_id(user_id)
+        .count(db.get_connection())
+        .await?)
+}
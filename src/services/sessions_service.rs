@@ -0,0 +1,113 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use anyhow::Error;
+use chrono::Utc;
+use redis::AsyncCommands;
+
+use crate::common::{ClientInfo, ServiceError, SOMETHING_WENT_WRONG};
+use crate::dtos::objects::Session;
+use crate::providers::Cache;
+
+const SESSIONS: &str = "sessions";
+
+fn get_sessions_key(user_id: i32) -> String {
+    format!("{}:{}", SESSIONS, user_id)
+}
+
+pub async fn create_session(
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
+    exp: i64,
+    client: &ClientInfo,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("sessions_service::create_session", id = %user_id);
+    let session = serde_json::to_string(&Session::new(token_id, exp, client))
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let ttl = exp - Utc::now().timestamp();
+    let key = get_sessions_key(user_id);
+    let (key, session) = (key.as_str(), session.as_str());
+    cache
+        .execute(|mut connection| async move {
+            connection
+                .hset::<&str, &str, &str, ()>(key, token_id, session)
+                .await
+        })
+        .await?;
+    // Sessions share the refresh TTL, so the newest one always outlives the rest
+    cache
+        .execute(|mut connection| async move { connection.expire::<&str, ()>(key, ttl).await })
+        .await
+}
+
+pub async fn find_sessions(cache: &Cache, user_id: i32) -> Result<Vec<Session>, ServiceError> {
+    tracing::info_span!("sessions_service::find_sessions", id = %user_id);
+    let key = get_sessions_key(user_id);
+    let key = key.as_str();
+    let values = cache
+        .execute(|mut connection| async move {
+            connection
+                .hgetall::<&str, HashMap<String, String>>(key)
+                .await
+        })
+        .await?;
+    let (mut active, expired): (Vec<Session>, Vec<Session>) = values
+        .values()
+        .filter_map(|value| serde_json::from_str::<Session>(value).ok())
+        .partition(|session| !session.is_expired());
+
+    for session in expired {
+        remove_session(cache, user_id, &session.token_id).await?;
+    }
+
+    active.sort_by_key(|session| Reverse(session.created_at));
+    Ok(active)
+}
+
+pub async fn find_session(
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
+) -> Result<Session, ServiceError> {
+    tracing::info_span!("sessions_service::find_session", id = %user_id);
+    let key = get_sessions_key(user_id);
+    let key = key.as_str();
+    let value = cache
+        .execute(|mut connection| async move {
+            connection
+                .hget::<&str, &str, Option<String>>(key, token_id)
+                .await
+        })
+        .await?;
+
+    match value.and_then(|value| serde_json::from_str::<Session>(&value).ok()) {
+        Some(session) if !session.is_expired() => Ok(session),
+        _ => Err(ServiceError::not_found::<Error>("Session not found", None)),
+    }
+}
+
+pub async fn remove_session(
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("sessions_service::remove_session", id = %user_id);
+    let key = get_sessions_key(user_id);
+    let key = key.as_str();
+    cache
+        .execute(
+            |mut connection| async move { connection.hdel::<&str, &str, ()>(key, token_id).await },
+        )
+        .await
+}
+
+pub async fn clear_sessions(cache: &Cache, user_id: i32) -> Result<(), ServiceError> {
+    tracing::info_span!("sessions_service::clear_sessions", id = %user_id);
+    cache.del(&get_sessions_key(user_id)).await
+}
@@ -0,0 +1,301 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use sea_orm::{
+    ColumnTrait, Condition, EntityTrait, ModelTrait, QueryFilter, QueryOrder, QuerySelect,
+};
+use uuid::Uuid;
+
+use entities::uploaded_file;
+
+use crate::common::ServiceError;
+use crate::dtos::objects::StorageGc;
+use crate::providers::{Cache, Database, ListedObject, ObjectStorage};
+
+use super::uploader_service;
+
+const ORPHAN_GRACE_HOURS: i64 = 24;
+const LOCK_KEY: &str = "storage_gc:lock";
+/// Longer than a collection should take, a crashed instance only blocks the job this long.
+const LOCK_TTL: u64 = 60 * 60;
+/// User prefixes diffed per query, and rows read per page of the unlisted ones.
+const BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct StorageOrphans {
+    /// Objects no uploaded file points at.
+    pub objects: Vec<ListedObject>,
+    /// Uploaded files whose original object is gone from the bucket.
+    pub files: Vec<uploaded_file::Model>,
+}
+
+/// Diffs the rows of a single user against the objects listed under their prefix.
+pub fn find_orphans(
+    object_storage: &ObjectStorage,
+    files: Vec<uploaded_file::Model>,
+    objects: Vec<ListedObject>,
+) -> StorageOrphans {
+    let referenced = files
+        .iter()
+        .flat_map(uploader_service::file_locations)
+        .map(|location| object_storage.get_file_key(&location))
+        .collect::<HashSet<String>>();
+    // Resized variants belong to their original, they go when it does
+    let resized_stems = files
+        .iter()
+        .map(|file| uploader_service::original_key_stem(object_storage, file))
+        .collect::<HashSet<String>>();
+    let stored = objects
+        .iter()
+        .map(|object| object.key.clone())
+        .collect::<HashSet<String>>();
+
+    StorageOrphans {
+        files: files
+            .into_iter()
+            .filter(|file| !stored.contains(&object_storage.get_file_key(&file.url)))
+            .collect(),
+        objects: objects
+            .into_iter()
+            .filter(|object| {
+                !referenced.contains(&object.key)
+                    && !uploader_service::resized_image_stem(&object.key)
+                        .is_some_and(|stem| resized_stems.contains(stem))
+            })
+            .collect(),
+    }
+}
+
+/// The user prefix of an object key, keys outside one are not uploads.
+fn key_prefix(key: &str) -> Option<&str> {
+    key.split_once('/').map(|(prefix, _)| prefix)
+}
+
+fn file_prefix(object_storage: &ObjectStorage, file: &uploaded_file::Model) -> Option<String> {
+    key_prefix(&object_storage.get_file_key(&file.url)).map(str::to_string)
+}
+
+/// Diffs a batch of listed prefixes against their rows with a single query. Rows written
+/// after `started_at` may point at objects uploaded after their prefix was listed.
+async fn diff_prefixes(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    prefixes: Vec<(String, Vec<ListedObject>)>,
+    started_at: NaiveDateTime,
+    orphans: &mut StorageOrphans,
+) -> Result<(), ServiceError> {
+    // Public files store the URL of their key, private ones the bare key
+    let condition = prefixes
+        .iter()
+        .fold(Condition::any(), |condition, (prefix, _)| {
+            let key_prefix = format!("{}/", prefix);
+            condition
+                .add(
+                    uploaded_file::Column::Url
+                        .starts_with(object_storage.get_file_url(&key_prefix)),
+                )
+                .add(uploaded_file::Column::Url.starts_with(&key_prefix))
+        });
+    let mut files_by_prefix = HashMap::<String, Vec<uploaded_file::Model>>::new();
+
+    for file in uploaded_file::Entity::find()
+        .filter(condition)
+        .all(db.get_connection())
+        .await?
+    {
+        if let Some(prefix) = file_prefix(object_storage, &file) {
+            files_by_prefix.entry(prefix).or_default().push(file);
+        }
+    }
+
+    for (prefix, objects) in prefixes {
+        let files = files_by_prefix.remove(&prefix).unwrap_or_default();
+        let prefix_orphans = find_orphans(object_storage, files, objects);
+        orphans.objects.extend(prefix_orphans.objects);
+        orphans.files.extend(
+            prefix_orphans
+                .files
+                .into_iter()
+                .filter(|file| file.created_at < started_at),
+        );
+    }
+
+    Ok(())
+}
+
+/// Rows under prefixes the listing never reached lost every one of their objects.
+async fn find_unlisted_files(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    listed_prefixes: &HashSet<String>,
+    started_at: NaiveDateTime,
+) -> Result<Vec<uploaded_file::Model>, ServiceError> {
+    let mut files = Vec::<uploaded_file::Model>::new();
+    let mut last_id = Uuid::nil();
+
+    loop {
+        let batch = uploaded_file::Entity::find()
+            .filter(uploaded_file::Column::Id.gt(last_id))
+            .filter(uploaded_file::Column::CreatedAt.lt(started_at))
+            .order_by_asc(uploaded_file::Column::Id)
+            .limit(BATCH_SIZE as u64)
+            .all(db.get_connection())
+            .await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        last_id = last.id;
+
+        files.extend(batch.into_iter().filter(|file| {
+            !file_prefix(object_storage, file)
+                .is_some_and(|prefix| listed_prefixes.contains(&prefix))
+        }));
+    }
+
+    Ok(files)
+}
+
+/// Lists both kinds of orphans with a single pass over the bucket. The listing is in key
+/// order, so the objects of each user prefix arrive together and are diffed in batches.
+/// Prefixes of hard deleted users have no rows left, everything under them is orphaned.
+pub async fn report(
+    db: &Database,
+    object_storage: &ObjectStorage,
+) -> Result<StorageOrphans, ServiceError> {
+    tracing::info_span!("storage_gc_service::report");
+    let started_at = Utc::now().naive_utc();
+    let mut orphans = StorageOrphans::default();
+    let mut listed_prefixes = HashSet::<String>::new();
+    let mut pending = Vec::<(String, Vec<ListedObject>)>::new();
+    let mut token = None;
+
+    loop {
+        let page = object_storage.list_files_page("", token).await?;
+
+        for object in page.objects {
+            let Some(prefix) = key_prefix(&object.key).map(str::to_string) else {
+                continue;
+            };
+
+            match pending.last_mut() {
+                Some((last, objects)) if *last == prefix => objects.push(object),
+                _ => {
+                    listed_prefixes.insert(prefix.clone());
+                    pending.push((prefix, vec![object]));
+                }
+            }
+        }
+
+        token = page.next_token;
+        // The last prefix may go on in the next page
+        let ready = match token {
+            Some(_) => pending.len().saturating_sub(1),
+            None => pending.len(),
+        };
+
+        if ready >= BATCH_SIZE || token.is_none() {
+            let mut prefixes = pending.drain(..ready).collect::<Vec<_>>();
+
+            while !prefixes.is_empty() {
+                let rest = prefixes.split_off(prefixes.len().min(BATCH_SIZE));
+                diff_prefixes(db, object_storage, prefixes, started_at, &mut orphans).await?;
+                prefixes = rest;
+            }
+        }
+
+        if token.is_none() {
+            break;
+        }
+    }
+
+    orphans
+        .files
+        .extend(find_unlisted_files(db, object_storage, &listed_prefixes, started_at).await?);
+    Ok(orphans)
+}
+
+/// Deletes unreferenced objects past the grace period, which covers uploads whose row
+/// is not written yet, and the rows pointing at missing objects. Returns `None` when
+/// another instance holds the lock and is already collecting.
+pub async fn purge(
+    db: &Database,
+    cache: &Cache,
+    object_storage: &ObjectStorage,
+    dry_run: bool,
+) -> Result<Option<StorageGc>, ServiceError> {
+    tracing::info_span!("storage_gc_service::purge", %dry_run);
+
+    if !cache
+        .set_once(LOCK_KEY, Utc::now().timestamp(), LOCK_TTL)
+        .await?
+    {
+        tracing::info!("Storage is already being collected by another instance");
+        return Ok(None);
+    }
+
+    let result = collect(db, object_storage, dry_run).await;
+    if let Err(e) = cache.del(LOCK_KEY).await {
+        tracing::warn!("Failed to release the storage collection lock: {:?}", e);
+    }
+
+    result.map(Some)
+}
+
+async fn collect(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    dry_run: bool,
+) -> Result<StorageGc, ServiceError> {
+    let orphans = report(db, object_storage).await?;
+    let threshold = Utc::now() - Duration::hours(ORPHAN_GRACE_HOURS);
+    let objects = orphans
+        .objects
+        .into_iter()
+        .filter(|object| object.last_modified < threshold)
+        .collect::<Vec<ListedObject>>();
+    let mut result = StorageGc {
+        dry_run,
+        orphaned_objects: objects.len() as u64,
+        missing_files: orphans.files.len() as u64,
+        ..Default::default()
+    };
+
+    if dry_run {
+        return Ok(result);
+    }
+
+    for object in objects {
+        if let Err(e) = object_storage.delete_file(&object.key).await {
+            tracing::error!("Failed to delete orphaned object {}: {:?}", &object.key, e);
+            continue;
+        }
+
+        result.deleted_objects += 1;
+    }
+
+    for file in orphans.files {
+        let id = file.id;
+
+        // Renditions left behind go with the row, pictures are unset by the foreign key
+        if let Err(e) = uploader_service::delete_file_objects(db, object_storage, &file).await {
+            tracing::error!("Failed to delete objects of file {}: {:?}", id, e);
+            continue;
+        }
+
+        file.delete(db.get_connection()).await?;
+        result.deleted_files += 1;
+    }
+
+    tracing::info!(
+        "Collected {} objects and {} files",
+        result.deleted_objects,
+        result.deleted_files
+    );
+    Ok(result)
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use anyhow::Error;
+use chrono::{NaiveDateTime, Utc};
+use redis::AsyncCommands;
+use sea_orm::{
+    sea_query::OnConflict, ColumnTrait, ConnectionTrait, EntityTrait, PaginatorTrait, QueryFilter,
+    Set,
+};
+
+use entities::blacklisted_token;
+
+use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+use crate::providers::{Cache, Database, Metrics};
+
+const BLACKLIST_TOKEN: &str = "blacklist_token";
+
+fn get_blacklist_key(token_id: &str) -> String {
+    format!("{}:{}", BLACKLIST_TOKEN, token_id)
+}
+
+fn get_ttl(exp: i64) -> u64 {
+    (exp - Utc::now().timestamp()).max(1) as u64
+}
+
+async fn cache_token(
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
+    ttl: u64,
+) -> Result<(), ServiceError> {
+    let key = get_blacklist_key(token_id);
+    let key = key.as_str();
+    cache
+        .execute(|mut connection| async move {
+            connection.set_ex::<&str, i32, ()>(key, user_id, ttl).await
+        })
+        .await
+}
+
+/// Writes the token to the database first, Redis only speeds up the lookups.
+pub async fn blacklist_token(
+    db: &Database,
+    cache: &Cache,
+    user_id: i32,
+    token_id: &str,
+    exp: i64,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("token_blacklist_service::blacklist_token", id = %user_id);
+    insert_token(db.get_connection(), user_id, token_id, exp).await?;
+    cache_blacklisted_token(cache, user_id, token_id, exp).await;
+    Ok(())
+}
+
+/// The database half of `blacklist_token`, for flows that blacklist inside their own
+/// transaction and cache the token once it is committed.
+pub async fn insert_token<C: ConnectionTrait>(
+    connection: &C,
+    user_id: i32,
+    token_id: &str,
+    exp: i64,
+) -> Result<(), ServiceError> {
+    let expires_at = NaiveDateTime::from_timestamp_opt(exp, 0)
+        .ok_or_else(|| ServiceError::internal_server_error::<Error>(SOMETHING_WENT_WRONG, None))?;
+    // Signing out twice with the same token is not an error
+    blacklisted_token::Entity::insert(blacklisted_token::ActiveModel {
+        token_id: Set(token_id.to_string()),
+        user_id: Set(user_id),
+        expires_at: Set(expires_at),
+        ..Default::default()
+    })
+    .on_conflict(
+        OnConflict::column(blacklisted_token::Column::TokenId)
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(connection)
+    .await?;
+    Ok(())
+}
+
+/// Lookups fall back to the database, so a failure is only logged.
+pub async fn cache_blacklisted_token(cache: &Cache, user_id: i32, token_id: &str, exp: i64) {
+    if let Err(e) = cache_token(cache, user_id, token_id, get_ttl(exp)).await {
+        tracing::warn!("Failed to cache blacklisted token: {:?}", e);
+    }
+}
+
+/// Checks Redis first and falls back to the database, warming the cache on a hit.
+pub async fn is_blacklisted(
+    db: &Database,
+    cache: &Cache,
+    token_id: &str,
+) -> Result<bool, ServiceError> {
+    tracing::info_span!("token_blacklist_service::is_blacklisted");
+    let key = get_blacklist_key(token_id);
+    let key = key.as_str();
+    let cached = cache
+        .execute(|mut connection| async move { connection.exists::<&str, bool>(key).await })
+        .await;
+
+    match cached {
+        Ok(true) => return Ok(true),
+        Ok(false) => (),
+        Err(e) => tracing::warn!("Failed to check the blacklist cache: {:?}", e),
+    }
+
+    let Some(token) = blacklisted_token::Entity::find_active_by_token_id(token_id)
+        .one(db.get_connection())
+        .await?
+    else {
+        return Ok(false);
+    };
+
+    let ttl = get_ttl(token.expires_at.timestamp());
+    if let Err(e) = cache_token(cache, token.user_id, token_id, ttl).await {
+        tracing::warn!("Failed to cache blacklisted token: {:?}", e);
+    }
+
+    Ok(true)
+}
+
+/// Deletes the rows of tokens that expired, as they can no longer be verified anyway.
+pub async fn purge_expired(db: &Database, metrics: &Metrics) -> Result<u64, ServiceError> {
+    tracing::info_span!("token_blacklist_service::purge_expired");
+    let connection = db.get_connection();
+    let purged = blacklisted_token::Entity::delete_many()
+        .filter(blacklisted_token::Column::ExpiresAt.lte(Utc::now().naive_utc()))
+        .exec(connection)
+        .await?
+        .rows_affected;
+    let size = blacklisted_token::Entity::find_active()
+        .count(connection)
+        .await?;
+    metrics.set_blacklist_size(size);
+    tracing::info!(
+        "Purged {} expired blacklisted tokens, {} remain",
+        purged,
+        size
+    );
+    Ok(purged)
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use sea_orm::{ColumnTrait, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect};
+
+use entities::user;
+
+use crate::common::ServiceError;
+use crate::dtos::objects::UnconfirmedAccountStats;
+use crate::providers::{
+    Cache, Database, Jwt, Mailer, Metrics, ObjectStorage, TokenType, UnconfirmedExpiryConfig,
+};
+
+use super::{auth_service, users_service};
+
+const EXPIRY_PREFIX: &str = "unconfirmed_expiry";
+const WARNING_HOURS: i64 = 24;
+const BATCH_SIZE: u64 = 100;
+/// Longer than a run should take, a crashed instance only blocks the job this long.
+const LOCK_TTL: u64 = 15 * 60;
+/// Outlives the grace period, losing the mark only means a second warning.
+const WARNING_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExpiryRun {
+    pub warned: u64,
+    pub deleted: u64,
+}
+
+enum Expiry {
+    Waiting,
+    Warned,
+    Deleted,
+}
+
+fn lock_key() -> String {
+    format!("{}:lock", EXPIRY_PREFIX)
+}
+
+fn warning_key(user_id: i32) -> String {
+    format!("{}:warned:{}", EXPIRY_PREFIX, user_id)
+}
+
+/// Accounts created before the first date are expired, before the second are warned.
+fn get_thresholds(expiry: &UnconfirmedExpiryConfig) -> (NaiveDateTime, NaiveDateTime) {
+    let deadline = Utc::now().naive_utc() - Duration::days(expiry.days);
+    (deadline, deadline + Duration::hours(WARNING_HOURS))
+}
+
+pub async fn find_stats(
+    db: &Database,
+    expiry: &UnconfirmedExpiryConfig,
+) -> Result<UnconfirmedAccountStats, ServiceError> {
+    tracing::info_span!("unconfirmed_accounts_service::find_stats");
+    let connection = db.get_read_connection();
+    let (deadline, warn_before) = get_thresholds(expiry);
+    let unconfirmed = user::Entity::find_unconfirmed().count(connection).await?;
+    let expiring = user::Entity::find_unconfirmed_before(warn_before)
+        .filter(user::Column::CreatedAt.gte(deadline))
+        .count(connection)
+        .await?;
+    let expired = user::Entity::find_unconfirmed_before(deadline)
+        .count(connection)
+        .await?;
+    Ok(UnconfirmedAccountStats {
+        expiry_days: expiry.days,
+        unconfirmed,
+        expiring,
+        expired,
+    })
+}
+
+async fn send_warning(
+    db: &Database,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    user: &user::Model,
+) -> Result<(), ServiceError> {
+    let confirmation_token = jwt.generate_email_token(TokenType::Confirmation, user)?;
+    mailer
+        .send_account_expiry_email(
+            db.get_connection(),
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            WARNING_HOURS,
+            &confirmation_token,
+        )
+        .await?;
+    auth_service::record_confirmation_sent(cache, jwt, user.id).await
+}
+
+/// Warns the account once, then deletes it when it is past the deadline and the
+/// warning is a day old, so accounts that expired unwarned still get that day.
+async fn expire_account(
+    db: &Database,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    object_storage: &ObjectStorage,
+    user: user::Model,
+    deadline: NaiveDateTime,
+) -> Result<Expiry, ServiceError> {
+    let key = warning_key(user.id);
+    let now = Utc::now().timestamp();
+
+    let Some(warned_at) = cache.get_json::<i64>(&key).await? else {
+        // Marked before sending, so a run that overlaps another never sends it twice
+        if !cache.set_once(&key, now, WARNING_TTL).await? {
+            return Ok(Expiry::Waiting);
+        }
+        if let Err(e) = send_warning(db, cache, jwt, mailer, &user).await {
+            cache.del(&key).await.ok();
+            return Err(e);
+        }
+
+        return Ok(Expiry::Warned);
+    };
+
+    if user.created_at >= deadline || now - warned_at < WARNING_HOURS * 3600 {
+        return Ok(Expiry::Waiting);
+    }
+
+    let id = user.id;
+    let condition = user::Entity::unconfirmed_before(deadline);
+    if !users_service::purge_user(&db.session(), object_storage, id, condition).await? {
+        // Confirmed or deleted since the batch was read
+        return Ok(Expiry::Waiting);
+    }
+    if let Err(e) = users_service::invalidate_cached_user(cache, id).await {
+        tracing::warn!("Failed to invalidate the cached user {}: {:?}", id, e);
+    }
+    cache.del(&key).await.ok();
+    Ok(Expiry::Deleted)
+}
+
+async fn expire_batches(
+    db: &Database,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    object_storage: &ObjectStorage,
+    expiry: &UnconfirmedExpiryConfig,
+) -> Result<ExpiryRun, ServiceError> {
+    let (deadline, warn_before) = get_thresholds(expiry);
+    let mut run = ExpiryRun::default();
+    let mut last_id = 0;
+
+    loop {
+        let users = user::Entity::find_unconfirmed_before(warn_before)
+            .filter(user::Column::Id.gt(last_id))
+            .order_by_asc(user::Column::Id)
+            .limit(BATCH_SIZE)
+            .all(db.get_connection())
+            .await?;
+        let Some(last) = users.last() else {
+            break;
+        };
+        last_id = last.id;
+
+        for user in users {
+            let id = user.id;
+
+            match expire_account(db, cache, jwt, mailer, object_storage, user, deadline).await {
+                Ok(Expiry::Waiting) => (),
+                Ok(Expiry::Warned) => run.warned += 1,
+                Ok(Expiry::Deleted) => run.deleted += 1,
+                Err(e) => tracing::error!("Failed to expire unconfirmed user {}: {:?}", id, e),
+            }
+        }
+    }
+
+    Ok(run)
+}
+
+/// Warns unconfirmed accounts a day before they expire and deletes them afterwards.
+/// Returns `None` when another instance holds the lock and is already running it.
+pub async fn expire_unconfirmed_accounts(
+    db: &Database,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    object_storage: &ObjectStorage,
+    metrics: &Metrics,
+    expiry: &UnconfirmedExpiryConfig,
+) -> Result<Option<ExpiryRun>, ServiceError> {
+    tracing::info_span!("unconfirmed_accounts_service::expire_unconfirmed_accounts");
+    let lock_key = lock_key();
+
+    if !cache
+        .set_once(&lock_key, Utc::now().timestamp(), LOCK_TTL)
+        .await?
+    {
+        tracing::info!("Unconfirmed accounts are already being expired by another instance");
+        return Ok(None);
+    }
+
+    let result = expire_batches(db, cache, jwt, mailer, object_storage, expiry).await;
+    if let Err(e) = cache.del(&lock_key).await {
+        tracing::warn!("Failed to release the unconfirmed accounts lock: {:?}", e);
+    }
+
+    let run = result?;
+    metrics.observe_unconfirmed_expiry(run.warned, run.deleted);
+    tracing::info!(
+        "Warned {} and deleted {} unconfirmed accounts",
+        run.warned,
+        run.deleted
+    );
+    Ok(Some(run))
+}
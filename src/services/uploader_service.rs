@@ -6,34 +6,214 @@
 
 use std::{
     cmp::min,
+    collections::HashMap,
     io::{BufReader, Cursor},
+    path::Path,
+    time::{Duration, Instant},
 };
 
+use actix_web::rt::task::spawn_blocking;
 use anyhow::Error as AnyHowError;
 use async_graphql::{Context, Error, Upload};
-use image::{GenericImageView, ImageFormat, ImageOutputFormat::Jpeg};
-use sea_orm::{ActiveModelTrait, Set};
+use chrono::Utc;
+use image::{
+    imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat::Jpeg,
+};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter,
+    QuerySelect, Set,
+};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use entities::uploaded_file::{ActiveModel, Entity, Model};
+use entities::uploaded_file::{ActiveModel, Column, Entity, Model};
 
 use crate::common::{InternalCause, ServiceError, SOMETHING_WENT_WRONG};
+use crate::dtos::{objects::StorageUsage, FileKind, ImageSize, Ratio};
 use crate::helpers::AccessUser;
-use crate::providers::Database;
-use crate::{dtos::ratio::Ratio, providers::ObjectStorage};
+use crate::providers::ObjectStorage;
+use crate::providers::{Cache, Database, Metrics};
+
+use super::helpers::{sign_url, verify_url_signature};
+
+const SIGNED_URL: &str = "signed_url";
+const SIGNED_URL_EXPIRATION: u64 = 900;
+const SIGNED_URL_MARGIN: u64 = 60;
+const DEFAULT_DOCUMENT_MAX_SIZE: u64 = 5 * 1024 * 1024;
+const DEFAULT_DOCUMENT_EXTENSION: &str = "bin";
+/// Smaller payloads can not be a valid image or document worth storing.
+const MIN_UPLOAD_SIZE: u64 = 100;
+const STORAGE_QUOTA_EXCEEDED: &str = "Storage quota exceeded";
+const MIN_IMAGE_WIDTH: i64 = 32;
+const MAX_IMAGE_WIDTH: i64 = 1024;
+const INVALID_IMAGE_WIDTH: &str = "Width must be between 32 and 1024 pixels";
+const RESIZED_IMAGE_SUFFIX: &str = "_w";
+
+pub const IMAGES_PATH: &str = "/api/images";
+
+struct DocumentType {
+    content_type: &'static str,
+    extensions: &'static [&'static str],
+    max_size: u64,
+}
+
+// Known types pin their extensions, so a renamed file can not pass as another type
+const DOCUMENT_TYPES: [DocumentType; 2] = [
+    DocumentType {
+        content_type: "application/pdf",
+        extensions: &["pdf"],
+        max_size: 10 * 1024 * 1024,
+    },
+    DocumentType {
+        content_type: "text/plain",
+        extensions: &["txt", "text", "md", "csv", "log"],
+        max_size: 1024 * 1024,
+    },
+];
+
+fn find_document_type(content_type: &str) -> Option<&'static DocumentType> {
+    DOCUMENT_TYPES
+        .iter()
+        .find(|document_type| document_type.content_type == content_type)
+}
+
+/// Largest accepted size of a document type in bytes.
+pub fn document_max_size(content_type: &str) -> u64 {
+    find_document_type(content_type)
+        .map(|document_type| document_type.max_size)
+        .unwrap_or(DEFAULT_DOCUMENT_MAX_SIZE)
+}
+
+/// Keeps the extension of the original file name unless it contradicts a known type.
+pub fn document_extension(filename: &str, content_type: &str) -> String {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .filter(|extension| {
+            !extension.is_empty()
+                && extension.len() <= 10
+                && extension.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+
+    match (find_document_type(content_type), extension) {
+        (Some(document_type), Some(extension))
+            if document_type.extensions.contains(&extension.as_str()) =>
+        {
+            extension
+        }
+        (Some(document_type), _) => document_type.extensions[0].to_string(),
+        (None, Some(extension)) => extension,
+        (None, None) => DEFAULT_DOCUMENT_EXTENSION.to_string(),
+    }
+}
+
+/// Strips parameters such as the charset and checks the type against the allow-list.
+pub fn document_content_type(
+    content_type: Option<&str>,
+    allowed_types: &[String],
+) -> Result<String, ServiceError> {
+    let content_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_lowercase())
+        .unwrap_or_default();
+
+    if !allowed_types.contains(&content_type) {
+        return Err(ServiceError::bad_request(
+            "File type is not allowed",
+            Some(InternalCause::new(&format!(
+                "Unsupported document type: {}",
+                content_type
+            ))),
+        ));
+    }
+
+    Ok(content_type)
+}
+
+/// Rejected before anything reaches the object storage.
+pub fn check_upload_size(size: u64) -> Result<(), ServiceError> {
+    match size {
+        0 => Err(ServiceError::bad_request::<AnyHowError>(
+            "File is empty",
+            None,
+        )),
+        size if size < MIN_UPLOAD_SIZE => Err(ServiceError::bad_request(
+            "File is too small",
+            Some(InternalCause::new(&format!(
+                "{} bytes under the {} bytes minimum",
+                size, MIN_UPLOAD_SIZE
+            ))),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Bytes stored by a user, files uploaded before sizes were recorded count as 0.
+pub async fn storage_usage(db: &Database, user_id: i32) -> Result<i64, ServiceError> {
+    let used = Entity::find()
+        .select_only()
+        .column_as(Expr::cust("COALESCE(SUM(size_bytes), 0)::bigint"), "used")
+        .filter(Column::UserId.eq(user_id))
+        .into_tuple::<i64>()
+        .one(db.get_connection())
+        .await?;
+    Ok(used.unwrap_or(0))
+}
+
+pub async fn find_storage_usage(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    user_id: i32,
+) -> Result<StorageUsage, ServiceError> {
+    Ok(StorageUsage {
+        used_bytes: storage_usage(db, user_id).await?,
+        limit_bytes: object_storage.user_quota().map(|quota| quota as i64),
+    })
+}
+
+/// Two uploads racing may both pass, the quota is a soft limit.
+async fn check_storage_quota(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    user_id: i32,
+    size: u64,
+) -> Result<(), ServiceError> {
+    let Some(quota) = object_storage.user_quota() else {
+        return Ok(());
+    };
+    let used = storage_usage(db, user_id).await?.max(0) as u64;
+
+    if used + size > quota {
+        return Err(ServiceError::forbidden(
+            STORAGE_QUOTA_EXCEEDED,
+            Some(InternalCause::new(&format!(
+                "{} bytes used, {} bytes more would cross the {} bytes quota",
+                used, size, quota
+            ))),
+        ));
+    }
+
+    Ok(())
+}
 
 type ImageData = Vec<u8>;
-type ImageId = Uuid;
+pub type Renditions = Vec<(ImageSize, ImageData)>;
 
 fn image_processor(
     ctx: &Context<'_>,
     file: Upload,
     ratio: Ratio,
-) -> Result<(ImageId, ImageData), ServiceError> {
+) -> Result<Renditions, ServiceError> {
     tracing::info!("Processing image...");
     let file_info = file
         .value(ctx)
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    check_upload_size(
+        file_info
+            .size()
+            .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?,
+    )?;
     let file_type = file_info
         .content_type
         .ok_or(ServiceError::internal_server_error(
@@ -43,7 +223,10 @@ fn image_processor(
 
     if !file_type.contains("image") {
         tracing::warn!("File is not an image");
-        return Err(ServiceError::bad_request::<AnyHowError>("File is not an image", None).into());
+        return Err(ServiceError::bad_request::<AnyHowError>(
+            "File is not an image",
+            None,
+        ));
     }
 
     tracing::info!("Loading image data...");
@@ -69,6 +252,14 @@ fn image_processor(
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
     tracing::info!("Successfully loaded image data of type: {}", file_type);
 
+    process_image(image_control, ratio)
+}
+
+/// Crops the image to the given ratio and encodes one JPEG per [`ImageSize`] in a single pass.
+pub fn process_image(
+    image_control: DynamicImage,
+    ratio: Ratio,
+) -> Result<Renditions, ServiceError> {
     tracing::info!("Cropping image...");
     let (width, height) = image_control.dimensions();
     let cropped_image = match ratio {
@@ -101,14 +292,210 @@ fn image_processor(
     };
     tracing::info!("Successfully cropped image");
 
-    tracing::info!("Compressing image...");
-    let mut compressed_buffer = Cursor::new(Vec::<u8>::new());
-    cropped_image
-        .write_to(&mut compressed_buffer, Jpeg(75))
+    tracing::info!("Compressing image renditions...");
+    let (cropped_width, cropped_height) = cropped_image.dimensions();
+    let mut renditions = Renditions::with_capacity(ImageSize::RENDITIONS.len());
+    for size in ImageSize::RENDITIONS {
+        let mut compressed_buffer = Cursor::new(Vec::<u8>::new());
+        match size.dimension() {
+            // Never upscale, small sources keep their cropped size
+            Some(dimension) if dimension < min(cropped_width, cropped_height) => cropped_image
+                .resize_exact(dimension, dimension, FilterType::Lanczos3)
+                .write_to(&mut compressed_buffer, Jpeg(75)),
+            _ => cropped_image.write_to(&mut compressed_buffer, Jpeg(75)),
+        }
         .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
-    tracing::info!("Successfully compressed image");
+        renditions.push((size, compressed_buffer.into_inner()));
+    }
+    tracing::info!("Successfully compressed image renditions");
+
+    Ok(renditions)
+}
+
+async fn delete_locations(
+    object_storage: &ObjectStorage,
+    locations: &[String],
+) -> Result<(), ServiceError> {
+    for location in locations {
+        object_storage
+            .delete_file(&object_storage.get_file_key(location))
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn content_hash(renditions: &Renditions) -> Option<String> {
+    renditions
+        .iter()
+        .find(|(size, _)| *size == ImageSize::Original)
+        .map(|(_, image_data)| format!("{:x}", Sha256::digest(image_data)))
+}
+
+/// Uploads every rendition under the same key and records them in a single row,
+/// removing the already uploaded objects if any step fails. With deduplication the key
+/// is the hash of the original rendition and re-uploads return the user's existing row.
+pub async fn store_image(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    metrics: &Metrics,
+    user_id: i32,
+    renditions: Renditions,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("uploader_service::store_image", %user_id);
+    let image_id = Uuid::new_v4();
+    let content_hash = content_hash(&renditions);
+    let deduplicated_hash = content_hash
+        .as_ref()
+        .filter(|_| object_storage.deduplicates());
+
+    if let Some(hash) = deduplicated_hash {
+        if let Some(existing) = Entity::find_by_content_hash(user_id, hash)
+            .one(db.get_connection())
+            .await?
+        {
+            tracing::info!("Image already stored as {}", existing.id);
+            return Ok(existing);
+        }
+    }
+
+    let size_bytes = renditions
+        .iter()
+        .map(|(_, image_data)| image_data.len() as u64)
+        .sum::<u64>();
+    check_storage_quota(db, object_storage, user_id, size_bytes).await?;
+    let file_key = match deduplicated_hash {
+        Some(hash) => hash.clone(),
+        None => image_id.to_string(),
+    };
+    let start = Instant::now();
+    let mut url = None;
+    let mut sizes = HashMap::<ImageSize, String>::new();
+    let mut uploaded = Vec::<String>::with_capacity(renditions.len());
+
+    for (size, image_data) in renditions {
+        let result = match size.suffix() {
+            Some(suffix) => {
+                object_storage
+                    .upload_file_rendition(
+                        user_id,
+                        &file_key,
+                        &suffix,
+                        "jpg",
+                        "image/jpeg",
+                        image_data,
+                    )
+                    .await
+            }
+            None => {
+                object_storage
+                    .upload_file(user_id, &file_key, "jpg", "image/jpeg", image_data)
+                    .await
+            }
+        };
+        let stored_object = match result {
+            Ok(stored_object) => stored_object,
+            Err(e) => {
+                tracing::error!("Failed to upload {:?} rendition", size);
+                delete_locations(object_storage, &uploaded).await?;
+                return Err(e);
+            }
+        };
+        uploaded.push(stored_object.url.clone());
+
+        match size {
+            ImageSize::Original => url = Some(stored_object.url),
+            _ => {
+                sizes.insert(size, stored_object.url);
+            }
+        }
+    }
+    metrics.observe_object_storage_upload(start.elapsed().as_secs_f64());
+
+    let url = match url {
+        Some(url) => url,
+        None => {
+            delete_locations(object_storage, &uploaded).await?;
+            return Err(ServiceError::internal_server_error(
+                SOMETHING_WENT_WRONG,
+                Some(InternalCause::new("Missing original image rendition")),
+            ));
+        }
+    };
+    let sizes = serde_json::to_value(sizes)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let uploaded_file = ActiveModel {
+        id: Set(image_id),
+        user_id: Set(user_id),
+        url: Set(url),
+        extension: Set("jpg".to_string()),
+        sizes: Set(Some(sizes)),
+        content_hash: Set(deduplicated_hash.cloned()),
+        size_bytes: Set(size_bytes as i64),
+        ..Default::default()
+    }
+    .insert(db.get_connection())
+    .await;
+
+    match uploaded_file {
+        Ok(uploaded_file) => Ok(uploaded_file),
+        Err(e) => {
+            delete_locations(object_storage, &uploaded).await?;
+            Err(ServiceError::internal_server_error(
+                SOMETHING_WENT_WRONG,
+                Some(e),
+            ))
+        }
+    }
+}
+
+/// Removes the original object and every resized rendition of an uploaded file. Objects
+/// stored under a content hash are kept while another row of the user still points at them.
+pub async fn delete_file_objects(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    file: &Model,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("uploader_service::delete_file_objects", id = %file.id);
+
+    if let Some(hash) = &file.content_hash {
+        let shared = Entity::find_by_content_hash(file.user_id, hash)
+            .filter(Column::Id.ne(file.id))
+            .count(db.get_connection())
+            .await?;
+
+        if shared > 0 {
+            tracing::info!("Keeping objects shared with {} other files", shared);
+            return Ok(());
+        }
+    }
+
+    delete_locations(object_storage, &file_locations(file)).await?;
+    let prefix = format!(
+        "{}{}",
+        file_key_stem(&object_storage.get_file_key(&file.url)),
+        RESIZED_IMAGE_SUFFIX
+    );
 
-    Ok((Uuid::new_v4(), compressed_buffer.into_inner()))
+    for object in object_storage.list_files(&prefix).await? {
+        object_storage.delete_file(&object.key).await?;
+    }
+
+    Ok(())
+}
+
+/// The original location followed by every rendition.
+pub fn file_locations(file: &Model) -> Vec<String> {
+    let mut locations = vec![file.url.clone()];
+    locations.extend(file_sizes(file).into_values());
+    locations
+}
+
+pub fn file_sizes(file: &Model) -> HashMap<ImageSize, String> {
+    file.sizes
+        .as_ref()
+        .and_then(|sizes| serde_json::from_value(sizes.clone()).ok())
+        .unwrap_or_default()
 }
 
 pub async fn upload_image(
@@ -137,20 +524,87 @@ pub async fn upload_image(
         Some(db) => db,
         None => ctx.data::<Database>()?,
     };
-    let (image_id, image_data) = image_processor(ctx, file, ratio)?;
-    let url = object_storage
-        .upload_file(user_id, &image_id, "jpg", image_data)
+    let renditions = image_processor(ctx, file, ratio)?;
+    Ok(store_image(
+        db,
+        object_storage,
+        ctx.data::<Metrics>()?,
+        user_id,
+        renditions,
+    )
+    .await?)
+}
+
+/// Stores a document as uploaded, without the image processing pipeline.
+pub async fn upload_document(ctx: &Context<'_>, file: Upload) -> Result<Model, Error> {
+    tracing::info_span!("uploader_service::upload_document");
+    let user_id = ctx
+        .data::<Option<AccessUser>>()?
+        .as_ref()
+        .ok_or_else(|| Error::new("Unauthorized"))?
+        .id;
+    let object_storage = ctx.data::<ObjectStorage>()?;
+    let db = ctx.data::<Database>()?;
+    let file_info = file
+        .value(ctx)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let content_type = document_content_type(
+        file_info.content_type.as_deref(),
+        object_storage.allowed_types(),
+    )?;
+    let size = file_info
+        .size()
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let max_size = document_max_size(&content_type);
+    check_upload_size(size)?;
+
+    if size > max_size {
+        return Err(ServiceError::bad_request(
+            "File is too large",
+            Some(InternalCause::new(&format!(
+                "{} bytes over the {} bytes limit of {}",
+                size, max_size, content_type
+            ))),
+        )
+        .into());
+    }
+
+    check_storage_quota(db, object_storage, user_id, size).await?;
+    let file_id = Uuid::new_v4();
+    let extension = document_extension(&file_info.filename, &content_type);
+    let start = Instant::now();
+    let stored_object = object_storage
+        .upload_file_stream(
+            user_id,
+            &file_id,
+            &extension,
+            &content_type,
+            size as usize,
+            file_info.into_read(),
+        )
         .await?;
+    ctx.data::<Metrics>()?
+        .observe_object_storage_upload(start.elapsed().as_secs_f64());
+
     let uploaded_file = ActiveModel {
-        id: Set(image_id),
+        id: Set(file_id),
         user_id: Set(user_id),
-        url: Set(url),
-        extension: Set("jpg".to_string()),
+        url: Set(stored_object.url.clone()),
+        extension: Set(extension),
+        sizes: Set(None),
+        size_bytes: Set(size as i64),
         ..Default::default()
     }
     .insert(db.get_connection())
-    .await?;
-    Ok(uploaded_file)
+    .await;
+
+    match uploaded_file {
+        Ok(uploaded_file) => Ok(uploaded_file),
+        Err(e) => {
+            delete_locations(object_storage, &[stored_object.url]).await?;
+            Err(ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)).into())
+        }
+    }
 }
 
 pub async fn find_one_by_id(db: &Database, id: &str) -> Result<Model, ServiceError> {
@@ -170,3 +624,196 @@ pub async fn find_one_by_id(db: &Database, id: &str) -> Result<Model, ServiceErr
         None,
     ))
 }
+
+pub async fn get_file_url(
+    cache: &Cache,
+    object_storage: &ObjectStorage,
+    location: &str,
+) -> Result<String, ServiceError> {
+    if object_storage.is_public() || location.starts_with("http") {
+        return Ok(location.to_string());
+    }
+
+    let key = format!("{}:{}", SIGNED_URL, location);
+
+    if let Some(url) = cache.get_json::<String>(&key).await? {
+        return Ok(url);
+    }
+
+    tracing::info!("Signing url for file {}", location);
+    let url = object_storage.get_signed_url(location, Duration::from_secs(SIGNED_URL_EXPIRATION));
+    // Expire the memoized url before the signature does, leaving room for clock skew
+    cache
+        .set_json(&key, &url, SIGNED_URL_EXPIRATION - SIGNED_URL_MARGIN)
+        .await?;
+    Ok(url)
+}
+
+/// Snaps a requested width to the nearest power of two, so few variants of an image
+/// are ever stored. Ties round up.
+pub fn snap_image_width(width: i64) -> Result<u32, ServiceError> {
+    if !(MIN_IMAGE_WIDTH..=MAX_IMAGE_WIDTH).contains(&width) {
+        return Err(ServiceError::bad_request::<AnyHowError>(
+            INVALID_IMAGE_WIDTH,
+            None,
+        ));
+    }
+
+    let width = width as u32;
+    let lower = 1 << (u32::BITS - 1 - width.leading_zeros());
+    let upper = lower << 1;
+
+    if width - lower < upper - width {
+        return Ok(lower);
+    }
+
+    Ok(upper)
+}
+
+fn file_key_stem(key: &str) -> &str {
+    match key.rsplit_once('.') {
+        Some((stem, _)) if !stem.ends_with('/') => stem,
+        _ => key,
+    }
+}
+
+/// Resized variants live next to the original, e.g. `<key>_w128.jpg`.
+pub fn resized_image_key(original_key: &str, width: u32) -> String {
+    format!(
+        "{}{}{}.jpg",
+        file_key_stem(original_key),
+        RESIZED_IMAGE_SUFFIX,
+        width
+    )
+}
+
+/// The original key stem a resized variant was derived from, `None` for other keys.
+pub fn resized_image_stem(key: &str) -> Option<&str> {
+    let (stem, width) = key
+        .strip_suffix(".jpg")?
+        .rsplit_once(RESIZED_IMAGE_SUFFIX)?;
+
+    if width.is_empty() || !width.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(stem)
+}
+
+pub fn original_key_stem(object_storage: &ObjectStorage, file: &Model) -> String {
+    file_key_stem(&object_storage.get_file_key(&file.url)).to_string()
+}
+
+fn image_signature_message(id: &Uuid, width: u32, exp: i64) -> String {
+    format!("{}:{}:{}:{}", IMAGES_PATH, id, width, exp)
+}
+
+/// Private buckets get a URL signed for as long as their presigned ones, so it works
+/// in an `img` tag without giving the image away for good.
+pub fn resized_image_url(object_storage: &ObjectStorage, id: &Uuid, width: u32) -> String {
+    let url = format!(
+        "{}{}/{}?w={}",
+        object_storage.get_backend_url(),
+        IMAGES_PATH,
+        id,
+        width
+    );
+
+    if object_storage.is_public() {
+        return url;
+    }
+
+    let exp = Utc::now().timestamp() + SIGNED_URL_EXPIRATION as i64;
+    let sig = sign_url(&image_signature_message(id, width, exp));
+    format!("{}&exp={}&sig={}", url, exp, sig)
+}
+
+/// Who may see a resized image from a private bucket: the owner or a signed URL.
+pub struct ImageAccess {
+    pub viewer_id: Option<i32>,
+    pub exp: Option<i64>,
+    pub sig: Option<String>,
+}
+
+impl ImageAccess {
+    fn allows(&self, file: &Model, width: u32) -> bool {
+        if self.viewer_id == Some(file.user_id) {
+            return true;
+        }
+
+        match (self.exp, self.sig.as_deref()) {
+            (Some(exp), Some(sig)) => {
+                exp >= Utc::now().timestamp()
+                    && verify_url_signature(&image_signature_message(&file.id, width, exp), sig)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keeps the aspect ratio and never upscales, narrower images are only re-encoded.
+fn resize_image(image_data: &[u8], width: u32) -> Result<ImageData, ServiceError> {
+    let image_control = image::load_from_memory(image_data)
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    let resized_image = if width < image_control.width() {
+        image_control.resize(width, u32::MAX, FilterType::Lanczos3)
+    } else {
+        image_control
+    };
+    let mut compressed_buffer = Cursor::new(Vec::<u8>::new());
+    resized_image
+        .write_to(&mut compressed_buffer, Jpeg(75))
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
+    Ok(compressed_buffer.into_inner())
+}
+
+/// Serves the stored variant of an image at a snapped width, resizing the original
+/// and storing the result the first time it is asked for.
+pub async fn resized_image(
+    db: &Database,
+    object_storage: &ObjectStorage,
+    id: Uuid,
+    width: u32,
+    access: &ImageAccess,
+) -> Result<ImageData, ServiceError> {
+    tracing::info_span!("uploader_service::resized_image", %id, width);
+    let file = find_one_by_id(db, &id.to_string()).await?;
+
+    if !object_storage.is_public() && !access.allows(&file, width) {
+        tracing::warn!("Unsigned request for a private image");
+        return Err(ServiceError::not_found::<AnyHowError>(
+            "File not found",
+            None,
+        ));
+    }
+
+    if FileKind::from_extension(&file.extension) != FileKind::Image {
+        return Err(ServiceError::not_found::<AnyHowError>(
+            "File not found",
+            None,
+        ));
+    }
+
+    let original_key = object_storage.get_file_key(&file.url);
+    let key = resized_image_key(&original_key, width);
+
+    if let Ok(image_data) = object_storage.get_file(&key).await {
+        return Ok(image_data);
+    }
+
+    tracing::info!("Resizing image to {} pixels wide", width);
+    let original = object_storage.get_file(&original_key).await?;
+    let image_data = spawn_blocking(move || resize_image(&original, width))
+        .await
+        .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))??;
+
+    // A failed write only costs a resize on the next request
+    if let Err(e) = object_storage
+        .upload_derived_file(&key, "image/jpeg", image_data.clone())
+        .await
+    {
+        tracing::warn!("Failed to store resized image {}: {:?}", key, e);
+    }
+
+    Ok(image_data)
+}
@@ -4,43 +4,156 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::{collections::HashSet, future::Future, sync::OnceLock};
+
+use actix_web::web::Bytes;
 use anyhow::Error;
 use async_graphql::{Context, Error as GqlError, Upload};
-use chrono::NaiveDate;
+use async_stream::try_stream;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use entities::user::Column;
+use futures::{Stream, StreamExt};
+use rand::{thread_rng, Rng};
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, IntoActiveModel, ModelTrait, PaginatorTrait,
-    QueryFilter, QuerySelect, Set, TransactionError, TransactionTrait,
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseTransaction, DbErr,
+    EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+    SqlErr, TransactionTrait,
 };
+use serde_json::json;
+use sha2::{Digest, Sha256};
 
-use entities::helpers::GQLQuery;
+use entities::helpers::{decode_filtered_cursor, GQLQuery, PageCursor};
 use entities::{
-    enums::{CursorEnum, OAuthProviderEnum, OrderEnum},
-    oauth_provider,
-    user::{ActiveModel, Entity, Model},
+    enums::{CursorEnum, OAuthProviderEnum, OrderEnum, RoleEnum},
+    oauth_provider, reserved_username, uploaded_file,
+    user::{ActiveModel, Entity, Model, NO_PASSWORD},
 };
 
 use crate::common::{
-    format_name, format_point_slug, ServiceError, INVALID_CREDENTIALS, SOMETHING_WENT_WRONG,
-    UNAUTHORIZED,
+    canonical_locale, format_name, format_point_slug, mask_email, normalize_email,
+    validate_date_of_birth, validate_locale, validate_timezone, validations_handler, CsvReader,
+    InternalCause, ServiceError, ValidatorEnum, DEFAULT_LOCALE, DEFAULT_TIMEZONE,
+    INVALID_CREDENTIALS, SOMETHING_WENT_WRONG, UNAUTHORIZED,
+};
+use crate::dtos::{
+    bodies,
+    objects::{BulkDeleteReport, User},
+    queries::ExportFormat,
+    responses, Ratio,
 };
-use crate::dtos::Ratio;
 use crate::helpers::AccessUser;
-use crate::providers::{Database, ObjectStorage};
+use crate::providers::{
+    Cache, Database, DbSession, Jwt, Mailer, ObjectStorage, SignUpMode, TermsVersion, TokenType,
+    Webhooks, DEFAULT_RESERVED_USERNAMES,
+};
 
-use super::{helpers::hash_password, uploader_service};
+use super::{helpers::hash_password, invitations_service, uploader_service};
 
 const USER_NOT_FOUND: &str = "User not found";
+const VERSION_CONFLICT: &str = "Please retry";
+const USER_CACHE: &str = "user";
+const EXPORT_CHUNK_SIZE: usize = 100;
+const IMPORT_CHUNK_SIZE: usize = 100;
+const BULK_DELETE_MAX_IDS: usize = 500;
+const BULK_DELETE_CHUNK_SIZE: usize = 50;
+/// Not a hash, so no password verifies against it.
+const UNUSABLE_PASSWORD: &str = "!imported";
+const USERNAME_CHANGE_COOLDOWN_DAYS: i64 = 30;
+const DELETED_USER_GRACE_DAYS: i64 = 30;
+const SEARCH_THRESHOLD: f32 = 0.3;
+const USERNAME_ATTEMPTS: u32 = 5;
+const USERNAME_INDEX: &str = "user_username_idx";
+const USERNAME_UNAVAILABLE: &str = "Username unavailable";
+const USER_ALREADY_EXISTS: &str = "User already exists";
+/// Appended to derived usernames that are reserved, "support.user" instead of "support".
+const RESERVED_FALLBACK_SUFFIX: &str = "user";
+static RESERVED_USERNAMES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Sets the usernames from `Config::reserved_usernames` once, at startup before any
+/// username is checked. Without it the defaults apply.
+pub fn set_reserved_usernames(usernames: &[String]) {
+    if RESERVED_USERNAMES.set(usernames.to_vec()).is_err() {
+        tracing::debug!("Reserved usernames are already set");
+    }
+}
+
+/// Usernames nobody can claim, on top of the ones admins reserve.
+fn get_reserved_usernames() -> &'static [String] {
+    RESERVED_USERNAMES.get_or_init(|| DEFAULT_RESERVED_USERNAMES.map(str::to_string).to_vec())
+}
 
 fn get_full_name(first_name: &str, last_name: &str) -> String {
     format!("{} {}", first_name, last_name)
 }
 
-async fn create_username(db: &Database, full_name: String) -> Result<String, ServiceError> {
-    let point_slug = format_point_slug(&full_name);
+/// The username without its `.<number>` suffix, "admin.2" comes from "admin".
+fn username_base(username: &str) -> &str {
+    match username.rsplit_once('.') {
+        Some((base, suffix))
+            if !base.is_empty()
+                && !suffix.is_empty()
+                && suffix.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => username,
+    }
+}
+
+/// Reserved names are blocked with any numbered suffix, in any case.
+async fn is_username_reserved<C: ConnectionTrait>(
+    conn: &C,
+    username: &str,
+) -> Result<bool, ServiceError> {
+    let username = username.to_lowercase();
+    let base = username_base(&username);
+
+    if get_reserved_usernames()
+        .iter()
+        .any(|reserved| reserved == base || *reserved == username)
+    {
+        return Ok(true);
+    }
+
+    let count = reserved_username::Entity::find()
+        .filter(reserved_username::Column::Username.is_in([base, username.as_str()]))
+        .count(conn)
+        .await?;
+    Ok(count > 0)
+}
+
+/// The slug usernames are derived from, moved off a reserved name.
+async fn derived_slug<C: ConnectionTrait>(
+    conn: &C,
+    full_name: &str,
+) -> Result<String, ServiceError> {
+    let point_slug = format_point_slug(full_name);
+
+    if !is_username_reserved(conn, &point_slug).await? {
+        return Ok(point_slug);
+    }
+
+    let point_slug = format!("{}.{}", point_slug, RESERVED_FALLBACK_SUFFIX);
+    if is_username_reserved(conn, &point_slug).await? {
+        return Err(ServiceError::conflict::<Error>(USERNAME_UNAVAILABLE, None));
+    }
+
+    Ok(point_slug)
+}
+
+/// Only `slug` and `slug.<suffix>` count, so "john.smithson" does not bump "john.smith".
+async fn create_username<C: ConnectionTrait>(
+    conn: &C,
+    full_name: &str,
+) -> Result<String, ServiceError> {
+    let point_slug = derived_slug(conn, full_name).await?;
     let count = Entity::find()
-        .filter(Column::Username.like(format!("{}%", point_slug)))
-        .count(db.get_connection())
+        .filter(
+            Condition::any()
+                .add(Column::Username.eq(&point_slug))
+                .add(Column::Username.like(format!("{}.%", point_slug))),
+        )
+        .count(conn)
         .await?;
 
     if count > 0 {
@@ -50,87 +163,503 @@ async fn create_username(db: &Database, full_name: String) -> Result<String, Ser
     Ok(point_slug)
 }
 
+/// Parses a `YYYY-MM-DD` date of birth, rejecting future, absurd and under age dates.
+fn parse_date_of_birth(date_of_birth: &str) -> Result<NaiveDate, ServiceError> {
+    if let ValidatorEnum::Invalid(message) = validate_date_of_birth(date_of_birth) {
+        return Err(ServiceError::bad_request::<Error>(&message, None));
+    }
+
+    NaiveDate::parse_from_str(date_of_birth, "%Y-%m-%d")
+        .map_err(|e| ServiceError::bad_request("Could not parse date", Some(e)))
+}
+
+/// Fallback once the counted username was taken by a concurrent insert, keeping its base
+/// so a reserved name stays avoided.
+fn random_username(username: &str) -> String {
+    format!(
+        "{}.{}",
+        username_base(username),
+        thread_rng().gen_range(1000..10000)
+    )
+}
+
+fn is_username_taken(error: &DbErr) -> bool {
+    matches!(
+        error.sql_err(),
+        Some(SqlErr::UniqueConstraintViolation(message)) if message.contains(USERNAME_INDEX)
+    )
+}
+
+pub fn version_conflict() -> ServiceError {
+    ServiceError::conflict(
+        VERSION_CONFLICT,
+        Some(InternalCause::new("User was updated since it was read")),
+    )
+}
+
+/// Applies the changes only while the user is still as it was read, so concurrent
+/// writers never silently overwrite each other. Only credential and security changes
+/// move the version, `None` means another writer got there first.
+pub async fn update_versioned<C: ConnectionTrait>(
+    conn: &C,
+    user: &Model,
+    mut changes: ActiveModel,
+) -> Result<Option<Model>, DbErr> {
+    // Bulk updates skip the active model hooks
+    changes.updated_at = Set(Utc::now().naive_utc());
+    let users = Entity::update_many()
+        .set(changes)
+        .filter(Column::Id.eq(user.id))
+        .filter(Column::Version.eq(user.version))
+        .filter(Column::UpdatedAt.eq(user.updated_at))
+        .exec_with_returning(conn)
+        .await?;
+    Ok(users.into_iter().next())
+}
+
+/// The version `step` ahead of `version`, an overflow is an error instead of a wrap.
+pub fn next_version(version: i32, step: i32) -> Result<i32, ServiceError> {
+    version.checked_add(step).ok_or_else(|| {
+        ServiceError::internal_server_error(
+            SOMETHING_WENT_WRONG,
+            Some(InternalCause::new("User version overflowed")),
+        )
+    })
+}
+
+/// Credential changes move the version and revoke every token issued before it.
+pub fn revoke_tokens(changes: &mut ActiveModel, version: i32) {
+    changes.version = Set(version);
+    changes.min_token_version = Set(version);
+}
+
+/// Profile changes are safe to reapply, so a conflict is retried once on a fresh read.
+async fn update_profile<F, Fut>(
+    db: &DbSession<'_>,
+    user: Model,
+    apply: F,
+) -> Result<Model, ServiceError>
+where
+    F: Fn(Model) -> Fut,
+    Fut: Future<Output = Result<ActiveModel, ServiceError>>,
+{
+    let id = user.id;
+
+    if let Some(user) = update_versioned(db, &user, apply(user.clone()).await?).await? {
+        return Ok(user);
+    }
+
+    tracing::info!("User with id {} changed concurrently, retrying", id);
+    let user = find_one_by_id(db, id).await?;
+    update_versioned(db, &user, apply(user.clone()).await?)
+        .await?
+        .ok_or_else(version_conflict)
+}
+
+/// Locale and timezone a user renders dates and receives emails with.
+#[derive(Debug, Clone)]
+pub struct UserPreferences {
+    pub locale: String,
+    pub timezone: String,
+}
+
+impl Default for UserPreferences {
+    fn default() -> Self {
+        Self {
+            locale: DEFAULT_LOCALE.to_string(),
+            timezone: DEFAULT_TIMEZONE.to_string(),
+        }
+    }
+}
+
+impl UserPreferences {
+    /// Validates both values, resolving the locale to its whitelisted casing.
+    pub fn canonical(self) -> Result<Self, ServiceError> {
+        validations_handler(&[
+            validate_locale(&self.locale),
+            validate_timezone(&self.timezone),
+        ])?;
+        Ok(Self {
+            locale: canonical_locale(&self.locale)
+                .unwrap_or(DEFAULT_LOCALE)
+                .to_string(),
+            timezone: self.timezone,
+        })
+    }
+}
+
 // TODO: add traces to all pub fn
 
 // add user name
+/// Inserts and commits the user on its own, sign ups go through `insert_user` to share
+/// their transaction. Only tests seed users this way.
+#[cfg(test)]
+#[allow(clippy::too_many_arguments)]
 pub async fn create_user(
-    db: &Database,
+    db: &DbSession<'_>,
+    webhooks: &Webhooks,
     first_name: String,
     last_name: String,
-    date_of_birth: String,
+    date_of_birth: Option<String>,
+    email: String,
+    password: String,
+    provider: OAuthProviderEnum,
+    preferences: UserPreferences,
+    terms_version: Option<String>,
+) -> Result<Model, ServiceError> {
+    let txn = db.begin().await?;
+    let user = insert_user(
+        db,
+        &txn,
+        first_name,
+        last_name,
+        date_of_birth,
+        email,
+        password,
+        provider,
+        preferences,
+        terms_version,
+    )
+    .await?;
+    txn.commit().await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::UserRegistered,
+        &user,
+    ));
+    Ok(user)
+}
+
+/// Inserts the user under a username derived from its name, retrying with a random
+/// suffix when a concurrent insert takes it first.
+async fn insert_with_username(
+    txn: &DatabaseTransaction,
+    mut new_user: ActiveModel,
+    full_name: &str,
+) -> Result<Model, ServiceError> {
+    let mut username = create_username(txn, full_name).await?;
+    let mut attempt = 1;
+
+    loop {
+        new_user.username = Set(username.clone());
+        // The savepoint keeps the transaction usable after a unique violation
+        let savepoint = txn.begin().await?;
+
+        match new_user.clone().insert(&savepoint).await {
+            Ok(user) => {
+                savepoint.commit().await?;
+                return Ok(user);
+            }
+            Err(e) if is_username_taken(&e) && attempt < USERNAME_ATTEMPTS => {
+                savepoint.rollback().await?;
+                tracing::info!("Username {} already taken, retrying", username);
+                username = random_username(&username);
+                attempt += 1;
+            }
+            Err(e) if is_username_taken(&e) => {
+                return Err(ServiceError::conflict(
+                    "Could not generate a unique username, please try again",
+                    Some(e),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The 409 already tells that the email is taken, the details only add how the account
+/// signs in so the client can offer the right way back. Checking the password first is
+/// not possible for accounts without one, so this is accepted, ids and names never leave.
+async fn existing_user_conflict(
+    db: &DbSession<'_>,
+    existing: &Model,
+) -> Result<ServiceError, ServiceError> {
+    if !existing.confirmed {
+        return Ok(ServiceError::conflict_with_details(
+            USER_ALREADY_EXISTS,
+            json!({ "reason": "exists_unconfirmed" }),
+        ));
+    }
+    if existing.has_password() {
+        return Ok(ServiceError::conflict_with_details(
+            USER_ALREADY_EXISTS,
+            json!({ "reason": "exists" }),
+        ));
+    }
+
+    let providers = oauth_provider::Entity::find_by_email(&existing.email)
+        .all(db)
+        .await?
+        .into_iter()
+        .filter(|provider| provider.provider != OAuthProviderEnum::Local)
+        .map(|provider| provider.provider.to_str())
+        .collect::<Vec<_>>();
+    // Imported accounts have neither a password nor a provider yet
+    let details = if providers.is_empty() {
+        json!({ "reason": "exists" })
+    } else {
+        json!({ "reason": "exists_oauth", "providers": providers })
+    };
+    Ok(ServiceError::conflict_with_details(
+        USER_ALREADY_EXISTS,
+        details,
+    ))
+}
+
+/// Validates and inserts the user and its OAuth provider inside `txn`, leaving the
+/// commit to the caller so follow-up writes (e.g. queued emails) share it. The terms
+/// version is the one the user accepted, if any.
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_user(
+    db: &DbSession<'_>,
+    txn: &DatabaseTransaction,
+    first_name: String,
+    last_name: String,
+    date_of_birth: Option<String>,
     email: String,
     mut password: String,
     provider: OAuthProviderEnum,
+    preferences: UserPreferences,
+    terms_version: Option<String>,
 ) -> Result<Model, ServiceError> {
-    tracing::info_span!("users_service::create_user", %first_name);
-    let email = email.to_lowercase();
+    tracing::info_span!("users_service::insert_user", %first_name);
+    let email = email.trim().to_lowercase();
+    let normalized_email = normalize_email(&email);
+    let preferences = preferences.canonical()?;
     let first_name = format_name(&first_name)?;
     let last_name = format_name(&last_name)?;
+    // Providers that do not share a date of birth (e.g. GitHub) cannot be checked
+    let date_of_birth = date_of_birth
+        .as_deref()
+        .map(parse_date_of_birth)
+        .transpose()?;
 
-    if provider == OAuthProviderEnum::Local {
-        let count = Entity::find_by_email(&email)
-            .count(db.get_connection())
-            .await?;
+    // Aliases of an existing address reach the same inbox, so they conflict as well
+    let existing = Entity::find_by_normalized_email_with_deleted(&normalized_email)
+        .one(db)
+        .await?;
 
-        if count > 0 {
-            return Err(ServiceError::conflict::<Error>("User already exists", None));
-        }
+    if let Some(existing) = existing {
+        return Err(existing_user_conflict(db, &existing).await?);
+    }
 
+    if provider == OAuthProviderEnum::Local {
         password = hash_password(&password)
             .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e)))?;
     }
 
-    let date_of_birth = NaiveDate::parse_from_str(&date_of_birth, "%Y-%m-%d")
-        .map_err(|e| ServiceError::bad_request("Could not parse date", Some(e)))?;
-    let username = create_username(db, get_full_name(&first_name, &last_name)).await?;
-    let user = db
-        .get_connection()
-        .transaction::<_, Model, DbErr>(|txn| {
-            Box::pin(async move {
-                tracing::info!("Creating user...");
-                let user = ActiveModel {
-                    email: Set(email.clone()),
-                    first_name: Set(first_name),
-                    last_name: Set(last_name),
-                    username: Set(username),
-                    password: Set(password),
-                    date_of_birth: Set(date_of_birth),
-                    confirmed: Set(provider != OAuthProviderEnum::Local),
-                    ..Default::default()
-                }
-                .insert(txn)
-                .await?;
-                tracing::info!("User created");
-                tracing::info!("Creating OAuth provider...");
-                oauth_provider::ActiveModel {
-                    user_email: Set(email),
-                    provider: Set(provider),
-                    two_factor: Set(provider == OAuthProviderEnum::Local),
-                    ..Default::default()
-                }
-                .insert(txn)
-                .await?;
-                tracing::info!("OAuth provider created");
-                Ok(user)
-            })
-        })
-        .await
-        .map_err(|e| match e {
-            TransactionError::Connection(e) => e,
-            TransactionError::Transaction(e) => e,
-        })?;
+    let full_name = get_full_name(&first_name, &last_name);
+    let new_user = ActiveModel {
+        email: Set(email.clone()),
+        normalized_email: Set(normalized_email),
+        first_name: Set(first_name),
+        last_name: Set(last_name),
+        password: Set(password),
+        date_of_birth: Set(date_of_birth),
+        confirmed: Set(provider != OAuthProviderEnum::Local),
+        preferred_locale: Set(preferences.locale),
+        timezone: Set(preferences.timezone),
+        terms_version: Set(terms_version),
+        two_factor: Set(provider == OAuthProviderEnum::Local),
+        registration_provider: Set(provider),
+        ..Default::default()
+    };
+    tracing::info!("Creating user...");
+    let user = insert_with_username(txn, new_user, &full_name).await?;
+    tracing::info!("User created");
+    tracing::info!("Creating OAuth provider...");
+    oauth_provider::ActiveModel {
+        provider_email: Set((provider != OAuthProviderEnum::Local).then(|| email.clone())),
+        user_email: Set(email),
+        provider: Set(provider),
+        two_factor: Set(provider == OAuthProviderEnum::Local),
+        ..Default::default()
+    }
+    .insert(txn)
+    .await?;
+    tracing::info!("OAuth provider created");
     tracing::trace_span!("Successfully created user", id=%user.id);
     Ok(user)
 }
 
+/// Creates a confirmed local user without a usable password, they set one through a
+/// password reset. Runs inside `txn` so a whole import chunk shares one transaction.
+pub async fn create_imported_user(
+    txn: &DatabaseTransaction,
+    imported: bodies::ImportedUser,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::create_imported_user");
+    let email = imported.email.trim().to_lowercase();
+    let normalized_email = normalize_email(&email);
+    let first_name = format_name(&imported.first_name)?;
+    let last_name = format_name(&imported.last_name)?;
+    let date_of_birth = imported
+        .date_of_birth
+        .as_deref()
+        .map(parse_date_of_birth)
+        .transpose()?;
+
+    // Counted on the transaction, so earlier rows of the same import conflict as well
+    let count = Entity::find_by_normalized_email_with_deleted(&normalized_email)
+        .count(txn)
+        .await?;
+
+    if count > 0 {
+        return Err(ServiceError::conflict::<Error>(USER_ALREADY_EXISTS, None));
+    }
+
+    let full_name = get_full_name(&first_name, &last_name);
+    let new_user = ActiveModel {
+        email: Set(email.clone()),
+        normalized_email: Set(normalized_email),
+        first_name: Set(first_name),
+        last_name: Set(last_name),
+        password: Set(UNUSABLE_PASSWORD.to_string()),
+        date_of_birth: Set(date_of_birth),
+        role: Set(imported.role),
+        confirmed: Set(true),
+        preferred_locale: Set(DEFAULT_LOCALE.to_string()),
+        timezone: Set(DEFAULT_TIMEZONE.to_string()),
+        two_factor: Set(true),
+        registration_provider: Set(OAuthProviderEnum::Local),
+        ..Default::default()
+    };
+    let user = insert_with_username(txn, new_user, &full_name).await?;
+    oauth_provider::ActiveModel {
+        user_email: Set(email),
+        provider: Set(OAuthProviderEnum::Local),
+        two_factor: Set(true),
+        ..Default::default()
+    }
+    .insert(txn)
+    .await?;
+    Ok(user)
+}
+
+async fn import_chunk(
+    db: &DbSession<'_>,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    rows: Vec<(usize, bodies::ImportedUser)>,
+    send_reset_emails: bool,
+    report: &mut responses::ImportReport,
+) -> Result<(), ServiceError> {
+    let txn = db.begin().await?;
+
+    for (line, imported) in rows {
+        let email = imported.email.clone();
+        // A failing row only rolls back its savepoint, the rest of the chunk is kept
+        let savepoint = txn.begin().await?;
+        let result = async {
+            let user = create_imported_user(&savepoint, imported).await?;
+
+            if send_reset_emails {
+                let reset_token = jwt.generate_email_token(TokenType::Reset, &user)?;
+                mailer
+                    .send_password_reset_email(
+                        &savepoint,
+                        &user.email,
+                        &user.full_name(),
+                        &user.preferred_locale,
+                        &reset_token,
+                    )
+                    .await?;
+            }
+
+            Ok::<(), ServiceError>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                savepoint.commit().await?;
+                report.created += 1;
+            }
+            Err(e) => {
+                savepoint.rollback().await?;
+                report.fail(line, &email, &e);
+            }
+        }
+    }
+
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Imports users from CSV chunks as they arrive, inserting them in transactions of
+/// `IMPORT_CHUNK_SIZE` rows. Invalid and duplicate rows are reported by line instead of
+/// failing the import, only an unusable header or a database failure does.
+pub async fn import_users(
+    db: &DbSession<'_>,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    chunks: impl Stream<Item = Result<Bytes, ServiceError>>,
+    send_reset_emails: bool,
+) -> Result<responses::ImportReport, ServiceError> {
+    tracing::info_span!("users_service::import_users");
+    futures::pin_mut!(chunks);
+    let mut reader = CsvReader::default();
+    let mut columns: Option<bodies::ImportedUserColumns> = None;
+    let mut rows = Vec::with_capacity(IMPORT_CHUNK_SIZE);
+    let mut report = responses::ImportReport::default();
+
+    loop {
+        let (records, done) = match chunks.next().await {
+            Some(chunk) => (reader.push(&chunk?), false),
+            None => (
+                std::mem::take(&mut reader).finish().into_iter().collect(),
+                true,
+            ),
+        };
+
+        for record in records {
+            let Some(header) = &columns else {
+                columns = Some(bodies::ImportedUserColumns::from_header(&record.fields)?);
+                continue;
+            };
+
+            match header.read(&record.fields) {
+                Ok(imported) => rows.push((record.line, imported)),
+                Err(e) => report.fail(record.line, header.email(&record.fields), &e),
+            }
+
+            if rows.len() >= IMPORT_CHUNK_SIZE {
+                let chunk = std::mem::replace(&mut rows, Vec::with_capacity(IMPORT_CHUNK_SIZE));
+                import_chunk(db, jwt, mailer, chunk, send_reset_emails, &mut report).await?;
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    if columns.is_none() {
+        return Err(ServiceError::bad_request::<Error>(
+            "The CSV file is empty",
+            None,
+        ));
+    }
+    if !rows.is_empty() {
+        import_chunk(db, jwt, mailer, rows, send_reset_emails, &mut report).await?;
+    }
+
+    tracing::info!(
+        "Imported {} users, {} rows failed",
+        report.created,
+        report.failed.len()
+    );
+    Ok(report)
+}
+
 pub async fn find_or_create_oauth_provider(
-    db: &Database,
+    db: &DbSession<'_>,
     email: &str,
     provider: OAuthProviderEnum,
+    provider_email: &str,
 ) -> Result<(), ServiceError> {
     tracing::info_span!("users_service::find_or_create_oauth_provider");
     let count = oauth_provider::Entity::find_by_email_and_provider(email, provider)
-        .count(db.get_connection())
+        .count(db)
         .await?;
 
     if count == 0 {
@@ -139,54 +668,146 @@ pub async fn find_or_create_oauth_provider(
         oauth_provider::ActiveModel {
             user_email: Set(email.to_string()),
             provider: Set(provider),
+            provider_email: Set(Some(provider_email.to_string())),
             ..Default::default()
         }
-        .insert(db.get_connection())
+        .insert(db)
         .await?;
     }
 
     Ok(())
 }
 
+/// Users created here get the current terms version recorded, signing in through the
+/// provider counts as accepting them. Invite only sign ups need a pending invitation
+/// for the provider's address.
+#[allow(clippy::too_many_arguments)]
 pub async fn find_or_create(
-    db: &Database,
+    db: &DbSession<'_>,
+    webhooks: &Webhooks,
+    terms_version: &TermsVersion,
+    sign_up_mode: SignUpMode,
     provider: OAuthProviderEnum,
     first_name: String,
     last_name: String,
-    date_of_birth: String,
+    date_of_birth: Option<String>,
     email: String,
 ) -> Result<Model, ServiceError> {
     tracing::info_span!("users_service::find_or_create");
     let formatted_email = email.to_lowercase();
-    let user = Entity::find_by_email(&formatted_email)
-        .one(db.get_connection())
+    // An identity linked from the settings signs into its account whatever the address
+    let linked = oauth_provider::Entity::find_by_provider_email(provider, &formatted_email)
+        .one(db)
         .await?;
 
-    if let Some(model) = user {
-        tracing::info!("User found");
-        find_or_create_oauth_provider(db, &formatted_email, provider).await?;
+    if let Some(link) = linked {
+        if let Some(model) = Entity::find_by_email(&link.user_email).one(db).await? {
+            tracing::info!("Linked user found");
+            return Ok(model);
+        }
+    }
+
+    if let Some(model) = find_one_by_email(db, &formatted_email).await? {
+        find_or_create_oauth_provider(db, &model.email, provider, &formatted_email).await?;
         return Ok(model);
     }
 
     tracing::info!("User not found");
     tracing::info!("Creating user");
-    let user = create_user(
+    let txn = db.begin().await?;
+    // Accepted in the same transaction, a failed sign up leaves the invitation usable
+    if sign_up_mode == SignUpMode::InviteOnly {
+        invitations_service::accept_invitation_for_email(&txn, &formatted_email).await?;
+    }
+    let user = insert_user(
         db,
+        &txn,
         first_name,
         last_name,
         date_of_birth,
         formatted_email,
-        "none".to_string(),
+        NO_PASSWORD.to_string(),
         provider,
+        UserPreferences::default(),
+        terms_version.current().map(str::to_string),
     )
     .await?;
+    txn.commit().await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::UserRegistered,
+        &user,
+    ));
     tracing::info!("New user created");
     Ok(user)
 }
 
-pub async fn find_one_by_id(db: &Database, id: i32) -> Result<Model, ServiceError> {
+/// Connects an external identity to the user even when its address differs from the
+/// user's. An identity that would sign into another account, through a link or its
+/// email, is a conflict.
+pub async fn link_oauth_provider(
+    db: &DbSession<'_>,
+    user_id: i32,
+    provider: OAuthProviderEnum,
+    provider_email: &str,
+) -> Result<oauth_provider::Model, ServiceError> {
+    tracing::info_span!("users_service::link_oauth_provider");
+    let user = find_one_by_id(db, user_id).await?;
+    let provider_email = provider_email.to_lowercase();
+    let linked = oauth_provider::Entity::find_by_provider_email(provider, &provider_email)
+        .one(db)
+        .await?;
+
+    if let Some(link) = linked {
+        if link.user_email != user.email {
+            return Err(ServiceError::conflict::<Error>(
+                "Provider account is linked to another user",
+                None,
+            ));
+        }
+
+        return Ok(link);
+    }
+
+    let owner = Entity::find_by_normalized_email(&normalize_email(&provider_email))
+        .filter(Column::Id.ne(user.id))
+        .count(db)
+        .await?;
+
+    if owner > 0 {
+        return Err(ServiceError::conflict::<Error>(
+            "Provider account is linked to another user",
+            None,
+        ));
+    }
+
+    // Linking another account of the same provider replaces the previous one
+    let existing = oauth_provider::Entity::find_by_email_and_provider(&user.email, provider)
+        .one(db)
+        .await?;
+    let link = match existing {
+        Some(existing) => {
+            let mut link: oauth_provider::ActiveModel = existing.into();
+            link.provider_email = Set(Some(provider_email));
+            link.update(db).await?
+        }
+        None => {
+            oauth_provider::ActiveModel {
+                user_email: Set(user.email),
+                provider: Set(provider),
+                provider_email: Set(Some(provider_email)),
+                ..Default::default()
+            }
+            .insert(db)
+            .await?
+        }
+    };
+    tracing::info!("Linked {} to user with id {}", provider.to_str(), user_id);
+    Ok(link)
+}
+
+pub async fn find_one_by_id(db: &DbSession<'_>, id: i32) -> Result<Model, ServiceError> {
     tracing::info_span!("users_service::find_one_by_id", %id);
-    let user = Entity::find_by_id(id).one(db.get_connection()).await?;
+    let user = Entity::find_by_id(id).one(db).await?;
     match user {
         Some(value) => {
             tracing::info!("User found");
@@ -196,28 +817,88 @@ pub async fn find_one_by_id(db: &Database, id: i32) -> Result<Model, ServiceErro
     }
 }
 
-pub async fn find_one_by_email(db: &Database, email: &str) -> Result<Model, ServiceError> {
+fn get_user_cache_key(id: i32) -> String {
+    format!("{}:{}", USER_CACHE, id)
+}
+
+/// Profiles are cached without credentials, auth paths read the row with `find_one_by_id`.
+pub async fn cached_find_one_by_id(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    id: i32,
+) -> Result<User, ServiceError> {
+    tracing::info_span!("users_service::cached_find_one_by_id", %id);
+    let key = get_user_cache_key(id);
+
+    if let Some(user) = cache.get_json::<User>(&key).await? {
+        tracing::info!("User found in cache");
+        return Ok(user);
+    }
+
+    let user = User::from(find_one_by_id(&db.read_only(), id).await?);
+    cache.set_json(&key, &user, cache.get_ttl()).await?;
+    Ok(user)
+}
+
+/// Rejects tokens issued before the user's last revocation and suspended accounts.
+pub fn check_token_user(user: &Model, version: i32) -> Result<(), ServiceError> {
+    if !user.accepts_token_version(version) {
+        tracing::warn!("Revoked token used by user with id {}", user.id);
+        return Err(ServiceError::unauthorized::<Error>(UNAUTHORIZED, None));
+    }
+    if user.suspended {
+        tracing::warn!("User with id {} suspended", user.id);
+        return Err(ServiceError::forbidden::<Error>(
+            "Your account has been suspended",
+            None,
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn invalidate_cached_user(cache: &Cache, id: i32) -> Result<(), ServiceError> {
+    tracing::info!("Invalidating cached user with id {}", id);
+    cache.del(&get_user_cache_key(id)).await
+}
+
+/// Plain lookup, each caller decides what a missing user means.
+pub async fn find_one_by_email(
+    db: &DbSession<'_>,
+    email: &str,
+) -> Result<Option<Model>, ServiceError> {
     tracing::info_span!("users_service::find_one_by_email");
-    let user = Entity::find_by_email(email)
-        .one(db.get_connection())
+    let user = Entity::find_by_normalized_email(&normalize_email(email))
+        .one(db)
         .await?;
 
-    match user {
-        Some(value) => {
-            tracing::info!("User found");
-            Ok(value)
-        }
-        None => Err(ServiceError::unauthorized::<ServiceError>(
-            INVALID_CREDENTIALS,
-            None,
-        )),
+    if user.is_some() {
+        tracing::info!("User found");
     }
+
+    Ok(user)
 }
 
-pub async fn find_one_by_username(db: &Database, username: &str) -> Result<Model, ServiceError> {
+/// The user together with its local provider, `None` for accounts that only use
+/// social login, in a single query.
+pub async fn find_one_by_email_with_local_provider(
+    db: &DbSession<'_>,
+    email: &str,
+) -> Result<(Model, Option<oauth_provider::Model>), ServiceError> {
+    tracing::info_span!("users_service::find_one_by_email_with_local_provider");
+    Entity::find_by_normalized_email_with_local_provider(&normalize_email(email))
+        .one(db)
+        .await?
+        .ok_or_else(|| ServiceError::unauthorized::<ServiceError>(INVALID_CREDENTIALS, None))
+}
+
+pub async fn find_one_by_username(
+    db: &DbSession<'_>,
+    username: &str,
+) -> Result<Model, ServiceError> {
     tracing::info_span!("users_service::find_one_by_username");
     let user = Entity::find_by_username(username)
-        .one(db.get_connection())
+        .one(&db.read_only())
         .await?;
 
     match user {
@@ -230,13 +911,11 @@ pub async fn find_one_by_username(db: &Database, username: &str) -> Result<Model
 }
 
 pub async fn find_one_by_version(
-    db: &Database,
+    db: &DbSession<'_>,
     id: i32,
-    version: i16,
+    version: i32,
 ) -> Result<Model, ServiceError> {
-    let user = Entity::find_by_version(id, version)
-        .one(db.get_connection())
-        .await?;
+    let user = Entity::find_by_version(id, version).one(db).await?;
 
     match user {
         Some(value) => Ok(value),
@@ -244,36 +923,567 @@ pub async fn find_one_by_version(
     }
 }
 
-pub async fn delete_user(db: &Database, id: i32) -> Result<(), ServiceError> {
+pub async fn update_last_login(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user: Model,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_last_login", id = %user.id);
+    let id = user.id;
+    let user = update_profile(db, user, |user| async move {
+        let mut user = user.into_active_model();
+        user.last_login_at = Set(Some(Utc::now().naive_utc()));
+        Ok(user)
+    })
+    .await?;
+    invalidate_cached_user(cache, id).await?;
+    Ok(user)
+}
+
+pub async fn delete_user(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    webhooks: &Webhooks,
+    id: i32,
+) -> Result<(), ServiceError> {
+    tracing::info_span!("users_service::delete_user", %id);
     let user = find_one_by_id(db, id).await?;
-    let result = user.delete(db.get_connection()).await?;
+    let mut changes = user.clone().into_active_model();
+    changes.deleted_at = Set(Some(Utc::now().naive_utc()));
+    // Every token issued before the deletion is revoked
+    revoke_tokens(&mut changes, next_version(user.version, 1)?);
+    update_versioned(db, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    invalidate_cached_user(cache, id).await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::UserDeleted,
+        &user,
+    ));
+    Ok(())
+}
 
-    if result.rows_affected > 0 {
-        return Ok(());
+pub async fn restore_user(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    id: i32,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::restore_user", %id);
+    let user = Entity::find_deleted_by_id(id)
+        .one(db)
+        .await?
+        .ok_or_else(|| ServiceError::not_found::<Error>(USER_NOT_FOUND, None))?;
+
+    if user.deleted_at < Some(get_purge_threshold()) {
+        tracing::warn!("User grace period has expired");
+        return Err(ServiceError::not_found::<Error>(USER_NOT_FOUND, None));
     }
 
-    Err(ServiceError::internal_server_error::<Error>(
-        SOMETHING_WENT_WRONG,
-        None,
-    ))
+    let mut changes = user.clone().into_active_model();
+    changes.deleted_at = Set(None);
+    let user = update_versioned(db, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    invalidate_cached_user(cache, id).await?;
+    Ok(user)
 }
 
-pub async fn query(
+fn get_purge_threshold() -> NaiveDateTime {
+    Utc::now().naive_utc() - Duration::days(DELETED_USER_GRACE_DAYS)
+}
+
+pub async fn purge_deleted_users(
+    db: &DbSession<'_>,
+    object_storage: &ObjectStorage,
+) -> Result<u64, ServiceError> {
+    tracing::info_span!("users_service::purge_deleted_users");
+    let threshold = get_purge_threshold();
+    let users = Entity::find_deleted_before(threshold).all(db).await?;
+    let mut purged = 0;
+
+    for user in users {
+        let id = user.id;
+
+        match purge_user(db, object_storage, id, Entity::deleted_before(threshold)).await {
+            Ok(true) => purged += 1,
+            Ok(false) => (),
+            Err(e) => tracing::error!("Failed to purge user {}: {:?}", id, e),
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Deletes the user for good with their files, only while the row still matches
+/// `condition`: a user confirmed or restored since it was read is kept and `false`
+/// returned. File rows and sign in methods cascade with the row.
+pub async fn purge_user(
+    db: &DbSession<'_>,
+    object_storage: &ObjectStorage,
+    id: i32,
+    condition: Condition,
+) -> Result<bool, ServiceError> {
+    let txn = db.begin().await?;
+    let files = uploaded_file::Entity::find()
+        .filter(uploaded_file::Column::UserId.eq(id))
+        .all(&txn)
+        .await?;
+    let result = Entity::delete_many()
+        .filter(Column::Id.eq(id))
+        .filter(condition)
+        .exec(&txn)
+        .await?;
+
+    if result.rows_affected == 0 {
+        tracing::info!("User {} no longer matches, keeping it", id);
+        return Ok(false);
+    }
+
+    txn.commit().await?;
+
+    // The rows are gone, objects left behind by a failure are found by the storage report
+    let mut deleted = HashSet::new();
+    for file in files {
+        if !deleted.insert(file.url.clone()) {
+            continue;
+        }
+        if let Err(e) =
+            uploader_service::delete_file_objects(db.database(), object_storage, &file).await
+        {
+            tracing::error!("Failed to delete the objects of file {}: {:?}", file.id, e);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Soft deletes the user inside `txn`, their files and sign in methods stay until the
+/// purge so a restore gets them back.
+async fn bulk_delete_user(txn: &DatabaseTransaction, user: &Model) -> Result<(), ServiceError> {
+    let mut changes = user.clone().into_active_model();
+    changes.deleted_at = Set(Some(Utc::now().naive_utc()));
+    revoke_tokens(&mut changes, next_version(user.version, 1)?);
+    update_versioned(txn, user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    Ok(())
+}
+
+async fn bulk_delete_chunk(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    webhooks: &Webhooks,
+    users: &[Model],
+    report: &mut BulkDeleteReport,
+) -> Result<(), ServiceError> {
+    let txn = db.begin().await?;
+    let mut deleted = Vec::with_capacity(users.len());
+
+    for user in users {
+        // A failing user only rolls back its savepoint, the rest of the chunk is kept
+        let savepoint = txn.begin().await?;
+
+        match bulk_delete_user(&savepoint, user).await {
+            Ok(()) => {
+                savepoint.commit().await?;
+                deleted.push(user);
+            }
+            Err(e) => {
+                savepoint.rollback().await?;
+                tracing::warn!("Failed to bulk delete user {}: {:?}", user.id, e);
+                report.fail(user.id, e.to_string());
+            }
+        }
+    }
+
+    txn.commit().await?;
+
+    for user in deleted {
+        // The deletion is committed, a stale cache entry is rejected by its token version
+        if let Err(e) = invalidate_cached_user(cache, user.id).await {
+            tracing::error!("Failed to invalidate the cached user {}: {:?}", user.id, e);
+        }
+        webhooks.dispatch(responses::WebhookEvent::new(
+            responses::WebhookEventType::UserDeleted,
+            user,
+        ));
+        report.succeed(user.id);
+    }
+
+    Ok(())
+}
+
+/// Soft deletes up to `BULK_DELETE_MAX_IDS` users in transactions of
+/// `BULK_DELETE_CHUNK_SIZE`, their files and sign in methods go with the purge.
+/// Admins and the caller are always skipped, and a dry run only reports what would go.
+pub async fn bulk_delete_users(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    webhooks: &Webhooks,
+    caller_id: i32,
+    ids: Vec<i32>,
+    dry_run: bool,
+) -> Result<BulkDeleteReport, ServiceError> {
+    tracing::info_span!("users_service::bulk_delete_users", %caller_id, %dry_run);
+    let mut ids = ids;
+    ids.sort_unstable();
+    ids.dedup();
+
+    if ids.len() > BULK_DELETE_MAX_IDS {
+        return Err(ServiceError::bad_request::<Error>(
+            &format!(
+                "At most {} users can be deleted at once",
+                BULK_DELETE_MAX_IDS
+            ),
+            None,
+        ));
+    }
+
+    let users = Entity::find_by_ids(&ids)
+        .order_by_asc(Column::Id)
+        .all(db)
+        .await?;
+    let mut report = BulkDeleteReport {
+        dry_run,
+        found: users.iter().map(|user| user.id).collect(),
+        ..Default::default()
+    };
+    report.missing = ids
+        .into_iter()
+        .filter(|id| !report.found.contains(id))
+        .collect();
+
+    let mut deletable = Vec::with_capacity(users.len());
+    for user in users {
+        if user.id == caller_id {
+            report.skip(user.id, "Cannot delete yourself");
+        } else if user.role == RoleEnum::Admin {
+            report.skip(user.id, "Admins cannot be bulk deleted");
+        } else {
+            deletable.push(user);
+        }
+    }
+
+    if !deletable.is_empty() {
+        report.uploaded_files = uploaded_file::Entity::find()
+            .filter(uploaded_file::Column::UserId.is_in(deletable.iter().map(|user| user.id)))
+            .count(db)
+            .await?;
+        report.oauth_providers = oauth_provider::Entity::find()
+            .filter(
+                oauth_provider::Column::UserEmail
+                    .is_in(deletable.iter().map(|user| user.email.as_str())),
+            )
+            .count(db)
+            .await?;
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    for chunk in deletable.chunks(BULK_DELETE_CHUNK_SIZE) {
+        bulk_delete_chunk(db, cache, webhooks, chunk, &mut report).await?;
+    }
+
+    tracing::info!(
+        "Bulk deleted {} users, {} failed",
+        report
+            .results
+            .iter()
+            .filter(|result| result.deleted)
+            .count(),
+        report
+            .results
+            .iter()
+            .filter(|result| !result.deleted)
+            .count()
+    );
+    Ok(report)
+}
+
+pub async fn update_role(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    id: i32,
+    role: RoleEnum,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_role", %id);
+    let user = find_one_by_id(db, id).await?;
+
+    if user.role == role {
+        return Ok(user);
+    }
+
+    let version = next_version(user.version, 1)?;
+    let user = db
+        .transaction(|txn| {
+            Box::pin(async move {
+                if user.role == RoleEnum::Admin {
+                    let admins = Entity::find()
+                        .filter(Column::Role.eq(RoleEnum::Admin))
+                        .filter(Column::DeletedAt.is_null())
+                        .lock_exclusive()
+                        .all(txn)
+                        .await?;
+
+                    if admins.len() <= 1 {
+                        tracing::warn!("Cannot demote the last admin");
+                        return Err(ServiceError::conflict::<Error>(
+                            "Cannot demote the last admin",
+                            None,
+                        ));
+                    }
+                }
+
+                let mut changes = user.clone().into_active_model();
+                changes.role = Set(role);
+                revoke_tokens(&mut changes, version);
+                update_versioned(txn, &user, changes)
+                    .await?
+                    .ok_or_else(version_conflict)
+            })
+        })
+        .await?;
+
+    invalidate_cached_user(cache, id).await?;
+    Ok(user)
+}
+
+fn format_exported_user(format: ExportFormat, user: Model) -> Result<String, ServiceError> {
+    let user = responses::ExportedUser::from(user);
+    match format {
+        ExportFormat::Csv => Ok(user.to_csv_row()),
+        ExportFormat::Json => serde_json::to_string(&user)
+            .map(|row| row + "\n")
+            .map_err(|e| ServiceError::internal_server_error(SOMETHING_WENT_WRONG, Some(e))),
+    }
+}
+
+pub fn export_confirmed(
     db: &Database,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, ServiceError>> {
+    tracing::info_span!("users_service::export_confirmed");
+    let db = db.clone();
+    try_stream! {
+        let mut rows = Entity::find()
+            .filter(Column::Confirmed.eq(true))
+            .filter(Column::DeletedAt.is_null())
+            .order_by_asc(Column::Id)
+            .stream(db.get_connection())
+            .await?;
+        let mut chunk = match format {
+            ExportFormat::Csv => responses::EXPORTED_USER_CSV_HEADER.to_string(),
+            ExportFormat::Json => String::new(),
+        };
+        let mut chunk_rows = 0;
+
+        while let Some(user) = rows.next().await {
+            chunk.push_str(&format_exported_user(format, user?)?);
+            chunk_rows += 1;
+
+            if chunk_rows == EXPORT_CHUNK_SIZE {
+                yield Bytes::from(std::mem::take(&mut chunk));
+                chunk_rows = 0;
+            }
+        }
+
+        if !chunk.is_empty() {
+            yield Bytes::from(chunk);
+        }
+    }
+}
+
+/// Which parts of a page the client selected, anything not selected is not queried.
+pub struct PageSelection {
+    pub total_count: bool,
+    pub previous_count: bool,
+    pub has_previous_page: bool,
+    pub has_next_page: bool,
+}
+
+/// Narrows the users connection, every field set must match.
+#[derive(Default)]
+pub struct UsersFilter {
+    pub search: Option<String>,
+    pub role: Option<RoleEnum>,
+    /// Inclusive lower bound on the sign up date.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on the sign up date.
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl UsersFilter {
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        match (self.created_after, self.created_before) {
+            (Some(after), Some(before)) if after >= before => {
+                Err(ServiceError::bad_request::<Error>(
+                    "createdAfter must be before createdBefore",
+                    None,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn condition(&self) -> Condition {
+        let mut condition = Condition::all();
+
+        if let Some(role) = self.role {
+            condition = condition.add(Column::Role.eq(role));
+        }
+        if let Some(created_after) = self.created_after {
+            condition = condition.add(Column::CreatedAt.gte(created_after.naive_utc()));
+        }
+        if let Some(created_before) = self.created_before {
+            condition = condition.add(Column::CreatedAt.lt(created_before.naive_utc()));
+        }
+
+        condition
+    }
+
+    /// Short hash of every argument that decides which users a page holds and in what
+    /// order, cursors carry it so they are not reused with other arguments.
+    pub fn hash(&self, order: OrderEnum, cursor: CursorEnum) -> String {
+        let filters = format!(
+            "{:?}|{:?}|{}|{:?}|{}|{}",
+            order,
+            cursor,
+            self.search.as_deref().unwrap_or_default(),
+            self.role,
+            self.created_after
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+            self.created_before
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+        );
+        format!("{:x}", Sha256::digest(filters.as_bytes()))[..8].to_string()
+    }
+}
+
+pub struct UsersPage {
+    pub users: Vec<Model>,
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub total_count: Option<u64>,
+    pub previous_count: Option<u64>,
+}
+
+pub async fn query(
+    db: &DbSession<'_>,
     order: OrderEnum,
     cursor: CursorEnum,
     limit: u64,
-    after: Option<String>,
-    search: Option<String>,
-) -> Result<(Vec<Model>, u64, u64), ServiceError> {
-    let (select, inverse_select) = Entity::query(order, cursor, after, search);
-    let users = select.clone().limit(limit).all(db.get_connection()).await?;
-    let count = select.count(db.get_connection()).await?;
-    let previous_count = match inverse_select {
-        Some(select) => select.count(db.get_connection()).await?,
-        None => 0,
+    page_cursor: PageCursor,
+    filter: UsersFilter,
+    selection: PageSelection,
+) -> Result<UsersPage, ServiceError> {
+    tracing::info_span!("users_service::query");
+    let value = match &page_cursor {
+        PageCursor::After(value) | PageCursor::Before(value) => value.as_deref(),
     };
-    Ok((users, count, previous_count))
+
+    // Plain cursors predate filter hashes and are still accepted
+    if let Some(filter_hash) = value
+        .and_then(decode_filtered_cursor)
+        .and_then(|cursor| cursor.filter_hash)
+    {
+        if filter_hash != filter.hash(order, cursor) {
+            return Err(ServiceError::bad_request::<Error>(
+                "Cursor does not match the current filters, restart pagination without it",
+                None,
+            ));
+        }
+    }
+
+    let connection = &db.read_only();
+    let backward = page_cursor.is_backward();
+    // Rows are read away from the cursor, so backward pages swap what lies ahead and behind
+    let (count_ahead, count_behind, check_behind) = if backward {
+        (
+            selection.previous_count,
+            selection.total_count,
+            selection.has_next_page && !selection.total_count,
+        )
+    } else {
+        (
+            selection.total_count,
+            selection.previous_count,
+            selection.has_previous_page && !selection.previous_count,
+        )
+    };
+    let condition = filter.condition();
+    let (select, inverse_select) =
+        Entity::query(order, cursor, page_cursor, filter.search, condition);
+    // One extra row tells whether there are more rows ahead without counting
+    let users = select.clone().limit(limit + 1).all(connection);
+    let ahead_count = async {
+        if !count_ahead {
+            return Ok(None);
+        }
+
+        select.clone().count(connection).await.map(Some)
+    };
+    let behind_count = async {
+        match &inverse_select {
+            Some(inverse_select) if count_behind => {
+                inverse_select.clone().count(connection).await.map(Some)
+            }
+            _ => Ok(count_behind.then_some(0)),
+        }
+    };
+    // A count already answers this, otherwise a single row is enough
+    let has_behind = async {
+        match &inverse_select {
+            Some(inverse_select) if check_behind => inverse_select
+                .clone()
+                .limit(1)
+                .one(connection)
+                .await
+                .map(|user| user.is_some()),
+            _ => Ok(false),
+        }
+    };
+    let (mut users, ahead_count, behind_count, has_behind) =
+        tokio::try_join!(users, ahead_count, behind_count, has_behind)?;
+    let has_ahead = users.len() as u64 > limit;
+    let has_behind = has_behind || behind_count.is_some_and(|count| count > 0);
+    users.truncate(limit as usize);
+
+    if !backward {
+        return Ok(UsersPage {
+            users,
+            has_next_page: has_ahead,
+            has_previous_page: has_behind,
+            total_count: ahead_count,
+            previous_count: behind_count,
+        });
+    }
+
+    // Counts stay relative to the start of the page in the requested order
+    let page_len = users.len() as u64;
+    users.reverse();
+    Ok(UsersPage {
+        users,
+        has_next_page: has_behind,
+        has_previous_page: has_ahead,
+        total_count: behind_count.map(|count| count + page_len),
+        previous_count: ahead_count.map(|count| count.saturating_sub(page_len)),
+    })
+}
+
+pub async fn search(
+    db: &DbSession<'_>,
+    query: &str,
+    limit: u64,
+) -> Result<Vec<Model>, ServiceError> {
+    tracing::info_span!("users_service::search");
+    Ok(Entity::search(query, SEARCH_THRESHOLD)
+        .limit(limit)
+        .all(&db.read_only())
+        .await?)
 }
 
 pub async fn update_picture(ctx: &Context<'_>, picture: Upload) -> Result<Model, GqlError> {
@@ -281,45 +1491,432 @@ pub async fn update_picture(ctx: &Context<'_>, picture: Upload) -> Result<Model,
         .data::<Option<AccessUser>>()?
         .as_ref()
         .ok_or_else(|| ServiceError::unauthorized::<Error>(UNAUTHORIZED, None))?;
-    let db = ctx.data::<Database>()?;
+    let db = &ctx.data::<Database>()?.session();
     let user = find_one_by_id(db, access_user.id).await?;
     let object_storage = ctx.data::<ObjectStorage>()?;
     let image = uploader_service::upload_image(
         ctx,
         Some(access_user.id),
-        Some(db),
+        Some(db.database()),
         Some(object_storage),
         picture,
         Ratio::Square,
     )
     .await?;
-    let mut user = user.into_active_model();
-    user.picture = Set(Some(image.id));
-    let user = user.update(db.get_connection()).await?;
+    let user = update_profile(db, user, |user| async move {
+        let mut user = user.into_active_model();
+        user.picture = Set(Some(image.id));
+        Ok(user)
+    })
+    .await?;
+    invalidate_cached_user(ctx.data::<Cache>()?, user.id).await?;
     Ok(user)
 }
 
 pub async fn update_name(
-    db: &Database,
+    db: &DbSession<'_>,
+    cache: &Cache,
     user_id: i32,
     first_name: String,
     last_name: String,
 ) -> Result<Model, ServiceError> {
     let first_name = format_name(&first_name)?;
     let last_name = format_name(&last_name)?;
-    let mut user = find_one_by_id(db, user_id).await?.into_active_model();
-    let username = create_username(db, get_full_name(&first_name, &last_name)).await?;
-    user.first_name = Set(first_name);
-    user.last_name = Set(last_name);
-    user.username = Set(username);
-    let user = user.update(db.get_connection()).await?;
+    let user = find_one_by_id(db, user_id).await?;
+    let user = update_profile(db, user, |user| {
+        let (first_name, last_name) = (first_name.clone(), last_name.clone());
+        async move {
+            let username_customized = user.username_customized;
+            let mut user = user.into_active_model();
+
+            if !username_customized {
+                let username = create_username(db, &get_full_name(&first_name, &last_name)).await?;
+                user.username = Set(username);
+            }
+
+            user.first_name = Set(first_name);
+            user.last_name = Set(last_name);
+            Ok(user)
+        }
+    })
+    .await?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+/// Optional profile fields, `None` keeps the current value.
+#[derive(Debug, Default)]
+pub struct ProfileChanges {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub date_of_birth: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl ProfileChanges {
+    pub fn is_empty(&self) -> bool {
+        self.first_name.is_none()
+            && self.last_name.is_none()
+            && self.date_of_birth.is_none()
+            && self.locale.is_none()
+            && self.timezone.is_none()
+    }
+}
+
+/// Applies every provided field in one transaction, holding the user row until it
+/// commits so the whole form is saved with a single version bump.
+pub async fn update_user_profile(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+    changes: ProfileChanges,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_user_profile", %user_id);
+
+    if changes.is_empty() {
+        return Err(ServiceError::bad_request::<Error>(
+            "At least one profile field must be provided",
+            None,
+        ));
+    }
+
+    let first_name = changes.first_name.as_deref().map(format_name).transpose()?;
+    let last_name = changes.last_name.as_deref().map(format_name).transpose()?;
+    let date_of_birth = changes
+        .date_of_birth
+        .as_deref()
+        .map(parse_date_of_birth)
+        .transpose()?;
+    let txn = db.begin().await?;
+    let user = Entity::find_by_id(user_id)
+        .lock_exclusive()
+        .one(&txn)
+        .await?
+        .ok_or_else(|| ServiceError::not_found::<Error>(USER_NOT_FOUND, None))?;
+    let preferences = UserPreferences {
+        locale: changes
+            .locale
+            .unwrap_or_else(|| user.preferred_locale.clone()),
+        timezone: changes.timezone.unwrap_or_else(|| user.timezone.clone()),
+    }
+    .canonical()?;
+    let mut updated_user = user.clone().into_active_model();
+
+    if first_name.is_some() || last_name.is_some() {
+        let first_name = first_name.unwrap_or_else(|| user.first_name.clone());
+        let last_name = last_name.unwrap_or_else(|| user.last_name.clone());
+
+        if !user.username_customized && user.full_name() != get_full_name(&first_name, &last_name) {
+            let username = create_username(&txn, &get_full_name(&first_name, &last_name)).await?;
+            updated_user.username = Set(username);
+        }
+
+        updated_user.first_name = Set(first_name);
+        updated_user.last_name = Set(last_name);
+    }
+    if let Some(date_of_birth) = date_of_birth {
+        updated_user.date_of_birth = Set(Some(date_of_birth));
+    }
+
+    updated_user.preferred_locale = Set(preferences.locale);
+    updated_user.timezone = Set(preferences.timezone);
+    let user = update_versioned(&txn, &user, updated_user)
+        .await?
+        .ok_or_else(version_conflict)?;
+    txn.commit().await?;
+    invalidate_cached_user(cache, user_id).await?;
     Ok(user)
 }
 
-pub async fn update_email(db: &Database, user_id: i32, email: &str) -> Result<Model, ServiceError> {
+pub async fn update_privacy_settings(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+    show_age: bool,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_privacy_settings", %user_id);
+    let user = find_one_by_id(db, user_id).await?;
+
+    if user.show_age == show_age {
+        return Ok(user);
+    }
+
+    let user = update_profile(db, user, |user| async move {
+        let mut user = user.into_active_model();
+        user.show_age = Set(show_age);
+        Ok(user)
+    })
+    .await?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+/// Records the current terms version as accepted by the user.
+pub async fn accept_terms(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    terms_version: &TermsVersion,
+    user_id: i32,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::accept_terms", %user_id);
+    let user = find_one_by_id(db, user_id).await?;
+    let current = match terms_version.current() {
+        Some(current) => current.to_string(),
+        None => return Ok(user),
+    };
+
+    if user.terms_version.as_deref() == Some(current.as_str()) {
+        return Ok(user);
+    }
+
+    let user = update_profile(db, user, |user| {
+        let current = current.clone();
+        async move {
+            let mut user = user.into_active_model();
+            user.terms_version = Set(Some(current));
+            Ok(user)
+        }
+    })
+    .await?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+pub async fn update_preferences(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+    locale: Option<String>,
+    timezone: Option<String>,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_preferences", %user_id);
+    let user = find_one_by_id(db, user_id).await?;
+    let preferences = UserPreferences {
+        locale: locale.unwrap_or_else(|| user.preferred_locale.clone()),
+        timezone: timezone.unwrap_or_else(|| user.timezone.clone()),
+    }
+    .canonical()?;
+
+    if user.preferred_locale == preferences.locale && user.timezone == preferences.timezone {
+        return Ok(user);
+    }
+
+    let user = update_profile(db, user, |user| {
+        let preferences = preferences.clone();
+        async move {
+            let mut user = user.into_active_model();
+            user.preferred_locale = Set(preferences.locale);
+            user.timezone = Set(preferences.timezone);
+            Ok(user)
+        }
+    })
+    .await?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+pub async fn update_username(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+    username: &str,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::update_username", %user_id);
+    let username = username.to_lowercase();
+    let user = find_one_by_id(db, user_id).await?;
+
+    if user.username == username {
+        return Ok(user);
+    }
+
+    if let Some(changed_at) = user.username_changed_at {
+        let available_at = changed_at + Duration::days(USERNAME_CHANGE_COOLDOWN_DAYS);
+
+        if available_at > Utc::now().naive_utc() {
+            return Err(ServiceError::bad_request::<Error>(
+                &format!(
+                    "Username can only be changed once every {} days",
+                    USERNAME_CHANGE_COOLDOWN_DAYS
+                ),
+                None,
+            ));
+        }
+    }
+
+    let count = Entity::find_by_username_with_deleted(&username)
+        .count(db)
+        .await?;
+
+    if count > 0 {
+        return Err(ServiceError::conflict::<Error>(
+            "Username already taken",
+            None,
+        ));
+    }
+    if is_username_reserved(db, &username).await? {
+        return Err(ServiceError::conflict::<Error>(USERNAME_UNAVAILABLE, None));
+    }
+
+    // Not retried, the cooldown was only checked against this read
+    let mut changes = user.clone().into_active_model();
+    changes.username = Set(username);
+    changes.username_customized = Set(true);
+    changes.username_changed_at = Set(Some(Utc::now().naive_utc()));
+    let user = update_versioned(db, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+/// Derives the username from the current name again, as on sign up, and lets later name
+/// changes keep it in sync. A username already derived from the name is kept.
+pub async fn regenerate_username(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    user_id: i32,
+) -> Result<Model, ServiceError> {
+    tracing::info_span!("users_service::regenerate_username", %user_id);
+    let user = find_one_by_id(db, user_id).await?;
+    let full_name = user.full_name();
+
+    if username_base(&user.username) == derived_slug(db, &full_name).await? {
+        return Ok(user);
+    }
+
+    let mut changes = user.clone().into_active_model();
+    changes.username = Set(create_username(db, &full_name).await?);
+    changes.username_customized = Set(false);
+    let user = update_versioned(db, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    invalidate_cached_user(cache, user_id).await?;
+    Ok(user)
+}
+
+/// Blocks `username` and its numbered variants for everyone, existing owners keep theirs.
+pub async fn reserve_username(db: &DbSession<'_>, username: &str) -> Result<(), ServiceError> {
+    tracing::info_span!("users_service::reserve_username");
+    let username = username.to_lowercase();
+
+    if is_username_reserved(db, &username).await? {
+        return Ok(());
+    }
+
+    reserved_username::ActiveModel {
+        username: Set(username),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(())
+}
+
+/// The previous address is notified in the same transaction, with a link that can undo
+/// the change in case the account was taken over.
+pub async fn update_email(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    jwt: &Jwt,
+    mailer: &Mailer,
+    webhooks: &Webhooks,
+    user_id: i32,
+    email: &str,
+) -> Result<Model, ServiceError> {
     let email = email.to_lowercase();
-    let mut user = find_one_by_id(db, user_id).await?.into_active_model();
-    user.email = Set(email);
-    let user = user.update(db.get_connection()).await?;
+    let normalized_email = normalize_email(&email);
+    let count = Entity::find_by_normalized_email_with_deleted(&normalized_email)
+        .filter(Column::Id.ne(user_id))
+        .count(db)
+        .await?;
+
+    if count > 0 {
+        return Err(ServiceError::conflict::<Error>(USER_ALREADY_EXISTS, None));
+    }
+
+    let user = find_one_by_id(db, user_id).await?;
+    let mut changes = user.clone().into_active_model();
+    changes.email = Set(email);
+    changes.normalized_email = Set(normalized_email);
+    // Tokens stay valid, the version only marks the security change
+    changes.version = Set(next_version(user.version, 1)?);
+    let txn = db.begin().await?;
+    let updated = update_versioned(&txn, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    mailer
+        .send_email_changed_notification(
+            &txn,
+            &user.email,
+            &user.full_name(),
+            &user.preferred_locale,
+            &mask_email(&updated.email),
+            &jwt.generate_revert_token(&updated, &user.email)?,
+        )
+        .await?;
+    txn.commit().await?;
+    invalidate_cached_user(cache, user_id).await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::EmailChanged,
+        &updated,
+    ));
+    Ok(updated)
+}
+
+/// Puts back the address an email change replaced. Every token is revoked since
+/// whoever made the change may still be signed in.
+pub async fn restore_email(
+    db: &DbSession<'_>,
+    cache: &Cache,
+    webhooks: &Webhooks,
+    user_id: i32,
+    version: i32,
+    previous_email: &str,
+) -> Result<Model, ServiceError> {
+    let user = find_one_by_id(db, user_id).await?;
+
+    // Reverting revokes every older token, so links of changes made before the revert,
+    // e.g. by whoever took over the account, can not be replayed to undo it
+    if !user.accepts_token_version(version) {
+        return Err(ServiceError::unauthorized(
+            "Invalid token",
+            Some(InternalCause::new(
+                "Revert token predates the last revocation",
+            )),
+        ));
+    }
+    if user.email == previous_email {
+        return Err(ServiceError::bad_request::<Error>(
+            "Email change already reverted",
+            None,
+        ));
+    }
+
+    let normalized_email = normalize_email(previous_email);
+    let count = Entity::find_by_normalized_email_with_deleted(&normalized_email)
+        .filter(Column::Id.ne(user_id))
+        .count(db)
+        .await?;
+
+    if count > 0 {
+        return Err(ServiceError::conflict::<Error>(
+            "Previous email is already in use",
+            None,
+        ));
+    }
+
+    let mut changes = user.clone().into_active_model();
+    changes.email = Set(previous_email.to_string());
+    changes.normalized_email = Set(normalized_email);
+    revoke_tokens(&mut changes, next_version(user.version, 1)?);
+    let user = update_versioned(db, &user, changes)
+        .await?
+        .ok_or_else(version_conflict)?;
+    invalidate_cached_user(cache, user_id).await?;
+    webhooks.dispatch(responses::WebhookEvent::new(
+        responses::WebhookEventType::EmailChanged,
+        &user,
+    ));
     Ok(user)
 }
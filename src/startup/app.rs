@@ -4,20 +4,132 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{io, net::TcpListener};
+use std::{io, net::TcpListener, time::Duration};
 
 use actix_web::guard;
-use actix_web::{dev::Server, web, App, HttpServer};
-use anyhow::Error;
+use actix_web::{
+    dev::{Server, Service},
+    rt,
+    web::{self, Data},
+    App, HttpServer,
+};
+use anyhow::{anyhow, Context, Error};
+use async_graphql::http::MultipartOptions;
+use futures::future::{ready, Either};
 use tracing_actix_web::TracingLogger;
 
+use crate::common::{json_error_handler, set_email_alias_domains, set_minimum_age};
+use crate::controllers::admin_controller::admin_router;
 use crate::controllers::auth_controller::auth_router;
+use crate::controllers::docs_controller::docs_router;
 use crate::controllers::health_controller::health_router;
+use crate::controllers::images_controller::images_router;
+use crate::controllers::metrics_controller::metrics_router;
+use crate::controllers::uploads_controller::uploads_router;
 use crate::providers::{
-    ApiURLs, Cache, Database, Environment, Jwt, Mailer, OAuth, ObjectStorage, ServerLocation,
+    BreachChecker, Cache, CaptchaVerifier, Config, Database, Jwt, Lockout, Mailer, Maintenance,
+    Metrics, OAuth, ObjectStorage, ObjectStorageBackend, QueryAllowlist, UnconfirmedExpiryConfig,
+    Webhooks,
+};
+use crate::services::{
+    helpers, outbox_service, storage_gc_service, token_blacklist_service,
+    unconfirmed_accounts_service, users_service,
+};
+
+use super::graphql_ws::graphql_ws;
+use super::idempotency::Idempotency;
+use super::maintenance::MaintenanceGate;
+use super::metrics::HttpMetrics;
+use super::preflight::{run_preflight, StartupReport};
+use super::request_id::{RequestIdHeader, RequestIdRootSpanBuilder};
+use super::schema_builder::{
+    build_schema, check_content_length, graphql_playground, graphql_request, is_graphql_get,
+    limit_payload, MAX_UPLOAD_FILES,
 };
+use super::security_headers::SecurityHeaders;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(10);
+const BLACKLIST_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Providers built once per process and handed to every worker, so they all share the
+/// same Redis pool, storage client and mail transport.
+#[derive(Clone)]
+pub struct Providers {
+    pub metrics: Data<Metrics>,
+    pub cache: Data<Cache>,
+    pub jwt: Data<Jwt>,
+    pub mailer: Data<Mailer>,
+    /// Only built with the auth module, the GraphQL API never signs in with the providers.
+    pub oauth: Option<Data<OAuth>>,
+    /// Only built with the GraphQL module, the jobs deleting files run where it is.
+    pub object_storage: Option<Data<ObjectStorage>>,
+    pub webhooks: Data<Webhooks>,
+    pub lockout: Data<Lockout>,
+    pub captcha: Data<CaptchaVerifier>,
+    pub breach_checker: Data<BreachChecker>,
+    pub maintenance: Data<Maintenance>,
+    pub allowlist: Data<QueryAllowlist>,
+    /// Set once the startup checks ran, tests building the app directly have none.
+    pub startup_report: Option<Data<StartupReport>>,
+}
+
+impl Providers {
+    pub fn new(config: &Config, metrics: &Metrics) -> Self {
+        let environment = &config.environment;
+        let modules = config.modules;
+        let cache = Cache::new(&config.cache, metrics);
+        Self {
+            metrics: Data::new(metrics.clone().with_token(config.metrics_token.clone())),
+            maintenance: Data::new(Maintenance::new(&cache)),
+            cache: Data::new(cache),
+            jwt: Data::new(Jwt::new(&config.jwt)),
+            mailer: Data::new(Mailer::new(environment, &config.mailer, metrics)),
+            oauth: modules
+                .auth
+                .then(|| Data::new(OAuth::new(&config.oauth, &config.http_client))),
+            object_storage: modules.graphql.then(|| {
+                Data::new(
+                    ObjectStorage::new(environment, &config.object_storage)
+                        .with_backend_url(&config.urls.backend_url),
+                )
+            }),
+            webhooks: Data::new(Webhooks::new(&config.webhooks)),
+            lockout: Data::new(Lockout::new(config.sign_in_max_attempts)),
+            captcha: Data::new(CaptchaVerifier::new(&config.captcha)),
+            breach_checker: Data::new(BreachChecker::new(&config.password_breach)),
+            allowlist: Data::new(QueryAllowlist::new(
+                config.graphql_allowlist_path.as_deref(),
+            )),
+            startup_report: None,
+        }
+    }
+
+    pub fn with_mailer(mut self, mailer: Mailer) -> Self {
+        self.mailer = Data::new(mailer);
+        self
+    }
 
-use super::schema_builder::{build_schema, graphql_playground, graphql_request};
+    pub fn with_breach_checker(mut self, breach_checker: BreachChecker) -> Self {
+        self.breach_checker = Data::new(breach_checker);
+        self
+    }
+
+    pub fn with_object_storage(mut self, object_storage: ObjectStorage) -> Self {
+        self.object_storage = Some(Data::new(object_storage));
+        self
+    }
+
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Data::new(maintenance);
+        self
+    }
+
+    pub fn with_startup_report(mut self, startup_report: StartupReport) -> Self {
+        self.startup_report = Some(Data::new(startup_report));
+        self
+    }
+}
 
 pub struct ActixApp {
     port: u16,
@@ -31,14 +143,65 @@ impl ActixApp {
             tracing::warn!("Using default environment variables");
         }
 
-        let ServerLocation(host, port) = ServerLocation::new();
-        let db = Database::new().await?;
-        let listener = TcpListener::bind(format!("{}:{}", &host, &port))?;
+        // Every missing or invalid variable is reported at once, before any connection is made
+        let config = Config::try_new()?;
+        Self::from_config(&config).await
+    }
+
+    /// Connects the database and builds the providers for the configuration, then starts.
+    pub async fn from_config(config: &Config) -> Result<Self, Error> {
+        let mut db = Database::new(&config.database).await?;
+        // Shared across workers so a scrape sees the whole process
+        let metrics = Metrics::new();
+        db.set_metrics(&metrics);
+        let providers = Providers::new(config, &metrics);
+        Self::start(config, &db, providers).await
+    }
+
+    /// Checks the dependencies, then spawns the background jobs and binds the listener.
+    pub async fn start(
+        config: &Config,
+        db: &Database,
+        providers: Providers,
+    ) -> Result<Self, Error> {
+        set_email_alias_domains(&config.email_alias_domains);
+        users_service::set_reserved_usernames(&config.reserved_usernames);
+        set_minimum_age(config.minimum_age);
+        helpers::set_code_secret(&config.code_secret);
+        helpers::set_password_params(&config.password_hash);
+        Self::prepare_database(config, db).await?;
+        let startup_report = run_preflight(config, db, &providers).await?;
+        let providers = providers.with_startup_report(startup_report);
+        let metrics = providers.metrics.get_ref().clone();
+        // Jobs deleting files only run on instances with the storage client
+        if let Some(object_storage) = &providers.object_storage {
+            Self::spawn_purge_job(db, object_storage.get_ref().clone());
+            Self::spawn_storage_gc(
+                db,
+                &providers,
+                object_storage.get_ref().clone(),
+                config.storage_gc_interval,
+            );
+            Self::spawn_unconfirmed_expiry(
+                db,
+                &providers,
+                object_storage.get_ref().clone(),
+                config.unconfirmed_expiry,
+            );
+        }
+        Self::spawn_blacklist_cleanup(db, &metrics);
+        Self::spawn_allowlist_reload(providers.allowlist.get_ref().clone());
+        Self::spawn_outbox_worker(db, providers.mailer.get_ref().clone());
+        let listener = TcpListener::bind(format!("{}:{}", &config.host, &config.port))?;
         let port = listener.local_addr().unwrap().port();
+        let app_config = Self::build_app_config(config, db, &providers);
         let server = HttpServer::new(move || {
             App::new()
-                .wrap(TracingLogger::default())
-                .configure(Self::build_app_config(Environment::new(), port, &db))
+                .wrap(TracingLogger::<RequestIdRootSpanBuilder>::new())
+                .wrap(HttpMetrics::new(&metrics))
+                .wrap(RequestIdHeader)
+                .wrap(SecurityHeaders)
+                .configure(app_config.clone())
         })
         .listen(listener)?
         .run();
@@ -46,6 +209,169 @@ impl ActixApp {
         Ok(Self { port, server })
     }
 
+    /// Applies pending migrations with `RUN_MIGRATIONS=true`, otherwise refuses to run
+    /// against an outdated schema, development only warns about it.
+    async fn prepare_database(config: &Config, db: &Database) -> Result<(), Error> {
+        if config.run_migrations {
+            let applied = db
+                .run_migrations()
+                .await
+                .context("Failed to run the database migrations")?;
+
+            for name in &applied {
+                tracing::info!("Applied migration {}", name);
+            }
+
+            return Ok(());
+        }
+
+        let pending = db
+            .pending_migrations()
+            .await
+            .context("Failed to check the database migrations")?;
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let message = format!(
+            "The database is missing {} migration(s): {}, run the migrations or set RUN_MIGRATIONS=true",
+            pending.len(),
+            pending.join(", ")
+        );
+
+        if config.environment.is_production() {
+            return Err(anyhow!(message));
+        }
+
+        tracing::warn!("{}", message);
+        Ok(())
+    }
+
+    fn spawn_purge_job(db: &Database, object_storage: ObjectStorage) {
+        let db = db.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(PURGE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                match users_service::purge_deleted_users(&db.session(), &object_storage).await {
+                    Ok(purged) => tracing::info!("Purged {} deleted users", purged),
+                    Err(e) => tracing::error!("Failed to purge deleted users: {:?}", e),
+                }
+            }
+        });
+    }
+
+    fn spawn_storage_gc(
+        db: &Database,
+        providers: &Providers,
+        object_storage: ObjectStorage,
+        interval_seconds: Option<u64>,
+    ) {
+        let Some(interval_seconds) = interval_seconds else {
+            return;
+        };
+        let db = db.clone();
+        let cache = providers.cache.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(Duration::from_secs(interval_seconds));
+
+            loop {
+                interval.tick().await;
+                if let Err(e) = storage_gc_service::purge(&db, &cache, &object_storage, false).await
+                {
+                    tracing::error!("Failed to collect orphaned storage: {:?}", e);
+                }
+            }
+        });
+    }
+
+    fn spawn_blacklist_cleanup(db: &Database, metrics: &Metrics) {
+        let db = db.clone();
+        let metrics = metrics.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(BLACKLIST_CLEANUP_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                if let Err(e) = token_blacklist_service::purge_expired(&db, &metrics).await {
+                    tracing::error!("Failed to purge expired blacklisted tokens: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Reloads the query allow-list on SIGHUP.
+    fn spawn_allowlist_reload(allowlist: QueryAllowlist) {
+        if !allowlist.is_enabled() {
+            return;
+        }
+
+        #[cfg(unix)]
+        rt::spawn(async move {
+            use rt::signal::unix::{signal, SignalKind};
+
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    tracing::error!("Failed to listen for SIGHUP: {:?}", e);
+                    return;
+                }
+            };
+
+            while hangups.recv().await.is_some() {
+                if let Err(e) = allowlist.reload() {
+                    tracing::error!("Failed to reload the query allow-list: {:?}", e);
+                }
+            }
+        });
+    }
+
+    fn spawn_outbox_worker(db: &Database, mailer: Mailer) {
+        let db = db.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(OUTBOX_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                if let Err(e) = outbox_service::process_email_outbox(&db, &mailer).await {
+                    tracing::error!("Failed to process the email outbox: {:?}", e);
+                }
+            }
+        });
+    }
+
+    fn spawn_unconfirmed_expiry(
+        db: &Database,
+        providers: &Providers,
+        object_storage: ObjectStorage,
+        expiry: UnconfirmedExpiryConfig,
+    ) {
+        let db = db.clone();
+        let providers = providers.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(Duration::from_secs(expiry.interval_seconds));
+
+            loop {
+                interval.tick().await;
+                if let Err(e) = unconfirmed_accounts_service::expire_unconfirmed_accounts(
+                    &db,
+                    &providers.cache,
+                    &providers.jwt,
+                    &providers.mailer,
+                    &object_storage,
+                    &providers.metrics,
+                    &expiry,
+                )
+                .await
+                {
+                    tracing::error!("Failed to expire unconfirmed accounts: {:?}", e);
+                }
+            }
+        });
+    }
+
     pub fn port(&self) -> u16 {
         self.port
     }
@@ -54,36 +380,204 @@ impl ActixApp {
         self.server.await
     }
 
+    /// Composes the enabled modules. Everything is built here, the returned closure only
+    /// registers it on each worker.
     pub fn build_app_config(
-        environment: Environment,
-        port: u16,
+        config: &Config,
         db: &Database,
-    ) -> impl Fn(&mut web::ServiceConfig) {
-        let db = db.clone();
+        providers: &Providers,
+    ) -> impl Fn(&mut web::ServiceConfig) + Clone {
+        let modules = config.modules;
+        let shared = Self::configure_shared(config, db, providers);
+        let graphql = modules
+            .graphql
+            .then(|| Self::configure_graphql(config, db, providers));
+        let auth_rest = modules
+            .auth
+            .then(|| Self::configure_auth_rest(config, providers));
+        let health = modules
+            .health
+            .then(|| Self::configure_health(config, providers));
         move |cfg: &mut web::ServiceConfig| {
-            let urls = ApiURLs::new(&environment, port);
-            let jwt = Jwt::new(&environment, &urls.api_id);
-            cfg.app_data(web::Data::new(build_schema(
-                &db,
-                ObjectStorage::new(&environment),
-            )))
-            .service(
-                web::resource("/api/graphql")
-                    .guard(guard::Post())
-                    .to(graphql_request),
-            )
-            .service(
-                web::resource("/api/graphql")
-                    .guard(guard::Get())
-                    .to(graphql_playground),
+            shared(cfg);
+
+            // The health router goes last, its `/api` scope would shadow the others
+            if let Some(graphql) = &graphql {
+                graphql(cfg);
+            }
+            if let Some(auth_rest) = &auth_rest {
+                auth_rest(cfg);
+            }
+            if let Some(health) = &health {
+                health(cfg);
+            }
+        }
+    }
+
+    /// Data every module reads.
+    fn configure_shared(
+        config: &Config,
+        db: &Database,
+        providers: &Providers,
+    ) -> impl Fn(&mut web::ServiceConfig) + Clone {
+        let providers = providers.clone();
+        let environment = Data::new(config.environment.clone());
+        let db = Data::new(db.clone());
+        let body_limits = config.body_limits;
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(
+                web::JsonConfig::default()
+                    .limit(body_limits.json)
+                    .error_handler(json_error_handler),
             )
-            .app_data(web::Data::new(OAuth::new(urls.backend_url)))
-            .app_data(web::Data::new(db.clone()))
-            .app_data(web::Data::new(Cache::new()))
-            .app_data(web::Data::new(jwt))
-            .app_data(web::Data::new(Mailer::new(&environment, urls.frontend_url)))
-            .service(auth_router())
-            .service(health_router());
+            .app_data(Data::new(body_limits))
+            .app_data(environment.clone())
+            .app_data(db.clone())
+            .app_data(providers.cache.clone())
+            .app_data(providers.jwt.clone())
+            .app_data(providers.mailer.clone())
+            .app_data(providers.metrics.clone());
+        }
+    }
+
+    /// The GraphQL API with its playground, and the images and local uploads it links to.
+    pub fn configure_graphql(
+        config: &Config,
+        db: &Database,
+        providers: &Providers,
+    ) -> impl Fn(&mut web::ServiceConfig) + Clone {
+        let providers = providers.clone();
+        let object_storage = providers
+            .object_storage
+            .clone()
+            .expect("The GraphQL module needs the object storage provider");
+        let schema = Data::new(build_schema(
+            &config.environment,
+            &config.graphql_limits,
+            &config.graphql_execution,
+            db,
+            &providers.cache,
+            &providers.jwt,
+            &providers.metrics,
+            object_storage.get_ref().clone(),
+            providers.allowlist.get_ref(),
+            &providers.webhooks,
+            &providers.mailer,
+            &providers.maintenance,
+            &config.terms_version,
+            &config.unconfirmed_expiry,
+        ));
+        let body_limits = config.body_limits;
+        let graphql_execution = config.graphql_execution;
+        let playground = config.playground;
+        let local_uploads = config.object_storage.backend == ObjectStorageBackend::Filesystem;
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(schema.clone())
+                .app_data(Data::new(graphql_execution))
+                .app_data(object_storage.clone())
+                .service(
+                    web::resource("/api/graphql")
+                        .guard(guard::Post())
+                        .app_data(
+                            MultipartOptions::default()
+                                .max_file_size(body_limits.graphql)
+                                .max_num_files(MAX_UPLOAD_FILES),
+                        )
+                        .wrap_fn(move |mut req, srv| {
+                            match check_content_length(&req, body_limits.graphql) {
+                                Ok(()) => {
+                                    limit_payload(&mut req, body_limits.graphql);
+                                    Either::Left(srv.call(req))
+                                }
+                                Err(e) => Either::Right(ready(Err(e.into()))),
+                            }
+                        })
+                        .to(graphql_request),
+                )
+                .service(
+                    web::resource("/api/graphql")
+                        .guard(guard::Get())
+                        .guard(guard::fn_guard(is_graphql_get))
+                        .to(graphql_request),
+                )
+                .service(
+                    web::resource("/api/graphql")
+                        .guard(guard::Get())
+                        .guard(guard::Header("upgrade", "websocket"))
+                        .to(graphql_ws),
+                );
+
+            // Bare GETs are a 404 without it, GraphQL GETs are routed above either way
+            if playground {
+                cfg.service(
+                    web::resource("/api/graphql")
+                        .guard(guard::Get())
+                        .to(graphql_playground),
+                );
+            }
+
+            if local_uploads {
+                cfg.service(uploads_router());
+            }
+
+            cfg.service(images_router().wrap(MaintenanceGate::new(&providers.maintenance)));
+        }
+    }
+
+    /// The REST auth and admin controllers, with the docs describing them.
+    pub fn configure_auth_rest(
+        config: &Config,
+        providers: &Providers,
+    ) -> impl Fn(&mut web::ServiceConfig) + Clone {
+        let providers = providers.clone();
+        let oauth = providers
+            .oauth
+            .clone()
+            .expect("The auth module needs the OAuth provider");
+        let sign_up_mode = config.sign_up_mode;
+        let terms_version = Data::new(config.terms_version.clone());
+        let device_alerts = config.device_alerts;
+        let api_docs = config.api_docs;
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(oauth.clone())
+                .app_data(providers.captcha.clone())
+                .app_data(providers.breach_checker.clone())
+                .app_data(providers.lockout.clone())
+                .app_data(providers.webhooks.clone())
+                .app_data(Data::new(sign_up_mode))
+                .app_data(terms_version.clone())
+                .app_data(Data::new(device_alerts));
+
+            if api_docs {
+                cfg.configure(docs_router);
+            }
+
+            cfg.service(admin_router().wrap(MaintenanceGate::new(&providers.maintenance)))
+                .service(
+                    auth_router()
+                        .wrap(Idempotency::new(&providers.cache))
+                        .wrap(MaintenanceGate::new(&providers.maintenance)),
+                );
+        }
+    }
+
+    /// Health checks and metrics, the health check stays up during maintenance so
+    /// orchestrators do not restart the instances.
+    pub fn configure_health(
+        config: &Config,
+        providers: &Providers,
+    ) -> impl Fn(&mut web::ServiceConfig) + Clone {
+        let providers = providers.clone();
+        let readiness = config.readiness;
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(Data::new(readiness));
+
+            if let Some(startup_report) = &providers.startup_report {
+                cfg.app_data(startup_report.clone());
+            }
+
+            cfg.service(health_router())
+                .service(metrics_router().wrap(MaintenanceGate::new(&providers.maintenance)));
         }
     }
 }
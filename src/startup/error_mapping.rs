@@ -0,0 +1,101 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::{Arc, Mutex};
+
+use async_graphql::{
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextResolve, ResolveInfo,
+    },
+    from_value, Response, ServerError, ServerResult, Value,
+};
+
+use crate::common::{FieldError, VALIDATION_ERRORS_EXTENSION};
+
+/// Splits validation errors into one error per field, and resolves failing root and
+/// nullable fields to null so their siblings still return their data.
+pub struct ErrorMapping;
+
+impl ExtensionFactory for ErrorMapping {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ErrorMappingExtension {
+            errors: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+struct ErrorMappingExtension {
+    errors: Mutex<Vec<ServerError>>,
+}
+
+pub fn split_validation_errors(error: ServerError) -> Vec<ServerError> {
+    let field_errors = error
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.get(VALIDATION_ERRORS_EXTENSION))
+        .and_then(|value| from_value::<Vec<FieldError>>(value.clone()).ok())
+        .unwrap_or_default();
+
+    if field_errors.is_empty() {
+        return vec![error];
+    }
+
+    field_errors
+        .into_iter()
+        .map(|field_error| {
+            let mut error = error.clone();
+            error.message = field_error.message;
+            let extensions = error.extensions.get_or_insert_with(Default::default);
+            extensions.unset(VALIDATION_ERRORS_EXTENSION);
+            extensions.set("field", field_error.field);
+            error
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Extension for ErrorMappingExtension {
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let mut response = next.run(ctx, operation_name).await;
+
+        if let Ok(mut errors) = self.errors.lock() {
+            response.errors.append(&mut errors);
+        }
+
+        response.errors = response
+            .errors
+            .into_iter()
+            .flat_map(split_validation_errors)
+            .collect();
+        response
+    }
+
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        let is_root = info.path_node.parent.is_none();
+        let is_nullable = !info.return_type.ends_with('!');
+
+        match next.run(ctx, info).await {
+            Err(error) if is_root || is_nullable => match self.errors.lock() {
+                Ok(mut errors) => {
+                    errors.push(error);
+                    Ok(None)
+                }
+                Err(_) => Err(error),
+            },
+            result => result,
+        }
+    }
+}
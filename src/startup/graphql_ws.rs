@@ -0,0 +1,229 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix::{
+    Actor, ActorContext, ActorStreamExt, AsyncContext, ContextFutureSpawner, StreamHandler,
+    WrapStream,
+};
+use actix_http::ws::Item;
+use actix_web::{
+    error::ErrorBadRequest,
+    web::{Data, Payload},
+    HttpRequest, HttpResponse, Result,
+};
+use actix_web_actors::ws::{
+    CloseCode, CloseReason, Message, ProtocolError, WebsocketContext, WsResponseBuilder,
+};
+use async_graphql::{
+    http::{WebSocket, WebSocketProtocols, WsMessage, ALL_WEBSOCKET_PROTOCOLS},
+    Data as GraphQLData, EmptySubscription, Schema,
+};
+use chrono::Utc;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+
+use crate::common::UNAUTHORIZED;
+use crate::helpers::AccessUser;
+use crate::providers::{Database, Jwt};
+
+use super::read_after_write::scope_database;
+use super::schema_builder::{MutationRoot, QueryRoot};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const TOKEN_EXPIRED: &str = "Token expired";
+
+/// graphql-ws close code for missing, invalid or expired credentials.
+pub const UNAUTHORIZED_CLOSE_CODE: u16 = 4401;
+
+enum Handshake {
+    Accepted(Option<i64>),
+    Rejected,
+}
+
+/// Opens a GraphQL WebSocket connection, authenticated through the
+/// `connection_init` payload instead of the upgrade request headers.
+pub async fn graphql_ws(
+    schema: Data<Schema<QueryRoot, MutationRoot, EmptySubscription>>,
+    jwt: Data<Jwt>,
+    db: Data<Database>,
+    req: HttpRequest,
+    payload: Payload,
+) -> Result<HttpResponse> {
+    let protocol = req
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|protocols| {
+            protocols
+                .split(',')
+                .find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok())
+        })
+        .ok_or_else(|| ErrorBadRequest("Unsupported GraphQL WebSocket protocol"))?;
+    let connection = GraphQLWsConnection {
+        schema: schema.as_ref().clone(),
+        jwt: jwt.as_ref().clone(),
+        db: db.as_ref().clone(),
+        protocol,
+        handshake: Arc::new(Mutex::new(None)),
+        last_heartbeat: Instant::now(),
+        messages: None,
+        continuation: Vec::new(),
+    };
+
+    WsResponseBuilder::new(connection, &req, payload)
+        .protocols(&ALL_WEBSOCKET_PROTOCOLS)
+        .start()
+}
+
+struct GraphQLWsConnection {
+    schema: Schema<QueryRoot, MutationRoot, EmptySubscription>,
+    jwt: Jwt,
+    db: Database,
+    protocol: WebSocketProtocols,
+    // Set by the `connection_init` callback, read when its reply is forwarded
+    handshake: Arc<Mutex<Option<Handshake>>>,
+    last_heartbeat: Instant,
+    messages: Option<UnboundedSender<Vec<u8>>>,
+    continuation: Vec<u8>,
+}
+
+impl GraphQLWsConnection {
+    fn send_heartbeats(&self, ctx: &mut WebsocketContext<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn close_unauthorized(ctx: &mut WebsocketContext<Self>, description: &str) {
+        ctx.close(Some(CloseReason {
+            code: CloseCode::Other(UNAUTHORIZED_CLOSE_CODE),
+            description: Some(description.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    /// Stopping the actor drops the executor stream, ending every active subscription.
+    fn expire_at(ctx: &mut WebsocketContext<Self>, exp: i64) {
+        let remaining = (exp - Utc::now().timestamp()).max(0) as u64;
+        ctx.run_later(Duration::from_secs(remaining), |_, ctx| {
+            Self::close_unauthorized(ctx, TOKEN_EXPIRED);
+        });
+    }
+
+    fn forward(&mut self, message: WsMessage, ctx: &mut WebsocketContext<Self>) {
+        let handshake = self.handshake.lock().unwrap().take();
+
+        match (message, &handshake) {
+            (WsMessage::Text(text), _) => ctx.text(text),
+            (WsMessage::Close(_, _), Some(Handshake::Rejected)) => (),
+            (WsMessage::Close(code, description), _) => ctx.close(Some(CloseReason {
+                code: code.into(),
+                description: Some(description),
+            })),
+        }
+
+        match handshake {
+            Some(Handshake::Rejected) => Self::close_unauthorized(ctx, UNAUTHORIZED),
+            Some(Handshake::Accepted(Some(exp))) => Self::expire_at(ctx, exp),
+            _ => (),
+        }
+    }
+}
+
+impl Actor for GraphQLWsConnection {
+    type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.send_heartbeats(ctx);
+
+        let (tx, rx) = unbounded();
+        let jwt = self.jwt.clone();
+        let db = self.db.clone();
+        let handshake = self.handshake.clone();
+
+        WebSocket::new(self.schema.clone(), rx, self.protocol)
+            .on_connection_init(move |params| async move {
+                match AccessUser::from_connection_params(&jwt, &params) {
+                    Ok(access) => {
+                        let (user, exp) = access.unzip();
+                        *handshake.lock().unwrap() = Some(Handshake::Accepted(exp));
+                        let mut data = GraphQLData::default();
+                        data.insert(user);
+                        // Scoped to the connection, a write pins its later reads
+                        scope_database(&mut data, &db);
+                        Ok(data)
+                    }
+                    Err(_) => {
+                        *handshake.lock().unwrap() = Some(Handshake::Rejected);
+                        Err(async_graphql::Error::new(UNAUTHORIZED))
+                    }
+                }
+            })
+            .into_actor(self)
+            .map(|message, act, ctx| act.forward(message, ctx))
+            .finish()
+            .spawn(ctx);
+
+        self.messages = Some(tx);
+    }
+}
+
+impl StreamHandler<Result<Message, ProtocolError>> for GraphQLWsConnection {
+    fn handle(&mut self, msg: Result<Message, ProtocolError>, ctx: &mut Self::Context) {
+        let message = match msg {
+            Ok(Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+                None
+            }
+            Ok(Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+                None
+            }
+            Ok(Message::Continuation(item)) => match item {
+                Item::FirstText(bytes) | Item::FirstBinary(bytes) => {
+                    self.continuation = bytes.to_vec();
+                    None
+                }
+                Item::Continue(bytes) => {
+                    self.continuation.extend_from_slice(&bytes);
+                    None
+                }
+                Item::Last(bytes) => {
+                    self.continuation.extend_from_slice(&bytes);
+                    Some(std::mem::take(&mut self.continuation))
+                }
+            },
+            Ok(Message::Text(text)) => Some(text.into_bytes().to_vec()),
+            Ok(Message::Binary(bytes)) => Some(bytes.to_vec()),
+            Ok(Message::Nop) => None,
+            Ok(Message::Close(_)) | Err(_) => {
+                ctx.stop();
+                None
+            }
+        };
+
+        if let Some(message) = message {
+            let sent = self
+                .messages
+                .as_ref()
+                .is_some_and(|messages| messages.unbounded_send(message).is_ok());
+
+            if !sent {
+                ctx.stop();
+            }
+        }
+    }
+}
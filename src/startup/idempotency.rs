@@ -0,0 +1,357 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    time::Duration,
+};
+
+use actix_http::h1;
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderName, HeaderValue, CONTENT_TYPE, SET_COOKIE},
+        Method, StatusCode,
+    },
+    rt::time::{sleep, Instant},
+    web::Bytes,
+    Error, HttpResponse, ResponseError,
+};
+use anyhow::Error as AnyError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::common::{ServiceError, SOMETHING_WENT_WRONG};
+use crate::providers::Cache;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const IDEMPOTENCY_PREFIX: &str = "idem";
+const IDEMPOTENT_ROUTES: [&str; 4] = ["sign-up", "sign-in", "forgot-password", "confirm-sign-in"];
+const RESPONSE_TTL: u64 = 24 * 60 * 60;
+/// Refreshed while the request runs, a crashed holder only blocks the key this long.
+const LOCK_TTL: u64 = 30;
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(LOCK_TTL / 3);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a duplicate waits for the request holding the key before a 409.
+const LOCK_WAIT: Duration = Duration::from_secs(LOCK_TTL);
+
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    /// SHA-256 of the request body, a key can not replay another request's response.
+    fingerprint: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl StoredResponse {
+    fn to_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        );
+
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_str(value),
+            ) {
+                response.append_header((name, value));
+            }
+        }
+
+        response.body(self.body.clone())
+    }
+}
+
+enum Lookup {
+    Stored(StoredResponse),
+    Acquired,
+    InFlight,
+}
+
+/// Replays the first response to a POST on the idempotent auth routes for every retry
+/// with the same `Idempotency-Key` within a day, so a retried sign up does not conflict
+/// and a retried sign in does not send a second code. Duplicates that arrive while the
+/// first request runs wait for it, and requests without the header are untouched.
+/// Responses carrying tokens or cookies are never stored, their retries run again.
+pub struct Idempotency {
+    cache: Cache,
+}
+
+impl Idempotency {
+    pub fn new(cache: &Cache) -> Self {
+        Self {
+            cache: cache.clone(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = IdempotencyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            cache: self.cache.clone(),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    cache: Cache,
+}
+
+fn idempotent_route(req: &ServiceRequest) -> Option<&'static str> {
+    if req.method() != Method::POST {
+        return None;
+    }
+
+    let route = req.path().trim_end_matches('/').rsplit('/').next()?;
+    IDEMPOTENT_ROUTES
+        .iter()
+        .find(|idempotent| **idempotent == route)
+        .copied()
+}
+
+fn idempotency_key(req: &ServiceRequest) -> Option<Result<Uuid, ServiceError>> {
+    let header = req.headers().get(IDEMPOTENCY_KEY_HEADER)?;
+    Some(
+        header
+            .to_str()
+            .ok()
+            .and_then(|key| Uuid::parse_str(key.trim()).ok())
+            .ok_or_else(|| {
+                ServiceError::bad_request::<AnyError>("Idempotency-Key must be a UUID", None)
+            }),
+    )
+}
+
+async fn read_body(req: &mut ServiceRequest) -> Result<Bytes, Error> {
+    let body = req.extract::<Bytes>().await?;
+    // The handler still reads the body, so it is put back
+    let (_, mut payload) = h1::Payload::create(true);
+    payload.unread_data(body.clone());
+    req.set_payload(payload.into());
+    Ok(body)
+}
+
+async fn lookup(cache: &Cache, key: &str, lock_key: &str) -> Result<Lookup, ServiceError> {
+    if let Some(stored) = cache.get_json::<StoredResponse>(key).await? {
+        return Ok(Lookup::Stored(stored));
+    }
+
+    let acquired = cache
+        .execute(|mut connection| async move {
+            redis::cmd("SET")
+                .arg(lock_key)
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(LOCK_TTL)
+                .query_async::<_, Option<String>>(&mut connection)
+                .await
+        })
+        .await?
+        .is_some();
+
+    // The holder may have stored its response between both commands
+    if !acquired {
+        if let Some(stored) = cache.get_json::<StoredResponse>(key).await? {
+            return Ok(Lookup::Stored(stored));
+        }
+    }
+
+    Ok(if acquired {
+        Lookup::Acquired
+    } else {
+        Lookup::InFlight
+    })
+}
+
+/// Keeps the lock alive while `future` runs, so a slow request holds it to the end.
+async fn while_locked<F: Future>(cache: &Cache, lock_key: &str, future: F) -> F::Output {
+    tokio::pin!(future);
+
+    loop {
+        tokio::select! {
+            output = &mut future => return output,
+            _ = sleep(LOCK_REFRESH_INTERVAL) => {
+                let refreshed = cache
+                    .execute(|mut connection| async move {
+                        redis::cmd("EXPIRE")
+                            .arg(lock_key)
+                            .arg(LOCK_TTL)
+                            .query_async::<_, bool>(&mut connection)
+                            .await
+                    })
+                    .await;
+                if let Err(e) = refreshed {
+                    tracing::warn!("Failed to refresh the idempotency lock: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Sign in and confirm sign in answer with tokens, in the body and the refresh cookie,
+/// which must not sit in Redis for a day.
+fn carries_tokens<B>(response: &HttpResponse<B>, body: &Bytes) -> bool {
+    response.headers().contains_key(SET_COOKIE)
+        || serde_json::from_slice::<Value>(body)
+            .map(|body| body.get("access_token").is_some() || body.get("refresh_token").is_some())
+            .unwrap_or(false)
+}
+
+fn replay(stored: &StoredResponse, fingerprint: &str) -> HttpResponse {
+    if stored.fingerprint != fingerprint {
+        return ServiceError::bad_request::<AnyError>(
+            "Idempotency-Key was already used with a different request",
+            None,
+        )
+        .error_response();
+    }
+
+    tracing::info!("Replaying idempotent response");
+    stored.to_response()
+}
+
+/// Server errors and token responses are not stored, a retry runs the request again.
+fn to_stored<B>(
+    response: &HttpResponse<B>,
+    body: &Bytes,
+    fingerprint: String,
+) -> Option<StoredResponse> {
+    if response.status().is_server_error() || carries_tokens(response, body) {
+        return None;
+    }
+
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| **name == CONTENT_TYPE)
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    Some(StoredResponse {
+        fingerprint,
+        status: response.status().as_u16(),
+        headers,
+        body: String::from_utf8(body.to_vec()).ok()?,
+    })
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            let (Some(route), Some(idempotency_key)) =
+                (idempotent_route(&req), idempotency_key(&req))
+            else {
+                return service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_boxed_body);
+            };
+            let idempotency_key = match idempotency_key {
+                Ok(idempotency_key) => idempotency_key,
+                Err(e) => return Ok(req.into_response(e.error_response())),
+            };
+
+            let key = format!("{}:{}:{}", IDEMPOTENCY_PREFIX, route, idempotency_key);
+            let lock_key = format!("{}:lock", key);
+            let fingerprint = format!("{:x}", Sha256::digest(read_body(&mut req).await?));
+            let deadline = Instant::now() + LOCK_WAIT;
+
+            loop {
+                match lookup(&cache, &key, &lock_key).await {
+                    Ok(Lookup::Stored(stored)) => {
+                        return Ok(req.into_response(replay(&stored, &fingerprint)));
+                    }
+                    Ok(Lookup::Acquired) => break,
+                    Ok(Lookup::InFlight) if Instant::now() < deadline => {
+                        sleep(LOCK_POLL_INTERVAL).await;
+                    }
+                    Ok(Lookup::InFlight) => {
+                        let error = ServiceError::conflict::<AnyError>(
+                            "A request with this Idempotency-Key is still in progress",
+                            None,
+                        );
+                        return Ok(req.into_response(error.error_response()));
+                    }
+                    // Auth keeps working without Redis, only retries are not deduplicated
+                    Err(e) => {
+                        tracing::warn!("Skipping the idempotency check: {:?}", e);
+                        return service
+                            .call(req)
+                            .await
+                            .map(ServiceResponse::map_into_boxed_body);
+                    }
+                }
+            }
+
+            let result = while_locked(&cache, &lock_key, service.call(req)).await;
+            let res = match result {
+                Ok(res) => res,
+                Err(e) => {
+                    cache.del(&lock_key).await.ok();
+                    return Err(e);
+                }
+            };
+            let (req, response) = res.into_parts();
+            let (response, body) = response.into_parts();
+            let body = match body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(_) => {
+                    cache.del(&lock_key).await.ok();
+                    return Err(ServiceError::internal_server_error::<AnyError>(
+                        SOMETHING_WENT_WRONG,
+                        None,
+                    )
+                    .into());
+                }
+            };
+
+            if let Some(stored) = to_stored(&response, &body, fingerprint) {
+                if let Err(e) = cache.set_json(&key, &stored, RESPONSE_TTL).await {
+                    tracing::warn!("Failed to store the idempotent response: {:?}", e);
+                }
+            }
+            if let Err(e) = cache.del(&lock_key).await {
+                tracing::warn!("Failed to release the idempotency lock: {:?}", e);
+            }
+
+            Ok(ServiceResponse::new(
+                req,
+                response.set_body(body).map_into_boxed_body(),
+            ))
+        })
+    }
+}
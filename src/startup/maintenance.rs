@@ -0,0 +1,183 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderValue, RETRY_AFTER},
+    Error, HttpResponse, ResponseError,
+};
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery},
+    parser::types::{ExecutableDocument, OperationType, Selection},
+    ErrorExtensions, ServerError, ServerResult, Variables,
+};
+
+use crate::common::ServiceError;
+use crate::providers::{Maintenance, MaintenanceStatus};
+
+/// The only operation GraphQL still runs during maintenance.
+const TOGGLE_FIELD: &str = "setMaintenanceMode";
+
+pub fn maintenance_response(status: &MaintenanceStatus) -> HttpResponse {
+    let mut response =
+        ServiceError::service_unavailable::<ServiceError>(&status.message, None).error_response();
+
+    if let Some(retry_after) = status.retry_after() {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+
+    response
+}
+
+/// Answers 503 while maintenance mode is on. Only wraps the REST scopes, GraphQL is
+/// held back by `MaintenanceCheck` so the toggle mutation stays reachable.
+pub struct MaintenanceGate {
+    maintenance: Maintenance,
+}
+
+impl MaintenanceGate {
+    pub fn new(maintenance: &Maintenance) -> Self {
+        Self {
+            maintenance: maintenance.clone(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MaintenanceGate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = MaintenanceGateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MaintenanceGateMiddleware {
+            service: Rc::new(service),
+            maintenance: self.maintenance.clone(),
+        }))
+    }
+}
+
+pub struct MaintenanceGateMiddleware<S> {
+    service: Rc<S>,
+    maintenance: Maintenance,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceGateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let maintenance = self.maintenance.clone();
+
+        Box::pin(async move {
+            if let Some(status) = maintenance.status().await {
+                let response = maintenance_response(&status);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// Rejects every GraphQL operation but the toggle while maintenance mode is on, with the
+/// same message and retry delay as the REST endpoints.
+pub struct MaintenanceCheck {
+    maintenance: Maintenance,
+}
+
+impl MaintenanceCheck {
+    pub fn new(maintenance: &Maintenance) -> Self {
+        Self {
+            maintenance: maintenance.clone(),
+        }
+    }
+}
+
+impl ExtensionFactory for MaintenanceCheck {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(MaintenanceCheckExtension {
+            maintenance: self.maintenance.clone(),
+        })
+    }
+}
+
+struct MaintenanceCheckExtension {
+    maintenance: Maintenance,
+}
+
+fn is_toggle(document: &ExecutableDocument) -> bool {
+    document.operations.iter().all(|(_, operation)| {
+        operation.node.ty == OperationType::Mutation
+            && operation
+                .node
+                .selection_set
+                .node
+                .items
+                .iter()
+                .all(|selection| match &selection.node {
+                    Selection::Field(field) => field.node.name.node == TOGGLE_FIELD,
+                    _ => false,
+                })
+    })
+}
+
+fn unavailable_error(status: &MaintenanceStatus) -> ServerError {
+    let error = ServiceError::service_unavailable::<ServiceError>(&status.message, None).extend();
+    let mut server_error = ServerError::new(error.message, None);
+    let mut extensions = error.extensions.unwrap_or_default();
+
+    if let Some(retry_after) = status.retry_after() {
+        extensions.set("retryAfter", retry_after);
+    }
+
+    server_error.extensions = Some(extensions);
+    server_error
+}
+
+#[async_trait::async_trait]
+impl Extension for MaintenanceCheckExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+
+        match self.maintenance.status().await {
+            Some(status) if !is_toggle(&document) => Err(unavailable_error(&status)),
+            _ => Ok(document),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use async_graphql::{
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextPrepareRequest, NextRequest,
+    },
+    Request, Response, ServerResult,
+};
+
+use crate::providers::Metrics;
+
+const UNMATCHED_ROUTE: &str = "unmatched";
+pub const ANONYMOUS_OPERATION: &str = "anonymous";
+
+pub struct HttpMetrics {
+    metrics: Metrics,
+}
+
+impl HttpMetrics {
+    pub fn new(metrics: &Metrics) -> Self {
+        Self {
+            metrics: metrics.clone(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for HttpMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = HttpMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpMetricsMiddleware {
+            service,
+            metrics: self.metrics.clone(),
+        }))
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: S,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        // Route patterns keep the label cardinality bounded, raw paths would not
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+        let method = req.method().to_string();
+        let metrics = self.metrics.clone();
+        let future = self.service.call(req);
+
+        Box::pin(async move {
+            let result = future.await;
+            let status = match &result {
+                Ok(res) => res.status().as_u16(),
+                Err(e) => e.as_response_error().status_code().as_u16(),
+            };
+            metrics.observe_http_request(&method, &route, status, start.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
+
+pub struct GraphQLMetrics {
+    metrics: Metrics,
+}
+
+impl GraphQLMetrics {
+    pub fn new(metrics: &Metrics) -> Self {
+        Self {
+            metrics: metrics.clone(),
+        }
+    }
+}
+
+impl ExtensionFactory for GraphQLMetrics {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(GraphQLMetricsExtension {
+            metrics: self.metrics.clone(),
+            operation: Mutex::new(None),
+        })
+    }
+}
+
+struct GraphQLMetricsExtension {
+    metrics: Metrics,
+    operation: Mutex<Option<String>>,
+}
+
+impl GraphQLMetricsExtension {
+    fn set_operation(&self, operation: Option<&str>) {
+        if let (Some(operation), Ok(mut current)) = (operation, self.operation.lock()) {
+            *current = Some(operation.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for GraphQLMetricsExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        let response = next.run(ctx).await;
+        let operation = self
+            .operation
+            .lock()
+            .ok()
+            .and_then(|operation| operation.clone())
+            .unwrap_or_else(|| ANONYMOUS_OPERATION.to_string());
+        self.metrics
+            .observe_graphql_operation(&operation, response.errors.len());
+        response
+    }
+
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        self.set_operation(request.operation_name.as_deref());
+        next.run(ctx, request).await
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        self.set_operation(operation_name);
+        next.run(ctx, operation_name).await
+    }
+}
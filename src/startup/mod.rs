@@ -5,9 +5,38 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use app::*;
+pub use error_mapping::*;
+pub use graphql_ws::*;
+pub use idempotency::*;
+pub use maintenance::*;
+pub use metrics::*;
+pub use operation_allowlist::*;
+pub use operation_log::*;
+pub use persisted_queries::*;
+pub use preflight::*;
+pub use read_after_write::*;
+pub use request_id::*;
 pub use schema_builder::*;
+pub use security_headers::*;
+pub use slow_queries::*;
 pub use telemetry::*;
 
 pub mod app;
+pub mod error_mapping;
+pub mod graphql_ws;
+pub mod idempotency;
+pub mod maintenance;
+pub mod metrics;
+pub mod operation_allowlist;
+pub mod operation_log;
+pub mod persisted_queries;
+pub mod preflight;
+pub mod read_after_write;
+pub mod request_id;
 pub mod schema_builder;
+pub mod security_headers;
+pub mod slow_queries;
 pub mod telemetry;
+
+#[cfg(test)]
+mod tests;
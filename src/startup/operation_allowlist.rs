@@ -0,0 +1,106 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{any::TypeId, sync::Arc};
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest},
+    Error, Request, ServerError, ServerResult,
+};
+use sha2::{Digest, Sha256};
+
+use entities::enums::RoleEnum;
+
+use crate::common::{GraphQLError, ServiceError};
+use crate::helpers::AccessUser;
+use crate::providers::QueryAllowlist;
+
+const PERSISTED_QUERY_EXTENSION: &str = "persistedQuery";
+const SHA256_HASH: &str = "sha256Hash";
+
+/// Rejects operations missing from the allow-list, admins may still run any query.
+/// Registered before the persisted queries so it sees the hash clients sent.
+pub struct OperationAllowlist {
+    allowlist: QueryAllowlist,
+}
+
+impl OperationAllowlist {
+    pub fn new(allowlist: &QueryAllowlist) -> Self {
+        Self {
+            allowlist: allowlist.clone(),
+        }
+    }
+}
+
+impl ExtensionFactory for OperationAllowlist {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(OperationAllowlistExtension {
+            allowlist: self.allowlist.clone(),
+        })
+    }
+}
+
+struct OperationAllowlistExtension {
+    allowlist: QueryAllowlist,
+}
+
+fn operation_hash(request: &Request) -> Option<String> {
+    if !request.query.is_empty() {
+        return Some(format!("{:x}", Sha256::digest(request.query.as_bytes())));
+    }
+
+    let persisted_query = request.extensions.get(PERSISTED_QUERY_EXTENSION)?;
+    match persisted_query {
+        async_graphql::Value::Object(fields) => match fields.get(SHA256_HASH) {
+            Some(async_graphql::Value::String(hash)) => Some(hash.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// HTTP requests carry the user in their own data, WebSocket ones in the session data.
+fn is_admin(ctx: &ExtensionContext<'_>, request: &Request) -> bool {
+    request
+        .data
+        .get(&TypeId::of::<Option<AccessUser>>())
+        .and_then(|data| data.downcast_ref::<Option<AccessUser>>())
+        .or_else(|| ctx.data_opt::<Option<AccessUser>>())
+        .and_then(Option::as_ref)
+        .is_some_and(|user| user.role == RoleEnum::Admin && !user.is_impersonated())
+}
+
+fn forbidden_error() -> ServerError {
+    let error = Error::from(GraphQLError::from(ServiceError::forbidden::<ServiceError>(
+        "Operation is not allowed",
+        None,
+    )));
+    let mut server_error = ServerError::new(error.message, None);
+    server_error.extensions = error.extensions;
+    server_error
+}
+
+#[async_trait::async_trait]
+impl Extension for OperationAllowlistExtension {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        if !self.allowlist.is_enabled() || is_admin(ctx, &request) {
+            return next.run(ctx, request).await;
+        }
+
+        match operation_hash(&request) {
+            Some(hash) if self.allowlist.contains(&hash) => next.run(ctx, request).await,
+            _ => {
+                tracing::warn!("Rejected an operation missing from the allow-list");
+                Err(forbidden_error())
+            }
+        }
+    }
+}
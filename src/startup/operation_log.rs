@@ -0,0 +1,239 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery},
+    parser::types::{ExecutableDocument, Selection, SelectionSet},
+    Response, ServerResult, Value, Variables,
+};
+use tracing::{field::Empty, Instrument, Level};
+
+use super::metrics::ANONYMOUS_OPERATION;
+use crate::common::RequestId;
+use crate::helpers::AccessUser;
+
+pub const REQUEST_ID_EXTENSION: &str = "requestId";
+const REDACTED: &str = "[REDACTED]";
+const SENSITIVE_NAMES: [&str; 4] = ["password", "token", "code", "secret"];
+
+/// Runs each operation inside a `graphql_operation` span with its type, top level
+/// fields and user, and stamps the response with the request id. Nothing is logged
+/// while INFO is disabled, and the redacted variables only at TRACE.
+pub struct OperationLog;
+
+impl ExtensionFactory for OperationLog {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(OperationLogExtension::default())
+    }
+}
+
+struct OperationSummary {
+    name: Option<String>,
+    operation_type: String,
+    fields: Vec<String>,
+}
+
+#[derive(Default)]
+struct OperationLogExtension {
+    operations: Mutex<Vec<OperationSummary>>,
+    variables: Mutex<Option<String>>,
+}
+
+impl OperationLogExtension {
+    /// The named operation, or the only one of the document when none is named.
+    fn take_operation(&self, operation_name: Option<&str>) -> Option<OperationSummary> {
+        let mut operations = self.operations.lock().ok()?;
+        let position = match operation_name {
+            Some(name) => operations
+                .iter()
+                .position(|operation| operation.name.as_deref() == Some(name))?,
+            None if operations.len() == 1 => 0,
+            None => return None,
+        };
+        Some(operations.swap_remove(position))
+    }
+
+    fn take_variables(&self) -> Option<String> {
+        self.variables.lock().ok()?.take()
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE_NAMES
+        .iter()
+        .any(|sensitive| name.contains(sensitive))
+}
+
+/// Input objects are walked too, `input: { password }` is redacted as well.
+fn redact(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(name, value)| {
+                    if is_sensitive(&name) {
+                        (name, Value::from(REDACTED))
+                    } else {
+                        (name, redact(value))
+                    }
+                })
+                .collect(),
+        ),
+        Value::List(list) => Value::List(list.into_iter().map(redact).collect()),
+        value => value,
+    }
+}
+
+fn redacted_variables(variables: &Variables) -> String {
+    redact(variables.clone().into_value()).to_string()
+}
+
+/// Root fragments are expanded, the document is not validated yet so cycles are cut.
+fn collect_fields<'a>(
+    document: &'a ExecutableDocument,
+    selection_set: &'a SelectionSet,
+    visited: &mut HashSet<&'a str>,
+    fields: &mut Vec<String>,
+) {
+    for selection in &selection_set.items {
+        match &selection.node {
+            Selection::Field(field) => {
+                let name = field.node.name.node.to_string();
+                if !fields.contains(&name) {
+                    fields.push(name);
+                }
+            }
+            Selection::InlineFragment(fragment) => {
+                collect_fields(document, &fragment.node.selection_set.node, visited, fields)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = &spread.node.fragment_name.node;
+                if let Some(fragment) = document.fragments.get(name) {
+                    if visited.insert(name.as_str()) {
+                        collect_fields(
+                            document,
+                            &fragment.node.selection_set.node,
+                            visited,
+                            fields,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn summarize(document: &ExecutableDocument) -> Vec<OperationSummary> {
+    document
+        .operations
+        .iter()
+        .map(|(name, operation)| {
+            let mut fields = Vec::new();
+            collect_fields(
+                document,
+                &operation.node.selection_set.node,
+                &mut HashSet::new(),
+                &mut fields,
+            );
+            OperationSummary {
+                name: name.map(|name| name.to_string()),
+                operation_type: operation.node.ty.to_string(),
+                fields,
+            }
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Extension for OperationLogExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+
+        if tracing::enabled!(Level::INFO) {
+            if let Ok(mut operations) = self.operations.lock() {
+                *operations = summarize(&document);
+            }
+        }
+        if tracing::enabled!(Level::TRACE) {
+            if let Ok(mut current) = self.variables.lock() {
+                *current = Some(redacted_variables(variables));
+            }
+        }
+
+        Ok(document)
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let request_id = RequestId::current();
+        let operation = self.take_operation(operation_name);
+        let user_id = ctx
+            .data_opt::<Option<AccessUser>>()
+            .and_then(Option::as_ref)
+            .map(|user| user.id);
+        let span = tracing::info_span!(
+            "graphql_operation",
+            operation = operation_name.unwrap_or(ANONYMOUS_OPERATION),
+            operation_type = operation
+                .as_ref()
+                .map(|operation| operation.operation_type.as_str()),
+            fields = operation
+                .as_ref()
+                .map(|operation| operation.fields.join(","))
+                .as_deref(),
+            user_id = user_id,
+            request_id = request_id.as_deref(),
+            elapsed_ms = Empty,
+            errors = Empty,
+        );
+
+        let mut response = if span.is_disabled() {
+            next.run(ctx, operation_name).await
+        } else {
+            let variables = self.take_variables();
+            let operation_span = span.clone();
+            async move {
+                if let Some(variables) = variables {
+                    tracing::trace!(variables = %variables, "GraphQL variables");
+                }
+
+                let start = Instant::now();
+                let response = next.run(ctx, operation_name).await;
+                operation_span.record("elapsed_ms", start.elapsed().as_millis() as u64);
+                operation_span.record("errors", response.errors.len());
+                tracing::info!("GraphQL operation");
+                response
+            }
+            .instrument(span)
+            .await
+        };
+
+        if let Some(request_id) = request_id {
+            response
+                .extensions
+                .insert(REQUEST_ID_EXTENSION.to_string(), Value::from(request_id));
+        }
+
+        response
+    }
+}
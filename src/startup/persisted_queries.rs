@@ -0,0 +1,134 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest},
+    ErrorExtensionValues, Request, ServerError, ServerResult,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::providers::Cache;
+
+const PERSISTED_QUERY: &str = "persisted_query";
+const PERSISTED_QUERY_EXTENSION: &str = "persistedQuery";
+
+pub const PERSISTED_QUERY_NOT_FOUND: &str = "PERSISTED_QUERY_NOT_FOUND";
+pub const PERSISTED_QUERY_HASH_MISMATCH: &str = "PERSISTED_QUERY_HASH_MISMATCH";
+
+#[derive(Deserialize)]
+struct PersistedQuery {
+    version: i32,
+    #[serde(rename = "sha256Hash")]
+    sha256_hash: String,
+}
+
+fn persisted_query_error(message: &str, code: &str) -> ServerError {
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("code", code);
+    let mut error = ServerError::new(message, None);
+    error.extensions = Some(extensions);
+    error
+}
+
+/// Apollo automatic persisted queries, storing the query text in Redis so every
+/// instance can serve hashes registered on any other.
+pub struct PersistedQueries {
+    cache: Cache,
+    ttl: u64,
+}
+
+impl PersistedQueries {
+    pub fn new(cache: &Cache, ttl: u64) -> Self {
+        Self {
+            cache: cache.clone(),
+            ttl,
+        }
+    }
+}
+
+impl ExtensionFactory for PersistedQueries {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(PersistedQueriesExtension {
+            cache: self.cache.clone(),
+            ttl: self.ttl,
+        })
+    }
+}
+
+struct PersistedQueriesExtension {
+    cache: Cache,
+    ttl: u64,
+}
+
+impl PersistedQueriesExtension {
+    async fn load_query(&self, hash: &str) -> Option<String> {
+        let key = format!("{}:{}", PERSISTED_QUERY, hash);
+
+        match self.cache.get_json::<String>(&key).await {
+            Ok(query) => query,
+            Err(_) => {
+                // The client falls back to sending the full query on a miss
+                tracing::warn!("Failed to load persisted query {}", hash);
+                None
+            }
+        }
+    }
+
+    async fn store_query(&self, hash: &str, query: &str) {
+        let key = format!("{}:{}", PERSISTED_QUERY, hash);
+
+        if self.cache.set_json(&key, &query, self.ttl).await.is_err() {
+            tracing::warn!("Failed to store persisted query {}", hash);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for PersistedQueriesExtension {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        mut request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        let persisted_query = match request.extensions.remove(PERSISTED_QUERY_EXTENSION) {
+            Some(value) => async_graphql::from_value::<PersistedQuery>(value)
+                .map_err(|_| ServerError::new("Invalid \"persistedQuery\" extension.", None))?,
+            None => return next.run(ctx, request).await,
+        };
+
+        if persisted_query.version != 1 {
+            return Err(ServerError::new(
+                format!(
+                    "Unsupported \"persistedQuery\" version: {}",
+                    persisted_query.version
+                ),
+                None,
+            ));
+        }
+
+        let hash = persisted_query.sha256_hash.to_lowercase();
+        if request.query.is_empty() {
+            request.query = self.load_query(&hash).await.ok_or_else(|| {
+                persisted_query_error("PersistedQueryNotFound", PERSISTED_QUERY_NOT_FOUND)
+            })?;
+        } else {
+            if format!("{:x}", Sha256::digest(request.query.as_bytes())) != hash {
+                return Err(persisted_query_error(
+                    "Provided sha does not match query",
+                    PERSISTED_QUERY_HASH_MISMATCH,
+                ));
+            }
+
+            self.store_query(&hash, &request.query).await;
+        }
+
+        next.run(ctx, request).await
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fmt::Debug, future::Future, time::Duration};
+
+use actix_web::rt::time::{timeout, Instant};
+use anyhow::{anyhow, Error};
+
+use crate::dtos::responses;
+use crate::providers::{Config, Database, EmailTransportKind, ObjectStorageBackend, StartupChecks};
+
+use super::Providers;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the health check keeps sending the report after boot.
+const REPORT_TTL: Duration = Duration::from_secs(5 * 60);
+const OK: &str = "ok";
+const FAILED: &str = "failed";
+const SKIPPED: &str = "skipped";
+
+/// Outcome of the dependency checks run before the server started.
+#[derive(Clone, Debug)]
+pub struct StartupReport {
+    checked_at: Instant,
+    report: responses::StartupReport,
+}
+
+impl StartupReport {
+    fn skipped() -> Self {
+        Self {
+            checked_at: Instant::now(),
+            report: responses::StartupReport {
+                database: SKIPPED.to_string(),
+                cache: SKIPPED.to_string(),
+                object_storage: SKIPPED.to_string(),
+                mailer: SKIPPED.to_string(),
+            },
+        }
+    }
+
+    /// The report during the first minutes after boot, `None` afterwards.
+    pub fn recent(&self) -> Option<&responses::StartupReport> {
+        (self.checked_at.elapsed() < REPORT_TTL).then_some(&self.report)
+    }
+}
+
+/// `None` when the check is skipped, `Some(true)` when it passed in time.
+async fn check<E: Debug>(
+    name: &str,
+    skip: bool,
+    future: impl Future<Output = Result<(), E>>,
+) -> Option<bool> {
+    if skip {
+        return None;
+    }
+
+    match timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(())) => Some(true),
+        Ok(Err(e)) => {
+            tracing::error!("Startup check of {} failed: {:?}", name, e);
+            Some(false)
+        }
+        Err(_) => {
+            tracing::error!(
+                "Startup check of {} timed out after {}s",
+                name,
+                CHECK_TIMEOUT.as_secs()
+            );
+            Some(false)
+        }
+    }
+}
+
+fn status(result: Option<bool>) -> String {
+    match result {
+        Some(true) => OK,
+        Some(false) => FAILED,
+        None => SKIPPED,
+    }
+    .to_string()
+}
+
+/// Checks every dependency at once and warms the database pool. Failures abort startup
+/// in production, unless `STARTUP_CHECKS=off`, and are only logged in development.
+pub async fn run_preflight(
+    config: &Config,
+    db: &Database,
+    providers: &Providers,
+) -> Result<StartupReport, Error> {
+    if config.startup_checks == StartupChecks::Off {
+        if let Err(e) = db.warm_up().await {
+            tracing::warn!("Failed to warm up the database pool: {:?}", e);
+        }
+
+        return Ok(StartupReport::skipped());
+    }
+
+    let (database, cache, object_storage, mailer) = tokio::join!(
+        check("database", false, async {
+            db.get_connection().ping().await?;
+            db.warm_up().await
+        }),
+        check("redis", false, providers.cache.ping()),
+        check(
+            "object storage",
+            providers.object_storage.is_none()
+                || config.object_storage.backend == ObjectStorageBackend::Filesystem,
+            async {
+                match &providers.object_storage {
+                    Some(object_storage) => object_storage.head_bucket().await,
+                    None => Ok(()),
+                }
+            },
+        ),
+        check(
+            "mailer",
+            config.mailer.transport == EmailTransportKind::Console,
+            async {
+                if providers.mailer.verify().await {
+                    Ok(())
+                } else {
+                    Err(anyhow!("The mail transport did not accept the connection"))
+                }
+            },
+        ),
+    );
+    let failed = [
+        ("database", database),
+        ("redis", cache),
+        ("object storage", object_storage),
+        ("mailer", mailer),
+    ]
+    .into_iter()
+    .filter(|(_, result)| *result == Some(false))
+    .map(|(name, _)| name)
+    .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        let message = format!("Startup checks failed for {}", failed.join(", "));
+
+        if config.environment.is_production() {
+            return Err(anyhow!(message));
+        }
+
+        tracing::warn!("{}, continuing in development", message);
+    }
+
+    Ok(StartupReport {
+        checked_at: Instant::now(),
+        report: responses::StartupReport {
+            database: status(database),
+            cache: status(cache),
+            object_storage: status(object_storage),
+            mailer: status(mailer),
+        },
+    })
+}
@@ -0,0 +1,58 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    dataloader::DataLoader,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextResolve, ResolveInfo},
+    Data, OutputType, ServerResult, Value,
+};
+
+use crate::data_loaders::SeaOrmLoader;
+use crate::providers::Database;
+
+use super::schema_builder::MutationRoot;
+
+/// Gives the request its own database and loader, so its writes only pin its reads.
+pub fn scope_database(data: &mut Data, database: &Database) {
+    let database = database.for_request();
+    data.insert(DataLoader::new(
+        SeaOrmLoader::new(&database),
+        tokio::task::spawn,
+    ));
+    data.insert(database);
+}
+
+/// Records a write before every mutation field runs, the rest of the request then
+/// reads from the primary instead of a replica that may lag behind.
+pub struct ReadAfterWrite;
+
+impl ExtensionFactory for ReadAfterWrite {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ReadAfterWriteExtension)
+    }
+}
+
+struct ReadAfterWriteExtension;
+
+#[async_trait::async_trait]
+impl Extension for ReadAfterWriteExtension {
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        if info.parent_type == MutationRoot::type_name() {
+            if let Some(database) = ctx.data_opt::<Database>() {
+                database.record_write();
+            }
+        }
+
+        next.run(ctx, info).await
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error,
+};
+use tracing::Span;
+use tracing_actix_web::{root_span, DefaultRootSpanBuilder, RootSpanBuilder};
+
+use crate::common::{RequestId, REQUEST_ID_HEADER};
+
+pub struct RequestIdRootSpanBuilder;
+
+impl RootSpanBuilder for RequestIdRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let request_id = RequestId::from_request(request);
+        root_span!(request, correlation_id = %request_id.as_str())
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+pub struct RequestIdHeader;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdHeader
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdHeaderMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdHeaderMiddleware { service }))
+    }
+}
+
+pub struct RequestIdHeaderMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdHeaderMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = RequestId::from_request(&req);
+        let header = HeaderValue::from_str(request_id.as_str()).ok();
+        let future = self.service.call(req);
+
+        // Error responses are rendered while the handler runs, so they can read the id
+        Box::pin(request_id.scope(async move {
+            let mut res = future.await?;
+
+            if let Some(header) = header {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+            }
+
+            Ok(res)
+        }))
+    }
+}
@@ -4,71 +4,304 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use actix_web::{web::Data, HttpRequest, HttpResponse, Result};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    guard::GuardContext,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_SECURITY_POLICY},
+        StatusCode,
+    },
+    web::Data,
+    HttpMessage, HttpRequest, HttpResponse, Result,
+};
 use async_graphql::{
     dataloader::DataLoader,
     http::{playground_source, GraphQLPlaygroundConfig},
-    EmptySubscription, MergedObject, Schema,
+    parser::{parse_query, types::OperationType},
+    EmptySubscription, MergedObject, Response, Schema, SchemaBuilder,
 };
 use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use futures::StreamExt;
 
-use crate::data_loaders::SeaOrmLoader;
+use super::error_mapping::ErrorMapping;
+use super::maintenance::MaintenanceCheck;
+use super::metrics::{GraphQLMetrics, ANONYMOUS_OPERATION};
+use super::operation_allowlist::OperationAllowlist;
+use super::operation_log::OperationLog;
+use super::persisted_queries::PersistedQueries;
+use super::read_after_write::{scope_database, ReadAfterWrite};
+use super::slow_queries::{execute_with_timeout, SlowQueryLog};
+use crate::common::{body_too_large, has_csrf_token, AuthTokens, RequestId, ServiceError};
+use crate::data_loaders::{CacheLoader, SeaOrmLoader};
 use crate::{
     helpers::AccessUser,
-    providers::{Database, ObjectStorage},
+    providers::{
+        BodyLimitsConfig, Cache, Database, Environment, GraphQLExecutionConfig, GraphQLLimits,
+        Mailer, Maintenance, Metrics, ObjectStorage, QueryAllowlist, TermsVersion,
+        UnconfirmedExpiryConfig, Webhooks,
+    },
 };
 use crate::{
     providers::Jwt,
-    resolvers::{health_resolver, uploader_resolver, users_resolver},
+    resolvers::{
+        allowlist_resolver, api_keys_resolver, data_export_resolver, health_resolver,
+        invitations_resolver, maintenance_resolver, node_resolver, oauth_providers_resolver,
+        outbox_resolver, storage_resolver, uploader_resolver, users_resolver,
+    },
 };
 
 #[derive(MergedObject, Default)]
-pub struct MutationRoot(users_resolver::UsersMutation);
+pub struct MutationRoot(
+    users_resolver::UsersMutation,
+    storage_resolver::StorageMutation,
+    api_keys_resolver::ApiKeysMutation,
+    uploader_resolver::UploaderMutation,
+    allowlist_resolver::AllowlistMutation,
+    invitations_resolver::InvitationsMutation,
+    maintenance_resolver::MaintenanceMutation,
+    data_export_resolver::DataExportMutation,
+);
 
 #[derive(MergedObject, Default)]
 pub struct QueryRoot(
     users_resolver::UsersQuery,
     uploader_resolver::UploaderQuery,
     health_resolver::HealthQuery,
+    node_resolver::NodeQuery,
+    outbox_resolver::OutboxQuery,
+    api_keys_resolver::ApiKeysQuery,
+    oauth_providers_resolver::OAuthProvidersQuery,
+    invitations_resolver::InvitationsQuery,
+    data_export_resolver::DataExportQuery,
 );
 
-pub fn build_schema(
-    database: &Database,
-    object_storage: ObjectStorage,
-) -> Schema<QueryRoot, MutationRoot, EmptySubscription> {
+/// The roots and limits only. Providers are attached as data by `build_schema`, so the
+/// shape of the schema can be built without any of them.
+pub fn schema_builder(
+    limits: &GraphQLLimits,
+) -> SchemaBuilder<QueryRoot, MutationRoot, EmptySubscription> {
     Schema::build(
         QueryRoot::default(),
         MutationRoot::default(),
         EmptySubscription,
     )
-    .data(DataLoader::new(
-        SeaOrmLoader::new(database),
-        tokio::task::spawn,
-    ))
-    .data(database.to_owned())
-    .data(object_storage)
-    .finish()
+    .limit_depth(limits.max_depth)
+    .limit_complexity(limits.max_complexity)
+}
+
+/// The SDL clients generate their types from, printed by `--print-schema`.
+pub fn schema_sdl() -> String {
+    schema_builder(&GraphQLLimits::default()).finish().sdl()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn build_schema(
+    environment: &Environment,
+    limits: &GraphQLLimits,
+    execution: &GraphQLExecutionConfig,
+    database: &Database,
+    cache: &Cache,
+    jwt: &Jwt,
+    metrics: &Metrics,
+    object_storage: ObjectStorage,
+    allowlist: &QueryAllowlist,
+    webhooks: &Webhooks,
+    mailer: &Mailer,
+    maintenance: &Maintenance,
+    terms_version: &TermsVersion,
+    unconfirmed_expiry: &UnconfirmedExpiryConfig,
+) -> Schema<QueryRoot, MutationRoot, EmptySubscription> {
+    let builder = schema_builder(limits)
+        .extension(GraphQLMetrics::new(metrics))
+        .extension(OperationLog)
+        .extension(SlowQueryLog::new(Duration::from_millis(
+            execution.slow_query_ms,
+        )))
+        .extension(MaintenanceCheck::new(maintenance))
+        .extension(OperationAllowlist::new(allowlist))
+        .extension(PersistedQueries::new(cache, execution.persisted_query_ttl))
+        .extension(ReadAfterWrite)
+        .extension(ErrorMapping)
+        .data(DataLoader::new(
+            SeaOrmLoader::new(database),
+            tokio::task::spawn,
+        ))
+        .data(DataLoader::new(CacheLoader::new(cache), tokio::task::spawn))
+        .data(database.to_owned())
+        .data(cache.to_owned())
+        .data(jwt.to_owned())
+        .data(metrics.to_owned())
+        .data(allowlist.to_owned())
+        .data(webhooks.to_owned())
+        .data(mailer.to_owned())
+        .data(maintenance.to_owned())
+        .data(terms_version.to_owned())
+        .data(unconfirmed_expiry.to_owned())
+        .data(object_storage);
+
+    if environment.is_production() {
+        return builder.disable_introspection().finish();
+    }
+
+    builder.finish()
 }
 
+/// Rejects bodies announced as larger than the GraphQL limit before they are read.
+pub fn check_content_length(req: &ServiceRequest, limit: usize) -> Result<(), ServiceError> {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    match content_length {
+        Some(content_length) if content_length > limit => Err(body_too_large(limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Files a single GraphQL request may upload, every upload mutation takes one.
+pub const MAX_UPLOAD_FILES: usize = 1;
+
+/// Set once the body of a request went past the GraphQL limit while being read.
+#[derive(Clone, Default)]
+struct PayloadOverflow(Arc<AtomicBool>);
+
+impl PayloadOverflow {
+    fn is_set(req: &HttpRequest) -> bool {
+        req.extensions()
+            .get::<Self>()
+            .is_some_and(|overflow| overflow.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Fails the body stream past the GraphQL limit, a chunked body has no `Content-Length`
+/// and a wrong one is not checked by the GraphQL extractor.
+pub fn limit_payload(req: &mut ServiceRequest, limit: usize) {
+    let overflow = PayloadOverflow::default();
+    req.extensions_mut().insert(overflow.clone());
+    let mut size = 0;
+    let payload = req.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+        size += chunk.len();
+
+        if size > limit {
+            overflow.0.store(true, Ordering::Relaxed);
+            return Err(PayloadError::Overflow);
+        }
+
+        Ok(chunk)
+    });
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(payload),
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn graphql_request(
     schema: Data<Schema<QueryRoot, MutationRoot, EmptySubscription>>,
     jwt: Data<Jwt>,
+    db: Data<Database>,
+    cache: Data<Cache>,
+    body_limits: Data<BodyLimitsConfig>,
+    execution: Data<GraphQLExecutionConfig>,
+    metrics: Data<Metrics>,
     req: HttpRequest,
-    gql_req: GraphQLRequest,
-) -> GraphQLResponse {
-    schema
-        .execute(
-            gql_req
-                .into_inner()
-                .data(AccessUser::from_request(jwt.as_ref(), &req)),
-        )
-        .await
-        .into()
+    gql_req: Result<GraphQLRequest>,
+) -> Result<GraphQLResponse, ServiceError> {
+    // The extractor answers in plain text, uploads over the multipart limit included
+    let gql_req = gql_req.map_err(|e| {
+        if e.as_response_error().status_code() == StatusCode::PAYLOAD_TOO_LARGE
+            || PayloadOverflow::is_set(&req)
+        {
+            body_too_large(body_limits.graphql)
+        } else {
+            ServiceError::bad_request("Invalid GraphQL request", Some(e))
+        }
+    })?;
+    let request = gql_req.into_inner();
+    if AuthTokens::new(&req, jwt.as_ref()).access_cookie
+        && may_mutate(&request.query)
+        && !has_csrf_token(&req)
+    {
+        return Err(ServiceError::forbidden::<ServiceError>(
+            "Missing or invalid CSRF token",
+            None,
+        ));
+    }
+    let user = AccessUser::from_request(jwt.as_ref(), db.as_ref(), cache.as_ref(), &req).await;
+    let mut request = request.data(user);
+    scope_database(&mut request.data, db.as_ref());
+    let operation = request.operation_name.clone();
+    let timeout = Duration::from_secs(execution.timeout_seconds);
+    let mut response = match execute_with_timeout(schema.as_ref(), request, timeout).await {
+        Ok(response) => response,
+        Err(error) => {
+            // Timed out operations never reach `GraphQLMetrics`, count them here
+            metrics
+                .observe_graphql_operation(operation.as_deref().unwrap_or(ANONYMOUS_OPERATION), 1);
+            Response::from_errors(vec![error])
+        }
+    };
+
+    // Most resolver errors skip `GraphQLError`, so tag every error here as well
+    if let Some(request_id) = RequestId::current() {
+        for error in response.errors.iter_mut() {
+            error
+                .extensions
+                .get_or_insert_with(Default::default)
+                .set("requestId", request_id.as_str());
+        }
+    }
+
+    Ok(response.into())
 }
 
+/// Persisted queries arrive without the document, they are treated as mutations.
+fn may_mutate(query: &str) -> bool {
+    match parse_query(query) {
+        Ok(document) => document
+            .operations
+            .iter()
+            .any(|(_, operation)| operation.node.ty != OperationType::Query),
+        Err(_) => true,
+    }
+}
+
+/// GETs carrying a query or a persisted query hash are GraphQL requests, bare ones
+/// open the playground.
+pub fn is_graphql_get(ctx: &GuardContext) -> bool {
+    ctx.head().uri.query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|param| matches!(param.split('=').next(), Some("query") | Some("extensions")))
+    })
+}
+
+/// The playground page loads its bundle from jsDelivr and its fonts from Google, with
+/// inline bootstrap code, and only talks to this origin.
+pub const PLAYGROUND_CSP: &str = "default-src 'none'; \
+    script-src 'unsafe-inline' https://cdn.jsdelivr.net; \
+    style-src 'unsafe-inline' https://cdn.jsdelivr.net https://fonts.googleapis.com; \
+    font-src https://fonts.gstatic.com; \
+    img-src 'self' data: https://cdn.jsdelivr.net; \
+    connect-src 'self'; \
+    frame-ancestors 'none'";
+
+/// Only routed when `ENABLE_PLAYGROUND` is on, by default outside production.
 pub async fn graphql_playground() -> Result<HttpResponse> {
     let source = playground_source(GraphQLPlaygroundConfig::new("/api/graphql"));
     Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
+        .insert_header((CONTENT_SECURITY_POLICY, PLAYGROUND_CSP))
         .body(source))
 }
@@ -0,0 +1,98 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::timeout;
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute},
+    ErrorExtensions, Executor, Request, Response, ServerError,
+};
+
+use super::metrics::ANONYMOUS_OPERATION;
+use crate::common::{RequestId, ServiceError};
+use crate::helpers::AccessUser;
+
+/// Logs operations that take longer than the threshold, with who ran them. Resolver
+/// errors are counted per operation by `GraphQLMetrics`.
+pub struct SlowQueryLog {
+    threshold: Duration,
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Duration) -> Self {
+        Self { threshold }
+    }
+}
+
+impl ExtensionFactory for SlowQueryLog {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(SlowQueryLogExtension {
+            threshold: self.threshold,
+        })
+    }
+}
+
+struct SlowQueryLogExtension {
+    threshold: Duration,
+}
+
+#[async_trait::async_trait]
+impl Extension for SlowQueryLogExtension {
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let start = Instant::now();
+        let response = next.run(ctx, operation_name).await;
+        let elapsed = start.elapsed();
+
+        if elapsed >= self.threshold {
+            let user_id = ctx
+                .data_opt::<Option<AccessUser>>()
+                .and_then(Option::as_ref)
+                .map(|user| user.id);
+            tracing::warn!(
+                operation = operation_name.unwrap_or(ANONYMOUS_OPERATION),
+                user_id = ?user_id,
+                request_id = ?RequestId::current(),
+                elapsed_ms = elapsed.as_millis() as u64,
+                errors = response.errors.len(),
+                "Slow GraphQL operation"
+            );
+        }
+
+        response
+    }
+}
+
+fn timeout_error() -> ServerError {
+    let error = ServiceError::gateway_timeout::<ServiceError>(
+        "The operation took too long to complete",
+        None,
+    )
+    .extend();
+    let mut server_error = ServerError::new(error.message, None);
+    server_error.extensions = error.extensions;
+    server_error
+}
+
+/// Runs the request, giving up once the timeout is reached so a stuck resolver or
+/// database connection cannot hold the request open.
+pub async fn execute_with_timeout(
+    executor: &impl Executor,
+    request: Request,
+    limit: Duration,
+) -> Result<Response, ServerError> {
+    timeout(limit, executor.execute(request))
+        .await
+        .map_err(|_| timeout_error())
+}
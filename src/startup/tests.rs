@@ -0,0 +1,381 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{rt::time::sleep, test, web, App, HttpResponse};
+use async_graphql::{
+    EmptyMutation, EmptySubscription, Error, ErrorExtensions, Object, Pos, Request, Schema,
+    ServerError, Value, Variables,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::common::{
+    FieldError, GraphQLError, RequestId, ServiceError, REQUEST_ID_HEADER, SOMETHING_WENT_WRONG,
+    VALIDATION_ERRORS_EXTENSION,
+};
+
+use super::{
+    execute_with_timeout, schema_sdl, split_validation_errors, OperationLog, RequestIdHeader,
+    SlowQueryLog, REQUEST_ID_EXTENSION,
+};
+
+const SCHEMA_SNAPSHOT: &str = "schema.graphql";
+
+async fn ok_handler() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+async fn failing_handler() -> Result<HttpResponse, ServiceError> {
+    Err(ServiceError::internal_server_error::<ServiceError>(
+        SOMETHING_WENT_WRONG,
+        None,
+    ))
+}
+
+macro_rules! request_id_app {
+    () => {
+        test::init_service(
+            App::new()
+                .wrap(RequestIdHeader)
+                .route("/ok", web::get().to(ok_handler))
+                .route("/fail", web::get().to(failing_handler)),
+        )
+        .await
+    };
+}
+
+fn get_request_id_header<B>(resp: &actix_web::dev::ServiceResponse<B>) -> String {
+    resp.headers()
+        .get(REQUEST_ID_HEADER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[actix_web::test]
+async fn test_request_id_round_trip() {
+    let app = request_id_app!();
+    let req = test::TestRequest::get()
+        .uri("/ok")
+        .insert_header(("X-Request-Id", "client-request-1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(get_request_id_header(&resp), "client-request-1");
+}
+
+#[actix_web::test]
+async fn test_request_id_generated() {
+    let app = request_id_app!();
+    let req = test::TestRequest::get().uri("/ok").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(Uuid::parse_str(&get_request_id_header(&resp)).is_ok());
+
+    // Ids that are unsafe to log are replaced
+    let req = test::TestRequest::get()
+        .uri("/ok")
+        .insert_header(("X-Request-Id", "a".repeat(200)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(Uuid::parse_str(&get_request_id_header(&resp)).is_ok());
+}
+
+#[actix_web::test]
+async fn test_request_id_in_error_body() {
+    let app = request_id_app!();
+    let req = test::TestRequest::get()
+        .uri("/fail")
+        .insert_header(("X-Request-Id", "client-request-2"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 500);
+    assert_eq!(get_request_id_header(&resp), "client-request-2");
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], SOMETHING_WENT_WRONG);
+    assert_eq!(body["request_id"], "client-request-2");
+}
+
+#[actix_web::test]
+async fn test_request_id_in_graphql_error() {
+    let req = test::TestRequest::default()
+        .insert_header(("X-Request-Id", "client-request-3"))
+        .to_srv_request();
+    let error = RequestId::from_request(&req)
+        .scope(async {
+            Error::from(GraphQLError::from(ServiceError::internal_server_error::<
+                ServiceError,
+            >(SOMETHING_WENT_WRONG, None)))
+        })
+        .await;
+    assert_eq!(
+        error.extensions.unwrap().get("requestId"),
+        Some(&Value::from("client-request-3"))
+    );
+}
+
+#[actix_web::test]
+async fn test_split_validation_errors() {
+    let error = ServiceError::validation(vec![
+        FieldError::new("firstName", "Invalid First name"),
+        FieldError::new(
+            "lastName",
+            "Last name needs to be between 3 and 50 characters.",
+        ),
+    ])
+    .extend()
+    .into_server_error(Pos::default());
+    let errors = split_validation_errors(error);
+    assert_eq!(errors.len(), 2);
+
+    for (error, (field, message)) in errors.iter().zip([
+        ("firstName", "Invalid First name"),
+        (
+            "lastName",
+            "Last name needs to be between 3 and 50 characters.",
+        ),
+    ]) {
+        let extensions = error.extensions.as_ref().unwrap();
+        assert_eq!(error.message, message);
+        assert_eq!(extensions.get("field"), Some(&Value::from(field)));
+        assert_eq!(extensions.get("code"), Some(&Value::from("400")));
+        assert_eq!(extensions.get("type"), Some(&Value::from("Bad Request")));
+        assert!(extensions.get(VALIDATION_ERRORS_EXTENSION).is_none());
+    }
+
+    // Any other error is left as it is
+    let error = ServiceError::not_found::<ServiceError>("User not found", None)
+        .extend()
+        .into_server_error(Pos::default());
+    let errors = split_validation_errors(error);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "User not found");
+    assert_eq!(
+        errors[0].extensions.as_ref().unwrap().get("code"),
+        Some(&Value::from("404"))
+    );
+    assert_eq!(
+        split_validation_errors(ServerError::new("Unknown", None)).len(),
+        1
+    );
+}
+
+struct SleepQuery;
+
+#[Object]
+impl SleepQuery {
+    async fn sleep(&self, ms: u64) -> bool {
+        sleep(Duration::from_millis(ms)).await;
+        true
+    }
+}
+
+fn sleep_schema(slow_query: Duration) -> Schema<SleepQuery, EmptyMutation, EmptySubscription> {
+    Schema::build(SleepQuery, EmptyMutation, EmptySubscription)
+        .extension(SlowQueryLog::new(slow_query))
+        .finish()
+}
+
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the query with logs written to a buffer instead of the global subscriber.
+async fn captured_query(schema: &Schema<SleepQuery, EmptyMutation, EmptySubscription>) -> String {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let response = execute_with_timeout(
+        schema,
+        "query Nap { sleep(ms: 0) }".into(),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert!(response.errors.is_empty());
+    logs.contents()
+}
+
+#[actix_web::test]
+async fn test_graphql_timeout() {
+    let schema = sleep_schema(Duration::from_secs(60));
+    let error = execute_with_timeout(
+        &schema,
+        "{ sleep(ms: 500) }".into(),
+        Duration::from_millis(50),
+    )
+    .await
+    .unwrap_err();
+    let extensions = error.extensions.unwrap();
+    assert_eq!(extensions.get("code"), Some(&Value::from("504")));
+    assert_eq!(
+        extensions.get("type"),
+        Some(&Value::from("Gateway Timeout"))
+    );
+
+    let response = execute_with_timeout(
+        &schema,
+        "{ sleep(ms: 0) }".into(),
+        Duration::from_millis(500),
+    )
+    .await
+    .unwrap();
+    assert!(response.errors.is_empty());
+}
+
+#[actix_web::test]
+async fn test_slow_query_log() {
+    let logs = captured_query(&sleep_schema(Duration::from_secs(60))).await;
+    assert!(!logs.contains("Slow GraphQL operation"));
+
+    let logs = captured_query(&sleep_schema(Duration::ZERO)).await;
+    assert!(logs.contains("Slow GraphQL operation"));
+    assert!(logs.contains("operation=\"Nap\""));
+}
+
+struct PasswordMutation;
+
+#[Object]
+impl PasswordMutation {
+    async fn change_password(&self, email: String, password: String) -> bool {
+        !email.is_empty() && !password.is_empty()
+    }
+}
+
+#[actix_web::test]
+async fn test_operation_log() {
+    let schema = Schema::build(SleepQuery, PasswordMutation, EmptySubscription)
+        .extension(OperationLog)
+        .finish();
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let request = Request::new(
+        "mutation ChangePassword($email: String!, $password: String!) { \
+            changePassword(email: $email, password: $password) \
+        }",
+    )
+    .variables(Variables::from_json(json!({
+        "email": "john@gmail.com",
+        "password": "Valid_Password12",
+    })));
+    let http_request = test::TestRequest::get()
+        .insert_header((REQUEST_ID_HEADER, "operation-log-1"))
+        .to_srv_request();
+    let response = RequestId::from_request(&http_request)
+        .scope(schema.execute(request))
+        .await;
+    assert!(response.errors.is_empty());
+    assert_eq!(
+        response.extensions.get(REQUEST_ID_EXTENSION),
+        Some(&Value::from("operation-log-1"))
+    );
+
+    let logs = logs.contents();
+    assert!(logs.contains("GraphQL operation"));
+    assert!(logs.contains("operation=\"ChangePassword\""));
+    assert!(logs.contains("operation_type=\"mutation\""));
+    assert!(logs.contains("fields=\"changePassword\""));
+    assert!(logs.contains("request_id=\"operation-log-1\""));
+    assert!(logs.contains("john@gmail.com"));
+    assert!(logs.contains("[REDACTED]"));
+    assert!(!logs.contains("Valid_Password12"));
+}
+
+/// Lines only in the snapshot are marked with `-`, lines only in the new schema with `+`.
+fn line_diff(expected: &str, actual: &str) -> String {
+    let expected = expected.lines().collect::<Vec<&str>>();
+    let actual = actual.lines().collect::<Vec<&str>>();
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j, mut diff) = (0, 0, String::new());
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1])
+        {
+            diff.push_str(&format!("{:>5} - {}\n", i + 1, expected[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("{:>5} + {}\n", j + 1, actual[j]));
+            j += 1;
+        }
+    }
+
+    diff
+}
+
+#[actix_web::test]
+async fn test_line_diff() {
+    assert_eq!(line_diff("a\nb\nc", "a\nb\nc"), "");
+    assert_eq!(
+        line_diff("a\nb\nc", "a\nd\nc\ne"),
+        "    2 - b\n    2 + d\n    4 + e\n"
+    );
+}
+
+#[actix_web::test]
+async fn test_schema_snapshot() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_SNAPSHOT);
+    let sdl = schema_sdl();
+
+    if env::var("UPDATE_SCHEMA").is_ok() {
+        fs::write(&path, &sdl).unwrap();
+        return;
+    }
+
+    let snapshot = fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        snapshot == sdl,
+        "The GraphQL schema no longer matches {}, if the change is intended run \
+        `cargo run -- --print-schema > {}` or this test with UPDATE_SCHEMA=1:\n{}",
+        SCHEMA_SNAPSHOT,
+        SCHEMA_SNAPSHOT,
+        line_diff(&snapshot, &sdl)
+    );
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2023 Afonso Barracha
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{env, thread};
+
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    rt, test, App, Error,
+};
+use entities::{enums::OAuthProviderEnum, user};
+use fake::{faker::name::raw::*, locales::EN, Fake};
+use rand::{thread_rng, Rng};
+use sea_orm::{ActiveModelTrait, ConnectionTrait, Set};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use tracing_actix_web::TracingLogger;
+use uuid::Uuid;
+
+use crate::providers::{
+    Cache, Config, Database, Environment, Jwt, Maintenance, Metrics, TokenType, Webhooks,
+};
+use crate::services::users_service;
+use crate::startup::{ActixApp, Providers, SecurityHeaders};
+
+pub const VALID_PASSWORD: &str = "Valid_Password12";
+pub const GRAPHQL_PATH: &str = "/api/graphql";
+
+static EXTENSIONS: OnceCell<()> = OnceCell::const_new();
+
+/// Schema owned by a single test, dropped with everything in it once the test ends.
+pub struct TestSchema {
+    database_url: String,
+    name: String,
+}
+
+impl TestSchema {
+    /// Creates an empty schema, with the extensions the migrations rely on installed.
+    pub async fn new() -> Self {
+        dotenvy::dotenv().expect("Failed to load .env file");
+        let database_url =
+            env::var("DATABASE_URL").expect("Missing the DATABASE_URL environment variable.");
+        let schema = Self {
+            database_url,
+            name: format!("test_{}", Uuid::new_v4().simple()),
+        };
+        let db = Database::connect(&schema.database_url, None)
+            .await
+            .expect("Failed to connect to database");
+        EXTENSIONS
+            .get_or_init(|| async {
+                db.get_connection()
+                    .execute_unprepared("CREATE EXTENSION IF NOT EXISTS pg_trgm SCHEMA public")
+                    .await
+                    .expect("Failed to create the database extensions");
+            })
+            .await;
+        db.get_connection()
+            .execute_unprepared(&format!("CREATE SCHEMA \"{}\"", schema.name))
+            .await
+            .expect("Failed to create test schema");
+        schema
+    }
+
+    pub async fn connect(&self) -> Database {
+        Database::connect_with_schema(&self.database_url, &self.name)
+            .await
+            .expect("Failed to connect to test schema")
+    }
+}
+
+impl Drop for TestSchema {
+    fn drop(&mut self) {
+        let database_url = self.database_url.clone();
+        let statement = format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", self.name);
+
+        // The test runtime may already be gone, so the schema is dropped on a fresh one
+        let result = thread::spawn(move || {
+            rt::System::new().block_on(async move {
+                let db = Database::connect(&database_url, None).await?;
+                db.get_connection().execute_unprepared(&statement).await?;
+                anyhow::Ok(())
+            })
+        })
+        .join();
+
+        if !matches!(result, Ok(Ok(()))) {
+            eprintln!("Failed to drop test schema {}", self.name);
+        }
+    }
+}
+
+/// The app wired with every provider against a migrated schema of its own, so tests
+/// running in parallel never see each other's rows.
+pub struct TestApp<S> {
+    pub config: Config,
+    pub db: Database,
+    pub jwt: Jwt,
+    pub cache: Cache,
+    pub maintenance: Maintenance,
+    service: S,
+    _schema: TestSchema,
+}
+
+impl TestApp<()> {
+    pub async fn new(
+    ) -> TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>
+    {
+        Self::with_config(|_| {}).await
+    }
+
+    /// Same as `new`, with the configuration adjusted before the app is built.
+    pub async fn with_config<F: FnOnce(&mut Config)>(
+        configure: F,
+    ) -> TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>
+    {
+        let schema = TestSchema::new().await;
+        let mut config = Config::try_with_environment(Environment::Development)
+            .expect("Invalid test configuration");
+        configure(&mut config);
+        let db = schema.connect().await;
+        db.run_migrations().await.expect("Failed to run migrations");
+        // Redis is shared between schemas, distinct user ids keep their cache keys apart
+        db.get_connection()
+            .execute_unprepared(&format!(
+                "SELECT setval(pg_get_serial_sequence('users', 'id'), {})",
+                thread_rng().gen_range(1_000..1_000_000_000)
+            ))
+            .await
+            .expect("Failed to offset user ids");
+        let providers = Providers::new(&config, &Metrics::new());
+        // Like the ids, the maintenance flag is kept apart from other tests sharing Redis
+        let maintenance =
+            Maintenance::with_key(&providers.cache, &format!("maintenance:{}", schema.name));
+        let providers = providers.with_maintenance(maintenance.clone());
+        let service = test::init_service(
+            App::new()
+                .wrap(TracingLogger::default())
+                .wrap(SecurityHeaders)
+                .configure(ActixApp::build_app_config(&config, &db, &providers)),
+        )
+        .await;
+
+        TestApp {
+            jwt: providers.jwt.get_ref().clone(),
+            cache: providers.cache.get_ref().clone(),
+            maintenance,
+            config,
+            db,
+            service,
+            _schema: schema,
+        }
+    }
+}
+
+impl<S, B> TestApp<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    /// A local user with a fake name and `VALID_PASSWORD`.
+    pub async fn create_user(&self, confirm: bool) -> user::Model {
+        let user = users_service::create_user(
+            &self.db.session(),
+            &Webhooks::disabled(),
+            Name(EN).fake(),
+            Name(EN).fake(),
+            Some("1990-01-01".to_string()),
+            format!("{}@gmail.com", Uuid::new_v4()),
+            VALID_PASSWORD.to_string(),
+            OAuthProviderEnum::Local,
+            users_service::UserPreferences::default(),
+            None,
+        )
+        .await
+        .expect("Failed to create user");
+
+        if !confirm {
+            return user;
+        }
+
+        let mut user: user::ActiveModel = user.into();
+        user.confirmed = Set(true);
+        user.version = Set(1);
+        user.update(self.db.get_connection())
+            .await
+            .expect("Failed to confirm user")
+    }
+
+    pub fn bearer_for(&self, user: &user::Model) -> String {
+        format!(
+            "Bearer {}",
+            self.jwt
+                .generate_access_token(user)
+                .expect("Failed to generate access token")
+        )
+    }
+
+    pub fn token_for(&self, user: &user::Model, token_type: TokenType) -> String {
+        self.jwt
+            .generate_email_token(token_type, user)
+            .expect("Failed to generate email token")
+    }
+
+    pub async fn call(&self, req: Request) -> ServiceResponse<B> {
+        test::call_service(&self.service, req).await
+    }
+
+    pub async fn post_json(&self, path: &str, body: Value) -> ServiceResponse<B> {
+        self.call(
+            test::TestRequest::post()
+                .uri(path)
+                .set_json(body)
+                .to_request(),
+        )
+        .await
+    }
+
+    pub async fn graphql(&self, query: &str) -> Value {
+        test::call_and_read_body_json(
+            &self.service,
+            test::TestRequest::post()
+                .uri(GRAPHQL_PATH)
+                .set_json(json!({ "query": query }))
+                .to_request(),
+        )
+        .await
+    }
+
+    pub async fn graphql_as(&self, user: &user::Model, query: &str) -> Value {
+        test::call_and_read_body_json(
+            &self.service,
+            test::TestRequest::post()
+                .uri(GRAPHQL_PATH)
+                .insert_header(("Authorization", self.bearer_for(user)))
+                .set_json(json!({ "query": query }))
+                .to_request(),
+        )
+        .await
+    }
+}